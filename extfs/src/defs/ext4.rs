@@ -1,8 +1,19 @@
+use fs_common::bytes::{FromBytes, ToBytes};
+use fs_common::clock::Timestamp;
+use glenda::error::Error;
+
 pub const SUPER_BLOCK_OFFSET: usize = 1024;
 pub const EXT4_SUPER_MAGIC: u16 = 0xEF53;
 
 // Fixed inode numbers
 pub const ROOT_INO: u32 = 2;
+// Holds the reserved descriptor table blocks online growth draws on
+pub const RESIZE_INO: u32 = 7;
+
+// i_mode file type bits
+pub const EXT4_S_IFMT: u16 = 0xF000;
+pub const EXT4_S_IFDIR: u16 = 0x4000;
+pub const EXT4_S_IFREG: u16 = 0x8000;
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -68,26 +79,26 @@ pub struct SuperBlock {
     pub s_flags: u32,
     pub s_raid_stride: u16,
     pub s_mmp_interval: u16,
-    pub s_mmp_block: usize,
+    pub s_mmp_block: u64,
     pub s_raid_stripe_width: u32,
     pub s_log_groups_per_flex: u8,
     pub s_checksum_type: u8,
     pub s_reserved_pad: u16,
-    pub s_kbytes_written: usize,
+    pub s_kbytes_written: u64,
     pub s_snapshot_inum: u32,
     pub s_snapshot_id: u32,
-    pub s_snapshot_r_blocks_count: usize,
+    pub s_snapshot_r_blocks_count: u64,
     pub s_snapshot_list: u32,
     pub s_error_count: u32,
     pub s_first_error_time: u32,
     pub s_first_error_ino: u32,
-    pub s_first_error_block: usize,
+    pub s_first_error_block: u64,
     pub s_first_error_func: [u8; 32],
     pub s_first_error_line: u32,
     pub s_last_error_time: u32,
     pub s_last_error_ino: u32,
     pub s_last_error_line: u32,
-    pub s_last_error_block: usize,
+    pub s_last_error_block: u64,
     pub s_last_error_func: [u8; 32],
     pub s_mount_opts: [u8; 64],
     pub s_usr_quota_inum: u32,
@@ -103,6 +114,100 @@ pub struct SuperBlock {
     pub s_checksum: u32,
 }
 
+fs_common::impl_le_codec!(SuperBlock {
+    s_inodes_count,
+    s_blocks_count_lo,
+    s_r_blocks_count_lo,
+    s_free_blocks_count_lo,
+    s_free_inodes_count,
+    s_first_data_block,
+    s_log_block_size,
+    s_log_cluster_size,
+    s_blocks_per_group,
+    s_clusters_per_group,
+    s_inodes_per_group,
+    s_mtime,
+    s_wtime,
+    s_mnt_count,
+    s_max_mnt_count,
+    s_magic,
+    s_state,
+    s_errors,
+    s_minor_rev_level,
+    s_lastcheck,
+    s_checkinterval,
+    s_creator_os,
+    s_rev_level,
+    s_def_resuid,
+    s_def_resgid,
+    s_first_ino,
+    s_inode_size,
+    s_block_group_nr,
+    s_feature_compat,
+    s_feature_incompat,
+    s_feature_ro_compat,
+    s_uuid,
+    s_volume_name,
+    s_last_mounted,
+    s_algo_bitmap,
+    s_prealloc_blocks,
+    s_prealloc_dir_blocks,
+    s_reserved_gdt_blocks,
+    s_journal_uuid,
+    s_journal_inum,
+    s_journal_dev,
+    s_last_orphan,
+    s_hash_seed,
+    s_def_hash_version,
+    s_jnl_backup_type,
+    s_desc_size,
+    s_default_mount_opts,
+    s_first_meta_bg,
+    s_mkfs_time,
+    s_jnl_blocks,
+    s_blocks_count_hi,
+    s_r_blocks_count_hi,
+    s_free_blocks_count_hi,
+    s_min_extra_isize,
+    s_want_extra_isize,
+    s_flags,
+    s_raid_stride,
+    s_mmp_interval,
+    s_mmp_block,
+    s_raid_stripe_width,
+    s_log_groups_per_flex,
+    s_checksum_type,
+    s_reserved_pad,
+    s_kbytes_written,
+    s_snapshot_inum,
+    s_snapshot_id,
+    s_snapshot_r_blocks_count,
+    s_snapshot_list,
+    s_error_count,
+    s_first_error_time,
+    s_first_error_ino,
+    s_first_error_block,
+    s_first_error_func,
+    s_first_error_line,
+    s_last_error_time,
+    s_last_error_ino,
+    s_last_error_line,
+    s_last_error_block,
+    s_last_error_func,
+    s_mount_opts,
+    s_usr_quota_inum,
+    s_grp_quota_inum,
+    s_overhead_blocks,
+    s_backup_bgs,
+    s_encrypt_algos,
+    s_encrypt_pw_salt,
+    s_lpf_ino,
+    s_prj_quota_inum,
+    s_checksum_seed,
+    s_reserved,
+    s_checksum,
+});
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct GroupDesc {
@@ -114,16 +219,50 @@ pub struct GroupDesc {
     pub bg_used_dirs_count_lo: u16,
     pub bg_flags: u16,
     pub bg_exclude_bitmap_lo: u32,
-    pub bg_block_bitmap_hi: u16,
-    pub bg_inode_bitmap_hi: u16,
-    pub bg_inode_table_hi: u16,
+    pub bg_block_bitmap_csum_lo: u16,
+    pub bg_inode_bitmap_csum_lo: u16,
+    pub bg_itable_unused_lo: u16,
+    pub bg_checksum: u16,
+    // Only present when the descriptor size is at least 64 (INCOMPAT_64BIT)
+    pub bg_block_bitmap_hi: u32,
+    pub bg_inode_bitmap_hi: u32,
+    pub bg_inode_table_hi: u32,
     pub bg_free_blocks_count_hi: u16,
     pub bg_free_inodes_count_hi: u16,
     pub bg_used_dirs_count_hi: u16,
-    pub bg_pad: u16,
-    pub bg_reserved: [u32; 3],
+    pub bg_itable_unused_hi: u16,
+    pub bg_exclude_bitmap_hi: u32,
+    pub bg_block_bitmap_csum_hi: u16,
+    pub bg_inode_bitmap_csum_hi: u16,
+    pub bg_reserved: u32,
 }
 
+fs_common::impl_le_codec!(GroupDesc {
+    bg_block_bitmap_lo,
+    bg_inode_bitmap_lo,
+    bg_inode_table_lo,
+    bg_free_blocks_count_lo,
+    bg_free_inodes_count_lo,
+    bg_used_dirs_count_lo,
+    bg_flags,
+    bg_exclude_bitmap_lo,
+    bg_block_bitmap_csum_lo,
+    bg_inode_bitmap_csum_lo,
+    bg_itable_unused_lo,
+    bg_checksum,
+    bg_block_bitmap_hi,
+    bg_inode_bitmap_hi,
+    bg_inode_table_hi,
+    bg_free_blocks_count_hi,
+    bg_free_inodes_count_hi,
+    bg_used_dirs_count_hi,
+    bg_itable_unused_hi,
+    bg_exclude_bitmap_hi,
+    bg_block_bitmap_csum_hi,
+    bg_inode_bitmap_csum_hi,
+    bg_reserved,
+});
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
 pub struct Inode {
@@ -145,13 +284,165 @@ pub struct Inode {
     pub i_size_hi: u32,
     pub i_obso_faddr: u32,
     pub i_osd2: [u8; 12],
+    // Past the first 128 bytes, as far as i_extra_isize says the slot holds them
+    pub i_extra_isize: u16,
+    pub i_checksum_hi: u16,
+    pub i_ctime_extra: u32,
+    pub i_mtime_extra: u32,
+    pub i_atime_extra: u32,
+    pub i_crtime: u32,
+    pub i_crtime_extra: u32,
+    pub i_version_hi: u32,
+    pub i_projid: u32,
+}
+
+fs_common::impl_le_codec!(Inode {
+    i_mode,
+    i_uid,
+    i_size_lo,
+    i_atime,
+    i_ctime,
+    i_mtime,
+    i_dtime,
+    i_gid,
+    i_links_count,
+    i_blocks_lo,
+    i_flags,
+    i_osd1,
+    i_block,
+    i_generation,
+    i_file_acl_lo,
+    i_size_hi,
+    i_obso_faddr,
+    i_osd2,
+    i_extra_isize,
+    i_checksum_hi,
+    i_ctime_extra,
+    i_mtime_extra,
+    i_atime_extra,
+    i_crtime,
+    i_crtime_extra,
+    i_version_hi,
+    i_projid,
+});
+impl Inode {
+    pub fn size(&self) -> u64 {
+        ((self.i_size_hi as u64) << 32) | self.i_size_lo as u64
+    }
+
+    pub fn set_size(&mut self, size: u64) {
+        self.i_size_lo = size as u32;
+        self.i_size_hi = (size >> 32) as u32;
+    }
+
+    // The high halves of the owner live in osd2 (l_i_uid_high, l_i_gid_high)
+    pub fn uid(&self) -> u32 {
+        self.i_uid as u32 | (u16::from_le_bytes([self.i_osd2[4], self.i_osd2[5]]) as u32) << 16
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.i_gid as u32 | (u16::from_le_bytes([self.i_osd2[6], self.i_osd2[7]]) as u32) << 16
+    }
+
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.i_uid = uid as u16;
+        self.i_gid = gid as u16;
+        self.i_osd2[4..6].copy_from_slice(&((uid >> 16) as u16).to_le_bytes());
+        self.i_osd2[6..8].copy_from_slice(&((gid >> 16) as u16).to_le_bytes());
+    }
+
+    /// Bytes of an inode slot of `slot_size` the inode takes up: the base
+    /// inode and the i_extra_isize bytes after it that this struct knows.
+    pub fn stored_len(&self, slot_size: usize) -> usize {
+        if slot_size <= EXT4_GOOD_OLD_INODE_SIZE {
+            return EXT4_GOOD_OLD_INODE_SIZE;
+        }
+        let extra = core::cmp::min(self.i_extra_isize as usize, EXT4_INODE_EXTRA_SIZE);
+        core::cmp::min(EXT4_GOOD_OLD_INODE_SIZE + extra, slot_size)
+    }
+
+    /// Zeroes the fields the slot does not hold, which were read from
+    /// in-inode attributes or the next inode.
+    pub fn clear_unstored(&mut self, slot_size: usize) -> Result<(), Error> {
+        let mut raw = [0u8; <Inode as ToBytes>::SIZE];
+        self.to_bytes(&mut raw)?;
+        raw[self.stored_len(slot_size)..].fill(0);
+        *self = Inode::from_bytes(&raw)?;
+        Ok(())
+    }
+
+    pub fn atime(&self) -> Timestamp {
+        decode_time(self.i_atime, self.i_atime_extra)
+    }
+
+    pub fn mtime(&self) -> Timestamp {
+        decode_time(self.i_mtime, self.i_mtime_extra)
+    }
+
+    pub fn ctime(&self) -> Timestamp {
+        decode_time(self.i_ctime, self.i_ctime_extra)
+    }
+
+    pub fn set_atime(&mut self, time: Timestamp) {
+        (self.i_atime, self.i_atime_extra) = encode_time(time);
+    }
+
+    pub fn set_mtime(&mut self, time: Timestamp) {
+        (self.i_mtime, self.i_mtime_extra) = encode_time(time);
+    }
+
+    pub fn set_ctime(&mut self, time: Timestamp) {
+        (self.i_ctime, self.i_ctime_extra) = encode_time(time);
+    }
+
+    pub fn set_crtime(&mut self, time: Timestamp) {
+        (self.i_crtime, self.i_crtime_extra) = encode_time(time);
+    }
 }
+
 pub const EXT4_FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x0004;
+pub const EXT4_FEATURE_COMPAT_RESIZE_INODE: u32 = 0x0010;
+pub const EXT4_FEATURE_COMPAT_DIR_INDEX: u32 = 0x0020;
+pub const EXT4_FEATURE_INCOMPAT_COMPRESSION: u32 = 0x0001;
+pub const EXT4_FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+pub const EXT4_FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+pub const EXT4_FEATURE_INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+pub const EXT4_FEATURE_INCOMPAT_META_BG: u32 = 0x0010;
 pub const EXT4_FEATURE_INCOMPAT_EXTENTS: u32 = 0x0040;
 pub const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x0080;
+pub const EXT4_FEATURE_INCOMPAT_MMP: u32 = 0x0100;
+pub const EXT4_FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+pub const EXT4_FEATURE_INCOMPAT_EA_INODE: u32 = 0x0400;
+pub const EXT4_FEATURE_INCOMPAT_DIRDATA: u32 = 0x1000;
+pub const EXT4_FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000;
+pub const EXT4_FEATURE_INCOMPAT_LARGEDIR: u32 = 0x4000;
+pub const EXT4_FEATURE_INCOMPAT_INLINE_DATA: u32 = 0x8000;
+pub const EXT4_FEATURE_INCOMPAT_ENCRYPT: u32 = 0x10000;
+pub const EXT4_FEATURE_INCOMPAT_CASEFOLD: u32 = 0x20000;
 pub const EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const EXT4_FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+pub const EXT4_FEATURE_RO_COMPAT_HUGE_FILE: u32 = 0x0008;
+pub const EXT4_FEATURE_RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+pub const EXT4_FEATURE_RO_COMPAT_DIR_NLINK: u32 = 0x0020;
+pub const EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE: u32 = 0x0040;
+pub const EXT4_FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+// bg_checksum sits at the end of the 32-byte base descriptor
+pub const EXT4_BG_CHECKSUM_OFFSET: usize = 0x1E;
+pub const EXT4_MIN_DESC_SIZE: u16 = 32;
+// bg_flags: the group's inode table, or its block bitmap, was never initialized
+pub const EXT4_BG_INODE_UNINIT: u16 = 0x0001;
+pub const EXT4_BG_BLOCK_UNINIT: u16 = 0x0002;
+// s_state: cleanly unmounted, and errors detected
+pub const EXT4_VALID_FS: u16 = 0x0001;
+pub const EXT4_ERROR_FS: u16 = 0x0002;
+pub const EXT4_MIN_DESC_SIZE_64BIT: u16 = 64;
+pub const EXT4_INDEX_FL: u32 = 0x1000;
 pub const EXT4_EXTENTS_FL: u32 = 0x80000;
+// s_flags: directory hashes were computed with unsigned chars
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x0002;
 pub const EXT4_EXT_MAGIC: u16 = 0xF30A;
+// ee_len above this marks an uninitialized extent of (ee_len - EXT_INIT_MAX_LEN) blocks
+pub const EXT_INIT_MAX_LEN: u16 = 32768;
 
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -163,6 +454,8 @@ pub struct ExtentHeader {
     pub eh_generation: u32,
 }
 
+fs_common::impl_le_codec!(ExtentHeader { eh_magic, eh_entries, eh_max, eh_depth, eh_generation });
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Extent {
@@ -172,6 +465,8 @@ pub struct Extent {
     pub ee_start_lo: u32,
 }
 
+fs_common::impl_le_codec!(Extent { ee_block, ee_len, ee_start_hi, ee_start_lo });
+
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ExtentIndex {
@@ -181,6 +476,56 @@ pub struct ExtentIndex {
     pub ei_unused: u16,
 }
 
+fs_common::impl_le_codec!(ExtentIndex { ei_block, ei_leaf_lo, ei_leaf_hi, ei_unused });
+
+// Extended attributes: a header magic, then entries up to four zero bytes. In the inode
+// they follow i_extra_isize past the first 128 bytes; a block has a 32-byte header.
+pub const EXT4_XATTR_MAGIC: u32 = 0xEA02_0000;
+pub const EXT4_GOOD_OLD_INODE_SIZE: usize = 128;
+pub const EXT4_XATTR_BLOCK_HEADER_SIZE: usize = 32;
+// i_extra_isize of the fields Inode knows, up to i_projid
+pub const EXT4_INODE_EXTRA_SIZE: usize = 32;
+
+// A timestamp's extra field: two bits extending the seconds past 2038, then nanoseconds
+const EXT4_EPOCH_BITS: u32 = 2;
+const EXT4_EPOCH_MASK: u32 = (1 << EXT4_EPOCH_BITS) - 1;
+
+fn decode_time(sec: u32, extra: u32) -> Timestamp {
+    let epoch = (extra & EXT4_EPOCH_MASK) as i64;
+    Timestamp::new(sec as i32 as i64 + (epoch << 32), extra >> EXT4_EPOCH_BITS)
+}
+
+fn encode_time(time: Timestamp) -> (u32, u32) {
+    let epoch = ((time.sec - time.sec as i32 as i64) >> 32) as u32 & EXT4_EPOCH_MASK;
+    (time.sec as u32, epoch | time.nsec << EXT4_EPOCH_BITS)
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XattrEntry {
+    pub e_name_len: u8,
+    pub e_name_index: u8,
+    pub e_value_offs: u16,
+    // Inode holding the value (EA_INODE), 0 when it is stored next to the entries
+    pub e_value_inum: u32,
+    pub e_value_size: u32,
+    pub e_hash: u32,
+    // Name follows, padded to 4 bytes
+}
+
+fs_common::impl_le_codec!(XattrEntry {
+    e_name_len,
+    e_name_index,
+    e_value_offs,
+    e_value_inum,
+    e_value_size,
+    e_hash
+});
+
+pub const EXT4_NAME_LEN: usize = 255;
+// Most links an inode may have
+pub const EXT4_LINK_MAX: u16 = 65000;
+
 // Directory types
 pub const EXT4_FT_UNKNOWN: u8 = 0;
 pub const EXT4_FT_REG_FILE: u8 = 1;
@@ -195,3 +540,5 @@ pub struct DirEntry2 {
     pub file_type: u8,
     // Name follows
 }
+
+fs_common::impl_le_codec!(DirEntry2 { inode, rec_len, name_len, file_type });
@@ -0,0 +1,68 @@
+use crate::defs::ext4::*;
+use glenda::error::Error;
+
+// Incompat features this driver reads and writes correctly
+const INCOMPAT_SUPP: u32 = EXT4_FEATURE_INCOMPAT_FILETYPE
+    | EXT4_FEATURE_INCOMPAT_EXTENTS
+    | EXT4_FEATURE_INCOMPAT_64BIT
+    | EXT4_FEATURE_INCOMPAT_FLEX_BG
+    | EXT4_FEATURE_INCOMPAT_CSUM_SEED;
+
+// Incompat features that leave the on-disk layout readable but would be
+// damaged by our writes: a journal awaiting replay, multi-mount protection,
+// three-level htrees and shared xattr inodes.
+const INCOMPAT_READ_ONLY: u32 = EXT4_FEATURE_INCOMPAT_RECOVER
+    | EXT4_FEATURE_INCOMPAT_MMP
+    | EXT4_FEATURE_INCOMPAT_LARGEDIR
+    | EXT4_FEATURE_INCOMPAT_EA_INODE;
+
+// Read-only compatible features whose metadata our writes keep consistent
+const RO_COMPAT_SUPP: u32 = EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER
+    | EXT4_FEATURE_RO_COMPAT_LARGE_FILE
+    | EXT4_FEATURE_RO_COMPAT_DIR_NLINK
+    | EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE;
+
+/// How far a volume can be trusted to this driver, given its feature bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureSupport {
+    ReadWrite,
+    /// Readable, but writing would corrupt the features listed.
+    ReadOnly {
+        incompat: u32,
+        ro_compat: u32,
+    },
+    /// Even reading could misinterpret the layout, e.g. inline data,
+    /// encryption or case-folded names.
+    Unsupported {
+        incompat: u32,
+    },
+}
+
+impl FeatureSupport {
+    pub fn check(sb: &SuperBlock) -> Self {
+        let incompat = sb.s_feature_incompat & !INCOMPAT_SUPP;
+        let unknown = incompat & !INCOMPAT_READ_ONLY;
+        if unknown != 0 {
+            return FeatureSupport::Unsupported { incompat: unknown };
+        }
+        let ro_compat = sb.s_feature_ro_compat & !RO_COMPAT_SUPP;
+        if incompat != 0 || ro_compat != 0 {
+            return FeatureSupport::ReadOnly { incompat, ro_compat };
+        }
+        FeatureSupport::ReadWrite
+    }
+
+    pub fn writable(&self) -> bool {
+        *self == FeatureSupport::ReadWrite
+    }
+
+    /// Error reported for a request the features rule out: NotSupported when the
+    /// volume cannot be mounted at all, PermissionDenied for writes to a
+    /// volume held read-only.
+    pub fn error(&self) -> Error {
+        match self {
+            FeatureSupport::Unsupported { .. } => Error::NotSupported,
+            _ => Error::PermissionDenied,
+        }
+    }
+}
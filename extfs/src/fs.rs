@@ -1,14 +1,45 @@
-use crate::block::BlockReader;
 use crate::defs::ext4::*;
-use crate::layout::{NOTIFY_SLOT, RECV_BUFFER_SLOT, RECV_RING_SLOT};
+use crate::features::FeatureSupport;
+use crate::htree::{self, DxHash};
+use crate::layout::DeviceSlots;
+use crate::mkfs;
 use crate::ops::ExtOps;
 use crate::versions::ext2::Ext2Ops;
 use crate::versions::ext3::Ext3Ops;
 use crate::versions::ext4::Ext4Ops;
+use crate::volume::ExtVolume;
+use crate::xattr;
 use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use core::slice;
+use fs_block::BlockReader;
+use fs_common::batch::{ReadBatch, SCAN_BATCH_BLOCKS};
+use fs_common::bytes::{FromBytes, ToBytes};
+use fs_common::casefold;
+use fs_common::cbt::ChangeTracker;
+use fs_common::clock::{self, TimesRequest, Timestamp};
+use fs_common::coalesce::WriteCombiner;
+use fs_common::creds::{Cred, CredentialMap, MAY_EXEC, MAY_READ, MAY_WRITE};
+use fs_common::device::IoTuning;
+use fs_common::errors::FsError;
+use fs_common::handle::FsHandle;
+use fs_common::health::IoErrorCounts;
+use fs_common::heat::HeatMap;
+use fs_common::history::{self, VersionStore};
+use fs_common::jobs::{Job, Step};
+use fs_common::limits;
+use fs_common::mount::MountOptions;
+use fs_common::partition::{self, PartitionSelect};
+use fs_common::proto::{
+    dentry, CURRENT_OFFSET, DT_DIR, DT_REG, DT_UNKNOWN, FALLOC_KEEP_SIZE, SEEK_DATA, SEEK_HOLE,
+};
+use fs_common::readahead::Readahead;
+use fs_common::scrub::ScrubReport;
+use fs_common::statfs::FsStats;
+use fs_common::tune::{CacheTunables, WritebackTimer};
 use glenda::cap::{Endpoint, Frame};
 use glenda::error::Error;
 use glenda::interface::fs::FileHandleService;
@@ -19,37 +50,53 @@ use glenda::mem::shm::ShmParams;
 use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
 
+// Removals per journal transaction in `rmtree`
+const RMTREE_BATCH: usize = 64;
+
 pub struct ExtFs {
     reader: BlockReader,
-    sb: SuperBlock,
     block_size: u32,
-    group_desc_size: u16,
-    inodes_per_group: u32,
     ops: Arc<dyn ExtOps>,
+    vol: Arc<ExtVolume>,
     ring_vaddr: usize,
     ring_size: usize,
+    tunables: CacheTunables,
+    writeback: WritebackTimer,
+    options: MountOptions,
+    features: FeatureSupport,
+    creds: CredentialMap,
+    // Set when the volume has dir_index
+    dx_hash: Option<DxHash>,
 }
 
+// Cache budget is accounted in in-memory inodes
+const CACHED_INODE_BYTES: usize = core::mem::size_of::<Inode>();
+
 use glenda::client::ResourceClient;
 use glenda::interface::ResourceService;
 
 impl ExtFs {
-    pub fn new(
+    /// Connects to the block device and narrows IO to the selected
+    /// partition. The volume is not looked at beyond the partition table, so
+    /// the caller can probe it before committing to `mount`.
+    pub fn open_reader(
         block_device: Endpoint,
         ring_vaddr: usize,
         ring_size: usize,
+        slots: DeviceSlots,
         res_client: &mut ResourceClient,
         vspace: &mut VSpaceManager,
         cspace: &mut CSpaceManager,
-    ) -> Result<Self, Error> {
+        select: PartitionSelect,
+    ) -> Result<BlockReader, Error> {
         // 1. Setup IoUring Params
         let sq_entries = 4;
         let cq_entries = 4;
-        let notify_slot = NOTIFY_SLOT;
+        let notify_slot = slots.notify;
         res_client.alloc(Badge::null(), glenda::cap::CapType::Endpoint, 0, notify_slot)?;
         let notify_ep = glenda::cap::Endpoint::from(notify_slot);
-        let recv_ring_slot = RECV_RING_SLOT;
-        let recv_buffer_slot = RECV_BUFFER_SLOT;
+        let recv_ring_slot = slots.recv_ring;
+        let recv_buffer_slot = slots.recv_buffer;
 
         let ring_params = RingParams {
             sq_entries,
@@ -71,20 +118,52 @@ impl ExtFs {
         // 2. Create reader and init (VolumeClient handles handshake)
         let mut reader = BlockReader::new(block_device, res_client, ring_params, shm_params);
         reader.init(vspace, cspace)?;
+        let geometry = reader.geometry();
+        glenda::log!(
+            "ExtFS: device blocks of {} bytes, {} physical",
+            geometry.logical_block,
+            geometry.physical_block
+        );
+        let part = partition::select(
+            |offset, buf| reader.read_offset(offset as usize, buf).map(|_| ()),
+            select,
+            geometry.logical_block as u64,
+        )?;
+        if let Some(part) = &part {
+            glenda::log!(
+                "ExtFS: partition {} at {:#x}, {} bytes",
+                part.index,
+                part.start,
+                part.len
+            );
+        }
+        reader.set_partition(part.as_ref());
+        Ok(reader)
+    }
 
+    pub fn mount(reader: BlockReader, ring_vaddr: usize, ring_size: usize) -> Result<Self, Error> {
         // ... (existing helper logic in new)
         let mut sb_buf = [0u8; 1024];
         reader.read_offset(SUPER_BLOCK_OFFSET, &mut sb_buf)?;
 
-        let sb = unsafe { core::ptr::read_unaligned(sb_buf.as_ptr() as *const SuperBlock) };
+        let sb = SuperBlock::from_bytes(&sb_buf)?;
         let magic = sb.s_magic;
 
         if magic != EXT4_SUPER_MAGIC {
             return Err(Error::InvalidArgs);
         }
+        // Blocks are at most 64 KiB, and a group's inode bitmap is one block
+        // so it holds no more inodes than that block has bits
+        let ipg = sb.s_inodes_per_group as usize;
+        if sb.s_log_block_size > 6 || ipg == 0 || ipg > (1024 << sb.s_log_block_size) * 8 {
+            return Err(Error::InvalidArgs);
+        }
 
-        let block_size = 1024 << sb.s_log_block_size;
-        let group_desc_size = if (sb.s_feature_incompat & 0x80) != 0 { sb.s_desc_size } else { 32 };
+        let features = FeatureSupport::check(&sb);
+        if let FeatureSupport::Unsupported { incompat } = features {
+            glenda::log!("ExtFS: refusing to mount, unsupported incompat features {:#x}", incompat);
+            return Err(features.error());
+        }
 
         // Determine OPS based on features
         let ops: Arc<dyn ExtOps> = if (sb.s_feature_incompat & EXT4_FEATURE_INCOMPAT_EXTENTS) != 0 {
@@ -98,132 +177,264 @@ impl ExtFs {
             Arc::new(Ext2Ops)
         };
 
+        let dx_hash = DxHash::new(&sb);
+        let vol = Arc::new(ExtVolume::new(sb, ops.clone()));
+        let cache_bytes = vol.icache_capacity() * CACHED_INODE_BYTES;
+        let tunables = CacheTunables::new(cache_bytes, reader.tuning().readahead_blocks);
         Ok(Self {
             reader,
-            sb,
-            block_size,
-            group_desc_size,
-            inodes_per_group: sb.s_inodes_per_group,
+            block_size: vol.block_size,
             ops,
+            vol,
             ring_vaddr,
             ring_size,
+            tunables,
+            writeback: WritebackTimer::new(),
+            options: MountOptions::default(),
+            features,
+            creds: CredentialMap::new(),
+            dx_hash,
         })
     }
 
-    fn read_group_desc(&self, group: u32) -> Result<GroupDesc, Error> {
-        let first_bg_block = self.sb.s_first_data_block + 1;
-        let offset = (first_bg_block as usize * self.block_size as usize)
-            + (group as usize * self.group_desc_size as usize);
-
-        let mut buf = [0u8; 64];
-        self.reader.read_offset(offset, &mut buf)?;
+    /// Writes an empty ext2 filesystem of `size` bytes over the volume, the
+    /// whole partition or device for 0, and mounts it in place of the old
+    /// one. Mount options, cache tunables and credentials carry over.
+    pub fn format(&mut self, size: usize, label: &str) -> Result<(), Error> {
+        let size = match size {
+            0 => self.reader.capacity().ok_or(Error::InvalidArgs)?,
+            size => size,
+        };
+        mkfs::format(&self.reader, size, label)?;
+        let mut fs = Self::mount(self.reader.clone(), self.ring_vaddr, self.ring_size)?;
+        fs.set_mount_options(self.options);
+        fs.set_cache_tunables(self.tunables);
+        fs.creds = core::mem::take(&mut self.creds);
+        *self = fs;
+        Ok(())
+    }
 
-        // Handling packed struct read safely
-        let gd = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const GroupDesc) };
-        Ok(gd)
+    /// Grows the mounted volume to `size` bytes, the whole partition or device
+    /// for 0, in one transaction. Returns the size it has now.
+    pub fn grow(&self, size: usize) -> Result<usize, Error> {
+        let size = match size {
+            0 => self.reader.capacity().ok_or(Error::InvalidArgs)?,
+            size => size,
+        };
+        let blocks = (size / self.block_size as usize) as u64;
+        let tid = self.vol.transaction_start();
+        match self.vol.grow(&self.reader, tid, blocks) {
+            Ok(blocks) => {
+                self.vol.transaction_commit(&self.reader, tid)?;
+                Ok(blocks as usize * self.block_size as usize)
+            }
+            Err(e) => {
+                self.vol.transaction_abort(tid)?;
+                Err(e)
+            }
+        }
     }
 
-    fn read_inode(&self, ino: u32) -> Result<Inode, Error> {
-        if ino < 1 {
-            return Err(Error::NotFound);
+    /// TRIM: discards every run of free blocks at least `min_len` bytes
+    /// long. Returns the bytes discarded.
+    pub fn trim(&self, min_len: usize) -> Result<usize, Error> {
+        if !self.reader.tuning().discard {
+            return Err(Error::NotSupported);
         }
-        let group = (ino - 1) / self.inodes_per_group;
-        let index = (ino - 1) % self.inodes_per_group;
+        let min_blocks = min_len.div_ceil(self.block_size as usize) as u64;
+        let blocks = self.vol.trim(&self.reader, min_blocks)?;
+        Ok(blocks as usize * self.block_size as usize)
+    }
+
+    pub fn set_io_tuning(&mut self, tuning: IoTuning) {
+        self.reader.set_tuning(tuning);
+        self.tunables.readahead_blocks = tuning.readahead_blocks;
+    }
 
-        let gd = self.read_group_desc(group)?;
+    pub fn features(&self) -> FeatureSupport {
+        self.features
+    }
 
-        let table_block = gd.bg_inode_table_lo;
+    pub fn io_tuning(&self) -> IoTuning {
+        self.reader.tuning()
+    }
 
-        let inode_size = self.sb.s_inode_size as usize;
-        let offset = (table_block as usize * self.block_size as usize) + (index as usize * inode_size);
+    pub fn stats(&self) -> Result<FsStats, Error> {
+        self.vol.stats(&self.reader)
+    }
 
-        let mut buf = [0u8; 256];
-        self.reader.read_offset(offset, &mut buf)?;
+    pub fn io_error_counts(&self) -> IoErrorCounts {
+        self.reader.error_counts()
+    }
 
-        let inode = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Inode) };
-        Ok(inode)
+    pub fn heat(&self) -> &HeatMap {
+        self.reader.heat()
     }
 
-    fn get_block_addr(&self, inode: &Inode, lblock: u32) -> Result<u32, Error> {
+    pub fn changes(&self) -> &ChangeTracker {
+        self.reader.changes()
+    }
+
+    /// Options the volume is mounted with. Handles opened from now on follow them.
+    pub fn set_mount_options(&mut self, options: MountOptions) {
+        self.options = options;
+    }
+
+    pub fn cache_tunables(&self) -> CacheTunables {
+        self.tunables
+    }
+
+    /// Resizes the inode cache and changes the readahead window. Handles
+    /// opened from now on use the new window. The dirty ratio and flush
+    /// interval drive `writeback_tick`.
+    pub fn set_cache_tunables(&mut self, tunables: CacheTunables) {
+        self.vol.set_icache_capacity(tunables.cache_max_bytes / CACHED_INODE_BYTES);
+        let tuning =
+            IoTuning { readahead_blocks: tunables.readahead_blocks, ..self.reader.tuning() };
+        self.reader.set_tuning(tuning);
+        self.tunables = tunables;
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, Error> {
+        self.vol.read_inode(&self.reader, ino)
+    }
+
+    fn get_block_addr(&self, inode: &Inode, lblock: u32) -> Result<u64, Error> {
         self.ops.get_block_addr(&self.reader, inode, lblock, self.block_size)
     }
 
-    fn resolve_path(&self, path: &str) -> Result<u32, Error> {
+    /// Which user and group `badge` acts as on this mount.
+    pub fn cred(&self, badge: Badge) -> Cred {
+        self.creds.get(badge.bits())
+    }
+
+    pub fn credentials_mut(&mut self) -> &mut CredentialMap {
+        &mut self.creds
+    }
+
+    // Every directory on the way must be searchable by `cred`
+    fn resolve_path(&self, cred: Cred, path: &str) -> Result<u32, Error> {
         let mut current_ino = ROOT_INO;
         for part in path.split('/') {
             if part.is_empty() || part == "." {
                 continue;
             }
-            current_ino = self.find_entry(current_ino, part)?;
+            current_ino = self.lookup(cred, current_ino, part)?;
         }
         Ok(current_ino)
     }
 
+    fn lookup(&self, cred: Cred, dir_ino: u32, name: &str) -> Result<u32, Error> {
+        if !cred.is_root() {
+            let dir = self.read_inode(dir_ino)?;
+            // find_entry reports a component that is not a directory
+            if (dir.i_mode & EXT4_S_IFMT) == EXT4_S_IFDIR {
+                check_access(cred, &dir, MAY_EXEC)?;
+            }
+        }
+        self.find_entry(dir_ino, name)
+    }
+
     fn find_entry(&self, dir_ino: u32, name: &str) -> Result<u32, Error> {
         let inode = self.read_inode(dir_ino)?;
-        if (inode.i_mode & 0xF000) != 0x4000 {
-            return Err(Error::DeviceError);
+        // A path component that is not a directory
+        if (inode.i_mode & EXT4_S_IFMT) != EXT4_S_IFDIR {
+            return Err(FsError::NotDir.into());
+        }
+        let casefold = self.options.casefold;
+        // The index hashes names as stored, so it is no use ignoring case
+        let indexed = inode.i_flags & EXT4_INDEX_FL != 0 && !casefold;
+        if let (Some(hash), true) = (&self.dx_hash, indexed) {
+            let scan = |block: &[u8]| Self::scan_dir_block(block, self.block_size, name, false);
+            let found =
+                htree::lookup(&self.vol, &self.reader, &*self.ops, hash, &inode, name, scan);
+            // An index that cannot be followed leaves the linear scan below
+            if let Ok(found) = found {
+                return found.ok_or(Error::NotFound);
+            }
         }
 
-        let size = inode.i_size_lo;
-        let mut offset = 0;
-
-        while offset < size {
-            let lblock = offset / self.block_size;
-            let pblock = self.get_block_addr(&inode, lblock)?;
+        let block_size = self.block_size as usize;
+        let blocks = inode.size().div_ceil(block_size as u64) as u32;
+        let mut batch_start = 0;
+
+        while batch_start < blocks {
+            // Map a run of directory blocks, then read them in one batch
+            let batch_end = core::cmp::min(batch_start + SCAN_BATCH_BLOCKS as u32, blocks);
+            let mut bufs = Vec::new();
+            for lblock in batch_start..batch_end {
+                let pblock = self.get_block_addr(&inode, lblock)?;
+                bufs.push((pblock, alloc::vec![0u8; block_size]));
+            }
+            let mut batch = ReadBatch::new();
+            for (pblock, buf) in bufs.iter_mut() {
+                batch.push((*pblock * block_size as u64) as usize, buf);
+            }
+            self.reader.submit(&mut batch)?;
+            batch_start = batch_end;
 
-            let mut block_buf = alloc::vec![0u8; self.block_size as usize];
-            let read_offset = pblock as usize * self.block_size as usize;
-            self.reader.read_offset(read_offset, &mut block_buf)?;
+            for (_, block_buf) in &bufs {
+                if let Some(ino) = Self::scan_dir_block(block_buf, self.block_size, name, casefold)?
+                {
+                    return Ok(ino);
+                }
+            }
+        }
 
-            let mut block_offset = 0;
-            while block_offset < self.block_size {
-                let ptr = unsafe { block_buf.as_ptr().add(block_offset as usize) };
-                let de = unsafe { core::ptr::read_unaligned(ptr as *const DirEntry2) };
+        Err(Error::NotFound)
+    }
 
-                if de.inode != 0 {
-                    let name_len = de.name_len as usize;
-                    let name_slice = unsafe { slice::from_raw_parts(ptr.add(8), name_len) };
-                    if name.as_bytes() == name_slice {
-                        return Ok(de.inode);
-                    }
+    // Looks `name` up in one directory block
+    fn scan_dir_block(
+        block_buf: &[u8],
+        block_size: u32,
+        name: &str,
+        casefold: bool,
+    ) -> Result<Option<u32>, Error> {
+        let mut block_offset = 0;
+        while block_offset < block_size {
+            let de = DirEntry2::from_bytes_at(block_buf, block_offset as usize)?;
+
+            if de.inode != 0 {
+                let name_start = block_offset as usize + <DirEntry2 as FromBytes>::SIZE;
+                let name_slice = block_buf
+                    .get(name_start..name_start + de.name_len as usize)
+                    .ok_or(Error::DeviceError)?;
+                if name_matches(name_slice, name, casefold) {
+                    return Ok(Some(de.inode));
                 }
+            }
 
-                block_offset += de.rec_len as u32;
-                if de.rec_len == 0 {
-                    break;
-                }
+            block_offset += de.rec_len as u32;
+            if de.rec_len == 0 {
+                break;
             }
-            offset += self.block_size;
         }
-
-        Err(Error::NotFound)
+        Ok(None)
     }
 }
 
 impl FileSystemJournalService for ExtFs {
     fn transaction_start(&mut self, _badge: Badge) -> Result<usize, Error> {
-        Ok(1)
+        Ok(self.vol.transaction_start())
     }
 
-    fn transaction_commit(&mut self, _badge: Badge, _tid: usize) -> Result<(), Error> {
-        Ok(())
+    fn transaction_commit(&mut self, _badge: Badge, tid: usize) -> Result<(), Error> {
+        self.vol.transaction_commit(&self.reader, tid)
     }
 
-    fn transaction_abort(&mut self, _badge: Badge, _tid: usize) -> Result<(), Error> {
-        Ok(())
+    fn transaction_abort(&mut self, _badge: Badge, tid: usize) -> Result<(), Error> {
+        self.vol.transaction_abort(tid)
     }
 
     fn log_block(
         &mut self,
         _badge: Badge,
-        _tid: usize,
+        tid: usize,
         block_num: usize,
         data: &[u8],
     ) -> Result<(), Error> {
-        let sector = block_num * (self.block_size as usize / 512);
-        self.reader.write_blocks(sector, data)?;
-        Ok(())
+        self.vol.log_block(&self.reader, tid, block_num as u64, data)
     }
 }
 
@@ -232,17 +443,73 @@ impl FileSystemJournalService for ExtFs {
 impl ExtFs {
     pub fn open_handle(
         &mut self,
-        _badge: Badge,
+        badge: Badge,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let found = self.resolve_path(self.cred(badge), path);
+        self.open_resolved(badge, path, found, flags, mode)
+    }
+
+    /// One directory lookup of an OPEN_ASYNC walk.
+    pub fn lookup_step(&self, badge: Badge, dir_ino: u32, name: &str) -> Result<u32, Error> {
+        self.lookup(self.cred(badge), dir_ino, name)
+    }
+
+    /// Finishes an OPEN of `path` once the walk down it ended in `found`.
+    pub fn open_resolved(
+        &mut self,
+        badge: Badge,
         path: &str,
-        _flags: OpenFlags,
-        _mode: u32,
-    ) -> Result<Box<dyn FileHandleService + Send>, Error> {
-        let ino = self.resolve_path(path)?;
+        found: Result<u32, Error>,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let cred = self.cred(badge);
+        let (ino, created) = match found {
+            Ok(_) if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) => {
+                return Err(Error::AlreadyExists);
+            }
+            Ok(ino) => (ino, false),
+            Err(Error::NotFound) if flags.contains(OpenFlags::O_CREAT) => {
+                (self.in_transaction(badge, |fs, tid| fs.create_in(tid, cred, path, mode))?, true)
+            }
+            Err(e) => return Err(e),
+        };
         let inode = self.read_inode(ino)?;
-        let handle = ExtFileHandle {
+        let is_dir = (inode.i_mode & EXT4_S_IFMT) == EXT4_S_IFDIR;
+        if flags.contains(OpenFlags::O_DIRECTORY) && !is_dir {
+            return Err(FsError::NotDir.into());
+        }
+        // Directories change through MKDIR, UNLINK and RENAME only
+        let writes = OpenFlags::O_WRONLY | OpenFlags::O_RDWR | OpenFlags::O_TRUNC;
+        if is_dir && flags.intersects(writes) {
+            return Err(FsError::IsDir.into());
+        }
+        // The creator may open a new file as asked, whatever mode it was given
+        if !created {
+            check_access(cred, &inode, open_access(flags))?;
+        }
+        self.vol.hold_inode(ino);
+        if is_dir {
+            return Ok(Box::new(ExtDirHandle {
+                ops: self.ops.clone(),
+                vol: self.vol.clone(),
+                reader: self.reader.clone(),
+                inode,
+                ino,
+                block_size: self.block_size,
+                pos: 0,
+            }));
+        }
+
+        let mut handle = ExtFileHandle {
             ops: self.ops.clone(),
+            vol: self.vol.clone(),
             reader: self.reader.clone(),
             inode,
+            ino,
             block_size: self.block_size,
             pos: 0,
             ring_vaddr: self.ring_vaddr,
@@ -250,38 +517,932 @@ impl ExtFs {
             uring: None,
             user_shm_base: 0,
             server_shm_base: 0,
+            pending: WriteCombiner::new(self.block_size as usize),
+            sync_writes: self.options.sync,
+            atime: !self.options.noatime,
+            append: flags.contains(OpenFlags::O_APPEND),
+            access: open_access(flags),
+            readahead: Readahead::new(),
         };
+        if flags.contains(OpenFlags::O_TRUNC) && !created && handle.inode.size() > 0 {
+            handle.truncate(badge, 0)?;
+        }
         Ok(Box::new(handle))
     }
 
-    pub fn mkdir(&mut self, badge: Badge, _path: &str, _mode: u32) -> Result<(), Error> {
-        let tid = self.transaction_start(badge)?;
-        self.transaction_commit(badge, tid)?;
-        Ok(())
+    pub fn mkdir(&mut self, badge: Badge, path: &str, mode: u32) -> Result<(), Error> {
+        let cred = self.cred(badge);
+        self.in_transaction(badge, |fs, tid| fs.mkdir_in(tid, cred, path, mode))
+    }
+
+    pub fn unlink(&mut self, badge: Badge, path: &str) -> Result<(), Error> {
+        let cred = self.cred(badge);
+        self.in_transaction(badge, |fs, tid| fs.unlink_in(tid, cred, path))
+    }
+
+    /// PermissionDenied unless `badge` may reach `path` and has `want`
+    /// (creds::MAY_* bits) on it.
+    pub fn access(&self, badge: Badge, path: &str, want: u32) -> Result<(), Error> {
+        let cred = self.cred(badge);
+        check_access(cred, &self.read_inode(self.resolve_path(cred, path)?)?, want)
+    }
+
+    /// Keeps a version of `path` before an OPEN by `badge` truncates it. The
+    /// copy is made by the service, so only once `badge` may write the file.
+    pub fn save_version(&mut self, badge: Badge, path: &str) -> Result<(), Error> {
+        match self.access(badge, path, MAY_WRITE) {
+            Ok(()) => history::save(self, path),
+            Err(Error::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Value of the extended attribute `name` of `path`.
+    pub fn getxattr(&self, path: &str, name: &str) -> Result<Vec<u8>, Error> {
+        xattr::get(&self.vol, &self.reader, self.resolve_path(Cred::ROOT, path)?, name)
     }
 
-    pub fn unlink(&mut self, badge: Badge, _path: &str) -> Result<(), Error> {
+    /// Names of the extended attributes of `path`.
+    pub fn listxattr(&self, path: &str) -> Result<Vec<String>, Error> {
+        xattr::list(&self.vol, &self.reader, self.resolve_path(Cred::ROOT, path)?)
+    }
+
+    /// Adds `new_path` as another name of the file at `old_path`. Directories
+    /// cannot be linked.
+    pub fn link(&mut self, badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let cred = self.cred(badge);
+        self.in_transaction(badge, |fs, tid| fs.link_in(tid, cred, old_path, new_path))
+    }
+
+    /// SET_TIMES of `path`: the times asked for, and the change time now.
+    pub fn set_times(&mut self, badge: Badge, path: &str, req: TimesRequest) -> Result<(), Error> {
+        let cred = self.cred(badge);
+        let ino = self.resolve_path(cred, path)?;
+        let mut inode = self.read_inode(ino)?;
+        let owner = cred.is_root() || cred.uid == inode.uid();
+        if !owner && (req.explicit || check_access(cred, &inode, MAY_WRITE).is_err()) {
+            return Err(Error::PermissionDenied);
+        }
+        self.in_transaction(badge, |fs, tid| {
+            if let Some(atime) = req.atime {
+                inode.set_atime(atime);
+            }
+            if let Some(mtime) = req.mtime {
+                inode.set_mtime(mtime);
+            }
+            touch_changed(&mut inode);
+            fs.vol.write_inode(&fs.reader, tid, ino, &inode)
+        })
+    }
+
+    // Runs `f` in a transaction of its own, aborted when `f` fails
+    fn in_transaction<T>(
+        &mut self,
+        badge: Badge,
+        f: impl FnOnce(&mut Self, usize) -> Result<T, Error>,
+    ) -> Result<T, Error> {
         let tid = self.transaction_start(badge)?;
-        self.transaction_commit(badge, tid)?;
+        match f(self, tid) {
+            Ok(value) => {
+                self.transaction_commit(badge, tid)?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.transaction_abort(badge, tid)?;
+                Err(e)
+            }
+        }
+    }
+
+    // Parent directory inode and final component of `path`
+    fn resolve_parent<'p>(&self, cred: Cred, path: &'p str) -> Result<(u32, &'p str), Error> {
+        let trimmed = path.trim_end_matches('/');
+        let (parent, name) = match trimmed.rfind('/') {
+            Some(idx) => (&trimmed[..idx], &trimmed[idx + 1..]),
+            None => ("", trimmed),
+        };
+        if name.is_empty() || name == "." || name == ".." || name.len() > EXT4_NAME_LEN {
+            return Err(Error::InvalidArgs);
+        }
+        Ok((self.resolve_path(cred, parent)?, name))
+    }
+
+    // As resolve_parent, for adding or removing the final component: `cred`
+    // must be able to write the parent
+    fn resolve_parent_for_change<'p>(
+        &self,
+        cred: Cred,
+        path: &'p str,
+    ) -> Result<(u32, &'p str), Error> {
+        let (parent_ino, name) = self.resolve_parent(cred, path)?;
+        check_access(cred, &self.read_inode(parent_ino)?, MAY_WRITE | MAY_EXEC)?;
+        Ok((parent_ino, name))
+    }
+
+    // Allocates an inode owned by `cred` for a new entry `name` of `parent_ino`,
+    // failing if the name is taken
+    fn new_inode(
+        &mut self,
+        tid: usize,
+        cred: Cred,
+        parent_ino: u32,
+        name: &str,
+        mode: u16,
+    ) -> Result<(u32, Inode), Error> {
+        match self.find_entry(parent_ino, name) {
+            Ok(_) => return Err(Error::AlreadyExists),
+            Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let is_dir = (mode & EXT4_S_IFMT) == EXT4_S_IFDIR;
+        let ino =
+            self.vol.alloc_inode(&self.reader, tid, is_dir, self.vol.inode_group(parent_ino))?;
+        let mut inode = self.read_inode(ino)?;
+        inode.i_mode = mode;
+        inode.set_owner(cred.uid, cred.gid);
+        inode.i_extra_isize = self.vol.extra_isize();
+        if let Some(now) = clock::now() {
+            inode.set_atime(now);
+            inode.set_mtime(now);
+            inode.set_ctime(now);
+            inode.set_crtime(now);
+        }
+        Ok((ino, inode))
+    }
+
+    fn create_in(&mut self, tid: usize, cred: Cred, path: &str, mode: u32) -> Result<u32, Error> {
+        let (parent_ino, name) = self.resolve_parent_for_change(cred, path)?;
+        let mode = EXT4_S_IFREG | (mode & 0o7777) as u16;
+        let (ino, mut inode) = self.new_inode(tid, cred, parent_ino, name, mode)?;
+        inode.i_links_count = 1;
+        self.vol.write_inode(&self.reader, tid, ino, &inode)?;
+        self.add_dir_entry(tid, parent_ino, name, ino, EXT4_FT_REG_FILE)?;
+        Ok(ino)
+    }
+
+    fn mkdir_in(&mut self, tid: usize, cred: Cred, path: &str, mode: u32) -> Result<(), Error> {
+        let (parent_ino, name) = self.resolve_parent_for_change(cred, path)?;
+        let mode = EXT4_S_IFDIR | (mode & 0o7777) as u16;
+        let (ino, mut inode) = self.new_inode(tid, cred, parent_ino, name, mode)?;
+
+        let goal = self.vol.inode_group(ino);
+        let (pblock, allocated) =
+            self.ops.map_block(&self.vol, &self.reader, tid, &mut inode, 0, goal)?;
+        let block_size = self.block_size as usize;
+        let mut block = alloc::vec![0u8; block_size];
+        let dot_len = dir_rec_len(1);
+        put_dir_entry(&mut block, 0, ino, dot_len, ".", EXT4_FT_DIR)?;
+        put_dir_entry(&mut block, dot_len, parent_ino, block_size - dot_len, "..", EXT4_FT_DIR)?;
+        self.vol.log_block(&self.reader, tid, pblock, &block)?;
+
+        // "." and the parent's entry
+        inode.i_links_count = 2;
+        inode.i_blocks_lo = allocated * (self.block_size / 512);
+        inode.set_size(block_size as u64);
+        self.vol.write_inode(&self.reader, tid, ino, &inode)?;
+
+        self.add_dir_entry(tid, parent_ino, name, ino, EXT4_FT_DIR)?;
+        let mut parent = self.read_inode(parent_ino)?;
+        parent.i_links_count += 1;
+        self.vol.write_inode(&self.reader, tid, parent_ino, &parent)
+    }
+
+    fn link_in(
+        &mut self,
+        tid: usize,
+        cred: Cred,
+        old_path: &str,
+        new_path: &str,
+    ) -> Result<(), Error> {
+        let (old_parent, old_name) = self.resolve_parent(cred, old_path)?;
+        let (ino, file_type) = self
+            .dir_entries(old_parent)?
+            .into_iter()
+            .find(|(name, _, _)| name_matches(name.as_bytes(), old_name, self.options.casefold))
+            .map(|(_, ino, file_type)| (ino, file_type))
+            .ok_or(Error::NotFound)?;
+        let mut inode = self.read_inode(ino)?;
+        if (inode.i_mode & EXT4_S_IFMT) == EXT4_S_IFDIR {
+            return Err(Error::PermissionDenied);
+        }
+        if inode.i_links_count >= EXT4_LINK_MAX {
+            return Err(Error::InvalidArgs);
+        }
+        let (new_parent, new_name) = self.resolve_parent_for_change(cred, new_path)?;
+        match self.find_entry(new_parent, new_name) {
+            Ok(_) => return Err(Error::AlreadyExists),
+            Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        self.add_dir_entry(tid, new_parent, new_name, ino, file_type)?;
+        inode.i_links_count += 1;
+        touch_changed(&mut inode);
+        self.vol.write_inode(&self.reader, tid, ino, &inode)
+    }
+
+    fn unlink_in(&mut self, tid: usize, cred: Cred, path: &str) -> Result<(), Error> {
+        let (parent_ino, name) = self.resolve_parent_for_change(cred, path)?;
+        let ino = self.find_entry(parent_ino, name)?;
+        let mut inode = self.read_inode(ino)?;
+        let parent = self.read_inode(parent_ino)?;
+        if !cred.may_remove(parent.i_mode as u32, parent.uid(), inode.uid()) {
+            return Err(Error::PermissionDenied);
+        }
+        let is_dir = (inode.i_mode & EXT4_S_IFMT) == EXT4_S_IFDIR;
+        if is_dir && self.dir_entries(ino)?.iter().any(|(n, _, _)| n != "." && n != "..") {
+            return Err(Error::InvalidArgs);
+        }
+        self.remove_dir_entry(tid, parent_ino, name)?;
+
+        if is_dir {
+            // The child's ".." no longer counts against the parent
+            let mut parent = self.read_inode(parent_ino)?;
+            parent.i_links_count = parent.i_links_count.saturating_sub(1);
+            self.vol.write_inode(&self.reader, tid, parent_ino, &parent)?;
+            inode.i_links_count = 0;
+        } else {
+            inode.i_links_count = inode.i_links_count.saturating_sub(1);
+        }
+        if inode.i_links_count > 0 {
+            touch_changed(&mut inode);
+            return self.vol.write_inode(&self.reader, tid, ino, &inode);
+        }
+        if self.vol.is_open(ino) {
+            // Handles still reach it by inode; release_orphans deletes it once
+            // the last is gone, the next mount if that never happens
+            touch_changed(&mut inode);
+            inode.i_dtime = self.vol.last_orphan();
+            self.vol.write_inode(&self.reader, tid, ino, &inode)?;
+            return self.vol.set_last_orphan(&self.reader, tid, ino);
+        }
+        self.delete_inode(tid, ino, &mut inode)
+    }
+
+    // Frees the blocks and the slot of `ino`, which has no links left
+    fn delete_inode(&self, tid: usize, ino: u32, inode: &mut Inode) -> Result<(), Error> {
+        // Fast symlinks keep their target in i_block and own no blocks
+        if inode.i_blocks_lo != 0 {
+            self.ops.truncate_blocks(&self.vol, &self.reader, tid, inode, 0)?;
+        }
+        inode.i_blocks_lo = 0;
+        inode.set_size(0);
+        // Any nonzero dtime marks the inode deleted; while the clock is unset
+        // the ctime stands in for the time of deletion
+        inode.i_dtime = match clock::now() {
+            Some(now) if now.sec > 0 => now.sec as u32,
+            _ => core::cmp::max(inode.i_ctime, 1),
+        };
+        self.vol.write_inode(&self.reader, tid, ino, inode)?;
+        let is_dir = (inode.i_mode & EXT4_S_IFMT) == EXT4_S_IFDIR;
+        self.vol.free_inode(&self.reader, tid, ino, is_dir)
+    }
+
+    // Adds `name` to directory `dir_ino`, splitting the first record with enough
+    // slack or appending a block to the directory when none has room.
+    fn add_dir_entry(
+        &self,
+        tid: usize,
+        dir_ino: u32,
+        name: &str,
+        ino: u32,
+        file_type: u8,
+    ) -> Result<(), Error> {
+        let block_size = self.block_size as usize;
+        let needed = dir_rec_len(name.len());
+        let mut dir = self.read_inode(dir_ino)?;
+        // The entry lands wherever it fits, out of hash order; the index
+        // stops being trusted and lookups scan the blocks again
+        let unindexed = dir.i_flags & EXT4_INDEX_FL != 0;
+        dir.i_flags &= !EXT4_INDEX_FL;
+        let blocks = (dir.size() as usize).div_ceil(block_size);
+        let mut block = alloc::vec![0u8; block_size];
+
+        for lblock in 0..blocks {
+            let pblock = self.get_block_addr(&dir, lblock as u32)?;
+            if pblock == 0 {
+                continue;
+            }
+            self.vol.read_block(&self.reader, pblock, &mut block)?;
+
+            let mut offset = 0;
+            while offset + <DirEntry2 as FromBytes>::SIZE <= block_size {
+                let mut de = DirEntry2::from_bytes_at(&block, offset)?;
+                let rec_len = de.rec_len as usize;
+                if rec_len == 0 {
+                    break;
+                }
+                let used = if de.inode == 0 { 0 } else { dir_rec_len(de.name_len as usize) };
+                if rec_len >= used + needed {
+                    if used != 0 {
+                        de.rec_len = used as u16;
+                        de.to_bytes_at(&mut block, offset)?;
+                    }
+                    put_dir_entry(&mut block, offset + used, ino, rec_len - used, name, file_type)?;
+                    self.vol.log_block(&self.reader, tid, pblock, &block)?;
+                    if touch_modified(&mut dir) || unindexed {
+                        self.vol.write_inode(&self.reader, tid, dir_ino, &dir)?;
+                    }
+                    return Ok(());
+                }
+                offset += rec_len;
+            }
+        }
+
+        let goal = self.vol.inode_group(dir_ino);
+        let (pblock, allocated) =
+            self.ops.map_block(&self.vol, &self.reader, tid, &mut dir, blocks as u32, goal)?;
+        block.fill(0);
+        put_dir_entry(&mut block, 0, ino, block_size, name, file_type)?;
+        self.vol.log_block(&self.reader, tid, pblock, &block)?;
+        dir.i_blocks_lo += allocated * (self.block_size / 512);
+        dir.set_size(((blocks + 1) * block_size) as u64);
+        touch_modified(&mut dir);
+        self.vol.write_inode(&self.reader, tid, dir_ino, &dir)
+    }
+
+    // Drops `name` from directory `dir_ino` by folding its record into the one
+    // before it, or clearing its inode when it starts the block.
+    fn remove_dir_entry(&self, tid: usize, dir_ino: u32, name: &str) -> Result<(), Error> {
+        let block_size = self.block_size as usize;
+        let mut dir = self.read_inode(dir_ino)?;
+        let mut block = alloc::vec![0u8; block_size];
+
+        for lblock in 0..(dir.size() as usize).div_ceil(block_size) {
+            let pblock = self.get_block_addr(&dir, lblock as u32)?;
+            if pblock == 0 {
+                continue;
+            }
+            self.vol.read_block(&self.reader, pblock, &mut block)?;
+
+            let mut prev: Option<usize> = None;
+            let mut offset = 0;
+            while offset + <DirEntry2 as FromBytes>::SIZE <= block_size {
+                let mut de = DirEntry2::from_bytes_at(&block, offset)?;
+                if de.rec_len == 0 {
+                    break;
+                }
+                let name_start = offset + <DirEntry2 as FromBytes>::SIZE;
+                let stored = block.get(name_start..name_start + de.name_len as usize);
+                if de.inode != 0
+                    && stored.is_some_and(|s| name_matches(s, name, self.options.casefold))
+                {
+                    match prev {
+                        Some(prev_offset) => {
+                            let mut prev_de = DirEntry2::from_bytes_at(&block, prev_offset)?;
+                            prev_de.rec_len += de.rec_len;
+                            prev_de.to_bytes_at(&mut block, prev_offset)?;
+                        }
+                        None => {
+                            de.inode = 0;
+                            de.to_bytes_at(&mut block, offset)?;
+                        }
+                    }
+                    self.vol.log_block(&self.reader, tid, pblock, &block)?;
+                    if touch_modified(&mut dir) {
+                        self.vol.write_inode(&self.reader, tid, dir_ino, &dir)?;
+                    }
+                    return Ok(());
+                }
+                prev = Some(offset);
+                offset += de.rec_len as usize;
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    /// Removes `path` and everything below it, committing every
+    /// `RMTREE_BATCH` removals instead of once per entry.
+    pub fn rmtree(&mut self, badge: Badge, path: &str) -> Result<(), Error> {
+        let mut job = RmtreeJob::new(badge, path)?;
+        loop {
+            match job.step(self) {
+                Ok(Step::Continue) => {}
+                Ok(Step::Done) => return Ok(()),
+                Err(e) => {
+                    job.stop(self);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Writes out metadata held in memory and waits for it to reach the
+    /// medium, leaving the device consistent on its own. Data buffered by
+    /// open handles is flushed through the handles.
+    pub fn sync_all(&self) -> Result<(), Error> {
+        self.vol.flush_inodes(&self.reader)?;
+        self.reader.barrier()
+    }
+
+    /// Called once per request: writes the dirty inodes back once the flush
+    /// interval has passed, or sooner when more of the inode cache than the
+    /// dirty ratio allows is dirty.
+    pub fn writeback_tick(&mut self) -> Result<(), Error> {
+        let due = self.writeback.tick(&self.tunables);
+        let dirty = self.vol.dirty_inodes();
+        let over = dirty * 100 > self.vol.icache_capacity() * self.tunables.dirty_ratio;
+        if dirty == 0 || !(due || over) {
+            return Ok(());
+        }
+        self.sync_all()
+    }
+
+    /// Quick read-only pass over the superblock copies, the group descriptor
+    /// checksums and the root directory, run at mount before serving.
+    pub fn scrub(&self) -> Result<ScrubReport, Error> {
+        let mut report = ScrubReport::new();
+        self.vol.scrub(&self.reader, &mut report)?;
+        match self.read_inode(ROOT_INO) {
+            Ok(root) => {
+                let (mode, links) = (root.i_mode, root.i_links_count);
+                report.check((mode & EXT4_S_IFMT) == EXT4_S_IFDIR && links >= 2, || {
+                    format!("root inode has mode {:#o} and {} links", mode, links)
+                });
+                report.check(self.find_entry(ROOT_INO, "..") == Ok(ROOT_INO), || {
+                    String::from("root directory has no '..' pointing at itself")
+                });
+            }
+            Err(e) => report.check(false, || format!("root inode unreadable: {:?}", e)),
+        }
+        Ok(report)
+    }
+
+    /// Whether the volume needs a check before it is written to.
+    pub fn unclean(&self) -> bool {
+        self.vol.unclean()
+    }
+
+    pub fn mark_in_use(&self) -> Result<(), Error> {
+        self.vol.mark_in_use(&self.reader)
+    }
+
+    pub fn mark_clean(&self) -> Result<(), Error> {
+        self.vol.mark_clean(&self.reader)
+    }
+
+    /// Finishes what a crash left on the orphan list: inodes without links
+    /// are deleted, the others cut back to their size. The list is cleared
+    /// in the same transaction. Returns how many inodes were on it.
+    pub fn process_orphans(&self) -> Result<usize, Error> {
+        if !self.has_orphans() {
+            return Ok(0);
+        }
+        let tid = self.vol.transaction_start();
+        match self.process_orphans_in(tid) {
+            Ok(count) => {
+                self.vol.transaction_commit(&self.reader, tid)?;
+                Ok(count)
+            }
+            Err(e) => {
+                self.vol.transaction_abort(tid)?;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn has_orphans(&self) -> bool {
+        self.vol.last_orphan() != 0
+    }
+
+    /// Deletes the inodes that were unlinked while open and have no handle
+    /// left, taking them off the orphan list. Returns how many went.
+    pub fn release_orphans(&self) -> Result<usize, Error> {
+        if !self.has_orphans() {
+            return Ok(0);
+        }
+        let tid = self.vol.transaction_start();
+        match self.release_orphans_in(tid) {
+            Ok(count) => {
+                self.vol.transaction_commit(&self.reader, tid)?;
+                Ok(count)
+            }
+            Err(e) => {
+                self.vol.transaction_abort(tid)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn release_orphans_in(&self, tid: usize) -> Result<usize, Error> {
+        let inodes = self.vol.inodes_count();
+        // The inode linking to `next`, 0 while that is the head
+        let mut prev = 0;
+        let mut next = self.vol.last_orphan();
+        let (mut seen, mut count) = (0, 0);
+        while next != 0 {
+            if next > inodes || seen >= inodes {
+                return Err(FsError::Corrupt.into());
+            }
+            seen += 1;
+            let ino = next;
+            let mut inode = self.read_inode(ino)?;
+            next = inode.i_dtime;
+            if inode.i_links_count != 0 || self.vol.is_open(ino) {
+                prev = ino;
+                continue;
+            }
+            if prev == 0 {
+                self.vol.set_last_orphan(&self.reader, tid, next)?;
+            } else {
+                let mut before = self.read_inode(prev)?;
+                before.i_dtime = next;
+                self.vol.write_inode(&self.reader, tid, prev, &before)?;
+            }
+            self.delete_inode(tid, ino, &mut inode)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn process_orphans_in(&self, tid: usize) -> Result<usize, Error> {
+        let inodes = self.vol.inodes_count();
+        let block_size = self.block_size as u64;
+        let mut next = self.vol.last_orphan();
+        let mut count = 0;
+        while next != 0 {
+            // Past the inode table, or a chain that loops
+            if next > inodes || count >= inodes as usize {
+                return Err(FsError::Corrupt.into());
+            }
+            let ino = next;
+            let mut inode = self.read_inode(ino)?;
+            next = inode.i_dtime;
+            if inode.i_links_count == 0 {
+                self.delete_inode(tid, ino, &mut inode)?;
+            } else {
+                if inode.i_blocks_lo != 0 {
+                    let first_free = inode.size().div_ceil(block_size) as u32;
+                    let freed = self.ops.truncate_blocks(
+                        &self.vol,
+                        &self.reader,
+                        tid,
+                        &mut inode,
+                        first_free,
+                    )?;
+                    let sectors = freed * (block_size / 512);
+                    inode.i_blocks_lo = (inode.i_blocks_lo as u64).saturating_sub(sectors) as u32;
+                }
+                inode.i_dtime = 0;
+                self.vol.write_inode(&self.reader, tid, ino, &inode)?;
+            }
+            count += 1;
+        }
+        self.vol.set_last_orphan(&self.reader, tid, 0)?;
+        Ok(count)
+    }
+
+    /// fsck-lite: the allocation counts and the orphan list (see
+    /// `ExtVolume::check`), then the directory tree from the root. With
+    /// `repair` the counts are fixed in a transaction; the tree is only
+    /// reported on.
+    pub fn check(&self, repair: bool) -> Result<ScrubReport, Error> {
+        let mut report = ScrubReport::new();
+        let tid = repair.then(|| self.vol.transaction_start());
+        let checked = self.vol.check(&self.reader, tid, &mut report);
+        if let Some(tid) = tid {
+            match checked {
+                Ok(()) => self.vol.transaction_commit(&self.reader, tid)?,
+                Err(_) => self.vol.transaction_abort(tid)?,
+            }
+        }
+        checked?;
+        self.check_tree(&mut report)?;
+        Ok(report)
+    }
+
+    // Every directory reachable from the root has "." and ".." pointing at
+    // itself and its parent, and every entry names an inode that is marked
+    // in use and still has links
+    fn check_tree(&self, report: &mut ScrubReport) -> Result<(), Error> {
+        let inodes = self.vol.inodes_count();
+        let per_group = self.vol.inodes_per_group;
+        let mut bitmaps: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        let mut seen = BTreeSet::from([ROOT_INO]);
+        let mut queue = VecDeque::from([(ROOT_INO, ROOT_INO)]);
+
+        while let Some((dir, parent)) = queue.pop_front() {
+            let entries = match self.dir_entries(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    report.check(false, || format!("directory {} unreadable: {:?}", dir, e));
+                    continue;
+                }
+            };
+            let find = |name: &str| entries.iter().find(|(n, _, _)| n == name).map(|e| e.1);
+            report.check(find(".") == Some(dir), || format!("directory {} has a bad '.'", dir));
+            report.check(find("..") == Some(parent), || {
+                format!("directory {} has a '..' not pointing at {}", dir, parent)
+            });
+
+            for (name, ino, _) in &entries {
+                let ino = *ino;
+                if name == "." || name == ".." {
+                    continue;
+                }
+                if ino > inodes {
+                    report.check(false, || format!("'{}' in {} names inode {}", name, dir, ino));
+                    continue;
+                }
+                let group = (ino - 1) / per_group;
+                let bit = ((ino - 1) % per_group) as usize;
+                let bitmap = match bitmaps.get(&group) {
+                    Some(bitmap) => bitmap,
+                    None => {
+                        let bitmap = self.vol.inode_bitmap(&self.reader, group)?;
+                        bitmaps.entry(group).or_insert(bitmap)
+                    }
+                };
+                report.check(bitmap[bit / 8] & (1 << (bit % 8)) != 0, || {
+                    format!("'{}' in {} names inode {}, which is free", name, dir, ino)
+                });
+                let inode = match self.read_inode(ino) {
+                    Ok(inode) => inode,
+                    Err(e) => {
+                        report.check(false, || format!("inode {} unreadable: {:?}", ino, e));
+                        continue;
+                    }
+                };
+                report.check(inode.i_links_count > 0, || {
+                    format!("'{}' in {} names inode {}, which has no links", name, dir, ino)
+                });
+                if (inode.i_mode & EXT4_S_IFMT) == EXT4_S_IFDIR && seen.insert(ino) {
+                    queue.push_back((ino, dir));
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn stat_path(&mut self, _badge: Badge, path: &str) -> Result<Stat, Error> {
-        let ino = self.resolve_path(path)?;
+    // Every live entry of a directory as (name, inode, file type)
+    fn dir_entries(&self, dir_ino: u32) -> Result<Vec<(String, u32, u8)>, Error> {
+        let inode = self.read_inode(dir_ino)?;
+        let block_size = self.block_size as usize;
+        let mut block_buf = alloc::vec![0u8; block_size];
+        let mut entries = Vec::new();
+
+        for lblock in 0..(inode.size() as usize).div_ceil(block_size) {
+            let pblock = self.get_block_addr(&inode, lblock as u32)?;
+            if pblock == 0 {
+                continue;
+            }
+            self.reader.read_offset((pblock * block_size as u64) as usize, &mut block_buf)?;
+
+            let mut offset = 0;
+            while offset + <DirEntry2 as FromBytes>::SIZE <= block_size {
+                let de = DirEntry2::from_bytes_at(&block_buf, offset)?;
+                if de.rec_len == 0 {
+                    break;
+                }
+                if de.inode != 0 {
+                    let name_start = offset + <DirEntry2 as FromBytes>::SIZE;
+                    let name = block_buf
+                        .get(name_start..name_start + de.name_len as usize)
+                        .ok_or(Error::DeviceError)?;
+                    let name = core::str::from_utf8(name).map_err(|_| Error::DeviceError)?;
+                    entries.push((String::from(name), de.inode, de.file_type));
+                }
+                offset += de.rec_len as usize;
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn stat_path(&mut self, badge: Badge, path: &str) -> Result<Stat, Error> {
+        let ino = self.resolve_path(self.cred(badge), path)?;
         let inode = self.read_inode(ino)?;
-        Ok(Stat {
-            ino: ino as usize,
-            size: inode.i_size_lo as usize,
-            mode: inode.i_mode as u32,
-            ..Default::default()
+        Ok(inode_stat(ino, &inode, inode.size() as usize))
+    }
+}
+
+// Stat of inode `ino`, `size` bytes long counting data not yet written out
+fn inode_stat(ino: u32, inode: &Inode, size: usize) -> Stat {
+    Stat {
+        ino: ino as usize,
+        size,
+        mode: inode.i_mode as u32,
+        atime: inode.atime().stat_secs(),
+        mtime: inode.mtime().stat_secs(),
+        ctime: inode.ctime().stat_secs(),
+        ..Default::default()
+    }
+}
+
+// Stamps new contents (data or entries): mtime and ctime. False, leaving the
+// inode alone, while the clock is unset.
+fn touch_modified(inode: &mut Inode) -> bool {
+    let Some(now) = clock::now() else {
+        return false;
+    };
+    inode.set_mtime(now);
+    inode.set_ctime(now);
+    true
+}
+
+// Stamps a change to the inode itself, such as its link count
+fn touch_changed(inode: &mut Inode) {
+    if let Some(now) = clock::now() {
+        inode.set_ctime(now);
+    }
+}
+
+// Access times follow relatime: only once the file changed since the last
+// access, or a day after it, so reading stays free of metadata writes
+fn atime_due(inode: &Inode, now: Timestamp) -> bool {
+    let atime = inode.atime();
+    atime <= inode.mtime() || atime <= inode.ctime() || now.sec - atime.sec >= 24 * 3600
+}
+
+// PermissionDenied unless `cred` may `want` (creds::MAY_* bits) of `inode`
+fn check_access(cred: Cred, inode: &Inode, want: u32) -> Result<(), Error> {
+    if !cred.permits(inode.i_mode as u32, inode.uid(), inode.gid(), want) {
+        return Err(Error::PermissionDenied);
+    }
+    Ok(())
+}
+
+// Access an OPEN with `flags` needs of an existing file
+fn open_access(flags: OpenFlags) -> u32 {
+    let want = if flags.contains(OpenFlags::O_RDWR) {
+        MAY_READ | MAY_WRITE
+    } else if flags.contains(OpenFlags::O_WRONLY) {
+        MAY_WRITE
+    } else {
+        MAY_READ
+    };
+    if flags.contains(OpenFlags::O_TRUNC) {
+        want | MAY_WRITE
+    } else {
+        want
+    }
+}
+
+// Whether a stored entry name is `name`, ignoring case on casefold mounts
+fn name_matches(stored: &[u8], name: &str, casefold: bool) -> bool {
+    if casefold {
+        casefold::eq_bytes(stored, name)
+    } else {
+        stored == name.as_bytes()
+    }
+}
+
+// Bytes a directory record with a `name_len` byte name takes up
+fn dir_rec_len(name_len: usize) -> usize {
+    (<DirEntry2 as FromBytes>::SIZE + name_len + 3) & !3
+}
+
+fn put_dir_entry(
+    block: &mut [u8],
+    offset: usize,
+    ino: u32,
+    rec_len: usize,
+    name: &str,
+    file_type: u8,
+) -> Result<(), Error> {
+    let de =
+        DirEntry2 { inode: ino, rec_len: rec_len as u16, name_len: name.len() as u8, file_type };
+    de.to_bytes_at(block, offset)?;
+    let name_start = offset + <DirEntry2 as FromBytes>::SIZE;
+    block
+        .get_mut(name_start..name_start + name.len())
+        .ok_or(Error::InternalError)?
+        .copy_from_slice(name.as_bytes());
+    Ok(())
+}
+
+// Copies in history are made on behalf of the service, not of a client
+impl VersionStore for ExtFs {
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Box<dyn FsHandle>, Error> {
+        self.open_handle(Badge::null(), path, flags, 0o644)
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Stat, Error> {
+        self.stat_path(Badge::null(), path)
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), Error> {
+        ExtFs::unlink(self, Badge::null(), path)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+}
+
+/// Removal of a directory tree, one directory per step so it can run as a job.
+pub struct RmtreeJob {
+    badge: Badge,
+    path: String,
+    // Depth-first without recursion; a directory is removed once its children are gone
+    stack: Vec<(String, bool)>,
+    tid: Option<usize>,
+    pending: usize,
+    removed: usize,
+    found: usize,
+}
+
+impl RmtreeJob {
+    pub fn new(badge: Badge, path: &str) -> Result<Self, Error> {
+        if path.split('/').all(|part| part.is_empty()) {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(Self {
+            badge,
+            path: String::from(path),
+            stack: alloc::vec![(String::from(path), false)],
+            tid: None,
+            pending: 0,
+            removed: 0,
+            found: 1,
         })
     }
+
+    // Transaction for the next removal, rolling over every RMTREE_BATCH removals
+    fn tid(&mut self, fs: &mut ExtFs) -> Result<usize, Error> {
+        if self.pending >= RMTREE_BATCH {
+            if let Some(tid) = self.tid.take() {
+                fs.transaction_commit(self.badge, tid)?;
+            }
+            self.pending = 0;
+        }
+        match self.tid {
+            Some(tid) => Ok(tid),
+            None => {
+                let tid = fs.transaction_start(self.badge)?;
+                self.tid = Some(tid);
+                Ok(tid)
+            }
+        }
+    }
+
+    fn remove(&mut self, fs: &mut ExtFs, path: &str) -> Result<(), Error> {
+        let tid = self.tid(fs)?;
+        fs.unlink_in(tid, fs.cred(self.badge), path)?;
+        self.pending += 1;
+        self.removed += 1;
+        Ok(())
+    }
+}
+
+impl Job<ExtFs> for RmtreeJob {
+    fn step(&mut self, fs: &mut ExtFs) -> Result<Step, Error> {
+        let Some((dir, emptied)) = self.stack.pop() else {
+            return Ok(Step::Done);
+        };
+        let ino = fs.resolve_path(fs.cred(self.badge), &dir)?;
+        let inode = fs.read_inode(ino)?;
+        if emptied || (inode.i_mode & EXT4_S_IFMT) != EXT4_S_IFDIR {
+            self.remove(fs, &dir)?;
+        } else {
+            // Emptying a directory takes listing it as well as writing it
+            check_access(fs.cred(self.badge), &inode, MAY_READ | MAY_WRITE | MAY_EXEC)?;
+            let mut subdirs = Vec::new();
+            for (name, _, file_type) in fs.dir_entries(ino)? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let child = fs_common::path::join(&dir, &name);
+                self.found += 1;
+                if file_type == EXT4_FT_DIR {
+                    subdirs.push((child, false));
+                } else {
+                    self.remove(fs, &child)?;
+                }
+            }
+            self.stack.push((dir, true));
+            self.stack.extend(subdirs);
+        }
+
+        if !self.stack.is_empty() {
+            return Ok(Step::Continue);
+        }
+        if let Some(tid) = self.tid.take() {
+            fs.transaction_commit(self.badge, tid)?;
+        }
+        Ok(Step::Done)
+    }
+
+    fn progress(&self) -> (usize, usize) {
+        (self.removed, self.found)
+    }
+
+    // Removals already made are complete on their own, so keep them
+    fn stop(&mut self, fs: &mut ExtFs) {
+        if let Some(tid) = self.tid.take() {
+            let _ = fs.transaction_commit(self.badge, tid);
+        }
+    }
+
+    fn touches(&self) -> Option<&str> {
+        Some(&self.path)
+    }
 }
 
 pub struct ExtFileHandle {
     ops: Arc<dyn ExtOps>,
+    vol: Arc<ExtVolume>,
     reader: BlockReader,
     inode: Inode,
+    ino: u32,
     block_size: u32,
     pos: usize,
     ring_vaddr: usize,
@@ -289,32 +1450,52 @@ pub struct ExtFileHandle {
     uring: Option<glenda::io::uring::IoUringBuffer>,
     user_shm_base: usize,
     server_shm_base: usize,
+    // Small sequential writes not yet on disk; other handles see them once flushed
+    pending: WriteCombiner,
+    // MNT_SYNC: every write goes to the device before returning
+    sync_writes: bool,
+    // Reads update the access time; off with MNT_NOATIME
+    atime: bool,
+    // O_APPEND: every write goes to the end of the file, whatever its offset
+    append: bool,
+    // Access the OPEN was granted (creds::MAY_* bits); the mode bits are
+    // only checked then
+    access: u32,
+    readahead: Readahead,
+}
+
+impl Drop for ExtFileHandle {
+    fn drop(&mut self) {
+        self.vol.release_inode(self.ino);
+    }
 }
 
 impl FileHandleService for ExtFileHandle {
     fn close(&mut self, _badge: Badge) -> Result<(), Error> {
-        Ok(())
+        self.flush_pending()
     }
 
     fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
-        Ok(Stat {
-            size: self.inode.i_size_lo as usize,
-            mode: self.inode.i_mode as u32,
-            ..Default::default()
-        })
+        // The handle's copy misses access times kept in the inode cache
+        let inode = self.vol.read_inode(&self.reader, self.ino)?;
+        Ok(inode_stat(self.ino, &inode, self.size()))
     }
 
     fn read(&mut self, _badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        self.flush_pending()?;
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
         let _start_block_idx = (offset / self.block_size as usize) as u32;
         // let end_block_idx = ((offset + buf.len() as usize + self.block_size as usize - 1)
         //     / self.block_size as usize) as u32;
 
+        let len = limits::read_len(offset, buf.len(), self.inode.size() as usize);
         let mut read_len = 0;
         let mut current_offset = offset;
         let mut buf_ptr = 0;
 
         // Simple loop
-        while buf_ptr < buf.len() {
+        while buf_ptr < len {
             let lblock = (current_offset / self.block_size as usize) as u32;
             let pblock = self
                 .ops
@@ -323,11 +1504,11 @@ impl FileHandleService for ExtFileHandle {
 
             let blk_offset_in_buf = (current_offset % self.block_size as usize) as usize;
             let chuck_len =
-                core::cmp::min(buf.len() - buf_ptr, self.block_size as usize - blk_offset_in_buf);
+                core::cmp::min(len - buf_ptr, self.block_size as usize - blk_offset_in_buf);
 
             let mut block_data = alloc::vec![0u8; self.block_size as usize];
             if pblock != 0 {
-                let read_offset = pblock as usize * self.block_size as usize;
+                let read_offset = (pblock * self.block_size as u64) as usize;
                 self.reader.read_offset(read_offset, &mut block_data)?;
             } else {
                 // Sparse block, zeroed
@@ -340,76 +1521,248 @@ impl FileHandleService for ExtFileHandle {
             current_offset += chuck_len as usize;
             buf_ptr += chuck_len;
 
-            if current_offset >= self.inode.i_size_lo as usize {
+            if current_offset >= self.inode.size() as usize {
                 break;
             }
         }
+        if advance {
+            self.pos = current_offset;
+        }
+        self.read_ahead(offset, read_len);
+        self.touch_atime()?;
         Ok(read_len)
     }
 
     fn write(&mut self, _badge: Badge, offset: usize, buf: &[u8]) -> Result<usize, Error> {
-        // Simplified write - assumes no allocation needed for existing blocks or implementing minimal allocation is hard here without FS ref.
-        // But writes usually go through FS service for allocation?
-        // Wait, `FileHandle::write` is called on the handle. The handle needs access to allocator if extending.
-        // `ExtFileHandle` only has `read-only` ops access (get_block_addr).
-        // `ExtOps` is just for traversing maps.
-        // Real write support needs `allocator` etc.
-        // The user said: "write logic can be moved from ExtFs::write_file to here."
-        // `ExtFs::write_file` did: get_block_addr (failed if not present?), read, modify, write.
-        // It used `self.log_block`. `ExtFs` had `FileSystemJournalService`. `ExtFileHandle` does NOT have `FileSystemJournalService`.
-        // So `write` might be difficult without `ExtFs` ref.
-        // However, `log_block` calls `reader.write_blocks`.
-        // `ExtFileHandle` has `reader` so it can write blocks.
-        // But `log_block` was part of `transaction`.
-        // If I skip transaction overhead for now (as `write_file` seemed to use it just for locking/logging?), I can just write.
+        self.may_write()?;
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if self.append {
+            // The end as other handles left it, not as this one last saw it
+            self.inode = self.vol.read_inode(&self.reader, self.ino)?;
+            self.size()
+        } else if advance {
+            self.pos
+        } else {
+            offset
+        };
+        limits::checked_end(offset, buf.len(), self.ops.max_file_size(self.block_size))?;
 
-        let mut written = 0;
-        let mut current_offset = offset;
-        let mut buf_ptr = 0;
+        let written = if !self.sync_writes && self.pending.absorb(offset, buf) {
+            buf.len()
+        } else {
+            self.flush_pending()?;
+            if !self.sync_writes && self.pending.absorb(offset, buf) {
+                buf.len()
+            } else {
+                self.write_through(offset, buf)?
+            }
+        };
+        if self.pending.is_full() {
+            if let Some((at, data)) = self.pending.take_blocks() {
+                self.write_through(at, &data)?;
+            }
+        }
+        if advance {
+            self.pos = offset + written;
+        }
+        Ok(written)
+    }
 
-        while buf_ptr < buf.len() {
-            let lblock = (current_offset / self.block_size as usize) as u32;
-            // This fails if block not allocated
-            let pblock = self
-                .ops
-                .get_block_addr(&self.reader, &self.inode, lblock, self.block_size)
-                .map_err(|_| Error::IoError)?;
+    fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
+        Err(Error::NotImplemented)
+    }
 
-            if pblock == 0 {
-                return Err(Error::InternalError); // Cannot allocate in this simple handle
-            }
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        if whence == SEEK_DATA || whence == SEEK_HOLE {
+            // Buffered writes may fill holes the block map still shows
+            self.flush_pending()?;
+            self.pos = self.seek_sparse(offset, whence == SEEK_DATA)?;
+            return Ok(self.pos);
+        }
+        let size = self.size();
+        let max_size = self.ops.max_file_size(self.block_size);
+        self.pos = limits::seek_target(self.pos, size, offset, whence, max_size)?;
+        Ok(self.pos)
+    }
 
-            let blk_offset_in_buf = (current_offset % self.block_size as usize) as usize;
-            let chuck_len =
-                core::cmp::min(buf.len() - buf_ptr, self.block_size as usize - blk_offset_in_buf);
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.flush_pending()?;
+        self.reader.barrier()
+    }
 
-            // Read
-            let mut block_data = alloc::vec![0u8; self.block_size as usize];
-            let read_offset = pblock as usize * self.block_size as usize;
-            self.reader.read_offset(read_offset, &mut block_data)?;
+    fn truncate(&mut self, _badge: Badge, size: usize) -> Result<(), Error> {
+        self.may_write()?;
+        limits::checked_end(size, 0, self.ops.max_file_size(self.block_size))?;
+        self.flush_pending()?;
+        let tid = self.vol.transaction_start();
+        match self.truncate_in(tid, size as u64) {
+            Ok(()) => self.vol.transaction_commit(&self.reader, tid),
+            Err(e) => {
+                self.vol.transaction_abort(tid)?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl FsHandle for ExtFileHandle {
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        self.vol.hold_inode(self.ino);
+        Ok(Box::new(ExtFileHandle {
+            ops: self.ops.clone(),
+            vol: self.vol.clone(),
+            reader: self.reader.clone(),
+            inode: self.inode,
+            ino: self.ino,
+            block_size: self.block_size,
+            pos: 0,
+            ring_vaddr: self.ring_vaddr,
+            ring_size: self.ring_size,
+            uring: None,
+            user_shm_base: 0,
+            server_shm_base: 0,
+            pending: WriteCombiner::new(self.block_size as usize),
+            sync_writes: self.sync_writes,
+            atime: self.atime,
+            append: self.append,
+            access: self.access,
+            readahead: Readahead::new(),
+        }))
+    }
 
-            // Modify
-            block_data[blk_offset_in_buf..blk_offset_in_buf + chuck_len]
-                .copy_from_slice(&buf[buf_ptr..buf_ptr + chuck_len]);
+    fn getxattr(&self, name: &str) -> Result<Vec<u8>, Error> {
+        xattr::get(&self.vol, &self.reader, self.ino, name)
+    }
 
-            // Write
-            self.reader
-                .write_blocks(pblock as usize * (self.block_size / 512) as usize, &block_data)?;
+    fn listxattr(&self) -> Result<Vec<String>, Error> {
+        xattr::list(&self.vol, &self.reader, self.ino)
+    }
 
-            written += chuck_len;
-            current_offset += chuck_len as usize;
-            buf_ptr += chuck_len;
+    fn fallocate(&mut self, offset: usize, len: usize, flags: usize) -> Result<(), Error> {
+        if flags & !FALLOC_KEEP_SIZE != 0 || len == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        self.may_write()?;
+        let end = limits::checked_end(offset, len, self.ops.max_file_size(self.block_size))?;
+        self.flush_pending()?;
+        let tid = self.vol.transaction_start();
+        let keep_size = flags & FALLOC_KEEP_SIZE != 0;
+        match self.fallocate_in(tid, offset, end, keep_size) {
+            Ok(()) => self.vol.transaction_commit(&self.reader, tid),
+            Err(e) => {
+                self.vol.transaction_abort(tid)?;
+                Err(e)
+            }
         }
+    }
+}
 
-        Ok(written)
+pub struct ExtDirHandle {
+    ops: Arc<dyn ExtOps>,
+    vol: Arc<ExtVolume>,
+    reader: BlockReader,
+    inode: Inode,
+    ino: u32,
+    block_size: u32,
+    // Byte offset of the next directory entry to return
+    pos: usize,
+}
+
+impl FsHandle for ExtDirHandle {
+    fn getxattr(&self, name: &str) -> Result<Vec<u8>, Error> {
+        xattr::get(&self.vol, &self.reader, self.ino, name)
     }
 
-    fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
-        Err(Error::NotImplemented)
+    fn listxattr(&self) -> Result<Vec<String>, Error> {
+        xattr::list(&self.vol, &self.reader, self.ino)
     }
+}
 
-    fn seek(&mut self, _badge: Badge, _offset: i64, _whence: usize) -> Result<usize, Error> {
-        Err(Error::NotImplemented)
+impl Drop for ExtDirHandle {
+    fn drop(&mut self) {
+        self.vol.release_inode(self.ino);
+    }
+}
+
+impl FileHandleService for ExtDirHandle {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        let inode = self.vol.read_inode(&self.reader, self.ino)?;
+        Ok(inode_stat(self.ino, &inode, inode.size() as usize))
+    }
+
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::InvalidArgs)
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::InvalidArgs)
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        let block_size = self.block_size as usize;
+        let size = self.inode.size() as usize;
+        let mut entries = Vec::new();
+        let mut block_buf = alloc::vec![0u8; block_size];
+        let mut loaded_block = None;
+
+        while entries.len() < count && self.pos < size {
+            let lblock = (self.pos / block_size) as u32;
+            let next_block_pos = (lblock as usize + 1) * block_size;
+            if loaded_block != Some(lblock) {
+                let pblock =
+                    self.ops.get_block_addr(&self.reader, &self.inode, lblock, self.block_size)?;
+                if pblock == 0 {
+                    // Directory holes carry no entries
+                    self.pos = next_block_pos;
+                    continue;
+                }
+                self.reader.read_offset((pblock * block_size as u64) as usize, &mut block_buf)?;
+                loaded_block = Some(lblock);
+            }
+
+            let block_offset = self.pos % block_size;
+            let de = match DirEntry2::from_bytes_at(&block_buf, block_offset) {
+                Ok(de) => de,
+                Err(_) => {
+                    self.pos = next_block_pos;
+                    continue;
+                }
+            };
+            if de.rec_len == 0 {
+                // Corrupt record, skip the rest of this block
+                self.pos = next_block_pos;
+                continue;
+            }
+            self.pos += de.rec_len as usize;
+
+            if de.inode != 0 {
+                let name_start = block_offset + <DirEntry2 as FromBytes>::SIZE;
+                let name_len = core::cmp::min(de.name_len as usize, block_size - name_start);
+                let type_ = match de.file_type {
+                    EXT4_FT_REG_FILE => DT_REG,
+                    EXT4_FT_DIR => DT_DIR,
+                    _ => DT_UNKNOWN,
+                };
+                entries.push(dentry(
+                    de.inode as usize,
+                    self.pos,
+                    type_,
+                    &block_buf[name_start..name_start + name_len],
+                ));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        // Positions are byte offsets of directory records, as returned in `off`
+        let size = self.inode.size() as usize;
+        self.pos = limits::seek_target(self.pos, size, offset, whence, size as u64)?;
+        Ok(self.pos)
     }
 
     fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
@@ -417,16 +1770,235 @@ impl FileHandleService for ExtFileHandle {
     }
 
     fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
-        Err(Error::NotImplemented)
+        Err(Error::InvalidArgs)
     }
 }
 
 impl ExtFileHandle {
+    // Size including writes still held in `pending`
+    fn size(&self) -> usize {
+        let size = self.inode.size() as usize;
+        self.pending.end().map_or(size, |end| core::cmp::max(end, size))
+    }
+
+    // Prefetches the blocks a sequential reader is about to want after
+    // `len` bytes at `offset`, a run of adjacent blocks at a time
+    fn read_ahead(&mut self, offset: usize, len: usize) {
+        let block_size = self.block_size as usize;
+        let max_blocks = self.reader.tuning().readahead_blocks;
+        let Some((start, len)) =
+            self.readahead.advise(offset, len, self.size(), block_size, max_blocks)
+        else {
+            return;
+        };
+        let mut run: Option<(u64, usize)> = None;
+        for lblock in start / block_size..(start + len) / block_size {
+            let pblock = self
+                .ops
+                .get_block_addr(&self.reader, &self.inode, lblock as u32, self.block_size)
+                .unwrap_or(0);
+            match run {
+                Some((first, count)) if pblock != 0 && pblock == first + count as u64 => {
+                    run = Some((first, count + 1));
+                    continue;
+                }
+                Some(done) => self.prefetch_blocks(done),
+                None => {}
+            }
+            // Holes have nothing to read
+            run = (pblock != 0).then_some((pblock, 1));
+        }
+        if let Some(done) = run {
+            self.prefetch_blocks(done);
+        }
+    }
+
+    fn prefetch_blocks(&self, (first, count): (u64, usize)) {
+        let block_size = self.block_size as usize;
+        let _ = self.reader.prefetch(first as usize * block_size, count * block_size, block_size);
+    }
+
+    // Access times are kept in the inode cache until the next flush, as a
+    // transaction per read would cost more than the read
+    fn touch_atime(&self) -> Result<(), Error> {
+        let now = match clock::now() {
+            Some(now) if self.atime => now,
+            _ => return Ok(()),
+        };
+        let mut inode = self.vol.read_inode(&self.reader, self.ino)?;
+        if atime_due(&inode, now) {
+            inode.set_atime(now);
+            self.vol.write_inode_lazy(self.ino, &inode);
+        }
+        Ok(())
+    }
+
+    // PermissionDenied for a handle not opened for writing
+    fn may_write(&self) -> Result<(), Error> {
+        if self.access & MAY_WRITE == 0 {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    /// Writes out everything buffered by small writes. On failure the
+    /// buffered data is dropped and the error reported here instead.
+    fn flush_pending(&mut self) -> Result<(), Error> {
+        match self.pending.take() {
+            Some((offset, data)) => self.write_through(offset, &data).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_through(&mut self, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        let tid = self.vol.transaction_start();
+        match self.write_in(tid, offset, buf) {
+            Ok(written) => {
+                self.vol.transaction_commit(&self.reader, tid)?;
+                Ok(written)
+            }
+            Err(e) => {
+                self.vol.transaction_abort(tid)?;
+                Err(e)
+            }
+        }
+    }
+
+    // Writes `buf` at `offset`, allocating blocks for holes and past the end
+    fn write_in(&mut self, tid: usize, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        let block_size = self.block_size as usize;
+        let goal = self.vol.inode_group(self.ino);
+        let mut inode = self.vol.read_inode(&self.reader, self.ino)?;
+        let mut allocated = 0;
+        let mut block = alloc::vec![0u8; block_size];
+        let mut written = 0;
+
+        while written < buf.len() {
+            let current_offset = offset + written;
+            let lblock = (current_offset / block_size) as u32;
+            let in_block = current_offset % block_size;
+            let chunk_len = core::cmp::min(buf.len() - written, block_size - in_block);
+
+            let mut pblock =
+                self.ops.get_block_addr(&self.reader, &inode, lblock, self.block_size)?;
+            if pblock == 0 {
+                let (new_block, count) =
+                    self.ops.map_block(&self.vol, &self.reader, tid, &mut inode, lblock, goal)?;
+                pblock = new_block;
+                allocated += count;
+                block.fill(0);
+            } else if chunk_len < block_size {
+                self.vol.read_block(&self.reader, pblock, &mut block)?;
+            }
+
+            block[in_block..in_block + chunk_len]
+                .copy_from_slice(&buf[written..written + chunk_len]);
+            self.vol.log_block(&self.reader, tid, pblock, &block)?;
+            written += chunk_len;
+        }
+
+        let end = (offset + written) as u64;
+        let stamped = touch_modified(&mut inode);
+        if allocated > 0 || end > inode.size() || stamped {
+            inode.i_blocks_lo += allocated * (self.block_size / 512);
+            inode.set_size(core::cmp::max(end, inode.size()));
+            self.vol.write_inode(&self.reader, tid, self.ino, &inode)?;
+        }
+        self.inode = inode;
+        Ok(written)
+    }
+
+    fn truncate_in(&mut self, tid: usize, size: u64) -> Result<(), Error> {
+        let block_size = self.block_size as u64;
+        let mut inode = self.vol.read_inode(&self.reader, self.ino)?;
+        let old_size = inode.size();
+
+        // Bytes past the old or new end inside the boundary block must read as zero
+        let edge = core::cmp::min(old_size, size);
+        if edge % block_size != 0 {
+            let lblock = (edge / block_size) as u32;
+            let pblock = self.ops.get_block_addr(&self.reader, &inode, lblock, self.block_size)?;
+            if pblock != 0 {
+                let mut block = alloc::vec![0u8; block_size as usize];
+                self.vol.read_block(&self.reader, pblock, &mut block)?;
+                block[(edge % block_size) as usize..].fill(0);
+                self.vol.log_block(&self.reader, tid, pblock, &block)?;
+            }
+        }
+
+        if size < old_size {
+            let first_free = size.div_ceil(block_size) as u32;
+            let freed =
+                self.ops.truncate_blocks(&self.vol, &self.reader, tid, &mut inode, first_free)?;
+            let sectors = freed * (block_size / 512);
+            inode.i_blocks_lo = (inode.i_blocks_lo as u64).saturating_sub(sectors) as u32;
+        }
+
+        inode.set_size(size);
+        touch_modified(&mut inode);
+        self.vol.write_inode(&self.reader, tid, self.ino, &inode)?;
+        self.inode = inode;
+        Ok(())
+    }
+
+    // SEEK_DATA (`data`) or SEEK_HOLE from `offset`, walking the mapping a
+    // run of alike blocks at a time; the end of the file counts as a hole
+    fn seek_sparse(&self, offset: i64, data: bool) -> Result<usize, Error> {
+        let offset = u64::try_from(offset).map_err(|_| Error::InvalidArgs)?;
+        let size = self.inode.size();
+        if offset >= size {
+            return Err(Error::NotFound);
+        }
+        let block_size = self.block_size as u64;
+        let end_block = size.div_ceil(block_size);
+        let mut lblock = offset / block_size;
+        while lblock < end_block {
+            let (mapped, run) =
+                self.ops.block_run(&self.reader, &self.inode, lblock as u32, self.block_size)?;
+            if mapped == data {
+                return Ok(core::cmp::max(lblock * block_size, offset) as usize);
+            }
+            lblock = lblock.saturating_add(run.max(1));
+        }
+        if data {
+            Err(Error::NotFound)
+        } else {
+            Ok(size as usize)
+        }
+    }
+
+    fn fallocate_in(
+        &mut self,
+        tid: usize,
+        offset: usize,
+        end: usize,
+        keep_size: bool,
+    ) -> Result<(), Error> {
+        let block_size = self.block_size as usize;
+        let first = (offset / block_size) as u32;
+        let count = (end.div_ceil(block_size) - offset / block_size) as u32;
+        let goal = self.vol.inode_group(self.ino);
+        let mut inode = self.vol.read_inode(&self.reader, self.ino)?;
+        let allocated =
+            self.ops.preallocate(&self.vol, &self.reader, tid, &mut inode, first, count, goal)?;
+
+        inode.i_blocks_lo += allocated * (self.block_size / 512);
+        if !keep_size && end as u64 > inode.size() {
+            inode.set_size(end as u64);
+            touch_modified(&mut inode);
+        } else {
+            touch_changed(&mut inode);
+        }
+        self.vol.write_inode(&self.reader, tid, self.ino, &inode)?;
+        self.inode = inode;
+        Ok(())
+    }
+
     fn read_shm_internal(&self, offset: usize, len: u32, shm_vaddr: usize) -> Result<usize, Error> {
         let mut read_len = 0;
         let mut current_offset = offset;
         let mut current_shm_vaddr = shm_vaddr;
-        let mut remaining = len as usize;
+        let mut remaining = limits::read_len(offset, len as usize, self.inode.size() as usize);
 
         while remaining > 0 {
             let lblock = (current_offset / self.block_size as usize) as u32;
@@ -441,7 +2013,7 @@ impl ExtFileHandle {
 
             if pblock != 0 {
                 let read_offset =
-                    pblock as usize * self.block_size as usize + blk_offset_in_block as usize;
+                    (pblock * self.block_size as u64) as usize + blk_offset_in_block as usize;
                 self.reader.read_shm(read_offset, chunk_len as u32, current_shm_vaddr)?;
             } else {
                 unsafe { core::ptr::write_bytes(current_shm_vaddr as *mut u8, 0, chunk_len) };
@@ -452,10 +2024,11 @@ impl ExtFileHandle {
             current_shm_vaddr += chunk_len;
             remaining -= chunk_len;
 
-            if current_offset >= self.inode.i_size_lo as usize {
+            if current_offset >= self.inode.size() as usize {
                 break;
             }
         }
+        self.touch_atime()?;
         Ok(read_len)
     }
 }
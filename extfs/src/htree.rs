@@ -0,0 +1,285 @@
+//! Hashed directory index (dir_index). A directory flagged EXT4_INDEX_FL
+//! keeps a tree of name hashes in its first block, behind the "." and ".."
+//! entries, with any interior nodes in blocks that look empty to a linear
+//! scan. The leaves are ordinary directory blocks holding the names of one
+//! hash range each, so a lookup reads a block per tree level and one leaf
+//! instead of the whole directory.
+//!
+//! Only lookups go through the tree. Adding a name clears the flag, as
+//! drivers without dir_index do, and the directory is scanned linearly
+//! until a full fsck rebuilds the index.
+
+use crate::defs::ext4::*;
+use crate::ops::ExtOps;
+use crate::volume::ExtVolume;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u16, le_u32};
+use glenda::error::Error;
+
+// dx_root_info follows the 12-byte "." and ".." entries of the root block
+const ROOT_INFO_OFFSET: usize = 24;
+// Interior nodes start with an empty 8-byte entry spanning the block
+const NODE_ENTRIES_OFFSET: usize = 8;
+// Index levels below the root; two only with LARGEDIR
+const MAX_INDIRECT_LEVELS: u8 = 2;
+// Index entries address logical blocks in their low 28 bits
+const DX_BLOCK_MASK: u32 = 0x0fff_ffff;
+
+const HASH_LEGACY: u8 = 0;
+const HASH_HALF_MD4: u8 = 1;
+const HASH_TEA: u8 = 2;
+const HASH_LEGACY_UNSIGNED: u8 = 3;
+const HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const HASH_TEA_UNSIGNED: u8 = 5;
+// Hashes stay below this; the low bit of an index hash marks a collision
+// continued from the previous leaf
+const HTREE_EOF: u32 = 0x7fff_ffff;
+// Used when s_hash_seed is all zero
+const DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+// Word order, shifts and constant of the three half-MD4 rounds
+const MD4_ROUNDS: [([usize; 8], [u32; 4], u32); 3] = [
+    ([0, 1, 2, 3, 4, 5, 6, 7], [3, 7, 11, 19], 0),
+    ([1, 3, 5, 7, 0, 2, 4, 6], [3, 5, 9, 13], 0x5a82_7999),
+    ([3, 7, 2, 6, 1, 5, 0, 4], [3, 9, 11, 15], 0x6ed9_eba1),
+];
+
+/// The volume's name hash parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct DxHash {
+    seed: [u32; 4],
+    // Set by mkfs on platforms where char is unsigned
+    unsigned: bool,
+}
+
+impl DxHash {
+    /// None when the volume does not have dir_index.
+    pub fn new(sb: &SuperBlock) -> Option<Self> {
+        if sb.s_feature_compat & EXT4_FEATURE_COMPAT_DIR_INDEX == 0 {
+            return None;
+        }
+        let seed = sb.s_hash_seed;
+        let seed = if seed == [0; 4] { DEFAULT_SEED } else { seed };
+        Some(Self { seed, unsigned: sb.s_flags & EXT2_FLAGS_UNSIGNED_HASH != 0 })
+    }
+
+    /// Hash of `name` under the version a tree root records, or None for a
+    /// version this driver does not implement.
+    pub fn hash(&self, version: u8, name: &[u8]) -> Option<u32> {
+        let version = if self.unsigned && version <= HASH_TEA { version + 3 } else { version };
+        let mut buf = self.seed;
+        let hash = match version {
+            HASH_LEGACY | HASH_LEGACY_UNSIGNED => legacy_hash(name, version == HASH_LEGACY),
+            HASH_HALF_MD4 | HASH_HALF_MD4_UNSIGNED => {
+                for start in (0..name.len()).step_by(32) {
+                    let input = str_to_words(&name[start..], 8, version == HASH_HALF_MD4);
+                    half_md4_transform(&mut buf, &input);
+                }
+                buf[1]
+            }
+            HASH_TEA | HASH_TEA_UNSIGNED => {
+                for start in (0..name.len()).step_by(16) {
+                    let input = str_to_words(&name[start..], 4, version == HASH_TEA);
+                    tea_transform(&mut buf, &input);
+                }
+                buf[0]
+            }
+            _ => return None,
+        };
+        let hash = hash & !1;
+        Some(if hash == HTREE_EOF << 1 { (HTREE_EOF - 1) << 1 } else { hash })
+    }
+}
+
+/// Looks `name` up in indexed directory `dir`: descends from the root to the
+/// leaf covering the name's hash and hands it to `scan`, going on to the next
+/// leaves while they continue the same hash. An index this driver cannot
+/// follow fails with NotSupported, a damaged one with DeviceError; either way
+/// the caller still has the linear scan.
+pub fn lookup(
+    vol: &ExtVolume,
+    reader: &BlockReader,
+    ops: &dyn ExtOps,
+    hash: &DxHash,
+    dir: &Inode,
+    name: &str,
+    scan: impl Fn(&[u8]) -> Result<Option<u32>, Error>,
+) -> Result<Option<u32>, Error> {
+    let read = |lblock: u32| -> Result<Vec<u8>, Error> {
+        let pblock = ops.get_block_addr(reader, dir, lblock, vol.block_size)?;
+        if pblock == 0 {
+            return Err(Error::DeviceError);
+        }
+        let mut buf = alloc::vec![0u8; vol.block_size as usize];
+        vol.read_block(reader, pblock, &mut buf)?;
+        Ok(buf)
+    };
+
+    let root = read(0)?;
+    let info = root.get(ROOT_INFO_OFFSET..ROOT_INFO_OFFSET + 8).ok_or(Error::DeviceError)?;
+    let (version, info_len, levels) = (info[4], info[5] as usize, info[6]);
+    if le_u32(info, 0)? != 0 || info_len < 8 || levels > MAX_INDIRECT_LEVELS {
+        return Err(Error::NotSupported);
+    }
+    let target = hash.hash(version, name.as_bytes()).ok_or(Error::NotSupported)?;
+
+    // Entries of each index level on the way down, and the one followed
+    let mut path = Vec::new();
+    let mut entries = node_entries(&root, ROOT_INFO_OFFSET + info_len)?;
+    loop {
+        // The first entry stands for hash 0, so there is always one to take
+        let at = entries.partition_point(|&(h, _)| h <= target) - 1;
+        let child = entries[at].1;
+        path.push((entries, at));
+        if path.len() > levels as usize {
+            break;
+        }
+        entries = node_entries(&read(child)?, NODE_ENTRIES_OFFSET)?;
+    }
+
+    loop {
+        let (entries, at) = &path[path.len() - 1];
+        if let Some(ino) = scan(&read(entries[*at].1)?)? {
+            return Ok(Some(ino));
+        }
+        if !next_leaf(&mut path, target, &read)? {
+            return Ok(None);
+        }
+    }
+}
+
+type Level = (Vec<(u32, u32)>, usize);
+
+// Steps `path` on to the following leaf if it continues `hash`
+fn next_leaf(
+    path: &mut Vec<Level>,
+    hash: u32,
+    read: &impl Fn(u32) -> Result<Vec<u8>, Error>,
+) -> Result<bool, Error> {
+    let depth = path.len();
+    // The lowest level with an entry to the right of the one followed
+    let mut level = depth;
+    loop {
+        if level == 0 {
+            return Ok(false);
+        }
+        level -= 1;
+        let (entries, at) = &mut path[level];
+        if *at + 1 < entries.len() {
+            *at += 1;
+            if entries[*at].0 & !1 != hash {
+                return Ok(false);
+            }
+            break;
+        }
+    }
+    path.truncate(level + 1);
+    while path.len() < depth {
+        let (entries, at) = &path[path.len() - 1];
+        let child = node_entries(&read(entries[*at].1)?, NODE_ENTRIES_OFFSET)?;
+        path.push((child, 0));
+    }
+    Ok(true)
+}
+
+// (hash, logical block) of each entry of the index node at `offset`
+fn node_entries(block: &[u8], offset: usize) -> Result<Vec<(u32, u32)>, Error> {
+    let limit = le_u16(block, offset)? as usize;
+    let count = le_u16(block, offset + 2)? as usize;
+    if count == 0 || count > limit || offset + count * 8 > block.len() {
+        return Err(Error::DeviceError);
+    }
+    let mut entries = Vec::with_capacity(count);
+    entries.push((0, le_u32(block, offset + 4)? & DX_BLOCK_MASK));
+    for i in 1..count {
+        let at = offset + i * 8;
+        entries.push((le_u32(block, at)?, le_u32(block, at + 4)? & DX_BLOCK_MASK));
+    }
+    Ok(entries)
+}
+
+// A name byte as the C code mkfs ran saw it, with char signed or not
+fn char_value(b: u8, signed: bool) -> u32 {
+    if signed {
+        b as i8 as i32 as u32
+    } else {
+        b as u32
+    }
+}
+
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12a3_fe2du32, 0x37ab_e8f9u32);
+    for &b in name {
+        let mixed = char_value(b, signed).wrapping_mul(7_152_373);
+        let mut hash = hash1.wrapping_add(hash0 ^ mixed);
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+// Packs up to `words` * 4 bytes of `msg` big end first, padding with a
+// pattern made of the length of all of `msg`
+fn str_to_words(msg: &[u8], words: usize, signed: bool) -> [u32; 8] {
+    let len = msg.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+    let mut out = [pad; 8];
+    let take = msg.len().min(words * 4);
+    let mut val = pad;
+    for (i, &b) in msg[..take].iter().enumerate() {
+        val = char_value(b, signed).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            out[i / 4] = val;
+            val = pad;
+        }
+    }
+    if take % 4 != 0 {
+        out[take / 4] = val;
+    }
+    out
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d, ..] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9e37_79b9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    let mut s = *buf;
+    for (round, (order, shifts, k)) in MD4_ROUNDS.iter().enumerate() {
+        for (i, &word) in order.iter().enumerate() {
+            // Steps update a, d, c, b in turn, mixing in the other three
+            let t = (4 - i % 4) % 4;
+            let (x, y, z) = (s[(t + 1) % 4], s[(t + 2) % 4], s[(t + 3) % 4]);
+            let f = match round {
+                0 => z ^ (x & (y ^ z)),
+                1 => (x & y).wrapping_add((x ^ y) & z),
+                _ => x ^ y ^ z,
+            };
+            s[t] = s[t]
+                .wrapping_add(f)
+                .wrapping_add(input[word].wrapping_add(*k))
+                .rotate_left(shifts[i % 4]);
+        }
+    }
+    for (b, v) in buf.iter_mut().zip(s) {
+        *b = b.wrapping_add(v);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::defs::ext4::Inode;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+pub const INODE_CACHE_SIZE: usize = 64;
+
+struct CachedInode {
+    inode: Inode,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Bounded inode cache keyed by inode number.
+///
+/// Dirty inodes are never evicted; they stay pinned until `take_dirty` hands
+/// them to the writer. Entries remember `i_generation` so a reused inode
+/// number can be told apart from the file a handle originally opened.
+pub struct InodeCache {
+    entries: BTreeMap<u32, CachedInode>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl InodeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: BTreeMap::new(), capacity, clock: 0 }
+    }
+
+    pub fn get(&mut self, ino: u32) -> Option<Inode> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(&ino).map(|entry| {
+            entry.last_used = clock;
+            entry.inode
+        })
+    }
+
+    pub fn insert(&mut self, ino: u32, inode: Inode) {
+        self.store(ino, inode, false);
+    }
+
+    pub fn mark_dirty(&mut self, ino: u32, inode: Inode) {
+        self.store(ino, inode, true);
+    }
+
+    /// Records that `inode` reached the disk. It was made from the cached
+    /// copy, so it replaces that even while modifications are pending.
+    pub fn written(&mut self, ino: u32, inode: Inode) {
+        self.entries.remove(&ino);
+        self.store(ino, inode, false);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.entries.values().filter(|entry| entry.dirty).count()
+    }
+
+    /// Changes the number of cached inodes, evicting clean ones that no longer
+    /// fit. Dirty inodes stay even if that leaves the cache over capacity.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = core::cmp::max(capacity, 1);
+        while self.entries.len() > self.capacity {
+            let before = self.entries.len();
+            self.evict();
+            if self.entries.len() == before {
+                break;
+            }
+        }
+    }
+
+    pub fn invalidate(&mut self, ino: u32) {
+        self.entries.remove(&ino);
+    }
+
+    /// Generation of the cached inode, if present.
+    pub fn generation(&self, ino: u32) -> Option<u32> {
+        self.entries.get(&ino).map(|entry| entry.inode.i_generation)
+    }
+
+    /// Returns true if `ino` still refers to the same file generation.
+    pub fn is_current(&self, ino: u32, generation: u32) -> bool {
+        self.generation(ino).map_or(true, |current| current == generation)
+    }
+
+    /// Drains the dirty set, leaving the entries cached as clean.
+    pub fn take_dirty(&mut self) -> Vec<(u32, Inode)> {
+        let mut dirty = Vec::new();
+        for (ino, entry) in self.entries.iter_mut() {
+            if entry.dirty {
+                entry.dirty = false;
+                dirty.push((*ino, entry.inode));
+            }
+        }
+        dirty
+    }
+
+    fn store(&mut self, ino: u32, inode: Inode, dirty: bool) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            // A clean re-read never clears pending modifications
+            if dirty || !entry.dirty {
+                entry.inode = inode;
+            }
+            entry.dirty |= dirty;
+            entry.last_used = self.clock;
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.entries.insert(ino, CachedInode { inode, dirty, last_used: self.clock });
+    }
+
+    fn evict(&mut self) {
+        let victim = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.dirty)
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(ino, _)| *ino);
+        if let Some(ino) = victim {
+            self.entries.remove(&ino);
+        }
+    }
+}
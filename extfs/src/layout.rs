@@ -5,10 +5,43 @@ use glenda::{
 pub const DEVICE_SLOT: CapPtr = CapPtr::from(13);
 pub const VOLUME_SLOT: CapPtr = CapPtr::from(12);
 pub const VOLUME_CAP: Endpoint = Endpoint::from(VOLUME_SLOT);
+pub const VFS_SLOT: CapPtr = CapPtr::from(14);
 
 pub const NOTIFY_SLOT: CapPtr = CapPtr::from(9);
 pub const RECV_RING_SLOT: CapPtr = CapPtr::from(10);
 pub const RECV_BUFFER_SLOT: CapPtr = CapPtr::from(11);
 
-pub const RING_VADDR: usize = 0x6000_0000;
+// Where the client of one block device takes its notifications and receives its ring and
+// buffer; devices attached with ATTACH_DEVICE get slots allocated at runtime
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceSlots {
+    pub notify: CapPtr,
+    pub recv_ring: CapPtr,
+    pub recv_buffer: CapPtr,
+}
+
+pub const DEVICE_SLOTS: DeviceSlots =
+    DeviceSlots { notify: NOTIFY_SLOT, recv_ring: RECV_RING_SLOT, recv_buffer: RECV_BUFFER_SLOT };
+
+// Slots the CSpaceManager hands out stay below this
+pub const DYNAMIC_SLOT_LIMIT: CapPtr = CapPtr::from(0x100);
+// Where caps sent along with a call arrive
+pub const RECV_SLOT: CapPtr = CapPtr::from(0x100);
+
+// Notification endpoints of running jobs, one slot per job id
+pub const JOB_SLOT_BASE: CapPtr = CapPtr::from(0x180);
+// Reply caps of parked OPEN_ASYNC calls
+pub const PARKED_SLOT_BASE: CapPtr = CapPtr::from(0x1a0);
+// Job and parked call slots of volumes attached later are reserved from here
+pub const RUN_SLOT_START: CapPtr = CapPtr::from(0x200);
+pub const RUN_SLOT_END: CapPtr = CapPtr::from(0x400);
+
 pub const RING_SIZE: usize = PGSIZE;
+
+// Block device rings and client rings from SETUP_IOURING are mapped between these; the
+// VSpaceManager has the range above to itself
+pub const MAP_START: usize = 0x4000_0000;
+pub const MAP_END: usize = 0x7000_0000;
+
+// Where the volume appears in the VFS namespace until MOUNT_AT moves it
+pub const MOUNT_PATH: &str = "/mnt/ext";
@@ -0,0 +1,257 @@
+//! Writes a fresh, empty ext2 volume for FORMAT: 4 KiB blocks, 256-byte
+//! inodes, sparse superblock backups and a root directory, as mke2fs lays
+//! out a revision 1 filesystem without a journal or extents.
+
+use crate::defs::ext4::*;
+use crate::volume::{has_sparse_super, set_bits};
+use fs_block::BlockReader;
+use fs_common::bytes::{put_le_u32, FromBytes, ToBytes};
+use fs_common::clock;
+use fs_common::crc::crc32c;
+use fs_common::zeroing;
+use glenda::error::Error;
+
+const BLOCK_SIZE: usize = 4096;
+const LOG_BLOCK_SIZE: u32 = 2;
+// One block bitmap covers a group
+const BLOCKS_PER_GROUP: u32 = BLOCK_SIZE as u32 * 8;
+const INODE_SIZE: usize = 256;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BYTES_PER_INODE: usize = 16 * 1024;
+// Inodes 1 to 10 are reserved, the root directory among them
+const FIRST_INO: u32 = 11;
+const RESERVED_PERCENT: u32 = 5;
+// A short last group with less room than this for data is left off
+const MIN_GROUP_DATA: u32 = 64;
+const DESC_SIZE: usize = EXT4_MIN_DESC_SIZE as usize;
+const ERRORS_CONTINUE: u16 = 1;
+
+struct Layout {
+    blocks: u32,
+    groups: u32,
+    inodes_per_group: u32,
+    gdt_blocks: u32,
+    itable_blocks: u32,
+}
+
+impl Layout {
+    fn new(size: usize) -> Result<Self, Error> {
+        let mut blocks = u32::try_from(size / BLOCK_SIZE).map_err(|_| Error::InvalidArgs)?;
+        let mut groups = blocks.div_ceil(BLOCKS_PER_GROUP);
+        let inodes = (size / BYTES_PER_INODE) as u64;
+        let per_group = inodes.div_ceil(groups.max(1) as u64).min(BLOCKS_PER_GROUP as u64) as u32;
+        let inodes_per_group = per_group.next_multiple_of(INODES_PER_BLOCK).max(INODES_PER_BLOCK);
+        let mut layout = Self {
+            blocks,
+            groups,
+            inodes_per_group,
+            gdt_blocks: 0,
+            itable_blocks: inodes_per_group / INODES_PER_BLOCK,
+        };
+        layout.gdt_blocks = layout.gdt_blocks_for(groups);
+        if groups > 0 {
+            let last = groups - 1;
+            if layout.group_len(last) < layout.overhead(last) + MIN_GROUP_DATA {
+                groups -= 1;
+                blocks = groups * BLOCKS_PER_GROUP;
+                layout.groups = groups;
+                layout.blocks = blocks;
+                layout.gdt_blocks = layout.gdt_blocks_for(groups);
+            }
+        }
+        // The root directory takes a block of group 0
+        if groups == 0 || layout.group_len(0) <= layout.overhead(0) {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(layout)
+    }
+
+    fn gdt_blocks_for(&self, groups: u32) -> u32 {
+        (groups as usize * DESC_SIZE).div_ceil(BLOCK_SIZE) as u32
+    }
+
+    fn group_start(&self, group: u32) -> u32 {
+        group * BLOCKS_PER_GROUP
+    }
+
+    fn group_len(&self, group: u32) -> u32 {
+        (self.blocks - self.group_start(group)).min(BLOCKS_PER_GROUP)
+    }
+
+    fn has_super(&self, group: u32) -> bool {
+        group == 0 || has_sparse_super(group)
+    }
+
+    // Superblock and descriptor table copy at the start of `group`, if any
+    fn super_blocks(&self, group: u32) -> u32 {
+        if self.has_super(group) {
+            1 + self.gdt_blocks
+        } else {
+            0
+        }
+    }
+
+    // Blocks at the start of `group` that hold metadata
+    fn overhead(&self, group: u32) -> u32 {
+        self.super_blocks(group) + 2 + self.itable_blocks
+    }
+
+    fn block_bitmap(&self, group: u32) -> u32 {
+        self.group_start(group) + self.super_blocks(group)
+    }
+
+    fn inode_bitmap(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 2
+    }
+
+    // First block after group 0's metadata, the root directory's
+    fn root_block(&self) -> u32 {
+        self.overhead(0)
+    }
+}
+
+/// Formats the first `size` bytes of the volume behind `reader` as ext2,
+/// labelled `label` when it is not empty.
+pub fn format(reader: &BlockReader, size: usize, label: &str) -> Result<(), Error> {
+    if label.len() > 16 {
+        return Err(Error::InvalidArgs);
+    }
+    let layout = Layout::new(size)?;
+    let ipg = layout.inodes_per_group;
+    let now = clock::now().unwrap_or_default();
+    let write =
+        |block: u32, buf: &[u8]| reader.write_blocks(block as usize * BLOCK_SIZE / 512, buf);
+
+    let mut gdt = alloc::vec![0u8; layout.gdt_blocks as usize * BLOCK_SIZE];
+    let (mut free_blocks, mut free_inodes) = (0u32, 0u32);
+    let mut bitmap = [0u8; BLOCK_SIZE];
+    for group in 0..layout.groups {
+        let len = layout.group_len(group);
+        let mut used = layout.overhead(group);
+        if group == 0 {
+            used += 1;
+        }
+        bitmap.fill(0);
+        set_bits(&mut bitmap, 0..used as usize);
+        set_bits(&mut bitmap, len as usize..BLOCK_SIZE * 8);
+        write(layout.block_bitmap(group), &bitmap)?;
+
+        let reserved = if group == 0 { FIRST_INO - 1 } else { 0 };
+        bitmap.fill(0);
+        set_bits(&mut bitmap, 0..reserved as usize);
+        set_bits(&mut bitmap, ipg as usize..BLOCK_SIZE * 8);
+        write(layout.inode_bitmap(group), &bitmap)?;
+
+        let table = layout.inode_table(group) as usize * BLOCK_SIZE;
+        let table_len = layout.itable_blocks as usize * BLOCK_SIZE;
+        zeroing::fill(table, table_len, |at, zeros| reader.write_blocks(at / 512, zeros))?;
+
+        let mut gd = GroupDesc::from_bytes(&[0u8; <GroupDesc as FromBytes>::SIZE])?;
+        gd.bg_block_bitmap_lo = layout.block_bitmap(group);
+        gd.bg_inode_bitmap_lo = layout.inode_bitmap(group);
+        gd.bg_inode_table_lo = layout.inode_table(group);
+        gd.bg_free_blocks_count_lo = (len - used) as u16;
+        gd.bg_free_inodes_count_lo = (ipg - reserved) as u16;
+        gd.bg_used_dirs_count_lo = (group == 0) as u16;
+        let mut full = [0u8; <GroupDesc as FromBytes>::SIZE];
+        gd.to_bytes(&mut full)?;
+        let at = group as usize * DESC_SIZE;
+        gdt[at..at + DESC_SIZE].copy_from_slice(&full[..DESC_SIZE]);
+        free_blocks += len - used;
+        free_inodes += ipg - reserved;
+    }
+
+    let mut sb = SuperBlock::from_bytes(&[0u8; <SuperBlock as FromBytes>::SIZE])?;
+    sb.s_inodes_count = ipg * layout.groups;
+    sb.s_blocks_count_lo = layout.blocks;
+    sb.s_r_blocks_count_lo = (layout.blocks as u64 * RESERVED_PERCENT as u64 / 100) as u32;
+    sb.s_free_blocks_count_lo = free_blocks;
+    sb.s_free_inodes_count = free_inodes;
+    sb.s_first_data_block = 0;
+    sb.s_log_block_size = LOG_BLOCK_SIZE;
+    sb.s_log_cluster_size = LOG_BLOCK_SIZE;
+    sb.s_blocks_per_group = BLOCKS_PER_GROUP;
+    sb.s_clusters_per_group = BLOCKS_PER_GROUP;
+    sb.s_inodes_per_group = ipg;
+    sb.s_wtime = now.sec as u32;
+    sb.s_max_mnt_count = 0xFFFF;
+    sb.s_magic = EXT4_SUPER_MAGIC;
+    sb.s_state = EXT4_VALID_FS;
+    sb.s_errors = ERRORS_CONTINUE;
+    sb.s_lastcheck = now.sec as u32;
+    sb.s_rev_level = 1;
+    sb.s_first_ino = FIRST_INO;
+    sb.s_inode_size = INODE_SIZE as u16;
+    sb.s_feature_incompat = EXT4_FEATURE_INCOMPAT_FILETYPE;
+    sb.s_feature_ro_compat = EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER
+        | EXT4_FEATURE_RO_COMPAT_LARGE_FILE
+        | EXT4_FEATURE_RO_COMPAT_EXTRA_ISIZE;
+    sb.s_uuid = volume_uuid(now.sec, now.nsec, size);
+    sb.s_volume_name[..label.len()].copy_from_slice(label.as_bytes());
+    sb.s_mkfs_time = now.sec as u32;
+    sb.s_min_extra_isize = EXT4_INODE_EXTRA_SIZE as u16;
+    sb.s_want_extra_isize = EXT4_INODE_EXTRA_SIZE as u16;
+
+    // The primary superblock sits 1024 bytes into block 0, the backups at
+    // the start of their groups; each is followed by the descriptor table.
+    // Block 0 is written whole, so nothing the old volume kept in front of
+    // its superblock survives.
+    let mut block = [0u8; BLOCK_SIZE];
+    for group in (0..layout.groups).filter(|&g| layout.has_super(g)) {
+        sb.s_block_group_nr = group as u16;
+        block.fill(0);
+        let at = if group == 0 { SUPER_BLOCK_OFFSET } else { 0 };
+        sb.to_bytes(&mut block[at..])?;
+        write(layout.group_start(group), &block)?;
+        write(layout.group_start(group) + 1, &gdt)?;
+    }
+
+    // Root directory: inode 2 with one block holding "." and ".."
+    let root = layout.root_block();
+    let mut inode = Inode::from_bytes(&[0u8; <Inode as FromBytes>::SIZE])?;
+    inode.i_mode = EXT4_S_IFDIR | 0o755;
+    inode.i_links_count = 2;
+    inode.set_size(BLOCK_SIZE as u64);
+    inode.i_blocks_lo = (BLOCK_SIZE / 512) as u32;
+    put_le_u32(&mut inode.i_block, 0, root)?;
+    inode.set_atime(now);
+    inode.set_mtime(now);
+    inode.set_ctime(now);
+    inode.set_crtime(now);
+    inode.i_extra_isize = EXT4_INODE_EXTRA_SIZE as u16;
+    let mut slot = [0u8; INODE_SIZE];
+    inode.to_bytes(&mut slot)?;
+    let slot_at =
+        layout.inode_table(0) as usize * BLOCK_SIZE + (ROOT_INO as usize - 1) * INODE_SIZE;
+    reader.write_blocks(slot_at / 512, &slot)?;
+
+    block.fill(0);
+    let dot = DirEntry2 { inode: ROOT_INO, rec_len: 12, name_len: 1, file_type: EXT4_FT_DIR };
+    dot.to_bytes(&mut block)?;
+    block[8] = b'.';
+    let rest = (BLOCK_SIZE - 12) as u16;
+    let dotdot = DirEntry2 { inode: ROOT_INO, rec_len: rest, name_len: 2, file_type: EXT4_FT_DIR };
+    dotdot.to_bytes(&mut block[12..])?;
+    block[20..22].copy_from_slice(b"..");
+    write(root, &block)?;
+    reader.barrier()
+}
+
+// Not random, but distinct for volumes formatted at different times or sizes
+fn volume_uuid(sec: i64, nsec: u32, size: usize) -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    let mut seed = crc32c(0, &sec.to_le_bytes());
+    for chunk in uuid.chunks_mut(4) {
+        seed = crc32c(seed, &nsec.to_le_bytes());
+        seed = crc32c(seed, &size.to_le_bytes());
+        chunk.copy_from_slice(&seed.to_le_bytes());
+    }
+    // Version 4, RFC 4122 variant
+    uuid[6] = (uuid[6] & 0x0F) | 0x40;
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    uuid
+}
@@ -1,5 +1,7 @@
-use crate::block::BlockReader;
 use crate::defs::ext4::*;
+use crate::volume::ExtVolume;
+use fs_block::BlockReader;
+use fs_common::limits::ext_blockmap_max_file_size;
 use glenda::error::Error;
 
 pub trait ExtOps: Send + Sync {
@@ -9,5 +11,61 @@ pub trait ExtOps: Send + Sync {
         inode: &Inode,
         lblock: u32,
         block_size: u32,
+    ) -> Result<u64, Error>;
+
+    /// Whether logical block `lblock` of `inode` holds data, and for how many
+    /// blocks from it on at least that stays so, letting hole searches skip
+    /// unmapped subtrees and whole extents. Unwritten extents count as holes.
+    fn block_run(
+        &self,
+        reader: &BlockReader,
+        inode: &Inode,
+        lblock: u32,
+        block_size: u32,
+    ) -> Result<(bool, u64), Error>;
+
+    /// Frees every block mapped at logical index `first_free` or beyond,
+    /// including mapping blocks left empty, and clears it from `inode`.
+    /// Returns the number of filesystem blocks released.
+    fn truncate_blocks(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first_free: u32,
+    ) -> Result<u64, Error>;
+
+    /// Maps logical block `lblock` of `inode`, allocating the data block and
+    /// any missing mapping blocks near `goal_group`. Returns the physical
+    /// block and the number of filesystem blocks allocated on the way.
+    fn map_block(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        lblock: u32,
+        goal_group: u32,
+    ) -> Result<(u64, u32), Error>;
+
+    /// Reserves blocks for the logical blocks `first..first + count` of
+    /// `inode` that are not mapped yet, so that writing them later cannot run
+    /// out of space. They read as zeros. Returns the number of filesystem
+    /// blocks allocated.
+    #[allow(clippy::too_many_arguments)]
+    fn preallocate(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first: u32,
+        count: u32,
+        goal_group: u32,
     ) -> Result<u32, Error>;
+
+    fn max_file_size(&self, block_size: u32) -> u64 {
+        ext_blockmap_max_file_size(block_size)
+    }
 }
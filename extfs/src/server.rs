@@ -1,53 +1,242 @@
-use crate::fs::ExtFs;
+use crate::features::FeatureSupport;
+use crate::fs::{ExtFs, RmtreeJob};
+use crate::defs::ext4::ROOT_INO;
+use crate::layout::{
+    DeviceSlots, DEVICE_SLOTS, DYNAMIC_SLOT_LIMIT, JOB_SLOT_BASE, MAP_END, MAP_START,
+    PARKED_SLOT_BASE, RECV_SLOT, RUN_SLOT_END, RUN_SLOT_START,
+};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
-use glenda::cap::{CapPtr, Endpoint, Reply};
-use glenda::client::ResourceClient;
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CapPtr, CapRights, Endpoint, Frame, Reply, CSPACE_CAP};
+use glenda::client::{FsClient, ResourceClient};
 use glenda::error::Error;
-use glenda::interface::fs::FileHandleService;
 use glenda::interface::system::SystemService;
+use glenda::interface::VSpaceService;
 use glenda::ipc::server::handle_call;
-use glenda::ipc::{MsgTag, UTCB};
+use glenda::ipc::{Badge, MsgFlags, MsgTag, UTCB};
+use glenda::mem::Perms;
+use fs_block::DEVICE_RING_SIZE;
+use fs_common::attr::{AttrCache, ATTR_CACHE_SIZE};
+use fs_common::audit::{self, AuditLog};
+use fs_common::badge;
+use fs_common::call;
+use fs_common::clock::{self, TimesRequest};
+use fs_common::creds::{MAY_READ, MAY_WRITE};
+use fs_common::deferred::{ParkedCalls, PathWalk, Settled, MAX_PARKED};
+use fs_common::device::DeviceInfo;
+use fs_common::errors::FsError;
+use fs_common::events::{self, EventBus};
+use fs_common::handle::{FsHandle, ReadOnly};
+use fs_common::history;
+use fs_common::jobs::{Job, JobTable, MAX_JOBS};
+use fs_common::locks::{Grant, LockTable};
+use fs_common::mount::{MountOptions, MountPoint, MNT_RDONLY};
+use fs_common::partition::PartitionSelect;
+use fs_common::path;
+use fs_common::policy::{ExportPolicy, OP_READ, OP_WRITE};
+use fs_common::probe::{self, FsType};
+use fs_common::proto::{self, CURRENT_OFFSET};
+use fs_common::ring::{self, RingAccess, RingSignal, SharedRing};
+use fs_common::scrub::ScrubReport;
+use fs_common::slots::SlotAllocator;
+use fs_common::snapshots::{self, NoSnapshots, SnapshotSource};
+use fs_common::vaddr::VaddrAllocator;
+use fs_common::version::{self, Versions};
+use fs_common::wire::WireGuard;
 use glenda::protocol::fs::OpenFlags;
 use glenda::protocol::process;
 use glenda::protocol::{FS_PROTO, PROCESS_PROTO};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
 
+struct OpenHandle {
+    handle: Box<dyn FsHandle>,
+    path: String,
+    // Other links to the file are cached under their own paths
+    ino: usize,
+    is_dir: bool,
+    refs: usize,
+    // Set up by SETUP_IOURING
+    ring: Option<SharedRing>,
+    // Badge of the caller that opened it; ids are sequential, so anyone could
+    // name another client's handle
+    owner: usize,
+}
+
+// Handle `id` if `badge` opened it; another client's handle is as good as unknown
+fn owned(
+    handles: &BTreeMap<usize, OpenHandle>,
+    badge: Badge,
+    id: usize,
+) -> Result<&OpenHandle, Error> {
+    handles.get(&id).filter(|h| h.owner == badge.bits()).ok_or(Error::NotFound)
+}
+
+fn owned_mut(
+    handles: &mut BTreeMap<usize, OpenHandle>,
+    badge: Badge,
+    id: usize,
+) -> Result<&mut OpenHandle, Error> {
+    handles.get_mut(&id).filter(|h| h.owner == badge.bits()).ok_or(Error::NotFound)
+}
+
+// What the service keeps for each block device it serves. The fields of
+// Ext4Service hold the volume being served; the others wait in `volumes`,
+// swapped in by `select` for the calls and doorbells that reach them.
+struct Volume {
+    fs: Option<ExtFs>,
+    handles: BTreeMap<usize, OpenHandle>,
+    attrs: AttrCache,
+    read_only: bool,
+    frozen: bool,
+    options: MountOptions,
+    mount_point: MountPoint,
+    mount_endpoint: Endpoint,
+    snapshots: Box<dyn SnapshotSource>,
+    device: DeviceInfo,
+    events: EventBus,
+    locks: LockTable<String>,
+    jobs: JobTable<ExtFs>,
+    opens: ParkedCalls<OpenWalk>,
+}
+
+impl Volume {
+    // Nothing mounted yet; job and parked call caps go from the given slots on
+    fn new((job_slots, parked_slots): (CapPtr, CapPtr)) -> Self {
+        Self {
+            fs: None,
+            handles: BTreeMap::new(),
+            attrs: AttrCache::new(ATTR_CACHE_SIZE),
+            read_only: false,
+            frozen: false,
+            options: MountOptions::default(),
+            mount_point: MountPoint::none(),
+            mount_endpoint: Endpoint::from(CapPtr::null()),
+            snapshots: Box::new(NoSnapshots),
+            device: DeviceInfo::unknown(),
+            events: EventBus::new(),
+            locks: LockTable::new(),
+            jobs: JobTable::new(job_slots),
+            opens: ParkedCalls::new(parked_slots),
+        }
+    }
+}
+
 pub struct Ext4Service<'a> {
     fs: Option<ExtFs>,
-    handles: BTreeMap<usize, Box<dyn FileHandleService + Send>>,
+    handles: BTreeMap<usize, OpenHandle>,
+    attrs: AttrCache,
+    read_only: bool,
+    // Between FREEZE and THAW: nothing may reach the device
+    frozen: bool,
+    options: MountOptions,
+    mount_point: MountPoint,
+    // Registered with the VFS: the service endpoint, or one minted for the volume
+    mount_endpoint: Endpoint,
+    // Behind the synthetic .snapshots directory
+    snapshots: Box<dyn SnapshotSource>,
+    device: DeviceInfo,
+    // What the volume held when init_fs declined it
+    declined: Option<FsType>,
+    wire: WireGuard,
+    policy: ExportPolicy,
+    versions: Versions,
+    audit: AuditLog,
+    events: EventBus,
+    // Advisory locks by path, owned by handle id
+    locks: LockTable<String>,
+    jobs: JobTable<ExtFs>,
+    opens: ParkedCalls<OpenWalk>,
+    // Set by a handler that parked its call; serve then leaves the reply for later
+    parked: bool,
+    // Every volume served, by index; the slot of the active one holds a placeholder
+    volumes: Vec<Volume>,
+    active: usize,
+    // Where attached volumes get mounted
+    vfs: Option<Endpoint>,
     endpoint: Endpoint,
     reply: Reply,
     recv: CapPtr,
     running: bool,
     next_handle_id: usize,
-    ring_vaddr: usize,
     ring_size: usize,
+    // Addresses for block device rings and client rings
+    maps: VaddrAllocator,
 
-    pub cspace: &'a mut CSpaceManager,
+    pub res_client: &'a mut ResourceClient,
+    pub slots: SlotAllocator<'a>,
     pub vspace: &'a mut VSpaceManager,
 }
 
-const RECV_SLOT: CapPtr = CapPtr::from(0x100);
+// A parked OPEN_ASYNC
+struct OpenWalk {
+    walk: PathWalk<u32>,
+    flags: OpenFlags,
+    mode: u32,
+}
+
+const FEATURES: usize = version::FEAT_RING_NOTIFY
+    | version::FEAT_IOVEC
+    | version::FEAT_LOCKS
+    | version::FEAT_CLONE
+    | version::FEAT_JOBS
+    | version::FEAT_WIRE
+    | version::FEAT_OPEN_ASYNC
+    | version::FEAT_HISTORY
+    | version::FEAT_EVENTS
+    | version::FEAT_LINK
+    | version::FEAT_XATTR
+    | version::FEAT_SET_TIMES
+    | version::FEAT_STATFS
+    | version::FEAT_FALLOCATE
+    | version::FEAT_NEXT
+    | version::FEAT_STREAM
+    | version::FEAT_ATTACH
+    | version::FEAT_ERROR_DETAIL;
 
 impl<'a> Ext4Service<'a> {
     pub fn new(
-        ring_vaddr: usize,
         ring_size: usize,
+        res_client: &'a mut ResourceClient,
         cspace: &'a mut CSpaceManager,
         vspace: &'a mut VSpaceManager,
     ) -> Self {
         Self {
             fs: None,
             handles: BTreeMap::new(),
+            attrs: AttrCache::new(ATTR_CACHE_SIZE),
+            read_only: false,
+            frozen: false,
+            options: MountOptions::default(),
+            mount_point: MountPoint::none(),
+            mount_endpoint: Endpoint::from(CapPtr::null()),
+            snapshots: Box::new(NoSnapshots),
+            device: DeviceInfo::unknown(),
+            declined: None,
+            wire: WireGuard::new(),
+            policy: ExportPolicy::new(),
+            versions: Versions::new(FEATURES),
+            audit: AuditLog::new(cfg!(feature = "audit")),
+            events: EventBus::new(),
+            locks: LockTable::new(),
+            jobs: JobTable::new(JOB_SLOT_BASE),
+            opens: ParkedCalls::new(PARKED_SLOT_BASE),
+            parked: false,
+            volumes: alloc::vec![Volume::new((JOB_SLOT_BASE, PARKED_SLOT_BASE))],
+            active: 0,
+            vfs: None,
             endpoint: Endpoint::from(CapPtr::null()),
             reply: Reply::from(CapPtr::null()),
             recv: CapPtr::null(),
             running: false,
             next_handle_id: 100,
-            ring_vaddr,
             ring_size,
-            cspace,
+            maps: VaddrAllocator::new(MAP_START, MAP_END),
+            res_client,
+            slots: SlotAllocator::new(cspace, DYNAMIC_SLOT_LIMIT)
+                .with_runs(RUN_SLOT_START, RUN_SLOT_END),
             vspace,
         }
     }
@@ -55,18 +244,559 @@ impl<'a> Ext4Service<'a> {
     pub fn init_fs(
         &mut self,
         block_device: Endpoint,
-        res_client: &mut ResourceClient,
+        options: MountOptions,
+        partition: PartitionSelect,
     ) -> Result<(), Error> {
-        self.fs = Some(ExtFs::new(
+        self.mount_device(block_device, options, partition, DEVICE_SLOTS)
+    }
+
+    // Mounts the volume on `block_device` as the active one
+    fn mount_device(
+        &mut self,
+        block_device: Endpoint,
+        options: MountOptions,
+        partition: PartitionSelect,
+        slots: DeviceSlots,
+    ) -> Result<(), Error> {
+        // Left reserved if the mount fails, as the ring may be mapped already
+        let ring_vaddr = self.maps.reserve(self.ring_size, PGSIZE)?;
+        self.mount_at(block_device, options, partition, ring_vaddr, slots)
+    }
+
+    fn mount_at(
+        &mut self,
+        block_device: Endpoint,
+        options: MountOptions,
+        partition: PartitionSelect,
+        ring_vaddr: usize,
+        slots: DeviceSlots,
+    ) -> Result<(), Error> {
+        let utcb = unsafe { UTCB::new() };
+        self.device = DeviceInfo::query(block_device, utcb);
+        let mut reader = ExtFs::open_reader(
             block_device,
-            self.ring_vaddr,
+            ring_vaddr,
             self.ring_size,
-            res_client,
+            slots,
+            self.res_client,
             self.vspace,
-            self.cspace,
-        )?);
+            self.slots.cspace(),
+            partition,
+        )?;
+        let found =
+            probe::probe(|offset, buf| reader.read_offset(offset as usize, buf).map(|_| ()))?;
+        if !matches!(found, FsType::Ext) {
+            glenda::log!("ExtFS: declining volume, it holds {:?}", found);
+            self.declined = Some(found);
+            return Err(Error::NotSupported);
+        }
+        let batch_vaddr = self.maps.reserve(DEVICE_RING_SIZE, PGSIZE)?;
+        let cspace = self.slots.cspace();
+        if let Err(e) = reader.setup_ring(batch_vaddr, self.res_client, self.vspace, cspace) {
+            glenda::log!("ExtFS: no device ring, batched reads go out one run at a time: {:?}", e);
+            let _ = self.maps.release(batch_vaddr);
+        }
+        let mut fs = ExtFs::mount(reader, ring_vaddr, self.ring_size)?;
+        fs.set_io_tuning(self.device.tuning());
+        fs.set_mount_options(options);
+        self.options = options;
+        self.attrs = AttrCache::new(ATTR_CACHE_SIZE).with_casefold(options.casefold);
+        if let FeatureSupport::ReadOnly { incompat, ro_compat } = fs.features() {
+            glenda::log!(
+                "ExtFS: mounting read-only, cannot write features incompat {:#x} ro_compat {:#x}",
+                incompat,
+                ro_compat
+            );
+            self.read_only = true;
+        }
+        glenda::log!(
+            "ExtFS: device '{}' serial '{}', rotational: {}, discard: {}, cache: {:?}",
+            self.device.model,
+            self.device.serial,
+            self.device.rotational,
+            self.device.discard,
+            self.device.cache
+        );
+        let unclean = fs.unclean();
+        if self.check_writable().is_ok() {
+            let orphans = fs.process_orphans()?;
+            if orphans > 0 {
+                glenda::log!("ExtFS: finished deleting or truncating {} orphan inode(s)", orphans);
+            }
+        } else if fs.has_orphans() {
+            glenda::log!("ExtFS: read-only, leaving the orphan list for a read-write mount");
+        }
+        self.fs = Some(fs);
+        if unclean {
+            glenda::log!("ExtFS: volume was not unmounted cleanly, checking it");
+            let report = self.check(self.check_writable().is_ok())?;
+            if report.outstanding() > 0 {
+                glenda::log!("ExtFS: mounting read-only, {} issue(s) left", report.outstanding());
+                self.read_only = true;
+            }
+        }
+        if self.check_writable().is_ok() {
+            self.fs.as_ref().ok_or(Error::NotInitialized)?.mark_in_use()?;
+        }
         Ok(())
     }
+
+    /// Where the service registers in the VFS namespace once it runs.
+    pub fn set_mount_point(&mut self, mount_point: MountPoint) {
+        self.mount_point = mount_point;
+    }
+
+    /// The VFS volumes attached with ATTACH_DEVICE are mounted with.
+    pub fn set_vfs(&mut self, vfs: Endpoint) {
+        self.vfs = Some(vfs);
+    }
+
+    /// Set when init_fs failed because the volume is not this service's
+    /// format, so the caller can hand it to another service.
+    pub fn declined(&self) -> Option<FsType> {
+        self.declined
+    }
+
+    // CHECK, and the check an unclean volume gets at mount
+    fn check(&mut self, repair: bool) -> Result<ScrubReport, Error> {
+        if repair {
+            self.check_writable()?;
+        }
+        let fs = self.fs.as_ref().ok_or(Error::NotInitialized)?;
+        let report = fs.check(repair)?;
+        glenda::log!("ExtFS: check: {}", report);
+        for issue in &report.issues {
+            glenda::log!("ExtFS: check: {}", issue);
+        }
+        if !report.is_clean() {
+            self.events.publish(events::EV_CORRUPTION, report.issues.len());
+        }
+        Ok(report)
+    }
+
+    // FORMAT: the new filesystem replaces the mounted one, so nothing may
+    // be open on it
+    fn format(&mut self, badge: Badge, utcb: &mut UTCB) -> Result<(), Error> {
+        self.check_admin(badge)?;
+        self.check_writable()?;
+        if !self.handles.is_empty() || self.jobs.busy() || !self.opens.is_empty() {
+            return Err(Error::WouldBlock);
+        }
+        let label = String::from(path::from_buffer(utcb.buffer())?);
+        let fs = self.fs.as_mut().ok_or(Error::NotInitialized)?;
+        fs.format(utcb.get_mr(0), &label)?;
+        fs.mark_in_use()?;
+        self.attrs = AttrCache::new(ATTR_CACHE_SIZE).with_casefold(self.options.casefold);
+        glenda::log!("ExtFS: volume formatted as ext2");
+        Ok(())
+    }
+
+    /// Runs the quick metadata scrub and reports the result, in the log and
+    /// as events observers read once they subscribe, before the service
+    /// starts answering requests. Findings are reported, not fatal.
+    pub fn scrub(&mut self) -> Result<(), Error> {
+        let fs = self.fs.as_ref().ok_or(Error::NotInitialized)?;
+        let report = fs.scrub()?;
+        glenda::log!("ExtFS: mount scrub: {}", report);
+        for issue in &report.issues {
+            glenda::log!("ExtFS: scrub: {}", issue);
+        }
+        self.events.publish(events::EV_SCRUB, report.checked);
+        if !report.is_clean() {
+            self.events.publish(events::EV_CORRUPTION, report.issues.len());
+        }
+        Ok(())
+    }
+
+    // Path an audited request is about, taken before dispatch overwrites the buffer
+    fn audit_path(&self, utcb: &UTCB) -> String {
+        let badge = utcb.get_badge();
+        audit::call_path(utcb, |id| owned(&self.handles, badge, id).ok().map(|h| h.path.as_str()))
+    }
+
+    // Mount options in effect as MNT_* bits, with MNT_RDONLY when the service forced it
+    fn mounted_options(&self) -> usize {
+        let forced = if self.read_only { MNT_RDONLY } else { 0 };
+        self.options.bits() | forced
+    }
+
+    // Makes volume `index` the active one, the one the service fields hold
+    fn select(&mut self, index: usize) -> Result<(), Error> {
+        if index >= self.volumes.len() {
+            return Err(Error::NotFound);
+        }
+        if index != self.active {
+            // The placeholder in the active slot goes back for that volume's state
+            self.swap_volume(self.active);
+            self.swap_volume(index);
+            self.active = index;
+        }
+        Ok(())
+    }
+
+    fn swap_volume(&mut self, index: usize) {
+        use core::mem::swap;
+        let vol = &mut self.volumes[index];
+        swap(&mut self.fs, &mut vol.fs);
+        swap(&mut self.handles, &mut vol.handles);
+        swap(&mut self.attrs, &mut vol.attrs);
+        swap(&mut self.read_only, &mut vol.read_only);
+        swap(&mut self.frozen, &mut vol.frozen);
+        swap(&mut self.options, &mut vol.options);
+        swap(&mut self.mount_point, &mut vol.mount_point);
+        swap(&mut self.mount_endpoint, &mut vol.mount_endpoint);
+        swap(&mut self.snapshots, &mut vol.snapshots);
+        swap(&mut self.device, &mut vol.device);
+        swap(&mut self.events, &mut vol.events);
+        swap(&mut self.locks, &mut vol.locks);
+        swap(&mut self.jobs, &mut vol.jobs);
+        swap(&mut self.opens, &mut vol.opens);
+    }
+
+    // Gate for the calls that manage the volume rather than its files
+    fn check_admin(&self, badge: Badge) -> Result<(), Error> {
+        if !badge::is_admin(badge.bits()) {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Gate for every call that would modify the volume
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only || self.options.read_only {
+            return Err(FsError::ReadOnly.into());
+        }
+        if self.frozen {
+            return Err(Error::WouldBlock);
+        }
+        Ok(())
+    }
+
+    fn insert_handle(
+        &mut self,
+        handle: Box<dyn FsHandle>,
+        path: String,
+        badge: glenda::ipc::Badge,
+    ) -> Result<usize, Error> {
+        let stat = handle.stat(badge)?;
+        let is_dir = (stat.mode & 0o170000) == 0o040000;
+        let id = self.next_handle_id;
+        self.next_handle_id += 1;
+        let owner = badge.bits();
+        let entry = OpenHandle { handle, path, ino: stat.ino, is_dir, refs: 1, ring: None, owner };
+        self.handles.insert(id, entry);
+        Ok(id)
+    }
+
+    fn unmap_ring(&mut self, ring: &SharedRing) {
+        if let Err(e) = self.maps.unmap(self.vspace, ring.window().server_base) {
+            glenda::log!("ExtFS: cannot unmap a client ring: {:?}", e);
+        }
+    }
+
+    // Completes ring lock submissions that were waiting on a released lock
+    fn complete_grants(&self, grants: Vec<Grant>) {
+        ring::complete_grants(grants, |id| self.handles.get(&id).and_then(|h| h.ring.as_ref()));
+    }
+
+    // Serves the submissions on handle `id`'s ring, for PROCESS_IOURING or a doorbell
+    fn process_ring(&mut self, id: usize, badge: Badge) -> Result<(), Error> {
+        // Serving the ring only needs OP_READ; writes on it need OP_WRITE
+        let writable =
+            self.check_writable().and_then(|_| self.policy.permit(badge.bits(), OP_WRITE));
+        let entry = owned_mut(&mut self.handles, badge, id)?;
+        let ring = entry.ring.as_mut().ok_or(Error::InvalidArgs)?;
+        let access = RingAccess {
+            writable,
+            frozen: self.frozen,
+            locks: &mut self.locks,
+            key: &entry.path,
+            owner: id,
+            audit: Some((&mut self.audit, &entry.path)),
+        };
+        let grants = ring.process(entry.handle.as_mut(), badge, access);
+        ring.announce();
+        self.attrs.invalidate(&entry.path);
+        self.attrs.invalidate_ino(entry.ino);
+        self.complete_grants(grants);
+        Ok(())
+    }
+
+    // A notification on the service endpoint: serves the rings the notifying
+    // badge registered with RING_NOTIFY
+    fn doorbell(&mut self, badge: usize, bits: usize) {
+        if bits & proto::RING_DOORBELL_BITS == 0 || self.policy.permit(badge, OP_READ).is_err() {
+            return;
+        }
+        if self.select(badge::volume(badge)).is_err() {
+            return;
+        }
+        let rings = self.handles.iter().map(|(&id, h)| (id, h.ring.as_ref()));
+        for id in ring::rung(rings, badge) {
+            if let Err(e) = self.process_ring(id, Badge::new(badge)) {
+                glenda::log!("ExtFS: ring of handle {} failed: {:?}", id, e);
+            }
+        }
+    }
+
+    // A call: checked, dispatched, audited and answered
+    fn serve(&mut self, badge: usize, utcb: &mut UTCB) {
+        let tag = utcb.get_msg_tag();
+        let selected = self.select(badge::volume(badge));
+        let audit_path = self.audit.wants(utcb).then(|| self.audit_path(utcb));
+        let result = selected
+            .and_then(|_| call::admit(&mut self.wire, Some(&self.policy), badge, utcb))
+            .and_then(|_| self.dispatch(utcb));
+        if let Some(path) = audit_path {
+            self.audit.record(badge, tag.proto(), tag.label(), path, result);
+        }
+        if core::mem::take(&mut self.parked) && result.is_ok() {
+            return;
+        }
+        call::answer(&mut self.wire, &self.versions, badge, utcb, result);
+        let _ = self.reply(utcb);
+    }
+
+    // UNMOUNT and EXIT: closes the volume. The loop ends after this call's
+    // reply; the parked calls, the VFS entry and attached volumes are dealt
+    // with then. UNMOUNT of an attached volume only detaches that one.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        if self.frozen {
+            return Err(Error::WouldBlock);
+        }
+        if self.active != 0 {
+            let result = self.close_volume();
+            self.release_volume();
+            return result;
+        }
+        self.running = false;
+        self.close_volume()
+    }
+
+    // Cancels the jobs of the active volume, closes every handle with its
+    // data written out and flushes the volume
+    fn close_volume(&mut self) -> Result<(), Error> {
+        self.jobs.cancel_all();
+        self.run_jobs();
+        let mut result = Ok(());
+        for (_, mut entry) in core::mem::take(&mut self.handles) {
+            let synced = entry.handle.sync(Badge::null());
+            result = result.and(synced).and(entry.handle.close(Badge::null()));
+            if let Some(ring) = entry.ring.take() {
+                self.unmap_ring(&ring);
+            }
+        }
+        self.locks = LockTable::new();
+        result = result.and(self.release_orphans());
+        // A volume remounted read-only was flushed then, and after errors
+        // stays marked for checking
+        if let Some(fs) = self.fs.as_ref().filter(|_| !self.read_only) {
+            result = result.and(fs.sync_all());
+            if result.is_ok() {
+                result = fs.mark_clean();
+            }
+        }
+        result
+    }
+
+    // Deletes files unlinked while open once their last handle is gone. A
+    // volume that may not be written keeps them listed for a later mount.
+    fn release_orphans(&self) -> Result<(), Error> {
+        match self.fs.as_ref() {
+            Some(fs) if self.check_writable().is_ok() => fs.release_orphans().map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // Takes the active volume out of the VFS namespace and drops it; calls
+    // still reaching it and its parked opens fail with NotInitialized
+    fn release_volume(&mut self) {
+        if self.fs.is_none() {
+            return;
+        }
+        if let Err(e) = self.mount_point.unregister() {
+            glenda::log!("ExtFS: cannot unmount volume {} from the VFS: {:?}", self.active, e);
+        }
+        self.events.publish(events::EV_UNMOUNT, 0);
+        self.fs = None;
+    }
+
+    // ATTACH_DEVICE: mounts the device transferred with the call as one more
+    // volume, reached through an endpoint minted for it
+    fn attach(&mut self, badge: Badge, utcb: &mut UTCB) -> Result<(), Error> {
+        self.check_admin(badge)?;
+        if !utcb.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+            return Err(Error::InvalidArgs);
+        }
+        let options = MountOptions::from_bits(utcb.get_mr(0))?;
+        let partition = PartitionSelect::from_raw(utcb.get_mr(1));
+        let path = String::from(path::from_buffer(utcb.buffer())?);
+        let index = self.volumes.len();
+        let volume_badge = badge::volume_badge(index).map_err(|_| Error::OutOfMemory)?;
+
+        // Jobs, then parked calls
+        let runs = self.slots.reserve(MAX_JOBS + MAX_PARKED)?;
+        let device = self.slots.alloc(self.res_client)?;
+        CSPACE_CAP.move_cap(RECV_SLOT, device)?;
+        let slots = DeviceSlots {
+            notify: self.slots.alloc(self.res_client)?,
+            recv_ring: self.slots.alloc(self.res_client)?,
+            recv_buffer: self.slots.alloc(self.res_client)?,
+        };
+        let previous = self.active;
+        self.volumes.push(Volume::new((runs, CapPtr::from(runs.bits() + MAX_JOBS))));
+        self.select(index)?;
+        if let Err(e) = self.mount_device(Endpoint::from(device), options, partition, slots) {
+            // The index stays taken, like the ring addresses reserved for it
+            for slot in [device, slots.notify, slots.recv_ring, slots.recv_buffer] {
+                let _ = CSPACE_CAP.delete(slot);
+                self.slots.free(slot);
+            }
+            self.select(previous)?;
+            return Err(e);
+        }
+
+        let slot = self.slots.alloc(self.res_client)?;
+        CSPACE_CAP.mint(self.endpoint.cap(), slot, Badge::new(volume_badge), CapRights::ALL)?;
+        self.mount_endpoint = Endpoint::from(slot);
+        if let Some(vfs) = self.vfs {
+            self.mount_point = MountPoint::new(FsClient::new(vfs), &path);
+            if !path.is_empty() {
+                match self.mount_point.register(self.mount_endpoint) {
+                    Ok(()) => self.events.publish(events::EV_MOUNT, self.mounted_options()),
+                    Err(e) => glenda::log!("ExtFS: cannot mount volume {}: {:?}", index, e),
+                }
+            }
+        }
+        glenda::log!("ExtFS: attached volume {}", index);
+        utcb.set_cap_transfer(slot);
+        utcb.set_mr(0, index);
+        Ok(())
+    }
+
+    // OPEN_ASYNC: parks the call with the path still to walk
+    fn park_open(&mut self, badge: Badge, utcb: &mut UTCB) -> Result<(), Error> {
+        let flags = OpenFlags::from_bits_truncate(utcb.get_mr(0));
+        if proto::open_mutates(flags) {
+            self.check_writable()?;
+        }
+        let fs = self.fs.as_mut().ok_or(Error::NotInitialized)?;
+        let path = path::from_buffer(utcb.buffer())?;
+        // Snapshot views are not on the device; answer at once
+        if let Some(opened) = snapshots::open(self.snapshots.as_mut(), path, flags) {
+            let id = self.insert_handle(opened?, String::from(path), badge)?;
+            utcb.set_mr(0, id);
+            return Ok(());
+        }
+        // Keeping the version is not worth parking for; it touches one directory
+        if flags.contains(OpenFlags::O_TRUNC) {
+            fs.save_version(badge, path)?;
+        }
+        let walk = PathWalk::new(path, ROOT_INO);
+        let open = OpenWalk { walk, flags, mode: utcb.get_mr(1) as u32 };
+        self.opens.park(self.reply, badge, open)?;
+        self.parked = true;
+        Ok(())
+    }
+
+    // Takes parked opens one lookup further and answers those that are done,
+    // so a deep path on a slow device never holds up other clients for long
+    fn walk_opens(&mut self) {
+        if self.fs.is_none() {
+            let refused = self.opens.advance(|_, _| Some(Err(Error::NotInitialized)));
+            for done in refused {
+                self.finish_open(done);
+            }
+            return;
+        }
+        let fs = match self.fs.as_ref() {
+            Some(fs) if !self.opens.is_empty() && !self.frozen => fs,
+            _ => return,
+        };
+        let settled = self.opens.advance(|badge, open| {
+            let name = match open.walk.next_component() {
+                Some(name) => name,
+                None => return Some(Ok(*open.walk.node())),
+            };
+            match fs.lookup_step(badge, *open.walk.node(), name) {
+                Ok(ino) => {
+                    open.walk.advance(ino);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            }
+        });
+        for done in settled {
+            self.finish_open(done);
+        }
+    }
+
+    // Whether a parked open on any volume can be taken further. The walk only
+    // moves on a turn of the loop, which would otherwise wait in recv for a
+    // call that a client alone on the service never makes.
+    fn opens_pending(&self) -> bool {
+        let pending = |opens: &ParkedCalls<OpenWalk>, frozen: bool| !opens.is_empty() && !frozen;
+        pending(&self.opens, self.frozen)
+            || self.volumes.iter().any(|vol| pending(&vol.opens, vol.frozen))
+    }
+
+    fn finish_open(&mut self, done: Settled<OpenWalk, Result<u32, Error>>) {
+        let Settled { reply, badge, work, result } = done;
+        let path = String::from(work.walk.path());
+        let opened = self.fs.as_mut().ok_or(Error::NotInitialized).and_then(|fs| {
+            fs.open_resolved(badge, &path, result, work.flags, work.mode)
+        });
+        let utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        match opened.and_then(|handle| self.insert_handle(handle, path, badge)) {
+            Ok(id) => {
+                utcb.set_msg_tag(MsgTag::ok());
+                utcb.set_mr(0, id);
+            }
+            Err(e) => {
+                utcb.set_msg_tag(MsgTag::err());
+                utcb.set_mr(0, e as usize);
+            }
+        }
+        self.wire.seal(badge.bits(), utcb);
+        if let Err(e) = reply.reply(utcb) {
+            glenda::log!("ExtFS: cannot answer a parked open: {:?}", e);
+        }
+    }
+
+    // Takes over the notification endpoint transferred with the call
+    fn start_job(
+        &mut self,
+        badge: glenda::ipc::Badge,
+        job: Box<dyn Job<ExtFs>>,
+    ) -> Result<usize, Error> {
+        let (id, slot) = self.jobs.vacant()?;
+        CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+        self.jobs.start(id, badge, job)?;
+        Ok(id)
+    }
+
+    fn run_jobs(&mut self) {
+        if self.frozen {
+            return;
+        }
+        if let Some(fs) = self.fs.as_mut() {
+            let attrs = &mut self.attrs;
+            self.jobs.run(fs, |path| attrs.invalidate(path));
+        }
+    }
+
+    // Periodic writeback of the selected volume's dirty inodes
+    fn writeback_tick(&mut self) {
+        if self.check_writable().is_err() {
+            return;
+        }
+        if let Some(fs) = self.fs.as_mut() {
+            if let Err(e) = fs.writeback_tick() {
+                glenda::log!("ExtFS: writeback failed: {:?}", e);
+            }
+        }
+    }
 }
 
 impl<'a> SystemService for Ext4Service<'a> {
@@ -76,27 +806,55 @@ impl<'a> SystemService for Ext4Service<'a> {
 
     fn listen(&mut self, ep: Endpoint, reply: CapPtr, recv: CapPtr) -> Result<(), Error> {
         self.endpoint = ep;
+        self.mount_endpoint = ep;
         self.reply = Reply::from(reply);
         self.recv = recv;
         Ok(())
     }
 
     fn run(&mut self) -> Result<(), Error> {
+        match self.mount_point.register(self.mount_endpoint) {
+            Ok(()) => self.events.publish(events::EV_MOUNT, self.mounted_options()),
+            Err(e) => glenda::log!("ExtFS: cannot mount with the VFS: {:?}", e),
+        }
         self.running = true;
         while self.running {
             let mut utcb = unsafe { UTCB::new() };
             utcb.clear();
             utcb.set_reply_window(self.reply.cap());
             utcb.set_recv_window(RECV_SLOT);
+            if self.opens_pending() {
+                let _ = self.endpoint.notify(proto::WAKE_BITS);
+            }
 
             if self.endpoint.recv(&mut utcb).is_ok() {
-                if let Err(e) = self.dispatch(&mut utcb) {
-                    utcb.set_msg_tag(MsgTag::err());
-                    utcb.set_mr(0, e as usize);
+                let badge = utcb.get_badge().bits();
+                match ring::notification(utcb) {
+                    Some(bits) => self.doorbell(badge, bits),
+                    None => self.serve(badge, utcb),
+                }
+            }
+            for index in 0..self.volumes.len() {
+                if self.select(index).is_ok() {
+                    self.walk_opens();
+                    self.run_jobs();
+                    self.writeback_tick();
                 }
-                let _ = self.reply(&mut utcb);
             }
         }
+        for index in 0..self.volumes.len() {
+            if self.select(index).is_err() {
+                continue;
+            }
+            // The first volume was closed by the call that stopped the service
+            if index != 0 && self.fs.is_some() {
+                if let Err(e) = self.close_volume() {
+                    glenda::log!("ExtFS: writeback of volume {} failed: {:?}", index, e);
+                }
+            }
+            self.release_volume();
+            self.walk_opens();
+        }
         Ok(())
     }
 
@@ -106,44 +864,606 @@ impl<'a> SystemService for Ext4Service<'a> {
             self, utcb,
             (FS_PROTO, glenda::protocol::fs::OPEN) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
-                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
                     let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(0));
+                    if proto::open_mutates(flags) {
+                        s.check_writable()?;
+                    }
                     let mode = u_inner.get_mr(1) as u32;
-                    let path = "mock_path"; // TODO: read path from IPC buffer
+                    let path = String::from(path::from_buffer(u_inner.buffer())?);
+
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let file_handle = match snapshots::open(s.snapshots.as_mut(), &path, flags) {
+                        Some(opened) => opened?,
+                        None => {
+                            if flags.contains(OpenFlags::O_TRUNC) {
+                                fs.save_version(badge, &path)?;
+                            }
+                            fs.open_handle(badge, &path, flags, mode)?
+                        }
+                    };
+                    let id = s.insert_handle(file_handle, path, badge)?;
+
+                    u_inner.set_mr(0, id);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::OPEN_ASYNC) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.park_open(badge, u_inner))
+            },
+            (FS_PROTO, proto::OPENAT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let base = owned(&s.handles, badge, u_inner.get_mr(0))?;
+                    if !base.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(1));
+                    if proto::open_mutates(flags) {
+                        s.check_writable()?;
+                    }
+                    let mode = u_inner.get_mr(2) as u32;
+                    let path = path::join(&base.path, path::from_buffer(u_inner.buffer())?);
 
-                    let file_handle = fs.open_handle(badge, path, flags, mode)?;
-                    let id = s.next_handle_id;
-                    s.next_handle_id += 1;
-                    s.handles.insert(id, file_handle);
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let file_handle = match snapshots::open(s.snapshots.as_mut(), &path, flags) {
+                        Some(opened) => opened?,
+                        None => {
+                            if flags.contains(OpenFlags::O_TRUNC) {
+                                fs.save_version(badge, &path)?;
+                            }
+                            fs.open_handle(badge, &path, flags, mode)?
+                        }
+                    };
+                    let id = s.insert_handle(file_handle, path, badge)?;
 
                     u_inner.set_mr(0, id);
                     Ok(())
                 })
             },
+            (FS_PROTO, proto::DUP) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    entry.refs += 1;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::CLONE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned(&s.handles, badge, u_inner.get_mr(0))?;
+                    if entry.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let handle = Box::new(ReadOnly::new(entry.handle.duplicate()?));
+                    let path = entry.path.clone();
+                    let id = s.insert_handle(handle, path, badge)?;
+                    u_inner.set_mr(0, id);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::LOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = u_inner.get_mr(0);
+                    let entry = owned(&s.handles, badge, id)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    s.locks.lock(&entry.path, id, start, len, u_inner.get_mr(3))
+                })
+            },
+            (FS_PROTO, proto::UNLOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = u_inner.get_mr(0);
+                    let entry = owned(&s.handles, badge, id)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    let grants = s.locks.unlock(&entry.path, id, start, len)?;
+                    s.complete_grants(grants);
+                    Ok(())
+                })
+            },
+            // MR0: handle, MR1: client address of the shared memory, MR2: its size;
+            // the memory's frame comes with the call
+            (FS_PROTO, glenda::protocol::fs::SETUP_IOURING) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = u_inner.get_mr(0);
+                    let user_vaddr = u_inner.get_mr(1);
+                    let size = u_inner.get_mr(2);
+                    if size == 0 || size % 4096 != 0 {
+                        return Err(Error::InvalidArgs);
+                    }
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let entry = owned_mut(&mut s.handles, badge, id)?;
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                    let server_vaddr = s.maps.reserve(size, PGSIZE)?;
+                    let mapped = s.vspace.map_frame(
+                        Frame::from(slot),
+                        server_vaddr,
+                        Perms::READ | Perms::WRITE,
+                        size / 4096,
+                        s.res_client,
+                        s.slots.cspace(),
+                    );
+                    if let Err(e) = mapped {
+                        let _ = s.maps.release(server_vaddr);
+                        return Err(e);
+                    }
+                    let ring = SharedRing::attach(server_vaddr, user_vaddr, size);
+                    if let Some(old) = entry.ring.replace(ring) {
+                        s.unmap_ring(&old);
+                    }
+                    Ok(())
+                })
+            },
+            // MR0: handle, MR1: bits; the endpoint to notify comes with the call
+            (FS_PROTO, proto::RING_NOTIFY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    let ring = entry.ring.as_mut().ok_or(Error::InvalidArgs)?;
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                    let signal = RingSignal {
+                        notify: Endpoint::from(slot),
+                        bits: u_inner.get_mr(1),
+                        badge: badge.bits(),
+                    };
+                    if let Some(old) = ring.set_signal(signal) {
+                        CSPACE_CAP.delete(old.notify.cap())?;
+                        s.slots.free(old.notify.cap());
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, glenda::protocol::fs::PROCESS_IOURING) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.process_ring(u_inner.get_mr(0), badge))
+            },
+            (FS_PROTO, glenda::protocol::fs::CLOSE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = u_inner.get_mr(0);
+                    let entry = owned_mut(&mut s.handles, badge, id)?;
+                    entry.refs -= 1;
+                    if entry.refs == 0 {
+                        let grants = s.locks.release(id);
+                        s.complete_grants(grants);
+                        if let Some(mut entry) = s.handles.remove(&id) {
+                            if let Some(ring) = entry.ring.take() {
+                                s.unmap_ring(&ring);
+                            }
+                            entry.handle.close(badge)?;
+                        }
+                        s.release_orphans()?;
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, glenda::protocol::fs::GETDENTS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    let count = core::cmp::min(
+                        u_inner.get_mr(1),
+                        proto::dents_capacity(u_inner.buffer()),
+                    );
+                    let entries = entry.handle.getdents(badge, count)?;
+                    let written = proto::encode_dents(u_inner.buffer_mut(), &entries);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
             (FS_PROTO, glenda::protocol::fs::MKDIR) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
+                    s.check_writable()?;
                     let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
                     let mode = u_inner.get_mr(0) as u32;
-                    let path = "mock_path";
+                    let path = path::from_buffer(u_inner.buffer())?;
                     fs.mkdir(badge, path, mode)?;
+                    s.attrs.invalidate(path);
                     Ok(())
                 })
             },
             (FS_PROTO, glenda::protocol::fs::UNLINK) => |s: &mut Self, u: &mut UTCB| {
-                handle_call(u, |_u_inner| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
                     let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
-                    let path = "mock_path";
+                    let path = path::from_buffer(u_inner.buffer())?;
                     fs.unlink(badge, path)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::GETXATTR) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned(&s.handles, badge, u_inner.get_mr(0))?;
+                    let value = entry.handle.getxattr(path::from_buffer(u_inner.buffer())?)?;
+                    let buf = u_inner.buffer_mut();
+                    if value.len() > buf.len() {
+                        return Err(Error::MessageTooLong);
+                    }
+                    buf[..value.len()].copy_from_slice(&value);
+                    u_inner.set_buffer_len(value.len());
+                    u_inner.set_mr(0, value.len());
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::LISTXATTR) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned(&s.handles, badge, u_inner.get_mr(0))?;
+                    let names = entry.handle.listxattr()?;
+                    let buf = u_inner.buffer_mut();
+                    let mut len = 0;
+                    for name in &names {
+                        let end = len + name.len() + 1;
+                        if end > buf.len() {
+                            return Err(Error::MessageTooLong);
+                        }
+                        buf[len..end - 1].copy_from_slice(name.as_bytes());
+                        buf[end - 1] = 0;
+                        len = end;
+                    }
+                    u_inner.set_buffer_len(len);
+                    u_inner.set_mr(0, len);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::LINK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let (old_path, new_path) = path::pair_from_buffer(u_inner.buffer())?;
+                    let ino = fs.stat_path(badge, old_path)?.ino;
+                    fs.link(badge, old_path, new_path)?;
+                    // The link count went up under every name the file has
+                    s.attrs.invalidate(old_path);
+                    s.attrs.invalidate(new_path);
+                    s.attrs.invalidate_ino(ino);
+                    Ok(())
+                })
+            },
+            // buffer: path, MR0-MR3: access and modification times; see proto::SET_TIMES.
+            (FS_PROTO, proto::SET_TIMES) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let req = TimesRequest::from_utcb(u_inner)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    fs.set_times(badge, path, req)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::RMTREE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    if u_inner.get_mr(0) & proto::JOB_ASYNC != 0 {
+                        let id = s.start_job(badge, Box::new(RmtreeJob::new(badge, path)?))?;
+                        u_inner.set_mr(0, id);
+                        return Ok(());
+                    }
+                    fs.rmtree(badge, path)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::JOB_STATUS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let status = s.jobs.status(badge, u_inner.get_mr(0))?;
+                    let (state, error) = status.state.encode();
+                    u_inner.set_mr(0, state);
+                    u_inner.set_mr(1, status.done);
+                    u_inner.set_mr(2, status.total);
+                    u_inner.set_mr(3, error);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::JOB_CANCEL) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.jobs.cancel(badge, u_inner.get_mr(0)))
+            },
+            (FS_PROTO, proto::JOB_RELEASE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let slot = s.jobs.release(badge, u_inner.get_mr(0))?;
+                    CSPACE_CAP.delete(slot)
+                })
+            },
+            (FS_PROTO, proto::REMOUNT_RO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.read_only = true;
+                    s.events.publish(events::EV_REMOUNT_RO, 0);
+                    s.jobs.cancel_all();
+                    // Best effort: the device may already be failing. Handles
+                    // first, as writing out their buffers dirties metadata
+                    for entry in s.handles.values_mut() {
+                        let _ = entry.handle.sync(badge);
+                    }
+                    if let Some(fs) = s.fs.as_ref() {
+                        let _ = fs.sync_all();
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::REMOUNT_RW) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    let features = s.fs.as_ref().ok_or(Error::NotInitialized)?.features();
+                    if !features.writable() {
+                        return Err(features.error());
+                    }
+                    // Only a new MOUNT_OPTIONS lifts a read-only mount
+                    if s.options.read_only {
+                        return Err(Error::PermissionDenied);
+                    }
+                    s.fs.as_ref().ok_or(Error::NotInitialized)?.mark_in_use()?;
+                    s.read_only = false;
+                    s.events.publish(events::EV_REMOUNT_RW, 0);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::FREEZE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    // Handles first: writing out their buffers dirties metadata
+                    for entry in s.handles.values_mut() {
+                        entry.handle.sync(badge)?;
+                    }
+                    fs.sync_all()?;
+                    s.frozen = true;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::THAW) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    if !s.frozen {
+                        return Err(Error::InvalidArgs);
+                    }
+                    s.frozen = false;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::MOUNT_OPTIONS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let options = MountOptions::from_bits(u_inner.get_mr(0))?;
+                    if !s.handles.is_empty() {
+                        return Err(Error::WouldBlock);
+                    }
+                    s.fs.as_mut().ok_or(Error::NotInitialized)?.set_mount_options(options);
+                    if options.casefold != s.options.casefold {
+                        s.attrs = AttrCache::new(ATTR_CACHE_SIZE).with_casefold(options.casefold);
+                    }
+                    s.options = options;
+                    u_inner.set_mr(0, s.mounted_options());
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::HISTORY_LIST) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    fs.access(badge, path::from_buffer(u_inner.buffer())?, MAY_READ)?;
+                    history::list(fs, u_inner)
+                })
+            },
+            (FS_PROTO, proto::HISTORY_RESTORE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    fs.access(badge, path, MAY_WRITE)?;
+                    history::restore(fs, path, u_inner.get_mr(0) as u64)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::UNMOUNT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.shutdown()
+                })
+            },
+            (FS_PROTO, proto::MOUNT_AT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    s.mount_point.move_to(path, s.mount_endpoint)?;
+                    if !path.is_empty() {
+                        s.events.publish(events::EV_MOUNT, s.mounted_options());
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::CHECK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let report = s.check(u_inner.get_mr(0) & proto::CHECK_REPAIR != 0)?;
+                    u_inner.set_mr(0, report.checked);
+                    u_inner.set_mr(1, report.issues.len());
+                    u_inner.set_mr(2, report.repaired);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::ATTACH_DEVICE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.attach(badge, u_inner))
+            },
+            (FS_PROTO, proto::FORMAT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.format(badge, u_inner))
+            },
+            (FS_PROTO, proto::RESIZE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    s.check_writable()?;
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let size = fs.grow(u_inner.get_mr(0))?;
+                    glenda::log!("ExtFS: volume is {} bytes now", size);
+                    u_inner.set_mr(0, size);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::TRIM) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    s.check_writable()?;
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let trimmed = fs.trim(u_inner.get_mr(0))?;
+                    glenda::log!("ExtFS: discarded {} free bytes", trimmed);
+                    u_inner.set_mr(0, trimmed);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::VOLUME_INFO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    s.device.encode(u_inner, fs.io_tuning())
+                })
+            },
+            (FS_PROTO, proto::VOLUME_STATS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.io_error_counts().encode(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::STATFS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.stats()?.encode(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::HEAT_EXPORT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let flags = u_inner.get_mr(0);
+                    fs.heat().encode(u_inner, flags)
+                })
+            },
+            (FS_PROTO, proto::CBT_EPOCH) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.changes().next_epoch(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::CBT_QUERY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.changes().query(u_inner)
+                })
+            },
+            (FS_PROTO, proto::VERSION) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.versions.negotiate(badge.bits(), u_inner))
+            },
+            (FS_PROTO, proto::WIRE_PROTECT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.wire.configure(badge.bits(), u_inner))
+            },
+            (FS_PROTO, proto::SET_OP_MASK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.policy.configure(badge.bits(), u_inner))
+            },
+            (FS_PROTO, proto::SET_CREDS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    fs.credentials_mut().configure(badge.bits(), u_inner)
+                })
+            },
+            (FS_PROTO, proto::SET_CLOCK) => |_s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| clock::configure(badge.bits(), u_inner))
+            },
+            // MR0: events::EV_* bits; the endpoint to notify comes with the call.
+            // Without an endpoint, ends the subscription.
+            (FS_PROTO, proto::EVENT_SUBSCRIBE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let old = if u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        let slot = s.slots.alloc(s.res_client)?;
+                        CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                        let mask = u_inner.get_mr(0);
+                        match s.events.subscribe(badge.bits(), mask, Endpoint::from(slot)) {
+                            Ok(old) => old,
+                            Err(e) => {
+                                CSPACE_CAP.delete(slot)?;
+                                s.slots.free(slot);
+                                return Err(e);
+                            }
+                        }
+                    } else {
+                        s.events.unsubscribe(badge.bits())
+                    };
+                    if let Some(old) = old {
+                        CSPACE_CAP.delete(old.cap())?;
+                        s.slots.free(old.cap());
+                    }
+                    u_inner.set_mr(0, s.events.next_seq() as usize);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::EVENT_READ) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.events.read(u_inner))
+            },
+            (FS_PROTO, proto::AUDIT_READ) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.audit.read(u_inner))
+            },
+            (FS_PROTO, proto::CACHE_TUNE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let tunables = fs.cache_tunables().update(u_inner)?;
+                    fs.set_cache_tunables(tunables);
+                    tunables.encode(u_inner);
                     Ok(())
                 })
             },
             (FS_PROTO, glenda::protocol::fs::STAT_PATH) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
                     let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
-                    let path = "mock_path";
-                    let stat = fs.stat_path(badge, path)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    // Cached attributes skip the permission checks of the walk
+                    let cached = if fs.cred(badge).is_root() { s.attrs.get(path) } else { None };
+                    let stat = match (cached, snapshots::stat(s.snapshots.as_mut(), path)) {
+                        (_, Some(snap)) => snap?,
+                        (Some(stat), None) => stat,
+                        (None, None) => {
+                            let stat = fs.stat_path(badge, path)?;
+                            s.attrs.insert(path, stat);
+                            stat
+                        }
+                    };
                     u_inner.set_mr(0, stat.size as usize);
                     u_inner.set_mr(1, stat.mode as usize);
+                    u_inner.set_mr(2, proto::ATTR_TIMEOUT_MS);
+                    u_inner.set_mr(3, stat.atime);
+                    u_inner.set_mr(4, stat.mtime);
+                    u_inner.set_mr(5, stat.ctime);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, glenda::protocol::fs::SEEK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    let offset = u_inner.get_mr(1) as i64;
+                    let pos = entry.handle.seek(badge, offset, u_inner.get_mr(2))?;
+                    u_inner.set_mr(0, pos);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, glenda::protocol::fs::TRUNCATE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    entry.handle.truncate(badge, u_inner.get_mr(1))?;
+                    s.attrs.invalidate(&entry.path);
+                    s.attrs.invalidate_ino(entry.ino);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::FALLOCATE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let (offset, len, flags) =
+                        (u_inner.get_mr(1), u_inner.get_mr(2), u_inner.get_mr(3));
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    entry.handle.fallocate(offset, len, flags)?;
+                    s.attrs.invalidate(&entry.path);
+                    s.attrs.invalidate_ino(entry.ino);
                     Ok(())
                 })
             },
@@ -152,15 +1472,45 @@ impl<'a> SystemService for Ext4Service<'a> {
                     let id = u_inner.get_mr(0);
                     let offset = u_inner.get_mr(1) as usize;
                     let len = u_inner.get_mr(2);
-                    let handle = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
+                    let entry = owned_mut(&mut s.handles, badge, id)?;
 
                     let mut buf = alloc::vec![0u8; len];
-                    let read_len = handle.read(badge, offset, &mut buf)?;
+                    let read_len = entry.handle.read(badge, offset, &mut buf)?;
+                    u_inner.set_mr(0, read_len);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::READ_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    let len = u_inner.get_mr(1);
+                    let buf = u_inner.buffer_mut();
+                    if len > buf.len() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let read_len = entry.handle.read(badge, CURRENT_OFFSET, &mut buf[..len])?;
+                    u_inner.set_buffer_len(read_len);
                     u_inner.set_mr(0, read_len);
                     Ok(())
                 })
             },
+            (FS_PROTO, proto::WRITE_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let entry = owned_mut(&mut s.handles, badge, u_inner.get_mr(0))?;
+                    let written = entry.handle.write(badge, CURRENT_OFFSET, u_inner.buffer())?;
+                    s.attrs.invalidate(&entry.path);
+                    s.attrs.invalidate_ino(entry.ino);
+                    u_inner.set_buffer_len(0);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
             (PROCESS_PROTO, process::EXIT) => |s: &mut Self, _u: &mut UTCB| {
+                // Best effort: the process goes away either way
+                if let Err(e) = s.shutdown() {
+                    glenda::log!("ExtFS: writeback at exit failed: {:?}", e);
+                }
                 s.running = false;
                 Ok(())
             }
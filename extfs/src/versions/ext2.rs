@@ -1,11 +1,24 @@
-use crate::block::BlockReader;
 use crate::defs::ext4::*;
 use crate::ops::ExtOps;
+use crate::volume::ExtVolume;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u32, put_le_u32};
+use fs_common::errors::FsError;
 use glenda::error::Error;
 
 pub struct Ext2Ops;
 
 impl Ext2Ops {
+    // i_block holds 15 little-endian block pointers in the non-extent layout
+    pub fn block_ptr(inode: &Inode, index: usize) -> Result<u32, Error> {
+        le_u32(&inode.i_block, index * 4)
+    }
+
+    pub fn set_block_ptr(inode: &mut Inode, index: usize, block: u32) -> Result<(), Error> {
+        put_le_u32(&mut inode.i_block, index * 4, block)
+    }
+
     pub fn resolve_indirect(
         reader: &BlockReader,
         block: u32,
@@ -15,8 +28,7 @@ impl Ext2Ops {
         let offset = block as usize * block_size as usize + index as usize * 4;
         let mut buf = [0u8; 4];
         reader.read_offset(offset, &mut buf)?;
-        let data = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const u32) };
-        Ok(data)
+        Ok(u32::from_le_bytes(buf))
     }
 
     pub fn get_block_addr_map(
@@ -25,13 +37,9 @@ impl Ext2Ops {
         lblock: u32,
         block_size: u32,
     ) -> Result<u32, Error> {
-        // Cast i_block to [u32; 15]
-        let blocks =
-            unsafe { core::slice::from_raw_parts(inode.i_block.as_ptr() as *const u32, 15) };
-
         // Direct blocks 0-11
         if lblock < 12 {
-            return Ok(unsafe { core::ptr::read_unaligned(&blocks[lblock as usize]) });
+            return Self::block_ptr(inode, lblock as usize);
         }
 
         let ptrs_per_block = block_size / 4;
@@ -39,7 +47,7 @@ impl Ext2Ops {
 
         // Indirect block 12
         if remaining < ptrs_per_block {
-            let indirect_block = unsafe { core::ptr::read_unaligned(&blocks[12]) };
+            let indirect_block = Self::block_ptr(inode, 12)?;
             if indirect_block == 0 {
                 return Ok(0);
             }
@@ -49,7 +57,7 @@ impl Ext2Ops {
 
         // Double indirect block 13
         if remaining < ptrs_per_block * ptrs_per_block {
-            let double_indirect = unsafe { core::ptr::read_unaligned(&blocks[13]) };
+            let double_indirect = Self::block_ptr(inode, 13)?;
             if double_indirect == 0 {
                 return Ok(0);
             }
@@ -68,7 +76,7 @@ impl Ext2Ops {
         remaining -= ptrs_per_block * ptrs_per_block;
 
         // Triple indirect block 14
-        let triple_indirect = unsafe { core::ptr::read_unaligned(&blocks[14]) };
+        let triple_indirect = Self::block_ptr(inode, 14)?;
         if triple_indirect == 0 {
             return Ok(0);
         }
@@ -93,6 +101,217 @@ impl Ext2Ops {
 
         Self::resolve_indirect(reader, indirect_block, third_idx, block_size)
     }
+
+    /// Block-map `block_run`: a missing indirect block is a hole as long as
+    /// the range it would map.
+    pub fn block_run_map(
+        reader: &BlockReader,
+        inode: &Inode,
+        lblock: u32,
+        block_size: u32,
+    ) -> Result<(bool, u64), Error> {
+        if lblock < 12 {
+            return Ok((Self::block_ptr(inode, lblock as usize)? != 0, 1));
+        }
+        let ptrs = (block_size / 4) as u64;
+        let mut rel = (lblock - 12) as u64;
+        // Slot in i_block and the blocks each level below it spans
+        let (slot, mut span) = if rel < ptrs {
+            (12, ptrs)
+        } else if rel - ptrs < ptrs * ptrs {
+            rel -= ptrs;
+            (13, ptrs * ptrs)
+        } else {
+            rel -= ptrs + ptrs * ptrs;
+            if rel >= ptrs * ptrs * ptrs {
+                return Ok((false, u64::MAX));
+            }
+            (14, ptrs * ptrs * ptrs)
+        };
+
+        let mut block = Self::block_ptr(inode, slot)?;
+        loop {
+            if block == 0 {
+                return Ok((false, span - rel % span));
+            }
+            if span == 1 {
+                return Ok((true, 1));
+            }
+            span /= ptrs;
+            block = Self::resolve_indirect(reader, block, (rel / span % ptrs) as u32, block_size)?;
+        }
+    }
+}
+
+impl Ext2Ops {
+    pub fn truncate_block_map(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first_free: u32,
+    ) -> Result<u64, Error> {
+        let mut freed = 0;
+
+        // Direct blocks 0-11
+        for index in core::cmp::min(first_free, 12)..12 {
+            let block = Self::block_ptr(inode, index as usize)?;
+            if block != 0 {
+                vol.free_blocks(reader, tid, block as u64, 1)?;
+                Self::set_block_ptr(inode, index as usize, 0)?;
+                freed += 1;
+            }
+        }
+
+        // Indirect (12), double indirect (13) and triple indirect (14) trees
+        let ptrs_per_block = (vol.block_size / 4) as u64;
+        let mut base = 12u64;
+        for (slot, level) in [(12usize, 1u32), (13, 2), (14, 3)] {
+            let span = ptrs_per_block.pow(level);
+            let root = Self::block_ptr(inode, slot)?;
+            if root != 0 && (first_free as u64) < base + span {
+                let keep = (first_free as u64).saturating_sub(base);
+                let (count, empty) = Self::truncate_indirect(vol, reader, tid, root, level, keep)?;
+                freed += count;
+                if empty {
+                    vol.free_blocks(reader, tid, root as u64, 1)?;
+                    Self::set_block_ptr(inode, slot, 0)?;
+                    freed += 1;
+                }
+            }
+            base += span;
+        }
+        Ok(freed)
+    }
+
+    // Frees entries at relative index `keep` and above in the `level`-deep tree
+    // rooted at `block`. Returns the blocks freed and whether the root is now empty.
+    fn truncate_indirect(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        block: u32,
+        level: u32,
+        keep: u64,
+    ) -> Result<(u64, bool), Error> {
+        let block_size = vol.block_size as usize;
+        let span = ((block_size / 4) as u64).pow(level - 1);
+        let mut buf = alloc::vec![0u8; block_size];
+        vol.read_block(reader, block as u64, &mut buf)?;
+
+        let mut freed = 0;
+        let mut dirty = false;
+        for i in 0..block_size / 4 {
+            let child = le_u32(&buf, i * 4)?;
+            let child_base = i as u64 * span;
+            if child == 0 || child_base + span <= keep {
+                continue;
+            }
+            if level > 1 {
+                let child_keep = keep.saturating_sub(child_base);
+                let (count, empty) =
+                    Self::truncate_indirect(vol, reader, tid, child, level - 1, child_keep)?;
+                freed += count;
+                if !empty {
+                    continue;
+                }
+            }
+            vol.free_blocks(reader, tid, child as u64, 1)?;
+            put_le_u32(&mut buf, i * 4, 0)?;
+            freed += 1;
+            dirty = true;
+        }
+
+        let empty = buf.iter().all(|&b| b == 0);
+        if dirty && !empty {
+            vol.log_block(reader, tid, block as u64, &buf)?;
+        }
+        Ok((freed, empty))
+    }
+
+    /// Block-map counterpart of `get_block_addr_map` that fills in missing
+    /// data and indirect blocks on the way down.
+    pub fn map_block_map(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        lblock: u32,
+        goal_group: u32,
+    ) -> Result<(u64, u32), Error> {
+        // Slot in i_block and the indices to follow through the indirect blocks
+        let ptrs = vol.block_size / 4;
+        let (slot, path): (usize, Vec<u32>) = if lblock < 12 {
+            (lblock as usize, Vec::new())
+        } else if lblock - 12 < ptrs {
+            (12, alloc::vec![lblock - 12])
+        } else if lblock - 12 - ptrs < ptrs * ptrs {
+            let rel = lblock - 12 - ptrs;
+            (13, alloc::vec![rel / ptrs, rel % ptrs])
+        } else {
+            let rel = lblock - 12 - ptrs - ptrs * ptrs;
+            (14, alloc::vec![rel / (ptrs * ptrs), (rel / ptrs) % ptrs, rel % ptrs])
+        };
+
+        let mut allocated = 0;
+        let mut block = Self::block_ptr(inode, slot)?;
+        if block == 0 {
+            block = Self::alloc_mapped(vol, reader, tid, goal_group)?;
+            Self::set_block_ptr(inode, slot, block)?;
+            allocated += 1;
+        }
+        for index in path {
+            let mut next = Self::resolve_indirect(reader, block, index, vol.block_size)?;
+            if next == 0 {
+                next = Self::alloc_mapped(vol, reader, tid, goal_group)?;
+                let mut buf = alloc::vec![0u8; vol.block_size as usize];
+                vol.read_block(reader, block as u64, &mut buf)?;
+                put_le_u32(&mut buf, index as usize * 4, next)?;
+                vol.log_block(reader, tid, block as u64, &buf)?;
+                allocated += 1;
+            }
+            block = next;
+        }
+        Ok((block as u64, allocated))
+    }
+
+    /// Maps every hole in `first..first + count`, with zeroed blocks.
+    /// Returns how many blocks that took, indirect ones included.
+    #[allow(clippy::too_many_arguments)]
+    pub fn preallocate_map(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first: u32,
+        count: u32,
+        goal_group: u32,
+    ) -> Result<u32, Error> {
+        let mut allocated = 0;
+        for lblock in first..first + count {
+            if Self::get_block_addr_map(reader, inode, lblock, vol.block_size)? == 0 {
+                allocated += Self::map_block_map(vol, reader, tid, inode, lblock, goal_group)?.1;
+            }
+        }
+        Ok(allocated)
+    }
+
+    // Block map pointers are 32 bits wide, so blocks above 2^32 cannot be mapped
+    fn alloc_mapped(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        goal_group: u32,
+    ) -> Result<u32, Error> {
+        let block = vol.alloc_block(reader, tid, goal_group)?;
+        match u32::try_from(block) {
+            Ok(block) => Ok(block),
+            Err(_) => {
+                vol.free_blocks(reader, tid, block, 1)?;
+                Err(FsError::NoSpace.into())
+            }
+        }
+    }
 }
 
 impl ExtOps for Ext2Ops {
@@ -102,7 +321,53 @@ impl ExtOps for Ext2Ops {
         inode: &Inode,
         lblock: u32,
         block_size: u32,
+    ) -> Result<u64, Error> {
+        Self::get_block_addr_map(reader, inode, lblock, block_size).map(u64::from)
+    }
+
+    fn block_run(
+        &self,
+        reader: &BlockReader,
+        inode: &Inode,
+        lblock: u32,
+        block_size: u32,
+    ) -> Result<(bool, u64), Error> {
+        Self::block_run_map(reader, inode, lblock, block_size)
+    }
+
+    fn map_block(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        lblock: u32,
+        goal_group: u32,
+    ) -> Result<(u64, u32), Error> {
+        Self::map_block_map(vol, reader, tid, inode, lblock, goal_group)
+    }
+
+    fn truncate_blocks(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first_free: u32,
+    ) -> Result<u64, Error> {
+        Self::truncate_block_map(vol, reader, tid, inode, first_free)
+    }
+
+    fn preallocate(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first: u32,
+        count: u32,
+        goal_group: u32,
     ) -> Result<u32, Error> {
-        Self::get_block_addr_map(reader, inode, lblock, block_size)
+        Self::preallocate_map(vol, reader, tid, inode, first, count, goal_group)
     }
 }
@@ -1,7 +1,8 @@
 use super::ext2::Ext2Ops;
-use crate::block::BlockReader;
 use crate::defs::ext4::Inode;
 use crate::ops::ExtOps;
+use crate::volume::ExtVolume;
+use fs_block::BlockReader;
 use glenda::error::Error;
 
 pub struct Ext3Ops;
@@ -13,9 +14,55 @@ impl ExtOps for Ext3Ops {
         inode: &Inode,
         lblock: u32,
         block_size: u32,
-    ) -> Result<u32, Error> {
+    ) -> Result<u64, Error> {
         // Ext3 uses generic block mapping (same as Ext2)
         // Journaling is handled at FS layer or separate service
-        Ext2Ops::get_block_addr_map(reader, inode, lblock, block_size)
+        Ext2Ops::get_block_addr_map(reader, inode, lblock, block_size).map(u64::from)
+    }
+
+    fn block_run(
+        &self,
+        reader: &BlockReader,
+        inode: &Inode,
+        lblock: u32,
+        block_size: u32,
+    ) -> Result<(bool, u64), Error> {
+        Ext2Ops::block_run_map(reader, inode, lblock, block_size)
+    }
+
+    fn map_block(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        lblock: u32,
+        goal_group: u32,
+    ) -> Result<(u64, u32), Error> {
+        Ext2Ops::map_block_map(vol, reader, tid, inode, lblock, goal_group)
+    }
+
+    fn truncate_blocks(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first_free: u32,
+    ) -> Result<u64, Error> {
+        Ext2Ops::truncate_block_map(vol, reader, tid, inode, first_free)
+    }
+
+    fn preallocate(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first: u32,
+        count: u32,
+        goal_group: u32,
+    ) -> Result<u32, Error> {
+        Ext2Ops::preallocate_map(vol, reader, tid, inode, first, count, goal_group)
     }
 }
@@ -1,27 +1,33 @@
 use super::ext2::Ext2Ops; // Reuse block map logic
-use crate::block::BlockReader;
 use crate::defs::ext4::{
-    Extent, ExtentHeader, ExtentIndex, Inode, EXT4_EXTENTS_FL, EXT4_EXT_MAGIC,
+    Extent, ExtentHeader, ExtentIndex, Inode, EXT4_EXTENTS_FL, EXT4_EXT_MAGIC, EXT_INIT_MAX_LEN,
 };
 use crate::ops::ExtOps;
-use core::mem::size_of;
+use crate::volume::ExtVolume;
+use alloc::vec;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::batch::{ReadBatch, SCAN_BATCH_BLOCKS};
+use fs_common::bytes::{FromBytes, ToBytes};
+use fs_common::errors::FsError;
+use fs_common::limits::ext_extent_max_file_size;
 use glenda::error::Error;
 
 pub struct Ext4Ops;
 
 impl Ext4Ops {
     // Helper to binary search extents in a block/buffer
-    fn search_extent_block(&self, data: &[u8], lblock: u32) -> Result<usize, Error> {
+    fn search_extent_block(&self, data: &[u8], lblock: u32) -> Result<u64, Error> {
         // data starts with ExtentHeader
-        let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const ExtentHeader) };
+        let header = ExtentHeader::from_bytes(data)?;
         if header.eh_magic != EXT4_EXT_MAGIC {
-            return Err(Error::DeviceError);
+            return Err(FsError::Corrupt.into());
         }
 
         let depth = header.eh_depth;
         let entries = header.eh_entries as usize;
-        let entry_size = size_of::<ExtentIndex>(); // 12 bytes. Extent is also 12 bytes.
-        let header_size = size_of::<ExtentHeader>(); // 12 bytes
+        let entry_size = <ExtentIndex as FromBytes>::SIZE; // 12 bytes. Extent is also 12 bytes.
+        let header_size = <ExtentHeader as FromBytes>::SIZE; // 12 bytes
 
         // Entries start at offset 12
         // We need to find the entry covering lblock.
@@ -32,14 +38,18 @@ impl Ext4Ops {
             // Leaf node: array of Extent
             for i in 0..entries {
                 let offset = header_size + i * entry_size;
-                let extent = unsafe {
-                    core::ptr::read_unaligned(data.as_ptr().add(offset) as *const Extent)
-                };
-                if lblock >= extent.ee_block && lblock < extent.ee_block + extent.ee_len as u32 {
+                let extent = Extent::from_bytes_at(data, offset)?;
+                let uninit = extent.ee_len > EXT_INIT_MAX_LEN;
+                let len = if uninit { extent.ee_len - EXT_INIT_MAX_LEN } else { extent.ee_len };
+                if lblock >= extent.ee_block && lblock < extent.ee_block + len as u32 {
+                    if uninit {
+                        // Unwritten: the blocks still hold what an earlier file left there
+                        return Ok(0);
+                    }
                     let relative = lblock - extent.ee_block;
                     let start_hi = (extent.ee_start_hi as u64) << 32;
                     let start_lo = extent.ee_start_lo as u64;
-                    return Ok((start_hi | start_lo) as usize + relative as usize);
+                    return Ok((start_hi | start_lo) + relative as u64);
                 }
             }
         } else {
@@ -47,19 +57,12 @@ impl Ext4Ops {
             // We need to find the last index where ei_block <= lblock
             for i in 0..entries {
                 let offset = header_size + i * entry_size;
-                let idx = unsafe {
-                    core::ptr::read_unaligned(data.as_ptr().add(offset) as *const ExtentIndex)
-                };
+                let idx = ExtentIndex::from_bytes_at(data, offset)?;
 
                 // Check next entry to see if we should go deeper here
                 let next_block = if i + 1 < entries {
                     let next_offset = header_size + (i + 1) * entry_size;
-                    let next_idx = unsafe {
-                        core::ptr::read_unaligned(
-                            data.as_ptr().add(next_offset) as *const ExtentIndex
-                        )
-                    };
-                    next_idx.ei_block
+                    ExtentIndex::from_bytes_at(data, next_offset)?.ei_block
                 } else {
                     u32::MAX
                 };
@@ -67,7 +70,7 @@ impl Ext4Ops {
                 if lblock >= idx.ei_block && lblock < next_block {
                     let leaf_block_hi = (idx.ei_leaf_hi as u64) << 32;
                     let leaf_block_lo = idx.ei_leaf_lo as u64;
-                    return Ok((leaf_block_hi | leaf_block_lo) as usize);
+                    return Ok(leaf_block_hi | leaf_block_lo);
                 }
             }
         }
@@ -76,6 +79,244 @@ impl Ext4Ops {
     }
 }
 
+impl Ext4Ops {
+    // Drops every mapping at or beyond `first_free` from the extent node in
+    // `node` (the inode root or a tree block), freeing data and emptied child
+    // blocks. The node is rewritten in place; the caller persists it.
+    fn truncate_extent_node(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        node: &mut [u8],
+        first_free: u32,
+    ) -> Result<u64, Error> {
+        let mut header = ExtentHeader::from_bytes(node)?;
+        if header.eh_magic != EXT4_EXT_MAGIC {
+            return Err(FsError::Corrupt.into());
+        }
+
+        let entry_size = <Extent as FromBytes>::SIZE;
+        let entry_offset = |i: usize| <ExtentHeader as FromBytes>::SIZE + i * entry_size;
+        let entries = header.eh_entries as usize;
+        let mut freed = 0;
+        let mut kept = 0;
+
+        if header.eh_depth == 0 {
+            for i in 0..entries {
+                let mut extent = Extent::from_bytes_at(node, entry_offset(i))?;
+                let uninit = extent.ee_len > EXT_INIT_MAX_LEN;
+                let len = if uninit { extent.ee_len - EXT_INIT_MAX_LEN } else { extent.ee_len };
+                let start = ((extent.ee_start_hi as u64) << 32) | extent.ee_start_lo as u64;
+                let first = extent.ee_block;
+
+                if first >= first_free {
+                    vol.free_blocks(reader, tid, start, len as u32)?;
+                    freed += len as u64;
+                    continue;
+                }
+                if first + len as u32 > first_free {
+                    let keep = (first_free - first) as u16;
+                    vol.free_blocks(reader, tid, start + keep as u64, (len - keep) as u32)?;
+                    freed += (len - keep) as u64;
+                    extent.ee_len = if uninit { keep + EXT_INIT_MAX_LEN } else { keep };
+                }
+                extent.to_bytes_at(node, entry_offset(kept))?;
+                kept += 1;
+            }
+        } else {
+            let block_size = vol.block_size as usize;
+            for chunk in (0..entries).step_by(SCAN_BATCH_BLOCKS) {
+                let chunk_end = core::cmp::min(chunk + SCAN_BATCH_BLOCKS, entries);
+                // Children of this run of entries the cut reaches, read in one
+                // batch; subtrees ending below the cut stay untouched. Entries
+                // are compacted towards the front, never past the one in hand.
+                let mut children = Vec::new();
+                for i in chunk..chunk_end {
+                    let idx = ExtentIndex::from_bytes_at(node, entry_offset(i))?;
+                    let next_first = if i + 1 < entries {
+                        ExtentIndex::from_bytes_at(node, entry_offset(i + 1))?.ei_block
+                    } else {
+                        u32::MAX
+                    };
+                    let child = ((idx.ei_leaf_hi as u64) << 32) | idx.ei_leaf_lo as u64;
+                    children
+                        .push((next_first > first_free).then(|| (child, vec![0u8; block_size])));
+                }
+                let mut batch = ReadBatch::new();
+                for (child, buf) in children.iter_mut().flatten() {
+                    batch.push((*child * block_size as u64) as usize, buf);
+                }
+                reader.submit(&mut batch)?;
+
+                for (i, child) in (chunk..chunk_end).zip(children) {
+                    let idx = ExtentIndex::from_bytes_at(node, entry_offset(i))?;
+                    if let Some((child, mut child_buf)) = child {
+                        freed += Self::truncate_extent_node(
+                            vol,
+                            reader,
+                            tid,
+                            &mut child_buf,
+                            first_free,
+                        )?;
+
+                        if ExtentHeader::from_bytes(&child_buf)?.eh_entries == 0 {
+                            vol.free_blocks(reader, tid, child, 1)?;
+                            freed += 1;
+                            continue;
+                        }
+                        vol.log_block(reader, tid, child, &child_buf)?;
+                    }
+                    idx.to_bytes_at(node, entry_offset(kept))?;
+                    kept += 1;
+                }
+            }
+            if kept == 0 {
+                // An empty tree collapses back to an empty leaf
+                header.eh_depth = 0;
+            }
+        }
+
+        header.eh_entries = kept as u16;
+        header.to_bytes(node)?;
+        Ok(freed)
+    }
+
+    // `block_run` below the extent node in `node`. Runs of holes end at the
+    // next extent, or at the next index entry for the subtrees of an index
+    // node; past the last extent they never end.
+    fn extent_run(
+        reader: &BlockReader,
+        node: &[u8],
+        lblock: u32,
+        block_size: u32,
+    ) -> Result<(bool, u64), Error> {
+        let header = ExtentHeader::from_bytes(node)?;
+        if header.eh_magic != EXT4_EXT_MAGIC {
+            return Err(FsError::Corrupt.into());
+        }
+        let entry_offset =
+            |i: usize| <ExtentHeader as FromBytes>::SIZE + i * <Extent as FromBytes>::SIZE;
+
+        if header.eh_depth == 0 {
+            for i in 0..header.eh_entries as usize {
+                let extent = Extent::from_bytes_at(node, entry_offset(i))?;
+                let uninit = extent.ee_len > EXT_INIT_MAX_LEN;
+                let len = if uninit { extent.ee_len - EXT_INIT_MAX_LEN } else { extent.ee_len };
+                if lblock < extent.ee_block {
+                    return Ok((false, (extent.ee_block - lblock) as u64));
+                }
+                if lblock - extent.ee_block < len as u32 {
+                    return Ok((!uninit, (extent.ee_block + len as u32 - lblock) as u64));
+                }
+            }
+            return Ok((false, u64::MAX));
+        }
+
+        // The last index starting at or before `lblock`, and where the next one starts
+        let mut child = None;
+        let mut limit = u64::MAX;
+        for i in 0..header.eh_entries as usize {
+            let idx = ExtentIndex::from_bytes_at(node, entry_offset(i))?;
+            if idx.ei_block > lblock {
+                limit = (idx.ei_block - lblock) as u64;
+                break;
+            }
+            child = Some(((idx.ei_leaf_hi as u64) << 32) | idx.ei_leaf_lo as u64);
+        }
+        let Some(child) = child else {
+            return Ok((false, limit));
+        };
+        let mut buf = vec![0u8; block_size as usize];
+        reader.read_offset((child * block_size as u64) as usize, &mut buf)?;
+        let (mapped, run) = Self::extent_run(reader, &buf, lblock, block_size)?;
+        Ok((mapped, run.min(limit)))
+    }
+
+    // Covers the holes in `first..first + count` of a tree that is just its
+    // root leaf with unwritten extents. Blocks are claimed without zeroing
+    // since unwritten extents read as zeros; adjacent ones share an extent.
+    // NotSupported once the root has no room left, as the tree cannot grow.
+    fn preallocate_extents(
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        root: &mut [u8],
+        first: u32,
+        count: u32,
+        goal_group: u32,
+    ) -> Result<u32, Error> {
+        let mut header = ExtentHeader::from_bytes(root)?;
+        if header.eh_magic != EXT4_EXT_MAGIC {
+            return Err(FsError::Corrupt.into());
+        }
+        if header.eh_depth != 0 {
+            return Err(Error::NotSupported);
+        }
+        let entry_offset =
+            |i: usize| <ExtentHeader as FromBytes>::SIZE + i * <Extent as FromBytes>::SIZE;
+        let mut extents = Vec::new();
+        for i in 0..header.eh_entries as usize {
+            extents.push(Extent::from_bytes_at(root, entry_offset(i))?);
+        }
+        let mapped = |lblock: u32| {
+            extents.iter().any(|e| {
+                let len = if e.ee_len > EXT_INIT_MAX_LEN {
+                    e.ee_len - EXT_INIT_MAX_LEN
+                } else {
+                    e.ee_len
+                };
+                lblock >= e.ee_block && lblock < e.ee_block + len as u32
+            })
+        };
+
+        // New extents as (first logical block, first physical block, length)
+        let mut added: Vec<(u32, u64, u16)> = Vec::new();
+        let mut claim = || -> Result<(), Error> {
+            for lblock in first..first + count {
+                if mapped(lblock) {
+                    continue;
+                }
+                let block = vol.claim_block(reader, tid, goal_group)?;
+                match added.last_mut() {
+                    Some((l, p, len))
+                        if *l + *len as u32 == lblock
+                            && *p + *len as u64 == block
+                            && *len < EXT_INIT_MAX_LEN - 1 =>
+                    {
+                        *len += 1
+                    }
+                    _ => added.push((lblock, block, 1)),
+                }
+            }
+            if extents.len() + added.len() > header.eh_max as usize {
+                return Err(Error::NotSupported);
+            }
+            Ok(())
+        };
+        if let Err(e) = claim() {
+            for &(_, block, len) in &added {
+                vol.free_blocks(reader, tid, block, len as u32)?;
+            }
+            return Err(e);
+        }
+
+        let allocated = added.iter().map(|&(_, _, len)| len as u32).sum();
+        extents.extend(added.into_iter().map(|(lblock, block, len)| Extent {
+            ee_block: lblock,
+            ee_len: len + EXT_INIT_MAX_LEN,
+            ee_start_hi: (block >> 32) as u16,
+            ee_start_lo: block as u32,
+        }));
+        extents.sort_by_key(|e| e.ee_block);
+        for (i, extent) in extents.iter().enumerate() {
+            extent.to_bytes_at(root, entry_offset(i))?;
+        }
+        header.eh_entries = extents.len() as u16;
+        header.to_bytes(root)?;
+        Ok(allocated)
+    }
+}
+
 impl ExtOps for Ext4Ops {
     fn get_block_addr(
         &self,
@@ -83,19 +324,18 @@ impl ExtOps for Ext4Ops {
         inode: &Inode,
         lblock: u32,
         block_size: u32,
-    ) -> Result<u32, Error> {
+    ) -> Result<u64, Error> {
         if (inode.i_flags & EXT4_EXTENTS_FL) == 0 {
-            return Ext2Ops::get_block_addr_map(reader, inode, lblock, block_size);
+            return Ext2Ops::get_block_addr_map(reader, inode, lblock, block_size).map(u64::from);
         }
 
         // Extents
         // i_block[0..60] contains the root node (Header + entries)
         let root_data = &inode.i_block; // [u8; 60]
 
-        let header =
-            unsafe { core::ptr::read_unaligned(root_data.as_ptr() as *const ExtentHeader) };
+        let header = ExtentHeader::from_bytes(root_data)?;
         if header.eh_magic != EXT4_EXT_MAGIC {
-            return Err(Error::DeviceError);
+            return Err(FsError::Corrupt.into());
         }
 
         let mut current_block_data = [0u8; 4096]; // Buffer for tree traversal
@@ -112,8 +352,7 @@ impl ExtOps for Ext4Ops {
 
         // If depth == 0, root is leaf
         if depth == 0 {
-            let physical = self.search_extent_block(root_data, lblock)?;
-            return Ok(physical as u32);
+            return self.search_extent_block(root_data, lblock);
         }
 
         // BFS/DFS down
@@ -128,7 +367,7 @@ impl ExtOps for Ext4Ops {
 
         while curr_depth > 0 {
             reader.read_offset(
-                curr_phys * block_size as usize,
+                (curr_phys * block_size as u64) as usize,
                 &mut current_block_data[0..block_size as usize],
             )?;
 
@@ -145,6 +384,69 @@ impl ExtOps for Ext4Ops {
         }
 
         // Found physical block of data
-        Ok(curr_phys as u32)
+        Ok(curr_phys)
+    }
+
+    fn block_run(
+        &self,
+        reader: &BlockReader,
+        inode: &Inode,
+        lblock: u32,
+        block_size: u32,
+    ) -> Result<(bool, u64), Error> {
+        if (inode.i_flags & EXT4_EXTENTS_FL) == 0 {
+            return Ext2Ops::block_run_map(reader, inode, lblock, block_size);
+        }
+        Self::extent_run(reader, &inode.i_block, lblock, block_size)
+    }
+
+    fn map_block(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        lblock: u32,
+        goal_group: u32,
+    ) -> Result<(u64, u32), Error> {
+        if (inode.i_flags & EXT4_EXTENTS_FL) == 0 {
+            return Ext2Ops::map_block_map(vol, reader, tid, inode, lblock, goal_group);
+        }
+        // Growing an extent tree is not supported yet; files created here use the block map
+        Err(Error::NotSupported)
+    }
+
+    fn truncate_blocks(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first_free: u32,
+    ) -> Result<u64, Error> {
+        if (inode.i_flags & EXT4_EXTENTS_FL) == 0 {
+            return Ext2Ops::truncate_block_map(vol, reader, tid, inode, first_free);
+        }
+        Self::truncate_extent_node(vol, reader, tid, &mut inode.i_block, first_free)
+    }
+
+    fn preallocate(
+        &self,
+        vol: &ExtVolume,
+        reader: &BlockReader,
+        tid: usize,
+        inode: &mut Inode,
+        first: u32,
+        count: u32,
+        goal_group: u32,
+    ) -> Result<u32, Error> {
+        if (inode.i_flags & EXT4_EXTENTS_FL) == 0 {
+            return Ext2Ops::preallocate_map(vol, reader, tid, inode, first, count, goal_group);
+        }
+        Self::preallocate_extents(vol, reader, tid, &mut inode.i_block, first, count, goal_group)
+    }
+
+    fn max_file_size(&self, block_size: u32) -> u64 {
+        ext_extent_max_file_size(block_size)
     }
 }
@@ -0,0 +1,1104 @@
+use crate::defs::ext4::*;
+use crate::icache::{InodeCache, INODE_CACHE_SIZE};
+use crate::ops::ExtOps;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u16, le_u32, put_le_u32, FromBytes, ToBytes};
+use fs_common::crc::{crc16, crc32c};
+use fs_common::errors::FsError;
+use fs_common::scrub::ScrubReport;
+use fs_common::statfs::FsStats;
+use fs_common::zeroing;
+use glenda::error::Error;
+use spin::Mutex;
+
+// Backup superblocks compared against the primary by the mount-time scrub
+const SCRUB_SB_BACKUPS: usize = 4;
+// A group added by `grow` with less room than this for data is left off
+const GROW_MIN_GROUP_DATA: u64 = 64;
+// Slot of the double indirect block in a block-mapped inode's i_block
+const DIND_BLOCK: usize = 13;
+
+/// Volume state shared between `ExtFs` and its open handles.
+///
+/// Like `ExtOps`, it does not own a `BlockReader`; callers pass theirs in.
+/// Metadata writes go through `log_block` under a transaction id so they can
+/// be journaled.
+pub struct ExtVolume {
+    pub block_size: u32,
+    pub group_desc_size: u16,
+    pub inodes_per_group: u32,
+    pub ops: Arc<dyn ExtOps>,
+    inode_size: u16,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    desc_64bit: bool,
+    // Also serializes bitmap and group descriptor updates
+    sb: Mutex<SuperBlock>,
+    icache: Mutex<InodeCache>,
+    next_tid: AtomicUsize,
+    // Set while s_state says the volume is mounted read-write
+    in_use: AtomicBool,
+    // Handles alive on each inode; an inode unlinked while listed here waits
+    // on the orphan list for the last of them to go
+    open: Mutex<BTreeMap<u32, usize>>,
+}
+
+impl ExtVolume {
+    pub fn new(sb: SuperBlock, ops: Arc<dyn ExtOps>) -> Self {
+        let desc_64bit = (sb.s_feature_incompat & EXT4_FEATURE_INCOMPAT_64BIT) != 0;
+        // 64BIT filesystems carry the hi halves, so the descriptor is never shorter than 64
+        let group_desc_size = if desc_64bit {
+            core::cmp::max(sb.s_desc_size, EXT4_MIN_DESC_SIZE_64BIT)
+        } else {
+            EXT4_MIN_DESC_SIZE
+        };
+        Self {
+            block_size: 1024 << sb.s_log_block_size,
+            group_desc_size,
+            inodes_per_group: sb.s_inodes_per_group,
+            ops,
+            inode_size: sb.s_inode_size,
+            first_data_block: sb.s_first_data_block,
+            blocks_per_group: sb.s_blocks_per_group,
+            desc_64bit,
+            sb: Mutex::new(sb),
+            icache: Mutex::new(InodeCache::new(INODE_CACHE_SIZE)),
+            next_tid: AtomicUsize::new(1),
+            in_use: AtomicBool::new(false),
+            open: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn transaction_start(&self) -> usize {
+        self.next_tid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Ends transaction `tid`. Without a journal its blocks went in place as
+    /// they were logged; the barrier has them on the medium before the
+    /// commit reports success, and ahead of anything written after it.
+    pub fn transaction_commit(&self, reader: &BlockReader, _tid: usize) -> Result<(), Error> {
+        reader.barrier()
+    }
+
+    pub fn transaction_abort(&self, _tid: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn log_block(
+        &self,
+        reader: &BlockReader,
+        _tid: usize,
+        block: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let sector = block as usize * (self.block_size as usize / 512);
+        reader.write_blocks(sector, data)
+    }
+
+    pub fn read_block(
+        &self,
+        reader: &BlockReader,
+        block: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        reader.read_offset(block as usize * self.block_size as usize, buf)?;
+        Ok(())
+    }
+
+    // Read-modify-write of `len` bytes at byte `offset`, logged as whole blocks
+    fn update_bytes(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        offset: usize,
+        len: usize,
+        f: impl FnOnce(&mut [u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let bs = self.block_size as usize;
+        let block = offset / bs;
+        let in_block = offset % bs;
+        if in_block + len > bs {
+            return Err(Error::InternalError);
+        }
+        let mut buf = alloc::vec![0u8; bs];
+        self.read_block(reader, block as u64, &mut buf)?;
+        f(&mut buf[in_block..in_block + len])?;
+        self.log_block(reader, tid, block as u64, &buf)
+    }
+
+    fn group_desc_offset(&self, group: u32) -> usize {
+        let first_bg_block = self.first_data_block as u64 + 1;
+        let offset =
+            first_bg_block * self.block_size as u64 + group as u64 * self.group_desc_size as u64;
+        offset as usize
+    }
+
+    pub fn read_group_desc(&self, reader: &BlockReader, group: u32) -> Result<GroupDesc, Error> {
+        let mut buf = [0u8; 64];
+        reader.read_offset(self.group_desc_offset(group), &mut buf)?;
+        GroupDesc::from_bytes(&buf)
+    }
+
+    fn write_group_desc(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        group: u32,
+        gd: &GroupDesc,
+    ) -> Result<(), Error> {
+        let len = core::cmp::min(<GroupDesc as FromBytes>::SIZE, self.group_desc_size as usize);
+        self.update_bytes(reader, tid, self.group_desc_offset(group), len, |buf| {
+            let mut full = [0u8; <GroupDesc as FromBytes>::SIZE];
+            full[..len].copy_from_slice(buf);
+            gd.to_bytes(&mut full)?;
+            buf.copy_from_slice(&full[..len]);
+            Ok(())
+        })
+    }
+
+    fn write_super(&self, reader: &BlockReader, tid: usize, sb: &SuperBlock) -> Result<(), Error> {
+        self.update_bytes(reader, tid, SUPER_BLOCK_OFFSET, <SuperBlock as FromBytes>::SIZE, |buf| {
+            sb.to_bytes(buf)
+        })
+    }
+
+    fn inode_offset(&self, reader: &BlockReader, ino: u32) -> Result<usize, Error> {
+        if ino < 1 {
+            return Err(Error::NotFound);
+        }
+        let group = (ino - 1) / self.inodes_per_group;
+        let index = (ino - 1) % self.inodes_per_group;
+        let gd = self.read_group_desc(reader, group)?;
+        let table_block = self.group_inode_table(&gd);
+        let offset = table_block * self.block_size as u64 + index as u64 * self.inode_size as u64;
+        Ok(offset as usize)
+    }
+
+    pub fn read_inode(&self, reader: &BlockReader, ino: u32) -> Result<Inode, Error> {
+        if let Some(inode) = self.icache.lock().get(ino) {
+            return Ok(inode);
+        }
+        let inode = self.read_inode_raw(reader, ino)?;
+        self.icache.lock().insert(ino, inode);
+        Ok(inode)
+    }
+
+    /// The whole on-disk slot of `ino`, with what follows the base inode.
+    pub fn read_inode_slot(&self, reader: &BlockReader, ino: u32) -> Result<Vec<u8>, Error> {
+        let offset = self.inode_offset(reader, ino)?;
+        let mut buf = alloc::vec![0u8; self.inode_size as usize];
+        reader.read_offset(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_inode_raw(&self, reader: &BlockReader, ino: u32) -> Result<Inode, Error> {
+        let offset = self.inode_offset(reader, ino)?;
+        let mut buf = [0u8; 256];
+        reader.read_offset(offset, &mut buf)?;
+        let mut inode = Inode::from_bytes(&buf)?;
+        inode.clear_unstored(self.inode_size as usize)?;
+        Ok(inode)
+    }
+
+    /// i_extra_isize for new inodes: as much of what Inode knows as the slot holds.
+    pub fn extra_isize(&self) -> u16 {
+        let spare = (self.inode_size as usize).saturating_sub(EXT4_GOOD_OLD_INODE_SIZE);
+        core::cmp::min(spare, EXT4_INODE_EXTRA_SIZE) as u16
+    }
+
+    pub fn write_inode(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        ino: u32,
+        inode: &Inode,
+    ) -> Result<(), Error> {
+        let offset = self.inode_offset(reader, ino)?;
+        // Only what the slot holds: in-inode attributes may follow the extra fields
+        let len = inode.stored_len(self.inode_size as usize);
+        self.update_bytes(reader, tid, offset, len, |buf| {
+            let mut raw = [0u8; <Inode as ToBytes>::SIZE];
+            inode.to_bytes(&mut raw)?;
+            buf.copy_from_slice(&raw[..len]);
+            Ok(())
+        })?;
+        self.icache.lock().written(ino, *inode);
+        Ok(())
+    }
+
+    /// Keeps a change to `ino` in the inode cache, written out with the next
+    /// flush_inodes, for updates not worth a transaction (access times).
+    pub fn write_inode_lazy(&self, ino: u32, inode: &Inode) {
+        self.icache.lock().mark_dirty(ino, *inode);
+    }
+
+    /// Returns `count` blocks starting at `start` to the block bitmaps.
+    pub fn free_blocks(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        start: u64,
+        count: u32,
+    ) -> Result<(), Error> {
+        let mut sb = self.sb.lock();
+        let bs = self.block_size as usize;
+        let mut bitmap = alloc::vec![0u8; bs];
+        let mut block = start;
+        let end = start + count as u64;
+
+        while block < end {
+            if block < self.first_data_block as u64 {
+                return Err(Error::DeviceError);
+            }
+            let rel = block - self.first_data_block as u64;
+            let group = (rel / self.blocks_per_group as u64) as u32;
+            let first_bit = (rel % self.blocks_per_group as u64) as usize;
+            let run =
+                core::cmp::min(end - block, (self.blocks_per_group as usize - first_bit) as u64)
+                    as usize;
+
+            let mut gd = self.read_group_desc(reader, group)?;
+            let bitmap_block = self.group_block_bitmap(&gd);
+            self.read_block(reader, bitmap_block, &mut bitmap)?;
+
+            let mut cleared = 0u32;
+            for bit in first_bit..first_bit + run {
+                let mask = 1u8 << (bit % 8);
+                if (bitmap[bit / 8] & mask) != 0 {
+                    bitmap[bit / 8] &= !mask;
+                    cleared += 1;
+                }
+            }
+            self.log_block(reader, tid, bitmap_block, &bitmap)?;
+
+            let free = self.group_free_blocks(&gd) + cleared;
+            set_group_free_blocks(&mut gd, free);
+            self.write_group_desc(reader, tid, group, &gd)?;
+            let sb_free = free_blocks(&sb) + cleared as u64;
+            set_free_blocks(&mut sb, sb_free);
+
+            block += run as u64;
+        }
+
+        self.write_super(reader, tid, &sb)?;
+        let _ = reader.discard(start as usize * bs, count as usize * bs);
+        Ok(())
+    }
+
+    /// Discards every run of free blocks at least `min_blocks` long, group by
+    /// group. Groups whose block bitmap was never initialised are passed
+    /// over. Returns the blocks discarded.
+    pub fn trim(&self, reader: &BlockReader, min_blocks: u64) -> Result<u64, Error> {
+        let sb = self.sb.lock();
+        let total = self.blocks_count(&sb);
+        let mut bitmap = alloc::vec![0u8; self.block_size as usize];
+        let mut trimmed = 0;
+        for group in 0..self.group_count(&sb) {
+            let gd = self.read_group_desc(reader, group)?;
+            if gd.bg_flags & EXT4_BG_BLOCK_UNINIT != 0 || self.group_free_blocks(&gd) == 0 {
+                continue;
+            }
+            let group_start =
+                self.first_data_block as u64 + group as u64 * self.blocks_per_group as u64;
+            let bits = (self.blocks_per_group as u64).min(total - group_start) as usize;
+            self.read_block(reader, self.group_block_bitmap(&gd), &mut bitmap)?;
+            let mut bit = 0;
+            while let Some(first) = first_clear_bit(&bitmap, bit, bits) {
+                let end =
+                    (first..bits).find(|&b| bitmap[b / 8] & (1 << (b % 8)) != 0).unwrap_or(bits);
+                let len = (end - first) as u64;
+                if len >= min_blocks {
+                    let bs = self.block_size as usize;
+                    reader.discard((group_start as usize + first) * bs, len as usize * bs)?;
+                    trimmed += len;
+                }
+                bit = end;
+            }
+        }
+        Ok(trimmed)
+    }
+
+    /// STATFS counts. The free counts are summed over the group
+    /// descriptors, which allocation keeps current along with the superblock.
+    pub fn stats(&self, reader: &BlockReader) -> Result<FsStats, Error> {
+        let sb = self.sb.lock();
+        let (mut free_blocks, mut free_inodes) = (0u64, 0u64);
+        for group in 0..self.group_count(&sb) {
+            let gd = self.read_group_desc(reader, group)?;
+            free_blocks += self.group_free_blocks(&gd) as u64;
+            free_inodes += self.group_free_inodes(&gd) as u64;
+        }
+        let reserved = (sb.s_r_blocks_count_hi as u64) << 32 | sb.s_r_blocks_count_lo as u64;
+        Ok(FsStats {
+            block_size: self.block_size as usize,
+            total_blocks: self.blocks_count(&sb),
+            free_blocks,
+            avail_blocks: free_blocks.saturating_sub(reserved),
+            total_inodes: sb.s_inodes_count as u64,
+            free_inodes,
+        })
+    }
+
+    /// Compares a few backup superblocks against the primary and verifies the
+    /// group descriptor checksums, without writing anything.
+    pub fn scrub(&self, reader: &BlockReader, report: &mut ScrubReport) -> Result<(), Error> {
+        let sb = *self.sb.lock();
+        let groups = self.group_count(&sb);
+        self.scrub_backup_supers(reader, &sb, groups, report)?;
+        self.scrub_group_descs(reader, &sb, groups, report)
+    }
+
+    fn scrub_backup_supers(
+        &self,
+        reader: &BlockReader,
+        sb: &SuperBlock,
+        groups: u32,
+        report: &mut ScrubReport,
+    ) -> Result<(), Error> {
+        let sparse = (sb.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER) != 0;
+        let mut buf = [0u8; 1024];
+        let backups = (1..groups).filter(|&g| !sparse || has_sparse_super(g));
+        for group in backups.take(SCRUB_SB_BACKUPS) {
+            let block = group as u64 * self.blocks_per_group as u64 + self.first_data_block as u64;
+            reader.read_offset(block as usize * self.block_size as usize, &mut buf)?;
+            let backup = SuperBlock::from_bytes(&buf)?;
+            let same = backup.s_magic == sb.s_magic
+                && backup.s_inodes_count == sb.s_inodes_count
+                && backup.s_blocks_count_lo == sb.s_blocks_count_lo
+                && backup.s_blocks_per_group == sb.s_blocks_per_group
+                && backup.s_inodes_per_group == sb.s_inodes_per_group
+                && backup.s_log_block_size == sb.s_log_block_size
+                && backup.s_uuid == sb.s_uuid;
+            report.check(same, || format!("backup superblock in group {} disagrees", group));
+        }
+        Ok(())
+    }
+
+    fn scrub_group_descs(
+        &self,
+        reader: &BlockReader,
+        sb: &SuperBlock,
+        groups: u32,
+        report: &mut ScrubReport,
+    ) -> Result<(), Error> {
+        let metadata_csum = (sb.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_METADATA_CSUM) != 0;
+        let gdt_csum = (sb.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_GDT_CSUM) != 0;
+        if !metadata_csum && !gdt_csum {
+            return Ok(());
+        }
+        let uuid = sb.s_uuid;
+        let seed = if (sb.s_feature_incompat & EXT4_FEATURE_INCOMPAT_CSUM_SEED) != 0 {
+            sb.s_checksum_seed
+        } else {
+            crc32c(!0, &uuid)
+        };
+
+        let size = self.group_desc_size as usize;
+        let mut desc = alloc::vec![0u8; size];
+        for group in 0..groups {
+            reader.read_offset(self.group_desc_offset(group), &mut desc)?;
+            let le_group = group.to_le_bytes();
+            let tail = desc.get(EXT4_BG_CHECKSUM_OFFSET + 2..).unwrap_or(&[]);
+            let expected = if metadata_csum {
+                let mut crc = crc32c(seed, &le_group);
+                crc = crc32c(crc, &desc[..EXT4_BG_CHECKSUM_OFFSET]);
+                crc = crc32c(crc, &[0, 0]);
+                crc32c(crc, tail) as u16
+            } else {
+                let mut crc = crc16(!0, &uuid);
+                crc = crc16(crc, &le_group);
+                crc = crc16(crc, &desc[..EXT4_BG_CHECKSUM_OFFSET]);
+                crc16(crc, tail)
+            };
+            let stored = le_u16(&desc, EXT4_BG_CHECKSUM_OFFSET)?;
+            report.check(stored == expected, || {
+                format!(
+                    "group {} descriptor checksum {:#06x}, expected {:#06x}",
+                    group, stored, expected
+                )
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the volume was left mounted read-write, by a crash or a driver
+    /// that never marked it clean, or has errors recorded.
+    pub fn unclean(&self) -> bool {
+        let state = self.sb.lock().s_state;
+        state & EXT4_VALID_FS == 0 || state & EXT4_ERROR_FS != 0
+    }
+
+    /// Clears EXT4_VALID_FS for as long as the volume is mounted read-write,
+    /// so a crash leaves it marked for checking.
+    pub fn mark_in_use(&self, reader: &BlockReader) -> Result<(), Error> {
+        if self.in_use.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.set_state(reader, |state| state & !EXT4_VALID_FS)
+    }
+
+    /// Sets EXT4_VALID_FS again once everything is written out.
+    pub fn mark_clean(&self, reader: &BlockReader) -> Result<(), Error> {
+        if !self.in_use.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.set_state(reader, |state| state | EXT4_VALID_FS)
+    }
+
+    // The state is written between barriers, as a journal writes its commit
+    // record: a clean mark never reaches the medium ahead of what it vouches
+    // for, and metadata never reaches it ahead of the mark saying the volume
+    // is in use.
+    fn set_state(&self, reader: &BlockReader, f: impl FnOnce(u16) -> u16) -> Result<(), Error> {
+        let mut sb = self.sb.lock();
+        sb.s_state = f(sb.s_state);
+        reader.barrier()?;
+        let tid = self.transaction_start();
+        match self.write_super(reader, tid, &sb) {
+            // The commit is the second barrier
+            Ok(()) => self.transaction_commit(reader, tid),
+            Err(e) => {
+                self.transaction_abort(tid)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// The allocation side of the fsck-lite check: each group's free block
+    /// and inode counts against its bitmaps, the superblock totals against
+    /// the groups, and the orphan list. Given a transaction, counts that
+    /// disagree are rewritten from the bitmaps, which are what allocation
+    /// goes by. Orphans are only reported.
+    pub fn check(
+        &self,
+        reader: &BlockReader,
+        repair: Option<usize>,
+        report: &mut ScrubReport,
+    ) -> Result<(), Error> {
+        let mut sb = self.sb.lock();
+        let groups = self.group_count(&sb);
+        let total = self.blocks_count(&sb);
+        let mut bitmap = alloc::vec![0u8; self.block_size as usize];
+        let (mut free_blocks_sum, mut free_inodes_sum) = (0u64, 0u64);
+
+        for group in 0..groups {
+            let mut gd = self.read_group_desc(reader, group)?;
+            let stored_blocks = self.group_free_blocks(&gd);
+            let stored_inodes = self.group_free_inodes(&gd);
+            let (mut counted_blocks, mut counted_inodes) = (stored_blocks, stored_inodes);
+            if gd.bg_flags & EXT4_BG_BLOCK_UNINIT == 0 {
+                let group_start =
+                    self.first_data_block as u64 + group as u64 * self.blocks_per_group as u64;
+                let bits = (self.blocks_per_group as u64).min(total - group_start) as usize;
+                self.read_block(reader, self.group_block_bitmap(&gd), &mut bitmap)?;
+                counted_blocks = clear_bits(&bitmap, bits) as u32;
+                report.check(counted_blocks == stored_blocks, || {
+                    format!(
+                        "group {} has {} free blocks, its descriptor says {}",
+                        group, counted_blocks, stored_blocks
+                    )
+                });
+            }
+            if gd.bg_flags & EXT4_BG_INODE_UNINIT == 0 {
+                self.read_block(reader, self.group_inode_bitmap(&gd), &mut bitmap)?;
+                counted_inodes = clear_bits(&bitmap, self.inodes_per_group as usize) as u32;
+                report.check(counted_inodes == stored_inodes, || {
+                    format!(
+                        "group {} has {} free inodes, its descriptor says {}",
+                        group, counted_inodes, stored_inodes
+                    )
+                });
+            }
+            free_blocks_sum += counted_blocks as u64;
+            free_inodes_sum += counted_inodes as u64;
+
+            let fixes = (counted_blocks != stored_blocks) as usize
+                + (counted_inodes != stored_inodes) as usize;
+            if let (Some(tid), true) = (repair, fixes > 0) {
+                set_group_free_blocks(&mut gd, counted_blocks);
+                set_group_free_inodes(&mut gd, counted_inodes);
+                self.write_group_desc(reader, tid, group, &gd)?;
+                report.repaired += fixes;
+            }
+        }
+
+        let stored_blocks = free_blocks(&sb);
+        let stored_inodes = sb.s_free_inodes_count as u64;
+        report.check(free_blocks_sum == stored_blocks, || {
+            format!(
+                "groups have {} free blocks, the superblock says {}",
+                free_blocks_sum, stored_blocks
+            )
+        });
+        report.check(free_inodes_sum == stored_inodes, || {
+            format!(
+                "groups have {} free inodes, the superblock says {}",
+                free_inodes_sum, stored_inodes
+            )
+        });
+        let fixes = (free_blocks_sum != stored_blocks) as usize
+            + (free_inodes_sum != stored_inodes) as usize;
+        if let (Some(tid), true) = (repair, fixes > 0) {
+            set_free_blocks(&mut sb, free_blocks_sum);
+            sb.s_free_inodes_count = free_inodes_sum as u32;
+            self.write_super(reader, tid, &sb)?;
+            report.repaired += fixes;
+        }
+
+        // Linked through i_dtime; bounded in case the chain loops
+        let mut next = sb.s_last_orphan;
+        drop(sb);
+        let mut left = self.inodes_count();
+        while next != 0 && left > 0 {
+            // Expected while a handle keeps an unlinked file alive
+            report.check(self.is_open(next), || format!("inode {} is on the orphan list", next));
+            next = self.read_inode(reader, next)?.i_dtime;
+            left -= 1;
+        }
+        Ok(())
+    }
+
+    /// Head of the orphan list: inodes unlinked or truncated while still in
+    /// use, chained through i_dtime, whose blocks are yet to be freed.
+    pub fn last_orphan(&self) -> u32 {
+        self.sb.lock().s_last_orphan
+    }
+
+    pub fn set_last_orphan(&self, reader: &BlockReader, tid: usize, ino: u32) -> Result<(), Error> {
+        let mut sb = self.sb.lock();
+        sb.s_last_orphan = ino;
+        self.write_super(reader, tid, &sb)
+    }
+
+    /// Counts a handle created on `ino`.
+    pub fn hold_inode(&self, ino: u32) {
+        *self.open.lock().entry(ino).or_insert(0) += 1;
+    }
+
+    /// Counts a handle on `ino` gone.
+    pub fn release_inode(&self, ino: u32) {
+        let mut open = self.open.lock();
+        if let Some(count) = open.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&ino);
+            }
+        }
+    }
+
+    pub fn is_open(&self, ino: u32) -> bool {
+        self.open.lock().contains_key(&ino)
+    }
+
+    pub fn inodes_count(&self) -> u32 {
+        self.sb.lock().s_inodes_count
+    }
+
+    /// Grows the volume to `blocks` blocks: the last group is filled out
+    /// and groups are added after it, as far as whole groups with room for
+    /// their metadata go. Descriptors past the end of the descriptor table
+    /// take over its reserved blocks from the resize inode. The superblock,
+    /// written last, is what makes the new groups part of the volume.
+    /// Returns the block count now.
+    pub fn grow(&self, reader: &BlockReader, tid: usize, blocks: u64) -> Result<u64, Error> {
+        let mut sb = self.sb.lock();
+        let old_blocks = self.blocks_count(&sb);
+        if blocks < old_blocks {
+            return Err(Error::InvalidArgs);
+        }
+        let bs = self.block_size as usize;
+        let bpg = self.blocks_per_group as u64;
+        let fdb = self.first_data_block as u64;
+        let old_groups = self.group_count(&sb);
+        let desc_per_block = (bs / self.group_desc_size as usize) as u32;
+        let old_gdt = old_groups.div_ceil(desc_per_block);
+        let resize_inode = sb.s_feature_compat & EXT4_FEATURE_COMPAT_RESIZE_INODE != 0;
+        let reserved = if resize_inode { sb.s_reserved_gdt_blocks as u32 } else { 0 };
+        // Blocks after each superblock copy: the table, then its reserve
+        let table_span = old_gdt + reserved;
+
+        // Inode numbers are 32 bits, and without 64BIT so is the block count
+        let block_limit = if self.desc_64bit { u64::MAX } else { u32::MAX as u64 };
+        let group_limit = (u32::MAX / self.inodes_per_group).min(table_span * desc_per_block);
+        let mut blocks = blocks.min(block_limit).min(fdb + group_limit as u64 * bpg);
+        let mut groups = (blocks - fdb).div_ceil(bpg) as u32;
+        if groups > old_groups {
+            let last = groups - 1;
+            let len = blocks - fdb - last as u64 * bpg;
+            if len < self.new_group_overhead(&sb, last, table_span) as u64 + GROW_MIN_GROUP_DATA {
+                groups -= 1;
+                blocks = fdb + groups as u64 * bpg;
+            }
+        }
+        if blocks <= old_blocks {
+            let table_full = old_groups >= table_span * desc_per_block;
+            return if table_full { Err(Error::NotSupported) } else { Ok(old_blocks) };
+        }
+        // The device must have the last block before anything refers to it
+        let mut buf = alloc::vec![0u8; bs];
+        self.read_block(reader, blocks - 1, &mut buf)?;
+
+        let mut added_free = 0u64;
+        // The old last group's bitmap marks the blocks past its end in use
+        let last = old_groups - 1;
+        let last_start = fdb + last as u64 * bpg;
+        let (old_len, new_len) = (old_blocks - last_start, (blocks - last_start).min(bpg));
+        if new_len > old_len {
+            let mut gd = self.read_group_desc(reader, last)?;
+            let bitmap_block = self.group_block_bitmap(&gd);
+            self.read_block(reader, bitmap_block, &mut buf)?;
+            for bit in old_len as usize..new_len as usize {
+                buf[bit / 8] &= !(1 << (bit % 8));
+            }
+            self.log_block(reader, tid, bitmap_block, &buf)?;
+            let free = self.group_free_blocks(&gd) + (new_len - old_len) as u32;
+            set_group_free_blocks(&mut gd, free);
+            self.write_group_desc(reader, tid, last, &gd)?;
+            added_free += new_len - old_len;
+        }
+
+        let new_gdt = groups.div_ceil(desc_per_block);
+        for index in old_gdt..new_gdt {
+            self.take_reserved_gdt(reader, tid, index)?;
+        }
+        sb.s_reserved_gdt_blocks -= (new_gdt - old_gdt) as u16;
+
+        let itable_blocks =
+            (self.inodes_per_group as usize * self.inode_size as usize).div_ceil(bs);
+        for group in old_groups..groups {
+            let start = fdb + group as u64 * bpg;
+            let len = (blocks - start).min(bpg) as usize;
+            let backup = self.has_backup(&sb, group);
+            let bitmap_block = start + if backup { 1 + table_span as u64 } else { 0 };
+            let overhead = self.new_group_overhead(&sb, group, table_span) as usize;
+
+            buf.fill(0);
+            set_bits(&mut buf, 0..overhead);
+            set_bits(&mut buf, len..bs * 8);
+            self.log_block(reader, tid, bitmap_block, &buf)?;
+            buf.fill(0);
+            set_bits(&mut buf, self.inodes_per_group as usize..bs * 8);
+            self.log_block(reader, tid, bitmap_block + 1, &buf)?;
+            let table = (bitmap_block + 2) as usize * bs;
+            zeroing::fill(table, itable_blocks * bs, |at, zeros| {
+                reader.write_blocks(at / 512, zeros)
+            })?;
+            if backup && resize_inode {
+                for index in new_gdt..table_span {
+                    self.add_reserved_backup(reader, tid, index, start + 1 + index as u64)?;
+                }
+            }
+
+            let mut gd = GroupDesc::from_bytes(&[0u8; <GroupDesc as FromBytes>::SIZE])?;
+            set_group_locations(&mut gd, bitmap_block);
+            set_group_free_blocks(&mut gd, (len - overhead) as u32);
+            set_group_free_inodes(&mut gd, self.inodes_per_group);
+            self.write_group_desc(reader, tid, group, &gd)?;
+            added_free += (len - overhead) as u64;
+        }
+
+        let added_inodes = (groups - old_groups) * self.inodes_per_group;
+        let reserved_blocks = (sb.s_r_blocks_count_hi as u64) << 32 | sb.s_r_blocks_count_lo as u64;
+        let reserved_blocks =
+            (reserved_blocks as u128 * blocks as u128 / old_blocks as u128) as u64;
+        sb.s_r_blocks_count_lo = reserved_blocks as u32;
+        sb.s_r_blocks_count_hi = (reserved_blocks >> 32) as u32;
+        sb.s_blocks_count_lo = blocks as u32;
+        sb.s_blocks_count_hi = (blocks >> 32) as u32;
+        let sb_free = free_blocks(&sb) + added_free;
+        set_free_blocks(&mut sb, sb_free);
+        sb.s_inodes_count += added_inodes;
+        sb.s_free_inodes_count += added_inodes;
+
+        // Every backup gets the new counts and the whole descriptor table
+        let mut table = alloc::vec![0u8; new_gdt as usize * bs];
+        self.read_block(reader, fdb + 1, &mut table)?;
+        for group in (1..groups).filter(|&g| self.has_backup(&sb, g)) {
+            let start = fdb + group as u64 * bpg;
+            let mut backup = *sb;
+            backup.s_block_group_nr = group as u16;
+            self.update_bytes(
+                reader,
+                tid,
+                start as usize * bs,
+                <SuperBlock as ToBytes>::SIZE,
+                |b| backup.to_bytes(b),
+            )?;
+            self.log_block(reader, tid, start + 1, &table)?;
+        }
+        self.write_super(reader, tid, &sb)?;
+        Ok(blocks)
+    }
+
+    // Blocks at the start of a group `grow` adds: the superblock copy with
+    // the table and its reserve if the group has one, bitmaps, inode table
+    fn new_group_overhead(&self, sb: &SuperBlock, group: u32, table_span: u32) -> u32 {
+        let bs = self.block_size as usize;
+        let itable = (self.inodes_per_group as usize * self.inode_size as usize).div_ceil(bs);
+        let backup = if self.has_backup(sb, group) { 1 + table_span } else { 0 };
+        backup + 2 + itable as u32
+    }
+
+    fn has_backup(&self, sb: &SuperBlock, group: u32) -> bool {
+        let sparse = (sb.s_feature_ro_compat & EXT4_FEATURE_RO_COMPAT_SPARSE_SUPER) != 0;
+        group == 0 || !sparse || has_sparse_super(group)
+    }
+
+    // Turns reserved block `index` after the descriptor table into a table
+    // block: the resize inode lets go of it and of its copies in the backup
+    // groups, which are zeroed for the descriptors to come
+    fn take_reserved_gdt(&self, reader: &BlockReader, tid: usize, index: u32) -> Result<(), Error> {
+        let bs = self.block_size as usize;
+        let primary = self.first_data_block as u64 + 1 + index as u64;
+        let mut inode = self.read_inode(reader, RESIZE_INO)?;
+        let dind_block = le_u32(&inode.i_block, DIND_BLOCK * 4)? as u64;
+        let mut dind = alloc::vec![0u8; bs];
+        self.read_block(reader, dind_block, &mut dind)?;
+        let slot = index as usize % (bs / 4) * 4;
+        if dind_block == 0 || le_u32(&dind, slot)? as u64 != primary {
+            return Err(FsError::Corrupt.into());
+        }
+        // The reserved block lists where its copies are
+        let mut list = alloc::vec![0u8; bs];
+        self.read_block(reader, primary, &mut list)?;
+        let copies: Vec<u64> = (0..bs / 4)
+            .map(|i| le_u32(&list, i * 4).map(|b| b as u64))
+            .filter(|b| !matches!(b, Ok(0)))
+            .collect::<Result<_, _>>()?;
+        put_le_u32(&mut dind, slot, 0)?;
+        self.log_block(reader, tid, dind_block, &dind)?;
+        let sectors = (copies.len() as u32 + 1) * (bs / 512) as u32;
+        inode.i_blocks_lo = inode.i_blocks_lo.saturating_sub(sectors);
+        self.write_inode(reader, tid, RESIZE_INO, &inode)?;
+
+        for block in core::iter::once(primary).chain(copies) {
+            self.log_block(reader, tid, block, zeroing::zeros(bs))?;
+        }
+        Ok(())
+    }
+
+    // Records `copy` as a backup of reserved block `index` in the resize inode
+    fn add_reserved_backup(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        index: u32,
+        copy: u64,
+    ) -> Result<(), Error> {
+        let bs = self.block_size as usize;
+        let primary = self.first_data_block as u64 + 1 + index as u64;
+        let mut list = alloc::vec![0u8; bs];
+        self.read_block(reader, primary, &mut list)?;
+        let Some(slot) = (0..bs / 4).find(|&i| matches!(le_u32(&list, i * 4), Ok(0))) else {
+            return Err(FsError::Corrupt.into());
+        };
+        put_le_u32(&mut list, slot * 4, copy as u32)?;
+        self.log_block(reader, tid, primary, &list)?;
+        let mut inode = self.read_inode(reader, RESIZE_INO)?;
+        inode.i_blocks_lo += (bs / 512) as u32;
+        self.write_inode(reader, tid, RESIZE_INO, &inode)
+    }
+
+    /// Inode bitmap of `group`, one bit per inode from the group's first.
+    pub fn inode_bitmap(&self, reader: &BlockReader, group: u32) -> Result<Vec<u8>, Error> {
+        let gd = self.read_group_desc(reader, group)?;
+        let mut bitmap = alloc::vec![0u8; self.block_size as usize];
+        if gd.bg_flags & EXT4_BG_INODE_UNINIT == 0 {
+            self.read_block(reader, self.group_inode_bitmap(&gd), &mut bitmap)?;
+        }
+        Ok(bitmap)
+    }
+
+    /// Allocates a block, preferring `goal_group`, and hands it back zeroed.
+    pub fn alloc_block(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        goal_group: u32,
+    ) -> Result<u64, Error> {
+        let block = self.claim_block(reader, tid, goal_group)?;
+        self.log_block(reader, tid, block, zeroing::zeros(self.block_size as usize))?;
+        Ok(block)
+    }
+
+    /// Allocates a block, preferring `goal_group`, leaving whatever it holds;
+    /// for unwritten extents, which read as zeros regardless.
+    pub fn claim_block(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        goal_group: u32,
+    ) -> Result<u64, Error> {
+        let mut sb = self.sb.lock();
+        let groups = self.group_count(&sb);
+        let total = self.blocks_count(&sb);
+        let bs = self.block_size as usize;
+        let mut bitmap = alloc::vec![0u8; bs];
+
+        for i in 0..groups {
+            let group = (goal_group + i) % groups;
+            let mut gd = self.read_group_desc(reader, group)?;
+            let free = self.group_free_blocks(&gd);
+            if free == 0 {
+                continue;
+            }
+            let group_start =
+                self.first_data_block as u64 + group as u64 * self.blocks_per_group as u64;
+            let bits = core::cmp::min(self.blocks_per_group as u64, total - group_start) as usize;
+            let bitmap_block = self.group_block_bitmap(&gd);
+            self.read_block(reader, bitmap_block, &mut bitmap)?;
+            let Some(bit) = first_clear_bit(&bitmap, 0, bits) else {
+                continue;
+            };
+
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            self.log_block(reader, tid, bitmap_block, &bitmap)?;
+            set_group_free_blocks(&mut gd, free - 1);
+            self.write_group_desc(reader, tid, group, &gd)?;
+            let sb_free = free_blocks(&sb) - 1;
+            set_free_blocks(&mut sb, sb_free);
+            self.write_super(reader, tid, &sb)?;
+
+            return Ok(group_start + bit as u64);
+        }
+        Err(FsError::NoSpace.into())
+    }
+
+    /// Allocates an inode, preferring `goal_group`, with its on-disk slot zeroed.
+    pub fn alloc_inode(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        is_dir: bool,
+        goal_group: u32,
+    ) -> Result<u32, Error> {
+        let mut sb = self.sb.lock();
+        let groups = self.group_count(&sb);
+        let first_ino = if sb.s_rev_level == 0 { 11 } else { sb.s_first_ino };
+        let mut bitmap = alloc::vec![0u8; self.block_size as usize];
+
+        for i in 0..groups {
+            let group = (goal_group + i) % groups;
+            let mut gd = self.read_group_desc(reader, group)?;
+            let free = self.group_free_inodes(&gd);
+            if free == 0 {
+                continue;
+            }
+            let bitmap_block = self.group_inode_bitmap(&gd);
+            self.read_block(reader, bitmap_block, &mut bitmap)?;
+            let base = group * self.inodes_per_group + 1;
+            // Reserved inodes are normally marked in use already; never hand them out
+            let skip = first_ino.saturating_sub(base) as usize;
+            let Some(bit) = first_clear_bit(&bitmap, skip, self.inodes_per_group as usize) else {
+                continue;
+            };
+            // A clear bit with no free inodes counted means the counts are corrupt
+            let sb_free = sb.s_free_inodes_count.checked_sub(1).ok_or(Error::DeviceError)?;
+
+            bitmap[bit / 8] |= 1 << (bit % 8);
+            self.log_block(reader, tid, bitmap_block, &bitmap)?;
+            set_group_free_inodes(&mut gd, free - 1);
+            if is_dir {
+                let dirs = self.group_used_dirs(&gd);
+                set_group_used_dirs(&mut gd, dirs + 1);
+            }
+            self.write_group_desc(reader, tid, group, &gd)?;
+            sb.s_free_inodes_count = sb_free;
+            self.write_super(reader, tid, &sb)?;
+
+            let ino = base + bit as u32;
+            let offset = self.inode_offset(reader, ino)?;
+            self.update_bytes(reader, tid, offset, self.inode_size as usize, |buf| {
+                buf.fill(0);
+                Ok(())
+            })?;
+            self.icache.lock().invalidate(ino);
+            return Ok(ino);
+        }
+        Err(FsError::NoSpace.into())
+    }
+
+    /// Returns an inode to its group's inode bitmap.
+    pub fn free_inode(
+        &self,
+        reader: &BlockReader,
+        tid: usize,
+        ino: u32,
+        is_dir: bool,
+    ) -> Result<(), Error> {
+        let mut sb = self.sb.lock();
+        let index = ino.checked_sub(1).ok_or(Error::DeviceError)?;
+        let group = index / self.inodes_per_group;
+        let bit = (index % self.inodes_per_group) as usize;
+        let mut gd = self.read_group_desc(reader, group)?;
+        let bitmap_block = self.group_inode_bitmap(&gd);
+        let mut bitmap = alloc::vec![0u8; self.block_size as usize];
+        self.read_block(reader, bitmap_block, &mut bitmap)?;
+        if bitmap[bit / 8] & (1 << (bit % 8)) == 0 {
+            return Err(Error::DeviceError);
+        }
+
+        bitmap[bit / 8] &= !(1 << (bit % 8));
+        self.log_block(reader, tid, bitmap_block, &bitmap)?;
+        let free = self.group_free_inodes(&gd);
+        set_group_free_inodes(&mut gd, free + 1);
+        if is_dir {
+            let dirs = self.group_used_dirs(&gd);
+            set_group_used_dirs(&mut gd, dirs.saturating_sub(1));
+        }
+        self.write_group_desc(reader, tid, group, &gd)?;
+        sb.s_free_inodes_count += 1;
+        self.write_super(reader, tid, &sb)?;
+        self.icache.lock().invalidate(ino);
+        Ok(())
+    }
+
+    /// Writes back the inodes the cache holds dirty. Those not written when
+    /// an error stops the flush stay dirty.
+    pub fn flush_inodes(&self, reader: &BlockReader) -> Result<(), Error> {
+        let dirty = self.icache.lock().take_dirty();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        let tid = self.transaction_start();
+        for (i, (ino, inode)) in dirty.iter().enumerate() {
+            if let Err(e) = self.write_inode(reader, tid, *ino, inode) {
+                let mut icache = self.icache.lock();
+                for (ino, inode) in &dirty[i..] {
+                    icache.mark_dirty(*ino, *inode);
+                }
+                drop(icache);
+                self.transaction_abort(tid)?;
+                return Err(e);
+            }
+        }
+        self.transaction_commit(reader, tid)
+    }
+
+    pub fn icache_capacity(&self) -> usize {
+        self.icache.lock().capacity()
+    }
+
+    pub fn set_icache_capacity(&self, inodes: usize) {
+        self.icache.lock().set_capacity(inodes);
+    }
+
+    /// Inodes modified in the cache and not yet written.
+    pub fn dirty_inodes(&self) -> usize {
+        self.icache.lock().dirty_count()
+    }
+
+    /// Block group an inode lives in, used as the allocation goal for its blocks.
+    pub fn inode_group(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group
+    }
+
+    fn blocks_count(&self, sb: &SuperBlock) -> u64 {
+        let hi = if self.desc_64bit { sb.s_blocks_count_hi as u64 } else { 0 };
+        (hi << 32) | sb.s_blocks_count_lo as u64
+    }
+
+    fn group_count(&self, sb: &SuperBlock) -> u32 {
+        let blocks = self.blocks_count(sb) - self.first_data_block as u64;
+        blocks.div_ceil(self.blocks_per_group as u64) as u32
+    }
+
+    fn group_block_bitmap(&self, gd: &GroupDesc) -> u64 {
+        let hi = if self.desc_64bit { gd.bg_block_bitmap_hi as u64 } else { 0 };
+        (hi << 32) | gd.bg_block_bitmap_lo as u64
+    }
+
+    fn group_inode_bitmap(&self, gd: &GroupDesc) -> u64 {
+        let hi = if self.desc_64bit { gd.bg_inode_bitmap_hi as u64 } else { 0 };
+        (hi << 32) | gd.bg_inode_bitmap_lo as u64
+    }
+
+    fn group_inode_table(&self, gd: &GroupDesc) -> u64 {
+        let hi = if self.desc_64bit { gd.bg_inode_table_hi as u64 } else { 0 };
+        (hi << 32) | gd.bg_inode_table_lo as u64
+    }
+
+    fn group_free_blocks(&self, gd: &GroupDesc) -> u32 {
+        let hi = if self.desc_64bit { gd.bg_free_blocks_count_hi as u32 } else { 0 };
+        (hi << 16) | gd.bg_free_blocks_count_lo as u32
+    }
+
+    fn group_free_inodes(&self, gd: &GroupDesc) -> u32 {
+        let hi = if self.desc_64bit { gd.bg_free_inodes_count_hi as u32 } else { 0 };
+        (hi << 16) | gd.bg_free_inodes_count_lo as u32
+    }
+
+    fn group_used_dirs(&self, gd: &GroupDesc) -> u32 {
+        let hi = if self.desc_64bit { gd.bg_used_dirs_count_hi as u32 } else { 0 };
+        (hi << 16) | gd.bg_used_dirs_count_lo as u32
+    }
+}
+
+// The hi halves are ignored on 32-byte descriptors, so writing them is harmless
+fn set_group_free_blocks(gd: &mut GroupDesc, count: u32) {
+    gd.bg_free_blocks_count_lo = count as u16;
+    gd.bg_free_blocks_count_hi = (count >> 16) as u16;
+}
+
+fn set_group_free_inodes(gd: &mut GroupDesc, count: u32) {
+    gd.bg_free_inodes_count_lo = count as u16;
+    gd.bg_free_inodes_count_hi = (count >> 16) as u16;
+}
+
+fn set_group_used_dirs(gd: &mut GroupDesc, count: u32) {
+    gd.bg_used_dirs_count_lo = count as u16;
+    gd.bg_used_dirs_count_hi = (count >> 16) as u16;
+}
+
+fn free_blocks(sb: &SuperBlock) -> u64 {
+    (sb.s_free_blocks_count_hi as u64) << 32 | sb.s_free_blocks_count_lo as u64
+}
+
+fn set_free_blocks(sb: &mut SuperBlock, count: u64) {
+    sb.s_free_blocks_count_lo = count as u32;
+    sb.s_free_blocks_count_hi = (count >> 32) as u32;
+}
+
+// Places the bitmaps and inode table of a group one after another from `block`
+fn set_group_locations(gd: &mut GroupDesc, block: u64) {
+    gd.bg_block_bitmap_lo = block as u32;
+    gd.bg_block_bitmap_hi = (block >> 32) as u32;
+    gd.bg_inode_bitmap_lo = (block + 1) as u32;
+    gd.bg_inode_bitmap_hi = ((block + 1) >> 32) as u32;
+    gd.bg_inode_table_lo = (block + 2) as u32;
+    gd.bg_inode_table_hi = ((block + 2) >> 32) as u32;
+}
+
+pub fn set_bits(bitmap: &mut [u8], bits: core::ops::Range<usize>) {
+    for bit in bits {
+        bitmap[bit / 8] |= 1 << (bit % 8);
+    }
+}
+
+fn clear_bits(bitmap: &[u8], bits: usize) -> usize {
+    (0..bits).filter(|&bit| bitmap[bit / 8] & (1 << (bit % 8)) == 0).count()
+}
+
+fn first_clear_bit(bitmap: &[u8], from: usize, bits: usize) -> Option<usize> {
+    (from..bits).find(|&bit| bitmap.get(bit / 8).is_some_and(|byte| byte & (1 << (bit % 8)) == 0))
+}
+
+// With sparse_super, backups live only in groups 1 and powers of 3, 5 and 7
+pub fn has_sparse_super(group: u32) -> bool {
+    group == 1 || [3, 5, 7].iter().any(|&base| is_power_of(group, base))
+}
+
+fn is_power_of(mut n: u32, base: u32) -> bool {
+    if n == 0 {
+        return false;
+    }
+    while n % base == 0 {
+        n /= base;
+    }
+    n == 1
+}
@@ -0,0 +1,96 @@
+//! Reading extended attributes, kept in the spare space after the inode and
+//! in the block i_file_acl points at. Names are stored as an index standing
+//! for a prefix ("user.", "security.", ...) and the rest of the name.
+
+use crate::defs::ext4::*;
+use crate::volume::ExtVolume;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u16, le_u32, FromBytes};
+use glenda::error::Error;
+
+// Name prefixes by e_name_index; the ACL indexes carry the whole name
+const PREFIXES: [(u8, &str); 7] = [
+    (1, "user."),
+    (2, "system.posix_acl_access"),
+    (3, "system.posix_acl_default"),
+    (4, "trusted."),
+    (6, "security."),
+    (7, "system."),
+    (8, "system.richacl"),
+];
+
+struct Attr {
+    name: String,
+    value: Result<Vec<u8>, Error>,
+}
+
+/// Value of attribute `name` of inode `ino`.
+pub fn get(vol: &ExtVolume, reader: &BlockReader, ino: u32, name: &str) -> Result<Vec<u8>, Error> {
+    attrs(vol, reader, ino)?.into_iter().find(|a| a.name == name).ok_or(Error::NotFound)?.value
+}
+
+/// Names of the attributes of inode `ino`, in-inode ones first.
+pub fn list(vol: &ExtVolume, reader: &BlockReader, ino: u32) -> Result<Vec<String>, Error> {
+    Ok(attrs(vol, reader, ino)?.into_iter().map(|a| a.name).collect())
+}
+
+fn attrs(vol: &ExtVolume, reader: &BlockReader, ino: u32) -> Result<Vec<Attr>, Error> {
+    let raw = vol.read_inode_slot(reader, ino)?;
+    let mut found = Vec::new();
+
+    if raw.len() > EXT4_GOOD_OLD_INODE_SIZE {
+        let extra = le_u16(&raw, EXT4_GOOD_OLD_INODE_SIZE)? as usize;
+        let body = EXT4_GOOD_OLD_INODE_SIZE + extra;
+        if le_u32(&raw, body).ok() == Some(EXT4_XATTR_MAGIC) {
+            // In the inode, value offsets count from the first entry
+            let entries = &raw[body + 4..];
+            parse_entries(entries, entries, &mut found)?;
+        }
+    }
+
+    let inode = vol.read_inode(reader, ino)?;
+    let block = inode.i_file_acl_lo as u64 | (le_u16(&inode.i_osd2, 2)? as u64) << 32;
+    if block != 0 {
+        let mut buf = alloc::vec![0u8; vol.block_size as usize];
+        vol.read_block(reader, block, &mut buf)?;
+        if le_u32(&buf, 0)? != EXT4_XATTR_MAGIC {
+            return Err(Error::DeviceError);
+        }
+        // In a block, they count from the start of the block
+        parse_entries(&buf, &buf[EXT4_XATTR_BLOCK_HEADER_SIZE..], &mut found)?;
+    }
+    Ok(found)
+}
+
+// Reads entries from `entries` until the four-byte terminator; values are
+// found at their offset into `values`
+fn parse_entries(values: &[u8], entries: &[u8], found: &mut Vec<Attr>) -> Result<(), Error> {
+    let header = <XattrEntry as FromBytes>::SIZE;
+    let mut offset = 0;
+    while offset + 4 <= entries.len() && le_u32(entries, offset)? != 0 {
+        let entry = XattrEntry::from_bytes_at(entries, offset)?;
+        let name_start = offset + header;
+        let suffix = entries
+            .get(name_start..name_start + entry.e_name_len as usize)
+            .ok_or(Error::DeviceError)?;
+        let mut name = match PREFIXES.iter().find(|(index, _)| *index == entry.e_name_index) {
+            Some((_, prefix)) => String::from(*prefix),
+            None => String::new(),
+        };
+        name.push_str(&String::from_utf8_lossy(suffix));
+
+        let value = if entry.e_value_inum != 0 {
+            // Values in their own inode (EA_INODE) are not read yet
+            Err(Error::NotSupported)
+        } else {
+            let start = entry.e_value_offs as usize;
+            let end = start + entry.e_value_size as usize;
+            values.get(start..end).map(|v| v.to_vec()).ok_or(Error::DeviceError)
+        };
+        found.push(Attr { name, value });
+        offset = name_start + (entry.e_name_len as usize).div_ceil(4) * 4;
+    }
+    Ok(())
+}
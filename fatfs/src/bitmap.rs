@@ -0,0 +1,111 @@
+//! exFAT's Allocation Bitmap: one bit per cluster of the heap, set while the
+//! cluster is in use. exFAT records allocation here and not in the FAT,
+//! which only chains the clusters of fragmented files, so a zero FAT entry
+//! says nothing about whether a cluster is free. The bitmap is read whole
+//! at mount; each change is written back to the sector holding its bit.
+
+use crate::dir::DirStream;
+use crate::ops::FatOps;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u32, le_u64};
+use glenda::error::Error;
+
+pub const EXFAT_ENTRY_BITMAP: u8 = 0x81;
+// Offsets inside the Allocation Bitmap entry
+const BITMAP_FLAGS: usize = 1;
+const BITMAP_FIRST_CLUSTER: usize = 20;
+const BITMAP_DATA_LENGTH: usize = 24;
+// BitmapFlags: the second bitmap of a TexFAT volume
+const BITMAP_SECOND: u8 = 0x01;
+// Unit the bitmap is written back in
+const SECTOR_SIZE: usize = 512;
+
+pub struct AllocBitmap {
+    // Whole clusters as read, bits past the last cluster included
+    bits: Vec<u8>,
+    // Device byte offset of each cluster holding the bitmap
+    clusters: Vec<usize>,
+    cluster_size: usize,
+    cluster_count: u32,
+    free: u32,
+}
+
+impl AllocBitmap {
+    /// Reads the bitmap the root directory points at. The second bitmap of
+    /// a TexFAT volume is left alone.
+    pub fn load(reader: &BlockReader, ops: &dyn FatOps) -> Result<Self, Error> {
+        let mut root = DirStream::new(ops.get_root_location());
+        let entry = loop {
+            let entry = root.find_raw(reader, ops, EXFAT_ENTRY_BITMAP)?.ok_or(Error::NotFound)?;
+            if entry[BITMAP_FLAGS] & BITMAP_SECOND == 0 {
+                break entry;
+            }
+        };
+        let count = ops.cluster_count();
+        let len = le_u64(&entry, BITMAP_DATA_LENGTH)? as usize;
+        if len < (count as usize).div_ceil(8) {
+            return Err(Error::DeviceError);
+        }
+
+        let bps = ops.bytes_per_sector() as usize;
+        let cluster_size = ops.sectors_per_cluster() as usize * bps;
+        let mut bits = alloc::vec![0u8; len.next_multiple_of(cluster_size)];
+        let mut clusters = Vec::new();
+        let mut cluster = le_u32(&entry, BITMAP_FIRST_CLUSTER)?;
+        for chunk in bits.chunks_mut(cluster_size) {
+            if cluster < 2 || cluster >= count + 2 {
+                return Err(Error::DeviceError);
+            }
+            let offset = ops.cluster_to_sector(cluster) * bps;
+            reader.read_offset(offset, chunk)?;
+            clusters.push(offset);
+            cluster = ops.get_next_cluster(reader, cluster)?;
+        }
+
+        let mut bitmap = Self { bits, clusters, cluster_size, cluster_count: count, free: 0 };
+        bitmap.free = (2..count + 2).filter(|&c| bitmap.is_free(c)).count() as u32;
+        Ok(bitmap)
+    }
+
+    /// Free by the bitmap; cluster numbers outside the heap never are.
+    pub fn is_free(&self, cluster: u32) -> bool {
+        match self.bit(cluster) {
+            Some(bit) => self.bits[bit / 8] & (1 << (bit % 8)) == 0,
+            None => false,
+        }
+    }
+
+    pub fn free_count(&self) -> u32 {
+        self.free
+    }
+
+    /// Marks `cluster` in use or free and writes back the sector holding it.
+    pub fn set(&mut self, reader: &BlockReader, cluster: u32, used: bool) -> Result<(), Error> {
+        let bit = self.bit(cluster).ok_or(Error::InvalidArgs)?;
+        let mask = 1 << (bit % 8);
+        let byte = &mut self.bits[bit / 8];
+        if (*byte & mask != 0) == used {
+            return Ok(());
+        }
+        if used {
+            *byte |= mask;
+            self.free -= 1;
+        } else {
+            *byte &= !mask;
+            self.free += 1;
+        }
+        self.store(reader, bit / 8)
+    }
+
+    fn bit(&self, cluster: u32) -> Option<usize> {
+        (2..self.cluster_count + 2).contains(&cluster).then(|| (cluster - 2) as usize)
+    }
+
+    fn store(&self, reader: &BlockReader, byte: usize) -> Result<(), Error> {
+        let start = byte - byte % SECTOR_SIZE;
+        let within = start % self.cluster_size;
+        let offset = self.clusters[start / self.cluster_size] + within;
+        reader.write_blocks(offset / SECTOR_SIZE, &self.bits[start..start + SECTOR_SIZE])
+    }
+}
@@ -0,0 +1,119 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fs_block::BlockReader;
+use fs_common::batch::ReadBatch;
+use glenda::error::Error;
+use spin::Mutex;
+
+// Number of FAT sectors kept in memory. A 512-byte FAT32 sector covers 128 clusters,
+// so even a few sectors absorb most of the hops of a sequential chain walk.
+pub const FAT_CACHE_SECTORS: usize = 8;
+// FAT sectors read ahead of a miss
+const FAT_PREFETCH_SECTORS: usize = 3;
+
+/// Small LRU of FAT sectors shared by the filesystem and all open handles.
+pub struct FatSectorCache {
+    // Most recently used sector at the back
+    sectors: Mutex<Vec<(usize, Vec<u8>)>>,
+    capacity: AtomicUsize,
+    // Further FAT copies that follow the first one, each `fat_sectors` long
+    mirrors: usize,
+    fat_sectors: usize,
+}
+
+impl FatSectorCache {
+    pub fn new() -> Self {
+        Self::mirrored(1, 0)
+    }
+
+    /// Cache for a volume with `num_fats` copies of a `fat_sectors` long FAT.
+    /// Sectors are cached and addressed by their place in the first copy.
+    pub fn mirrored(num_fats: u8, fat_sectors: usize) -> Self {
+        Self {
+            sectors: Mutex::new(Vec::with_capacity(FAT_CACHE_SECTORS)),
+            capacity: AtomicUsize::new(FAT_CACHE_SECTORS),
+            mirrors: (num_fats as usize).saturating_sub(1),
+            fat_sectors,
+        }
+    }
+
+    pub fn with_sector<R>(
+        &self,
+        reader: &BlockReader,
+        sector: usize,
+        bytes_per_sector: usize,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, Error> {
+        let mut sectors = self.sectors.lock();
+        if let Some(idx) = sectors.iter().position(|(s, _)| *s == sector) {
+            let hit = sectors.remove(idx);
+            sectors.push(hit);
+        } else {
+            // Chains mostly run forward, so the following sectors come along
+            // in the same batch, up to half the cache
+            let capacity = self.capacity.load(Ordering::Relaxed);
+            let ahead = core::cmp::min(FAT_PREFETCH_SECTORS, capacity / 2);
+            let mut fetched: Vec<(usize, Vec<u8>)> = (sector..=sector + ahead)
+                .rev()
+                .filter(|s| *s == sector || !sectors.iter().any(|(c, _)| c == s))
+                .map(|s| (s, alloc::vec![0u8; bytes_per_sector]))
+                .collect();
+            let mut batch = ReadBatch::new();
+            for (s, buf) in fetched.iter_mut() {
+                batch.push(*s * bytes_per_sector, buf);
+            }
+            reader.submit(&mut batch).map_err(|_| Error::IoError)?;
+            if sectors.len() + fetched.len() > capacity {
+                let excess =
+                    core::cmp::min(sectors.len() + fetched.len() - capacity, sectors.len());
+                sectors.drain(..excess);
+            }
+            // The requested sector goes in last, as the most recently used
+            sectors.extend(fetched);
+        }
+        let (_, data) = sectors.last().ok_or(Error::InternalError)?;
+        Ok(f(data))
+    }
+
+    /// Modifies a FAT sector in place and writes it through to the device,
+    /// into every FAT copy.
+    pub fn update<R>(
+        &self,
+        reader: &BlockReader,
+        sector: usize,
+        bytes_per_sector: usize,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R, Error> {
+        self.with_sector(reader, sector, bytes_per_sector, |_| ())?;
+        let mut sectors = self.sectors.lock();
+        let (_, data) = sectors.last_mut().ok_or(Error::InternalError)?;
+        let result = f(data);
+        for copy in 0..=self.mirrors {
+            let target = sector + copy * self.fat_sectors;
+            reader
+                .write_blocks(target * bytes_per_sector / 512, data)
+                .map_err(|_| Error::IoError)?;
+        }
+        Ok(result)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Resizes the cache to hold `sectors` sectors, dropping the least recently
+    /// used ones that no longer fit.
+    pub fn set_capacity(&self, sectors: usize) {
+        let capacity = core::cmp::max(sectors, 1);
+        let mut cached = self.sectors.lock();
+        self.capacity.store(capacity, Ordering::Relaxed);
+        if cached.len() > capacity {
+            let excess = cached.len() - capacity;
+            cached.drain(..excess);
+        }
+    }
+
+    pub fn invalidate(&self, sector: usize) {
+        self.sectors.lock().retain(|(s, _)| *s != sector);
+    }
+}
@@ -1,3 +1,5 @@
+use fs_common::clock::{self, Timestamp};
+
 pub const BPB_SEC_SIZE: usize = 11;
 
 #[repr(C, packed)]
@@ -34,6 +36,51 @@ pub struct BiosParameterBlock {
     pub fil_sys_type: [u8; 8],
 }
 
+fs_common::impl_le_codec!(BiosParameterBlock {
+    jmp_boot,
+    oem_name,
+    byts_per_sec,
+    sec_per_clus,
+    rsvd_sec_cnt,
+    num_fats,
+    root_ent_cnt,
+    tot_sec_16,
+    media,
+    fat_sz_16,
+    sec_per_trk,
+    num_heads,
+    hidd_sec,
+    tot_sec_32,
+    fat_sz_32,
+    ext_flags,
+    fs_ver,
+    root_clus,
+    fs_info,
+    bk_boot_sec,
+    reserved,
+    drv_num,
+    reserved1,
+    boot_sig,
+    vol_id,
+    vol_lab,
+    fil_sys_type,
+});
+
+// FAT32 ext_flags: with mirroring off, only the FAT in the low bits is used
+pub const EXT_FLAGS_NO_MIRROR: u16 = 0x0080;
+pub const EXT_FLAGS_ACTIVE_FAT: u16 = 0x000F;
+
+impl BiosParameterBlock {
+    /// The only FAT read and written when a FAT32 volume has mirroring turned
+    /// off; None when every copy is kept in step.
+    pub fn active_fat(&self) -> Option<u8> {
+        let ext_flags = self.ext_flags;
+        if self.fat_sz_16 != 0 || ext_flags & EXT_FLAGS_NO_MIRROR == 0 {
+            return None;
+        }
+        Some((ext_flags & EXT_FLAGS_ACTIVE_FAT) as u8)
+    }
+}
 
 pub const ATTR_READ_ONLY: u8 = 0x01;
 pub const ATTR_HIDDEN: u8 = 0x02;
@@ -44,7 +91,7 @@ pub const ATTR_ARCHIVE: u8 = 0x20;
 pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
 
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct DirEntry {
     pub name: [u8; 11],
     pub attr: u8,
@@ -59,3 +106,98 @@ pub struct DirEntry {
     pub fst_clus_lo: u16,
     pub file_size: u32,
 }
+
+fs_common::impl_le_codec!(DirEntry {
+    name,
+    attr,
+    nt_res,
+    crt_time_tenth,
+    crt_time,
+    crt_date,
+    lst_acc_date,
+    fst_clus_hi,
+    wrt_time,
+    wrt_date,
+    fst_clus_lo,
+    file_size,
+});
+
+impl DirEntry {
+    pub fn first_cluster(&self) -> u32 {
+        ((self.fst_clus_hi as u32) << 16) | self.fst_clus_lo as u32
+    }
+
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        self.fst_clus_hi = (cluster >> 16) as u16;
+        self.fst_clus_lo = cluster as u16;
+    }
+
+    pub fn created(&self) -> Timestamp {
+        dos_time(self.crt_date, self.crt_time, self.crt_time_tenth)
+    }
+
+    pub fn modified(&self) -> Timestamp {
+        dos_time(self.wrt_date, self.wrt_time, 0)
+    }
+
+    /// Only the date of the last access is kept.
+    pub fn accessed(&self) -> Timestamp {
+        dos_time(self.lst_acc_date, 0, 0)
+    }
+
+    pub fn set_modified(&mut self, at: Timestamp) {
+        let (date, time, _) = dos_stamp(at);
+        self.wrt_date = date;
+        self.wrt_time = time;
+    }
+
+    pub fn set_accessed(&mut self, at: Timestamp) {
+        self.lst_acc_date = dos_stamp(at).0;
+    }
+
+    /// Stamps a new entry: created, written and accessed at `now`.
+    pub fn stamp_created(&mut self, now: Timestamp) {
+        let (date, time, hundredths) = dos_stamp(now);
+        self.crt_date = date;
+        self.crt_time = time;
+        self.crt_time_tenth = hundredths;
+        self.wrt_date = date;
+        self.wrt_time = time;
+        self.lst_acc_date = date;
+    }
+}
+
+// DOS dates count years from 1980; no timezone is recorded, so times are
+// taken as UTC
+const DOS_EPOCH_YEAR: i64 = 1980;
+
+/// A DOS date and time, with the hundredths (0-199, so they also carry the
+/// odd second) FAT keeps for creation. A zero date, which entries written
+/// without a clock have, reads as the epoch.
+pub fn dos_time(date: u16, time: u16, hundredths: u8) -> Timestamp {
+    let (day, month) = ((date & 0x1F) as u32, ((date >> 5) & 0x0F) as u32);
+    if day == 0 || month == 0 || month > 12 {
+        return Timestamp::default();
+    }
+    let year = DOS_EPOCH_YEAR + (date >> 9) as i64;
+    let (sec, min, hour) =
+        ((time & 0x1F) as u32 * 2, ((time >> 5) & 0x3F) as u32, (time >> 11) as u32);
+    let hundredths = core::cmp::min(hundredths, 199) as u32;
+    let secs = clock::unix_seconds(year, month, day, hour, min, sec) + (hundredths / 100) as i64;
+    Timestamp::new(secs, (hundredths % 100) * 10_000_000)
+}
+
+/// (date, time, hundredths) of `at`, clamped to the years DOS dates can hold.
+pub fn dos_stamp(at: Timestamp) -> (u16, u16, u8) {
+    let (year, month, day, hour, min, sec) = clock::civil(at.sec);
+    if year < DOS_EPOCH_YEAR {
+        return ((1 << 5) | 1, 0, 0);
+    }
+    if year > DOS_EPOCH_YEAR + 127 {
+        return ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29, 199);
+    }
+    let date = (((year - DOS_EPOCH_YEAR) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((hour as u16) << 11) | ((min as u16) << 5) | (sec / 2) as u16;
+    let hundredths = (sec % 2) * 100 + at.nsec / 10_000_000;
+    (date, time, hundredths as u8)
+}
@@ -0,0 +1,597 @@
+use crate::defs::*;
+use crate::ops::{FatOps, RootLocation};
+use crate::versions::EXFAT_ENTRY_FILE;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::batch::{ReadBatch, MAX_BATCH_BYTES, SCAN_BATCH_BLOCKS};
+use fs_common::bytes::FromBytes;
+use fs_common::casefold;
+use fs_common::utf16;
+use glenda::error::Error;
+
+pub const DIR_ENTRY_SIZE: usize = 32;
+pub const LFN_CHARS_PER_ENTRY: usize = 13;
+pub const LFN_LAST_ENTRY: u8 = 0x40;
+pub const DELETED_ENTRY: u8 = 0xE5;
+
+// Offsets of the three UTF-16 name fragments inside a long-name slot
+const LFN_NAME_RANGES: [(usize, usize); 3] = [(1, 5), (14, 6), (28, 2)];
+const LFN_CHECKSUM: usize = 13;
+
+/// A live directory entry together with its decoded name.
+pub struct DirRecord {
+    // Slot index of the 8.3 entry
+    pub slot: usize,
+    // Slot index of the first long-name entry (equal to `slot` without LFN)
+    pub first_slot: usize,
+    pub entry: DirEntry,
+    pub name: String,
+    // Full file size; exFAT sizes do not fit `entry.file_size`
+    pub size: u64,
+    // exFAT: the clusters are contiguous and the FAT chain is not maintained
+    pub no_fat_chain: bool,
+}
+
+/// An exFAT File entry with its Stream Extension and File Name entries.
+pub struct EntrySet {
+    pub attributes: u16,
+    pub first_cluster: u32,
+    pub data_length: u64,
+    pub no_fat_chain: bool,
+    pub name: String,
+    // Timestamps as (date << 16 | time) in DOS encoding, creation with the
+    // extra 10 ms increments; the 8.3 view has nowhere for those of the
+    // modification
+    pub created: u32,
+    pub created_10ms: u8,
+    pub modified: u32,
+    pub accessed: u32,
+}
+
+impl DirRecord {
+    /// Record for the entry set starting at `slot`. The 8.3 view carries the
+    /// attributes, which share the FAT bit values, the first cluster and the
+    /// timestamps, which share the DOS encoding.
+    pub fn from_entry_set(slot: usize, set: EntrySet) -> Self {
+        let mut entry = DirEntry {
+            name: [b' '; 11],
+            attr: set.attributes as u8,
+            file_size: core::cmp::min(set.data_length, u32::MAX as u64) as u32,
+            crt_time_tenth: set.created_10ms,
+            crt_time: set.created as u16,
+            crt_date: (set.created >> 16) as u16,
+            lst_acc_date: (set.accessed >> 16) as u16,
+            wrt_time: set.modified as u16,
+            wrt_date: (set.modified >> 16) as u16,
+            ..Default::default()
+        };
+        entry.set_first_cluster(set.first_cluster);
+        Self {
+            slot,
+            first_slot: slot,
+            entry,
+            name: set.name,
+            size: set.data_length,
+            no_fat_chain: set.no_fat_chain,
+        }
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.entry.first_cluster()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        (self.entry.attr & ATTR_DIRECTORY) != 0
+    }
+}
+
+pub fn short_name_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+/// Formats an 8.3 name as `NAME.EXT`, honoring the NT lowercase flags.
+pub fn short_name_to_string(entry: &DirEntry) -> String {
+    let name = entry.name;
+    let mut out = String::new();
+    let base_lower = (entry.nt_res & 0x08) != 0;
+    let ext_lower = (entry.nt_res & 0x10) != 0;
+
+    for (i, &b) in name[..8].iter().enumerate() {
+        if b == b' ' {
+            break;
+        }
+        // 0x05 stands in for a leading 0xE5 byte
+        let b = if i == 0 && b == 0x05 { 0xE5 } else { b };
+        out.push(if base_lower { b.to_ascii_lowercase() } else { b } as char);
+    }
+    if name[8] != b' ' {
+        out.push('.');
+        for &b in name[8..].iter().take_while(|&&b| b != b' ') {
+            out.push(if ext_lower { b.to_ascii_lowercase() } else { b } as char);
+        }
+    }
+    out
+}
+
+// Characters allowed in an 8.3 name besides A-Z and 0-9
+const SHORT_NAME_SPECIALS: &[u8] = b"!#$%&'()-@^_`{}~";
+// Longest name a run of 20 long-name slots can hold
+pub const LFN_MAX_CHARS: usize = 255;
+
+/// True if `name` names the entry, either by its long name or its 8.3 alias.
+/// FAT names compare case-insensitively, long names beyond ASCII included.
+pub fn record_matches(record: &DirRecord, name: &str, ops: &dyn FatOps) -> bool {
+    ops.names_match(&record.name, name) || casefold::eq(&short_name_to_string(&record.entry), name)
+}
+
+fn short_char(c: char) -> Option<u8> {
+    if !c.is_ascii() {
+        return None;
+    }
+    let b = (c as u8).to_ascii_uppercase();
+    if b.is_ascii_alphanumeric() || SHORT_NAME_SPECIALS.contains(&b) {
+        Some(b)
+    } else {
+        None
+    }
+}
+
+/// Encodes `name` as an 8.3 name if it fits exactly, without a long name.
+pub fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(idx) => (&name[..idx], &name[idx + 1..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut out = [b' '; 11];
+    for (i, c) in base.chars().enumerate() {
+        // Lowercase would need the NT case flags; leave that to a long name
+        if c.is_ascii_lowercase() {
+            return None;
+        }
+        out[i] = short_char(c)?;
+    }
+    for (i, c) in ext.chars().enumerate() {
+        if c.is_ascii_lowercase() {
+            return None;
+        }
+        out[8 + i] = short_char(c)?;
+    }
+    if out[0] == DELETED_ENTRY {
+        out[0] = 0x05;
+    }
+    Some(out)
+}
+
+/// Generates a unique `BASE~N.EXT` alias for a long name. `taken` reports
+/// aliases already present in the target directory.
+pub fn generate_short_name(
+    name: &str,
+    taken: impl Fn(&[u8; 11]) -> bool,
+) -> Result<[u8; 11], Error> {
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(idx) => (&trimmed[..idx], &trimmed[idx + 1..]),
+        None => (trimmed, ""),
+    };
+    let strip = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| short_char(c).unwrap_or(b'_'))
+            .take(max)
+            .collect()
+    };
+    let base = strip(base, 8);
+    let ext = strip(ext, 3);
+
+    let mut out = [b' '; 11];
+    out[8..8 + ext.len()].copy_from_slice(&ext);
+    for n in 1..1_000_000u32 {
+        let mut digits = [0u8; 7];
+        let mut len = 0;
+        let mut v = n;
+        while v > 0 {
+            digits[len] = b'0' + (v % 10) as u8;
+            v /= 10;
+            len += 1;
+        }
+        let keep = core::cmp::min(base.len(), 8 - 1 - len);
+        out[..8].fill(b' ');
+        out[..keep].copy_from_slice(&base[..keep]);
+        out[keep] = b'~';
+        for i in 0..len {
+            out[keep + 1 + i] = digits[len - 1 - i];
+        }
+        if !taken(&out) {
+            return Ok(out);
+        }
+    }
+    Err(Error::AlreadyExists)
+}
+
+/// Builds the long-name slots for `name`, in on-disk order (last fragment first).
+pub fn long_name_slots(
+    name: &str,
+    short_name: &[u8; 11],
+) -> Result<Vec<[u8; DIR_ENTRY_SIZE]>, Error> {
+    let units = utf16::encode(name);
+    if units.is_empty() || units.len() > LFN_MAX_CHARS {
+        return Err(Error::InvalidArgs);
+    }
+    let count = units.len().div_ceil(LFN_CHARS_PER_ENTRY);
+    let checksum = short_name_checksum(short_name);
+
+    let mut slots = Vec::with_capacity(count);
+    for seq in (1..=count).rev() {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0] = seq as u8 | if seq == count { LFN_LAST_ENTRY } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[LFN_CHECKSUM] = checksum;
+
+        let mut idx = (seq - 1) * LFN_CHARS_PER_ENTRY;
+        for &(start, chars) in LFN_NAME_RANGES.iter() {
+            for i in 0..chars {
+                // NUL terminates a short final fragment, 0xFFFF pads the rest
+                let unit = match idx.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[idx],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                let off = start + i * 2;
+                raw[off..off + 2].copy_from_slice(&unit.to_le_bytes());
+                idx += 1;
+            }
+        }
+        slots.push(raw);
+    }
+    Ok(slots)
+}
+
+/// Accumulates a run of long-name slots preceding an 8.3 entry.
+struct LfnCollector {
+    chars: Vec<u16>,
+    expected: u8,
+    next_ord: u8,
+    checksum: u8,
+    first_slot: usize,
+}
+
+impl LfnCollector {
+    fn new() -> Self {
+        Self { chars: Vec::new(), expected: 0, next_ord: 0, checksum: 0, first_slot: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.expected = 0;
+        self.next_ord = 0;
+    }
+
+    fn push(&mut self, raw: &[u8], slot: usize) {
+        let ord = raw[0];
+        let seq = ord & 0x1F;
+        if (ord & LFN_LAST_ENTRY) != 0 {
+            self.expected = seq;
+            self.checksum = raw[LFN_CHECKSUM];
+            self.first_slot = slot;
+            self.chars.clear();
+            self.chars.resize(seq as usize * LFN_CHARS_PER_ENTRY, 0xFFFF);
+        } else if self.expected == 0 || seq != self.next_ord || raw[LFN_CHECKSUM] != self.checksum {
+            // Orphaned or out-of-order fragment
+            self.reset();
+            return;
+        }
+        if seq == 0 || seq > self.expected {
+            self.reset();
+            return;
+        }
+
+        let mut idx = (seq as usize - 1) * LFN_CHARS_PER_ENTRY;
+        for &(start, count) in LFN_NAME_RANGES.iter() {
+            for i in 0..count {
+                let off = start + i * 2;
+                self.chars[idx] = u16::from_le_bytes([raw[off], raw[off + 1]]);
+                idx += 1;
+            }
+        }
+        self.next_ord = seq - 1;
+    }
+
+    fn take(&mut self, short_name: &[u8; 11]) -> Option<(String, usize)> {
+        let complete = self.expected != 0 && self.next_ord == 0;
+        let matches = self.checksum == short_name_checksum(short_name);
+        let first_slot = self.first_slot;
+        self.reset();
+        if !complete || !matches {
+            return None;
+        }
+
+        let len =
+            self.chars.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(self.chars.len());
+        Some((utf16::decode(&self.chars[..len]), first_slot))
+    }
+}
+
+/// Sequential reader over the 32-byte slots of a FAT directory, either a
+/// cluster chain or the fixed FAT12/16 root area.
+pub struct DirStream {
+    location: RootLocation,
+    block: Vec<u8>,
+    // Index of the loaded block (cluster number in the chain) and its cluster
+    block_index: Option<usize>,
+    cluster: u32,
+    // Clusters read ahead of the loaded one: (index, cluster, contents)
+    ahead: Vec<(usize, u32, Vec<u8>)>,
+    // Next slot to examine
+    slot: usize,
+    lfn: LfnCollector,
+    done: bool,
+}
+
+impl DirStream {
+    pub fn new(location: RootLocation) -> Self {
+        let cluster = match location {
+            RootLocation::Cluster(c) | RootLocation::Contiguous(c, _) => c,
+            RootLocation::Sector(..) => 0,
+        };
+        Self {
+            location,
+            block: Vec::new(),
+            block_index: None,
+            cluster,
+            ahead: Vec::new(),
+            slot: 0,
+            lfn: LfnCollector::new(),
+            done: false,
+        }
+    }
+
+    /// Slot index the next call to `next` starts from.
+    pub fn position(&self) -> usize {
+        self.slot
+    }
+
+    /// Restarts iteration at `slot`, a value previously returned by `position`.
+    pub fn seek(&mut self, slot: usize) {
+        self.slot = slot;
+        self.lfn.reset();
+        self.done = false;
+    }
+
+    fn block_size(&self, ops: &dyn FatOps) -> usize {
+        match self.location {
+            RootLocation::Cluster(_) | RootLocation::Contiguous(..) => {
+                (ops.sectors_per_cluster() * ops.bytes_per_sector()) as usize
+            }
+            RootLocation::Sector(_, count) => count as usize * ops.bytes_per_sector() as usize,
+        }
+    }
+
+    // Makes sure the block holding `block_index` is loaded. Returns false past the end.
+    fn load(
+        &mut self,
+        reader: &BlockReader,
+        ops: &dyn FatOps,
+        block_index: usize,
+    ) -> Result<bool, Error> {
+        if self.block_index == Some(block_index) {
+            return Ok(true);
+        }
+        let block_size = self.block_size(ops);
+        let bps = ops.bytes_per_sector() as usize;
+        match self.location {
+            RootLocation::Sector(start, _) => {
+                if block_index > 0 {
+                    return Ok(false);
+                }
+                self.block.resize(block_size, 0);
+                reader.read_offset(start * bps, &mut self.block).map_err(|_| Error::IoError)?;
+            }
+            RootLocation::Contiguous(first, count) => {
+                if block_index >= count as usize {
+                    return Ok(false);
+                }
+                let cluster = first + block_index as u32;
+                self.block.resize(block_size, 0);
+                let offset = ops.cluster_to_sector(cluster) * bps;
+                reader.read_offset(offset, &mut self.block).map_err(|_| Error::IoError)?;
+                self.cluster = cluster;
+            }
+            RootLocation::Cluster(first) => {
+                if let Some(pos) = self.ahead.iter().position(|(idx, ..)| *idx == block_index) {
+                    let (_, cluster, data) = self.ahead.swap_remove(pos);
+                    self.block = data;
+                    self.cluster = cluster;
+                    self.block_index = Some(block_index);
+                    return Ok(true);
+                }
+                // Only forward, one cluster at a time; restart from the head otherwise
+                let (mut idx, mut cluster) = match self.block_index {
+                    Some(idx) if idx < block_index => (idx, self.cluster),
+                    _ => (0, first),
+                };
+                while idx < block_index {
+                    cluster = ops.get_next_cluster(reader, cluster)?;
+                    if cluster < 2 || cluster >= 0x0FFFFFF8 {
+                        return Ok(false);
+                    }
+                    idx += 1;
+                }
+                if cluster < 2 {
+                    return Ok(false);
+                }
+                // Read the clusters that follow in the chain along with this one
+                let limit = (MAX_BATCH_BYTES / block_size).clamp(1, SCAN_BATCH_BLOCKS);
+                let mut run = alloc::vec![(block_index, cluster)];
+                let mut next = cluster;
+                while run.len() < limit {
+                    next = ops.get_next_cluster(reader, next)?;
+                    if next < 2 || next >= 0x0FFFFFF8 {
+                        break;
+                    }
+                    run.push((block_index + run.len(), next));
+                }
+                let mut bufs: Vec<Vec<u8>> =
+                    run.iter().map(|_| alloc::vec![0u8; block_size]).collect();
+                let mut batch = ReadBatch::new();
+                for ((_, c), buf) in run.iter().zip(bufs.iter_mut()) {
+                    batch.push(ops.cluster_to_sector(*c) * bps, buf);
+                }
+                reader.submit(&mut batch).map_err(|_| Error::IoError)?;
+                let mut fetched = run.into_iter().zip(bufs).map(|((idx, c), data)| (idx, c, data));
+                let (_, _, data) = fetched.next().ok_or(Error::InternalError)?;
+                self.block = data;
+                self.ahead = fetched.collect();
+                self.cluster = cluster;
+            }
+        }
+        self.block_index = Some(block_index);
+        Ok(true)
+    }
+
+    /// Returns the next live entry, skipping deleted slots and volume labels.
+    pub fn next(
+        &mut self,
+        reader: &BlockReader,
+        ops: &dyn FatOps,
+    ) -> Result<Option<DirRecord>, Error> {
+        let block_size = self.block_size(ops);
+        if block_size < DIR_ENTRY_SIZE {
+            return Err(Error::DeviceError);
+        }
+        if ops.is_exfat() {
+            return self.next_entry_set(reader, ops);
+        }
+        let slots_per_block = block_size / DIR_ENTRY_SIZE;
+
+        while !self.done {
+            let slot = self.slot;
+            if !self.load(reader, ops, slot / slots_per_block)? {
+                self.done = true;
+                break;
+            }
+            self.slot += 1;
+
+            let offset = (slot % slots_per_block) * DIR_ENTRY_SIZE;
+            let raw = &self.block[offset..offset + DIR_ENTRY_SIZE];
+            match raw[0] {
+                0 => {
+                    // End-of-directory marker
+                    self.done = true;
+                    break;
+                }
+                DELETED_ENTRY => {
+                    self.lfn.reset();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let attr = raw[11];
+            if (attr & ATTR_LONG_NAME) == ATTR_LONG_NAME {
+                self.lfn.push(raw, slot);
+                continue;
+            }
+
+            let entry = DirEntry::from_bytes(raw)?;
+            if (attr & ATTR_VOLUME_ID) != 0 {
+                self.lfn.reset();
+                continue;
+            }
+
+            let (name, first_slot) = match self.lfn.take(&entry.name) {
+                Some(long) => long,
+                None => (short_name_to_string(&entry), slot),
+            };
+            let size = entry.file_size as u64;
+            return Ok(Some(DirRecord {
+                slot,
+                first_slot,
+                entry,
+                name,
+                size,
+                no_fat_chain: false,
+            }));
+        }
+        Ok(None)
+    }
+
+    // Copy of slot `slot`, or None past the end of the directory
+    fn raw_slot(
+        &mut self,
+        reader: &BlockReader,
+        ops: &dyn FatOps,
+        slot: usize,
+    ) -> Result<Option<[u8; DIR_ENTRY_SIZE]>, Error> {
+        let slots_per_block = self.block_size(ops) / DIR_ENTRY_SIZE;
+        if !self.load(reader, ops, slot / slots_per_block)? {
+            return Ok(None);
+        }
+        let offset = (slot % slots_per_block) * DIR_ENTRY_SIZE;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(&self.block[offset..offset + DIR_ENTRY_SIZE]);
+        Ok(Some(raw))
+    }
+
+    /// exFAT: the next entry of type `entry_type`, for the entries of the
+    /// root that describe the volume rather than a file.
+    pub fn find_raw(
+        &mut self,
+        reader: &BlockReader,
+        ops: &dyn FatOps,
+        entry_type: u8,
+    ) -> Result<Option<[u8; DIR_ENTRY_SIZE]>, Error> {
+        while let Some(raw) = self.raw_slot(reader, ops, self.slot)? {
+            self.slot += 1;
+            match raw[0] {
+                0 => break,
+                t if t == entry_type => return Ok(Some(raw)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    // exFAT: skips to the next File entry and decodes the set it heads
+    fn next_entry_set(
+        &mut self,
+        reader: &BlockReader,
+        ops: &dyn FatOps,
+    ) -> Result<Option<DirRecord>, Error> {
+        while !self.done {
+            let slot = self.slot;
+            let Some(primary) = self.raw_slot(reader, ops, slot)? else {
+                self.done = true;
+                break;
+            };
+            self.slot += 1;
+            match primary[0] {
+                0 => {
+                    self.done = true;
+                    break;
+                }
+                EXFAT_ENTRY_FILE => {}
+                // Bitmap, up-case table, label, deleted sets and stray secondaries
+                _ => continue,
+            }
+
+            let mut set = Vec::with_capacity(1 + primary[1] as usize);
+            set.push(primary);
+            for i in 0..primary[1] as usize {
+                match self.raw_slot(reader, ops, slot + 1 + i)? {
+                    Some(raw) => set.push(raw),
+                    None => break,
+                }
+            }
+            // A damaged set is skipped one slot at a time like any stray entry
+            if let Some(entry_set) = ops.decode_entry_set(&set)? {
+                self.slot = slot + set.len();
+                return Ok(Some(DirRecord::from_entry_set(slot, entry_set)));
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,123 @@
+//! Where new clusters go. FAT media are mostly read sequentially, so the aim
+//! is to keep each chain in one run: a chain grows into the cluster right
+//! after its last one when that is free, and otherwise into the free extent
+//! that fits best, looked up in a map of the free runs built on first use.
+//! The older policy, taking the first free cluster after the last one handed
+//! out, stays selectable.
+
+use alloc::collections::BTreeMap;
+use fs_common::proto::{ALLOC_CONTIGUOUS, ALLOC_NEXT_FREE};
+use glenda::error::Error;
+
+// Room a chain that cannot grow in place looks for, so that it can keep
+// growing in place for a while
+pub const GROWTH_ROOM: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    NextFree,
+    Contiguous,
+}
+
+impl AllocPolicy {
+    pub fn from_bits(bits: usize) -> Result<Self, Error> {
+        match bits {
+            ALLOC_NEXT_FREE => Ok(Self::NextFree),
+            ALLOC_CONTIGUOUS => Ok(Self::Contiguous),
+            _ => Err(Error::InvalidArgs),
+        }
+    }
+
+    pub fn bits(self) -> usize {
+        match self {
+            Self::NextFree => ALLOC_NEXT_FREE,
+            Self::Contiguous => ALLOC_CONTIGUOUS,
+        }
+    }
+}
+
+/// Runs of free clusters, first cluster to length.
+pub struct FreeExtents {
+    runs: BTreeMap<u32, u32>,
+}
+
+impl FreeExtents {
+    /// Maps the free runs among clusters 2..cluster_count + 2, asking
+    /// `is_free` about each.
+    pub fn build(
+        cluster_count: u32,
+        mut is_free: impl FnMut(u32) -> Result<bool, Error>,
+    ) -> Result<Self, Error> {
+        let mut runs = BTreeMap::new();
+        let mut run: Option<(u32, u32)> = None;
+        for cluster in 2..cluster_count + 2 {
+            match (is_free(cluster)?, run.as_mut()) {
+                (true, Some((_, len))) => *len += 1,
+                (true, None) => run = Some((cluster, 1)),
+                (false, _) => {
+                    if let Some((start, len)) = run.take() {
+                        runs.insert(start, len);
+                    }
+                }
+            }
+        }
+        if let Some((start, len)) = run {
+            runs.insert(start, len);
+        }
+        Ok(Self { runs })
+    }
+
+    /// First cluster of the smallest run of at least `want` clusters, or of
+    /// the largest run when none is that long.
+    pub fn best_fit(&self, want: u32) -> Option<u32> {
+        let fitting = self.runs.iter().filter(|(_, &len)| len >= want).min_by_key(|(_, &len)| len);
+        fitting.or_else(|| self.runs.iter().max_by_key(|(_, &len)| len)).map(|(&start, _)| start)
+    }
+
+    /// Marks `cluster` in use, splitting the run it was in.
+    pub fn take(&mut self, cluster: u32) {
+        let Some((&start, &len)) = self.runs.range(..=cluster).next_back() else {
+            return;
+        };
+        if cluster >= start + len {
+            return;
+        }
+        self.runs.remove(&start);
+        if cluster > start {
+            self.runs.insert(start, cluster - start);
+        }
+        if cluster + 1 < start + len {
+            self.runs.insert(cluster + 1, start + len - cluster - 1);
+        }
+    }
+
+    /// Marks `cluster` free, joining the runs on either side.
+    pub fn release(&mut self, cluster: u32) {
+        let mut start = cluster;
+        let mut len = 1;
+        if let Some((&prev, &prev_len)) = self.runs.range(..cluster).next_back() {
+            if prev + prev_len > cluster {
+                return; // Already free
+            }
+            if prev + prev_len == cluster {
+                self.runs.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.runs.remove(&(cluster + 1)) {
+            len += next_len;
+        }
+        self.runs.insert(start, len);
+    }
+
+    /// (first cluster, length) of each run, in cluster order.
+    pub fn runs(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.runs.iter().map(|(&start, &len)| (start, len))
+    }
+
+    /// (number of runs, clusters in the longest)
+    pub fn summary(&self) -> (usize, u32) {
+        (self.runs.len(), self.runs.values().copied().max().unwrap_or(0))
+    }
+}
@@ -1,40 +1,88 @@
-use crate::block::BlockReader;
+use crate::bitmap::AllocBitmap;
+use crate::cache::{FatSectorCache, FAT_CACHE_SECTORS};
 use crate::defs::*;
+use crate::dir::{
+    exact_short_name, generate_short_name, long_name_slots, record_matches, short_name_to_string,
+    DirRecord, DirStream, DELETED_ENTRY, DIR_ENTRY_SIZE,
+};
+use crate::extents::{AllocPolicy, FreeExtents, GROWTH_ROOM};
+use crate::fsinfo::FreeSpace;
 use crate::layout::{NOTIFY_SLOT, RECV_BUFFER_SLOT, RECV_RING_SLOT};
+use crate::mkfs;
 use crate::ops::{FatOps, RootLocation};
+use crate::versions::Fat12Ops;
 use crate::versions::Fat16Ops;
 use crate::versions::Fat32Ops;
 use crate::versions::{ExFatBpb, ExFatOps};
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::bytes::{FromBytes, ToBytes};
+use fs_common::cbt::ChangeTracker;
+use fs_common::clock::{self, TimesRequest};
+use fs_common::device::IoTuning;
+use fs_common::errors::FsError;
+use fs_common::handle::FsHandle;
+use fs_common::health::IoErrorCounts;
+use fs_common::heat::HeatMap;
+use fs_common::history::VersionStore;
+use fs_common::jobs::{Job, Step};
+use fs_common::limits;
+use fs_common::mount::MountOptions;
+use fs_common::partition::{self, PartitionSelect};
+use fs_common::probe;
+use fs_common::proto::{self, dentry, CURRENT_OFFSET, DT_DIR, DT_REG, SEEK_CUR, SEEK_SET};
+use fs_common::readahead::Readahead;
+use fs_common::scrub::ScrubReport;
+use fs_common::statfs::FsStats;
+use fs_common::tune::CacheTunables;
+use fs_common::zeroing;
 use glenda::cap::{Endpoint, Frame};
 use glenda::client::ResourceClient;
 use glenda::error::Error;
 use glenda::interface::fs::FileHandleService;
 use glenda::interface::ResourceService;
 use glenda::io::uring::RingParams;
-use glenda::ipc::Badge;
+use glenda::ipc::{Badge, UTCB};
 use glenda::mem::shm::ShmParams;
 use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use spin::{Mutex, MutexGuard};
 
 pub struct FatFs {
     reader: BlockReader,
     ops: Arc<dyn FatOps>,
     ring_vaddr: usize,
     ring_size: usize,
+    tunables: CacheTunables,
+    options: MountOptions,
+    space: Mutex<FreeSpace>,
+    policy: AllocPolicy,
+    // Free runs, mapped by the first allocation that needs them
+    extents: Mutex<Option<FreeExtents>>,
+    // exFAT's record of which clusters are in use
+    bitmap: Option<Mutex<AllocBitmap>>,
+    // Byte offset of the FAT32 backup boot sector the volume was mounted
+    // from, the primary being damaged
+    boot_backup: Option<usize>,
 }
 
 impl FatFs {
-    pub fn new(
+    /// Connects to the block device and narrows IO to the selected
+    /// partition. The volume is not looked at beyond the partition table, so
+    /// the caller can probe it before committing to `mount`.
+    pub fn open_reader(
         block_device: Endpoint,
         ring_vaddr: usize,
         ring_size: usize,
         res_client: &mut ResourceClient,
         vspace: &mut VSpaceManager,
         cspace: &mut CSpaceManager,
-    ) -> Result<Self, Error> {
+        select: PartitionSelect,
+    ) -> Result<BlockReader, Error> {
         // 1. Setup IoUring Params
         let sq_entries = 4;
         let cq_entries = 4;
@@ -64,31 +112,71 @@ impl FatFs {
         // 2. Create reader and init (VolumeClient handles the handshake internally)
         let mut reader = BlockReader::new(block_device, res_client, ring_params, shm_params);
         reader.init(vspace, cspace)?;
+        let geometry = reader.geometry();
+        glenda::log!(
+            "FatFS: device blocks of {} bytes, {} physical",
+            geometry.logical_block,
+            geometry.physical_block
+        );
+        // FAT metadata is rewritten in place a sector at a time, so writes
+        // are gathered into whole device blocks before they go out
+        reader.buffers().set_write_back(true);
+        let part = partition::select(
+            |offset, buf| reader.read_offset(offset as usize, buf).map(|_| ()),
+            select,
+            geometry.logical_block as u64,
+        )?;
+        if let Some(part) = &part {
+            glenda::log!(
+                "FatFS: partition {} at {:#x}, {} bytes",
+                part.index,
+                part.start,
+                part.len
+            );
+        }
+        reader.set_partition(part.as_ref());
+        Ok(reader)
+    }
 
+    pub fn mount(reader: BlockReader, ring_vaddr: usize, ring_size: usize) -> Result<Self, Error> {
         // Read BPB
         let mut buf = [0u8; 512];
         reader.read_offset(0, &mut buf)?;
 
+        // A FAT32 volume with a damaged boot sector is read through its backup
+        let mut boot_backup = None;
+        if &buf[3..11] != b"EXFAT   " && !probe::is_fat_bpb(&buf) {
+            let offset = probe::fat_backup(|offset, buf| {
+                reader.read_offset(offset as usize, buf).map(|_| ())
+            })
+            .ok_or(FsError::Corrupt)? as usize;
+            glenda::log!("FatFS: boot sector damaged, mounting from the backup at {:#x}", offset);
+            reader.read_offset(offset, &mut buf)?;
+            boot_backup = Some(offset);
+        }
         let oem_name = &buf[3..11];
+        let mut fs_info_sector = None;
         let ops: Arc<dyn FatOps> = if oem_name == b"EXFAT   " {
-            let bpb = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const ExFatBpb) };
+            let bpb = ExFatBpb::from_bytes(&buf)?;
             let bytes_per_sector = 1u32 << bpb.bytes_per_sector_shift;
             let sectors_per_cluster = 1u32 << bpb.sectors_per_cluster_shift;
 
-            Arc::new(ExFatOps {
+            let mut exfat = ExFatOps {
                 bytes_per_sector,
                 sectors_per_cluster,
-                fat_start_sector: bpb.partition_offset + bpb.fat_offset as usize,
-                data_start_sector: bpb.partition_offset + bpb.cluster_heap_offset as usize,
+                fat_start_sector: bpb.partition_offset as usize + bpb.fat_offset as usize,
+                data_start_sector: bpb.partition_offset as usize + bpb.cluster_heap_offset as usize,
                 root_cluster: bpb.root_dir_cluster,
-            })
-        } else {
-            if buf[510] != 0x55 || buf[511] != 0xAA {
-                // Warning: Invalid Signature
+                cluster_count: bpb.cluster_count,
+                fat_cache: FatSectorCache::new(),
+                upcase: None,
+            };
+            if let Err(e) = exfat.load_upcase(&reader) {
+                glenda::log!("FatFS: no usable up-case table ({:?}), folding case without it", e);
             }
-
-            let bpb =
-                unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const BiosParameterBlock) };
+            Arc::new(exfat)
+        } else {
+            let bpb = BiosParameterBlock::from_bytes(&buf)?;
 
             let bytes_per_sec = if bpb.byts_per_sec == 0 { 512 } else { bpb.byts_per_sec };
             let root_ent_cnt = bpb.root_ent_cnt;
@@ -102,7 +190,22 @@ impl FatFs {
                 - (bpb.rsvd_sec_cnt as u32 + (bpb.num_fats as u32 * fat_sz) + root_dir_sectors);
             let count_of_clusters = data_sec / bpb.sec_per_clus as u32;
 
-            if count_of_clusters < 65525 {
+            // The cluster count alone decides the FAT type
+            if count_of_clusters < 4085 {
+                Arc::new(Fat12Ops {
+                    bytes_per_sector: bytes_per_sec,
+                    sectors_per_cluster: bpb.sec_per_clus,
+                    fat_start_sector: bpb.rsvd_sec_cnt as usize,
+                    root_start_sector: (bpb.rsvd_sec_cnt as u32 + (bpb.num_fats as u32 * fat_sz))
+                        as usize,
+                    root_entries: bpb.root_ent_cnt,
+                    data_start_sector: (bpb.rsvd_sec_cnt as u32
+                        + (bpb.num_fats as u32 * fat_sz)
+                        + root_dir_sectors) as usize,
+                    cluster_count: count_of_clusters,
+                    fat_cache: FatSectorCache::mirrored(bpb.num_fats, fat_sz as usize),
+                })
+            } else if count_of_clusters < 65525 {
                 Arc::new(Fat16Ops {
                     bytes_per_sector: bytes_per_sec,
                     sectors_per_cluster: bpb.sec_per_clus,
@@ -113,20 +216,185 @@ impl FatFs {
                     data_start_sector: (bpb.rsvd_sec_cnt as u32
                         + (bpb.num_fats as u32 * fat_sz)
                         + root_dir_sectors) as usize,
+                    cluster_count: count_of_clusters,
+                    fat_cache: FatSectorCache::mirrored(bpb.num_fats, fat_sz as usize),
                 })
             } else {
+                fs_info_sector = Some(bpb.fs_info as usize).filter(|&s| s != 0 && s != 0xFFFF);
+                // Mirroring off: the active FAT alone is read and written,
+                // the other copies are left as they are
+                let (fat_start_sector, fat_cache) = match bpb.active_fat() {
+                    Some(active) if active >= bpb.num_fats => {
+                        glenda::log!(
+                            "FatFS: active FAT {} of {} does not exist",
+                            active,
+                            bpb.num_fats
+                        );
+                        return Err(FsError::Corrupt.into());
+                    }
+                    Some(active) => (
+                        bpb.rsvd_sec_cnt as usize + active as usize * fat_sz as usize,
+                        FatSectorCache::mirrored(1, fat_sz as usize),
+                    ),
+                    None => (
+                        bpb.rsvd_sec_cnt as usize,
+                        FatSectorCache::mirrored(bpb.num_fats, fat_sz as usize),
+                    ),
+                };
                 Arc::new(Fat32Ops {
                     bytes_per_sector: bytes_per_sec,
                     sectors_per_cluster: bpb.sec_per_clus,
-                    fat_start_sector: bpb.rsvd_sec_cnt as usize,
+                    fat_start_sector,
                     data_start_sector: (bpb.rsvd_sec_cnt as u32 + (bpb.num_fats as u32 * fat_sz))
                         as usize,
                     root_cluster: bpb.root_clus,
+                    cluster_count: count_of_clusters,
+                    fat_cache,
                 })
             }
         };
 
-        Ok(Self { reader, ops, ring_vaddr, ring_size })
+        let cache_bytes = ops.fat_cache().capacity() * ops.bytes_per_sector() as usize
+            + reader.buffers().capacity() * reader.device_block_size();
+        let tunables = CacheTunables::new(cache_bytes, reader.tuning().readahead_blocks);
+        let space = match fs_info_sector {
+            Some(sector) => {
+                let offset = sector * ops.bytes_per_sector() as usize;
+                FreeSpace::load(&reader, offset, ops.cluster_count())?
+            }
+            None => FreeSpace::new(ops.cluster_count()),
+        };
+        let bitmap = if ops.is_exfat() {
+            match AllocBitmap::load(&reader, ops.as_ref()) {
+                Ok(bitmap) => Some(Mutex::new(bitmap)),
+                Err(e) => {
+                    glenda::log!("FatFS: allocation bitmap unreadable ({:?}), not allocating", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            reader,
+            ops,
+            ring_vaddr,
+            ring_size,
+            tunables,
+            options: MountOptions::default(),
+            space: Mutex::new(space),
+            policy: AllocPolicy::Contiguous,
+            extents: Mutex::new(None),
+            bitmap,
+            boot_backup,
+        })
+    }
+
+    /// Replaces the volume with a fresh, empty FAT32 one of `size` bytes, the
+    /// whole partition or device for 0, and mounts that with the same settings.
+    pub fn format(&mut self, size: usize, label: &str) -> Result<(), Error> {
+        let size = match size {
+            0 => self.reader.capacity().ok_or(Error::InvalidArgs)?,
+            size => size,
+        };
+        mkfs::format(&self.reader, size, label)?;
+        let mut fs = Self::mount(self.reader.clone(), self.ring_vaddr, self.ring_size)?;
+        fs.set_mount_options(self.options)?;
+        fs.set_cache_tunables(self.tunables);
+        fs.set_alloc_policy(self.policy)?;
+        *self = fs;
+        Ok(())
+    }
+
+    /// Copies the backup boot sector the volume was mounted from over the
+    /// damaged primary. Returns whether there was anything to repair.
+    pub fn repair_boot_sector(&mut self) -> Result<bool, Error> {
+        let Some(offset) = self.boot_backup else {
+            return Ok(false);
+        };
+        let mut sector = alloc::vec![0u8; self.ops.bytes_per_sector() as usize];
+        self.reader.read_offset(offset, &mut sector)?;
+        self.reader.write_blocks(0, &sector)?;
+        self.reader.flush()?;
+        self.boot_backup = None;
+        Ok(true)
+    }
+
+    /// STATFS counts, in clusters. FAT has no inode table to run out of.
+    pub fn stats(&self) -> Result<FsStats, Error> {
+        let free = self.free_cluster_count()? as u64;
+        Ok(FsStats {
+            block_size: self.cluster_size(),
+            total_blocks: self.ops.cluster_count() as u64,
+            free_blocks: free,
+            avail_blocks: free,
+            ..Default::default()
+        })
+    }
+
+    pub fn set_io_tuning(&mut self, tuning: IoTuning) {
+        self.reader.set_tuning(tuning);
+        self.tunables.readahead_blocks = tuning.readahead_blocks;
+    }
+
+    pub fn io_tuning(&self) -> IoTuning {
+        self.reader.tuning()
+    }
+
+    pub fn io_error_counts(&self) -> IoErrorCounts {
+        self.reader.error_counts()
+    }
+
+    pub fn heat(&self) -> &HeatMap {
+        self.reader.heat()
+    }
+
+    pub fn changes(&self) -> &ChangeTracker {
+        self.reader.changes()
+    }
+
+    /// With `sync`, writes bypass the buffer cache; what it already holds is
+    /// flushed first.
+    pub fn set_mount_options(&mut self, options: MountOptions) -> Result<(), Error> {
+        if options.sync {
+            self.reader.flush()?;
+        }
+        self.reader.buffers().set_write_back(!options.sync);
+        self.options = options;
+        Ok(())
+    }
+
+    /// Writes out everything the buffer cache holds dirty and waits for it
+    /// to reach the medium.
+    pub fn sync_all(&self) -> Result<(), Error> {
+        self.reader.barrier()
+    }
+
+    pub fn dirty_blocks(&self) -> usize {
+        self.reader.buffers().dirty_blocks()
+    }
+
+    pub fn cache_tunables(&self) -> CacheTunables {
+        self.tunables
+    }
+
+    /// Splits the cache budget between the FAT sector cache, which gets up to
+    /// half of it but no more than its default, and the buffer cache, and
+    /// changes the readahead window. Handles opened from now on use the new
+    /// window.
+    pub fn set_cache_tunables(&mut self, tunables: CacheTunables) {
+        let bytes_per_sector = self.ops.bytes_per_sector() as usize;
+        let fat_bytes =
+            core::cmp::min(tunables.cache_max_bytes / 2, FAT_CACHE_SECTORS * bytes_per_sector);
+        self.ops.fat_cache().set_capacity(fat_bytes / bytes_per_sector);
+        let buffers = self.reader.buffers();
+        buffers
+            .set_capacity((tunables.cache_max_bytes - fat_bytes) / self.reader.device_block_size());
+        buffers.set_dirty_ratio(tunables.dirty_ratio);
+        let tuning =
+            IoTuning { readahead_blocks: tunables.readahead_blocks, ..self.reader.tuning() };
+        self.reader.set_tuning(tuning);
+        self.tunables = tunables;
     }
 
     pub fn get_next_cluster(&self, cluster: u32) -> Result<u32, Error> {
@@ -146,7 +414,7 @@ impl FatFs {
                 break;
             }
             if next == 0x0FFFFFF7 {
-                return Err(Error::IoError);
+                return Err(FsError::Corrupt.into());
             }
             curr = next;
         }
@@ -166,217 +434,966 @@ impl FatFs {
             .map(|_| ())
     }
 
-    fn read_sectors(
+    /// Finds `name` in a directory by long name or 8.3 alias.
+    pub fn find_entry(&self, location: RootLocation, name: &str) -> Result<DirEntry, Error> {
+        Ok(self.find_record(location, name)?.entry)
+    }
+
+    fn find_record(&self, location: RootLocation, name: &str) -> Result<DirRecord, Error> {
+        let mut stream = DirStream::new(location);
+        while let Some(record) = stream.next(&self.reader, self.ops.as_ref())? {
+            if record_matches(&record, name, self.ops.as_ref()) {
+                return Ok(record);
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn lookup(&self, path: &str) -> Result<DirEntry, Error> {
+        Ok(self.lookup_record(path)?.entry)
+    }
+
+    /// Resolves `path` to its directory record; the root gets a synthetic one.
+    pub fn lookup_record(&self, path: &str) -> Result<DirRecord, Error> {
+        let mut record = self.root_record();
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            record = self.lookup_step(&record, part)?;
+        }
+        Ok(record)
+    }
+
+    pub fn root_record(&self) -> DirRecord {
+        DirRecord {
+            slot: 0,
+            first_slot: 0,
+            entry: DirEntry { name: [0x20; 11], attr: ATTR_DIRECTORY, ..Default::default() },
+            name: String::new(),
+            size: 0,
+            no_fat_chain: false,
+        }
+    }
+
+    /// The entry `name` in the directory `dir`; one lookup of a path walk.
+    pub fn lookup_step(&self, dir: &DirRecord, name: &str) -> Result<DirRecord, Error> {
+        if !dir.is_dir() {
+            return Err(FsError::NotDir.into());
+        }
+        self.find_record(self.record_location(dir), name)
+    }
+}
+
+impl FatFs {
+    /// OPEN of `path`. O_CREAT adds an empty file where there is none, and
+    /// O_TRUNC gives its clusters back; FAT keeps no mode to set.
+    pub fn open_handle(
+        &mut self,
+        path: &str,
+        flags: OpenFlags,
+        _mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let record = match self.lookup_record(path) {
+            Ok(_) if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) => {
+                return Err(Error::AlreadyExists);
+            }
+            Ok(record) if flags.contains(OpenFlags::O_TRUNC) && record.size > 0 => {
+                self.truncate_entry(path)?
+            }
+            Ok(record) => record,
+            Err(Error::NotFound) if flags.contains(OpenFlags::O_CREAT) => self.create_file(path)?,
+            Err(e) => return Err(e),
+        };
+        self.open_record(record, flags)
+    }
+
+    // An empty file has no clusters, so creating one only takes its entry
+    fn create_file(&mut self, path: &str) -> Result<DirRecord, Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let (parent, _, name) = self.resolve_parent(path)?;
+        let mut entry = DirEntry::default();
+        entry.attr = ATTR_ARCHIVE;
+        if let Some(now) = clock::now() {
+            entry.stamp_created(now);
+        }
+        self.insert_entry(parent, name, entry)?;
+        self.find_record(parent, name)
+    }
+
+    // O_TRUNC of an existing file: the entry drops its clusters, then they
+    // are freed, so a failure part way leaks them rather than sharing them
+    fn truncate_entry(&mut self, path: &str) -> Result<DirRecord, Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let (location, _, name) = self.resolve_parent(path)?;
+        let mut record = self.find_record(location, name)?;
+        if record.is_dir() {
+            return Err(FsError::IsDir.into());
+        }
+        let first_cluster = record.first_cluster();
+        record.entry.set_first_cluster(0);
+        record.entry.file_size = 0;
+        record.size = 0;
+        if let Some(now) = clock::now() {
+            record.entry.set_modified(now);
+        }
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        record.entry.to_bytes(&mut raw)?;
+        self.write_slots(location, record.slot, &[raw])?;
+        if first_cluster != 0 {
+            self.free_chain(first_cluster)?;
+        }
+        Ok(record)
+    }
+
+    /// Opens the entry a path walk ended at.
+    pub fn open_record(
+        &mut self,
+        record: DirRecord,
+        flags: OpenFlags,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let entry = record.entry;
+        let is_dir = (entry.attr & ATTR_DIRECTORY) != 0;
+        if flags.contains(OpenFlags::O_DIRECTORY) && !is_dir {
+            return Err(Error::InvalidArgs);
+        }
+
+        let cluster_hi = entry.fst_clus_hi as u32;
+        let cluster_lo = entry.fst_clus_lo as u32;
+
+        let first_cluster = (cluster_hi << 16) | cluster_lo;
+
+        if is_dir {
+            let location = self.record_location(&record);
+            return Ok(Box::new(FatDirHandle {
+                reader: self.reader.clone(),
+                ops: self.ops.clone(),
+                entry,
+                location,
+                stream: DirStream::new(location),
+            }));
+        }
+
+        Ok(Box::new(FatFileHandle {
+            reader: self.reader.clone(),
+            ops: self.ops.clone(),
+            first_cluster,
+            contiguous: record.no_fat_chain,
+            entry,
+            pos: 0,
+            size: record.size as usize,
+            cursor_index: 0,
+            cursor_cluster: first_cluster,
+            ring_vaddr: self.ring_vaddr,
+            ring_size: self.ring_size,
+            uring: None,
+            user_shm_base: 0,
+            server_shm_base: 0,
+            readahead: Readahead::new(),
+        }))
+    }
+
+    /// Creates an empty directory holding only its "." and ".." entries.
+    pub fn mkdir(&mut self, path: &str, _mode: u32) -> Result<(), Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let (parent, parent_cluster, name) = self.resolve_parent(path)?;
+        match self.find_record(parent, name) {
+            Ok(_) => return Err(Error::AlreadyExists),
+            Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        // alloc_cluster hands back a zeroed cluster, so only the dot entries remain
+        let cluster = self.alloc_cluster(None)?;
+        let mut entry = DirEntry::default();
+        entry.attr = ATTR_DIRECTORY;
+        entry.set_first_cluster(cluster);
+        if let Some(now) = clock::now() {
+            entry.stamp_created(now);
+        }
+
+        let result = self
+            .write_dot_entries(entry, parent_cluster)
+            .and_then(|_| self.insert_entry(parent, name, entry));
+        if result.is_err() {
+            let _ = self.free_chain(cluster);
+        }
+        result
+    }
+
+    /// Removes a file or an empty directory: its entry and long-name slots
+    /// are marked deleted and its cluster chain goes back to the FAT.
+    pub fn unlink(&mut self, path: &str) -> Result<(), Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let (location, _, name) = self.resolve_parent(path)?;
+        let record = self.find_record(location, name)?;
+        let first_cluster = record.first_cluster();
+        if record.is_dir() && !self.dir_is_empty(first_cluster)? {
+            return Err(Error::InvalidArgs);
+        }
+
+        // Drop the entry first so a failure part way leaks clusters instead of
+        // leaving an entry that points into free space
+        self.delete_slots(location, &record)?;
+        self.free_chain(first_cluster)
+    }
+
+    /// Removes `path` and everything below it in a single request.
+    pub fn rmtree(&mut self, path: &str) -> Result<(), Error> {
+        let mut job = RmtreeJob::new(path)?;
+        while let Step::Continue = job.step(self)? {}
+        Ok(())
+    }
+
+    pub fn stat_path(&mut self, path: &str) -> Result<Stat, Error> {
+        let record = self.lookup_record(path)?;
+        let mode = if record.is_dir() { 0o040755 } else { 0o100644 };
+        Ok(entry_stat(&record.entry, record.size as usize, mode))
+    }
+
+    /// SET_TIMES of `path`. The entry keeps the date of the last access and
+    /// the modification time to two seconds; the root has no entry to keep
+    /// them in.
+    pub fn set_times(&mut self, path: &str, req: TimesRequest) -> Result<(), Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let (location, _, name) = self.resolve_parent(path)?;
+        let mut record = self.find_record(location, name)?;
+        if let Some(atime) = req.atime {
+            record.entry.set_accessed(atime);
+        }
+        if let Some(mtime) = req.mtime {
+            record.entry.set_modified(mtime);
+        }
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        record.entry.to_bytes(&mut raw)?;
+        self.write_slots(location, record.slot, &[raw])
+    }
+
+    /// Moves an entry to `new_path`, possibly in another directory. The
+    /// target must not exist; the new entry gets a fresh 8.3 alias and long name.
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let (old_dir, _, old_name) = self.resolve_parent(old_path)?;
+        let (new_dir, new_parent_cluster, new_name) = self.resolve_parent(new_path)?;
+        let record = self.find_record(old_dir, old_name)?;
+
+        match self.find_record(new_dir, new_name) {
+            // Renaming an entry onto itself only changes the spelling
+            Ok(existing) if new_dir != old_dir || existing.slot != record.slot => {
+                return Err(Error::AlreadyExists);
+            }
+            Ok(_) | Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        if record.is_dir() {
+            let mut old_prefix = fs_common::path::normalize(old_path).to_ascii_lowercase();
+            old_prefix.push('/');
+            if fs_common::path::normalize(new_path).to_ascii_lowercase().starts_with(&old_prefix) {
+                // A directory cannot move below itself
+                return Err(Error::InvalidArgs);
+            }
+        }
+
+        self.insert_entry(new_dir, new_name, record.entry)?;
+        self.delete_slots(old_dir, &record)?;
+
+        if record.is_dir() && new_dir != old_dir {
+            // Repoint ".." of the moved directory; the root is always cluster 0 there
+            let moved = RootLocation::Cluster(record.first_cluster());
+            let dotdot = self.find_record(moved, "..")?;
+            let mut entry = dotdot.entry;
+            entry.set_first_cluster(new_parent_cluster);
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            entry.to_bytes(&mut raw)?;
+            self.write_slots(moved, dotdot.slot, &[raw])?;
+        }
+        Ok(())
+    }
+}
+
+impl FatFs {
+    // Children of a directory record, honoring exFAT contiguous allocations
+    fn record_location(&self, record: &DirRecord) -> RootLocation {
+        if record.no_fat_chain && record.first_cluster() != 0 {
+            let clusters = record.size.div_ceil(self.cluster_size() as u64);
+            RootLocation::Contiguous(record.first_cluster(), clusters as u32)
+        } else {
+            self.dir_location(record.first_cluster())
+        }
+    }
+
+    // Children of a directory entry's cluster; cluster 0 stands for the root
+    fn dir_location(&self, first_cluster: u32) -> RootLocation {
+        if first_cluster == 0 {
+            self.ops.get_root_location()
+        } else {
+            RootLocation::Cluster(first_cluster)
+        }
+    }
+
+    // Splits `path` into the parent directory (location and first cluster) and the final name
+    fn resolve_parent<'p>(&self, path: &'p str) -> Result<(RootLocation, u32, &'p str), Error> {
+        let trimmed = path.trim_end_matches('/');
+        let (parent, name) = match trimmed.rfind('/') {
+            Some(idx) => (&trimmed[..idx], &trimmed[idx + 1..]),
+            None => ("", trimmed),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(Error::InvalidArgs);
+        }
+        let entry = self.lookup(parent)?;
+        if (entry.attr & ATTR_DIRECTORY) == 0 {
+            return Err(FsError::NotDir.into());
+        }
+        let cluster = entry.first_cluster();
+        Ok((self.dir_location(cluster), cluster, name))
+    }
+
+    fn short_names(&self, location: RootLocation) -> Result<Vec<[u8; 11]>, Error> {
+        let mut names = Vec::new();
+        let mut stream = DirStream::new(location);
+        while let Some(record) = stream.next(&self.reader, self.ops.as_ref())? {
+            names.push(record.entry.name);
+        }
+        Ok(names)
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.ops.sectors_per_cluster() as usize * self.ops.bytes_per_sector() as usize
+    }
+
+    // Device byte offset of a directory slot, or NotFound past the end of the directory
+    fn slot_offset(&self, location: RootLocation, slot: usize) -> Result<usize, Error> {
+        let bps = self.ops.bytes_per_sector() as usize;
+        let byte = slot * DIR_ENTRY_SIZE;
+        match location {
+            RootLocation::Sector(start, count) => {
+                if byte >= count as usize * bps {
+                    return Err(Error::NotFound);
+                }
+                Ok(start * bps + byte)
+            }
+            RootLocation::Cluster(first) => {
+                let cluster_size = self.cluster_size();
+                let mut cluster = first;
+                for _ in 0..byte / cluster_size {
+                    cluster = self.get_next_cluster(cluster)?;
+                    if cluster < 2 || cluster >= 0x0FFFFFF8 {
+                        return Err(Error::NotFound);
+                    }
+                }
+                Ok(self.ops.cluster_to_sector(cluster) * bps + byte % cluster_size)
+            }
+            RootLocation::Contiguous(first, count) => {
+                let cluster_size = self.cluster_size();
+                if byte / cluster_size >= count as usize {
+                    return Err(Error::NotFound);
+                }
+                let cluster = first + (byte / cluster_size) as u32;
+                Ok(self.ops.cluster_to_sector(cluster) * bps + byte % cluster_size)
+            }
+        }
+    }
+
+    fn read_slot(
         &self,
-        start_sector: usize,
-        num_sectors: u32,
-        buf: &mut [u8],
+        location: RootLocation,
+        slot: usize,
+    ) -> Result<[u8; DIR_ENTRY_SIZE], Error> {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.reader.read_offset(self.slot_offset(location, slot)?, &mut raw)?;
+        Ok(raw)
+    }
+
+    fn write_slots(
+        &self,
+        location: RootLocation,
+        first_slot: usize,
+        slots: &[[u8; DIR_ENTRY_SIZE]],
     ) -> Result<(), Error> {
         let bps = self.ops.bytes_per_sector() as usize;
-        let size = num_sectors as usize * bps;
-        if buf.len() < size as usize {
-            return Err(Error::MessageTooLong);
+        let mut sector = alloc::vec![0u8; bps];
+        for (i, raw) in slots.iter().enumerate() {
+            let offset = self.slot_offset(location, first_slot + i)?;
+            let sector_start = offset - offset % bps;
+            self.reader.read_offset(sector_start, &mut sector)?;
+            sector[offset % bps..offset % bps + DIR_ENTRY_SIZE].copy_from_slice(raw);
+            self.reader.write_blocks(sector_start / 512, &sector)?;
         }
-        let offset = start_sector * bps;
-        self.reader
-            .read_offset(offset, &mut buf[..size as usize])
-            .map_err(|_| Error::IoError)
-            .map(|_| ())
+        Ok(())
     }
 
-    fn matches(fat_name: &[u8; 11], name: &str) -> bool {
-        let mut normalized = [0x20u8; 11];
-        let mut name_iter = name.bytes();
-        let mut i = 0;
-        loop {
-            match name_iter.next() {
-                Some(b'.') => break,
-                Some(b) => {
-                    if i < 8 {
-                        normalized[i] = b.to_ascii_uppercase();
-                        i += 1;
-                    } else {
-                        return false;
-                    }
-                }
-                None => break,
+    /// Adds `entry` to a directory under `name`, with a fresh 8.3 alias and,
+    /// when the name does not fit one, long-name slots in front of it.
+    fn insert_entry(
+        &self,
+        location: RootLocation,
+        name: &str,
+        mut entry: DirEntry,
+    ) -> Result<(), Error> {
+        let (short_name, mut slots) = match exact_short_name(name) {
+            Some(short_name) => (short_name, Vec::new()),
+            None => {
+                let taken = self.short_names(location)?;
+                let short_name = generate_short_name(name, |n| taken.contains(n))?;
+                (short_name, long_name_slots(name, &short_name)?)
             }
+        };
+        entry.name = short_name;
+        entry.nt_res = 0;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        entry.to_bytes(&mut raw)?;
+        slots.push(raw);
+
+        let first_slot = self.alloc_slots(location, slots.len())?;
+        self.write_slots(location, first_slot, &slots)
+    }
+
+    // Fills the first two slots of a new directory with "." and ".."
+    fn write_dot_entries(&self, entry: DirEntry, parent_cluster: u32) -> Result<(), Error> {
+        let mut dot = entry;
+        dot.name = *b".          ";
+        let mut dotdot = entry;
+        dotdot.name = *b"..         ";
+        // The root comes back from resolve_parent as cluster 0, which is also what ".." wants
+        dotdot.set_first_cluster(parent_cluster);
+
+        let mut slots = [[0u8; DIR_ENTRY_SIZE]; 2];
+        dot.to_bytes(&mut slots[0])?;
+        dotdot.to_bytes(&mut slots[1])?;
+        self.write_slots(RootLocation::Cluster(entry.first_cluster()), 0, &slots)
+    }
+
+    // Marks the entry and the long-name slots in front of it deleted
+    fn delete_slots(&self, location: RootLocation, record: &DirRecord) -> Result<(), Error> {
+        for slot in record.first_slot..=record.slot {
+            let mut raw = self.read_slot(location, slot)?;
+            raw[0] = DELETED_ENTRY;
+            self.write_slots(location, slot, &[raw])?;
         }
+        Ok(())
+    }
 
-        let mut i = 8;
-        while let Some(b) = name_iter.next() {
-            if i < 11 {
-                normalized[i] = b.to_ascii_uppercase();
-                i += 1;
-            } else {
-                return false;
+    fn dir_is_empty(&self, first_cluster: u32) -> Result<bool, Error> {
+        let mut stream = DirStream::new(self.dir_location(first_cluster));
+        while let Some(record) = stream.next(&self.reader, self.ops.as_ref())? {
+            let name = short_name_to_string(&record.entry);
+            if name != "." && name != ".." {
+                return Ok(false);
             }
         }
+        Ok(true)
+    }
 
-        &normalized == fat_name
+    /// Returns every cluster of a chain to the FAT.
+    fn free_chain(&self, first_cluster: u32) -> Result<(), Error> {
+        // Walk the whole chain first; a freed entry no longer links to the next
+        let chain = self.get_cluster_chain(first_cluster)?;
+        for &cluster in &chain {
+            self.ops.set_next_cluster(&self.reader, cluster, 0)?;
+            if let Some(bitmap) = &self.bitmap {
+                bitmap.lock().set(&self.reader, cluster, false)?;
+            }
+        }
+        if let Some(extents) = self.extents.lock().as_mut() {
+            chain.iter().for_each(|&cluster| extents.release(cluster));
+        }
+        let mut space = self.space.lock();
+        space.released(chain.len() as u32);
+        space.store(&self.reader)?;
+        drop(space);
+        let mut rest = chain.as_slice();
+        while let Some(&first) = rest.first() {
+            let run = rest.iter().zip(first..).take_while(|(&c, n)| c == *n).count();
+            let _ = self.discard_clusters(first, run as u32);
+            rest = &rest[run..];
+        }
+        Ok(())
     }
 
-    fn scan_dir_entries(&self, data: &[u8], name: &str) -> Result<DirEntry, Error> {
-        for chunk in data.chunks(32) {
-            if chunk.len() < 32 {
-                break;
+    // Discards the device blocks under `count` clusters from `first`
+    fn discard_clusters(&self, first: u32, count: u32) -> Result<(), Error> {
+        let offset = self.ops.cluster_to_sector(first) * self.ops.bytes_per_sector() as usize;
+        self.reader.discard(offset, count as usize * self.cluster_size())
+    }
+
+    /// TRIM: discards every run of free clusters at least `min_len` bytes
+    /// long. Returns the bytes discarded.
+    pub fn trim(&self, min_len: usize) -> Result<usize, Error> {
+        if !self.reader.tuning().discard {
+            return Err(Error::NotSupported);
+        }
+        let cluster_size = self.cluster_size();
+        let runs: Vec<(u32, u32)> = match self.free_extents()?.as_ref() {
+            Some(extents) => extents.runs().collect(),
+            None => Vec::new(),
+        };
+        let mut trimmed = 0;
+        for (first, len) in runs {
+            let bytes = len as usize * cluster_size;
+            if bytes >= min_len {
+                self.discard_clusters(first, len)?;
+                trimmed += bytes;
             }
-            if chunk[0] == 0 {
-                return Err(Error::NotFound);
+        }
+        Ok(trimmed)
+    }
+
+    // Free clusters: exFAT's allocation bitmap keeps the exact count. On
+    // FAT, kept from the FAT32 FSInfo sector or an earlier count, otherwise
+    // counted through the FAT once. Without its bitmap an exFAT volume only
+    // has the percent-in-use of its boot sector.
+    fn free_cluster_count(&self) -> Result<u32, Error> {
+        if let Some(bitmap) = &self.bitmap {
+            return Ok(bitmap.lock().free_count());
+        }
+        if let Some(free) = self.space.lock().free() {
+            return Ok(free);
+        }
+        let count = self.ops.cluster_count();
+        let free = if self.ops.is_exfat() {
+            let mut boot = [0u8; 512];
+            self.reader.read_offset(0, &mut boot)?;
+            // 0xFF means not known; claiming nothing free is the safe side
+            match ExFatBpb::from_bytes(&boot)?.percent_in_use {
+                percent @ 0..=100 => (count as u64 * (100 - percent as u64) / 100) as u32,
+                _ => 0,
             }
-            if chunk[0] == 0xE5 {
-                continue;
+        } else {
+            let mut free = 0;
+            for cluster in 2..count + 2 {
+                if self.get_next_cluster(cluster)? == 0 {
+                    free += 1;
+                }
             }
+            free
+        };
+        self.space.lock().counted(free);
+        Ok(free)
+    }
 
-            let entry = unsafe { core::ptr::read_unaligned(chunk.as_ptr() as *const DirEntry) };
-            if (entry.attr & ATTR_LONG_NAME) == ATTR_LONG_NAME {
-                continue;
+    /// Finds `count` consecutive free slots, growing a cluster-chained
+    /// directory when it runs out. The fixed FAT12/16 root cannot grow.
+    fn alloc_slots(&self, location: RootLocation, count: usize) -> Result<usize, Error> {
+        let (block_size, mut cluster) = match location {
+            RootLocation::Sector(_, sectors) => {
+                (sectors as usize * self.ops.bytes_per_sector() as usize, 0)
             }
-            if (entry.attr & ATTR_VOLUME_ID) != 0 {
-                continue;
+            RootLocation::Cluster(first) => (self.cluster_size(), first),
+            // Growing would need the whole run to move or a FAT chain built
+            RootLocation::Contiguous(..) => return Err(Error::NotSupported),
+        };
+        let mut block = alloc::vec![0u8; block_size];
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut slot = 0;
+
+        loop {
+            let offset = match location {
+                RootLocation::Sector(start, _) => start * self.ops.bytes_per_sector() as usize,
+                RootLocation::Cluster(_) | RootLocation::Contiguous(..) => {
+                    self.ops.cluster_to_sector(cluster) * self.ops.bytes_per_sector() as usize
+                }
+            };
+            self.reader.read_offset(offset, &mut block)?;
+            for raw in block.chunks_exact(DIR_ENTRY_SIZE) {
+                if raw[0] == 0 || raw[0] == DELETED_ENTRY {
+                    if run_len == 0 {
+                        run_start = slot;
+                    }
+                    run_len += 1;
+                    if run_len == count {
+                        return Ok(run_start);
+                    }
+                } else {
+                    run_len = 0;
+                }
+                slot += 1;
             }
 
-            if Self::matches(&entry.name, name) {
-                return Ok(entry);
+            // The FAT12/16 root directory has a fixed size
+            if let RootLocation::Sector(..) = location {
+                return Err(FsError::NoSpace.into());
             }
+            let next = self.get_next_cluster(cluster)?;
+            cluster = if next < 2 || next >= 0x0FFFFFF8 {
+                self.alloc_cluster(Some(cluster))?
+            } else {
+                next
+            };
         }
-        Err(Error::NotFound)
     }
 
-    pub fn find_entry(&self, location: RootLocation, name: &str) -> Result<DirEntry, Error> {
-        match location {
-            RootLocation::Cluster(cluster) => {
-                let chain = self.get_cluster_chain(cluster)?;
-                let cluster_size = (self.ops.sectors_per_cluster() as usize)
-                    * (self.ops.bytes_per_sector() as usize);
-                let mut buf = alloc::vec![0u8; cluster_size];
-
-                for c in chain {
-                    self.read_cluster(c, &mut buf)?;
-                    match self.scan_dir_entries(&buf, name) {
-                        Ok(entry) => return Ok(entry),
-                        Err(Error::NotFound) => continue, // Check next cluster
-                        Err(e) => return Err(e),
-                    }
-                }
-                Err(Error::NotFound)
+    /// Allocates a zeroed cluster, marks it end-of-chain and links it after `prev`.
+    fn alloc_cluster(&self, prev: Option<u32>) -> Result<u32, Error> {
+        let cluster = match self.policy {
+            AllocPolicy::NextFree => self.next_free_cluster()?,
+            AllocPolicy::Contiguous => self.contiguous_cluster(prev)?,
+        }
+        .ok_or(FsError::NoSpace)?;
+        // FAT cannot mark a cluster unwritten, so it is zeroed here
+        let offset = self.ops.cluster_to_sector(cluster) * self.ops.bytes_per_sector() as usize;
+        let len = self.cluster_size();
+        zeroing::fill(offset, len, |at, zeros| self.reader.write_blocks(at / 512, zeros))?;
+        if self.options.zero_alloc {
+            // The cache flushes in block order, which would put the FAT first
+            self.reader.flush_blocks(offset / 512, len)?;
+        }
+        if let Some(bitmap) = &self.bitmap {
+            bitmap.lock().set(&self.reader, cluster, true)?;
+        }
+        self.ops.set_next_cluster(&self.reader, cluster, 0x0FFFFFFF)?;
+        if let Some(prev) = prev {
+            self.ops.set_next_cluster(&self.reader, prev, cluster)?;
+        }
+        if let Some(extents) = self.extents.lock().as_mut() {
+            extents.take(cluster);
+        }
+        let mut space = self.space.lock();
+        space.allocated(cluster);
+        space.store(&self.reader)?;
+        Ok(cluster)
+    }
+
+    // The first free cluster from where the last allocation ended, so a
+    // volume filling up does not rescan its full start every time
+    fn next_free_cluster(&self) -> Result<Option<u32>, Error> {
+        let count = self.ops.cluster_count();
+        let start = self.space.lock().next_free() - 2;
+        for i in 0..count {
+            let cluster = 2 + (start + i) % count;
+            if self.cluster_is_free(cluster)? {
+                return Ok(Some(cluster));
             }
-            RootLocation::Sector(start, count) => {
-                let bytes_len = (count as usize * self.ops.bytes_per_sector() as usize) as usize;
-                let mut buf = alloc::vec![0u8; bytes_len];
-                self.read_sectors(start, count, &mut buf)?;
-                self.scan_dir_entries(&buf, name)
+        }
+        Ok(None)
+    }
+
+    // The cluster right after `prev` when it is free, otherwise the start of
+    // the free extent that best fits a chain with room to grow
+    fn contiguous_cluster(&self, prev: Option<u32>) -> Result<Option<u32>, Error> {
+        if let Some(prev) = prev {
+            let next = prev + 1;
+            if next < self.ops.cluster_count() + 2 && self.cluster_is_free(next)? {
+                return Ok(Some(next));
             }
         }
+        Ok(self.free_extents()?.as_ref().and_then(|extents| extents.best_fit(GROWTH_ROOM)))
     }
 
-    pub fn lookup(&self, path: &str) -> Result<DirEntry, Error> {
-        let root_loc = self.ops.get_root_location();
-
-        let path_parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-        if path_parts.is_empty() {
-            return Ok(DirEntry {
-                name: [0x20; 11],
-                attr: ATTR_DIRECTORY,
-                nt_res: 0,
-                crt_time_tenth: 0,
-                crt_time: 0,
-                crt_date: 0,
-                lst_acc_date: 0,
-                fst_clus_hi: 0,
-                wrt_time: 0,
-                wrt_date: 0,
-                fst_clus_lo: 0,
-                file_size: 0,
-            });
+    // By the allocation bitmap on exFAT, whose FAT holds nothing for
+    // contiguous files; by the cluster's FAT entry otherwise
+    fn cluster_is_free(&self, cluster: u32) -> Result<bool, Error> {
+        match &self.bitmap {
+            Some(bitmap) => Ok(bitmap.lock().is_free(cluster)),
+            None if self.ops.is_exfat() => Err(Error::NotSupported),
+            None => Ok(self.get_next_cluster(cluster)? == 0),
         }
+    }
 
-        let mut current_loc = root_loc;
-        // Mock entry for initial state is tricky if we don't have it, but we only need it for return if path is empty.
-        // If loop runs, current_entry is updated.
-        let mut current_entry = DirEntry {
-            name: [0x20; 11],
-            attr: ATTR_DIRECTORY,
-            nt_res: 0,
-            crt_time_tenth: 0,
-            crt_time: 0,
-            crt_date: 0,
-            lst_acc_date: 0,
-            fst_clus_hi: 0,
-            wrt_time: 0,
-            wrt_date: 0,
-            fst_clus_lo: 0,
-            file_size: 0,
-        };
+    fn free_extents(&self) -> Result<MutexGuard<'_, Option<FreeExtents>>, Error> {
+        let mut extents = self.extents.lock();
+        if extents.is_none() {
+            let count = self.ops.cluster_count();
+            *extents = Some(FreeExtents::build(count, |c| self.cluster_is_free(c))?);
+        }
+        Ok(extents)
+    }
 
-        for (i, part) in path_parts.iter().enumerate() {
-            let entry = self.find_entry(current_loc, part)?;
+    pub fn alloc_policy(&self) -> AllocPolicy {
+        self.policy
+    }
 
-            if i < path_parts.len() - 1 {
-                if (entry.attr & ATTR_DIRECTORY) == 0 {
-                    return Err(Error::NotSupported); // Not a dir
-                }
-                let cluster_hi = entry.fst_clus_hi as u32;
-                let cluster_lo = entry.fst_clus_lo as u32;
-                let cluster = (cluster_hi << 16) | cluster_lo;
-                current_loc = RootLocation::Cluster(cluster);
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) -> Result<(), Error> {
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        self.policy = policy;
+        Ok(())
+    }
+
+    /// FRAG_STATS: walks the whole FAT, counting the breaks in every chain.
+    pub fn frag_stats(&self, utcb: &mut UTCB) -> Result<(), Error> {
+        // exFAT leaves the FAT empty for contiguous files
+        if self.ops.is_exfat() {
+            return Err(Error::NotSupported);
+        }
+        let count = self.ops.cluster_count();
+        let (mut used, mut breaks) = (0, 0);
+        for cluster in 2..count + 2 {
+            let next = self.get_next_cluster(cluster)?;
+            if next == 0 {
+                continue;
+            }
+            used += 1;
+            if (2..count + 2).contains(&next) && next != cluster + 1 {
+                breaks += 1;
             }
-            current_entry = entry;
         }
+        let (runs, longest) = self.free_extents()?.as_ref().map_or((0, 0), |e| e.summary());
+        utcb.set_mr(0, used);
+        utcb.set_mr(1, count as usize - used);
+        utcb.set_mr(2, runs);
+        utcb.set_mr(3, longest as usize);
+        utcb.set_mr(4, breaks);
+        Ok(())
+    }
+}
 
-        Ok(current_entry)
+// FAT sectors compared across the FAT copies by the mount-time scrub, and
+// read at a time by the full verify pass
+const SCRUB_FAT_SECTORS: usize = 64;
+
+// Sectors in one FAT copy
+fn fat_size(bpb: &BiosParameterBlock) -> usize {
+    if bpb.fat_sz_16 != 0 {
+        bpb.fat_sz_16 as usize
+    } else {
+        bpb.fat_sz_32 as usize
     }
 }
 
+// First sector of FAT copy `index`
+fn fat_location(bpb: &BiosParameterBlock, index: u8) -> usize {
+    bpb.rsvd_sec_cnt as usize + index as usize * fat_size(bpb)
+}
+
 impl FatFs {
-    pub fn open_handle(
-        &mut self,
-        path: &str,
-        _flags: OpenFlags,
-        _mode: u32,
-    ) -> Result<Box<dyn FileHandleService + Send>, Error> {
-        let entry = self.lookup(path)?;
-        if (entry.attr & 0x10) != 0 {
-            // Directory opening not fully supported in this simple handle
+    /// Quick read-only pass over the boot sector, the FAT copies and the root
+    /// directory, run at mount before the service starts answering requests.
+    pub fn scrub(&self) -> Result<ScrubReport, Error> {
+        let mut report = ScrubReport::new();
+        let mut boot = [0u8; 512];
+        self.reader.read_offset(0, &mut boot)?;
+        report.check(boot[510] == 0x55 && boot[511] == 0xAA, || {
+            String::from("boot sector signature missing")
+        });
+
+        if !self.ops.is_exfat() {
+            let bpb = BiosParameterBlock::from_bytes(&boot)?;
+            let backup_sector = bpb.bk_boot_sec as usize;
+            if bpb.fat_sz_16 == 0 && backup_sector != 0 {
+                let mut backup = [0u8; 512];
+                let bps = self.ops.bytes_per_sector() as usize;
+                self.reader.read_offset(backup_sector * bps, &mut backup)?;
+                report.check(backup == boot, || {
+                    format!("backup boot sector {} differs from the primary", backup_sector)
+                });
+            }
+            self.scrub_fat_copies(&bpb, &mut report)?;
         }
 
-        let cluster_hi = entry.fst_clus_hi as u32;
-        let cluster_lo = entry.fst_clus_lo as u32;
+        self.scrub_root(&mut report)?;
+        Ok(report)
+    }
 
-        let first_cluster = (cluster_hi << 16) | cluster_lo;
+    fn scrub_fat_copies(
+        &self,
+        bpb: &BiosParameterBlock,
+        report: &mut ScrubReport,
+    ) -> Result<(), Error> {
+        let bps = self.ops.bytes_per_sector() as usize;
+        let media = bpb.media;
+        let mut first = [0u8; 1];
+        self.reader
+            .read_offset(fat_location(bpb, bpb.active_fat().unwrap_or(0)) * bps, &mut first)?;
+        report
+            .check(first[0] == media, || format!("FAT[0] does not carry media byte {:#x}", media));
+        for (index, sector) in self.fat_mismatches(bpb, SCRUB_FAT_SECTORS)? {
+            report.check(false, || {
+                format!("FAT copy {} differs from FAT 0 at sector {}", index, sector)
+            });
+        }
+        Ok(())
+    }
 
-        Ok(Box::new(FatFileHandle {
-            reader: self.reader.clone(),
-            ops: self.ops.clone(),
-            first_cluster,
-            pos: 0,
-            size: entry.file_size as usize,
-            ring_vaddr: self.ring_vaddr,
-            ring_size: self.ring_size,
-            uring: None,
-            user_shm_base: 0,
-            server_shm_base: 0,
-        }))
+    /// Cross-checks the whole of every FAT copy against the first, run at
+    /// mount. Returns each copy that differs with its first differing sector.
+    /// FAT12/16/32 only; with mirroring off the copies are not compared.
+    pub fn verify_fat_copies(&self) -> Result<Vec<(usize, usize)>, Error> {
+        if self.ops.is_exfat() {
+            return Ok(Vec::new());
+        }
+        let mut boot = [0u8; 512];
+        self.reader.read_offset(self.boot_backup.unwrap_or(0), &mut boot)?;
+        let bpb = BiosParameterBlock::from_bytes(&boot)?;
+        self.fat_mismatches(&bpb, usize::MAX)
     }
 
-    pub fn mkdir(&mut self, _path: &str, _mode: u32) -> Result<(), Error> {
+    // Compares the first `sectors` sectors of each further FAT copy with
+    // FAT 0, a batch at a time. With mirroring off the copies other than the
+    // active one are stale on purpose, so nothing is compared.
+    fn fat_mismatches(
+        &self,
+        bpb: &BiosParameterBlock,
+        sectors: usize,
+    ) -> Result<Vec<(usize, usize)>, Error> {
+        let mut mismatches = Vec::new();
+        if bpb.active_fat().is_some() {
+            return Ok(mismatches);
+        }
+        let bps = self.ops.bytes_per_sector() as usize;
+        let sectors = core::cmp::min(fat_size(bpb), sectors);
+        let mut primary = alloc::vec![0u8; SCRUB_FAT_SECTORS * bps];
+        let mut copy = alloc::vec![0u8; SCRUB_FAT_SECTORS * bps];
+        for index in 1..bpb.num_fats {
+            for batch in (0..sectors).step_by(SCRUB_FAT_SECTORS) {
+                let len = core::cmp::min(SCRUB_FAT_SECTORS, sectors - batch) * bps;
+                let (primary, copy) = (&mut primary[..len], &mut copy[..len]);
+                self.reader.read_offset((fat_location(bpb, 0) + batch) * bps, primary)?;
+                self.reader.read_offset((fat_location(bpb, index) + batch) * bps, copy)?;
+                let differs = primary.chunks(bps).zip(copy.chunks(bps)).position(|(a, b)| a != b);
+                if let Some(sector) = differs {
+                    mismatches.push((index as usize, batch + sector));
+                    break;
+                }
+            }
+        }
+        Ok(mismatches)
+    }
+
+    fn scrub_root(&self, report: &mut ScrubReport) -> Result<(), Error> {
+        let limit = self.ops.cluster_count() + 2;
+        let root = self.ops.get_root_location();
+        if let RootLocation::Cluster(cluster) = root {
+            report.check((2..limit).contains(&cluster), || {
+                format!("root directory cluster {} out of range", cluster)
+            });
+        }
+        let mut stream = DirStream::new(root);
+        loop {
+            let record = match stream.next(&self.reader, self.ops.as_ref()) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    report.check(false, || format!("root directory unreadable: {:?}", e));
+                    break;
+                }
+            };
+            let cluster = record.first_cluster();
+            report.check(cluster == 0 || (2..limit).contains(&cluster), || {
+                format!("root entry {} points at cluster {}", record.name, cluster)
+            });
+        }
         Ok(())
     }
+}
+
+// File data cannot be written yet, so versions are only kept on RENAME and
+// cannot be restored
+impl VersionStore for FatFs {
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Box<dyn FsHandle>, Error> {
+        if proto::open_mutates(flags) {
+            return Err(Error::NotSupported);
+        }
+        self.open_handle(path, flags, 0)
+    }
+
+    fn stat(&mut self, path: &str) -> Result<Stat, Error> {
+        self.stat_path(path)
+    }
 
-    pub fn unlink(&mut self, _path: &str) -> Result<(), Error> {
+    fn unlink(&mut self, path: &str) -> Result<(), Error> {
+        FatFs::unlink(self, path)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        FatFs::rename(self, from, to)
+    }
+}
+
+/// Removal of a directory tree, one directory per step so it can run as a job.
+pub struct RmtreeJob {
+    path: String,
+    // Depth-first without recursion; a directory is removed once its children are gone
+    stack: Vec<(String, bool)>,
+    removed: usize,
+    found: usize,
+}
+
+impl RmtreeJob {
+    pub fn new(path: &str) -> Result<Self, Error> {
+        if path.split('/').all(|part| part.is_empty()) {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(Self {
+            path: String::from(path),
+            stack: alloc::vec![(String::from(path), false)],
+            removed: 0,
+            found: 1,
+        })
+    }
+
+    // Removes the files of `dir` and queues its subdirectories ahead of `dir` itself
+    fn expand(&mut self, fs: &mut FatFs, dir: String, first_cluster: u32) -> Result<(), Error> {
+        let mut stream = DirStream::new(RootLocation::Cluster(first_cluster));
+        let mut subdirs = Vec::new();
+        while let Some(record) = stream.next(&fs.reader, fs.ops.as_ref())? {
+            let name = short_name_to_string(&record.entry);
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child = fs_common::path::join(&dir, &name);
+            self.found += 1;
+            if record.is_dir() {
+                subdirs.push((child, false));
+            } else {
+                fs.unlink(&child)?;
+                self.removed += 1;
+            }
+        }
+        self.stack.push((dir, true));
+        self.stack.extend(subdirs);
         Ok(())
     }
+}
 
-    pub fn stat_path(&mut self, path: &str) -> Result<Stat, Error> {
-        let entry = self.lookup(path)?;
-        let mut stat = Stat::default();
-        stat.size = entry.file_size as usize;
-        stat.mode = if (entry.attr & 0x10) != 0 { 0o040755 } else { 0o100644 };
-        Ok(stat)
+impl Job<FatFs> for RmtreeJob {
+    fn step(&mut self, fs: &mut FatFs) -> Result<Step, Error> {
+        let Some((dir, emptied)) = self.stack.pop() else {
+            return Ok(Step::Done);
+        };
+        if !emptied {
+            let entry = fs.lookup(&dir)?;
+            if (entry.attr & ATTR_DIRECTORY) != 0 {
+                self.expand(fs, dir, entry.first_cluster())?;
+                return Ok(Step::Continue);
+            }
+        }
+        fs.unlink(&dir)?;
+        self.removed += 1;
+        Ok(if self.stack.is_empty() { Step::Done } else { Step::Continue })
     }
 
-    pub fn rename(&mut self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
-        Err(Error::NotImplemented)
+    fn progress(&self) -> (usize, usize) {
+        (self.removed, self.found)
+    }
+
+    fn touches(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+}
+
+// FAT keeps no change time, so the last write stands in for it
+fn entry_stat(entry: &DirEntry, size: usize, mode: u32) -> Stat {
+    let modified = entry.modified().stat_secs();
+    Stat {
+        size,
+        mode,
+        atime: entry.accessed().stat_secs(),
+        mtime: modified,
+        ctime: modified,
+        ..Default::default()
     }
 }
 
@@ -384,37 +1401,176 @@ pub struct FatFileHandle {
     reader: BlockReader,
     ops: Arc<dyn FatOps>,
     first_cluster: u32,
+    // exFAT NoFatChain: clusters follow `first_cluster` back to back
+    contiguous: bool,
+    // The entry opened, for its timestamps
+    entry: DirEntry,
     pos: usize,
     size: usize,
+    // Last resolved (cluster index, cluster) so sequential access continues the walk
+    cursor_index: u32,
+    cursor_cluster: u32,
     ring_vaddr: usize,
     ring_size: usize,
     uring: Option<glenda::io::uring::IoUringBuffer>,
     user_shm_base: usize,
     server_shm_base: usize,
+    readahead: Readahead,
+}
+
+pub struct FatDirHandle {
+    reader: BlockReader,
+    ops: Arc<dyn FatOps>,
+    entry: DirEntry,
+    location: RootLocation,
+    stream: DirStream,
+}
+
+impl FsHandle for FatDirHandle {}
+
+impl FileHandleService for FatDirHandle {
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::InvalidArgs)
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::InvalidArgs)
+    }
+
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.reader.flush()
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        Ok(entry_stat(&self.entry, 0, 0o040755))
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        let mut entries = Vec::new();
+        while entries.len() < count {
+            let record = match self.stream.next(&self.reader, self.ops.as_ref())? {
+                Some(record) => record,
+                None => break,
+            };
+            let type_ = if record.is_dir() { DT_DIR } else { DT_REG };
+            entries.push(dentry(
+                record.first_cluster() as usize,
+                self.stream.position(),
+                type_,
+                record.name.as_bytes(),
+            ));
+        }
+        Ok(entries)
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        // Directory positions are the `off` values handed out by getdents;
+        // the end of a FAT directory is only known by walking it.
+        if whence != SEEK_SET && whence != SEEK_CUR {
+            return Err(Error::InvalidArgs);
+        }
+        let target =
+            limits::seek_target(self.stream.position(), 0, offset, whence, u32::MAX as u64)?;
+        self.stream.seek(target);
+        Ok(target)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.reader.barrier()
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(Error::InvalidArgs)
+    }
 }
 
 impl FatFileHandle {
-    fn get_cluster_by_pos(&self, pos: usize) -> Result<u32, Error> {
+    // Bytes readable in one go from `cluster_offset` into the current cluster;
+    // a contiguous file is one run, so a read never has to stop at a boundary
+    fn run_left(&self, cluster_size: usize, cluster_offset: usize) -> usize {
+        if self.contiguous {
+            usize::MAX
+        } else {
+            cluster_size - cluster_offset
+        }
+    }
+
+    fn get_cluster_by_pos(&mut self, pos: usize) -> Result<u32, Error> {
         let cluster_size = (self.ops.sectors_per_cluster() * self.ops.bytes_per_sector()) as usize;
         let cluster_index = (pos / cluster_size) as u32;
+        if self.contiguous {
+            let cluster = self.first_cluster + cluster_index;
+            if cluster >= self.ops.cluster_count() + 2 {
+                return Err(FsError::Corrupt.into());
+            }
+            return Ok(cluster);
+        }
 
-        // Simple linear scan from start. Optimizations: cache current cluster key.
-        let mut curr = self.first_cluster;
-        for _ in 0..cluster_index {
+        // Resume from the cursor when moving forward, otherwise restart from the head
+        let (mut index, mut curr) = if cluster_index >= self.cursor_index {
+            (self.cursor_index, self.cursor_cluster)
+        } else {
+            (0, self.first_cluster)
+        };
+        while index < cluster_index {
             curr = self.ops.get_next_cluster(&self.reader, curr)?;
             if curr >= 0x0FFFFFF8 {
-                return Err(Error::IoError); // Unexpected EOF in chain
+                return Err(FsError::Corrupt.into()); // Unexpected EOF in chain
             }
+            index += 1;
         }
+
+        self.cursor_index = index;
+        self.cursor_cluster = curr;
         Ok(curr)
     }
 
-    fn read_shm_internal(&self, offset: usize, len: u32, shm_vaddr: usize) -> Result<usize, Error> {
-        if offset >= self.size {
-            return Ok(0);
+    // Prefetches the clusters a sequential reader is about to want after
+    // `len` bytes at `offset`, a run of adjacent clusters at a time
+    fn read_ahead(&mut self, offset: usize, len: usize) {
+        let cluster_size = (self.ops.sectors_per_cluster() * self.ops.bytes_per_sector()) as usize;
+        // The tuned window is in filesystem blocks, clusters here
+        let max_clusters = self.reader.tuning().readahead_blocks;
+        let Some((start, len)) =
+            self.readahead.advise(offset, len, self.size, cluster_size, max_clusters)
+        else {
+            return;
+        };
+        // Walking ahead moves the cursor past where the next read starts
+        let cursor = (self.cursor_index, self.cursor_cluster);
+        let mut run: Option<(u32, usize)> = None;
+        for pos in (start..start + len).step_by(cluster_size) {
+            let Ok(cluster) = self.get_cluster_by_pos(pos) else {
+                break;
+            };
+            match run {
+                Some((first, count)) if cluster == first + count as u32 => {
+                    run = Some((first, count + 1));
+                    continue;
+                }
+                Some(done) => self.prefetch_clusters(done, cluster_size),
+                None => {}
+            }
+            run = Some((cluster, 1));
         }
+        if let Some(done) = run {
+            self.prefetch_clusters(done, cluster_size);
+        }
+        (self.cursor_index, self.cursor_cluster) = cursor;
+    }
+
+    fn prefetch_clusters(&self, (first, count): (u32, usize), cluster_size: usize) {
+        let at = self.ops.cluster_to_sector(first) * self.ops.bytes_per_sector() as usize;
+        let _ = self.reader.prefetch(at, count * cluster_size, cluster_size);
+    }
 
-        let read_len = core::cmp::min(len as usize, self.size - offset) as usize;
+    fn read_shm_internal(
+        &mut self,
+        offset: usize,
+        len: u32,
+        shm_vaddr: usize,
+    ) -> Result<usize, Error> {
+        let read_len = limits::read_len(offset, len as usize, self.size);
         let cluster_size = (self.ops.sectors_per_cluster() * self.ops.bytes_per_sector()) as usize;
 
         let mut current_pos = offset;
@@ -424,7 +1580,7 @@ impl FatFileHandle {
         while remaining > 0 {
             let current_cluster = self.get_cluster_by_pos(current_pos)?;
             let cluster_offset = (current_pos % cluster_size) as usize;
-            let bytes_left_in_cluster = cluster_size as usize - cluster_offset;
+            let bytes_left_in_cluster = self.run_left(cluster_size, cluster_offset);
             let chunk_len = core::cmp::min(remaining, bytes_left_in_cluster);
 
             let cluster_start_sector = self.ops.cluster_to_sector(current_cluster);
@@ -444,11 +1600,9 @@ impl FatFileHandle {
 
 impl FileHandleService for FatFileHandle {
     fn read(&mut self, _badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
-        if offset >= self.size {
-            return Ok(0);
-        }
-
-        let read_len = core::cmp::min(buf.len() as usize, self.size - offset) as usize;
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        let read_len = limits::read_len(offset, buf.len(), self.size);
         if read_len == 0 {
             return Ok(0);
         }
@@ -460,7 +1614,7 @@ impl FileHandleService for FatFileHandle {
         while buf_offset < read_len {
             let current_cluster = self.get_cluster_by_pos(current_pos)?;
             let cluster_offset = (current_pos % cluster_size) as usize;
-            let bytes_left_in_cluster = cluster_size as usize - cluster_offset;
+            let bytes_left_in_cluster = self.run_left(cluster_size, cluster_offset);
             let bytes_to_read = core::cmp::min(read_len - buf_offset, bytes_left_in_cluster);
 
             // Calculate physical sector
@@ -485,39 +1639,66 @@ impl FileHandleService for FatFileHandle {
             buf_offset += bytes_to_read;
         }
 
-        self.pos = current_pos;
+        if advance {
+            self.pos = current_pos;
+        }
+        self.read_ahead(offset, read_len);
         Ok(read_len)
     }
 
-    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+    fn write(&mut self, _badge: Badge, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        let offset = if offset == CURRENT_OFFSET { self.pos } else { offset };
+        limits::checked_end(offset, buf.len(), self.ops.max_file_size())?;
         // Read-only for now
         Ok(0)
     }
 
     fn close(&mut self, _badge: Badge) -> Result<(), Error> {
-        Ok(())
+        self.reader.flush()
     }
 
     fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
-        let mut stat = Stat::default();
-        stat.size = self.size;
-        stat.mode = 0o100644;
-        Ok(stat)
+        Ok(entry_stat(&self.entry, self.size, 0o100644))
     }
 
     fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
         Err(Error::NotImplemented)
     }
 
-    fn seek(&mut self, _badge: Badge, _offset: i64, _whence: usize) -> Result<usize, Error> {
-        Err(Error::NotImplemented)
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        self.pos =
+            limits::seek_target(self.pos, self.size, offset, whence, self.ops.max_file_size())?;
+        Ok(self.pos)
     }
 
     fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
-        Ok(())
+        self.reader.barrier()
     }
 
     fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
         Err(Error::NotImplemented)
     }
 }
+
+impl FsHandle for FatFileHandle {
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        Ok(Box::new(FatFileHandle {
+            reader: self.reader.clone(),
+            ops: self.ops.clone(),
+            first_cluster: self.first_cluster,
+            contiguous: self.contiguous,
+            entry: self.entry,
+            pos: 0,
+            size: self.size,
+            // The chain does not change under a reader, so the walk carries over
+            cursor_index: self.cursor_index,
+            cursor_cluster: self.cursor_cluster,
+            ring_vaddr: self.ring_vaddr,
+            ring_size: self.ring_size,
+            uring: None,
+            user_shm_base: 0,
+            server_shm_base: 0,
+            readahead: Readahead::new(),
+        }))
+    }
+}
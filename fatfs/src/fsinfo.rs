@@ -0,0 +1,117 @@
+//! Free space bookkeeping: how many clusters are free and where the last
+//! allocation ended, neither of which the FAT itself records. FAT32 keeps
+//! both in its FSInfo sector as hints other implementations may leave stale,
+//! so they are checked against the volume when read and written back on every
+//! allocation and free. Other variants keep them in memory only.
+
+use fs_block::BlockReader;
+use glenda::error::Error;
+
+const FSINFO_LEAD_SIG: u32 = 0x41615252;
+const FSINFO_STRUC_SIG: u32 = 0x61417272;
+const FSINFO_TRAIL_SIG: u32 = 0xAA550000;
+const LEAD_SIG_OFFSET: usize = 0;
+const STRUC_SIG_OFFSET: usize = 484;
+const FREE_COUNT_OFFSET: usize = 488;
+const NEXT_FREE_OFFSET: usize = 492;
+const TRAIL_SIG_OFFSET: usize = 508;
+// Either hint when not known
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+const FSINFO_SIZE: usize = 512;
+
+pub struct FreeSpace {
+    // Byte offset of a valid FSInfo sector
+    fs_info: Option<usize>,
+    cluster_count: u32,
+    // None until read from FSInfo or counted
+    free: Option<u32>,
+    next_free: u32,
+}
+
+impl FreeSpace {
+    /// Nothing known yet about a volume of `cluster_count` clusters.
+    pub fn new(cluster_count: u32) -> Self {
+        Self { fs_info: None, cluster_count, free: None, next_free: 2 }
+    }
+
+    /// Reads the FSInfo sector at byte `offset`. Hints that do not fit the
+    /// volume are dropped; a sector without its signatures is left alone.
+    pub fn load(reader: &BlockReader, offset: usize, cluster_count: u32) -> Result<Self, Error> {
+        let mut space = Self::new(cluster_count);
+        let mut raw = [0u8; FSINFO_SIZE];
+        reader.read_offset(offset, &mut raw)?;
+        if field(&raw, LEAD_SIG_OFFSET) != FSINFO_LEAD_SIG
+            || field(&raw, STRUC_SIG_OFFSET) != FSINFO_STRUC_SIG
+            || field(&raw, TRAIL_SIG_OFFSET) != FSINFO_TRAIL_SIG
+        {
+            return Ok(space);
+        }
+        space.fs_info = Some(offset);
+        let free = field(&raw, FREE_COUNT_OFFSET);
+        space.free = (free <= cluster_count).then_some(free);
+        let next = field(&raw, NEXT_FREE_OFFSET);
+        if space.is_cluster(next) {
+            space.next_free = next;
+        }
+        Ok(space)
+    }
+
+    pub fn free(&self) -> Option<u32> {
+        self.free
+    }
+
+    /// Cluster the next allocation starts looking at.
+    pub fn next_free(&self) -> u32 {
+        self.next_free
+    }
+
+    /// Takes the result of counting the free clusters.
+    pub fn counted(&mut self, free: u32) {
+        self.free = Some(free);
+    }
+
+    pub fn allocated(&mut self, cluster: u32) {
+        self.free = self.free.map(|free| free.saturating_sub(1));
+        self.next_free = if self.is_cluster(cluster + 1) { cluster + 1 } else { 2 };
+    }
+
+    pub fn released(&mut self, clusters: u32) {
+        self.free = self.free.map(|free| core::cmp::min(free + clusters, self.cluster_count));
+    }
+
+    /// Writes the hints back to the FSInfo sector, if the volume has one.
+    pub fn store(&self, reader: &BlockReader) -> Result<(), Error> {
+        let Some(offset) = self.fs_info else {
+            return Ok(());
+        };
+        let mut raw = [0u8; FSINFO_SIZE];
+        reader.read_offset(offset, &mut raw)?;
+        let free = self.free.unwrap_or(FSINFO_UNKNOWN);
+        raw[FREE_COUNT_OFFSET..FREE_COUNT_OFFSET + 4].copy_from_slice(&free.to_le_bytes());
+        raw[NEXT_FREE_OFFSET..NEXT_FREE_OFFSET + 4].copy_from_slice(&self.next_free.to_le_bytes());
+        reader.write_blocks(offset / 512, &raw)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+}
+
+/// A fresh FSInfo sector holding both hints, for a newly formatted volume.
+pub fn encode(free: u32, next_free: u32) -> [u8; FSINFO_SIZE] {
+    let mut raw = [0u8; FSINFO_SIZE];
+    for (at, value) in [
+        (LEAD_SIG_OFFSET, FSINFO_LEAD_SIG),
+        (STRUC_SIG_OFFSET, FSINFO_STRUC_SIG),
+        (FREE_COUNT_OFFSET, free),
+        (NEXT_FREE_OFFSET, next_free),
+        (TRAIL_SIG_OFFSET, FSINFO_TRAIL_SIG),
+    ] {
+        raw[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    raw
+}
+
+fn field(raw: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]])
+}
@@ -4,16 +4,26 @@
 
 extern crate alloc;
 
+use fs_common::mount::{MountOptions, MountPoint};
+use fs_common::partition::PartitionSelect;
+use glenda::cap::{CapPtr, CapType, Endpoint, ENDPOINT_CAP, ENDPOINT_SLOT, REPLY_CAP};
+use glenda::client::FsClient;
 use glenda::interface::system::SystemService;
 use glenda::interface::ResourceService;
 use glenda::ipc::Badge;
+use glenda::protocol::resource::{ResourceType, FS_ENDPOINT};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
-use layout::{DEVICE_SLOT, RING_SIZE, RING_VADDR, VOLUME_CAP, VOLUME_SLOT};
+use layout::{DEVICE_SLOT, MOUNT_PATH, RING_SIZE, VFS_SLOT, VOLUME_CAP, VOLUME_SLOT};
 
-mod block;
+mod bitmap;
+mod cache;
 mod defs;
+mod dir;
+mod extents;
 mod fs;
+mod fsinfo;
 mod layout;
+mod mkfs;
 mod ops;
 mod server;
 mod versions;
@@ -42,8 +52,31 @@ fn main() -> usize {
         .get_device(Badge::null(), DEVICE_SLOT)
         .expect("FatFS: Failed to get block device");
 
-    let mut service = FatFsService::new(RING_VADDR, RING_SIZE, &mut cspace, &mut vspace);
-    service.init_fs(block_device, &mut res_client).expect("Failed to init FatFS");
+    res_client
+        .alloc(Badge::null(), CapType::Endpoint, 0, ENDPOINT_SLOT)
+        .expect("FatFS: Failed to allocate endpoint");
+    // Without a VFS the volume is only reachable through its endpoint
+    let vfs = res_client.get_cap(Badge::null(), ResourceType::Endpoint, FS_ENDPOINT, VFS_SLOT);
+
+    let mut service = FatFsService::new(RING_SIZE, &mut res_client, &mut cspace, &mut vspace);
+    service.listen(ENDPOINT_CAP, REPLY_CAP.cap(), CapPtr::null()).expect("FatFS: Failed to listen");
+    match vfs {
+        Ok(cap) => {
+            let vfs = FsClient::new(Endpoint::from(cap));
+            service.set_mount_point(MountPoint::new(vfs, MOUNT_PATH));
+        }
+        Err(e) => glenda::log!("FatFS: no VFS endpoint ({:?}), not mounting", e),
+    }
+    let init = service.init_fs(block_device, MountOptions::default(), PartitionSelect::Auto);
+    if let Err(e) = init {
+        // Not our format: tell the supervisor so it can try the next service
+        if let Some(found) = service.declined() {
+            return found.declined_exit();
+        }
+        panic!("Failed to init FatFS: {:?}", e);
+    }
+    #[cfg(feature = "scrub")]
+    service.scrub().expect("FatFS: mount scrub failed");
 
     service.run().expect("FatFs service crashed");
     0
@@ -0,0 +1,143 @@
+//! Writes a fresh, empty FAT32 volume for FORMAT: the boot sector and its
+//! backup, FSInfo, two FAT copies and an empty root directory, laid out the
+//! way the FAT specification recommends.
+
+use crate::defs::*;
+use crate::fsinfo;
+use fs_block::BlockReader;
+use fs_common::bytes::ToBytes;
+use fs_common::clock;
+use fs_common::zeroing;
+use glenda::error::Error;
+
+const SECTOR_SIZE: usize = 512;
+const RESERVED_SECTORS: u16 = 32;
+const NUM_FATS: u8 = 2;
+const FS_INFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+const ROOT_CLUSTER: u32 = 2;
+const MEDIA_FIXED_DISK: u8 = 0xF8;
+// With fewer clusters the volume would be FAT16 by definition
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+const OEM_NAME: &[u8; 8] = b"GLENDA  ";
+const NO_LABEL: &[u8; 11] = b"NO NAME    ";
+// Characters an 8.3 name, and so a volume label, may not hold
+const LABEL_FORBIDDEN: &[u8] = b"\"*+,./:;<=>?[\\]|";
+
+/// Formats the first `size` bytes of the volume behind `reader` as FAT32,
+/// labelled `label` when it is not empty.
+pub fn format(reader: &BlockReader, size: usize, label: &str) -> Result<(), Error> {
+    let label = volume_label(label)?;
+    let total = u32::try_from(size / SECTOR_SIZE).map_err(|_| Error::InvalidArgs)?;
+    let sec_per_clus = cluster_sectors(total);
+    let fat_sz = fat_sectors(total, sec_per_clus);
+    let data_start = RESERVED_SECTORS as u32 + NUM_FATS as u32 * fat_sz;
+    let clusters = total.checked_sub(data_start).ok_or(Error::InvalidArgs)? / sec_per_clus as u32;
+    if clusters < FAT32_MIN_CLUSTERS {
+        return Err(Error::InvalidArgs);
+    }
+    let now = clock::now();
+
+    // Whatever the old volume left in the metadata area goes first
+    let root_start = data_start as usize * SECTOR_SIZE;
+    let root_len = sec_per_clus as usize * SECTOR_SIZE;
+    zeroing::fill(0, root_start + root_len, |at, zeros| reader.write_blocks(at / 512, zeros))?;
+
+    let bpb = BiosParameterBlock {
+        jmp_boot: [0xEB, 0x58, 0x90],
+        oem_name: *OEM_NAME,
+        byts_per_sec: SECTOR_SIZE as u16,
+        sec_per_clus,
+        rsvd_sec_cnt: RESERVED_SECTORS,
+        num_fats: NUM_FATS,
+        root_ent_cnt: 0,
+        tot_sec_16: 0,
+        media: MEDIA_FIXED_DISK,
+        fat_sz_16: 0,
+        sec_per_trk: 63,
+        num_heads: 255,
+        hidd_sec: 0,
+        tot_sec_32: total,
+        fat_sz_32: fat_sz,
+        ext_flags: 0,
+        fs_ver: 0,
+        root_clus: ROOT_CLUSTER,
+        fs_info: FS_INFO_SECTOR,
+        bk_boot_sec: BACKUP_BOOT_SECTOR,
+        reserved: [0; 12],
+        drv_num: 0x80,
+        reserved1: 0,
+        boot_sig: 0x29,
+        vol_id: now.map_or(0, |t| t.sec as u32),
+        vol_lab: label.unwrap_or(*NO_LABEL),
+        fil_sys_type: *b"FAT32   ",
+    };
+    let mut boot = [0u8; SECTOR_SIZE];
+    bpb.to_bytes(&mut boot)?;
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    // The root directory's cluster is the only one in use
+    let info = fsinfo::encode(clusters - 1, ROOT_CLUSTER + 1);
+    for first in [0, BACKUP_BOOT_SECTOR as usize] {
+        reader.write_blocks(first, &boot)?;
+        reader.write_blocks(first + FS_INFO_SECTOR as usize, &info)?;
+    }
+
+    // FAT[0] carries the media byte, FAT[1] the end-of-chain mark, and the
+    // root directory is a chain of one cluster
+    let mut fat = [0u8; SECTOR_SIZE];
+    fat[0..4].copy_from_slice(&(0x0FFF_FF00 | MEDIA_FIXED_DISK as u32).to_le_bytes());
+    fat[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+    fat[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+    for copy in 0..NUM_FATS as usize {
+        reader.write_blocks(RESERVED_SECTORS as usize + copy * fat_sz as usize, &fat)?;
+    }
+
+    if let Some(name) = label {
+        let mut entry = DirEntry { name, attr: ATTR_VOLUME_ID, ..Default::default() };
+        if let Some(now) = now {
+            entry.set_modified(now);
+        }
+        let mut root = [0u8; SECTOR_SIZE];
+        entry.to_bytes(&mut root)?;
+        reader.write_blocks(data_start as usize, &root)?;
+    }
+    reader.barrier()
+}
+
+// Sectors per cluster for a volume of `sectors`, from the FAT
+// specification's table: 512-byte clusters up to 260 MiB, 4 KiB up to 8 GiB,
+// then doubling with each doubling of the volume up to 32 KiB
+fn cluster_sectors(sectors: u32) -> u8 {
+    match sectors {
+        ..=532_480 => 1,
+        ..=16_777_216 => 8,
+        ..=33_554_432 => 16,
+        ..=67_108_864 => 32,
+        _ => 64,
+    }
+}
+
+// Sectors in one FAT, by the specification's approximation; it errs on the
+// side of a few sectors too many
+fn fat_sectors(total: u32, sec_per_clus: u8) -> u32 {
+    let spread = total.saturating_sub(RESERVED_SECTORS as u32) as u64;
+    let per_sector = (256 * sec_per_clus as u64 + NUM_FATS as u64) / 2;
+    spread.div_ceil(per_sector) as u32
+}
+
+// The label as an 11-byte, space padded, upper case name; None for none
+fn volume_label(label: &str) -> Result<Option<[u8; 11]>, Error> {
+    if label.is_empty() {
+        return Ok(None);
+    }
+    let valid = |b: &u8| (b.is_ascii_graphic() || *b == b' ') && !LABEL_FORBIDDEN.contains(b);
+    if label.len() > 11 || !label.as_bytes().iter().all(valid) {
+        return Err(Error::InvalidArgs);
+    }
+    let mut name = [b' '; 11];
+    for (slot, b) in name.iter_mut().zip(label.bytes()) {
+        *slot = b.to_ascii_uppercase();
+    }
+    Ok(Some(name))
+}
@@ -1,17 +1,44 @@
-use crate::block::BlockReader;
+use crate::cache::FatSectorCache;
+use crate::dir::{EntrySet, DIR_ENTRY_SIZE};
+use fs_block::BlockReader;
+use fs_common::casefold;
+use fs_common::limits::FAT_MAX_FILE_SIZE;
 use glenda::error::Error;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RootLocation {
     Cluster(u32),
+    // exFAT NoFatChain: first cluster and cluster count, the FAT holds nothing for them
+    Contiguous(u32, u32),
     // sector, size_sectors
     Sector(usize, u32),
 }
 
 pub trait FatOps: Send + Sync {
     fn get_next_cluster(&self, reader: &BlockReader, cluster: u32) -> Result<u32, Error>;
+    // `value` uses the FAT32 conventions (0 free, >= 0x0FFFFFF8 end of chain)
+    fn set_next_cluster(&self, reader: &BlockReader, cluster: u32, value: u32)
+        -> Result<(), Error>;
+    // Number of data clusters; valid cluster numbers are 2..cluster_count + 2
+    fn cluster_count(&self) -> u32;
     fn cluster_to_sector(&self, cluster: u32) -> usize;
     fn get_root_location(&self) -> RootLocation;
     fn bytes_per_sector(&self) -> u32;
     fn sectors_per_cluster(&self) -> u32;
+    fn fat_cache(&self) -> &FatSectorCache;
+    // exFAT directories hold entry sets instead of 8.3 + LFN slots
+    fn is_exfat(&self) -> bool {
+        false
+    }
+    // Decodes an exFAT entry set, File entry first; None when the set is damaged
+    fn decode_entry_set(&self, _set: &[[u8; DIR_ENTRY_SIZE]]) -> Result<Option<EntrySet>, Error> {
+        Err(Error::NotSupported)
+    }
+    fn max_file_size(&self) -> u64 {
+        FAT_MAX_FILE_SIZE
+    }
+    // Whether stored name `stored` is `name`; FAT names ignore case
+    fn names_match(&self, stored: &str, name: &str) -> bool {
+        casefold::eq(stored, name)
+    }
 }
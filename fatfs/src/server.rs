@@ -1,53 +1,170 @@
-use crate::fs::FatFs;
+use crate::dir::DirRecord;
+use crate::extents::AllocPolicy;
+use crate::fs::{FatFs, RmtreeJob};
+use crate::layout::{
+    DYNAMIC_SLOT_LIMIT, JOB_SLOT_BASE, MAP_END, MAP_START, PARKED_SLOT_BASE, RECV_SLOT,
+};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
-use glenda::cap::{CapPtr, Endpoint, Reply};
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CapPtr, CapRights, Endpoint, Frame, Reply, CSPACE_CAP};
 use glenda::client::ResourceClient;
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
 use glenda::error::Error;
-use glenda::interface::fs::FileHandleService;
 use glenda::interface::system::SystemService;
+use glenda::interface::VSpaceService;
 use glenda::ipc::server::handle_call;
-use glenda::ipc::{MsgTag, UTCB};
+use glenda::ipc::{Badge, MsgFlags, MsgTag, UTCB};
+use glenda::mem::Perms;
+use fs_block::DEVICE_RING_SIZE;
+use fs_common::attr::{AttrCache, ATTR_CACHE_SIZE};
+use fs_common::audit::{self, AuditLog};
+use fs_common::badge;
+use fs_common::call;
+use fs_common::casefold;
+use fs_common::clock::{self, TimesRequest};
+use fs_common::deferred::{ParkedCalls, PathWalk, Settled};
+use fs_common::device::DeviceInfo;
+use fs_common::errors::FsError;
+use fs_common::events::{self, EventBus};
+use fs_common::handle::{FsHandle, ReadOnly};
+use fs_common::history;
+use fs_common::jobs::{Job, JobTable};
+use fs_common::locks::{Grant, LockTable};
+use fs_common::mount::{MountOptions, MountPoint, MNT_CASEFOLD, MNT_RDONLY};
+use fs_common::partition::PartitionSelect;
+use fs_common::path;
+use fs_common::policy::{ExportPolicy, OP_READ, OP_WRITE};
+use fs_common::probe::{self, FsType};
+use fs_common::proto::{self, CURRENT_OFFSET};
+use fs_common::ring::{self, RingAccess, RingSignal, SharedRing};
+use fs_common::slots::SlotAllocator;
+use fs_common::snapshots::{self, NoSnapshots, SnapshotSource};
+use fs_common::tune::WritebackTimer;
+use fs_common::vaddr::VaddrAllocator;
+use fs_common::version::{self, Versions};
+use fs_common::wire::WireGuard;
 use glenda::protocol;
 use glenda::protocol::fs::OpenFlags;
 use glenda::protocol::{FS_PROTO, PROCESS_PROTO};
 
+struct OpenHandle {
+    handle: Box<dyn FsHandle>,
+    path: String,
+    is_dir: bool,
+    refs: usize,
+    // Set up by SETUP_IOURING
+    ring: Option<SharedRing>,
+    // Our copy of the endpoint minted for the handle on OPEN, for clients
+    // that negotiated FEAT_HANDLE_ENDPOINTS
+    endpoint: Option<CapPtr>,
+}
+
 pub struct FatFsService<'a> {
     fs: Option<FatFs>,
-    handles: BTreeMap<usize, Box<dyn FileHandleService + Send>>,
+    // Keyed by handle badge, so lookups only find the caller's own handles
+    handles: BTreeMap<usize, OpenHandle>,
+    attrs: AttrCache,
+    read_only: bool,
+    // Between FREEZE and THAW: nothing may reach the device
+    frozen: bool,
+    writeback: WritebackTimer,
+    options: MountOptions,
+    mount_point: MountPoint,
+    // Behind the synthetic .snapshots directory
+    snapshots: Box<dyn SnapshotSource>,
+    device: DeviceInfo,
+    // What the volume held when init_fs declined it
+    declined: Option<FsType>,
+    wire: WireGuard,
+    policy: ExportPolicy,
+    versions: Versions,
+    audit: AuditLog,
+    events: EventBus,
+    // Advisory locks by path, owned by handle id
+    locks: LockTable<String>,
+    jobs: JobTable<FatFs>,
+    opens: ParkedCalls<OpenWalk>,
+    // Set by a handler that parked its call; serve then leaves the reply for later
+    parked: bool,
     next_handle_id: usize,
+    // Ids of closed handles, handed out again before new ones
+    free_ids: Vec<usize>,
     endpoint: Endpoint,
     reply: Reply,
     recv: CapPtr,
     running: bool,
-    ring_vaddr: usize,
     ring_size: usize,
+    // Addresses for the block device ring and client rings
+    maps: VaddrAllocator,
 
-    pub cspace: &'a mut CSpaceManager,
+    pub res_client: &'a mut ResourceClient,
+    pub slots: SlotAllocator<'a>,
     pub vspace: &'a mut VSpaceManager,
 }
 
-const RECV_SLOT: CapPtr = CapPtr::from(0x100);
+// A parked OPEN_ASYNC
+struct OpenWalk {
+    walk: PathWalk<DirRecord>,
+    flags: OpenFlags,
+}
+
+const FEATURES: usize = version::FEAT_HANDLE_ENDPOINTS
+    | version::FEAT_RING_NOTIFY
+    | version::FEAT_IOVEC
+    | version::FEAT_LOCKS
+    | version::FEAT_CLONE
+    | version::FEAT_JOBS
+    | version::FEAT_WIRE
+    | version::FEAT_OPEN_ASYNC
+    | version::FEAT_HISTORY
+    | version::FEAT_EVENTS
+    | version::FEAT_SET_TIMES
+    | version::FEAT_STATFS
+    | version::FEAT_NEXT
+    | version::FEAT_STREAM
+    | version::FEAT_ERROR_DETAIL;
 
 impl<'a> FatFsService<'a> {
     pub fn new(
-        ring_vaddr: usize,
         ring_size: usize,
+        res_client: &'a mut ResourceClient,
         cspace: &'a mut CSpaceManager,
         vspace: &'a mut VSpaceManager,
     ) -> Self {
         Self {
             fs: None,
             handles: BTreeMap::new(),
+            attrs: AttrCache::new(ATTR_CACHE_SIZE).with_casefold(true),
+            read_only: false,
+            frozen: false,
+            writeback: WritebackTimer::new(),
+            options: MountOptions::default(),
+            mount_point: MountPoint::none(),
+            snapshots: Box::new(NoSnapshots),
+            device: DeviceInfo::unknown(),
+            declined: None,
+            wire: WireGuard::new(),
+            policy: ExportPolicy::new(),
+            versions: Versions::new(FEATURES),
+            audit: AuditLog::new(cfg!(feature = "audit")),
+            events: EventBus::new(),
+            locks: LockTable::new(),
+            jobs: JobTable::new(JOB_SLOT_BASE),
+            opens: ParkedCalls::new(PARKED_SLOT_BASE),
+            parked: false,
             next_handle_id: 1,
+            free_ids: Vec::new(),
             endpoint: Endpoint::from(CapPtr::null()),
             reply: Reply::from(CapPtr::null()),
             recv: CapPtr::null(),
             running: false,
-            ring_vaddr,
             ring_size,
-            cspace,
+            maps: VaddrAllocator::new(MAP_START, MAP_END),
+            res_client,
+            slots: SlotAllocator::new(cspace, DYNAMIC_SLOT_LIMIT),
             vspace,
         }
     }
@@ -55,19 +172,387 @@ impl<'a> FatFsService<'a> {
     pub fn init_fs(
         &mut self,
         block_device: Endpoint,
-        res_client: &mut ResourceClient,
+        options: MountOptions,
+        partition: PartitionSelect,
     ) -> Result<(), Error> {
+        let utcb = unsafe { UTCB::new() };
+        self.device = DeviceInfo::query(block_device, utcb);
+        // Left reserved if the mount fails, as the ring may be mapped already
+        let ring_vaddr = self.maps.reserve(self.ring_size, PGSIZE)?;
         // Initialize FatFs with the block device
-        self.fs = Some(FatFs::new(
+        let mut reader = FatFs::open_reader(
             block_device,
-            self.ring_vaddr,
+            ring_vaddr,
             self.ring_size,
-            res_client,
+            self.res_client,
             self.vspace,
-            self.cspace,
-        )?);
+            self.slots.cspace(),
+            partition,
+        )?;
+        let found =
+            probe::probe(|offset, buf| reader.read_offset(offset as usize, buf).map(|_| ()))?;
+        if !matches!(found, FsType::Fat | FsType::ExFat) {
+            glenda::log!("FatFS: declining volume, it holds {:?}", found);
+            self.declined = Some(found);
+            return Err(Error::NotSupported);
+        }
+        let batch_vaddr = self.maps.reserve(DEVICE_RING_SIZE, PGSIZE)?;
+        let cspace = self.slots.cspace();
+        if let Err(e) = reader.setup_ring(batch_vaddr, self.res_client, self.vspace, cspace) {
+            glenda::log!("FatFS: no device ring, batched reads go out one run at a time: {:?}", e);
+            let _ = self.maps.release(batch_vaddr);
+        }
+        let mut fs = FatFs::mount(reader, ring_vaddr, self.ring_size)?;
+        fs.set_io_tuning(self.device.tuning());
+        fs.set_mount_options(options)?;
+        if !options.read_only {
+            // Not fatal: the backup keeps serving as the boot sector
+            match fs.repair_boot_sector() {
+                Ok(true) => glenda::log!("FatFS: boot sector restored from the backup"),
+                Ok(false) => {}
+                Err(e) => glenda::log!("FatFS: cannot restore the boot sector: {:?}", e),
+            }
+        }
+        match fs.verify_fat_copies() {
+            Ok(mismatches) => {
+                for (copy, sector) in &mismatches {
+                    glenda::log!(
+                        "FatFS: FAT copy {} differs from FAT 0 at sector {}",
+                        copy,
+                        sector
+                    );
+                }
+                if !mismatches.is_empty() {
+                    self.events.publish(events::EV_CORRUPTION, mismatches.len());
+                }
+            }
+            Err(e) => glenda::log!("FatFS: cannot compare the FAT copies: {:?}", e),
+        }
+        self.options = options;
+        glenda::log!(
+            "FatFS: device '{}' serial '{}', rotational: {}, discard: {}, cache: {:?}",
+            self.device.model,
+            self.device.serial,
+            self.device.rotational,
+            self.device.discard,
+            self.device.cache
+        );
+        self.fs = Some(fs);
+        Ok(())
+    }
+
+    /// Where the service registers in the VFS namespace once it runs.
+    pub fn set_mount_point(&mut self, mount_point: MountPoint) {
+        self.mount_point = mount_point;
+    }
+
+    /// Set when init_fs failed because the volume is not this service's
+    /// format, so the caller can hand it to another service.
+    pub fn declined(&self) -> Option<FsType> {
+        self.declined
+    }
+
+    /// Runs the quick metadata scrub and reports the result, in the log and
+    /// as events observers read once they subscribe, before the service
+    /// starts answering requests. Findings are reported, not fatal.
+    pub fn scrub(&mut self) -> Result<(), Error> {
+        let fs = self.fs.as_ref().ok_or(Error::NotInitialized)?;
+        let report = fs.scrub()?;
+        glenda::log!("FatFS: mount scrub: {}", report);
+        for issue in &report.issues {
+            glenda::log!("FatFS: scrub: {}", issue);
+        }
+        self.events.publish(events::EV_SCRUB, report.checked);
+        if !report.is_clean() {
+            self.events.publish(events::EV_CORRUPTION, report.issues.len());
+        }
         Ok(())
     }
+
+    // Path an audited request is about, taken before dispatch overwrites the buffer
+    fn audit_path(&self, utcb: &UTCB) -> String {
+        let badge = utcb.get_badge().bits();
+        audit::call_path(utcb, |id| {
+            let key = badge::handle_key(badge, id).ok()?;
+            self.handles.get(&key).map(|h| h.path.as_str())
+        })
+    }
+
+    // Mount options in effect as MNT_* bits, with MNT_RDONLY when the service forced it.
+    // FAT names never depend on case.
+    fn mounted_options(&self) -> usize {
+        let forced = if self.read_only { MNT_RDONLY } else { 0 };
+        self.options.bits() | forced | MNT_CASEFOLD
+    }
+
+    // Gate for the calls that manage the volume rather than its files
+    fn check_admin(&self, badge: Badge) -> Result<(), Error> {
+        if !badge::is_admin(badge.bits()) {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Gate for every call that would modify the volume
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only || self.options.read_only {
+            return Err(FsError::ReadOnly.into());
+        }
+        if self.frozen {
+            return Err(Error::WouldBlock);
+        }
+        Ok(())
+    }
+
+    // Registers a handle for the caller. Clients that negotiated it also get
+    // an endpoint minted for the handle, handed over with the id.
+    fn insert_handle(
+        &mut self,
+        handle: Box<dyn FsHandle>,
+        path: String,
+        badge: glenda::ipc::Badge,
+        utcb: &mut UTCB,
+    ) -> Result<(), Error> {
+        let is_dir = (handle.stat(badge)?.mode & 0o170000) == 0o040000;
+        let id = self.free_ids.last().copied().unwrap_or(self.next_handle_id);
+        let client = badge::client(badge.bits());
+        let key = badge::handle_badge(client, id)?;
+        let endpoint = if self.versions.has(client, version::FEAT_HANDLE_ENDPOINTS) {
+            let slot = self.slots.alloc(self.res_client)?;
+            CSPACE_CAP.mint(self.endpoint.cap(), slot, Badge::new(key), CapRights::ALL)?;
+            utcb.set_cap_transfer(slot);
+            Some(slot)
+        } else {
+            None
+        };
+        if self.free_ids.pop().is_none() {
+            self.next_handle_id += 1;
+        }
+        let entry = OpenHandle { handle, path, is_dir, refs: 1, ring: None, endpoint };
+        self.handles.insert(key, entry);
+        utcb.set_mr(0, id);
+        Ok(())
+    }
+
+    fn unmap_ring(&mut self, ring: &SharedRing) {
+        if let Err(e) = self.maps.unmap(self.vspace, ring.window().server_base) {
+            glenda::log!("FatFS: cannot unmap a client ring: {:?}", e);
+        }
+    }
+
+    // Completes ring lock submissions that were waiting on a released lock
+    fn complete_grants(&self, grants: Vec<Grant>) {
+        ring::complete_grants(grants, |id| self.handles.get(&id).and_then(|h| h.ring.as_ref()));
+    }
+
+    // Serves the submissions on the ring of the handle with key `id`, for
+    // PROCESS_IOURING or a doorbell
+    fn process_ring(&mut self, id: usize, badge: Badge) -> Result<(), Error> {
+        // Serving the ring only needs OP_READ; writes on it need OP_WRITE
+        let client = badge::client(badge.bits());
+        let writable = self.check_writable().and_then(|_| self.policy.permit(client, OP_WRITE));
+        let entry = self.handles.get_mut(&id).ok_or(Error::NotFound)?;
+        let ring = entry.ring.as_mut().ok_or(Error::InvalidArgs)?;
+        let access = RingAccess {
+            writable,
+            frozen: self.frozen,
+            locks: &mut self.locks,
+            key: &entry.path,
+            owner: id,
+            audit: Some((&mut self.audit, &entry.path)),
+        };
+        let grants = ring.process(entry.handle.as_mut(), badge, access);
+        ring.announce();
+        self.attrs.invalidate(&entry.path);
+        self.complete_grants(grants);
+        Ok(())
+    }
+
+    // A notification on the service endpoint: serves the rings the notifying
+    // badge registered with RING_NOTIFY
+    fn doorbell(&mut self, badge: usize, bits: usize) {
+        let client = badge::client(badge);
+        if bits & proto::RING_DOORBELL_BITS == 0 || self.policy.permit(client, OP_READ).is_err() {
+            return;
+        }
+        let rings = self.handles.iter().map(|(&id, h)| (id, h.ring.as_ref()));
+        for id in ring::rung(rings, badge) {
+            if let Err(e) = self.process_ring(id, Badge::new(badge)) {
+                glenda::log!("FatFS: ring of handle {} failed: {:?}", id, e);
+            }
+        }
+    }
+
+    // A call: checked, dispatched, audited and answered
+    fn serve(&mut self, badge: usize, utcb: &mut UTCB) {
+        let tag = utcb.get_msg_tag();
+        // Handle endpoints share their client's session, policy and record
+        let client = badge::client(badge);
+        let audit_path = self.audit.wants(utcb).then(|| self.audit_path(utcb));
+        let result = call::admit(&mut self.wire, Some(&self.policy), client, utcb)
+            .and_then(|_| self.dispatch(utcb));
+        if let Some(path) = audit_path {
+            self.audit.record(client, tag.proto(), tag.label(), path, result);
+        }
+        if core::mem::take(&mut self.parked) && result.is_ok() {
+            return;
+        }
+        call::answer(&mut self.wire, &self.versions, client, utcb, result);
+        let _ = self.reply(utcb);
+    }
+
+    // FORMAT: a fresh FAT32 volume in place of the mounted one. Nothing may
+    // still refer to the old one.
+    fn format(&mut self, badge: Badge, utcb: &mut UTCB) -> Result<(), Error> {
+        self.check_admin(badge)?;
+        self.check_writable()?;
+        if !self.handles.is_empty() || self.jobs.busy() || !self.opens.is_empty() {
+            return Err(Error::WouldBlock);
+        }
+        let label = String::from(path::from_buffer(utcb.buffer())?);
+        let fs = self.fs.as_mut().ok_or(Error::NotInitialized)?;
+        fs.format(utcb.get_mr(0), &label)?;
+        self.attrs = AttrCache::new(ATTR_CACHE_SIZE).with_casefold(true);
+        glenda::log!("FatFS: volume formatted as FAT32");
+        Ok(())
+    }
+
+    // UNMOUNT and EXIT: cancels the jobs, closes every handle with its data
+    // written out and flushes the volume. The loop ends after this call's
+    // reply; the parked calls and the VFS entry are dealt with then.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        if self.frozen {
+            return Err(Error::WouldBlock);
+        }
+        self.running = false;
+        self.jobs.cancel_all();
+        self.run_jobs();
+        let mut result = Ok(());
+        for (_, mut entry) in core::mem::take(&mut self.handles) {
+            if let Some(endpoint) = entry.endpoint {
+                let _ = CSPACE_CAP.delete(endpoint);
+                self.slots.free(endpoint);
+            }
+            let synced = entry.handle.sync(Badge::null());
+            result = result.and(synced).and(entry.handle.close(Badge::null()));
+            if let Some(ring) = entry.ring.take() {
+                self.unmap_ring(&ring);
+            }
+        }
+        self.locks = LockTable::new();
+        if let Some(fs) = self.fs.as_ref() {
+            result = result.and(fs.sync_all());
+        }
+        result
+    }
+
+    // OPEN_ASYNC: parks the call with the path still to walk
+    fn park_open(&mut self, badge: Badge, utcb: &mut UTCB) -> Result<(), Error> {
+        let flags = OpenFlags::from_bits_truncate(utcb.get_mr(0));
+        if proto::open_mutates(flags) {
+            self.check_writable()?;
+        }
+        let fs = self.fs.as_ref().ok_or(Error::NotInitialized)?;
+        let path = path::from_buffer(utcb.buffer())?;
+        // Snapshot views are not on the device; answer at once
+        if let Some(opened) = snapshots::open(self.snapshots.as_mut(), path, flags) {
+            return self.insert_handle(opened?, String::from(path), badge, utcb);
+        }
+        // Creating and truncating change the parent directory, which the walk
+        // does not keep; they are done at once like a plain OPEN
+        if flags.intersects(OpenFlags::O_CREAT | OpenFlags::O_TRUNC) {
+            let opened = self.fs.as_mut().ok_or(Error::NotInitialized)?.open_handle(path, flags, 0);
+            return self.insert_handle(opened?, String::from(path), badge, utcb);
+        }
+        let walk = PathWalk::new(path, fs.root_record());
+        self.opens.park(self.reply, badge, OpenWalk { walk, flags })?;
+        self.parked = true;
+        Ok(())
+    }
+
+    // Takes parked opens one lookup further and answers those that are done,
+    // so a deep path on a slow device never holds up other clients for long
+    fn walk_opens(&mut self) {
+        let fs = match self.fs.as_ref() {
+            Some(fs) if !self.opens.is_empty() && !self.frozen => fs,
+            _ => return,
+        };
+        let settled = self.opens.advance(|_, open| {
+            let name = match open.walk.next_component() {
+                Some(name) => name,
+                None => return Some(Ok(())),
+            };
+            match fs.lookup_step(open.walk.node(), name) {
+                Ok(record) => {
+                    open.walk.advance(record);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            }
+        });
+        for done in settled {
+            self.finish_open(done);
+        }
+    }
+
+    fn finish_open(&mut self, done: Settled<OpenWalk, Result<(), Error>>) {
+        let Settled { reply, badge, work, result } = done;
+        let path = String::from(work.walk.path());
+        let record = result.map(|_| work.walk.into_node());
+        let opened = self
+            .fs
+            .as_mut()
+            .ok_or(Error::NotInitialized)
+            .and_then(|fs| fs.open_record(record?, work.flags));
+        let utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        utcb.set_msg_tag(MsgTag::ok());
+        if let Err(e) = opened.and_then(|handle| self.insert_handle(handle, path, badge, utcb)) {
+            utcb.set_msg_tag(MsgTag::err());
+            utcb.set_mr(0, e as usize);
+        }
+        self.wire.seal(badge::client(badge.bits()), utcb);
+        if let Err(e) = reply.reply(utcb) {
+            glenda::log!("FatFS: cannot answer a parked open: {:?}", e);
+        }
+    }
+
+    // Takes over the notification endpoint transferred with the call
+    fn start_job(
+        &mut self,
+        badge: glenda::ipc::Badge,
+        job: Box<dyn Job<FatFs>>,
+    ) -> Result<usize, Error> {
+        let (id, slot) = self.jobs.vacant()?;
+        CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+        self.jobs.start(id, badge, job)?;
+        Ok(id)
+    }
+
+    // Periodic writeback, every flush interval; a zero interval leaves it
+    // to SYNC
+    fn writeback_tick(&mut self) {
+        let fs = match self.fs.as_ref() {
+            Some(fs) if !self.frozen => fs,
+            _ => return,
+        };
+        if !self.writeback.tick(&fs.cache_tunables()) || fs.dirty_blocks() == 0 {
+            return;
+        }
+        if let Err(e) = fs.sync_all() {
+            glenda::log!("FatFS: periodic writeback failed: {:?}", e);
+        }
+    }
+
+    fn run_jobs(&mut self) {
+        if self.frozen {
+            return;
+        }
+        if let Some(fs) = self.fs.as_mut() {
+            let attrs = &mut self.attrs;
+            self.jobs.run(fs, |path| attrs.invalidate(path));
+        }
+    }
 }
 
 impl<'a> SystemService for FatFsService<'a> {
@@ -83,85 +568,611 @@ impl<'a> SystemService for FatFsService<'a> {
     }
 
     fn run(&mut self) -> Result<(), Error> {
+        match self.mount_point.register(self.endpoint) {
+            Ok(()) => self.events.publish(events::EV_MOUNT, self.mounted_options()),
+            Err(e) => glenda::log!("FatFS: cannot mount with the VFS: {:?}", e),
+        }
         self.running = true;
         while self.running {
             let mut utcb = unsafe { UTCB::new() };
             utcb.clear();
             utcb.set_reply_window(self.reply.cap());
             utcb.set_recv_window(RECV_SLOT);
+            // Parked opens only move on a turn of the loop, so it must not wait
+            // in recv for a call that a client alone on the service never makes
+            if !self.opens.is_empty() && !self.frozen && self.fs.is_some() {
+                let _ = self.endpoint.notify(proto::WAKE_BITS);
+            }
 
             if self.endpoint.recv(&mut utcb).is_ok() {
-                if let Err(e) = self.dispatch(&mut utcb) {
-                    utcb.set_msg_tag(MsgTag::err());
-                    utcb.set_mr(0, e as usize);
+                let badge = utcb.get_badge().bits();
+                match ring::notification(utcb) {
+                    Some(bits) => self.doorbell(badge, bits),
+                    None => self.serve(badge, utcb),
                 }
-                let _ = self.reply(&mut utcb);
             }
+            self.walk_opens();
+            self.run_jobs();
+            self.writeback_tick();
+        }
+        let refused = self.opens.advance(|_, _| Some(Err(Error::NotInitialized)));
+        for done in refused {
+            self.finish_open(done);
+        }
+        if let Err(e) = self.mount_point.unregister() {
+            glenda::log!("FatFS: cannot unmount from the VFS: {:?}", e);
         }
+        self.events.publish(events::EV_UNMOUNT, 0);
         Ok(())
     }
 
     fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
         let badge = utcb.get_badge();
+        // Jobs and policy belong to the client, whichever endpoint it called
+        let client = Badge::new(badge::client(badge.bits()));
+        // The handle a call names in MR0, unless it came through a handle endpoint
+        let key = |id: usize| badge::handle_key(badge.bits(), id);
         glenda::ipc_dispatch! {
             self, utcb,
             (FS_PROTO, protocol::fs::OPEN) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
-                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
                     let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(0));
+                    if proto::open_mutates(flags) {
+                        s.check_writable()?;
+                    }
                     let mode = u_inner.get_mr(1) as u32;
-                    let path = "mock_path"; // TODO
+                    let path = String::from(path::from_buffer(u_inner.buffer())?);
 
-                    let handle = fs.open_handle(path, flags, mode)?;
-                    let id = s.next_handle_id;
-                    s.next_handle_id += 1;
-                    s.handles.insert(id, handle);
-                    u_inner.set_mr(0, id);
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let handle = match snapshots::open(s.snapshots.as_mut(), &path, flags) {
+                        Some(opened) => opened?,
+                        None => fs.open_handle(&path, flags, mode)?,
+                    };
+                    s.insert_handle(handle, path, badge, u_inner)
+                })
+            },
+            (FS_PROTO, proto::OPEN_ASYNC) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.park_open(badge, u_inner))
+            },
+            (FS_PROTO, proto::OPENAT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let base = s.handles.get(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    if !base.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(1));
+                    if proto::open_mutates(flags) {
+                        s.check_writable()?;
+                    }
+                    let mode = u_inner.get_mr(2) as u32;
+                    let path = path::join(&base.path, path::from_buffer(u_inner.buffer())?);
+
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let handle = match snapshots::open(s.snapshots.as_mut(), &path, flags) {
+                        Some(opened) => opened?,
+                        None => fs.open_handle(&path, flags, mode)?,
+                    };
+                    s.insert_handle(handle, path, badge, u_inner)
+                })
+            },
+            (FS_PROTO, proto::DUP) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    entry.refs += 1;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::CLONE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    if entry.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let handle = Box::new(ReadOnly::new(entry.handle.duplicate()?));
+                    let path = entry.path.clone();
+                    s.insert_handle(handle, path, badge, u_inner)
+                })
+            },
+            (FS_PROTO, proto::LOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let entry = s.handles.get(&id).ok_or(Error::NotFound)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    s.locks.lock(&entry.path, id, start, len, u_inner.get_mr(3))
+                })
+            },
+            (FS_PROTO, proto::UNLOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let entry = s.handles.get(&id).ok_or(Error::NotFound)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    let grants = s.locks.unlock(&entry.path, id, start, len)?;
+                    s.complete_grants(grants);
+                    Ok(())
+                })
+            },
+            // MR0: handle, MR1: client address of the shared memory, MR2: its size;
+            // the memory's frame comes with the call
+            (FS_PROTO, protocol::fs::SETUP_IOURING) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let user_vaddr = u_inner.get_mr(1);
+                    let size = u_inner.get_mr(2);
+                    if size == 0 || size % 4096 != 0 {
+                        return Err(Error::InvalidArgs);
+                    }
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let entry = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                    let server_vaddr = s.maps.reserve(size, PGSIZE)?;
+                    let mapped = s.vspace.map_frame(
+                        Frame::from(slot),
+                        server_vaddr,
+                        Perms::READ | Perms::WRITE,
+                        size / 4096,
+                        s.res_client,
+                        s.slots.cspace(),
+                    );
+                    if let Err(e) = mapped {
+                        let _ = s.maps.release(server_vaddr);
+                        return Err(e);
+                    }
+                    let ring = SharedRing::attach(server_vaddr, user_vaddr, size);
+                    if let Some(old) = entry.ring.replace(ring) {
+                        s.unmap_ring(&old);
+                    }
+                    Ok(())
+                })
+            },
+            // MR0: handle, MR1: bits; the endpoint to notify comes with the call
+            (FS_PROTO, proto::RING_NOTIFY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let ring = entry.ring.as_mut().ok_or(Error::InvalidArgs)?;
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                    let signal = RingSignal {
+                        notify: Endpoint::from(slot),
+                        bits: u_inner.get_mr(1),
+                        badge: badge.bits(),
+                    };
+                    if let Some(old) = ring.set_signal(signal) {
+                        CSPACE_CAP.delete(old.notify.cap())?;
+                        s.slots.free(old.notify.cap());
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::PROCESS_IOURING) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.process_ring(key(u_inner.get_mr(0))?, badge))
+            },
+            (FS_PROTO, protocol::fs::CLOSE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let entry = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
+                    entry.refs -= 1;
+                    if entry.refs == 0 {
+                        let grants = s.locks.release(id);
+                        s.complete_grants(grants);
+                        if let Some(mut entry) = s.handles.remove(&id) {
+                            s.free_ids.push(id & badge::HANDLE_ID_MASK);
+                            if let Some(endpoint) = entry.endpoint {
+                                CSPACE_CAP.delete(endpoint)?;
+                                s.slots.free(endpoint);
+                            }
+                            if let Some(ring) = entry.ring.take() {
+                                s.unmap_ring(&ring);
+                            }
+                            entry.handle.close(badge)?;
+                        }
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::GETDENTS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let count = core::cmp::min(
+                        u_inner.get_mr(1),
+                        proto::dents_capacity(u_inner.buffer()),
+                    );
+                    let entries = entry.handle.getdents(badge, count)?;
+                    let written = proto::encode_dents(u_inner.buffer_mut(), &entries);
+                    u_inner.set_mr(0, written);
                     Ok(())
                 })
             },
             (FS_PROTO, protocol::fs::MKDIR) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
+                    s.check_writable()?;
                     let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
                     let mode = u_inner.get_mr(0) as u32;
-                    let path = "mock_path";
+                    let path = path::from_buffer(u_inner.buffer())?;
                     fs.mkdir(path, mode)?;
+                    s.attrs.invalidate(path);
                     Ok(())
                 })
             },
             (FS_PROTO, protocol::fs::UNLINK) => |s: &mut Self, u: &mut UTCB| {
-                handle_call(u, |_u_inner| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
                     let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
-                    let path = "mock_path";
+                    let path = path::from_buffer(u_inner.buffer())?;
                     fs.unlink(path)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            // buffer: path, MR0-MR3: access and modification times; see proto::SET_TIMES.
+            (FS_PROTO, proto::SET_TIMES) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let req = TimesRequest::from_utcb(u_inner)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    fs.set_times(path, req)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::RENAME) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let (old_path, new_path) = path::pair_from_buffer(u_inner.buffer())?;
+                    // In a directory that keeps history the replaced file becomes a version;
+                    // renaming an entry onto itself replaces nothing
+                    let same = casefold::eq(&path::normalize(old_path), &path::normalize(new_path));
+                    let kept = if same { None } else { history::displace(fs, new_path)? };
+                    if let Err(e) = fs.rename(old_path, new_path) {
+                        if let Some(kept) = kept {
+                            let _ = fs.rename(&kept, new_path);
+                        }
+                        return Err(e);
+                    }
+                    s.attrs.invalidate(old_path);
+                    s.attrs.invalidate(new_path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::RMTREE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    if u_inner.get_mr(0) & proto::JOB_ASYNC != 0 {
+                        let id = s.start_job(client, Box::new(RmtreeJob::new(path)?))?;
+                        u_inner.set_mr(0, id);
+                        return Ok(());
+                    }
+                    fs.rmtree(path)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::JOB_STATUS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let status = s.jobs.status(client, u_inner.get_mr(0))?;
+                    let (state, error) = status.state.encode();
+                    u_inner.set_mr(0, state);
+                    u_inner.set_mr(1, status.done);
+                    u_inner.set_mr(2, status.total);
+                    u_inner.set_mr(3, error);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::JOB_CANCEL) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.jobs.cancel(client, u_inner.get_mr(0)))
+            },
+            (FS_PROTO, proto::JOB_RELEASE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let slot = s.jobs.release(client, u_inner.get_mr(0))?;
+                    CSPACE_CAP.delete(slot)
+                })
+            },
+            (FS_PROTO, proto::REMOUNT_RO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.read_only = true;
+                    s.events.publish(events::EV_REMOUNT_RO, 0);
+                    s.jobs.cancel_all();
+                    // Best effort: the device may already be failing
+                    for entry in s.handles.values_mut() {
+                        let _ = entry.handle.sync(badge);
+                    }
+                    if let Some(fs) = s.fs.as_ref() {
+                        let _ = fs.sync_all();
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::REMOUNT_RW) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    // Only a new MOUNT_OPTIONS lifts a read-only mount
+                    if s.options.read_only {
+                        return Err(Error::PermissionDenied);
+                    }
+                    s.read_only = false;
+                    s.events.publish(events::EV_REMOUNT_RW, 0);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::FORMAT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.format(badge, u_inner))
+            },
+            (FS_PROTO, proto::TRIM) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    s.check_writable()?;
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let trimmed = fs.trim(u_inner.get_mr(0))?;
+                    glenda::log!("FatFS: discarded {} free bytes", trimmed);
+                    u_inner.set_mr(0, trimmed);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::FREEZE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    for entry in s.handles.values_mut() {
+                        entry.handle.sync(badge)?;
+                    }
+                    fs.sync_all()?;
+                    s.frozen = true;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::THAW) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    if !s.frozen {
+                        return Err(Error::InvalidArgs);
+                    }
+                    s.frozen = false;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::MOUNT_OPTIONS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let options = MountOptions::from_bits(u_inner.get_mr(0))?;
+                    if !s.handles.is_empty() {
+                        return Err(Error::WouldBlock);
+                    }
+                    s.fs.as_mut().ok_or(Error::NotInitialized)?.set_mount_options(options)?;
+                    s.options = options;
+                    u_inner.set_mr(0, s.mounted_options());
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::HISTORY_LIST) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    history::list(fs, u_inner)
+                })
+            },
+            (FS_PROTO, proto::HISTORY_RESTORE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    history::restore(fs, path, u_inner.get_mr(0) as u64)?;
+                    s.attrs.invalidate(path);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::UNMOUNT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.shutdown()
+                })
+            },
+            (FS_PROTO, proto::MOUNT_AT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    s.mount_point.move_to(path, s.endpoint)?;
+                    if !path.is_empty() {
+                        s.events.publish(events::EV_MOUNT, s.mounted_options());
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::VOLUME_INFO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    s.device.encode(u_inner, fs.io_tuning())
+                })
+            },
+            (FS_PROTO, proto::VOLUME_STATS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.io_error_counts().encode(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::STATFS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.stats()?.encode(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::HEAT_EXPORT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let flags = u_inner.get_mr(0);
+                    fs.heat().encode(u_inner, flags)
+                })
+            },
+            (FS_PROTO, proto::CBT_EPOCH) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.changes().next_epoch(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::CBT_QUERY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.changes().query(u_inner)
+                })
+            },
+            (FS_PROTO, proto::VERSION) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.versions.negotiate(client.bits(), u_inner))
+            },
+            (FS_PROTO, proto::WIRE_PROTECT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.wire.configure(client.bits(), u_inner))
+            },
+            (FS_PROTO, proto::SET_OP_MASK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.policy.configure(client.bits(), u_inner))
+            },
+            (FS_PROTO, proto::SET_CLOCK) => |_s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| clock::configure(client.bits(), u_inner))
+            },
+            // MR0: events::EV_* bits; the endpoint to notify comes with the call.
+            // Without an endpoint, ends the subscription.
+            (FS_PROTO, proto::EVENT_SUBSCRIBE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let old = if u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        let slot = s.slots.alloc(s.res_client)?;
+                        CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                        let mask = u_inner.get_mr(0);
+                        match s.events.subscribe(client.bits(), mask, Endpoint::from(slot)) {
+                            Ok(old) => old,
+                            Err(e) => {
+                                CSPACE_CAP.delete(slot)?;
+                                s.slots.free(slot);
+                                return Err(e);
+                            }
+                        }
+                    } else {
+                        s.events.unsubscribe(client.bits())
+                    };
+                    if let Some(old) = old {
+                        CSPACE_CAP.delete(old.cap())?;
+                        s.slots.free(old.cap());
+                    }
+                    u_inner.set_mr(0, s.events.next_seq() as usize);
                     Ok(())
                 })
             },
+            (FS_PROTO, proto::EVENT_READ) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.events.read(u_inner))
+            },
+            (FS_PROTO, proto::AUDIT_READ) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.audit.read(u_inner))
+            },
+            (FS_PROTO, proto::CACHE_TUNE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let tunables = fs.cache_tunables().update(u_inner)?;
+                    fs.set_cache_tunables(tunables);
+                    tunables.encode(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::ALLOC_POLICY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let policy = AllocPolicy::from_bits(u_inner.get_mr(0))?;
+                    let old = fs.alloc_policy();
+                    fs.set_alloc_policy(policy)?;
+                    u_inner.set_mr(0, old.bits());
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::FRAG_STATS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.frag_stats(u_inner)
+                })
+            },
             (FS_PROTO, protocol::fs::STAT_PATH) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
                     let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
-                    let path = "mock_path";
-                    let stat = fs.stat_path(path)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    let cached = s.attrs.get(path);
+                    let stat = match (cached, snapshots::stat(s.snapshots.as_mut(), path)) {
+                        (_, Some(snap)) => snap?,
+                        (Some(stat), None) => stat,
+                        (None, None) => {
+                            let stat = fs.stat_path(path)?;
+                            s.attrs.insert(path, stat);
+                            stat
+                        }
+                    };
                     u_inner.set_mr(0, stat.size as usize);
                     u_inner.set_mr(1, stat.mode as usize);
+                    u_inner.set_mr(2, proto::ATTR_TIMEOUT_MS);
+                    u_inner.set_mr(3, stat.atime);
+                    u_inner.set_mr(4, stat.mtime);
+                    u_inner.set_mr(5, stat.ctime);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::SEEK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let offset = u_inner.get_mr(1) as i64;
+                    let pos = entry.handle.seek(badge, offset, u_inner.get_mr(2))?;
+                    u_inner.set_mr(0, pos);
                     Ok(())
                 })
             },
             (FS_PROTO, protocol::fs::READ_SYNC) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
-                    let id = u_inner.get_mr(0);
+                    let id = key(u_inner.get_mr(0))?;
                     let offset = u_inner.get_mr(1) as usize;
                     let len = u_inner.get_mr(2);
-                    let handle = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
+                    let entry = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
 
                     let mut buf = alloc::vec![0u8; len];
-                    let read_len = handle.read(badge, offset, &mut buf)?;
+                    let read_len = entry.handle.read(badge, offset, &mut buf)?;
                     u_inner.set_mr(0, read_len);
                     // TODO: copy buffer to UTCB or shared memory
                     Ok(())
                 })
             },
+            (FS_PROTO, proto::READ_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let len = u_inner.get_mr(1);
+                    let buf = u_inner.buffer_mut();
+                    if len > buf.len() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let read_len = entry.handle.read(badge, CURRENT_OFFSET, &mut buf[..len])?;
+                    u_inner.set_buffer_len(read_len);
+                    u_inner.set_mr(0, read_len);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::WRITE_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let written = entry.handle.write(badge, CURRENT_OFFSET, u_inner.buffer())?;
+                    s.attrs.invalidate(&entry.path);
+                    u_inner.set_buffer_len(0);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
             (PROCESS_PROTO, protocol::process::EXIT) => |s: &mut Self, _u: &mut UTCB| {
+                // Best effort: the process goes away either way
+                if let Err(e) = s.shutdown() {
+                    glenda::log!("FatFS: writeback at exit failed: {:?}", e);
+                }
                 s.running = false;
                 Ok(())
             }
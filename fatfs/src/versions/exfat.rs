@@ -1,5 +1,13 @@
-use crate::block::BlockReader;
+use crate::cache::FatSectorCache;
+use crate::dir::{DirStream, EntrySet, DIR_ENTRY_SIZE};
 use crate::ops::{FatOps, RootLocation};
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u16, le_u32, le_u64, put_le_u32};
+use fs_common::casefold;
+use fs_common::limits::EXFAT_MAX_FILE_SIZE;
+use fs_common::utf16::{self, UpcaseTable};
 use glenda::error::Error;
 
 #[repr(C, packed)]
@@ -7,8 +15,8 @@ pub struct ExFatBpb {
     pub jmp_boot: [u8; 3],
     pub oem_name: [u8; 8],
     pub padding: [u8; 53],
-    pub partition_offset: usize,
-    pub vol_length: usize,
+    pub partition_offset: u64,
+    pub vol_length: u64,
     pub fat_offset: u32,
     pub fat_length: u32,
     pub cluster_heap_offset: u32,
@@ -25,12 +33,107 @@ pub struct ExFatBpb {
     // ...
 }
 
+fs_common::impl_le_codec!(ExFatBpb {
+    jmp_boot,
+    oem_name,
+    padding,
+    partition_offset,
+    vol_length,
+    fat_offset,
+    fat_length,
+    cluster_heap_offset,
+    cluster_count,
+    root_dir_cluster,
+    vol_serial,
+    fs_revision,
+    vol_flags,
+    bytes_per_sector_shift,
+    sectors_per_cluster_shift,
+    num_fats,
+    drive_select,
+    percent_in_use,
+});
+
+// Directory entry types with the in-use bit set; cleared, they mark deleted entries
+pub const EXFAT_ENTRY_FILE: u8 = 0x85;
+pub const EXFAT_ENTRY_STREAM: u8 = 0xC0;
+pub const EXFAT_ENTRY_NAME: u8 = 0xC1;
+pub const EXFAT_ENTRY_UPCASE: u8 = 0x82;
+
+// GeneralSecondaryFlags of the Stream Extension
+pub const EXFAT_NO_FAT_CHAIN: u8 = 0x02;
+
+// UTF-16 units per File Name entry; a 255 unit name takes 17 entries
+const EXFAT_NAME_UNITS: usize = 15;
+const EXFAT_MAX_NAME_ENTRIES: usize = 17;
+
+// Offsets inside the File and Stream Extension entries
+const FILE_SET_CHECKSUM: usize = 2;
+const FILE_ATTRIBUTES: usize = 4;
+const FILE_CREATE_TIMESTAMP: usize = 8;
+const FILE_MODIFIED_TIMESTAMP: usize = 12;
+const FILE_ACCESSED_TIMESTAMP: usize = 16;
+const FILE_CREATE_10MS: usize = 20;
+const STREAM_FLAGS: usize = 1;
+const STREAM_NAME_LENGTH: usize = 3;
+const STREAM_FIRST_CLUSTER: usize = 20;
+const STREAM_DATA_LENGTH: usize = 24;
+const UPCASE_CHECKSUM: usize = 4;
+const UPCASE_FIRST_CLUSTER: usize = 20;
+const UPCASE_DATA_LENGTH: usize = 24;
+// A full table maps each of the 65536 units
+const UPCASE_MAX_BYTES: u64 = 0x20000;
+
+/// SetChecksum over every byte of the set except the checksum field itself.
+pub fn entry_set_checksum(set: &[[u8; DIR_ENTRY_SIZE]]) -> u16 {
+    let mut sum = 0u16;
+    for (i, entry) in set.iter().enumerate() {
+        for (j, &b) in entry.iter().enumerate() {
+            if i == 0 && (j == FILE_SET_CHECKSUM || j == FILE_SET_CHECKSUM + 1) {
+                continue;
+            }
+            sum = sum.rotate_right(1).wrapping_add(b as u16);
+        }
+    }
+    sum
+}
+
 pub struct ExFatOps {
     pub bytes_per_sector: u32,
     pub sectors_per_cluster: u32,
     pub fat_start_sector: usize,
     pub data_start_sector: usize,
     pub root_cluster: u32,
+    pub cluster_count: u32,
+    pub fat_cache: FatSectorCache,
+    // Name matching falls back to simple case folding without it
+    pub upcase: Option<UpcaseTable>,
+}
+
+impl ExFatOps {
+    /// Reads the up-case table the root directory points at.
+    pub fn load_upcase(&mut self, reader: &BlockReader) -> Result<(), Error> {
+        let mut root = DirStream::new(self.get_root_location());
+        let entry = root.find_raw(reader, self, EXFAT_ENTRY_UPCASE)?.ok_or(Error::NotFound)?;
+        let len = le_u64(&entry, UPCASE_DATA_LENGTH)?;
+        if len == 0 || len > UPCASE_MAX_BYTES {
+            return Err(Error::DeviceError);
+        }
+        let cluster_bytes = (self.bytes_per_sector * self.sectors_per_cluster) as usize;
+        let mut raw = alloc::vec![0u8; (len as usize).next_multiple_of(cluster_bytes)];
+        let mut cluster = le_u32(&entry, UPCASE_FIRST_CLUSTER)?;
+        for chunk in raw.chunks_mut(cluster_bytes) {
+            if cluster < 2 || cluster >= self.cluster_count + 2 {
+                return Err(Error::DeviceError);
+            }
+            let offset = self.cluster_to_sector(cluster) * self.bytes_per_sector as usize;
+            reader.read_offset(offset, chunk)?;
+            cluster = self.get_next_cluster(reader, cluster)?;
+        }
+        raw.truncate(len as usize);
+        self.upcase = Some(UpcaseTable::parse(&raw, le_u32(&entry, UPCASE_CHECKSUM)?)?);
+        Ok(())
+    }
 }
 
 impl FatOps for ExFatOps {
@@ -42,17 +145,36 @@ impl FatOps for ExFatOps {
 
         let sector = self.fat_start_sector + fat_sector_offset;
 
-        // TODO: Handle buffer size dynamically if sector > 512
-        let mut buf = alloc::vec![0u8; self.bytes_per_sector as usize];
-        let read_pos = sector * self.bytes_per_sector as usize;
-        reader.read_offset(read_pos, &mut buf).map_err(|_| Error::IoError)?;
-
-        let ptr = unsafe { buf.as_ptr().add(entry_offset) };
-        let val = unsafe { core::ptr::read_unaligned(ptr as *const u32) };
+        let val = self.fat_cache.with_sector(
+            reader,
+            sector,
+            self.bytes_per_sector as usize,
+            |buf| le_u32(buf, entry_offset),
+        )??;
 
         Ok(val) // All 32 bits are valid
     }
 
+    fn set_next_cluster(
+        &self,
+        reader: &BlockReader,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), Error> {
+        let fat_offset = cluster as usize * 4;
+        let sector = self.fat_start_sector + fat_offset / self.bytes_per_sector as usize;
+        let entry_offset = fat_offset % self.bytes_per_sector as usize;
+        let value = if value >= 0x0FFFFFF8 { 0xFFFFFFFF } else { value };
+
+        self.fat_cache.update(reader, sector, self.bytes_per_sector as usize, |buf| {
+            put_le_u32(buf, entry_offset, value)
+        })?
+    }
+
+    fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
     fn cluster_to_sector(&self, cluster: u32) -> usize {
         // exFAT 1st cluster is cluster 2 usually
         let rel_cluster = if cluster >= 2 { cluster - 2 } else { 0 };
@@ -69,4 +191,63 @@ impl FatOps for ExFatOps {
     fn sectors_per_cluster(&self) -> u32 {
         self.sectors_per_cluster
     }
+    fn fat_cache(&self) -> &FatSectorCache {
+        &self.fat_cache
+    }
+
+    fn is_exfat(&self) -> bool {
+        true
+    }
+
+    fn decode_entry_set(&self, set: &[[u8; DIR_ENTRY_SIZE]]) -> Result<Option<EntrySet>, Error> {
+        // File entry, Stream Extension and at least one File Name entry
+        let (Some(file), Some(stream)) = (set.first(), set.get(1)) else {
+            return Ok(None);
+        };
+        if set.len() < 3
+            || set.len() != file[1] as usize + 1
+            || stream[0] != EXFAT_ENTRY_STREAM
+            || le_u16(file, FILE_SET_CHECKSUM)? != entry_set_checksum(set)
+        {
+            return Ok(None);
+        }
+
+        let name_len = stream[STREAM_NAME_LENGTH] as usize;
+        let mut units = Vec::with_capacity(name_len);
+        for entry in set[2..].iter().take(EXFAT_MAX_NAME_ENTRIES) {
+            if entry[0] != EXFAT_ENTRY_NAME {
+                break;
+            }
+            for i in 0..core::cmp::min(EXFAT_NAME_UNITS, name_len - units.len()) {
+                units.push(le_u16(entry, 2 + i * 2)?);
+            }
+        }
+        if name_len == 0 || units.len() != name_len {
+            return Ok(None);
+        }
+        let name: String = utf16::decode(&units);
+
+        Ok(Some(EntrySet {
+            attributes: le_u16(file, FILE_ATTRIBUTES)?,
+            first_cluster: le_u32(stream, STREAM_FIRST_CLUSTER)?,
+            data_length: le_u64(stream, STREAM_DATA_LENGTH)?,
+            no_fat_chain: (stream[STREAM_FLAGS] & EXFAT_NO_FAT_CHAIN) != 0,
+            name,
+            created: le_u32(file, FILE_CREATE_TIMESTAMP)?,
+            created_10ms: file[FILE_CREATE_10MS],
+            modified: le_u32(file, FILE_MODIFIED_TIMESTAMP)?,
+            accessed: le_u32(file, FILE_ACCESSED_TIMESTAMP)?,
+        }))
+    }
+
+    fn max_file_size(&self) -> u64 {
+        EXFAT_MAX_FILE_SIZE
+    }
+
+    fn names_match(&self, stored: &str, name: &str) -> bool {
+        match &self.upcase {
+            Some(table) => table.eq(stored, name),
+            None => casefold::eq(stored, name),
+        }
+    }
 }
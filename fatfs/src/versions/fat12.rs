@@ -0,0 +1,120 @@
+use crate::cache::FatSectorCache;
+use crate::ops::{FatOps, RootLocation};
+use fs_block::BlockReader;
+use glenda::error::Error;
+
+pub struct Fat12Ops {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub fat_start_sector: usize,
+    pub root_start_sector: usize,
+    pub root_entries: u16,
+    pub data_start_sector: usize,
+    pub cluster_count: u32,
+    pub fat_cache: FatSectorCache,
+}
+
+impl Fat12Ops {
+    // Two entries share three bytes, so entry `cluster` starts at byte
+    // cluster * 1.5 of the FAT
+    fn entry_offset(cluster: u32) -> usize {
+        cluster as usize + cluster as usize / 2
+    }
+
+    // Sector of the first FAT holding byte `offset`, and the byte within it
+    fn locate(&self, offset: usize) -> (usize, usize) {
+        let bps = self.bytes_per_sector as usize;
+        (self.fat_start_sector + offset / bps, offset % bps)
+    }
+
+    fn read_byte(&self, reader: &BlockReader, offset: usize) -> Result<u8, Error> {
+        let (sector, byte) = self.locate(offset);
+        self.fat_cache.with_sector(reader, sector, self.bytes_per_sector as usize, |buf| buf[byte])
+    }
+
+    fn write_byte(&self, reader: &BlockReader, offset: usize, value: u8) -> Result<(), Error> {
+        let (sector, byte) = self.locate(offset);
+        self.fat_cache.update(reader, sector, self.bytes_per_sector as usize, |buf| {
+            buf[byte] = value;
+        })
+    }
+}
+
+// The two bytes holding entry `cluster` with `value` stored in them. An odd
+// entry takes the high nibble of the first byte, an even one the low nibble
+// of the second; the other nibble belongs to the neighbouring entry.
+fn pack(cluster: u32, (lo, hi): (u8, u8), value: u16) -> (u8, u8) {
+    if cluster % 2 == 1 {
+        ((lo & 0x0F) | (value << 4) as u8, (value >> 4) as u8)
+    } else {
+        (value as u8, (hi & 0xF0) | (value >> 8) as u8)
+    }
+}
+
+impl FatOps for Fat12Ops {
+    fn get_next_cluster(&self, reader: &BlockReader, cluster: u32) -> Result<u32, Error> {
+        let offset = Self::entry_offset(cluster);
+        // The entry may straddle two sectors, so its bytes are read one by one
+        let raw = self.read_byte(reader, offset)? as u16
+            | (self.read_byte(reader, offset + 1)? as u16) << 8;
+        let val = if cluster % 2 == 1 { raw >> 4 } else { raw & 0x0FFF };
+
+        // FAT12 end of chain is >= 0xFF8, a bad cluster 0xFF7
+        match val {
+            0x0FF8.. => Ok(0x0FFFFFFF), // Normalize to FAT32 EOF convention for internal logic
+            0x0FF7 => Ok(0x0FFFFFF7),
+            _ => Ok(val as u32),
+        }
+    }
+
+    fn set_next_cluster(
+        &self,
+        reader: &BlockReader,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), Error> {
+        let offset = Self::entry_offset(cluster);
+        let value = match value {
+            0x0FFFFFF8.. => 0x0FFF,
+            0x0FFFFFF7 => 0x0FF7,
+            _ => value as u16 & 0x0FFF,
+        };
+        let (sector, byte) = self.locate(offset);
+        if byte + 1 < self.bytes_per_sector as usize {
+            return self.fat_cache.update(reader, sector, self.bytes_per_sector as usize, |buf| {
+                (buf[byte], buf[byte + 1]) = pack(cluster, (buf[byte], buf[byte + 1]), value);
+            });
+        }
+        // Straddles two sectors: each is written through on its own
+        let old = (self.read_byte(reader, offset)?, self.read_byte(reader, offset + 1)?);
+        let (lo, hi) = pack(cluster, old, value);
+        self.write_byte(reader, offset, lo)?;
+        self.write_byte(reader, offset + 1, hi)
+    }
+
+    fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    fn cluster_to_sector(&self, cluster: u32) -> usize {
+        let rel_cluster = if cluster >= 2 { cluster - 2 } else { 0 };
+        self.data_start_sector + (rel_cluster as usize * self.sectors_per_cluster as usize)
+    }
+
+    fn get_root_location(&self) -> RootLocation {
+        // Fixed root directory, as on FAT16
+        let root_dir_size = (self.root_entries as usize * 32 + self.bytes_per_sector as usize - 1)
+            / self.bytes_per_sector as usize;
+        RootLocation::Sector(self.root_start_sector, root_dir_size as u32)
+    }
+
+    fn bytes_per_sector(&self) -> u32 {
+        self.bytes_per_sector as u32
+    }
+    fn sectors_per_cluster(&self) -> u32 {
+        self.sectors_per_cluster as u32
+    }
+    fn fat_cache(&self) -> &FatSectorCache {
+        &self.fat_cache
+    }
+}
@@ -1,5 +1,7 @@
-use crate::block::BlockReader;
+use crate::cache::FatSectorCache;
 use crate::ops::{FatOps, RootLocation};
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u16, put_le_u16};
 use glenda::error::Error;
 
 pub struct Fat16Ops {
@@ -9,6 +11,8 @@ pub struct Fat16Ops {
     pub root_start_sector: usize,
     pub root_entries: u16,
     pub data_start_sector: usize,
+    pub cluster_count: u32,
+    pub fat_cache: FatSectorCache,
 }
 
 impl FatOps for Fat16Ops {
@@ -19,15 +23,13 @@ impl FatOps for Fat16Ops {
 
         let sector = self.fat_start_sector + fat_sector_offset;
 
-        let mut buf = alloc::vec![0u8; self.bytes_per_sector as usize];
-        let read_pos = sector * self.bytes_per_sector as usize;
-        reader.read_offset(read_pos, &mut buf).map_err(|_| Error::IoError)?;
-
         // Read u16
-        let val = unsafe {
-            let ptr = buf.as_ptr().add(entry_offset);
-            core::ptr::read_unaligned(ptr as *const u16)
-        };
+        let val = self.fat_cache.with_sector(
+            reader,
+            sector,
+            self.bytes_per_sector as usize,
+            |buf| le_u16(buf, entry_offset),
+        )??;
 
         // FAT16 end of chain is >= 0xFFF8
         if val >= 0xFFF8 {
@@ -37,6 +39,26 @@ impl FatOps for Fat16Ops {
         }
     }
 
+    fn set_next_cluster(
+        &self,
+        reader: &BlockReader,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), Error> {
+        let fat_offset = cluster as usize * 2;
+        let sector = self.fat_start_sector + fat_offset / self.bytes_per_sector as usize;
+        let entry_offset = fat_offset % self.bytes_per_sector as usize;
+        let value = if value >= 0x0FFFFFF8 { 0xFFFF } else { value as u16 };
+
+        self.fat_cache.update(reader, sector, self.bytes_per_sector as usize, |buf| {
+            put_le_u16(buf, entry_offset, value)
+        })?
+    }
+
+    fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
     fn cluster_to_sector(&self, cluster: u32) -> usize {
         let rel_cluster = if cluster >= 2 { cluster - 2 } else { 0 };
         self.data_start_sector + (rel_cluster as usize * self.sectors_per_cluster as usize)
@@ -55,4 +77,7 @@ impl FatOps for Fat16Ops {
     fn sectors_per_cluster(&self) -> u32 {
         self.sectors_per_cluster as u32
     }
+    fn fat_cache(&self) -> &FatSectorCache {
+        &self.fat_cache
+    }
 }
@@ -1,5 +1,7 @@
-use crate::block::BlockReader;
+use crate::cache::FatSectorCache;
 use crate::ops::{FatOps, RootLocation};
+use fs_block::BlockReader;
+use fs_common::bytes::{le_u32, put_le_u32};
 use glenda::error::Error;
 
 pub struct Fat32Ops {
@@ -8,6 +10,8 @@ pub struct Fat32Ops {
     pub fat_start_sector: usize,
     pub data_start_sector: usize,
     pub root_cluster: u32,
+    pub cluster_count: u32,
+    pub fat_cache: FatSectorCache,
 }
 
 impl FatOps for Fat32Ops {
@@ -18,16 +22,37 @@ impl FatOps for Fat32Ops {
 
         let sector = self.fat_start_sector + fat_sector_offset;
 
-        let mut buf = alloc::vec![0u8; self.bytes_per_sector as usize];
-        let read_pos = sector * self.bytes_per_sector as usize;
-        reader.read_offset(read_pos, &mut buf).map_err(|_| Error::IoError)?;
-
-        let ptr = unsafe { buf.as_ptr().add(entry_offset) };
-        let val = unsafe { core::ptr::read_unaligned(ptr as *const u32) };
+        let val = self.fat_cache.with_sector(
+            reader,
+            sector,
+            self.bytes_per_sector as usize,
+            |buf| le_u32(buf, entry_offset),
+        )??;
 
         Ok(val & 0x0FFFFFFF)
     }
 
+    fn set_next_cluster(
+        &self,
+        reader: &BlockReader,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), Error> {
+        let fat_offset = cluster as usize * 4;
+        let sector = self.fat_start_sector + fat_offset / self.bytes_per_sector as usize;
+        let entry_offset = fat_offset % self.bytes_per_sector as usize;
+
+        self.fat_cache.update(reader, sector, self.bytes_per_sector as usize, |buf| {
+            // The top four bits are reserved and must be preserved
+            let old = le_u32(buf, entry_offset)?;
+            put_le_u32(buf, entry_offset, (old & 0xF0000000) | (value & 0x0FFFFFFF))
+        })?
+    }
+
+    fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
     fn cluster_to_sector(&self, cluster: u32) -> usize {
         let rel_cluster = if cluster >= 2 { cluster - 2 } else { 0 };
         self.data_start_sector + (rel_cluster as usize * self.sectors_per_cluster as usize)
@@ -43,4 +68,7 @@ impl FatOps for Fat32Ops {
     fn sectors_per_cluster(&self) -> u32 {
         self.sectors_per_cluster as u32
     }
+    fn fat_cache(&self) -> &FatSectorCache {
+        &self.fat_cache
+    }
 }
@@ -1,7 +1,9 @@
 mod exfat;
+mod fat12;
 mod fat16;
 mod fat32;
 
-pub use exfat::{ExFatBpb, ExFatOps};
+pub use exfat::{ExFatBpb, ExFatOps, EXFAT_ENTRY_FILE};
+pub use fat12::Fat12Ops;
 pub use fat16::Fat16Ops;
 pub use fat32::Fat32Ops;
@@ -0,0 +1,376 @@
+use crate::device::BlockDevice;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use fs_common::tune::DEFAULT_DIRTY_RATIO;
+use glenda::error::Error;
+use spin::Mutex;
+
+// Device blocks held by the write-back buffer cache
+pub const BUFFER_CACHE_BLOCKS: usize = 64;
+
+struct Buffer {
+    data: Vec<u8>,
+    dirty: bool,
+    // Clock value of the last access, for LRU eviction
+    used: usize,
+}
+
+struct Buffers {
+    blocks: BTreeMap<usize, Buffer>,
+    clock: usize,
+}
+
+/// Write-back cache of device blocks, shared by every clone of a reader.
+/// Writes land here and reach the device on `flush`, when too much of the
+/// cache is dirty, or when a dirty block has to make room. It starts out
+/// off, writing through, for filesystems that order their own writes.
+pub struct BufferCache {
+    inner: Mutex<Buffers>,
+    block_size: usize,
+    capacity: AtomicUsize,
+    // Percentage of the capacity that may be dirty before writes flush
+    dirty_ratio: AtomicUsize,
+    write_back: AtomicBool,
+}
+
+impl BufferCache {
+    /// Cache of `block_size` byte device blocks.
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            inner: Mutex::new(Buffers { blocks: BTreeMap::new(), clock: 0 }),
+            block_size,
+            capacity: AtomicUsize::new(BUFFER_CACHE_BLOCKS),
+            dirty_ratio: AtomicUsize::new(DEFAULT_DIRTY_RATIO),
+            write_back: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether writes are buffered. When off, writes go straight to the
+    /// device; switching it off does not flush what is already buffered.
+    pub fn write_back(&self) -> bool {
+        self.write_back.load(Ordering::Relaxed)
+    }
+
+    pub fn set_write_back(&self, enabled: bool) {
+        self.write_back.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Resizes the cache, dropping clean blocks that no longer fit. Dirty
+    /// blocks stay until the next flush.
+    pub fn set_capacity(&self, blocks: usize) {
+        let capacity = core::cmp::max(blocks, 1);
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let mut inner = self.inner.lock();
+        while inner.blocks.len() > capacity {
+            match lru_clean(&inner.blocks) {
+                Some(block) => inner.blocks.remove(&block),
+                None => break,
+            };
+        }
+    }
+
+    pub fn set_dirty_ratio(&self, percent: usize) {
+        self.dirty_ratio.store(percent, Ordering::Relaxed);
+    }
+
+    pub fn dirty_blocks(&self) -> usize {
+        self.inner.lock().blocks.values().filter(|b| b.dirty).count()
+    }
+
+    /// Buffers `buf` at device byte `offset`, reading in blocks it only
+    /// partly covers.
+    pub fn write(&self, device: &impl BlockDevice, offset: usize, buf: &[u8]) -> Result<(), Error> {
+        let bs = self.block_size;
+        let mut inner = self.inner.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let block = pos / bs;
+            let within = pos % bs;
+            let len = core::cmp::min(bs - within, buf.len() - done);
+            if !inner.blocks.contains_key(&block) {
+                let mut data = alloc::vec![0u8; bs];
+                if len < bs {
+                    device.read_device(block, &mut data)?;
+                }
+                self.make_room(&mut inner, device)?;
+                inner.blocks.insert(block, Buffer { data, dirty: false, used: 0 });
+            }
+            inner.clock += 1;
+            let clock = inner.clock;
+            let buffer = inner.blocks.get_mut(&block).ok_or(Error::InternalError)?;
+            buffer.data[within..within + len].copy_from_slice(&buf[done..done + len]);
+            buffer.dirty = true;
+            buffer.used = clock;
+            done += len;
+        }
+        let limit = self.capacity() * self.dirty_ratio.load(Ordering::Relaxed) / 100;
+        if inner.blocks.values().filter(|b| b.dirty).count() > limit {
+            flush_range(&mut inner, device, 0, usize::MAX)?;
+        }
+        Ok(())
+    }
+
+    /// Copies cached blocks over `buf`, which was read from device byte
+    /// `offset`, so reads see writes not yet flushed.
+    pub fn overlay(&self, offset: usize, buf: &mut [u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let bs = self.block_size;
+        let inner = self.inner.lock();
+        let first = offset / bs;
+        let last = (offset + buf.len() - 1) / bs;
+        for (&block, buffer) in inner.blocks.range(first..=last).filter(|(_, b)| b.dirty) {
+            let start = core::cmp::max(block * bs, offset);
+            let end = core::cmp::min((block + 1) * bs, offset + buf.len());
+            let src = start - block * bs;
+            buf[start - offset..end - offset].copy_from_slice(&buffer.data[src..src + end - start]);
+        }
+    }
+
+    /// Writes every dirty block to the device, in block order.
+    pub fn flush(&self, device: &impl BlockDevice) -> Result<(), Error> {
+        flush_range(&mut self.inner.lock(), device, 0, usize::MAX)
+    }
+
+    /// Writes the dirty blocks overlapping `len` bytes at device byte `offset`,
+    /// for reads that bypass the cache.
+    pub fn flush_bytes(
+        &self,
+        device: &impl BlockDevice,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), Error> {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = offset / self.block_size;
+        let last = (offset + len - 1) / self.block_size;
+        flush_range(&mut self.inner.lock(), device, first, last)
+    }
+
+    /// Drops `count` blocks from `first`, dirty or not, for space that no
+    /// longer holds data.
+    pub fn forget(&self, first: usize, count: usize) {
+        self.inner.lock().blocks.retain(|&block, _| !(first..first + count).contains(&block));
+    }
+
+    // Evicts the least recently used clean block once the cache is full,
+    // flushing first if every block is dirty
+    fn make_room(&self, inner: &mut Buffers, device: &impl BlockDevice) -> Result<(), Error> {
+        while inner.blocks.len() >= self.capacity() {
+            let block = match lru_clean(&inner.blocks) {
+                Some(block) => block,
+                None => {
+                    flush_range(inner, device, 0, usize::MAX)?;
+                    lru_clean(&inner.blocks).ok_or(Error::InternalError)?
+                }
+            };
+            inner.blocks.remove(&block);
+        }
+        Ok(())
+    }
+}
+
+fn lru_clean(blocks: &BTreeMap<usize, Buffer>) -> Option<usize> {
+    blocks.iter().filter(|(_, b)| !b.dirty).min_by_key(|(_, b)| b.used).map(|(&block, _)| block)
+}
+
+// A block that fails to write stays dirty and stops the flush
+fn flush_range(
+    inner: &mut Buffers,
+    device: &impl BlockDevice,
+    first: usize,
+    last: usize,
+) -> Result<(), Error> {
+    for (&block, buffer) in inner.blocks.range_mut(first..=last) {
+        if buffer.dirty {
+            device.write_device(block, &buffer.data)?;
+            buffer.dirty = false;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    const BS: usize = 512;
+
+    fn cache(capacity: usize) -> BufferCache {
+        let cache = BufferCache::new(BS);
+        cache.set_write_back(true);
+        cache.set_capacity(capacity);
+        cache.set_dirty_ratio(100);
+        cache
+    }
+
+    #[test]
+    fn writes_wait_for_flush() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, BS, &[5u8; BS]).unwrap();
+        assert!(dev.writes().is_empty());
+        assert_eq!(cache.dirty_blocks(), 1);
+        cache.flush(&dev).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![1]);
+        assert_eq!(dev.bytes(BS, BS), alloc::vec![5u8; BS]);
+        assert_eq!(cache.dirty_blocks(), 0);
+        // Nothing is left to write
+        cache.flush(&dev).unwrap();
+        assert_eq!(dev.writes().len(), 1);
+    }
+
+    #[test]
+    fn whole_block_write_skips_the_read() {
+        let dev = MockDevice::new(BS, 4);
+        cache(4).write(&dev, 0, &[1u8; 2 * BS]).unwrap();
+        assert_eq!(dev.reads(), 0);
+    }
+
+    #[test]
+    fn partial_write_reads_the_block_in() {
+        let dev = MockDevice::new(BS, 4).filled(0xaa);
+        let cache = cache(4);
+        cache.write(&dev, BS + 10, &[1u8; 4]).unwrap();
+        assert_eq!(dev.reads(), 1);
+        // A second write into the block finds it cached
+        cache.write(&dev, BS + 20, &[2u8; 4]).unwrap();
+        assert_eq!(dev.reads(), 1);
+        cache.flush(&dev).unwrap();
+        let block = dev.bytes(BS, BS);
+        assert_eq!(block[9..15], [0xaa, 1, 1, 1, 1, 0xaa]);
+        assert_eq!(block[19..25], [0xaa, 2, 2, 2, 2, 0xaa]);
+        assert_eq!(block.iter().filter(|&&b| b == 0xaa).count(), BS - 8);
+    }
+
+    #[test]
+    fn failed_read_in_buffers_nothing() {
+        let dev = MockDevice::new(BS, 4).failing();
+        let cache = cache(4);
+        assert!(cache.write(&dev, 10, &[1u8; 4]).is_err());
+        assert_eq!(cache.dirty_blocks(), 0);
+    }
+
+    #[test]
+    fn overlay_patches_dirty_bytes_across_blocks() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, BS - 2, &[9u8; 4]).unwrap();
+        // Read from the device before the flush: still zero there
+        let mut buf = dev.bytes(BS - 4, 8);
+        cache.overlay(BS - 4, &mut buf);
+        assert_eq!(buf, [0, 0, 9, 9, 9, 9, 0, 0]);
+        let mut empty = [];
+        cache.overlay(0, &mut empty);
+    }
+
+    #[test]
+    fn overlay_skips_clean_blocks() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, 0, &[3u8; BS]).unwrap();
+        cache.flush(&dev).unwrap();
+        let mut buf = [7u8; 4];
+        cache.overlay(0, &mut buf);
+        assert_eq!(buf, [7u8; 4]);
+    }
+
+    #[test]
+    fn forget_drops_dirty_blocks() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, 0, &[1u8; 3 * BS]).unwrap();
+        cache.forget(1, 1);
+        assert_eq!(cache.dirty_blocks(), 2);
+        let mut buf = [0u8; 1];
+        cache.overlay(BS, &mut buf);
+        assert_eq!(buf, [0]);
+        cache.flush(&dev).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![0, 2]);
+    }
+
+    #[test]
+    fn flush_bytes_only_writes_overlapping_blocks() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, 0, &[1u8; 4 * BS]).unwrap();
+        cache.flush_bytes(&dev, BS + 1, BS).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![1, 2]);
+        assert_eq!(cache.dirty_blocks(), 2);
+        cache.flush_bytes(&dev, 0, 0).unwrap();
+        assert_eq!(dev.writes().len(), 2);
+    }
+
+    #[test]
+    fn failed_flush_keeps_blocks_dirty() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, 0, &[1u8; 2 * BS]).unwrap();
+        dev.set_failing(true);
+        assert!(cache.flush(&dev).is_err());
+        assert_eq!(cache.dirty_blocks(), 2);
+        dev.set_failing(false);
+        cache.flush(&dev).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![0, 1]);
+    }
+
+    #[test]
+    fn dirty_ratio_forces_a_flush() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.set_dirty_ratio(50);
+        cache.write(&dev, 0, &[1u8; 2 * BS]).unwrap();
+        assert!(dev.writes().is_empty());
+        cache.write(&dev, 2 * BS, &[1u8; BS]).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![0, 1, 2]);
+        assert_eq!(cache.dirty_blocks(), 0);
+    }
+
+    #[test]
+    fn full_cache_of_dirty_blocks_flushes_to_make_room() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(2);
+        cache.write(&dev, 0, &[1u8; 2 * BS]).unwrap();
+        assert!(dev.writes().is_empty());
+        cache.write(&dev, 2 * BS, &[2u8; BS]).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![0, 1]);
+        assert_eq!(cache.dirty_blocks(), 1);
+    }
+
+    #[test]
+    fn eviction_takes_the_least_recently_used_clean_block() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(2);
+        cache.write(&dev, 0, &[1u8; 2 * BS]).unwrap();
+        cache.flush(&dev).unwrap();
+        // Block 0 is used again, so block 1 goes
+        cache.write(&dev, 0, &[2u8; BS]).unwrap();
+        cache.flush(&dev).unwrap();
+        cache.write(&dev, 2 * BS, &[3u8; BS]).unwrap();
+        // Partial writes only read in blocks that are not cached
+        cache.write(&dev, 1, &[4u8; 1]).unwrap();
+        assert_eq!(dev.reads(), 0);
+        cache.write(&dev, BS + 1, &[4u8; 1]).unwrap();
+        assert_eq!(dev.reads(), 1);
+    }
+
+    #[test]
+    fn shrinking_keeps_dirty_blocks() {
+        let dev = MockDevice::new(BS, 4);
+        let cache = cache(4);
+        cache.write(&dev, 0, &[1u8; 3 * BS]).unwrap();
+        cache.set_capacity(1);
+        assert_eq!(cache.dirty_blocks(), 3);
+        cache.flush(&dev).unwrap();
+        assert_eq!(dev.writes(), alloc::vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,81 @@
+//! The device under the caches: whole blocks in and out, and writes of byte
+//! ranges that do not line up with them.
+
+use glenda::error::Error;
+
+/// Whole device blocks, read and written past the buffer cache. `block`
+/// counts device blocks and `buf` holds a whole number of them.
+pub trait BlockDevice {
+    fn read_device(&self, block: usize, buf: &mut [u8]) -> Result<(), Error>;
+    fn write_device(&self, block: usize, buf: &[u8]) -> Result<(), Error>;
+}
+
+// First device block and the length of the whole blocks covering `len`
+// bytes at device offset `pos`
+pub(crate) fn span(block_size: usize, pos: usize, len: usize) -> (usize, usize) {
+    let first = pos / block_size;
+    let end = (pos + len).div_ceil(block_size);
+    (first, (end - first) * block_size)
+}
+
+// Writes `buf` at device offset `pos`. Blocks it only partly covers are
+// read, patched and written back whole.
+pub(crate) fn write_span(
+    device: &impl BlockDevice,
+    block_size: usize,
+    pos: usize,
+    buf: &[u8],
+) -> Result<(), Error> {
+    let (first, span) = span(block_size, pos, buf.len());
+    if span == buf.len() {
+        return device.write_device(first, buf);
+    }
+    let mut temp_buf = alloc::vec![0u8; span];
+    device.read_device(first, &mut temp_buf)?;
+    let copy_start = pos % block_size;
+    temp_buf[copy_start..copy_start + buf.len()].copy_from_slice(buf);
+    device.write_device(first, &temp_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    const BS: usize = 512;
+
+    #[test]
+    fn span_covers_partial_blocks() {
+        assert_eq!(span(BS, 0, BS), (0, BS));
+        assert_eq!(span(BS, 100, 10), (0, BS));
+        assert_eq!(span(BS, 500, 20), (0, 2 * BS));
+        assert_eq!(span(BS, 2 * BS, 3 * BS), (2, 3 * BS));
+    }
+
+    #[test]
+    fn aligned_write_skips_the_read() {
+        let dev = MockDevice::new(BS, 4);
+        write_span(&dev, BS, BS, &[7u8; 2 * BS]).unwrap();
+        assert_eq!(dev.reads(), 0);
+        assert_eq!(dev.writes(), alloc::vec![1]);
+        assert_eq!(dev.bytes(BS, 2 * BS), alloc::vec![7u8; 2 * BS]);
+    }
+
+    #[test]
+    fn partial_write_keeps_the_rest_of_the_block() {
+        let dev = MockDevice::new(BS, 4).filled(0xaa);
+        write_span(&dev, BS, BS - 4, &[1u8; 8]).unwrap();
+        assert_eq!(dev.reads(), 1);
+        assert_eq!(dev.writes(), alloc::vec![0]);
+        assert_eq!(dev.bytes(BS - 5, 10), [0xaa, 1, 1, 1, 1, 1, 1, 1, 1, 0xaa]);
+        assert_eq!(dev.bytes(0, BS - 4), alloc::vec![0xaa; BS - 4]);
+        assert_eq!(dev.bytes(BS + 4, BS - 4), alloc::vec![0xaa; BS - 4]);
+    }
+
+    #[test]
+    fn failed_read_writes_nothing() {
+        let dev = MockDevice::new(BS, 4).failing();
+        assert!(write_span(&dev, BS, 10, &[1u8; 8]).is_err());
+        assert!(dev.writes().is_empty());
+    }
+}
@@ -0,0 +1,24 @@
+//! Byte-addressed access to a volume behind a block device: partition
+//! bounds, read-modify-write of partial device blocks, batched reads sent
+//! on a ring shared with the driver when it takes one, readahead and an
+//! optional write-back buffer cache, with IO statistics, heat and
+//! changed-block tracking shared by every clone of a reader.
+//!
+//! Sector arguments are in 512-byte units whatever the device block size;
+//! offsets are bytes into the partition.
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+mod buffers;
+mod device;
+#[cfg(test)]
+mod mock;
+mod reader;
+mod ring;
+
+pub use buffers::{BufferCache, BUFFER_CACHE_BLOCKS};
+pub use device::BlockDevice;
+pub use reader::{BlockReader, DEV_BLOCK_SIZE, SECTOR_SIZE};
+pub use ring::DEVICE_RING_SIZE;
@@ -0,0 +1,84 @@
+//! A device in memory for the unit tests, recording what reaches it.
+
+use crate::device::BlockDevice;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use glenda::error::Error;
+
+pub struct MockDevice {
+    block_size: usize,
+    data: RefCell<Vec<u8>>,
+    reads: Cell<usize>,
+    // First block of every write, in order
+    writes: RefCell<Vec<usize>>,
+    failing: Cell<bool>,
+}
+
+impl MockDevice {
+    /// `blocks` zeroed blocks of `block_size` bytes.
+    pub fn new(block_size: usize, blocks: usize) -> Self {
+        Self {
+            block_size,
+            data: RefCell::new(alloc::vec![0u8; block_size * blocks]),
+            reads: Cell::new(0),
+            writes: RefCell::new(Vec::new()),
+            failing: Cell::new(false),
+        }
+    }
+
+    pub fn filled(self, byte: u8) -> Self {
+        self.data.borrow_mut().fill(byte);
+        self
+    }
+
+    /// Every transfer fails with DeviceError until `set_failing(false)`.
+    pub fn failing(self) -> Self {
+        self.set_failing(true);
+        self
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.set(failing);
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.get()
+    }
+
+    pub fn writes(&self) -> Vec<usize> {
+        self.writes.borrow().clone()
+    }
+
+    /// What the medium holds at byte `offset`.
+    pub fn bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.data.borrow()[offset..offset + len].to_vec()
+    }
+
+    // Byte range of a transfer, refusing partial blocks and the far side of the end
+    fn range(&self, block: usize, len: usize) -> Result<core::ops::Range<usize>, Error> {
+        if self.failing.get() {
+            return Err(Error::DeviceError);
+        }
+        let start = block * self.block_size;
+        if !len.is_multiple_of(self.block_size) || start + len > self.data.borrow().len() {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl BlockDevice for MockDevice {
+    fn read_device(&self, block: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let range = self.range(block, buf.len())?;
+        self.reads.set(self.reads.get() + 1);
+        buf.copy_from_slice(&self.data.borrow()[range]);
+        Ok(())
+    }
+
+    fn write_device(&self, block: usize, buf: &[u8]) -> Result<(), Error> {
+        let range = self.range(block, buf.len())?;
+        self.writes.borrow_mut().push(block);
+        self.data.borrow_mut()[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
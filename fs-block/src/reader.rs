@@ -0,0 +1,480 @@
+use crate::buffers::BufferCache;
+use crate::device::{span, write_span, BlockDevice};
+use crate::ring::DeviceRing;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use fs_common::batch::{ReadBatch, MAX_BATCH_BYTES};
+use fs_common::cbt::ChangeTracker;
+use fs_common::device::{self, Geometry, IoTuning};
+use fs_common::health::{IoErrorCounts, IoStats};
+use fs_common::heat::HeatMap;
+use fs_common::partition::Partition;
+use fs_common::readahead::PrefetchCache;
+use glenda::cap::Endpoint;
+use glenda::client::volume::VolumeClient;
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::io::uring::IoUringClient;
+use glenda::io::uring::RingParams;
+use glenda::ipc::UTCB;
+use glenda::mem::shm::SharedMemory;
+use glenda::mem::shm::ShmParams;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use spin::Mutex;
+
+// Unit the volume driver is taken to read and write in when it does not
+// report its geometry
+pub const DEV_BLOCK_SIZE: usize = 4096;
+// Unit of the `sector` arguments
+pub const SECTOR_SIZE: usize = 512;
+
+pub struct BlockReader {
+    client: VolumeClient,
+    tuning: IoTuning,
+    // Every transfer is aligned to its logical block
+    geometry: Geometry,
+    // Shared with every clone, so it covers all IO on the volume
+    stats: Arc<IoStats>,
+    heat: Arc<HeatMap>,
+    changes: Arc<ChangeTracker>,
+    buffers: Arc<BufferCache>,
+    prefetched: Arc<PrefetchCache>,
+    // Batched reads go out on it when the driver took one
+    ring: Option<Arc<Mutex<DeviceRing>>>,
+    bounds: Bounds,
+}
+
+// Device byte range the filesystem lives in and may do IO to
+#[derive(Debug, Clone, Copy, Default)]
+struct Bounds {
+    // Offsets into the volume are relative to this device offset
+    base: usize,
+    // Length of the partition, if IO is confined to one
+    size: Option<usize>,
+    // Bytes on the device, when the driver reports them
+    device: Option<usize>,
+}
+
+impl Bounds {
+    fn capacity(&self) -> Option<usize> {
+        let device = self.device.map(|bytes| bytes.saturating_sub(self.base));
+        match (self.size, device) {
+            (Some(part), Some(device)) => Some(part.min(device)),
+            (part, device) => part.or(device),
+        }
+    }
+
+    // Device offset of `len` bytes at `offset` into the volume. Nothing past
+    // the end goes to the driver: reads and writes reaching beyond it fail
+    // whole, with no partial transfer.
+    fn locate(&self, offset: usize, len: usize) -> Result<usize, Error> {
+        let end = offset.checked_add(len).ok_or(Error::InvalidArgs)?;
+        if self.capacity().is_some_and(|size| end > size) {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(self.base + offset)
+    }
+
+    // `len` cut short where the volume ends, for readahead that stops there
+    // rather than failing
+    fn readahead(&self, offset: usize, len: usize) -> usize {
+        self.capacity().map_or(len, |size| len.min(size.saturating_sub(offset)))
+    }
+}
+
+impl BlockReader {
+    pub fn new(
+        endpoint: Endpoint,
+        res_client: &mut ResourceClient,
+        ring_params: RingParams,
+        shm_params: ShmParams,
+    ) -> Self {
+        Self {
+            client: VolumeClient::new(endpoint, res_client, ring_params, shm_params),
+            tuning: IoTuning::default(),
+            geometry: Geometry {
+                capacity: None,
+                logical_block: DEV_BLOCK_SIZE,
+                physical_block: DEV_BLOCK_SIZE,
+            },
+            stats: Arc::new(IoStats::new()),
+            heat: Arc::new(HeatMap::new()),
+            changes: Arc::new(ChangeTracker::new()),
+            buffers: Arc::new(BufferCache::new(DEV_BLOCK_SIZE)),
+            prefetched: Arc::new(PrefetchCache::new()),
+            ring: None,
+            bounds: Bounds::default(),
+        }
+    }
+
+    /// Connects to the volume driver and takes on the geometry it reports;
+    /// drivers that report none are taken to use DEV_BLOCK_SIZE blocks.
+    pub fn init(
+        &mut self,
+        vspace: &mut VSpaceManager,
+        cspace: &mut CSpaceManager,
+    ) -> Result<(), Error> {
+        self.client.connect(vspace, cspace)?;
+        let utcb = unsafe { UTCB::new() };
+        if let Some(geometry) = Geometry::query(self.client.endpoint(), utcb) {
+            self.set_geometry(geometry);
+        }
+        Ok(())
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        self.client.endpoint()
+    }
+
+    pub fn set_shm(&mut self, shm: SharedMemory) {
+        self.client.set_shm(shm);
+    }
+
+    pub fn set_ring(&mut self, ring: IoUringClient) {
+        self.client.set_ring(ring);
+    }
+
+    /// Sets up a ring with the driver in DEVICE_RING_SIZE bytes of address
+    /// space at `vaddr`, for `submit` and `prefetch` to send batches on.
+    /// Drivers without VOLUME_RING_SETUP refuse it, and the caller may take
+    /// back `vaddr`: batches then go out one synchronous transfer per run.
+    pub fn setup_ring(
+        &mut self,
+        vaddr: usize,
+        res_client: &mut ResourceClient,
+        vspace: &mut VSpaceManager,
+        cspace: &mut CSpaceManager,
+    ) -> Result<(), Error> {
+        let ring = DeviceRing::setup(self.client.endpoint(), vaddr, res_client, vspace, cspace)?;
+        self.ring = Some(Arc::new(Mutex::new(ring)));
+        Ok(())
+    }
+
+    /// IO settings for this reader. Clones taken earlier keep the old ones.
+    pub fn set_tuning(&mut self, tuning: IoTuning) {
+        self.tuning = tuning;
+    }
+
+    pub fn tuning(&self) -> IoTuning {
+        self.tuning
+    }
+
+    /// Bytes the device reads and writes in; every transfer is aligned to it.
+    pub fn device_block_size(&self) -> usize {
+        self.geometry.logical_block
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// Aligns transfers to `geometry`'s logical block from now on. The
+    /// buffer cache starts over empty, so this is for a reader nothing has
+    /// been written through or cloned from yet.
+    pub fn set_geometry(&mut self, geometry: Geometry) {
+        if geometry.logical_block != self.geometry.logical_block {
+            self.buffers = Arc::new(BufferCache::new(geometry.logical_block));
+        }
+        self.geometry = geometry;
+        self.bounds.device = geometry.capacity;
+    }
+
+    /// Confines IO to `part`, or opens up the whole device again for `None`.
+    pub fn set_partition(&mut self, part: Option<&Partition>) {
+        self.bounds.base = part.map_or(0, |p| p.start as usize);
+        self.bounds.size = part.map(|p| p.len as usize);
+    }
+
+    /// Bytes IO is confined to: the partition, cut short where the device
+    /// ends, or the whole device. None when neither a partition nor the
+    /// driver gives a size.
+    pub fn capacity(&self) -> Option<usize> {
+        self.bounds.capacity()
+    }
+
+    fn locate(&self, offset: usize, len: usize) -> Result<usize, Error> {
+        self.bounds.locate(offset, len)
+    }
+
+    pub fn error_counts(&self) -> IoErrorCounts {
+        self.stats.counts()
+    }
+
+    pub fn heat(&self) -> &HeatMap {
+        &self.heat
+    }
+
+    pub fn changes(&self) -> &ChangeTracker {
+        &self.changes
+    }
+
+    /// The write-back cache; writes go through it once a filesystem turns
+    /// write-back on.
+    pub fn buffers(&self) -> &BufferCache {
+        &self.buffers
+    }
+
+    /// Writes everything the buffer cache holds dirty to the device.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.buffers.flush(self)
+    }
+
+    /// Writes the dirty blocks overlapping `len` bytes at `sector` to the device.
+    pub fn flush_blocks(&self, sector: usize, len: usize) -> Result<(), Error> {
+        let start = self.locate(sector * SECTOR_SIZE, len)?;
+        self.buffers.flush_bytes(self, start, len)
+    }
+
+    /// Write barrier: everything written before it is on the medium before
+    /// anything written after it. Flushes the buffer cache, then the
+    /// device's write cache when the tuning says it has a volatile one.
+    pub fn barrier(&self) -> Result<(), Error> {
+        self.flush()?;
+        if !self.tuning.flush_cache {
+            return Ok(());
+        }
+        let utcb = unsafe { UTCB::new() };
+        self.stats.run(0, || device::flush_cache(self.client.endpoint(), utcb))
+    }
+
+    /// Reads `buf.len()` bytes at `offset`, seeing writes the buffer cache
+    /// holds back.
+    pub fn read_offset(&self, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.heat.record(offset, buf.len());
+        let start_pos = self.locate(offset, buf.len())?;
+        if self.prefetched.take(start_pos, buf) {
+            self.buffers.overlay(start_pos, buf);
+            return Ok(buf.len());
+        }
+
+        let (first, span) = span(self.device_block_size(), start_pos, buf.len());
+        if span == buf.len() {
+            self.read_device(first, buf)?;
+        } else {
+            // Partial blocks at either end come in whole
+            let mut temp_buf = alloc::vec![0u8; span];
+            self.read_device(first, &mut temp_buf)?;
+            let copy_start = start_pos % self.device_block_size();
+            buf.copy_from_slice(&temp_buf[copy_start..copy_start + buf.len()]);
+        }
+        self.buffers.overlay(start_pos, buf);
+        Ok(buf.len())
+    }
+
+    /// Performs every read queued in `batch` before returning. It goes out as
+    /// one request per run of adjacent device blocks rather than one per
+    /// read, ordered by the IO scheduler in the tuning, and with a device
+    /// ring as one submission the reader waits on once.
+    pub fn submit(&self, batch: &mut ReadBatch) -> Result<(), Error> {
+        for (offset, buf) in batch.reads_mut() {
+            self.heat.record(*offset, buf.len());
+            *offset = self.locate(*offset, buf.len())?;
+        }
+        let scheduler = self.tuning().scheduler;
+        batch.execute(scheduler, self.device_block_size(), MAX_BATCH_BYTES, |runs| {
+            self.read_runs(runs)
+        })?;
+        for (offset, buf) in batch.reads_mut() {
+            self.buffers.overlay(*offset, buf);
+        }
+        Ok(())
+    }
+
+    // Device runs of a batch: all on the ring in one go, or one at a time
+    fn read_runs(&self, runs: &mut [(usize, Vec<u8>)]) -> Result<(), Error> {
+        match self.ring.as_ref() {
+            Some(ring) => {
+                let bytes = runs.iter().map(|(_, buf)| buf.len()).sum();
+                let block_size = self.device_block_size();
+                self.stats.run(bytes, || ring.lock().read(block_size, runs))
+            }
+            None => runs.iter_mut().try_for_each(|(block, buf)| self.read_device(*block, buf)),
+        }
+    }
+
+    /// Reads `len` bytes at `offset` into the prefetch cache, in pieces of
+    /// `piece` bytes starting at `offset` that later reads take whole or in
+    /// part. Readahead is only a guess, so callers may drop the error.
+    pub fn prefetch(&self, offset: usize, len: usize, piece: usize) -> Result<(), Error> {
+        let len = self.bounds.readahead(offset, len);
+        if len == 0 {
+            return Ok(());
+        }
+        let start = self.locate(offset, len)?;
+        let piece = piece.clamp(1, MAX_BATCH_BYTES);
+        let mut pieces: Vec<(usize, Vec<u8>)> = (start..start + len)
+            .step_by(piece)
+            .map(|at| (at, alloc::vec![0u8; piece.min(start + len - at)]))
+            .collect();
+        let mut batch = ReadBatch::new();
+        for (at, buf) in pieces.iter_mut() {
+            batch.push(*at, buf);
+        }
+        let scheduler = self.tuning().scheduler;
+        batch.execute(scheduler, self.device_block_size(), MAX_BATCH_BYTES, |runs| {
+            self.read_runs(runs)
+        })?;
+        for (at, buf) in pieces {
+            self.prefetched.insert(at, buf);
+        }
+        Ok(())
+    }
+
+    pub fn read_shm(&self, offset: usize, len: u32, shm_vaddr: usize) -> Result<(), Error> {
+        self.heat.record(offset, len as usize);
+        let offset = self.locate(offset, len as usize)?;
+        // The device fills the buffer directly, so it must see pending writes
+        self.buffers.flush_bytes(self, offset, len as usize)?;
+        self.stats.run(len as usize, || self.client.read_shm(offset, len, shm_vaddr))
+    }
+
+    /// Writes `buf` at `sector`, into the buffer cache when write-back is on.
+    /// Device blocks it only partly covers are read, patched and written
+    /// back whole.
+    pub fn write_blocks(&self, sector: usize, buf: &[u8]) -> Result<(), Error> {
+        self.heat.record(sector * SECTOR_SIZE, buf.len());
+        self.changes.record(sector * SECTOR_SIZE, buf.len());
+        let start_pos = self.locate(sector * SECTOR_SIZE, buf.len())?;
+        self.prefetched.invalidate(start_pos, buf.len());
+        if self.buffers.write_back() {
+            return self.buffers.write(self, start_pos, buf);
+        }
+
+        write_span(self, self.device_block_size(), start_pos, buf)
+    }
+
+    /// Tells the device that `len` bytes at `offset` no longer hold data,
+    /// when the tuning has discard on. Only the device blocks wholly inside
+    /// the range are discarded; writes to them still buffered are dropped.
+    /// A discard that fails leaves the old data in place, which is harmless,
+    /// so callers may ignore the error.
+    pub fn discard(&self, offset: usize, len: usize) -> Result<(), Error> {
+        if !self.tuning.discard {
+            return Ok(());
+        }
+        let start = self.locate(offset, len)?;
+        let block_size = self.device_block_size();
+        let first = start.div_ceil(block_size);
+        let end = (start + len) / block_size;
+        if first >= end {
+            return Ok(());
+        }
+        self.buffers.forget(first, end - first);
+        self.prefetched.invalidate(first * block_size, (end - first) * block_size);
+        let utcb = unsafe { UTCB::new() };
+        self.stats.run(0, || device::discard(self.client.endpoint(), utcb, first, end - first))
+    }
+}
+
+impl BlockDevice for BlockReader {
+    fn read_device(&self, block: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.stats.run(buf.len(), || self.client.read_at(block, buf.len() as u32, buf))
+    }
+
+    fn write_device(&self, block: usize, buf: &[u8]) -> Result<(), Error> {
+        self.prefetched.invalidate(block * self.device_block_size(), buf.len());
+        self.stats.run(buf.len(), || self.client.write_at(block, buf.len() as u32, buf))
+    }
+}
+
+impl Clone for BlockReader {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            tuning: self.tuning,
+            geometry: self.geometry,
+            stats: self.stats.clone(),
+            heat: self.heat.clone(),
+            changes: self.changes.clone(),
+            buffers: self.buffers.clone(),
+            prefetched: self.prefetched.clone(),
+            ring: self.ring.clone(),
+            bounds: self.bounds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAP: usize = 8 * DEV_BLOCK_SIZE;
+
+    fn device(bytes: usize) -> Bounds {
+        Bounds { device: Some(bytes), ..Bounds::default() }
+    }
+
+    #[test]
+    fn io_may_end_exactly_at_capacity() {
+        let bounds = device(CAP);
+        assert_eq!(bounds.capacity(), Some(CAP));
+        assert_eq!(bounds.locate(CAP - 512, 512), Ok(CAP - 512));
+        assert_eq!(bounds.locate(0, CAP), Ok(0));
+        assert_eq!(bounds.locate(CAP, 0), Ok(CAP));
+    }
+
+    #[test]
+    fn io_crossing_capacity_fails_whole() {
+        let bounds = device(CAP);
+        assert_eq!(bounds.locate(CAP - 512, 513), Err(Error::InvalidArgs));
+        assert_eq!(bounds.locate(0, CAP + 1), Err(Error::InvalidArgs));
+    }
+
+    #[test]
+    fn io_starting_past_capacity_fails() {
+        let bounds = device(CAP);
+        assert_eq!(bounds.locate(CAP, 1), Err(Error::InvalidArgs));
+        assert_eq!(bounds.locate(CAP + 512, 512), Err(Error::InvalidArgs));
+        assert_eq!(bounds.locate(usize::MAX, 1), Err(Error::InvalidArgs));
+    }
+
+    #[test]
+    fn partition_offsets_and_bounds_io() {
+        let bounds = Bounds { base: 1024, size: Some(4096), device: Some(CAP) };
+        assert_eq!(bounds.capacity(), Some(4096));
+        assert_eq!(bounds.locate(4000, 96), Ok(1024 + 4000));
+        assert_eq!(bounds.locate(4000, 97), Err(Error::InvalidArgs));
+    }
+
+    #[test]
+    fn device_end_cuts_a_partition_short() {
+        let bounds = Bounds { base: CAP - 4096, size: Some(CAP), device: Some(CAP) };
+        assert_eq!(bounds.capacity(), Some(4096));
+        assert_eq!(bounds.locate(4096, 1), Err(Error::InvalidArgs));
+        let past = Bounds { base: CAP + 4096, size: Some(4096), device: Some(CAP) };
+        assert_eq!(past.capacity(), Some(0));
+        assert_eq!(past.locate(0, 1), Err(Error::InvalidArgs));
+    }
+
+    #[test]
+    fn unknown_size_only_refuses_overflow() {
+        let bounds = Bounds::default();
+        assert_eq!(bounds.capacity(), None);
+        assert_eq!(bounds.locate(CAP * 1024, 512), Ok(CAP * 1024));
+        assert_eq!(bounds.locate(usize::MAX, 1), Err(Error::InvalidArgs));
+        assert_eq!(bounds.readahead(CAP * 1024, 4096), 4096);
+    }
+
+    #[test]
+    fn readahead_stops_at_capacity() {
+        let bounds = device(CAP);
+        assert_eq!(bounds.readahead(0, 4096), 4096);
+        assert_eq!(bounds.readahead(CAP - 4096, 4096), 4096);
+        assert_eq!(bounds.readahead(CAP - 1024, 4096), 1024);
+        assert_eq!(bounds.readahead(CAP, 4096), 0);
+        assert_eq!(bounds.readahead(CAP + 4096, 4096), 0);
+    }
+
+    #[test]
+    fn rounded_geometry_bounds_io_to_whole_blocks() {
+        // A device of 8.5 blocks can only address 8 of them
+        let geometry = Geometry::from_report(CAP + 2048, 4096, 4096).unwrap();
+        let bounds = device(geometry.capacity.unwrap());
+        assert_eq!(bounds.locate(CAP - 4096, 4096), Ok(CAP - 4096));
+        assert_eq!(bounds.locate(CAP, 512), Err(Error::InvalidArgs));
+        assert_eq!(bounds.readahead(CAP - 4096, 8192), 4096);
+    }
+}
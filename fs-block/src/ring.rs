@@ -0,0 +1,137 @@
+//! A ring shared with the volume driver. A batch of reads goes out on it as
+//! one SQE per run of adjacent blocks, all entered with a single call, and
+//! the reader waits once for their completions rather than once per run.
+//! Drivers that do not take VOLUME_RING_SETUP leave the reader on
+//! synchronous transfers.
+
+use alloc::vec::Vec;
+use fs_common::batch::MAX_BATCH_BYTES;
+use fs_common::device;
+use fs_common::errors;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CapType, Endpoint, Frame};
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::interface::{CSpaceService, ResourceService, VSpaceService};
+use glenda::io::uring::{IoUringBuffer, IoUringSqe, IOURING_OP_READ};
+use glenda::ipc::{Badge, UTCB};
+use glenda::mem::Perms;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+// SQEs one submission may carry
+const RING_ENTRIES: usize = 16;
+// The queues take the first page, read buffers the rest
+const QUEUE_SIZE: usize = PGSIZE;
+const DATA_SIZE: usize = 2 * MAX_BATCH_BYTES;
+/// Bytes of address space a device ring is mapped in.
+pub const DEVICE_RING_SIZE: usize = QUEUE_SIZE + DATA_SIZE;
+
+pub struct DeviceRing {
+    endpoint: Endpoint,
+    ring: IoUringBuffer,
+    vaddr: usize,
+    next_user_data: u64,
+}
+
+impl DeviceRing {
+    /// Maps a ring in fresh memory at `vaddr`, DEVICE_RING_SIZE bytes the
+    /// caller reserved, and hands it to the driver behind `endpoint`. The
+    /// memory is unmapped again if the driver declines it.
+    pub fn setup(
+        endpoint: Endpoint,
+        vaddr: usize,
+        res_client: &mut ResourceClient,
+        vspace: &mut VSpaceManager,
+        cspace: &mut CSpaceManager,
+    ) -> Result<Self, Error> {
+        let pages = DEVICE_RING_SIZE / PGSIZE;
+        let slot = cspace.alloc(res_client)?;
+        res_client.alloc(Badge::null(), CapType::Frame, pages, slot)?;
+        vspace.map_frame(
+            Frame::from(slot),
+            vaddr,
+            Perms::READ | Perms::WRITE,
+            pages,
+            res_client,
+            cspace,
+        )?;
+        let ring =
+            unsafe { IoUringBuffer::new(vaddr as *mut u8, QUEUE_SIZE, RING_ENTRIES, RING_ENTRIES) };
+        let utcb = unsafe { UTCB::new() };
+        if let Err(e) =
+            device::ring_setup(endpoint, utcb, slot, vaddr, DEVICE_RING_SIZE, QUEUE_SIZE)
+        {
+            let _ = vspace.unmap(vaddr, pages);
+            cspace.free(slot);
+            return Err(e);
+        }
+        Ok(Self { endpoint, ring, vaddr, next_user_data: 1 })
+    }
+
+    /// Reads every run, a first device block of `block_size` bytes and the
+    /// buffer it fills, with as many SQEs per VOLUME_RING_ENTER as the queue
+    /// and buffers take.
+    pub fn read(&mut self, block_size: usize, runs: &mut [(usize, Vec<u8>)]) -> Result<(), Error> {
+        let mut start = 0;
+        while start < runs.len() {
+            let first_user_data = self.next_user_data;
+            let mut used = 0;
+            let mut stop = start;
+            while stop < runs.len() && stop - start < RING_ENTRIES {
+                let len = runs[stop].1.len();
+                if used + len > DATA_SIZE {
+                    break;
+                }
+                let sqe = IoUringSqe {
+                    opcode: IOURING_OP_READ,
+                    off: (runs[stop].0 * block_size) as u64,
+                    addr: (self.vaddr + QUEUE_SIZE + used) as u64,
+                    len: len as u32,
+                    user_data: first_user_data + (stop - start) as u64,
+                    ..Default::default()
+                };
+                self.ring.submit(sqe).map_err(|_| Error::WouldBlock)?;
+                used += len;
+                stop += 1;
+            }
+            if stop == start {
+                return Err(Error::InvalidArgs);
+            }
+            self.next_user_data += (stop - start) as u64;
+            device::ring_enter(self.endpoint, unsafe { UTCB::new() })?;
+
+            // Completions of an earlier, failed submission are passed over
+            let mut done = alloc::vec![false; stop - start];
+            while let Some(cqe) = self.ring.pop_cqe() {
+                let Some(index) = cqe.user_data.checked_sub(first_user_data) else {
+                    continue;
+                };
+                let Some(slot) = done.get_mut(index as usize) else {
+                    continue;
+                };
+                if cqe.res < 0 {
+                    return Err(errors::decode(cqe.res.unsigned_abs() as usize));
+                }
+                if cqe.res as usize != runs[start + index as usize].1.len() {
+                    return Err(Error::IoError);
+                }
+                *slot = true;
+            }
+            if done.contains(&false) {
+                return Err(Error::IoError);
+            }
+
+            let data = unsafe {
+                core::slice::from_raw_parts((self.vaddr + QUEUE_SIZE) as *const u8, used)
+            };
+            let mut at = 0;
+            for (_, buf) in runs[start..stop].iter_mut() {
+                let len = buf.len();
+                buf.copy_from_slice(&data[at..at + len]);
+                at += len;
+            }
+            start = stop;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Server-side cache of path attributes so repeated STAT_PATH calls skip the
+//! path walk and the device.
+
+use crate::casefold;
+use crate::path;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use glenda::protocol::fs::Stat;
+
+pub const ATTR_CACHE_SIZE: usize = 128;
+
+struct CachedAttr {
+    stat: Stat,
+    last_used: u64,
+}
+
+pub struct AttrCache {
+    entries: BTreeMap<String, CachedAttr>,
+    capacity: usize,
+    tick: u64,
+    // Paths differing only in case name the same file
+    casefold: bool,
+}
+
+impl AttrCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: BTreeMap::new(), capacity, tick: 0, casefold: false }
+    }
+
+    /// For a volume whose names match regardless of case.
+    pub fn with_casefold(self, casefold: bool) -> Self {
+        Self { casefold, ..self }
+    }
+
+    fn key(&self, path: &str) -> String {
+        let path = path::normalize(path);
+        if self.casefold {
+            casefold::key(&path)
+        } else {
+            path
+        }
+    }
+
+    pub fn get(&mut self, path: &str) -> Option<Stat> {
+        self.tick += 1;
+        let tick = self.tick;
+        let key = self.key(path);
+        let entry = self.entries.get_mut(&key)?;
+        entry.last_used = tick;
+        Some(entry.stat)
+    }
+
+    pub fn insert(&mut self, path: &str, stat: Stat) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = self.key(path);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let victim =
+                self.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone());
+            if let Some(victim) = victim {
+                self.entries.remove(&victim);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(key, CachedAttr { stat, last_used: self.tick });
+    }
+
+    /// Drops `path`, everything below it and its parent, whose size, link
+    /// count or timestamps change along with it.
+    pub fn invalidate(&mut self, path: &str) {
+        let key = self.key(path);
+        let parent = String::from(path::parent(&key));
+        let mut prefix = key.clone();
+        prefix.push('/');
+        self.entries.retain(|k, _| *k != key && *k != parent && !k.starts_with(&prefix));
+    }
+
+    /// Drops every path that names inode `ino`. Hard links share its size,
+    /// link count and timestamps, so a change through one stales the rest.
+    pub fn invalidate_ino(&mut self, ino: usize) {
+        self.entries.retain(|_, e| e.stat.ino != ino);
+    }
+}
@@ -0,0 +1,213 @@
+//! Audit trail of the requests that change a volume or its service, for
+//! deployments that must account for every write. Records go into a bounded
+//! in-memory log that an auditor drains through AUDIT_READ; once full, the
+//! oldest records rotate out and the reader is told how many it missed.
+//!
+//! Records are ordered by a sequence number and dated by the clock the
+//! monitor sets, when it has. Writes and locks submitted on a ring pass
+//! through the service as one PROCESS_IOURING call or doorbell; the ring
+//! records those submissions one by one instead.
+
+use crate::clock::{self, Timestamp};
+use crate::locks::{IOURING_OP_LOCK, IOURING_OP_UNLOCK};
+use crate::path;
+use crate::policy::{required_ops, OP_ADMIN, OP_UNLINK, OP_WRITE};
+use crate::proto;
+use crate::ring::IOURING_OP_WRITEV;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use glenda::error::Error;
+use glenda::io::uring::IOURING_OP_WRITE;
+use glenda::ipc::UTCB;
+use glenda::protocol::fs;
+use glenda::protocol::{FS_PROTO, PROCESS_PROTO};
+
+// Records kept before the oldest rotate out
+pub const AUDIT_MAX_RECORDS: usize = 512;
+
+pub struct AuditRecord {
+    pub seq: u64,
+    // None while the clock was not set yet
+    pub time: Option<Timestamp>,
+    pub badge: usize,
+    pub proto: usize,
+    pub label: usize,
+    pub path: String,
+    pub result: Result<(), Error>,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.seq)?;
+        if let Some(time) = self.time {
+            write!(f, "time={}.{:09} ", time.sec, time.nsec)?;
+        }
+        write!(f, "badge={:#x} op={} ", self.badge, op_name(self.proto, self.label))?;
+        match self.result {
+            Ok(()) => write!(f, "result=ok")?,
+            Err(e) => write!(f, "result={:?}", e)?,
+        }
+        if !self.path.is_empty() {
+            write!(f, " path={}", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct AuditLog {
+    enabled: bool,
+    records: VecDeque<AuditRecord>,
+    next_seq: u64,
+}
+
+impl AuditLog {
+    /// A disabled log records nothing and refuses AUDIT_READ.
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, records: VecDeque::new(), next_seq: 0 }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the request in `utcb` is one the log keeps.
+    pub fn wants(&self, utcb: &UTCB) -> bool {
+        let tag = utcb.get_msg_tag();
+        if !self.enabled || (tag.proto() == FS_PROTO && tag.label() == proto::AUDIT_READ) {
+            return false;
+        }
+        required_ops(utcb) & (OP_WRITE | OP_UNLINK | OP_ADMIN) != 0
+    }
+
+    pub fn record(
+        &mut self,
+        badge: usize,
+        proto: usize,
+        label: usize,
+        path: String,
+        result: Result<(), Error>,
+    ) {
+        if self.records.len() == AUDIT_MAX_RECORDS {
+            self.records.pop_front();
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let time = clock::now();
+        self.records.push_back(AuditRecord { seq, time, badge, proto, label, path, result });
+    }
+
+    /// Records a ring submission from `badge` on the file at `path`, when it
+    /// is one that changes the file or its locks.
+    pub fn record_sqe(&mut self, badge: usize, opcode: u8, path: &str, result: Result<(), Error>) {
+        let label = match opcode {
+            IOURING_OP_WRITE | IOURING_OP_WRITEV => fs::WRITE_SYNC,
+            IOURING_OP_LOCK => proto::LOCK,
+            IOURING_OP_UNLOCK => proto::UNLOCK,
+            _ => return,
+        };
+        if self.enabled {
+            self.record(badge, FS_PROTO, label, String::from(path), result);
+        }
+    }
+
+    /// Handles AUDIT_READ: fills the buffer with the records from sequence
+    /// MR0 on, one per line, as many as fit. Returns MR0: sequence to ask for
+    /// next, MR1: records already rotated out before the first one returned.
+    pub fn read(&self, utcb: &mut UTCB) -> Result<(), Error> {
+        if !self.enabled {
+            return Err(Error::NotSupported);
+        }
+        let from = utcb.get_mr(0) as u64;
+        let oldest = self.records.front().map_or(self.next_seq, |r| r.seq);
+        let missed = oldest.saturating_sub(from);
+
+        let buf = utcb.buffer_mut();
+        let mut written = 0;
+        let start = from.max(oldest);
+        let mut next = start;
+        let mut line = String::new();
+        for record in self.records.iter().filter(|r| r.seq >= start) {
+            line.clear();
+            let _ = writeln!(line, "{}", record);
+            if written + line.len() > buf.len() {
+                break;
+            }
+            buf[written..written + line.len()].copy_from_slice(line.as_bytes());
+            written += line.len();
+            next = record.seq + 1;
+        }
+        if written < buf.len() {
+            buf[written] = 0;
+        }
+        utcb.set_mr(0, next as usize);
+        utcb.set_mr(1, missed as usize);
+        Ok(())
+    }
+}
+
+/// Path the call in `utcb` is about, for its record; taken before dispatch
+/// overwrites the buffer. `handle_path` finds the path of a handle the
+/// caller names in MR0.
+pub fn call_path<'h>(utcb: &UTCB, handle_path: impl Fn(usize) -> Option<&'h str>) -> String {
+    let buf = utcb.buffer();
+    match utcb.get_msg_tag().label() {
+        fs::WRITE_SYNC | fs::TRUNCATE | proto::FALLOCATE | proto::WRITE_NEXT => {
+            String::from(handle_path(utcb.get_mr(0)).unwrap_or_default())
+        }
+        fs::RENAME | proto::LINK => match path::pair_from_buffer(buf) {
+            Ok((from, to)) => alloc::format!("{} -> {}", from, to),
+            Err(_) => String::new(),
+        },
+        proto::OPENAT => match (handle_path(utcb.get_mr(0)), path::from_buffer(buf)) {
+            (Some(base), Ok(rel)) => path::join(base, rel),
+            _ => String::new(),
+        },
+        _ => String::from(path::from_buffer(buf).unwrap_or_default()),
+    }
+}
+
+fn op_name(proto: usize, label: usize) -> &'static str {
+    if proto == PROCESS_PROTO {
+        return "EXIT";
+    }
+    match label {
+        fs::OPEN => "OPEN",
+        proto::OPEN_ASYNC => "OPEN_ASYNC",
+        fs::WRITE_SYNC => "WRITE",
+        proto::WRITE_NEXT => "WRITE_NEXT",
+        fs::MKDIR => "MKDIR",
+        fs::UNLINK => "UNLINK",
+        fs::RENAME => "RENAME",
+        proto::LINK => "LINK",
+        proto::SET_TIMES => "SET_TIMES",
+        fs::TRUNCATE => "TRUNCATE",
+        proto::LOCK => "LOCK",
+        proto::UNLOCK => "UNLOCK",
+        proto::FALLOCATE => "FALLOCATE",
+        proto::OPENAT => "OPENAT",
+        proto::RMTREE => "RMTREE",
+        proto::HISTORY_RESTORE => "HISTORY_RESTORE",
+        proto::REMOUNT_RO => "REMOUNT_RO",
+        proto::REMOUNT_RW => "REMOUNT_RW",
+        proto::CACHE_TUNE => "CACHE_TUNE",
+        proto::MOUNT_OPTIONS => "MOUNT_OPTIONS",
+        proto::MOUNT_AT => "MOUNT_AT",
+        proto::UNMOUNT => "UNMOUNT",
+        proto::ATTACH_DEVICE => "ATTACH_DEVICE",
+        proto::ATTACH_LAYER => "ATTACH_LAYER",
+        proto::ATTACH_TRANSPORT => "ATTACH_TRANSPORT",
+        proto::FORMAT => "FORMAT",
+        proto::RESIZE => "RESIZE",
+        proto::TRIM => "TRIM",
+        proto::SET_OP_MASK => "SET_OP_MASK",
+        proto::SET_CREDS => "SET_CREDS",
+        proto::SET_CLOCK => "SET_CLOCK",
+        proto::ALLOC_POLICY => "ALLOC_POLICY",
+        proto::CHECK => "CHECK",
+        proto::FREEZE => "FREEZE",
+        proto::THAW => "THAW",
+        proto::CBT_EPOCH => "CBT_EPOCH",
+        _ => "OTHER",
+    }
+}
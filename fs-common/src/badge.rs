@@ -0,0 +1,72 @@
+//! Badges of the endpoints a service mints for open handles. Clients reach the
+//! service through an endpoint carrying their client badge; OPEN mints one more
+//! per handle, badged with both the client badge and the handle id, so a call
+//! through it names the handle without trusting MR0. Handle tables keyed by
+//! `handle_badge` only ever yield a client's own handles.
+//!
+//! A service serving several volumes reaches each one after the first
+//! through an endpoint it mints with `volume_badge`; calls through it carry
+//! that badge, whoever makes them.
+
+use glenda::error::Error;
+
+// Set in badges minted for a handle
+pub const HANDLE_FLAG: usize = 1 << (usize::BITS - 1);
+// Handle id in the low bits, client badge above
+pub const HANDLE_SHIFT: u32 = 24;
+pub const HANDLE_ID_MASK: usize = (1 << HANDLE_SHIFT) - 1;
+// Set in badges minted for a volume, with its index above VOLUME_SHIFT
+pub const VOLUME_FLAG: usize = 1 << (usize::BITS - 2);
+pub const VOLUME_SHIFT: u32 = 48;
+// Volumes one service can tell apart, the first included
+pub const MAX_VOLUMES: usize = 16;
+// Largest client badge that still fits next to a handle id
+pub const MAX_CLIENT_BADGE: usize = (VOLUME_FLAG >> HANDLE_SHIFT) - 1;
+
+/// Badge of handle `id` opened by `client`.
+pub fn handle_badge(client: usize, id: usize) -> Result<usize, Error> {
+    if client > MAX_CLIENT_BADGE || id > HANDLE_ID_MASK {
+        return Err(Error::InvalidArgs);
+    }
+    Ok(HANDLE_FLAG | client << HANDLE_SHIFT | id)
+}
+
+/// The client behind `badge`, whichever of its endpoints the call came through.
+pub fn client(badge: usize) -> usize {
+    if badge & HANDLE_FLAG == 0 {
+        return badge;
+    }
+    (badge & !HANDLE_FLAG) >> HANDLE_SHIFT
+}
+
+/// Badge of the endpoint volume `index` is reached through.
+pub fn volume_badge(index: usize) -> Result<usize, Error> {
+    if index == 0 || index >= MAX_VOLUMES {
+        return Err(Error::InvalidArgs);
+    }
+    Ok(VOLUME_FLAG | index << VOLUME_SHIFT)
+}
+
+/// The volume a call through `badge` is about: 0, the service's first,
+/// unless the badge was minted for another.
+pub fn volume(badge: usize) -> usize {
+    if badge & (HANDLE_FLAG | VOLUME_FLAG) != VOLUME_FLAG {
+        return 0;
+    }
+    (badge >> VOLUME_SHIFT) & (MAX_VOLUMES - 1)
+}
+
+/// Whether a call through `badge` comes from whoever runs the service:
+/// badge 0, or the endpoint of a volume it attached, handed only to them.
+pub fn is_admin(badge: usize) -> bool {
+    badge == 0 || volume_badge(volume(badge)) == Ok(badge)
+}
+
+/// Key of the handle a call is about: the handle its endpoint was minted for,
+/// or otherwise handle `id` (MR0) among the caller's own.
+pub fn handle_key(badge: usize, id: usize) -> Result<usize, Error> {
+    if badge & HANDLE_FLAG != 0 {
+        return Ok(badge);
+    }
+    handle_badge(badge, id)
+}
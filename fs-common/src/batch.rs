@@ -0,0 +1,146 @@
+//! Batches of block reads issued together. Scans that know several blocks
+//! ahead (directory blocks, extent tree children, FAT sectors) queue them up
+//! and wait once; reads landing on adjacent device blocks are merged into a
+//! single device request, in the order the device's IO scheduler calls for.
+
+use crate::device::Scheduler;
+use alloc::vec::Vec;
+use glenda::error::Error;
+
+// Largest single device request a batch is merged into
+pub const MAX_BATCH_BYTES: usize = 64 * 1024;
+// Blocks a scan reads ahead of itself in one batch
+pub const SCAN_BATCH_BLOCKS: usize = 8;
+
+pub struct ReadBatch<'a> {
+    reads: Vec<(usize, &'a mut [u8])>,
+}
+
+impl<'a> ReadBatch<'a> {
+    pub fn new() -> Self {
+        Self { reads: Vec::new() }
+    }
+
+    /// Queues a read of `buf.len()` bytes at byte `offset`.
+    pub fn push(&mut self, offset: usize, buf: &'a mut [u8]) {
+        if !buf.is_empty() {
+            self.reads.push((offset, buf));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.reads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty()
+    }
+
+    /// The queued reads as (offset, buffer) pairs.
+    pub fn reads_mut(&mut self) -> core::slice::IterMut<'_, (usize, &'a mut [u8])> {
+        self.reads.iter_mut()
+    }
+
+    /// Performs the queued reads as device runs handed to `read` all at
+    /// once, each a first block and the buffer its blocks go in, so it can
+    /// submit them together and wait once. Reads whose `block_size` device
+    /// blocks touch or overlap share a run of up to `max_bytes`. The elevator
+    /// sorts the reads by offset first; FIFO keeps them in the order queued
+    /// and only merges a read into the one before it when it starts inside
+    /// or right after it.
+    pub fn execute<F>(
+        &mut self,
+        scheduler: Scheduler,
+        block_size: usize,
+        max_bytes: usize,
+        read: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [(usize, Vec<u8>)]) -> Result<(), Error>,
+    {
+        if scheduler == Scheduler::Elevator {
+            self.reads.sort_by_key(|(offset, _)| *offset);
+        }
+        let span =
+            |offset: usize, len: usize| (offset / block_size, (offset + len).div_ceil(block_size));
+
+        let mut runs = Vec::new();
+        // Reads each run serves, as a range of `reads`
+        let mut served = Vec::new();
+        let mut start = 0;
+        while start < self.reads.len() {
+            let (first, mut end) = span(self.reads[start].0, self.reads[start].1.len());
+            let mut stop = start + 1;
+            while stop < self.reads.len() {
+                let (next_first, next_end) = span(self.reads[stop].0, self.reads[stop].1.len());
+                let merged_end = core::cmp::max(end, next_end);
+                if next_first < first
+                    || next_first > end
+                    || (merged_end - first) * block_size > max_bytes
+                {
+                    break;
+                }
+                end = merged_end;
+                stop += 1;
+            }
+
+            runs.push((first, alloc::vec![0u8; (end - first) * block_size]));
+            served.push(start..stop);
+            start = stop;
+        }
+
+        read(&mut runs)?;
+        for ((first, run), range) in runs.iter().zip(served) {
+            let base = first * block_size;
+            for (offset, buf) in self.reads[range].iter_mut() {
+                let at = *offset - base;
+                buf.copy_from_slice(&run[at..at + buf.len()]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ReadBatch<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BS: usize = 512;
+
+    // Runs a batch of one-block reads at `blocks`, returning the first block
+    // of every run
+    fn calls(scheduler: Scheduler, blocks: &[usize]) -> Vec<usize> {
+        let mut bufs = alloc::vec![[0u8; BS]; blocks.len()];
+        let mut batch = ReadBatch::new();
+        for (block, buf) in blocks.iter().zip(bufs.iter_mut()) {
+            batch.push(block * BS, buf);
+        }
+        let mut calls = Vec::new();
+        batch
+            .execute(scheduler, BS, MAX_BATCH_BYTES, |runs| {
+                for (first, buf) in runs.iter_mut() {
+                    calls.push(*first);
+                    buf.fill(*first as u8);
+                }
+                Ok(())
+            })
+            .unwrap();
+        calls
+    }
+
+    #[test]
+    fn elevator_sorts_and_merges() {
+        assert_eq!(calls(Scheduler::Elevator, &[7, 2, 3, 6]), [2, 6]);
+    }
+
+    #[test]
+    fn fifo_keeps_submission_order() {
+        assert_eq!(calls(Scheduler::Fifo, &[7, 2, 3, 6]), [7, 2, 6]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Length-checked, little-endian encoding and decoding of on-disk structures.
+
+use glenda::error::Error;
+
+/// Primitive that can be decoded from little-endian bytes.
+pub trait Decode: Sized {
+    fn decode(r: &mut ByteReader) -> Self;
+}
+
+/// Primitive that can be encoded as little-endian bytes.
+pub trait Encode {
+    fn encode(&self, w: &mut ByteWriter);
+}
+
+/// On-disk structure decoded field by field, independent of host endianness
+/// and of the alignment of the source buffer.
+pub trait FromBytes: Sized {
+    const SIZE: usize;
+
+    fn decode_fields(r: &mut ByteReader) -> Self;
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < Self::SIZE {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(Self::decode_fields(&mut ByteReader::new(&buf[..Self::SIZE])))
+    }
+
+    /// Decodes the structure at `offset` inside `buf`.
+    fn from_bytes_at(buf: &[u8], offset: usize) -> Result<Self, Error> {
+        Self::from_bytes(buf.get(offset..).ok_or(Error::InvalidArgs)?)
+    }
+}
+
+/// On-disk structure encoded field by field in little-endian order.
+pub trait ToBytes {
+    const SIZE: usize;
+
+    fn encode_fields(&self, w: &mut ByteWriter);
+
+    fn to_bytes(&self, buf: &mut [u8]) -> Result<(), Error> {
+        if buf.len() < Self::SIZE {
+            return Err(Error::InvalidArgs);
+        }
+        self.encode_fields(&mut ByteWriter::new(&mut buf[..Self::SIZE]));
+        Ok(())
+    }
+
+    fn to_bytes_at(&self, buf: &mut [u8], offset: usize) -> Result<(), Error> {
+        self.to_bytes(buf.get_mut(offset..).ok_or(Error::InvalidArgs)?)
+    }
+}
+
+/// Cursor over a buffer already validated to hold the whole structure.
+pub struct ByteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn read<T: Decode>(&mut self) -> T {
+        T::decode(self)
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.buf[self.pos..self.pos + N]);
+        self.pos += N;
+        out
+    }
+}
+
+/// Cursor over an output buffer already validated to hold the whole structure.
+pub struct ByteWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> ByteWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn write<T: Encode>(&mut self, value: &T) {
+        value.encode(self)
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+// Raw little-endian integers inside larger buffers (FAT entries, block pointers)
+
+pub fn le_u16(buf: &[u8], offset: usize) -> Result<u16, Error> {
+    let bytes = buf.get(offset..offset + 2).ok_or(Error::InvalidArgs)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn le_u32(buf: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = buf.get(offset..offset + 4).ok_or(Error::InvalidArgs)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn le_u64(buf: &[u8], offset: usize) -> Result<u64, Error> {
+    let bytes = buf.get(offset..offset + 8).ok_or(Error::InvalidArgs)?;
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(raw))
+}
+
+pub fn put_le_u16(buf: &mut [u8], offset: usize, value: u16) -> Result<(), Error> {
+    let bytes = buf.get_mut(offset..offset + 2).ok_or(Error::InvalidArgs)?;
+    bytes.copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+pub fn put_le_u32(buf: &mut [u8], offset: usize, value: u32) -> Result<(), Error> {
+    let bytes = buf.get_mut(offset..offset + 4).ok_or(Error::InvalidArgs)?;
+    bytes.copy_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+impl Decode for u8 {
+    fn decode(r: &mut ByteReader) -> Self {
+        r.take::<1>()[0]
+    }
+}
+
+impl Decode for u16 {
+    fn decode(r: &mut ByteReader) -> Self {
+        u16::from_le_bytes(r.take())
+    }
+}
+
+impl Decode for u32 {
+    fn decode(r: &mut ByteReader) -> Self {
+        u32::from_le_bytes(r.take())
+    }
+}
+
+impl Decode for u64 {
+    fn decode(r: &mut ByteReader) -> Self {
+        u64::from_le_bytes(r.take())
+    }
+}
+
+impl<T: Decode + Copy + Default, const N: usize> Decode for [T; N] {
+    fn decode(r: &mut ByteReader) -> Self {
+        let mut out = [T::default(); N];
+        for item in out.iter_mut() {
+            *item = r.read();
+        }
+        out
+    }
+}
+
+macro_rules! impl_encode_int {
+    ($($ty:ty),*) => {
+        $(impl Encode for $ty {
+            fn encode(&self, w: &mut ByteWriter) {
+                w.put(&self.to_le_bytes());
+            }
+        })*
+    };
+}
+
+impl_encode_int!(u8, u16, u32, u64);
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, w: &mut ByteWriter) {
+        for item in self.iter() {
+            w.write(item);
+        }
+    }
+}
+
+/// Implements `FromBytes` and `ToBytes` for a `#[repr(C, packed)]` struct.
+/// Fields must be listed in declaration order.
+#[macro_export]
+macro_rules! impl_le_codec {
+    ($ty:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::bytes::FromBytes for $ty {
+            const SIZE: usize = core::mem::size_of::<$ty>();
+
+            fn decode_fields(r: &mut $crate::bytes::ByteReader) -> Self {
+                Self { $($field: r.read()),* }
+            }
+        }
+
+        impl $crate::bytes::ToBytes for $ty {
+            const SIZE: usize = core::mem::size_of::<$ty>();
+
+            fn encode_fields(&self, w: &mut $crate::bytes::ByteWriter) {
+                // Copy out of the packed struct before taking references
+                $(let $field = self.$field; w.write(&$field);)*
+            }
+        }
+    };
+}
@@ -0,0 +1,53 @@
+//! What every call to a volume service goes through around its dispatch.
+//! `admit` refuses what the wire protection, the export policy or the
+//! snapshots forbid before the call is dispatched; `answer` turns what the
+//! dispatch left in the UTCB into the reply.
+//!
+//! `client` is whatever the service keys wire sessions and negotiated
+//! versions by: its client badge, or the badge the call came through for a
+//! service whose endpoints each have their own session.
+
+use crate::errors;
+use crate::policy::ExportPolicy;
+use crate::snapshots;
+use crate::version::{self, Versions};
+use crate::wire::WireGuard;
+use glenda::error::Error;
+use glenda::ipc::{MsgTag, UTCB};
+
+/// Checks a call before dispatch, clearing the error note of the last one.
+/// Services without an export policy pass None.
+pub fn admit(
+    wire: &mut WireGuard,
+    policy: Option<&ExportPolicy>,
+    client: usize,
+    utcb: &UTCB,
+) -> Result<(), Error> {
+    errors::clear();
+    wire.verify(client, utcb)?;
+    if let Some(policy) = policy {
+        policy.check(client, utcb)?;
+        snapshots::check(utcb)?;
+    }
+    Ok(())
+}
+
+/// Finishes the reply to a call that came to `result`: the error in MR0 if
+/// it failed, its detail for clients that negotiated FEAT_ERROR_DETAIL, and
+/// the seal of a protected session.
+pub fn answer(
+    wire: &mut WireGuard,
+    versions: &Versions,
+    client: usize,
+    utcb: &mut UTCB,
+    result: Result<(), Error>,
+) {
+    if let Err(e) = result {
+        utcb.set_msg_tag(MsgTag::err());
+        utcb.set_mr(0, e as usize);
+    }
+    if versions.has(client, version::FEAT_ERROR_DETAIL) {
+        errors::annotate(utcb);
+    }
+    wire.seal(client, utcb);
+}
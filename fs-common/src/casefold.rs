@@ -0,0 +1,34 @@
+//! Case-insensitive name matching, for FAT names and for volumes mounted
+//! with MNT_CASEFOLD. Characters compare by their uppercase form where that
+//! is a single character, as the upcase tables of FAT and exFAT map them;
+//! the few whose uppercase is several characters, such as 'ß', only match
+//! themselves.
+
+use alloc::string::String;
+
+pub fn fold(c: char) -> char {
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(u), None) => u,
+        _ => c,
+    }
+}
+
+/// True if `a` and `b` are the same name but for case.
+pub fn eq(a: &str, b: &str) -> bool {
+    a.chars().map(fold).eq(b.chars().map(fold))
+}
+
+/// As `eq`, for a name stored as bytes; one that is not UTF-8 has to match
+/// exactly.
+pub fn eq_bytes(stored: &[u8], name: &str) -> bool {
+    match core::str::from_utf8(stored) {
+        Ok(stored) => eq(stored, name),
+        Err(_) => stored == name.as_bytes(),
+    }
+}
+
+/// `name` folded, so names `eq` matches give the same key.
+pub fn key(name: &str) -> String {
+    name.chars().map(fold).collect()
+}
@@ -0,0 +1,125 @@
+//! Changed-block tracking for incremental backup. Every write is noted
+//! against the current epoch at chunk granularity; a backup tool starts a new
+//! epoch when it takes a snapshot (CBT_EPOCH) and later asks which chunks
+//! changed since then (CBT_QUERY), copying only those.
+//!
+//! Tracking lives in memory and starts over at every mount, so a tool must
+//! fall back to a full backup when its epoch predates what the tracker
+//! still covers.
+
+use alloc::collections::BTreeMap;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+use spin::Mutex;
+
+// Tracking granularity: 64 KiB chunks of the volume
+pub const CBT_CHUNK_SHIFT: usize = 16;
+// Chunks remembered before tracking resets and older epochs become unknown
+pub const CBT_MAX_CHUNKS: usize = 16384;
+// CBT_QUERY records: first chunk and chunk count, u64 each
+pub const CBT_RECORD_SIZE: usize = 16;
+
+struct Tracker {
+    epoch: u64,
+    // Oldest epoch whose changes are all still recorded
+    valid_from: u64,
+    // Chunk index -> last epoch it was written in
+    chunks: BTreeMap<u64, u64>,
+}
+
+pub struct ChangeTracker {
+    inner: Mutex<Tracker>,
+}
+
+impl ChangeTracker {
+    /// Epoch 1 starts at mount; changes made before are unknown.
+    pub fn new() -> Self {
+        Self { inner: Mutex::new(Tracker { epoch: 1, valid_from: 1, chunks: BTreeMap::new() }) }
+    }
+
+    /// Notes a write of `len` bytes at byte `offset` of the volume.
+    pub fn record(&self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = (offset >> CBT_CHUNK_SHIFT) as u64;
+        let last = ((offset + len - 1) >> CBT_CHUNK_SHIFT) as u64;
+        let mut inner = self.inner.lock();
+        let epoch = inner.epoch;
+        for chunk in first..=last {
+            if inner.chunks.len() >= CBT_MAX_CHUNKS && !inner.chunks.contains_key(&chunk) {
+                // Out of room: forget everything, so only epochs started
+                // after this one can still be answered
+                inner.chunks.clear();
+                inner.valid_from = epoch + 1;
+            }
+            inner.chunks.insert(chunk, epoch);
+        }
+    }
+
+    /// Handles CBT_EPOCH: starts a new epoch and returns its number in MR0.
+    pub fn next_epoch(&self, utcb: &mut UTCB) {
+        let mut inner = self.inner.lock();
+        inner.epoch += 1;
+        utcb.set_mr(0, inner.epoch as usize);
+    }
+
+    /// Handles CBT_QUERY: fills the buffer with runs of chunks written in
+    /// epoch MR0 or later, starting at chunk MR1. Returns MR0: chunk shift,
+    /// MR1: records written, MR2: chunk to continue from, 0 when complete.
+    /// NotFound if changes from epoch MR0 are no longer all known.
+    pub fn query(&self, utcb: &mut UTCB) -> Result<(), Error> {
+        let since = utcb.get_mr(0) as u64;
+        let cursor = utcb.get_mr(1) as u64;
+        let inner = self.inner.lock();
+        if since < inner.valid_from || since > inner.epoch {
+            return Err(Error::NotFound);
+        }
+
+        let buf = utcb.buffer_mut();
+        let capacity = buf.len() / CBT_RECORD_SIZE;
+        let mut count = 0;
+        let mut run: Option<(u64, u64)> = None;
+        let mut next = 0;
+        for (&chunk, _) in inner.chunks.range(cursor..).filter(|(_, &e)| e >= since) {
+            match run {
+                Some((start, len)) if start + len == chunk => run = Some((start, len + 1)),
+                Some((start, len)) => {
+                    if count + 1 == capacity {
+                        next = chunk;
+                        run = Some((start, len));
+                        break;
+                    }
+                    put_record(buf, count, start, len);
+                    count += 1;
+                    run = Some((chunk, 1));
+                }
+                None => run = Some((chunk, 1)),
+            }
+        }
+        if let Some((start, len)) = run {
+            if count < capacity {
+                put_record(buf, count, start, len);
+                count += 1;
+            } else {
+                next = start;
+            }
+        }
+        utcb.set_mr(0, CBT_CHUNK_SHIFT);
+        utcb.set_mr(1, count);
+        utcb.set_mr(2, next as usize);
+        Ok(())
+    }
+}
+
+impl Default for ChangeTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn put_record(buf: &mut [u8], index: usize, start: u64, len: u64) {
+    let at = index * CBT_RECORD_SIZE;
+    buf[at..at + 8].copy_from_slice(&start.to_le_bytes());
+    buf[at + 8..at + 16].copy_from_slice(&len.to_le_bytes());
+}
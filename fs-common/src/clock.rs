@@ -0,0 +1,136 @@
+//! Wall-clock time for stamping files. Services have no clock of their own:
+//! the monitor sends SET_CLOCK on the unbadged endpoint at start and again
+//! whenever it wants the time refreshed, so stamps are only as fine as those
+//! updates. Until the first one, nothing is stamped rather than stamped with
+//! a made-up time.
+
+use crate::proto::{UTIME_NOW, UTIME_OMIT};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// Seconds since the epoch as last set; 0 until SET_CLOCK
+static SECONDS: AtomicU64 = AtomicU64::new(0);
+static NANOS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    /// Seconds since 1970-01-01 UTC; negative before
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    pub const fn new(sec: i64, nsec: u32) -> Self {
+        Self { sec, nsec }
+    }
+
+    /// Seconds for a Stat field, which cannot go before the epoch.
+    pub fn stat_secs(&self) -> usize {
+        self.sec.max(0) as usize
+    }
+
+    /// Milliseconds from `earlier` to this time; 0 if it is not earlier.
+    pub fn millis_since(&self, earlier: Timestamp) -> u64 {
+        let nanos = (self.sec - earlier.sec) as i128 * 1_000_000_000 + self.nsec as i128
+            - earlier.nsec as i128;
+        (nanos.max(0) / 1_000_000) as u64
+    }
+}
+
+/// The time as last set, or None while the monitor never set it.
+pub fn now() -> Option<Timestamp> {
+    match SECONDS.load(Ordering::Relaxed) {
+        0 => None,
+        sec => Some(Timestamp::new(sec as i64, NANOS.load(Ordering::Relaxed))),
+    }
+}
+
+/// Handles SET_CLOCK: MR0: seconds since the epoch, MR1: nanoseconds. Only
+/// the unbadged endpoint, which stays with the monitor, may set it.
+pub fn configure(caller: usize, utcb: &UTCB) -> Result<(), Error> {
+    if caller != 0 {
+        return Err(Error::PermissionDenied);
+    }
+    let (sec, nsec) = (utcb.get_mr(0) as u64, utcb.get_mr(1));
+    if sec == 0 || nsec >= 1_000_000_000 {
+        return Err(Error::InvalidArgs);
+    }
+    NANOS.store(nsec as u32, Ordering::Relaxed);
+    SECONDS.store(sec, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Times a SET_TIMES call asks for; None leaves that one alone.
+#[derive(Debug, Clone, Copy)]
+pub struct TimesRequest {
+    pub atime: Option<Timestamp>,
+    pub mtime: Option<Timestamp>,
+    /// A time was given outright rather than as UTIME_NOW, which only the
+    /// owner of a file may do
+    pub explicit: bool,
+}
+
+impl TimesRequest {
+    /// Reads MR0-MR3 of SET_TIMES. UTIME_NOW while the clock is unset fails
+    /// with NotInitialized.
+    pub fn from_utcb(utcb: &UTCB) -> Result<Self, Error> {
+        let mut explicit = false;
+        let mut requested = |sec: usize, nsec: usize| match nsec {
+            UTIME_OMIT => Ok(None),
+            UTIME_NOW => now().map(Some).ok_or(Error::NotInitialized),
+            nsec if nsec < 1_000_000_000 => {
+                explicit = true;
+                Ok(Some(Timestamp::new(sec as isize as i64, nsec as u32)))
+            }
+            _ => Err(Error::InvalidArgs),
+        };
+        let atime = requested(utcb.get_mr(0), utcb.get_mr(1))?;
+        let mtime = requested(utcb.get_mr(2), utcb.get_mr(3))?;
+        Ok(Self { atime, mtime, explicit })
+    }
+
+    /// MR0-MR3 of a SET_TIMES asking for the same, for services that pass
+    /// the call on. Times asked for as UTIME_NOW go on as UTIME_NOW, so the
+    /// far side stamps them, unless an explicit one came with them.
+    pub fn to_mrs(&self) -> [usize; 4] {
+        let encode = |time: Option<Timestamp>| match time {
+            None => [0, UTIME_OMIT],
+            Some(_) if !self.explicit => [0, UTIME_NOW],
+            Some(time) => [time.sec as usize, time.nsec as usize],
+        };
+        let [asec, ansec] = encode(self.atime);
+        let [msec, mnsec] = encode(self.mtime);
+        [asec, ansec, msec, mnsec]
+    }
+}
+
+/// Seconds since the epoch of a civil date and time in UTC; `month` and
+/// `day` count from 1.
+pub fn unix_seconds(year: i64, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> i64 {
+    // Days from civil, counting years from March so the leap day comes last
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    days * 86400 + hour as i64 * 3600 + min as i64 * 60 + sec as i64
+}
+
+/// The civil date and time in UTC of `secs` since the epoch, the inverse of
+/// unix_seconds: (year, month, day, hour, minute, second).
+pub fn civil(secs: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, (rem / 3600) as u32, (rem % 3600 / 60) as u32, (rem % 60) as u32)
+}
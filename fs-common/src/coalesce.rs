@@ -0,0 +1,76 @@
+//! Per-handle buffer that collects small sequential writes, so append-style
+//! workloads reach the device as whole blocks instead of one read-modify-write
+//! per call.
+
+use alloc::vec::Vec;
+
+// Blocks a handle buffers before writing out
+pub const COALESCE_BLOCKS: usize = 4;
+
+pub struct WriteCombiner {
+    offset: usize,
+    data: Vec<u8>,
+    block_size: usize,
+}
+
+impl WriteCombiner {
+    pub fn new(block_size: usize) -> Self {
+        Self { offset: 0, data: Vec::new(), block_size }
+    }
+
+    pub fn capacity(&self) -> usize {
+        COALESCE_BLOCKS * self.block_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// End of the buffered range, if anything is buffered.
+    pub fn end(&self) -> Option<usize> {
+        (!self.data.is_empty()).then(|| self.offset + self.data.len())
+    }
+
+    /// Buffers `buf` at `offset` if it is smaller than a block and continues
+    /// the buffered range (or the buffer is empty) without overflowing it.
+    pub fn absorb(&mut self, offset: usize, buf: &[u8]) -> bool {
+        if buf.len() >= self.block_size {
+            return false;
+        }
+        match self.end() {
+            None => self.offset = offset,
+            Some(end) if end == offset && self.data.len() + buf.len() <= self.capacity() => {}
+            Some(_) => return false,
+        }
+        self.data.extend_from_slice(buf);
+        true
+    }
+
+    /// Whether the whole blocks should go out now; what stays behind plus
+    /// another small write always fits again.
+    pub fn is_full(&self) -> bool {
+        self.data.len() >= self.capacity() - self.block_size
+    }
+
+    /// Hands out the buffered data up to the last block boundary, keeping the
+    /// partial block at the end for the writes still to come.
+    pub fn take_blocks(&mut self) -> Option<(usize, Vec<u8>)> {
+        let end = self.end()?;
+        let cut = end - end % self.block_size;
+        if cut <= self.offset {
+            return None;
+        }
+        let tail = self.data.split_off(cut - self.offset);
+        let head = core::mem::replace(&mut self.data, tail);
+        let offset = core::mem::replace(&mut self.offset, cut);
+        Some((offset, head))
+    }
+
+    /// Hands out everything buffered.
+    pub fn take(&mut self) -> Option<(usize, Vec<u8>)> {
+        if self.data.is_empty() {
+            return None;
+        }
+        Some((self.offset, core::mem::take(&mut self.data)))
+    }
+}
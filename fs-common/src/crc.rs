@@ -0,0 +1,37 @@
+//! Checksums used by on-disk metadata, computed bitwise to stay table-free.
+
+/// CRC-16/ARC update (reflected polynomial 0xA001), as used by the ext4
+/// `gdt_csum` group descriptor checksum.
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Raw CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320) update. Start
+/// from `!0` and invert the result for the usual zlib/PNG value.
+pub fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// Raw CRC-32C (Castagnoli) update without the final inversion, matching
+/// how ext4 `metadata_csum` chains checksums over several buffers.
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    crc
+}
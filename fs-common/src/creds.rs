@@ -0,0 +1,91 @@
+//! Which user and group each badge acts as, for filesystems that keep POSIX
+//! ownership and mode bits. The monitor maps badges with SET_CREDS when it
+//! hands them out; like export masks, badges it never mapped keep full access,
+//! as before permissions were enforced. Uid 0 is root and bypasses the mode
+//! bits.
+
+use alloc::collections::BTreeMap;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// Access wanted, in the order of the rwx bits of a mode
+pub const MAY_READ: u32 = 4;
+pub const MAY_WRITE: u32 = 2;
+pub const MAY_EXEC: u32 = 1;
+
+const S_ISVTX: u32 = 0o1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Cred {
+    pub const ROOT: Cred = Cred { uid: 0, gid: 0 };
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether the owner (`uid`, `gid`) and `mode` of a file grant `want`
+    /// (MAY_* bits).
+    pub fn permits(&self, mode: u32, uid: u32, gid: u32, want: u32) -> bool {
+        if self.is_root() {
+            return true;
+        }
+        let granted = if self.uid == uid {
+            mode >> 6
+        } else if self.gid == gid {
+            mode >> 3
+        } else {
+            mode
+        };
+        granted & want == want
+    }
+
+    /// Whether an entry owned by `uid` may be removed from or renamed out of
+    /// a directory with `dir_mode` owned by `dir_uid`: in a sticky directory
+    /// only the owners of either may. Write access to the directory is
+    /// checked separately.
+    pub fn may_remove(&self, dir_mode: u32, dir_uid: u32, uid: u32) -> bool {
+        dir_mode & S_ISVTX == 0 || self.is_root() || self.uid == uid || self.uid == dir_uid
+    }
+}
+
+pub struct CredentialMap {
+    creds: BTreeMap<usize, Cred>,
+}
+
+impl CredentialMap {
+    pub fn new() -> Self {
+        Self { creds: BTreeMap::new() }
+    }
+
+    /// Handles SET_CREDS. Only the unbadged endpoint, which stays with the
+    /// monitor, may map badges.
+    pub fn configure(&mut self, caller: usize, utcb: &UTCB) -> Result<(), Error> {
+        if caller != 0 {
+            return Err(Error::PermissionDenied);
+        }
+        let badge = utcb.get_mr(0);
+        let uid = u32::try_from(utcb.get_mr(1)).map_err(|_| Error::InvalidArgs)?;
+        let gid = u32::try_from(utcb.get_mr(2)).map_err(|_| Error::InvalidArgs)?;
+        if badge == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        self.creds.insert(badge, Cred { uid, gid });
+        Ok(())
+    }
+
+    /// What `badge` acts as; root unless the monitor mapped it.
+    pub fn get(&self, badge: usize) -> Cred {
+        self.creds.get(&badge).copied().unwrap_or(Cred::ROOT)
+    }
+}
+
+impl Default for CredentialMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
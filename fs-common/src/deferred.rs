@@ -0,0 +1,119 @@
+//! Calls answered after the service has moved on. Resolving a path costs a
+//! directory lookup per component, which on a slow backend (a network
+//! filesystem, a cold device) adds up to long enough to stall every other
+//! client. OPEN_ASYNC instead parks the caller's reply cap, walks the path one
+//! component per turn of the server loop and answers through the saved cap
+//! once the handle exists. To the client it is an ordinary blocking call.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::cap::{CapPtr, Reply, CSPACE_CAP};
+use glenda::error::Error;
+use glenda::ipc::Badge;
+
+pub const MAX_PARKED: usize = 16;
+
+/// How far a walk down a path has got; `N` is the filesystem's node, the
+/// directory the next component is looked up in.
+pub struct PathWalk<N> {
+    path: String,
+    // Byte offset of the next component
+    next: usize,
+    node: N,
+}
+
+impl<N> PathWalk<N> {
+    pub fn new(path: &str, root: N) -> Self {
+        Self { path: String::from(path), next: 0, node: root }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The component to look up next, or None once `node` is the target.
+    pub fn next_component(&self) -> Option<&str> {
+        self.path[self.next..].split('/').find(|part| !part.is_empty() && *part != ".")
+    }
+
+    /// Records that the next component resolved to `node`.
+    pub fn advance(&mut self, node: N) {
+        if let Some(part) = self.next_component() {
+            let start = part.as_ptr() as usize - self.path.as_ptr() as usize;
+            self.next = start + part.len();
+        }
+        self.node = node;
+    }
+
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    pub fn into_node(self) -> N {
+        self.node
+    }
+}
+
+/// A parked call whose work has finished, ready to be answered.
+pub struct Settled<T, R> {
+    pub reply: Reply,
+    pub badge: Badge,
+    pub work: T,
+    pub result: R,
+}
+
+struct Parked<T> {
+    badge: Badge,
+    work: T,
+}
+
+pub struct ParkedCalls<T> {
+    calls: Vec<Option<Parked<T>>>,
+    slot_base: usize,
+}
+
+impl<T> ParkedCalls<T> {
+    /// The reply cap of parked call `i` lives in cap slot `slot_base + i`.
+    pub fn new(slot_base: CapPtr) -> Self {
+        Self { calls: (0..MAX_PARKED).map(|_| None).collect(), slot_base: slot_base.bits() }
+    }
+
+    /// Moves the reply cap of the call being served out of `reply`, so the
+    /// loop can take the next call, and keeps `work` for `advance`.
+    /// WouldBlock while every slot is taken; the caller may then retry or
+    /// fall back to the blocking call.
+    pub fn park(&mut self, reply: Reply, badge: Badge, work: T) -> Result<(), Error> {
+        let i = self.calls.iter().position(|c| c.is_none()).ok_or(Error::WouldBlock)?;
+        CSPACE_CAP.move_cap(reply.cap(), self.slot(i))?;
+        self.calls[i] = Some(Parked { badge, work });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.iter().all(|c| c.is_none())
+    }
+
+    /// Gives every parked call one turn of `f`. The calls it settles by
+    /// returning a result are taken out, with the reply cap to answer on.
+    pub fn advance<R>(
+        &mut self,
+        mut f: impl FnMut(Badge, &mut T) -> Option<R>,
+    ) -> Vec<Settled<T, R>> {
+        let mut settled = Vec::new();
+        for i in 0..self.calls.len() {
+            let result = match self.calls[i].as_mut() {
+                Some(call) => f(call.badge, &mut call.work),
+                None => continue,
+            };
+            if let (Some(result), Some(call)) = (result, self.calls[i].take()) {
+                let reply = Reply::from(self.slot(i));
+                settled.push(Settled { reply, badge: call.badge, work: call.work, result });
+            }
+        }
+        settled
+    }
+
+    fn slot(&self, i: usize) -> CapPtr {
+        CapPtr::from(self.slot_base + i)
+    }
+}
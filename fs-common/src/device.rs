@@ -0,0 +1,311 @@
+//! Identity and characteristics of the block device under a volume, as
+//! reported by the volume driver, and the IO defaults picked from them.
+
+use alloc::string::String;
+use glenda::cap::{CapPtr, Endpoint};
+use glenda::error::Error;
+use glenda::ipc::{MsgFlags, MsgTag, UTCB};
+use glenda::protocol::VOLUME_PROTO;
+
+// Volume driver call returning MR0: DEV_* flags, MR1: cache type,
+// buffer: model and serial, each NUL-terminated.
+pub const VOLUME_IDENTIFY: usize = 0x20;
+// Volume driver call returning MR0: capacity in bytes, MR1: logical and
+// MR2: physical block size in bytes.
+pub const VOLUME_GEOMETRY: usize = 0x21;
+// Volume driver call discarding MR1 logical blocks from block MR0.
+pub const VOLUME_DISCARD: usize = 0x22;
+// Volume driver call returning once everything written before it is on the medium,
+// out of any volatile write cache.
+pub const VOLUME_FLUSH: usize = 0x23;
+// Volume driver call taking a ring in the frame sent along: MR0: client address
+// it is mapped at, MR1: its size in bytes, MR2: bytes of queues at its start.
+// SQE `off` is a device byte offset and `addr` a client address in the frame.
+pub const VOLUME_RING_SETUP: usize = 0x24;
+// Volume driver call returning once every SQE queued on the ring from
+// VOLUME_RING_SETUP has its completion posted.
+pub const VOLUME_RING_ENTER: usize = 0x25;
+
+pub const DEV_ROTATIONAL: usize = 1 << 0;
+pub const DEV_DISCARD: usize = 1 << 1;
+
+// Block sizes a driver may report; anything else is taken as no answer
+const MIN_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+// Readahead defaults in filesystem blocks: seeks dominate on spinning media
+const READAHEAD_ROTATIONAL: usize = 32;
+const READAHEAD_FLASH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum CacheType {
+    Unknown = 0,
+    None = 1,
+    WriteThrough = 2,
+    WriteBack = 3,
+}
+
+impl CacheType {
+    pub fn from_raw(raw: usize) -> Self {
+        match raw {
+            1 => CacheType::None,
+            2 => CacheType::WriteThrough,
+            3 => CacheType::WriteBack,
+            _ => CacheType::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Scheduler {
+    /// Requests go out in submission order.
+    Fifo = 0,
+    /// Queued requests are sorted by sector to cut down on seeks.
+    Elevator = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoTuning {
+    pub scheduler: Scheduler,
+    pub readahead_blocks: usize,
+    /// Space is discarded on the device as the filesystem frees it.
+    pub discard: bool,
+    /// Barriers flush the device's volatile write cache.
+    pub flush_cache: bool,
+}
+
+impl Default for IoTuning {
+    fn default() -> Self {
+        DeviceInfo::unknown().tuning()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub model: String,
+    pub serial: String,
+    pub rotational: bool,
+    pub discard: bool,
+    pub cache: CacheType,
+}
+
+impl DeviceInfo {
+    /// What is assumed when the driver cannot tell: a plain spinning disk.
+    pub fn unknown() -> Self {
+        Self {
+            model: String::new(),
+            serial: String::new(),
+            rotational: true,
+            discard: false,
+            cache: CacheType::Unknown,
+        }
+    }
+
+    /// Asks the volume driver behind `device` who it is. Drivers that do not
+    /// answer VOLUME_IDENTIFY get `unknown()`.
+    pub fn query(device: Endpoint, utcb: &mut UTCB) -> Self {
+        utcb.clear();
+        utcb.set_msg_tag(MsgTag::new(VOLUME_PROTO, VOLUME_IDENTIFY, MsgFlags::NONE));
+        if device.call(utcb).is_err() || utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+            return Self::unknown();
+        }
+        let flags = utcb.get_mr(0);
+        let buf = utcb.buffer();
+        let model_len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        let rest = buf.get(model_len + 1..).unwrap_or(&[]);
+        let serial_len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        Self {
+            model: String::from_utf8_lossy(&buf[..model_len]).into_owned(),
+            serial: String::from_utf8_lossy(&rest[..serial_len]).into_owned(),
+            rotational: flags & DEV_ROTATIONAL != 0,
+            discard: flags & DEV_DISCARD != 0,
+            cache: CacheType::from_raw(utcb.get_mr(1)),
+        }
+    }
+
+    pub fn flags(&self) -> usize {
+        let mut flags = 0;
+        if self.rotational {
+            flags |= DEV_ROTATIONAL;
+        }
+        if self.discard {
+            flags |= DEV_DISCARD;
+        }
+        flags
+    }
+
+    pub fn tuning(&self) -> IoTuning {
+        if self.rotational {
+            IoTuning {
+                scheduler: Scheduler::Elevator,
+                readahead_blocks: READAHEAD_ROTATIONAL,
+                discard: self.discard,
+                flush_cache: self.cache == CacheType::WriteBack,
+            }
+        } else {
+            IoTuning {
+                scheduler: Scheduler::Fifo,
+                readahead_blocks: READAHEAD_FLASH,
+                discard: self.discard,
+                flush_cache: self.cache == CacheType::WriteBack,
+            }
+        }
+    }
+
+    /// Fills in a VOLUME_INFO reply for this device running with `tuning`.
+    pub fn encode(&self, utcb: &mut UTCB, tuning: IoTuning) -> Result<(), Error> {
+        let model = self.model.as_bytes();
+        let serial = self.serial.as_bytes();
+        let buf = utcb.buffer_mut();
+        let len = model.len() + serial.len() + 2;
+        if len > buf.len() {
+            return Err(Error::MessageTooLong);
+        }
+        buf[..model.len()].copy_from_slice(model);
+        buf[model.len()] = 0;
+        buf[model.len() + 1..len - 1].copy_from_slice(serial);
+        buf[len - 1] = 0;
+        utcb.set_buffer_len(len);
+        utcb.set_mr(0, self.flags());
+        utcb.set_mr(1, self.cache as usize);
+        utcb.set_mr(2, tuning.scheduler as usize);
+        utcb.set_mr(3, tuning.readahead_blocks);
+        Ok(())
+    }
+}
+
+/// Size and addressing unit of the block device under a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Bytes on the device, when the driver knows.
+    pub capacity: Option<usize>,
+    /// Unit transfers are addressed and sized in.
+    pub logical_block: usize,
+    /// Unit the medium writes in; smaller writes cost it a read-modify-write.
+    pub physical_block: usize,
+}
+
+impl Geometry {
+    /// Asks the volume driver behind `device` for its geometry. None when it
+    /// does not answer VOLUME_GEOMETRY or reports a block size that is not a
+    /// power of two between 512 bytes and 64 KiB.
+    pub fn query(device: Endpoint, utcb: &mut UTCB) -> Option<Self> {
+        utcb.clear();
+        utcb.set_msg_tag(MsgTag::new(VOLUME_PROTO, VOLUME_GEOMETRY, MsgFlags::NONE));
+        if device.call(utcb).is_err() || utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+            return None;
+        }
+        Self::from_report(utcb.get_mr(0), utcb.get_mr(1), utcb.get_mr(2))
+    }
+
+    /// Geometry from what a driver reports: `bytes` on the device, rounded
+    /// down to whole logical blocks, zero when unknown, and its logical and
+    /// physical block sizes.
+    pub fn from_report(bytes: usize, logical: usize, physical: usize) -> Option<Self> {
+        let valid = |size: usize| {
+            size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size)
+        };
+        if !valid(logical) {
+            return None;
+        }
+        // A physical block smaller than the logical one means nothing
+        let physical_block = if valid(physical) && physical > logical { physical } else { logical };
+        // Only whole logical blocks can be addressed
+        let capacity = Some(bytes / logical * logical).filter(|&b| b != 0);
+        Some(Self { capacity, logical_block: logical, physical_block })
+    }
+}
+
+/// Asks the volume driver behind `device` to discard `count` logical blocks
+/// from `block`.
+pub fn discard(device: Endpoint, utcb: &mut UTCB, block: usize, count: usize) -> Result<(), Error> {
+    utcb.clear();
+    utcb.set_msg_tag(MsgTag::new(VOLUME_PROTO, VOLUME_DISCARD, MsgFlags::NONE));
+    utcb.set_mr(0, block);
+    utcb.set_mr(1, count);
+    device.call(utcb)?;
+    if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+        return Err(Error::IoError);
+    }
+    Ok(())
+}
+
+/// Hands the driver behind `device` the ring in `frame`, mapped at `vaddr`
+/// for `size` bytes with its queues in the first `queue_size`.
+pub fn ring_setup(
+    device: Endpoint,
+    utcb: &mut UTCB,
+    frame: CapPtr,
+    vaddr: usize,
+    size: usize,
+    queue_size: usize,
+) -> Result<(), Error> {
+    utcb.clear();
+    utcb.set_msg_tag(MsgTag::new(VOLUME_PROTO, VOLUME_RING_SETUP, MsgFlags::HAS_CAP));
+    utcb.set_cap_transfer(frame);
+    utcb.set_mr(0, vaddr);
+    utcb.set_mr(1, size);
+    utcb.set_mr(2, queue_size);
+    device.call(utcb)?;
+    if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+        return Err(Error::NotSupported);
+    }
+    Ok(())
+}
+
+/// Has the driver behind `device` work through the SQEs on its ring.
+pub fn ring_enter(device: Endpoint, utcb: &mut UTCB) -> Result<(), Error> {
+    utcb.clear();
+    utcb.set_msg_tag(MsgTag::new(VOLUME_PROTO, VOLUME_RING_ENTER, MsgFlags::NONE));
+    device.call(utcb)?;
+    if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+        return Err(Error::IoError);
+    }
+    Ok(())
+}
+
+/// Asks the volume driver behind `device` to write out its volatile cache.
+pub fn flush_cache(device: Endpoint, utcb: &mut UTCB) -> Result<(), Error> {
+    utcb.clear();
+    utcb.set_msg_tag(MsgTag::new(VOLUME_PROTO, VOLUME_FLUSH, MsgFlags::NONE));
+    device.call(utcb)?;
+    if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+        return Err(Error::IoError);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_rounds_down_to_whole_logical_blocks() {
+        let geometry = Geometry::from_report(10 * 4096 + 4095, 4096, 4096).unwrap();
+        assert_eq!(geometry.capacity, Some(10 * 4096));
+        let geometry = Geometry::from_report(1000, 512, 512).unwrap();
+        assert_eq!(geometry.capacity, Some(512));
+    }
+
+    #[test]
+    fn less_than_a_block_is_an_unknown_size() {
+        assert_eq!(Geometry::from_report(4095, 4096, 4096).unwrap().capacity, None);
+        assert_eq!(Geometry::from_report(0, 512, 512).unwrap().capacity, None);
+    }
+
+    #[test]
+    fn logical_block_must_be_a_sane_power_of_two() {
+        assert!(Geometry::from_report(1 << 20, 256, 256).is_none());
+        assert!(Geometry::from_report(1 << 20, 3000, 4096).is_none());
+        assert!(Geometry::from_report(1 << 20, 128 * 1024, 128 * 1024).is_none());
+    }
+
+    #[test]
+    fn physical_block_is_at_least_the_logical_one() {
+        assert_eq!(Geometry::from_report(1 << 20, 512, 4096).unwrap().physical_block, 4096);
+        assert_eq!(Geometry::from_report(1 << 20, 4096, 512).unwrap().physical_block, 4096);
+        assert_eq!(Geometry::from_report(1 << 20, 512, 3000).unwrap().physical_block, 512);
+    }
+}
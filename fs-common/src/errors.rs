@@ -0,0 +1,102 @@
+//! Filesystem failures finer than glenda's `Error`. A failed call is answered
+//! with an `Error` code in MR0, which lumps a path component that is a file
+//! in with any other InvalidArgs and a full volume in with a failed
+//! allocation. Where the difference matters the services fail with an
+//! `FsError`: it turns into the nearest `Error`, so nothing changes for
+//! clients that know no better, and is noted on the side. `annotate` puts
+//! the note in MR1 of the error reply for clients that negotiated
+//! FEAT_ERROR_DETAIL.
+//!
+//! Services serve one call at a time, so a single note is enough; it is
+//! cleared before each call and only reported with the error it became.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use glenda::error::Error;
+use glenda::ipc::{MsgFlags, UTCB};
+
+// MR of an error reply holding the FsError code, 0 if there is none
+pub const ERROR_DETAIL_MR: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum FsError {
+    /// A path component other than the last is not a directory (ENOTDIR)
+    NotDir = 1,
+    /// A directory was opened for writing or used as a file (EISDIR)
+    IsDir = 2,
+    /// No free blocks, inodes or directory slots left (ENOSPC)
+    NoSpace = 3,
+    /// The volume is mounted or marked read-only (EROFS)
+    ReadOnly = 4,
+    /// On-disk metadata failed a consistency check (EUCLEAN)
+    Corrupt = 5,
+}
+
+impl FsError {
+    /// The code clients without FEAT_ERROR_DETAIL see.
+    pub const fn base(self) -> Error {
+        match self {
+            FsError::NotDir | FsError::IsDir => Error::InvalidArgs,
+            FsError::NoSpace => Error::OutOfMemory,
+            FsError::ReadOnly => Error::PermissionDenied,
+            FsError::Corrupt => Error::IoError,
+        }
+    }
+
+    pub fn from_code(code: usize) -> Option<Self> {
+        [FsError::NotDir, FsError::IsDir, FsError::NoSpace, FsError::ReadOnly, FsError::Corrupt]
+            .into_iter()
+            .find(|e| *e as usize == code)
+    }
+}
+
+static NOTED: AtomicUsize = AtomicUsize::new(0);
+
+impl From<FsError> for Error {
+    fn from(e: FsError) -> Self {
+        NOTED.store(e as usize, Ordering::Relaxed);
+        e.base()
+    }
+}
+
+/// Maps an error code from a reply, or a failed completion, back to the
+/// error the service returned.
+pub fn decode(code: usize) -> Error {
+    const KNOWN: [Error; 15] = [
+        Error::InvalidArgs,
+        Error::NotFound,
+        Error::DeviceError,
+        Error::IoError,
+        Error::MessageTooLong,
+        Error::InternalError,
+        Error::NotImplemented,
+        Error::NotSupported,
+        Error::Unknown,
+        Error::NotInitialized,
+        Error::PermissionDenied,
+        Error::OutOfMemory,
+        Error::AlreadyExists,
+        Error::Timeout,
+        Error::WouldBlock,
+    ];
+    KNOWN.into_iter().find(|e| *e as usize == code).unwrap_or(Error::Unknown)
+}
+
+/// Forgets the note of an earlier call; done before each dispatch.
+pub fn clear() {
+    NOTED.store(0, Ordering::Relaxed);
+}
+
+/// Fills in ERROR_DETAIL_MR of an error reply. The note counts only if it
+/// maps to the error being returned; a caller may have turned it into
+/// something else on the way.
+pub fn annotate(utcb: &mut UTCB) {
+    if !utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+        return;
+    }
+    let detail = match FsError::from_code(NOTED.load(Ordering::Relaxed)) {
+        Some(e) if e.base() as usize == utcb.get_mr(0) => e as usize,
+        _ => 0,
+    };
+    utcb.set_mr(ERROR_DETAIL_MR, detail);
+}
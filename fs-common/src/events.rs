@@ -0,0 +1,145 @@
+//! Volume events for system observers: mounts, unmounts, read-only
+//! remounts, the mount scrub and corruption found by the service, which otherwise only show
+//! up on the console. An observer (a UI, a logger, the automounter)
+//! subscribes with EVENT_SUBSCRIBE and an endpoint of its own; the service
+//! notifies it with the EV_* bits of every event it asked for, and the
+//! observer then drains the details with EVENT_READ. Like the audit log, the
+//! events go into a bounded log and a slow reader is told how many it
+//! missed.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// Event kinds, also the bits subscribers are notified with. Detail of each:
+// Registered in the VFS namespace, at start or after MOUNT_AT; mount::MNT_* bits in effect
+pub const EV_MOUNT: usize = 1 << 0;
+// Left the namespace on the way out; 0
+pub const EV_UNMOUNT: usize = 1 << 1;
+// REMOUNT_RO; 0
+pub const EV_REMOUNT_RO: usize = 1 << 2;
+// REMOUNT_RW; 0
+pub const EV_REMOUNT_RW: usize = 1 << 3;
+// The mount scrub found damage; how many issues
+pub const EV_CORRUPTION: usize = 1 << 4;
+// The mount scrub finished, clean or not; how many checks it made
+pub const EV_SCRUB: usize = 1 << 5;
+pub const EV_ALL: usize =
+    EV_MOUNT | EV_UNMOUNT | EV_REMOUNT_RO | EV_REMOUNT_RW | EV_CORRUPTION | EV_SCRUB;
+
+// EVENT_READ record: sequence, kind, detail, each u64 LE
+pub const EVENT_RECORD_SIZE: usize = 24;
+// Events kept before the oldest rotate out
+pub const EVENT_MAX_RECORDS: usize = 64;
+pub const MAX_SUBSCRIBERS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub seq: u64,
+    pub kind: usize,
+    pub detail: usize,
+}
+
+struct Subscriber {
+    badge: usize,
+    mask: usize,
+    notify: Endpoint,
+}
+
+pub struct EventBus {
+    events: VecDeque<Event>,
+    next_seq: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self { events: VecDeque::new(), next_seq: 0, subscribers: Vec::new() }
+    }
+
+    /// Logs an event and notifies the subscribers that want its kind.
+    pub fn publish(&mut self, kind: usize, detail: usize) {
+        if self.events.len() == EVENT_MAX_RECORDS {
+            self.events.pop_front();
+        }
+        self.events.push_back(Event { seq: self.next_seq, kind, detail });
+        self.next_seq += 1;
+        for sub in self.subscribers.iter().filter(|s| s.mask & kind != 0) {
+            // A subscriber that went away must not hold up the service
+            let _ = sub.notify.notify(kind);
+        }
+    }
+
+    /// Subscribes `badge` to the EV_* bits in `mask`, notified on `notify`.
+    /// Returns the endpoint of a subscription it replaces, for the caller to
+    /// free. OutOfMemory once MAX_SUBSCRIBERS badges subscribed.
+    pub fn subscribe(
+        &mut self,
+        badge: usize,
+        mask: usize,
+        notify: Endpoint,
+    ) -> Result<Option<Endpoint>, Error> {
+        if mask & !EV_ALL != 0 || mask == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        if let Some(sub) = self.subscribers.iter_mut().find(|s| s.badge == badge) {
+            sub.mask = mask;
+            return Ok(Some(core::mem::replace(&mut sub.notify, notify)));
+        }
+        if self.subscribers.len() == MAX_SUBSCRIBERS {
+            return Err(Error::OutOfMemory);
+        }
+        self.subscribers.push(Subscriber { badge, mask, notify });
+        Ok(None)
+    }
+
+    /// Ends the subscription of `badge`, returning its endpoint to free.
+    pub fn unsubscribe(&mut self, badge: usize) -> Option<Endpoint> {
+        let i = self.subscribers.iter().position(|s| s.badge == badge)?;
+        Some(self.subscribers.remove(i).notify)
+    }
+
+    /// Sequence number the next event will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Handles EVENT_READ: fills the buffer with the events from sequence
+    /// MR0 on, as many as fit. Returns MR0: sequence to ask for next, MR1:
+    /// events already rotated out before the first one returned, MR2: record
+    /// count.
+    pub fn read(&self, utcb: &mut UTCB) -> Result<(), Error> {
+        let from = utcb.get_mr(0) as u64;
+        let oldest = self.events.front().map_or(self.next_seq, |e| e.seq);
+        let missed = oldest.saturating_sub(from);
+
+        let buf = utcb.buffer_mut();
+        let start = from.max(oldest);
+        let mut next = start;
+        let mut count = 0;
+        for event in self.events.iter().filter(|e| e.seq >= start) {
+            let at = count * EVENT_RECORD_SIZE;
+            if at + EVENT_RECORD_SIZE > buf.len() {
+                break;
+            }
+            buf[at..at + 8].copy_from_slice(&event.seq.to_le_bytes());
+            buf[at + 8..at + 16].copy_from_slice(&(event.kind as u64).to_le_bytes());
+            buf[at + 16..at + 24].copy_from_slice(&(event.detail as u64).to_le_bytes());
+            count += 1;
+            next = event.seq + 1;
+        }
+        utcb.set_buffer_len(count * EVENT_RECORD_SIZE);
+        utcb.set_mr(0, next as usize);
+        utcb.set_mr(1, missed as usize);
+        utcb.set_mr(2, count);
+        Ok(())
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,96 @@
+//! Open handles as the services keep them, and the read-only view handed out
+//! by CLONE.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::ipc::Badge;
+use glenda::protocol::fs::{DEntry, Stat};
+
+pub trait FsHandle: FileHandleService + Send {
+    /// Another handle on the same open file, starting at offset 0 and sharing
+    /// the caches this one reads through, without resolving the path again.
+    /// NotSupported for handles that cannot be duplicated.
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Value of the extended attribute `name`; NotFound if the file has
+    /// none by that name, NotSupported where the filesystem keeps none.
+    fn getxattr(&self, _name: &str) -> Result<Vec<u8>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Names of the file's extended attributes.
+    fn listxattr(&self) -> Result<Vec<String>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Handles FALLOCATE: reserves the blocks of `len` bytes at `offset`.
+    fn fallocate(&mut self, _offset: usize, _len: usize, _flags: usize) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+}
+
+/// A handle that refuses to modify its file.
+pub struct ReadOnly(Box<dyn FsHandle>);
+
+impl ReadOnly {
+    pub fn new(inner: Box<dyn FsHandle>) -> Self {
+        Self(inner)
+    }
+}
+
+impl FileHandleService for ReadOnly {
+    fn close(&mut self, badge: Badge) -> Result<(), Error> {
+        self.0.close(badge)
+    }
+
+    fn stat(&self, badge: Badge) -> Result<Stat, Error> {
+        self.0.stat(badge)
+    }
+
+    fn read(&mut self, badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        self.0.read(badge, offset, buf)
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::PermissionDenied)
+    }
+
+    fn getdents(&mut self, badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        self.0.getdents(badge, count)
+    }
+
+    fn seek(&mut self, badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        self.0.seek(badge, offset, whence)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(Error::PermissionDenied)
+    }
+}
+
+impl FsHandle for ReadOnly {
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        Ok(Box::new(ReadOnly::new(self.0.duplicate()?)))
+    }
+
+    fn getxattr(&self, name: &str) -> Result<Vec<u8>, Error> {
+        self.0.getxattr(name)
+    }
+
+    fn listxattr(&self) -> Result<Vec<String>, Error> {
+        self.0.listxattr()
+    }
+
+    fn fallocate(&mut self, _offset: usize, _len: usize, _flags: usize) -> Result<(), Error> {
+        Err(Error::PermissionDenied)
+    }
+}
@@ -0,0 +1,86 @@
+//! Per-volume block IO error counters, so failing media shows up before it
+//! costs data.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// Attempts after the first one before a request counts as failed
+pub const IO_RETRIES: usize = 2;
+
+/// Block layer error counters shared by every reader of a volume.
+#[derive(Default)]
+pub struct IoStats {
+    retries: AtomicUsize,
+    timeouts: AtomicUsize,
+    failed_requests: AtomicUsize,
+    failed_sectors: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoErrorCounts {
+    pub retries: usize,
+    pub timeouts: usize,
+    pub failed_requests: usize,
+    /// 512-byte sectors covered by requests that failed for good.
+    pub failed_sectors: usize,
+}
+
+impl IoStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs a device request covering `bytes`, retrying transient failures
+    /// and counting them. Other errors are passed through untouched.
+    pub fn run<T>(
+        &self,
+        bytes: usize,
+        mut op: impl FnMut() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            let err = match op() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if !is_transient(err) {
+                return Err(err);
+            }
+            if err == Error::Timeout {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            if attempt == IO_RETRIES {
+                self.failed_requests.fetch_add(1, Ordering::Relaxed);
+                self.failed_sectors.fetch_add(bytes.div_ceil(512), Ordering::Relaxed);
+                return Err(err);
+            }
+            self.retries.fetch_add(1, Ordering::Relaxed);
+            attempt += 1;
+        }
+    }
+
+    pub fn counts(&self) -> IoErrorCounts {
+        IoErrorCounts {
+            retries: self.retries.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            failed_requests: self.failed_requests.load(Ordering::Relaxed),
+            failed_sectors: self.failed_sectors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl IoErrorCounts {
+    /// Fills in a VOLUME_STATS reply.
+    pub fn encode(&self, utcb: &mut UTCB) {
+        utcb.set_mr(0, self.retries);
+        utcb.set_mr(1, self.timeouts);
+        utcb.set_mr(2, self.failed_requests);
+        utcb.set_mr(3, self.failed_sectors);
+    }
+}
+
+// Errors a second attempt can plausibly fix; argument errors never are
+fn is_transient(err: Error) -> bool {
+    matches!(err, Error::IoError | Error::DeviceError | Error::Timeout | Error::WouldBlock)
+}
@@ -0,0 +1,90 @@
+//! Access frequency per device region, exported with HEAT_EXPORT so a tiering
+//! daemon can tell hot data from cold.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+use spin::Mutex;
+
+// Regions are 1 MiB of the volume
+pub const HEAT_REGION_SHIFT: u32 = 20;
+// Regions tracked at once; when full, every count is halved and cold ones drop out
+pub const HEAT_MAX_REGIONS: usize = 1024;
+// Exported record: region index (u32 LE), access count (u32 LE)
+pub const HEAT_RECORD_SIZE: usize = 8;
+
+// HEAT_EXPORT MR0 flag: start counting afresh once exported
+pub const HEAT_RESET: usize = 1;
+
+pub struct HeatMap {
+    regions: Mutex<BTreeMap<u32, u32>>,
+}
+
+impl HeatMap {
+    pub fn new() -> Self {
+        Self { regions: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Counts one access to every region overlapped by `len` bytes at byte `offset`.
+    pub fn record(&self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = offset >> HEAT_REGION_SHIFT;
+        let last = (offset + len - 1) >> HEAT_REGION_SHIFT;
+        let mut regions = self.regions.lock();
+        for region in first..=last {
+            let region = region as u32;
+            if !regions.contains_key(&region) && regions.len() >= HEAT_MAX_REGIONS {
+                decay(&mut regions);
+            }
+            let count = regions.entry(region).or_insert(0);
+            *count = count.saturating_add(1);
+        }
+    }
+
+    /// Fills in a HEAT_EXPORT reply with the hottest regions that fit.
+    pub fn encode(&self, utcb: &mut UTCB, flags: usize) -> Result<(), Error> {
+        if flags & !HEAT_RESET != 0 {
+            return Err(Error::InvalidArgs);
+        }
+        let mut regions = self.regions.lock();
+        let mut hot: Vec<(u32, u32)> = regions.iter().map(|(&r, &c)| (r, c)).collect();
+        hot.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let buf = utcb.buffer_mut();
+        let count = core::cmp::min(hot.len(), buf.len() / HEAT_RECORD_SIZE);
+        for (i, (region, accesses)) in hot.iter().take(count).enumerate() {
+            let at = i * HEAT_RECORD_SIZE;
+            buf[at..at + 4].copy_from_slice(&region.to_le_bytes());
+            buf[at + 4..at + 8].copy_from_slice(&accesses.to_le_bytes());
+        }
+        utcb.set_buffer_len(count * HEAT_RECORD_SIZE);
+        utcb.set_mr(0, HEAT_REGION_SHIFT as usize);
+        utcb.set_mr(1, count);
+        if flags & HEAT_RESET != 0 {
+            regions.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Default for HeatMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decay(regions: &mut BTreeMap<u32, u32>) {
+    regions.retain(|_, count| {
+        *count /= 2;
+        *count != 0
+    });
+    // Everything still warm: make room by dropping the coldest region
+    if regions.len() >= HEAT_MAX_REGIONS {
+        if let Some(coldest) = regions.iter().min_by_key(|(_, &c)| c).map(|(&r, _)| r) {
+            regions.remove(&coldest);
+        }
+    }
+}
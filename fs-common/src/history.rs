@@ -0,0 +1,234 @@
+//! Version history for small files, such as system configuration. A
+//! directory opts in by having a `.versions` subdirectory (MKDIR it to turn
+//! the mode on, RMTREE it to turn it off again). From then on, whenever a
+//! file in it is replaced, by an OPEN with O_TRUNC or a RENAME onto it, the
+//! previous contents are kept as `.versions/<name>;<generation>`, and only
+//! the newest KEEP_VERSIONS of each file are kept. HISTORY_LIST shows them and
+//! HISTORY_RESTORE copies one back. On volumes whose files cannot be
+//! rewritten, only RENAME keeps versions and nothing can be restored.
+
+use crate::handle::FsHandle;
+use crate::path;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::error::Error;
+use glenda::ipc::{Badge, UTCB};
+use glenda::protocol::fs::{OpenFlags, Stat};
+
+pub const HISTORY_DIR: &str = ".versions";
+// Previous versions kept per file
+pub const KEEP_VERSIONS: usize = 8;
+// Larger files are replaced without keeping a copy; RENAME onto them still
+// keeps the old file, as that costs no copy
+pub const MAX_VERSIONED_SIZE: usize = 1 << 20;
+// HISTORY_LIST record: generation (u64 LE), size in bytes (u64 LE)
+pub const VERSION_RECORD_SIZE: usize = 16;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const COPY_CHUNK: usize = 4096;
+// Entries asked for per GETDENTS while listing `.versions`
+const LIST_BATCH: usize = 32;
+
+/// What history needs of a filesystem. The calls act on the volume
+/// directly; they never recurse into history themselves.
+pub trait VersionStore {
+    fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Box<dyn FsHandle>, Error>;
+
+    fn stat(&mut self, path: &str) -> Result<Stat, Error>;
+
+    fn unlink(&mut self, path: &str) -> Result<(), Error>;
+
+    /// Moves `from` to `to`, which does not exist. NotSupported where the
+    /// filesystem cannot rename.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), Error>;
+}
+
+pub struct Version {
+    pub generation: u64,
+    pub size: usize,
+}
+
+/// Keeps a copy of `path` before an OPEN with O_TRUNC replaces its contents.
+/// Does nothing unless the directory keeps history and `path` is a regular
+/// file of at most MAX_VERSIONED_SIZE bytes.
+pub fn save(store: &mut dyn VersionStore, path: &str) -> Result<(), Error> {
+    let (dir, name) = match versioned(store, path) {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    match store.stat(path) {
+        Ok(stat) if is_regular(&stat) && stat.size <= MAX_VERSIONED_SIZE => {}
+        Ok(_) | Err(Error::NotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    let generation = next_generation(store, &dir, &name)?;
+    copy(store, path, &version_path(&dir, &name, generation))?;
+    prune(store, &dir, &name)
+}
+
+/// Moves `path` into history before a RENAME replaces it, so the rename can
+/// go ahead onto a free name. Returns where `path` went, for moving it back
+/// should the rename fail; without history, or when no file is at `path`,
+/// the rename goes ahead as it would have.
+pub fn displace(store: &mut dyn VersionStore, path: &str) -> Result<Option<String>, Error> {
+    let (dir, name) = match versioned(store, path) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    match store.stat(path) {
+        Ok(stat) if is_regular(&stat) => {}
+        Ok(_) | Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let generation = next_generation(store, &dir, &name)?;
+    let kept = version_path(&dir, &name, generation);
+    store.rename(path, &kept)?;
+    prune(store, &dir, &name)?;
+    Ok(Some(kept))
+}
+
+/// Versions kept of `path`, newest first. NotFound if its directory keeps no
+/// history.
+pub fn versions(store: &mut dyn VersionStore, path: &str) -> Result<Vec<Version>, Error> {
+    let (dir, name) = versioned(store, path).ok_or(Error::NotFound)?;
+    let mut found = generations(store, &dir, &name)?;
+    found.sort_unstable_by(|a, b| b.cmp(a));
+    let mut out = Vec::with_capacity(found.len());
+    for generation in found {
+        let stat = store.stat(&version_path(&dir, &name, generation))?;
+        out.push(Version { generation, size: stat.size });
+    }
+    Ok(out)
+}
+
+/// Handles HISTORY_LIST: buffer: path in, records out.
+pub fn list(store: &mut dyn VersionStore, utcb: &mut UTCB) -> Result<(), Error> {
+    let found = versions(store, path::from_buffer(utcb.buffer())?)?;
+    let buf = utcb.buffer_mut();
+    let count = core::cmp::min(found.len(), buf.len() / VERSION_RECORD_SIZE);
+    for (i, version) in found.iter().take(count).enumerate() {
+        let at = i * VERSION_RECORD_SIZE;
+        buf[at..at + 8].copy_from_slice(&version.generation.to_le_bytes());
+        buf[at + 8..at + 16].copy_from_slice(&(version.size as u64).to_le_bytes());
+    }
+    utcb.set_buffer_len(count * VERSION_RECORD_SIZE);
+    utcb.set_mr(0, count);
+    Ok(())
+}
+
+/// Copies version `generation` of `path` back over it. The contents it
+/// replaces become a version of their own, so a restore can be undone.
+pub fn restore(store: &mut dyn VersionStore, path: &str, generation: u64) -> Result<(), Error> {
+    let (dir, name) = versioned(store, path).ok_or(Error::NotFound)?;
+    let version = version_path(&dir, &name, generation);
+    store.stat(&version)?;
+    save(store, path)?;
+    copy(store, &version, path)
+}
+
+// History directory and name of `path` when its directory keeps history
+fn versioned(store: &mut dyn VersionStore, path: &str) -> Option<(String, String)> {
+    let path = path::normalize(path);
+    let name = &path[path.rfind('/').map_or(0, |i| i + 1)..];
+    if name.is_empty() || name == HISTORY_DIR {
+        return None;
+    }
+    let dir = format!("{}/{}", path::parent(&path).trim_end_matches('/'), HISTORY_DIR);
+    match store.stat(&dir) {
+        Ok(stat) if stat.mode & S_IFMT == S_IFDIR => Some((dir, String::from(name))),
+        _ => None,
+    }
+}
+
+fn version_path(dir: &str, name: &str, generation: u64) -> String {
+    format!("{}/{};{}", dir, name, generation)
+}
+
+fn is_regular(stat: &Stat) -> bool {
+    stat.mode & S_IFMT == S_IFREG
+}
+
+// Generations of `name` found in the history directory `dir`, unordered
+fn generations(store: &mut dyn VersionStore, dir: &str, name: &str) -> Result<Vec<u64>, Error> {
+    let mut handle = store.open(dir, OpenFlags::O_RDONLY | OpenFlags::O_DIRECTORY)?;
+    let mut found = Vec::new();
+    let listed = loop {
+        let batch = match handle.getdents(Badge::null(), LIST_BATCH) {
+            Ok(batch) => batch,
+            Err(e) => break Err(e),
+        };
+        if batch.is_empty() {
+            break Ok(());
+        }
+        for entry in &batch {
+            let len = entry.name.iter().position(|&b| b == 0).unwrap_or(entry.name.len());
+            let entry_name = core::str::from_utf8(&entry.name[..len]).unwrap_or("");
+            let generation = entry_name
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix(';'))
+                .and_then(|rest| rest.parse::<u64>().ok());
+            found.extend(generation);
+        }
+    };
+    handle.close(Badge::null())?;
+    listed.map(|_| found)
+}
+
+fn next_generation(store: &mut dyn VersionStore, dir: &str, name: &str) -> Result<u64, Error> {
+    Ok(generations(store, dir, name)?.into_iter().max().map_or(1, |g| g + 1))
+}
+
+// Drops the oldest versions of `name` beyond KEEP_VERSIONS
+fn prune(store: &mut dyn VersionStore, dir: &str, name: &str) -> Result<(), Error> {
+    let mut found = generations(store, dir, name)?;
+    if found.len() <= KEEP_VERSIONS {
+        return Ok(());
+    }
+    found.sort_unstable();
+    for generation in &found[..found.len() - KEEP_VERSIONS] {
+        store.unlink(&version_path(dir, name, *generation))?;
+    }
+    Ok(())
+}
+
+fn copy(store: &mut dyn VersionStore, from: &str, to: &str) -> Result<(), Error> {
+    let mut src = store.open(from, OpenFlags::O_RDONLY)?;
+    let flags = OpenFlags::O_WRONLY | OpenFlags::O_CREAT | OpenFlags::O_TRUNC;
+    let mut dst = match store.open(to, flags) {
+        Ok(dst) => dst,
+        Err(e) => {
+            let _ = src.close(Badge::null());
+            return Err(e);
+        }
+    };
+    let copied = copy_data(src.as_mut(), dst.as_mut());
+    let synced = copied.and(dst.sync(Badge::null()));
+    let closed = src.close(Badge::null()).and(dst.close(Badge::null()));
+    synced.and(closed)
+}
+
+fn copy_data(src: &mut dyn FsHandle, dst: &mut dyn FsHandle) -> Result<(), Error> {
+    let mut buf = alloc::vec![0u8; COPY_CHUNK];
+    let mut offset = 0;
+    loop {
+        let n = src.read(Badge::null(), offset, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        let mut written = 0;
+        while written < n {
+            let w = dst.write(Badge::null(), offset + written, &buf[written..n])?;
+            if w == 0 {
+                return Err(Error::IoError);
+            }
+            written += w;
+        }
+        offset += n;
+    }
+    // The target may have been longer than the source
+    dst.truncate(Badge::null(), offset)
+}
@@ -0,0 +1,176 @@
+//! Long-running operations (RMTREE, fsck, ...) executed as jobs.
+//!
+//! A job does a bounded slice of work per `step`, and the server steps its jobs
+//! between requests so other clients are still served while one runs. The
+//! client polls or cancels the job by id and is notified on the endpoint it
+//! handed over at start once the job has finished.
+
+use crate::proto;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use glenda::cap::{CapPtr, Endpoint};
+use glenda::error::Error;
+use glenda::ipc::Badge;
+
+pub const MAX_JOBS: usize = 16;
+
+pub enum Step {
+    Continue,
+    Done,
+}
+
+pub trait Job<C: ?Sized>: Send {
+    fn step(&mut self, ctx: &mut C) -> Result<Step, Error>;
+
+    /// Units of work done so far and the total, 0 while the total is unknown.
+    fn progress(&self) -> (usize, usize);
+
+    /// Called instead of the next step once the job is cancelled or has failed.
+    fn stop(&mut self, _ctx: &mut C) {}
+
+    /// Path whose cached attributes go stale while the job runs.
+    fn touches(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Done,
+    Failed(Error),
+    Cancelled,
+}
+
+impl JobState {
+    /// JOB_STATUS encoding: state in MR0, error code in MR3.
+    pub fn encode(&self) -> (usize, usize) {
+        match self {
+            JobState::Running => (proto::JOB_RUNNING, 0),
+            JobState::Done => (proto::JOB_DONE, 0),
+            JobState::Failed(e) => (proto::JOB_FAILED, *e as usize),
+            JobState::Cancelled => (proto::JOB_CANCELLED, 0),
+        }
+    }
+}
+
+pub struct JobStatus {
+    pub state: JobState,
+    pub done: usize,
+    pub total: usize,
+}
+
+struct Entry<C: ?Sized> {
+    owner: Badge,
+    job: Box<dyn Job<C>>,
+    state: JobState,
+    cancel: bool,
+    notify: Endpoint,
+}
+
+pub struct JobTable<C: ?Sized> {
+    entries: Vec<Option<Entry<C>>>,
+    slot_base: usize,
+}
+
+impl<C: ?Sized> JobTable<C> {
+    /// Notification endpoints of job `id` live in cap slot `slot_base + id`.
+    pub fn new(slot_base: CapPtr) -> Self {
+        Self { entries: (0..MAX_JOBS).map(|_| None).collect(), slot_base: slot_base.bits() }
+    }
+
+    /// Id for the next job and the slot its notification endpoint must be moved to.
+    pub fn vacant(&self) -> Result<(usize, CapPtr), Error> {
+        let id = self.entries.iter().position(|e| e.is_none()).ok_or(Error::OutOfMemory)?;
+        Ok((id, self.notify_slot(id)))
+    }
+
+    /// Starts `job` under an id returned by `vacant`, once its endpoint is in place.
+    pub fn start(&mut self, id: usize, owner: Badge, job: Box<dyn Job<C>>) -> Result<(), Error> {
+        let notify = Endpoint::from(self.notify_slot(id));
+        let entry = self.entries.get_mut(id).ok_or(Error::InvalidArgs)?;
+        if entry.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        *entry = Some(Entry { owner, job, state: JobState::Running, cancel: false, notify });
+        Ok(())
+    }
+
+    pub fn status(&self, owner: Badge, id: usize) -> Result<JobStatus, Error> {
+        let entry = self.entry(owner, id)?;
+        let (done, total) = entry.job.progress();
+        Ok(JobStatus { state: entry.state, done, total })
+    }
+
+    /// Asks a running job to stop before its next step; finished jobs are left alone.
+    pub fn cancel(&mut self, owner: Badge, id: usize) -> Result<(), Error> {
+        self.entry(owner, id)?;
+        if let Some(entry) = self.entries[id].as_mut() {
+            entry.cancel = true;
+        }
+        Ok(())
+    }
+
+    /// Asks every running job to stop, e.g. when the volume goes read-only.
+    pub fn cancel_all(&mut self) {
+        for entry in self.entries.iter_mut().flatten() {
+            entry.cancel = true;
+        }
+    }
+
+    /// Forgets a finished job, returning the slot of its notification endpoint
+    /// for the caller to delete.
+    pub fn release(&mut self, owner: Badge, id: usize) -> Result<CapPtr, Error> {
+        if self.entry(owner, id)?.state == JobState::Running {
+            return Err(Error::WouldBlock);
+        }
+        self.entries[id] = None;
+        Ok(self.notify_slot(id))
+    }
+
+    pub fn busy(&self) -> bool {
+        self.entries.iter().flatten().any(|e| e.state == JobState::Running)
+    }
+
+    /// Steps every running job once. `touched` gets the path each of them may
+    /// have changed.
+    pub fn run(&mut self, ctx: &mut C, mut touched: impl FnMut(&str)) {
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.state != JobState::Running {
+                continue;
+            }
+            let state = if entry.cancel {
+                entry.job.stop(ctx);
+                JobState::Cancelled
+            } else {
+                match entry.job.step(ctx) {
+                    Ok(Step::Continue) => JobState::Running,
+                    Ok(Step::Done) => JobState::Done,
+                    Err(e) => {
+                        entry.job.stop(ctx);
+                        JobState::Failed(e)
+                    }
+                }
+            };
+            if let Some(path) = entry.job.touches() {
+                touched(path);
+            }
+            if state != JobState::Running {
+                entry.state = state;
+                let _ = entry.notify.notify(proto::JOB_NOTIFY_BITS);
+            }
+        }
+    }
+
+    fn entry(&self, owner: Badge, id: usize) -> Result<&Entry<C>, Error> {
+        // Other clients' jobs look the same as free ids
+        match self.entries.get(id) {
+            Some(Some(entry)) if entry.owner == owner => Ok(entry),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    fn notify_slot(&self, id: usize) -> CapPtr {
+        CapPtr::from(self.slot_base + id)
+    }
+}
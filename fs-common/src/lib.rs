@@ -0,0 +1,46 @@
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod attr;
+pub mod audit;
+pub mod badge;
+pub mod batch;
+pub mod bytes;
+pub mod call;
+pub mod casefold;
+pub mod cbt;
+pub mod clock;
+pub mod coalesce;
+pub mod crc;
+pub mod creds;
+pub mod deferred;
+pub mod device;
+pub mod errors;
+pub mod events;
+pub mod handle;
+pub mod health;
+pub mod heat;
+pub mod history;
+pub mod jobs;
+pub mod limits;
+pub mod locks;
+pub mod mount;
+pub mod partition;
+pub mod path;
+pub mod policy;
+pub mod probe;
+pub mod proto;
+pub mod readahead;
+pub mod ring;
+pub mod scrub;
+pub mod slots;
+pub mod snapshots;
+pub mod statfs;
+pub mod stream;
+pub mod tune;
+pub mod utf16;
+pub mod vaddr;
+pub mod version;
+pub mod wire;
+pub mod zeroing;
@@ -0,0 +1,86 @@
+//! Per-format file size limits and overflow-checked offset arithmetic.
+
+use crate::proto::{SEEK_CUR, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET};
+use glenda::error::Error;
+
+/// FAT12/16/32 store the file size in a 32-bit field.
+pub const FAT_MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+/// exFAT sizes are 64-bit; cap them so offsets still fit a signed seek.
+pub const EXFAT_MAX_FILE_SIZE: u64 = i64::MAX as u64;
+/// Initrd entries record their size in a 32-bit header field.
+pub const INITRD_MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+/// ISO 9660 directory records hold a 32-bit size per extent; larger files
+/// are split over several records, which this driver joins up again.
+pub const ISO9660_MAX_FILE_SIZE: u64 = i64::MAX as u64;
+/// tmpfs files are only bounded by memory; cap them like exFAT.
+pub const TMPFS_MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+/// Largest file reachable through 32-bit logical block numbers (ext4 extents).
+pub const fn ext_extent_max_file_size(block_size: u32) -> u64 {
+    (u32::MAX as u64 + 1) * block_size as u64 - 1
+}
+
+/// Largest file reachable through the 12 direct and three indirect block
+/// pointers of the classic ext2/3 block map.
+pub const fn ext_blockmap_max_file_size(block_size: u32) -> u64 {
+    let ptrs = block_size as u64 / 4;
+    let blocks = 12 + ptrs + ptrs * ptrs + ptrs * ptrs * ptrs;
+    let limit = blocks * block_size as u64 - 1;
+    let extent_limit = ext_extent_max_file_size(block_size);
+    if limit < extent_limit {
+        limit
+    } else {
+        extent_limit
+    }
+}
+
+/// Returns `offset + len`, rejecting wrap-around and ends past `max_size`.
+pub fn checked_end(offset: usize, len: usize, max_size: u64) -> Result<usize, Error> {
+    let end = offset.checked_add(len).ok_or(Error::InvalidArgs)?;
+    if end as u64 > max_size {
+        return Err(Error::InvalidArgs);
+    }
+    Ok(end)
+}
+
+/// Resolves a seek request to an absolute position.
+///
+/// Positions past end of file are allowed up to `max_size`: reads there return
+/// nothing and writes either extend the file or leave a hole, depending on the
+/// filesystem. Negative targets and unknown `whence` values are rejected.
+/// SEEK_DATA and SEEK_HOLE treat the file as all data; filesystems that track
+/// holes resolve those themselves.
+pub fn seek_target(
+    pos: usize,
+    size: usize,
+    offset: i64,
+    whence: usize,
+    max_size: u64,
+) -> Result<usize, Error> {
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => pos,
+        SEEK_END => size,
+        SEEK_DATA | SEEK_HOLE => {
+            let offset = usize::try_from(offset).map_err(|_| Error::InvalidArgs)?;
+            if offset >= size {
+                return Err(Error::NotFound);
+            }
+            return Ok(if whence == SEEK_DATA { offset } else { size });
+        }
+        _ => return Err(Error::InvalidArgs),
+    };
+    let target = (base as i64).checked_add(offset).ok_or(Error::InvalidArgs)?;
+    if target < 0 || target as u64 > max_size {
+        return Err(Error::InvalidArgs);
+    }
+    Ok(target as usize)
+}
+
+/// Number of bytes a read of `len` at `offset` may return from a file of `size` bytes.
+pub fn read_len(offset: usize, len: usize, size: usize) -> usize {
+    if offset >= size {
+        return 0;
+    }
+    core::cmp::min(len, size - offset)
+}
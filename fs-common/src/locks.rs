@@ -0,0 +1,217 @@
+//! Advisory byte-range locks. Locks belong to an open handle and only matter
+//! to clients that ask for them; reads and writes never check them. They are
+//! taken through LOCK/UNLOCK or, without leaving the ring, through the
+//! IOURING_OP_LOCK/IOURING_OP_UNLOCK submissions, and dropped when the handle
+//! closes.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use glenda::error::Error;
+
+// LOCK flag: exclusive (write) lock; shared (read) lock otherwise
+pub const LOCK_EXCLUSIVE: usize = 1;
+// Ring flag: queue a conflicting lock and complete it once granted, instead of
+// failing with WouldBlock
+pub const LOCK_WAIT: usize = 2;
+
+// SQE opcodes, after the glenda ones. `off` and `len` give the range (len 0:
+// to the end of the file), `addr` the LOCK_* flags.
+pub const IOURING_OP_LOCK: u8 = 0x40;
+pub const IOURING_OP_UNLOCK: u8 = 0x41;
+
+#[derive(Clone, Copy)]
+struct Range {
+    start: u64,
+    // Exclusive; u64::MAX reaches to the end of the file however it grows
+    end: u64,
+}
+
+impl Range {
+    fn new(start: u64, len: u64) -> Result<Self, Error> {
+        let end =
+            if len == 0 { u64::MAX } else { start.checked_add(len).ok_or(Error::InvalidArgs)? };
+        Ok(Self { start, end })
+    }
+
+    fn overlaps(&self, other: &Range) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+struct Held {
+    owner: usize,
+    range: Range,
+    exclusive: bool,
+}
+
+struct Waiter {
+    owner: usize,
+    range: Range,
+    exclusive: bool,
+    user_data: u64,
+}
+
+/// A queued lock that has just been granted; its submission completes now.
+pub struct Grant {
+    pub owner: usize,
+    pub user_data: u64,
+}
+
+#[derive(Default)]
+struct FileLocks {
+    held: Vec<Held>,
+    waiting: VecDeque<Waiter>,
+}
+
+impl FileLocks {
+    fn conflicts(&self, owner: usize, range: &Range, exclusive: bool) -> bool {
+        self.held
+            .iter()
+            .any(|h| h.owner != owner && h.range.overlaps(range) && (exclusive || h.exclusive))
+    }
+
+    // Drops `owner`'s hold on `range`, splitting locks that stick out of it
+    fn remove(&mut self, owner: usize, range: &Range) {
+        let mut kept = Vec::with_capacity(self.held.len());
+        for h in self.held.drain(..) {
+            if h.owner != owner || !h.range.overlaps(range) {
+                kept.push(h);
+                continue;
+            }
+            if h.range.start < range.start {
+                let head = Range { start: h.range.start, end: range.start };
+                kept.push(Held { owner, range: head, exclusive: h.exclusive });
+            }
+            if h.range.end > range.end {
+                let tail = Range { start: range.end, end: h.range.end };
+                kept.push(Held { owner, range: tail, exclusive: h.exclusive });
+            }
+        }
+        self.held = kept;
+    }
+
+    // A lock replaces whatever the same owner held over its range
+    fn insert(&mut self, owner: usize, range: Range, exclusive: bool) {
+        self.remove(owner, &range);
+        self.held.push(Held { owner, range, exclusive });
+    }
+
+    // Grants queued locks that no longer conflict, oldest first
+    fn wake(&mut self, grants: &mut Vec<Grant>) {
+        let mut still = VecDeque::new();
+        while let Some(w) = self.waiting.pop_front() {
+            if self.conflicts(w.owner, &w.range, w.exclusive) {
+                still.push_back(w);
+            } else {
+                self.insert(w.owner, w.range, w.exclusive);
+                grants.push(Grant { owner: w.owner, user_data: w.user_data });
+            }
+        }
+        self.waiting = still;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.held.is_empty() && self.waiting.is_empty()
+    }
+}
+
+/// Locks of every file a service has open, by file key (path, inode, ...)
+/// and owning handle.
+pub struct LockTable<K> {
+    files: BTreeMap<K, FileLocks>,
+}
+
+impl<K: Ord + Clone> LockTable<K> {
+    pub fn new() -> Self {
+        Self { files: BTreeMap::new() }
+    }
+
+    /// Takes a lock on `len` bytes at `start` (0: to the end of the file).
+    /// WouldBlock if another owner holds a conflicting one.
+    pub fn lock(
+        &mut self,
+        key: &K,
+        owner: usize,
+        start: u64,
+        len: u64,
+        flags: usize,
+    ) -> Result<(), Error> {
+        let range = Range::new(start, len)?;
+        let exclusive = flags & LOCK_EXCLUSIVE != 0;
+        let file = self.files.entry(key.clone()).or_default();
+        if file.conflicts(owner, &range, exclusive) {
+            if file.is_empty() {
+                self.files.remove(key);
+            }
+            return Err(Error::WouldBlock);
+        }
+        file.insert(owner, range, exclusive);
+        Ok(())
+    }
+
+    /// Like `lock`, but with LOCK_WAIT a conflicting lock is queued under
+    /// `user_data` rather than refused. Returns whether it was granted now.
+    pub fn lock_or_wait(
+        &mut self,
+        key: &K,
+        owner: usize,
+        start: u64,
+        len: u64,
+        flags: usize,
+        user_data: u64,
+    ) -> Result<bool, Error> {
+        match self.lock(key, owner, start, len, flags) {
+            Ok(()) => Ok(true),
+            Err(Error::WouldBlock) if flags & LOCK_WAIT != 0 => {
+                let range = Range::new(start, len)?;
+                let exclusive = flags & LOCK_EXCLUSIVE != 0;
+                let file = self.files.entry(key.clone()).or_default();
+                file.waiting.push_back(Waiter { owner, range, exclusive, user_data });
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Drops `owner`'s locks on the range. Returns the queued locks this let through.
+    pub fn unlock(
+        &mut self,
+        key: &K,
+        owner: usize,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<Grant>, Error> {
+        let range = Range::new(start, len)?;
+        let mut grants = Vec::new();
+        if let Some(file) = self.files.get_mut(key) {
+            file.remove(owner, &range);
+            file.wake(&mut grants);
+            if file.is_empty() {
+                self.files.remove(key);
+            }
+        }
+        Ok(grants)
+    }
+
+    /// Drops every lock `owner` holds or waits for, when its handle closes.
+    /// Returns the queued locks this let through.
+    pub fn release(&mut self, owner: usize) -> Vec<Grant> {
+        let mut grants = Vec::new();
+        self.files.retain(|_, file| {
+            let before = file.held.len();
+            file.held.retain(|h| h.owner != owner);
+            file.waiting.retain(|w| w.owner != owner);
+            if file.held.len() != before {
+                file.wake(&mut grants);
+            }
+            !file.is_empty()
+        });
+        grants
+    }
+}
+
+impl<K: Ord + Clone> Default for LockTable<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
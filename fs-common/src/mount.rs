@@ -0,0 +1,134 @@
+//! Options a volume is mounted with, handed to the service at init and
+//! settled with the VFS through MOUNT_OPTIONS, and where the service is
+//! registered in the VFS namespace.
+
+use crate::path;
+use alloc::string::String;
+use glenda::cap::Endpoint;
+use glenda::client::FsClient;
+use glenda::error::Error;
+use glenda::interface::VirtualFileSystemService;
+use glenda::ipc::Badge;
+
+pub const MNT_RDONLY: usize = 1 << 0;
+// Access times are never written
+pub const MNT_NOATIME: usize = 1 << 1;
+// Writes reach the device before the call returns
+pub const MNT_SYNC: usize = 1 << 2;
+// Newly allocated space is zeroed on the device before a file can expose it, even
+// across a crash; for volumes shared between tenants. See zeroing.
+pub const MNT_ZERO_ALLOC: usize = 1 << 3;
+// Names match regardless of case, see casefold. FAT volumes always do.
+pub const MNT_CASEFOLD: usize = 1 << 4;
+const MNT_ALL: usize = MNT_RDONLY | MNT_NOATIME | MNT_SYNC | MNT_ZERO_ALLOC | MNT_CASEFOLD;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    pub read_only: bool,
+    pub noatime: bool,
+    pub sync: bool,
+    pub zero_alloc: bool,
+    pub casefold: bool,
+}
+
+impl MountOptions {
+    pub fn from_bits(bits: usize) -> Result<Self, Error> {
+        if bits & !MNT_ALL != 0 {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(Self {
+            read_only: bits & MNT_RDONLY != 0,
+            noatime: bits & MNT_NOATIME != 0,
+            sync: bits & MNT_SYNC != 0,
+            zero_alloc: bits & MNT_ZERO_ALLOC != 0,
+            casefold: bits & MNT_CASEFOLD != 0,
+        })
+    }
+
+    pub fn bits(&self) -> usize {
+        let mut bits = 0;
+        if self.read_only {
+            bits |= MNT_RDONLY;
+        }
+        if self.noatime {
+            bits |= MNT_NOATIME;
+        }
+        if self.sync {
+            bits |= MNT_SYNC;
+        }
+        if self.zero_alloc {
+            bits |= MNT_ZERO_ALLOC;
+        }
+        if self.casefold {
+            bits |= MNT_CASEFOLD;
+        }
+        bits
+    }
+}
+
+/// The service's entry in the VFS namespace. Services started without a
+/// VFS have none and are only reachable through endpoints handed out to
+/// them directly.
+pub struct MountPoint {
+    vfs: Option<FsClient>,
+    path: String,
+    mounted: bool,
+}
+
+impl MountPoint {
+    pub fn none() -> Self {
+        Self { vfs: None, path: String::new(), mounted: false }
+    }
+
+    /// To be registered at `path` once the service listens.
+    pub fn new(vfs: FsClient, path: &str) -> Self {
+        Self { vfs: Some(vfs), path: path::normalize(path), mounted: false }
+    }
+
+    /// Mounts the service at its path with the VFS, unless it already is.
+    pub fn register(&mut self, ep: Endpoint) -> Result<(), Error> {
+        match self.vfs.as_mut() {
+            Some(vfs) if !self.mounted => {
+                vfs.mount(Badge::null(), &self.path, ep)?;
+                self.mounted = true;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles MOUNT_AT: moves the entry to `path`, or removes it when
+    /// `path` is empty. The new entry is in place before the old one goes,
+    /// so the service stays reachable throughout.
+    pub fn move_to(&mut self, path: &str, ep: Endpoint) -> Result<(), Error> {
+        let vfs = self.vfs.as_mut().ok_or(Error::NotSupported)?;
+        if !path.is_empty() && !path.starts_with('/') {
+            return Err(Error::InvalidArgs);
+        }
+        let path = if path.is_empty() { String::new() } else { path::normalize(path) };
+        if self.mounted && path == self.path {
+            return Ok(());
+        }
+        if !path.is_empty() {
+            vfs.mount(Badge::null(), &path, ep)?;
+        }
+        let old = core::mem::replace(&mut self.path, path);
+        let was_mounted = core::mem::replace(&mut self.mounted, !self.path.is_empty());
+        if was_mounted {
+            vfs.umount(Badge::null(), &old)?;
+        }
+        Ok(())
+    }
+
+    /// Takes the entry out of the namespace, on shutdown.
+    pub fn unregister(&mut self) -> Result<(), Error> {
+        match self.vfs.as_mut() {
+            Some(vfs) if self.mounted => {
+                vfs.umount(Badge::null(), &self.path)?;
+                self.mounted = false;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! MBR and GPT partition tables, so a filesystem can be mounted from a
+//! partition of the device instead of assuming it starts at offset 0.
+
+use crate::bytes::{le_u32, le_u64};
+use crate::crc::crc32;
+use alloc::vec;
+use alloc::vec::Vec;
+use glenda::error::Error;
+
+// Bytes of an MBR, EBR or GPT header; the LBAs the tables count in are
+// the device's logical blocks, 4 KiB on 4Kn drives
+pub const SECTOR_SIZE: u64 = 512;
+
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Logical partitions followed through the EBR chain before giving up on a loop
+const MBR_MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MAX_ENTRIES: u32 = 1024;
+const GPT_MIN_ENTRY_SIZE: u32 = 128;
+const GPT_MAX_ENTRY_SIZE: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// MBR partition type byte.
+    Mbr(u8),
+    /// GPT partition type GUID, in on-disk byte order.
+    Gpt([u8; 16]),
+}
+
+// Raw PartitionSelect values, as in ATTACH_DEVICE; others name a partition by index
+pub const PARTITION_AUTO: usize = 0;
+pub const PARTITION_WHOLE: usize = usize::MAX;
+
+/// A partition as a byte range of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// 1-based, in table order; MBR logical partitions start at 5.
+    pub index: usize,
+    pub start: u64,
+    pub len: u64,
+    pub kind: PartitionKind,
+}
+
+/// Which part of the device a filesystem is mounted from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PartitionSelect {
+    /// The first partition if the device has a table, the whole device otherwise.
+    #[default]
+    Auto,
+    /// The raw device, whatever it contains.
+    Whole,
+    /// The partition with this index; NotFound if there is none.
+    Index(usize),
+}
+
+impl PartitionSelect {
+    pub fn from_raw(raw: usize) -> Self {
+        match raw {
+            PARTITION_AUTO => PartitionSelect::Auto,
+            PARTITION_WHOLE => PartitionSelect::Whole,
+            index => PartitionSelect::Index(index),
+        }
+    }
+}
+
+/// Reads the partition table through `read(offset, buf)`, with LBAs of
+/// `lba_size` bytes. Returns no partitions for a device without a
+/// recognisable table.
+pub fn probe<F>(mut read: F, lba_size: u64) -> Result<Vec<Partition>, Error>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    read(0, &mut mbr)?;
+    if !is_mbr(&mbr) {
+        return Ok(Vec::new());
+    }
+    let primary = mbr_entries(&mbr);
+    if primary.iter().any(|e| e.kind == MBR_TYPE_PROTECTIVE) {
+        return probe_gpt(&mut read, lba_size);
+    }
+    let mut parts = Vec::new();
+    for (i, entry) in primary.iter().enumerate() {
+        if entry.is_empty() {
+            continue;
+        }
+        if MBR_TYPE_EXTENDED.contains(&entry.kind) {
+            probe_logical(&mut read, lba_size, entry.lba as u64, &mut parts)?;
+            continue;
+        }
+        parts.push(entry.to_partition(i + 1, 0, lba_size));
+    }
+    parts.sort_by_key(|p| p.index);
+    Ok(parts)
+}
+
+/// Resolves `select` against the table read through `read`, with LBAs of
+/// `lba_size` bytes. `None` means the whole device.
+pub fn select<F>(
+    read: F,
+    select: PartitionSelect,
+    lba_size: u64,
+) -> Result<Option<Partition>, Error>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    if select == PartitionSelect::Whole {
+        return Ok(None);
+    }
+    let parts = probe(read, lba_size)?;
+    match select {
+        PartitionSelect::Index(index) => {
+            parts.into_iter().find(|p| p.index == index).map(Some).ok_or(Error::NotFound)
+        }
+        _ => Ok(parts.into_iter().next()),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct MbrEntry {
+    status: u8,
+    kind: u8,
+    lba: u32,
+    sectors: u32,
+}
+
+impl MbrEntry {
+    fn is_empty(&self) -> bool {
+        self.kind == 0 || self.sectors == 0
+    }
+
+    fn to_partition(self, index: usize, base_lba: u64, lba_size: u64) -> Partition {
+        Partition {
+            index,
+            start: (base_lba + self.lba as u64) * lba_size,
+            len: self.sectors as u64 * lba_size,
+            kind: PartitionKind::Mbr(self.kind),
+        }
+    }
+}
+
+fn mbr_entries(sector: &[u8]) -> [MbrEntry; 4] {
+    core::array::from_fn(|i| {
+        let raw = &sector[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        MbrEntry {
+            status: raw[0],
+            kind: raw[4],
+            lba: u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]),
+            sectors: u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]),
+        }
+    })
+}
+
+// A FAT boot sector carries the same 0xAA55 signature, so also require sane
+// status bytes, at least one entry, and no BPB filesystem tag.
+fn is_mbr(sector: &[u8]) -> bool {
+    if u16::from_le_bytes([sector[510], sector[511]]) != MBR_SIGNATURE {
+        return false;
+    }
+    if &sector[3..11] == b"EXFAT   " || &sector[54..57] == b"FAT" || &sector[82..87] == b"FAT32" {
+        return false;
+    }
+    let entries = mbr_entries(sector);
+    entries.iter().all(|e| e.status == 0 || e.status == 0x80)
+        && entries.iter().any(|e| !e.is_empty())
+}
+
+// Follows the EBR chain of the extended partition starting at `ext_lba`. Each
+// EBR holds a logical partition relative to itself and a link relative to the
+// extended partition.
+fn probe_logical<F>(
+    read: &mut F,
+    lba_size: u64,
+    ext_lba: u64,
+    parts: &mut Vec<Partition>,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    let mut ebr = [0u8; SECTOR_SIZE as usize];
+    let mut ebr_lba = ext_lba;
+    for n in 0..MBR_MAX_LOGICAL {
+        read(ebr_lba * lba_size, &mut ebr)?;
+        if u16::from_le_bytes([ebr[510], ebr[511]]) != MBR_SIGNATURE {
+            return Err(Error::InvalidArgs);
+        }
+        let [logical, link, ..] = mbr_entries(&ebr);
+        if !logical.is_empty() {
+            parts.push(logical.to_partition(5 + n, ebr_lba, lba_size));
+        }
+        if link.is_empty() {
+            return Ok(());
+        }
+        ebr_lba = ext_lba + link.lba as u64;
+    }
+    Err(Error::InvalidArgs)
+}
+
+fn probe_gpt<F>(read: &mut F, lba_size: u64) -> Result<Vec<Partition>, Error>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    // Every LBA and length in the table comes off the disk
+    let bytes = |lba: u64| lba.checked_mul(lba_size).ok_or(Error::InvalidArgs);
+    let mut header = [0u8; SECTOR_SIZE as usize];
+    read(bytes(1)?, &mut header)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err(Error::InvalidArgs);
+    }
+    let header_size = le_u32(&header, 12)? as usize;
+    if !(GPT_MIN_HEADER_SIZE..=header.len()).contains(&header_size) {
+        return Err(Error::InvalidArgs);
+    }
+    // The header CRC is taken with its own field zeroed
+    let header_crc = le_u32(&header, 16)?;
+    let mut copy = header;
+    copy[16..20].fill(0);
+    if !crc32(!0, &copy[..header_size]) != header_crc {
+        return Err(Error::InvalidArgs);
+    }
+
+    let entries_lba = le_u64(&header, 72)?;
+    let count = le_u32(&header, 80)?;
+    let entry_size = le_u32(&header, 84)?;
+    let entries_crc = le_u32(&header, 88)?;
+    if count > GPT_MAX_ENTRIES
+        || !(GPT_MIN_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entry_size)
+        || entry_size % 8 != 0
+    {
+        return Err(Error::InvalidArgs);
+    }
+    let table_len = count.checked_mul(entry_size).ok_or(Error::InvalidArgs)? as usize;
+    let mut table = vec![0u8; table_len.next_multiple_of(lba_size as usize)];
+    read(bytes(entries_lba)?, &mut table)?;
+    if !crc32(!0, &table[..table_len]) != entries_crc {
+        return Err(Error::InvalidArgs);
+    }
+
+    let mut parts = Vec::new();
+    for (i, raw) in table[..table_len].chunks_exact(entry_size as usize).enumerate() {
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&raw[..16]);
+        if guid == [0u8; 16] {
+            continue;
+        }
+        let first = le_u64(raw, 32)?;
+        let last = le_u64(raw, 40)?;
+        if last < first {
+            return Err(Error::InvalidArgs);
+        }
+        parts.push(Partition {
+            index: i + 1,
+            start: bytes(first)?,
+            len: bytes((last - first).checked_add(1).ok_or(Error::InvalidArgs)?)?,
+            kind: PartitionKind::Gpt(guid),
+        });
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LBA: u64 = 4096;
+
+    // A disk with a protective MBR and a GPT of one partition over LBAs 34..=99
+    fn gpt_disk(lba_size: u64, entry_size: u32) -> Vec<u8> {
+        let mut disk = vec![0u8; 8 * lba_size as usize];
+        disk[MBR_TABLE_OFFSET + 4] = MBR_TYPE_PROTECTIVE;
+        disk[MBR_TABLE_OFFSET + 8] = 1;
+        disk[MBR_TABLE_OFFSET + 12..MBR_TABLE_OFFSET + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE.to_le_bytes());
+
+        let entries = 2 * lba_size as usize;
+        let table_len = 4 * entry_size as usize;
+        disk[entries..entries + 16].fill(0xa5);
+        disk[entries + 32..entries + 40].copy_from_slice(&34u64.to_le_bytes());
+        disk[entries + 40..entries + 48].copy_from_slice(&99u64.to_le_bytes());
+        let entries_crc = if entries + table_len <= disk.len() {
+            !crc32(!0, &disk[entries..entries + table_len])
+        } else {
+            0
+        };
+
+        let header = &mut disk[lba_size as usize..][..GPT_MIN_HEADER_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_MIN_HEADER_SIZE as u32).to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let header_crc = !crc32(!0, header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        disk
+    }
+
+    fn reader(disk: &[u8]) -> impl FnMut(u64, &mut [u8]) -> Result<(), Error> + '_ {
+        |offset, buf| {
+            let src = disk.get(offset as usize..offset as usize + buf.len());
+            buf.copy_from_slice(src.ok_or(Error::InvalidArgs)?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn gpt_counts_in_device_lbas() {
+        let disk = gpt_disk(LBA, GPT_MIN_ENTRY_SIZE);
+        let parts = probe(reader(&disk), LBA).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!((parts[0].index, parts[0].start, parts[0].len), (1, 34 * LBA, 66 * LBA));
+        assert_eq!(parts[0].kind, PartitionKind::Gpt([0xa5; 16]));
+    }
+
+    #[test]
+    fn gpt_with_wrong_lba_size_is_not_found() {
+        let disk = gpt_disk(LBA, GPT_MIN_ENTRY_SIZE);
+        assert!(probe(reader(&disk), SECTOR_SIZE).is_err());
+    }
+
+    #[test]
+    fn oversized_gpt_entries_are_refused() {
+        let disk = gpt_disk(SECTOR_SIZE, GPT_MAX_ENTRY_SIZE * 2);
+        assert_eq!(probe(reader(&disk), SECTOR_SIZE), Err(Error::InvalidArgs));
+    }
+}
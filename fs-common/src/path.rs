@@ -0,0 +1,71 @@
+use alloc::string::String;
+use glenda::error::Error;
+
+/// Extracts the NUL-terminated UTF-8 path carried in an IPC buffer.
+pub fn from_buffer(buf: &[u8]) -> Result<&str, Error> {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len]).map_err(|_| Error::InvalidArgs)
+}
+
+/// Extracts two consecutive NUL-terminated paths, as sent with RENAME.
+pub fn pair_from_buffer(buf: &[u8]) -> Result<(&str, &str), Error> {
+    let first_len = buf.iter().position(|&b| b == 0).ok_or(Error::InvalidArgs)?;
+    let first = from_buffer(&buf[..first_len])?;
+    let second = from_buffer(&buf[first_len + 1..])?;
+    if first.is_empty() || second.is_empty() {
+        return Err(Error::InvalidArgs);
+    }
+    Ok((first, second))
+}
+
+/// Resolves `path` against the directory `base`. Absolute paths ignore the base.
+pub fn join(base: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return String::from(path);
+    }
+    let mut out = String::from(base.trim_end_matches('/'));
+    out.push('/');
+    out.push_str(path);
+    out
+}
+
+/// Canonical absolute form of `path`: duplicate and trailing slashes and `.`
+/// components removed. `..` is kept verbatim; servers resolve it on lookup.
+pub fn normalize(path: &str) -> String {
+    let mut out = String::new();
+    for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
+        out.push('/');
+        out.push_str(part);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+/// Like `normalize`, but with `..` resolved by name as well, for services
+/// that key state by path rather than walking a tree; `..` at the root stays
+/// there.
+pub fn canonical(path: &str) -> String {
+    let mut out = String::new();
+    for part in path.split('/').filter(|p| !p.is_empty() && *p != ".") {
+        if part == ".." {
+            out.truncate(out.rfind('/').unwrap_or(0));
+        } else {
+            out.push('/');
+            out.push_str(part);
+        }
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+/// Parent of a normalized path; the root is its own parent.
+pub fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(idx) => &path[..idx],
+    }
+}
@@ -0,0 +1,143 @@
+//! Per-badge masks of the operations a client may perform, so the monitor
+//! can hand out least-privilege endpoints to a shared volume. The server
+//! checks every request against its badge before dispatching it.
+
+use crate::proto;
+use alloc::collections::BTreeMap;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+use glenda::protocol::fs::{self, OpenFlags};
+use glenda::protocol::{FS_PROTO, PROCESS_PROTO};
+
+// Reading file data
+pub const OP_READ: usize = 1 << 0;
+// Creating files and directories, changing file data and size
+pub const OP_WRITE: usize = 1 << 1;
+// Removing and renaming entries
+pub const OP_UNLINK: usize = 1 << 2;
+// Looking up, listing and stat'ing entries, volume information
+pub const OP_METADATA: usize = 1 << 3;
+// Remounting, tuning and other calls that affect every client
+pub const OP_ADMIN: usize = 1 << 4;
+pub const OP_ALL: usize = OP_READ | OP_WRITE | OP_UNLINK | OP_METADATA | OP_ADMIN;
+
+pub const MASK_READ_ONLY: usize = OP_READ | OP_METADATA;
+pub const MASK_NO_UNLINK: usize = OP_ALL & !OP_UNLINK;
+pub const MASK_METADATA_ONLY: usize = OP_METADATA;
+
+/// Operation masks by badge. Badges without an entry may do everything, as
+/// before masks existed.
+pub struct ExportPolicy {
+    masks: BTreeMap<usize, usize>,
+}
+
+impl ExportPolicy {
+    pub fn new() -> Self {
+        Self { masks: BTreeMap::new() }
+    }
+
+    /// Handles SET_OP_MASK. Only the unbadged endpoint, which stays with the
+    /// monitor, may change masks.
+    pub fn configure(&mut self, caller: usize, utcb: &UTCB) -> Result<(), Error> {
+        if caller != 0 {
+            return Err(Error::PermissionDenied);
+        }
+        let (badge, mask) = (utcb.get_mr(0), utcb.get_mr(1));
+        if badge == 0 || mask & !OP_ALL != 0 {
+            return Err(Error::InvalidArgs);
+        }
+        if mask == OP_ALL {
+            self.masks.remove(&badge);
+        } else {
+            self.masks.insert(badge, mask);
+        }
+        Ok(())
+    }
+
+    /// Fails with PermissionDenied if the request in `utcb` needs an
+    /// operation the mask of `badge` leaves out.
+    pub fn check(&self, badge: usize, utcb: &UTCB) -> Result<(), Error> {
+        self.permit(badge, required_ops(utcb))
+    }
+
+    /// Fails with PermissionDenied unless the mask of `badge` has all of
+    /// `ops`; for work a request carries inside it, such as ring submissions.
+    pub fn permit(&self, badge: usize, ops: usize) -> Result<(), Error> {
+        let mask = match self.masks.get(&badge) {
+            Some(&mask) => mask,
+            None => return Ok(()),
+        };
+        if ops & !mask != 0 {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+}
+
+impl Default for ExportPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Operations a request needs. Calls on an already open handle (seek, close,
+/// job control) need none beyond what opening it took.
+pub fn required_ops(utcb: &UTCB) -> usize {
+    let tag = utcb.get_msg_tag();
+    if tag.proto() == PROCESS_PROTO {
+        return OP_ADMIN;
+    }
+    if tag.proto() != FS_PROTO {
+        return 0;
+    }
+    match tag.label() {
+        fs::OPEN | proto::OPEN_ASYNC => open_ops(utcb.get_mr(0)),
+        proto::OPENAT => open_ops(utcb.get_mr(1)),
+        fs::READ_SYNC | fs::SETUP_IOURING | fs::PROCESS_IOURING => OP_READ,
+        proto::READ_NEXT => OP_READ,
+        proto::LOCK | proto::UNLOCK | proto::CLONE | proto::RING_NOTIFY => OP_READ,
+        fs::WRITE_SYNC | fs::TRUNCATE | fs::MKDIR | proto::LINK => OP_WRITE,
+        proto::SET_TIMES | proto::FALLOCATE | proto::WRITE_NEXT => OP_WRITE,
+        proto::HISTORY_RESTORE => OP_WRITE,
+        fs::UNLINK | fs::RENAME | proto::RMTREE => OP_UNLINK,
+        fs::STAT | fs::STAT_PATH | fs::GETDENTS | proto::HISTORY_LIST => OP_METADATA,
+        proto::GETXATTR | proto::LISTXATTR => OP_METADATA,
+        proto::EVENT_SUBSCRIBE | proto::EVENT_READ => OP_METADATA,
+        proto::VOLUME_INFO | proto::VOLUME_STATS | proto::HEAT_EXPORT => OP_METADATA,
+        proto::STATFS => OP_METADATA,
+        proto::REMOUNT_RO
+        | proto::REMOUNT_RW
+        | proto::CACHE_TUNE
+        | proto::MOUNT_OPTIONS
+        | proto::MOUNT_AT
+        | proto::UNMOUNT
+        | proto::ATTACH_DEVICE
+        | proto::ATTACH_LAYER
+        | proto::ATTACH_TRANSPORT
+        | proto::FORMAT
+        | proto::RESIZE
+        | proto::TRIM
+        | proto::SET_OP_MASK
+        | proto::SET_CREDS
+        | proto::SET_CLOCK
+        | proto::ALLOC_POLICY
+        | proto::FRAG_STATS
+        | proto::CHECK
+        | proto::AUDIT_READ
+        | proto::FREEZE
+        | proto::THAW
+        | proto::CBT_EPOCH
+        | proto::CBT_QUERY => OP_ADMIN,
+        _ => 0,
+    }
+}
+
+fn open_ops(flags: usize) -> usize {
+    let flags = OpenFlags::from_bits_truncate(flags);
+    let base = if flags.contains(OpenFlags::O_DIRECTORY) { OP_METADATA } else { OP_READ };
+    if proto::open_mutates(flags) {
+        base | OP_WRITE
+    } else {
+        base
+    }
+}
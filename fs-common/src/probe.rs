@@ -0,0 +1,119 @@
+//! Recognises which filesystem a volume holds from its on-disk magics, so
+//! each service can decline media that is not its format and a supervisor
+//! can try the services in turn instead of knowing the format up front.
+
+use crate::bytes::{le_u16, le_u32};
+use glenda::error::Error;
+
+// Bytes read from the start of the volume; covers every magic below
+pub const PROBE_BYTES: usize = 4096;
+
+const EXT_MAGIC_OFFSET: usize = 1024 + 0x38;
+const EXT_MAGIC: u16 = 0xEF53;
+const INITRD_MAGIC: u32 = 0x99999999;
+// Standard identifier of the first ISO 9660 volume descriptor, past the
+// 32 KiB system area and so past PROBE_BYTES
+const ISO_MAGIC_OFFSET: u64 = 16 * 2048 + 1;
+const ISO_MAGIC: &[u8; 5] = b"CD001";
+// FAT32 keeps a copy of its boot sector at this sector. The primary that
+// would say otherwise may be the damaged one, so the usual place is assumed.
+const FAT_BACKUP_BOOT_SECTOR: u64 = 6;
+
+// A service that declined a volume exits with this bit set and the
+// detected FsType in the low bits.
+pub const EXIT_DECLINED: usize = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum FsType {
+    Unknown = 0,
+    Ext = 1,
+    Fat = 2,
+    ExFat = 3,
+    Initrd = 4,
+    Iso9660 = 5,
+}
+
+impl FsType {
+    /// Exit code telling the supervisor the volume was declined and what it holds.
+    pub fn declined_exit(self) -> usize {
+        EXIT_DECLINED | self as usize
+    }
+}
+
+/// Reads the start of the volume through `read(offset, buf)` and names the
+/// filesystem on it.
+pub fn probe<F>(mut read: F) -> Result<FsType, Error>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    let mut buf = [0u8; PROBE_BYTES];
+    read(0, &mut buf)?;
+    let found = identify(&buf);
+    if found != FsType::Unknown {
+        return Ok(found);
+    }
+    if fat_backup(&mut read).is_some() {
+        return Ok(FsType::Fat);
+    }
+    // A volume too small to reach it holds no ISO 9660 either
+    let mut magic = [0u8; 5];
+    let iso = read(ISO_MAGIC_OFFSET, &mut magic).is_ok() && &magic == ISO_MAGIC;
+    Ok(if iso { FsType::Iso9660 } else { FsType::Unknown })
+}
+
+/// Names the filesystem whose first PROBE_BYTES bytes are `buf`. ISO 9660
+/// keeps its magic further in, so only `probe` recognises it.
+pub fn identify(buf: &[u8; PROBE_BYTES]) -> FsType {
+    if le_u32(buf, 0).is_ok_and(|m| m == INITRD_MAGIC) {
+        return FsType::Initrd;
+    }
+    if &buf[3..11] == b"EXFAT   " {
+        return FsType::ExFat;
+    }
+    if le_u16(buf, EXT_MAGIC_OFFSET).is_ok_and(|m| m == EXT_MAGIC) {
+        return FsType::Ext;
+    }
+    if is_fat_bpb(buf) {
+        return FsType::Fat;
+    }
+    FsType::Unknown
+}
+
+/// Byte offset of a usable FAT32 backup boot sector, for a volume whose
+/// primary one is damaged. Each sector size is tried, since the backup's
+/// place is counted in sectors.
+pub fn fat_backup<F>(mut read: F) -> Option<u64>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<(), Error>,
+{
+    let mut buf = [0u8; 512];
+    [512u16, 1024, 2048, 4096].into_iter().find_map(|sector_size| {
+        let offset = FAT_BACKUP_BOOT_SECTOR * sector_size as u64;
+        read(offset, &mut buf).ok()?;
+        // Only FAT32 has a backup, and it leaves the 16-bit FAT size zero
+        let fat32 = le_u16(&buf, 22).is_ok_and(|fat_sz_16| fat_sz_16 == 0);
+        let matches = le_u16(&buf, 11).is_ok_and(|size| size == sector_size);
+        (is_fat_bpb(&buf) && fat32 && matches).then_some(offset)
+    })
+}
+
+/// Whether `buf` holds a FAT boot sector: a jump instruction, a power-of-two
+/// sector size, a non-zero power-of-two cluster size, reserved sectors, at
+/// least one FAT and the 0x55AA signature.
+pub fn is_fat_bpb(buf: &[u8]) -> bool {
+    if buf.len() < 512 {
+        return false;
+    }
+    let jump = buf[0] == 0xEB && buf[2] == 0x90 || buf[0] == 0xE9;
+    let bytes_per_sector = le_u16(buf, 11).unwrap_or(0);
+    let sectors_per_cluster = buf[13];
+    let reserved = le_u16(buf, 14).unwrap_or(0);
+    jump && (512..=4096).contains(&bytes_per_sector)
+        && bytes_per_sector.is_power_of_two()
+        && sectors_per_cluster.is_power_of_two()
+        && reserved != 0
+        && buf[16] != 0
+        && buf[510] == 0x55
+        && buf[511] == 0xAA
+}
@@ -0,0 +1,289 @@
+//! FS_PROTO labels layered on top of `glenda::protocol::fs`.
+//!
+//! Extension labels start at `EXT_BASE` so they never collide with upstream ones.
+
+use glenda::protocol::fs::{DEntry, OpenFlags};
+
+pub const EXT_BASE: usize = 0x100;
+
+// MR0: directory handle, MR1: flags, MR2: mode, buffer: path relative to the directory
+pub const OPENAT: usize = EXT_BASE;
+// MR0: handle. Takes another reference; the handle lives until every reference is closed.
+pub const DUP: usize = EXT_BASE + 1;
+// buffer: path. Removes the path and, for a directory, everything below it.
+pub const RMTREE: usize = EXT_BASE + 2;
+// MR0: job id. Returns MR0: JOB_* state, MR1: units done, MR2: total (0 while unknown),
+// MR3: error code of a failed job.
+pub const JOB_STATUS: usize = EXT_BASE + 3;
+// MR0: job id. The job stops before its next step.
+pub const JOB_CANCEL: usize = EXT_BASE + 4;
+// MR0: job id. Forgets a finished job; WouldBlock while it is still running.
+pub const JOB_RELEASE: usize = EXT_BASE + 5;
+
+// Administrative: flips the volume to read-only at once, flushing what can still be
+// flushed, e.g. on low battery or storage errors. Mutating calls then fail with
+// PermissionDenied.
+pub const REMOUNT_RO: usize = EXT_BASE + 6;
+// Administrative: makes a volume flipped by REMOUNT_RO writable again.
+pub const REMOUNT_RW: usize = EXT_BASE + 7;
+// Returns MR0: device::DEV_* flags, MR1: cache type, MR2: IO scheduler, MR3: readahead
+// in filesystem blocks, buffer: device model and serial, each NUL-terminated.
+pub const VOLUME_INFO: usize = EXT_BASE + 8;
+// Administrative: MR0: tune::TUNE_* mask, MR1: cache bytes, MR2: dirty ratio (percent),
+// MR3: flush interval (ms), MR4: readahead in filesystem blocks. Only the fields in the
+// mask change; returns the settings in effect in MR0..MR3, so a zero mask just reads them.
+pub const CACHE_TUNE: usize = EXT_BASE + 9;
+// Returns block layer error counters since mount: MR0: retries, MR1: timeouts,
+// MR2: failed requests, MR3: 512-byte sectors those requests covered.
+pub const VOLUME_STATS: usize = EXT_BASE + 10;
+// MR0: heat::HEAT_RESET or 0. Returns MR0: region size shift, MR1: record count,
+// buffer: hottest regions first, heat::HEAT_RECORD_SIZE bytes each.
+pub const HEAT_EXPORT: usize = EXT_BASE + 11;
+// MR0: 1 to protect this badge's messages with a sequence number and CRC from now on,
+// starting at sequence MR1, 0 to stop. See wire.
+pub const WIRE_PROTECT: usize = EXT_BASE + 12;
+// Sent by the VFS when it mounts the volume. MR0: mount::MNT_* flags. Only accepted while
+// no handles are open (WouldBlock otherwise); returns the flags in effect in MR0, which
+// include MNT_RDONLY when the volume cannot be written anyway.
+pub const MOUNT_OPTIONS: usize = EXT_BASE + 13;
+// Sent by the monitor on the unbadged endpoint when it hands out a badge. MR0: badge,
+// MR1: policy::OP_* mask the badge is limited to; policy::OP_ALL lifts the limit.
+pub const SET_OP_MASK: usize = EXT_BASE + 14;
+// Administrative: MR0: first audit record wanted. Returns MR0: record to ask for next,
+// MR1: records rotated out before the first returned, buffer: records one per line.
+// NotSupported unless the service is built with auditing. See audit.
+pub const AUDIT_READ: usize = EXT_BASE + 15;
+// Administrative: quiesces the volume for a block-level snapshot. Buffered writes and
+// cached metadata are written out and background jobs pause; until THAW, calls that
+// would modify the volume fail with WouldBlock.
+pub const FREEZE: usize = EXT_BASE + 16;
+pub const THAW: usize = EXT_BASE + 17;
+// Administrative: starts a new change-tracking epoch, typically right after FREEZE when
+// taking a snapshot. Returns MR0: the new epoch; writes from now on are counted in it.
+pub const CBT_EPOCH: usize = EXT_BASE + 18;
+// Administrative: MR0: epoch, MR1: chunk to start from (0 at first). Returns MR0: chunk
+// size shift, MR1: record count, MR2: chunk to continue from or 0 when done, buffer: runs
+// of chunks written since the epoch began, cbt::CBT_RECORD_SIZE bytes each. NotFound
+// when the epoch is older than what tracking still covers; take a full backup then.
+pub const CBT_QUERY: usize = EXT_BASE + 19;
+// MR0: handle, MR1: start, MR2: length (0: to the end of the file), MR3: locks::LOCK_*
+// flags. Takes an advisory byte-range lock for the handle; WouldBlock while another
+// handle holds a conflicting one. See locks.
+pub const LOCK: usize = EXT_BASE + 20;
+// MR0: handle, MR1: start, MR2: length (0: to the end of the file).
+pub const UNLOCK: usize = EXT_BASE + 21;
+// MR0: open file handle. Returns MR0: a new read-only handle on the same file with its
+// own position, sharing the original's caches; cheaper than opening the path again.
+pub const CLONE: usize = EXT_BASE + 22;
+// MR0: handle with a ring, MR1: notification bits. The call transfers an endpoint the
+// service notifies with those bits whenever it posted completions on the ring. The
+// client then needs no PROCESS_IOURING: notifying the service endpoint with
+// RING_DOORBELL_BITS has it serve every ring the notifying badge registered this way.
+pub const RING_NOTIFY: usize = EXT_BASE + 23;
+// MR0: newest protocol version the client speaks, MR1: version::FEAT_* bits it understands.
+// Returns MR0: the version in use, MR1: the features both sides have. See version.
+pub const VERSION: usize = EXT_BASE + 24;
+// Administrative: buffer: absolute path. Moves the service's entry in the VFS namespace
+// there; an empty path takes it out. NotSupported when the service runs without a VFS.
+pub const MOUNT_AT: usize = EXT_BASE + 25;
+// Registers and reply as OPEN. The service parks the call and resolves the path one
+// component at a time between other requests, for backends where lookups are slow.
+// WouldBlock while too many opens are parked. See deferred.
+pub const OPEN_ASYNC: usize = EXT_BASE + 26;
+// Administrative: stops the service cleanly. Running jobs are cancelled, every open
+// handle is closed with its buffered data written out, the volume is flushed and the
+// service leaves the VFS namespace; calls still parked fail with NotInitialized. The
+// service exits with status 0 after the reply. WouldBlock while frozen.
+pub const UNMOUNT: usize = EXT_BASE + 27;
+// buffer: path of a file in a directory that keeps history. Returns MR0: record count,
+// buffer: its previous versions newest first, history::VERSION_RECORD_SIZE bytes each.
+// NotFound when the directory keeps none. See history.
+pub const HISTORY_LIST: usize = EXT_BASE + 28;
+// MR0: generation, buffer: path. Copies that version back over the file; what it
+// replaces is kept as the newest version.
+pub const HISTORY_RESTORE: usize = EXT_BASE + 29;
+// buffer: existing path and new path, NUL-terminated as with RENAME. Adds the new path as
+// another name of the same file; AlreadyExists if it is taken, PermissionDenied for a
+// directory.
+pub const LINK: usize = EXT_BASE + 30;
+// MR0: handle, buffer: attribute name with its namespace ("user.", "security.", ...).
+// Returns MR0: value length, buffer: the value; MessageTooLong when it does not fit.
+pub const GETXATTR: usize = EXT_BASE + 31;
+// MR0: handle. Returns MR0: bytes in the buffer, buffer: attribute names, each NUL-terminated.
+pub const LISTXATTR: usize = EXT_BASE + 32;
+// Sent by the monitor on the unbadged endpoint, like SET_OP_MASK. MR0: badge, MR1: uid,
+// MR2: gid the badge acts as for owner and mode checks; uid 0 is root and passes them
+// all. Badges never mapped act as root. NotSupported on filesystems without owners.
+pub const SET_CREDS: usize = EXT_BASE + 33;
+// MR0: events::EV_* bits. The call transfers an endpoint the service notifies with the
+// bits of each such event; without one, it ends the caller's subscription. Returns MR0:
+// sequence number of the next event. See events.
+pub const EVENT_SUBSCRIBE: usize = EXT_BASE + 34;
+// MR0: first event wanted. Returns MR0: event to ask for next, MR1: events rotated out
+// before the first returned, MR2: record count, buffer: events::EVENT_RECORD_SIZE each.
+pub const EVENT_READ: usize = EXT_BASE + 35;
+// Administrative, unbadged endpoint only. MR0: seconds since the epoch, MR1: nanoseconds.
+// Sets the time files are stamped with; see clock.
+pub const SET_CLOCK: usize = EXT_BASE + 36;
+// buffer: path, MR0/MR1: access time seconds and nanoseconds, MR2/MR3: modification time.
+// A nanosecond field of UTIME_NOW takes the current time, UTIME_OMIT leaves the time
+// alone. Explicit times are for the owner only; UTIME_NOW also for anyone who may write.
+pub const SET_TIMES: usize = EXT_BASE + 37;
+// Returns MR0: block size in bytes, MR1: total blocks, MR2: free blocks, MR3: blocks free
+// for unprivileged use, MR4: total inodes, MR5: free inodes (both 0 without an inode table).
+pub const STATFS: usize = EXT_BASE + 38;
+// Administrative: MR0: ALLOC_* policy for clusters allocated from now on. Returns MR0: the
+// policy it replaced. NotSupported on filesystems that allocate otherwise.
+pub const ALLOC_POLICY: usize = EXT_BASE + 39;
+// Administrative, for debugging allocation. Walks the allocation table; returns MR0:
+// clusters in use, MR1: free clusters, MR2: free extents, MR3: clusters in the longest
+// free extent, MR4: breaks in cluster chains (0 when every file is contiguous).
+pub const FRAG_STATS: usize = EXT_BASE + 40;
+// MR0: handle, MR1: offset, MR2: length, MR3: FALLOC_* flags. Reserves the blocks of the
+// range not allocated yet, reading as zeros, so writing it later cannot run out of space.
+// The file grows to cover the range unless FALLOC_KEEP_SIZE is set.
+pub const FALLOCATE: usize = EXT_BASE + 41;
+// Sequential I/O at the handle position, which each call advances, for clients that would
+// rather not track offsets. READ_NEXT: MR0: handle, MR1: length, at most the buffer size.
+// Returns MR0: bytes read, buffer: the data. WRITE_NEXT: MR0: handle, buffer: data.
+// Returns MR0: bytes written.
+pub const READ_NEXT: usize = EXT_BASE + 42;
+pub const WRITE_NEXT: usize = EXT_BASE + 43;
+// Administrative, unbadged endpoint only. Serves one more volume from the same service:
+// the call transfers the endpoint of its block device; MR0: mount::MNT_* flags, MR1:
+// partition::PartitionSelect as raw (0 for the default), buffer: path to mount it at in
+// the VFS, empty for none. Returns MR0: volume index, with an endpoint of its own, badged
+// for the volume, handed over. UNMOUNT through that endpoint detaches only the volume.
+pub const ATTACH_DEVICE: usize = EXT_BASE + 44;
+// Administrative. MR0: CHECK_* flags. Runs the consistency check over allocation counts,
+// the orphan list and the directory tree; findings go to the log. Returns MR0: checks
+// made, MR1: issues found, MR2: issues repaired. CHECK_REPAIR needs a writable volume.
+pub const CHECK: usize = EXT_BASE + 45;
+// Administrative, unbadged endpoint only. Hands an overlay service one of the filesystems
+// it stacks: the call transfers that service's endpoint; MR0: LAYER_LOWER or LAYER_UPPER.
+// Fails with WouldBlock while handles are open.
+pub const ATTACH_LAYER: usize = EXT_BASE + 46;
+// Administrative, unbadged endpoint only. Hands a network filesystem client the transport
+// to its file server: the call transfers the transport's endpoint; MR0: uid to attach as,
+// buffer: name of the tree to attach, empty for the server's default. Fails with
+// WouldBlock while handles are open.
+pub const ATTACH_TRANSPORT: usize = EXT_BASE + 47;
+// Spoken to a 9P transport (a virtio-9p driver or a socket relay), not to a filesystem.
+// buffer: one 9P T-message; the R-message comes back in the buffer.
+pub const NINEP_RPC: usize = EXT_BASE + 48;
+// Administrative, unbadged endpoint only. Writes a fresh, empty filesystem over the
+// mounted volume and mounts it in place of the old one: FAT32 from fatfs, ext2 from
+// extfs. MR0: size in bytes, 0 for the whole partition or device; buffer: volume label,
+// may be empty. Fails with WouldBlock while handles are open, InvalidArgs for a size the
+// format cannot use or the device cannot hold.
+pub const FORMAT: usize = EXT_BASE + 49;
+// Administrative. Grows the mounted volume while it stays in use, after the device or
+// partition under it was enlarged; extfs only. MR0: new size in bytes, 0 for the whole
+// partition or device. Returns MR0: the size now, which stops short of MR0 by what would not make
+// a whole block group. Fails with InvalidArgs for a smaller size, NotSupported when the
+// group descriptor table has no room left for another group.
+pub const RESIZE: usize = EXT_BASE + 50;
+// Administrative. Discards the volume's free space on the device, as FITRIM does.
+// MR0: shortest free run worth discarding in bytes, 0 for any. Returns MR0: bytes
+// discarded. Fails with NotSupported when the device cannot discard.
+pub const TRIM: usize = EXT_BASE + 51;
+
+// SET_TIMES nanosecond values, as with utimensat
+pub const UTIME_NOW: usize = (1 << 30) - 1;
+pub const UTIME_OMIT: usize = (1 << 30) - 2;
+
+// ALLOC_POLICY values: the first free cluster after the last one allocated, or growing
+// chains in place and placing the rest in the best-fitting free extent
+pub const ALLOC_NEXT_FREE: usize = 0;
+pub const ALLOC_CONTIGUOUS: usize = 1;
+
+// FALLOCATE flag: reserve the blocks but leave the size alone, as with fallocate(2)
+pub const FALLOC_KEEP_SIZE: usize = 1;
+
+// CHECK flag: rewrite what can be derived again, such as free counts from the bitmaps
+pub const CHECK_REPAIR: usize = 1;
+
+// ATTACH_LAYER: the read-only layer below, or the writable one changes go to
+pub const LAYER_LOWER: usize = 0;
+pub const LAYER_UPPER: usize = 1;
+
+// MR0 flag of RMTREE: run it as a job and return the job id in MR0. The call
+// transfers a notification endpoint, signalled with JOB_NOTIFY_BITS on completion.
+pub const JOB_ASYNC: usize = 1;
+pub const JOB_NOTIFY_BITS: usize = 1;
+
+// Notification bits a client rings the service with after submitting; see RING_NOTIFY
+pub const RING_DOORBELL_BITS: usize = 1;
+// Notification bits a service rings itself with while parked calls still have
+// work, so its next recv returns at once instead of waiting for another call
+pub const WAKE_BITS: usize = 2;
+
+pub const JOB_RUNNING: usize = 0;
+pub const JOB_DONE: usize = 1;
+pub const JOB_FAILED: usize = 2;
+pub const JOB_CANCELLED: usize = 3;
+
+// MR0: handle, MR1: offset (i64), MR2: whence. Returns the new position in MR0.
+// fs::SEEK is upstream; these are its whence values.
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+// The next byte at or after the offset that is data, or the start of the next hole; as
+// with lseek, NotFound when the offset is at or past the end. Files a filesystem cannot
+// tell holes in are all data, followed by the hole at their end.
+pub const SEEK_DATA: usize = 3;
+pub const SEEK_HOLE: usize = 4;
+
+// READ_SYNC/WRITE_SYNC offset meaning "at the handle position, then advance it"
+pub const CURRENT_OFFSET: usize = usize::MAX;
+
+// STAT_PATH replies carry in MR2 how long (ms) the client may cache the attributes; those
+// of services that keep times carry the access, modification and change times (seconds)
+// in MR3-MR5
+pub const ATTR_TIMEOUT_MS: usize = 1000;
+// Attributes that never change (read-only images)
+pub const ATTR_TIMEOUT_NEVER: usize = usize::MAX;
+
+// DEntry::type_ values (same numbering as POSIX dirent d_type)
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+
+pub fn dentry(ino: usize, off: usize, type_: u8, name: &[u8]) -> DEntry {
+    let mut entry = DEntry::default();
+    let len = core::cmp::min(name.len(), entry.name.len() - 1);
+    entry.ino = ino;
+    entry.off = off;
+    entry.type_ = type_;
+    entry.reclen = core::mem::size_of::<DEntry>() as u16;
+    entry.name[..len].copy_from_slice(&name[..len]);
+    entry
+}
+
+/// Whether an OPEN with `flags` may modify the file.
+pub fn open_mutates(flags: OpenFlags) -> bool {
+    flags.intersects(
+        OpenFlags::O_WRONLY
+            | OpenFlags::O_RDWR
+            | OpenFlags::O_CREAT
+            | OpenFlags::O_TRUNC
+            | OpenFlags::O_APPEND,
+    )
+}
+
+/// Number of DEntry records that fit into an IPC buffer.
+pub fn dents_capacity(buf: &[u8]) -> usize {
+    buf.len() / core::mem::size_of::<DEntry>()
+}
+
+/// Packs `entries` back to back into `buf`, returning how many were written.
+pub fn encode_dents(buf: &mut [u8], entries: &[DEntry]) -> usize {
+    let size = core::mem::size_of::<DEntry>();
+    let count = core::cmp::min(entries.len(), dents_capacity(buf));
+    for (i, entry) in entries.iter().take(count).enumerate() {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(entry as *const DEntry as *const u8, size) };
+        buf[i * size..(i + 1) * size].copy_from_slice(bytes);
+    }
+    count
+}
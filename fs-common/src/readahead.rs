@@ -0,0 +1,149 @@
+//! Readahead for sequential reads. Each handle keeps a `Readahead` that
+//! watches where its reads land; once they follow on from one another it
+//! asks for the blocks past the read to be fetched as well, in a window that
+//! doubles with every sequential read up to the tuned readahead size. The
+//! first read elsewhere closes the window again, so random access costs
+//! nothing extra.
+//!
+//! Prefetched blocks wait in a `PrefetchCache` shared by every clone of a
+//! block reader, where the reads that follow find them. The volume client
+//! only offers synchronous transfers, so the gain is in the device round
+//! trips: a window goes out as a few large requests instead of one per block.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Window, in filesystem blocks, opened by the first sequential read
+const INITIAL_WINDOW: usize = 4;
+// Sequential reads in a row before anything is read ahead
+const SEQUENTIAL_TRIGGER: usize = 2;
+// Bytes of prefetched blocks kept before the oldest are dropped
+pub const PREFETCH_MAX_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct Readahead {
+    // File offset the next read starts at if it is sequential
+    next: Option<usize>,
+    // Sequential reads in a row so far
+    streak: usize,
+    window: usize,
+    // File offset up to which blocks were already asked for
+    ahead: usize,
+}
+
+impl Readahead {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a read of `len` bytes at `offset` into a file of `size` bytes.
+    /// Returns the byte range (offset, len) of the file to prefetch, if any:
+    /// at most `max_blocks` blocks of `block_size` bytes past what earlier
+    /// calls asked for.
+    pub fn advise(
+        &mut self,
+        offset: usize,
+        len: usize,
+        size: usize,
+        block_size: usize,
+        max_blocks: usize,
+    ) -> Option<(usize, usize)> {
+        let end = offset.saturating_add(len);
+        if self.next == Some(offset) {
+            self.streak += 1;
+        } else {
+            // A read elsewhere: whatever was fetched for the old run is left
+            // to age out of the cache
+            self.streak = usize::from(offset == 0);
+            self.window = 0;
+            self.ahead = end;
+        }
+        self.next = Some(end);
+        if self.streak < SEQUENTIAL_TRIGGER || max_blocks == 0 || len == 0 {
+            return None;
+        }
+
+        self.window = match self.window {
+            0 => INITIAL_WINDOW,
+            window => window * 2,
+        }
+        .min(max_blocks);
+        // Nothing new until the reader is into the second half of the window
+        let window = self.window * block_size;
+        if self.ahead > end && self.ahead - end > window / 2 {
+            return None;
+        }
+        let start = self.ahead.max(end).next_multiple_of(block_size);
+        let stop = end.saturating_add(window).min(size.next_multiple_of(block_size));
+        if start >= stop {
+            return None;
+        }
+        self.ahead = stop;
+        Some((start, stop - start))
+    }
+}
+
+/// Device blocks read ahead of the reads that will want them, oldest first.
+pub struct PrefetchCache {
+    blocks: Mutex<VecDeque<(usize, Vec<u8>)>>,
+}
+
+impl PrefetchCache {
+    pub fn new() -> Self {
+        Self { blocks: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Keeps `data`, read at device byte `offset`, for a later `take`.
+    pub fn insert(&self, offset: usize, data: Vec<u8>) {
+        let mut blocks = self.blocks.lock();
+        blocks.retain(|(at, block)| !overlaps(*at, block.len(), offset, data.len()));
+        let mut held: usize = blocks.iter().map(|(_, block)| block.len()).sum();
+        while held + data.len() > PREFETCH_MAX_BYTES {
+            match blocks.pop_front() {
+                Some((_, block)) => held -= block.len(),
+                None => return,
+            }
+        }
+        blocks.push_back((offset, data));
+    }
+
+    /// Fills `buf` from a prefetched block holding all of it. A sequential
+    /// reader passes every block once, so a block is dropped once its last
+    /// byte has been taken.
+    pub fn take(&self, offset: usize, buf: &mut [u8]) -> bool {
+        let mut blocks = self.blocks.lock();
+        let Some(i) = blocks
+            .iter()
+            .position(|(at, block)| *at <= offset && offset + buf.len() <= at + block.len())
+        else {
+            return false;
+        };
+        let (at, block) = &blocks[i];
+        let from = offset - at;
+        buf.copy_from_slice(&block[from..from + buf.len()]);
+        if from + buf.len() == block.len() {
+            blocks.remove(i);
+        }
+        true
+    }
+
+    /// Drops whatever was prefetched of `len` bytes at device byte `offset`,
+    /// which are being written.
+    pub fn invalidate(&self, offset: usize, len: usize) {
+        let mut blocks = self.blocks.lock();
+        if !blocks.is_empty() {
+            blocks.retain(|(at, block)| !overlaps(*at, block.len(), offset, len));
+        }
+    }
+}
+
+impl Default for PrefetchCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn overlaps(a: usize, a_len: usize, b: usize, b_len: usize) -> bool {
+    a < b + b_len && b < a + a_len
+}
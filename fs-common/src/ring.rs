@@ -0,0 +1,338 @@
+//! Client rings set up on a handle with SETUP_IOURING. The submission and
+//! completion queues sit at the start of memory the client shares with the
+//! service, and the data buffers submissions point at live in the same
+//! memory, so reads and writes need no copy through IPC. Rings registered with
+//! RING_NOTIFY are also served on a doorbell notification and announce their
+//! completions with one, so the client never has to wait on a call.
+
+use crate::audit::AuditLog;
+use crate::badge;
+use crate::locks::{Grant, LockTable, IOURING_OP_LOCK, IOURING_OP_UNLOCK};
+use crate::proto::CURRENT_OFFSET;
+use crate::stream::{Stream, IOURING_OP_READ_STREAM, MAX_STREAMS};
+use alloc::vec::Vec;
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::io::uring::{
+    IoUringBuffer, IoUringCqe, IOURING_OP_FSYNC, IOURING_OP_NOP, IOURING_OP_READ, IOURING_OP_WRITE,
+};
+use glenda::ipc::{Badge, UTCB};
+
+// Vectored SQEs: `addr` is the client address of an array of `len` iovecs in the
+// shared memory, `off` the file offset the first one starts at.
+pub const IOURING_OP_READV: u8 = 0x42;
+pub const IOURING_OP_WRITEV: u8 = 0x43;
+// Iovecs a single vectored SQE may carry
+pub const IOV_MAX: usize = 64;
+// Iovec: client buffer address (u64 LE), length (u64 LE)
+pub const IOVEC_SIZE: usize = 16;
+
+/// Memory shared with a client, mapped at `server_base` here and at
+/// `user_base` in the client.
+#[derive(Clone, Copy)]
+pub struct ShmWindow {
+    pub user_base: usize,
+    pub server_base: usize,
+    pub size: usize,
+}
+
+impl ShmWindow {
+    /// Service address of the `len` bytes the client sees at `addr`;
+    /// InvalidArgs unless they lie within the window.
+    pub fn translate(&self, addr: u64, len: usize) -> Result<usize, Error> {
+        let addr = usize::try_from(addr).map_err(|_| Error::InvalidArgs)?;
+        let at = addr.checked_sub(self.user_base).ok_or(Error::InvalidArgs)?;
+        let end = at.checked_add(len).ok_or(Error::InvalidArgs)?;
+        if end > self.size {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(self.server_base + at)
+    }
+
+    /// Decodes the `count` iovecs at client address `addr` into (client
+    /// address, length) pairs, each checked to lie within the window.
+    pub fn iovecs(&self, addr: u64, count: usize) -> Result<Vec<(u64, usize)>, Error> {
+        if count == 0 || count > IOV_MAX {
+            return Err(Error::InvalidArgs);
+        }
+        let table = self.translate(addr, count * IOVEC_SIZE)?;
+        let table = unsafe { core::slice::from_raw_parts(table as *const u8, count * IOVEC_SIZE) };
+        let mut iov = Vec::with_capacity(count);
+        for raw in table.chunks_exact(IOVEC_SIZE) {
+            let base = u64::from_le_bytes(raw[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(raw[8..].try_into().unwrap());
+            let len = usize::try_from(len).map_err(|_| Error::InvalidArgs)?;
+            self.translate(base, len)?;
+            iov.push((base, len));
+        }
+        Ok(iov)
+    }
+}
+
+// Puts a submission on record for the client behind `badge`
+fn audit(
+    audit: &mut Option<(&mut AuditLog, &str)>,
+    badge: Badge,
+    opcode: u8,
+    result: Result<(), Error>,
+) {
+    if let Some((log, path)) = audit {
+        log.record_sqe(badge::client(badge.bits()), opcode, path, result);
+    }
+}
+
+/// Runs `op(offset, buffer)` over the iovecs in turn, the file offset moving
+/// on by what each transferred, until one comes up short. An error after some
+/// bytes went through ends the transfer with those counted.
+pub fn transfer_vectored<F>(iov: &[(u64, usize)], offset: usize, mut op: F) -> Result<usize, Error>
+where
+    F: FnMut(u64, usize, usize) -> Result<usize, Error>,
+{
+    let mut done = 0;
+    for &(addr, len) in iov {
+        let at = if offset == CURRENT_OFFSET { CURRENT_OFFSET } else { offset + done };
+        let n = match op(addr, at, len) {
+            Ok(n) => n,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        };
+        done += n;
+        if n < len {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// The completion of `user_data`: the byte count on success, the negated
+/// error code otherwise.
+pub fn completion(user_data: u64, res: Result<usize, Error>, flags: u32) -> IoUringCqe {
+    let res = match res {
+        Ok(n) => i32::try_from(n).unwrap_or(i32::MAX),
+        Err(e) => -(e as i32),
+    };
+    IoUringCqe { user_data, res, flags }
+}
+
+/// Bits of a notification received on the service endpoint, or None when
+/// `utcb` holds a call. Notifications carry no protocol in their tag and
+/// expect no reply.
+pub fn notification(utcb: &UTCB) -> Option<usize> {
+    (utcb.get_msg_tag().proto() == 0).then(|| utcb.get_mr(0))
+}
+
+/// Handles of a service whose rings a doorbell from `badge` serves, out of
+/// every handle key and the ring set up on it, if any.
+pub fn rung<'r>(
+    rings: impl IntoIterator<Item = (usize, Option<&'r SharedRing>)>,
+    badge: usize,
+) -> Vec<usize> {
+    rings
+        .into_iter()
+        .filter(|(_, ring)| ring.is_some_and(|r| r.rung_by(badge)))
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Completes the ring lock submissions an unlock let through, each on the
+/// ring `ring_of` finds for the handle that owns it.
+pub fn complete_grants<'r>(grants: Vec<Grant>, ring_of: impl Fn(usize) -> Option<&'r SharedRing>) {
+    for grant in grants {
+        if let Some(ring) = ring_of(grant.owner) {
+            ring.complete(grant.user_data, Ok(0));
+            ring.announce();
+        }
+    }
+}
+
+/// Where a ring's completions are announced, as registered with RING_NOTIFY.
+#[derive(Clone, Copy)]
+pub struct RingSignal {
+    pub notify: Endpoint,
+    pub bits: usize,
+    /// The badge whose doorbell serves the ring.
+    pub badge: usize,
+}
+
+impl RingSignal {
+    pub fn raise(&self) {
+        let _ = self.notify.notify(self.bits);
+    }
+}
+
+pub struct SharedRing {
+    ring: IoUringBuffer,
+    window: ShmWindow,
+    signal: Option<RingSignal>,
+    // READ_STREAM submissions still pushing chunks
+    streams: Vec<Stream>,
+}
+
+/// What the submissions on a ring may do, as decided by the service.
+pub struct RingAccess<'a, K> {
+    /// The service's verdict on modifying the volume, returned for WRITE.
+    pub writable: Result<(), Error>,
+    /// Set between FREEZE and THAW; FSYNC fails with WouldBlock.
+    pub frozen: bool,
+    pub locks: &'a mut LockTable<K>,
+    /// The handle's file in `locks` and the handle itself as lock owner.
+    pub key: &'a K,
+    pub owner: usize,
+    /// Where the writes and locks submitted go on record, with the file's path.
+    pub audit: Option<(&'a mut AuditLog, &'a str)>,
+}
+
+impl SharedRing {
+    /// Attaches to the ring at the start of `size` bytes of shared memory,
+    /// mapped at `server_base` here and at `user_base` in the client.
+    pub fn attach(server_base: usize, user_base: usize, size: usize) -> Self {
+        let ring = unsafe { IoUringBuffer::attach(server_base as *mut u8, size) };
+        let window = ShmWindow { user_base, server_base, size };
+        Self { ring, window, signal: None, streams: Vec::new() }
+    }
+
+    /// Where the shared memory is mapped for the client and for the service.
+    pub fn window(&self) -> &ShmWindow {
+        &self.window
+    }
+
+    /// Registers where completions get announced, returning the previous
+    /// registration for the caller to dispose of.
+    pub fn set_signal(&mut self, signal: RingSignal) -> Option<RingSignal> {
+        self.signal.replace(signal)
+    }
+
+    /// Whether a doorbell from `badge` serves this ring.
+    pub fn rung_by(&self, badge: usize) -> bool {
+        self.signal.is_some_and(|s| s.badge == badge)
+    }
+
+    /// Tells the client new completions are waiting, if it asked to be told.
+    pub fn announce(&self) {
+        if let Some(signal) = &self.signal {
+            signal.raise();
+        }
+    }
+
+    /// The `len` bytes the client sees at `addr`; InvalidArgs unless they lie
+    /// within the shared memory.
+    fn buffer_mut(&mut self, addr: u64, len: usize) -> Result<&mut [u8], Error> {
+        let at = self.window.translate(addr, len)?;
+        Ok(unsafe { core::slice::from_raw_parts_mut(at as *mut u8, len) })
+    }
+
+    /// Posts a completion: the byte count on success, the negated error code
+    /// otherwise.
+    pub fn complete(&self, user_data: u64, res: Result<usize, Error>) {
+        self.ring.push_cqe(completion(user_data, res, 0)).ok();
+    }
+
+    /// Serves every queued submission through `handle`, then moves the
+    /// streams on as far as their free buffers allow. Returns the queued
+    /// locks, of this or other handles, that an unlock here let through;
+    /// the service completes those on their own rings.
+    pub fn process<K: Ord + Clone, H: FileHandleService + ?Sized>(
+        &mut self,
+        handle: &mut H,
+        badge: Badge,
+        mut access: RingAccess<'_, K>,
+    ) -> Vec<Grant> {
+        let mut grants = Vec::new();
+        while let Some(sqe) = self.ring.pop_sqe() {
+            let offset = sqe.off as usize;
+            let len = sqe.len as usize;
+            let res = match sqe.opcode {
+                IOURING_OP_NOP => Ok(0),
+                IOURING_OP_READ => {
+                    self.buffer_mut(sqe.addr, len).and_then(|buf| handle.read(badge, offset, buf))
+                }
+                IOURING_OP_WRITE => access
+                    .writable
+                    .and_then(|_| self.buffer_mut(sqe.addr, len))
+                    .and_then(|buf| handle.write(badge, offset, buf)),
+                IOURING_OP_READV => self.window.iovecs(sqe.addr, len).and_then(|iov| {
+                    transfer_vectored(&iov, offset, |addr, at, len| {
+                        handle.read(badge, at, self.buffer_mut(addr, len)?)
+                    })
+                }),
+                IOURING_OP_WRITEV => access
+                    .writable
+                    .and_then(|_| self.window.iovecs(sqe.addr, len))
+                    .and_then(|iov| {
+                        transfer_vectored(&iov, offset, |addr, at, len| {
+                            handle.write(badge, at, self.buffer_mut(addr, len)?)
+                        })
+                    }),
+                IOURING_OP_FSYNC if access.frozen => Err(Error::WouldBlock),
+                IOURING_OP_FSYNC => handle.sync(badge).map(|_| 0),
+                IOURING_OP_LOCK => {
+                    let flags = sqe.addr as usize;
+                    let (start, len) = (sqe.off, sqe.len as u64);
+                    match access.locks.lock_or_wait(
+                        access.key,
+                        access.owner,
+                        start,
+                        len,
+                        flags,
+                        sqe.user_data,
+                    ) {
+                        Ok(true) => Ok(0),
+                        // Completed by whichever unlock lets it through
+                        Ok(false) => {
+                            audit(&mut access.audit, badge, sqe.opcode, Ok(()));
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                IOURING_OP_UNLOCK => access
+                    .locks
+                    .unlock(access.key, access.owner, sqe.off, sqe.len as u64)
+                    .map(|granted| {
+                        grants.extend(granted);
+                        0
+                    }),
+                IOURING_OP_READ_STREAM if self.streams.len() == MAX_STREAMS => {
+                    Err(Error::OutOfMemory)
+                }
+                IOURING_OP_READ_STREAM => {
+                    match Stream::start(&self.window, sqe.user_data, sqe.addr, sqe.off, sqe.len) {
+                        Ok(stream) => {
+                            self.streams.push(stream);
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                _ => Err(Error::NotSupported),
+            };
+            audit(&mut access.audit, badge, sqe.opcode, res.map(|_| ()));
+            self.complete(sqe.user_data, res);
+        }
+        self.pump_streams(handle, badge);
+        grants
+    }
+
+    fn pump_streams<H: FileHandleService + ?Sized>(&mut self, handle: &mut H, badge: Badge) {
+        let (window, ring) = (self.window, &self.ring);
+        self.streams.retain_mut(|stream| {
+            let read = |addr, at, len| {
+                let buf = window.translate(addr, len)?;
+                handle.read(badge, at, unsafe {
+                    core::slice::from_raw_parts_mut(buf as *mut u8, len)
+                })
+            };
+            let post = |user_data, res, flags| {
+                ring.push_cqe(completion(user_data, res, flags)).ok();
+            };
+            match stream.pump(&window, read, post) {
+                Some(res) => {
+                    ring.push_cqe(completion(stream.user_data(), res, 0)).ok();
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+}
@@ -0,0 +1,50 @@
+//! Health summary of the quick metadata scrub a server can run at mount, and
+//! of the consistency checks that may repair what they find.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[derive(Default)]
+pub struct ScrubReport {
+    pub checked: usize,
+    pub issues: Vec<String>,
+    // Issues that were put right on disk
+    pub repaired: usize,
+}
+
+impl ScrubReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts one check and records `issue` when it did not hold.
+    pub fn check(&mut self, ok: bool, issue: impl FnOnce() -> String) {
+        self.checked += 1;
+        if !ok {
+            self.issues.push(issue());
+        }
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Issues found and not repaired.
+    pub fn outstanding(&self) -> usize {
+        self.issues.len().saturating_sub(self.repaired)
+    }
+}
+
+impl fmt::Display for ScrubReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_clean() {
+            write!(f, "{} checks, clean", self.checked)
+        } else if self.repaired > 0 {
+            let (issues, repaired) = (self.issues.len(), self.repaired);
+            write!(f, "{} checks, {} issue(s), {} repaired", self.checked, issues, repaired)
+        } else {
+            write!(f, "{} checks, {} issue(s)", self.checked, self.issues.len())
+        }
+    }
+}
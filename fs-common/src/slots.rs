@@ -0,0 +1,78 @@
+//! Cap slots a service fills at runtime. Single slots come from the
+//! CSpaceManager, which counts up from the first slot past the fixed ones of
+//! the service's layout; `SlotAllocator` holds it below a limit so it never
+//! runs into the receive slot or the runs above. Runs are contiguous slots
+//! for tables that address their caps by index, such as jobs and parked
+//! calls, and are handed out from their own window. Running out is an
+//! OutOfMemory error instead of a cap silently overwritten.
+
+use alloc::collections::BTreeMap;
+use glenda::cap::CapPtr;
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::interface::CSpaceService;
+use glenda::utils::manager::CSpaceManager;
+
+pub struct SlotAllocator<'a> {
+    cspace: &'a mut CSpaceManager,
+    // Single slots must stay below this
+    limit: usize,
+    run_start: usize,
+    run_end: usize,
+    // First slot of each reserved run and its length
+    runs: BTreeMap<usize, usize>,
+}
+
+impl<'a> SlotAllocator<'a> {
+    /// Single slots from `cspace` up to `limit`, and no runs.
+    pub fn new(cspace: &'a mut CSpaceManager, limit: CapPtr) -> Self {
+        Self { cspace, limit: limit.bits(), run_start: 0, run_end: 0, runs: BTreeMap::new() }
+    }
+
+    /// Hands out runs from `start` up to `end`.
+    pub fn with_runs(self, start: CapPtr, end: CapPtr) -> Self {
+        Self { run_start: start.bits(), run_end: end.bits(), ..self }
+    }
+
+    pub fn alloc(&mut self, res_client: &mut ResourceClient) -> Result<CapPtr, Error> {
+        let slot = self.cspace.alloc(res_client)?;
+        if slot.bits() >= self.limit {
+            self.cspace.free(slot);
+            return Err(Error::OutOfMemory);
+        }
+        Ok(slot)
+    }
+
+    pub fn free(&mut self, slot: CapPtr) {
+        self.cspace.free(slot);
+    }
+
+    /// Reserves `count` contiguous slots, the lowest that are free.
+    pub fn reserve(&mut self, count: usize) -> Result<CapPtr, Error> {
+        if count == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        let mut candidate = self.run_start;
+        for (&base, &len) in &self.runs {
+            if candidate + count <= base {
+                break;
+            }
+            candidate = base + len;
+        }
+        if candidate + count > self.run_end {
+            return Err(Error::OutOfMemory);
+        }
+        self.runs.insert(candidate, count);
+        Ok(CapPtr::from(candidate))
+    }
+
+    /// Gives back the run reserved at `base` once its caps are deleted.
+    pub fn release(&mut self, base: CapPtr) -> Result<usize, Error> {
+        self.runs.remove(&base.bits()).ok_or(Error::NotFound)
+    }
+
+    /// The CSpaceManager itself, for the calls that allocate through it.
+    pub fn cspace(&mut self) -> &mut CSpaceManager {
+        self.cspace
+    }
+}
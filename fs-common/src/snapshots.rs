@@ -0,0 +1,184 @@
+//! The synthetic `.snapshots` directory at the root of every mount. It holds
+//! one directory per snapshot of the volume, and lookups below it are
+//! redirected into a read-only view of that snapshot: `.snapshots/<name>/a/b`
+//! is `/a/b` as it was when `<name>` was taken. Like most hidden snapshot
+//! directories it is left out of the root's listing but can always be opened
+//! by name. Volumes that keep no snapshots serve it empty.
+
+use crate::handle::{FsHandle, ReadOnly};
+use crate::proto::{self, DT_DIR};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::ipc::{Badge, UTCB};
+use glenda::protocol::fs::{self, DEntry, OpenFlags, Stat};
+use glenda::protocol::FS_PROTO;
+
+pub const SNAPSHOT_DIR: &str = ".snapshots";
+
+const S_IFDIR: u32 = 0o040000;
+
+pub enum SnapPath<'a> {
+    /// Not below `.snapshots`: the live filesystem
+    Live,
+    /// `.snapshots` itself
+    Dir,
+    /// `path` in snapshot `name`, relative to its root ("" for the root)
+    In { name: &'a str, path: &'a str },
+}
+
+pub fn classify(path: &str) -> SnapPath<'_> {
+    let path = path.trim_start_matches('/');
+    let rest = match path.strip_prefix(SNAPSHOT_DIR) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_start_matches('/'),
+        _ => return SnapPath::Live,
+    };
+    if rest.is_empty() {
+        return SnapPath::Dir;
+    }
+    match rest.split_once('/') {
+        Some((name, path)) => SnapPath::In { name, path: path.trim_start_matches('/') },
+        None => SnapPath::In { name: rest, path: "" },
+    }
+}
+
+/// Where a service finds its snapshots.
+pub trait SnapshotSource: Send {
+    /// Snapshots that can be looked into, oldest first.
+    fn names(&self) -> Vec<String>;
+
+    /// Opens `path` (relative) as it is in snapshot `name`.
+    fn open(&mut self, name: &str, path: &str) -> Result<Box<dyn FsHandle>, Error>;
+
+    fn stat(&mut self, name: &str, path: &str) -> Result<Stat, Error>;
+}
+
+/// The source of a volume that keeps no snapshots.
+pub struct NoSnapshots;
+
+impl SnapshotSource for NoSnapshots {
+    fn names(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn open(&mut self, _name: &str, _path: &str) -> Result<Box<dyn FsHandle>, Error> {
+        Err(Error::NotFound)
+    }
+
+    fn stat(&mut self, _name: &str, _path: &str) -> Result<Stat, Error> {
+        Err(Error::NotFound)
+    }
+}
+
+/// OPEN of `path` if it lies below `.snapshots`, None for the live
+/// filesystem. Snapshots cannot be changed, so opening one of their files
+/// for writing fails with PermissionDenied.
+pub fn open(
+    source: &mut dyn SnapshotSource,
+    path: &str,
+    flags: OpenFlags,
+) -> Option<Result<Box<dyn FsHandle>, Error>> {
+    match classify(path) {
+        SnapPath::Live => None,
+        _ if proto::open_mutates(flags) => Some(Err(Error::PermissionDenied)),
+        SnapPath::Dir => Some(Ok(Box::new(SnapshotList { names: source.names(), next: 0 }))),
+        SnapPath::In { name, path } => {
+            Some(source.open(name, path).map(|h| Box::new(ReadOnly::new(h)) as Box<dyn FsHandle>))
+        }
+    }
+}
+
+/// STAT_PATH of `path` if it lies below `.snapshots`, None otherwise.
+pub fn stat(source: &mut dyn SnapshotSource, path: &str) -> Option<Result<Stat, Error>> {
+    match classify(path) {
+        SnapPath::Live => None,
+        SnapPath::Dir => Some(Ok(dir_stat())),
+        SnapPath::In { name, path } => Some(source.stat(name, path)),
+    }
+}
+
+/// Refuses calls that would change something below `.snapshots`; checked
+/// before dispatch, like the export policy.
+pub fn check(utcb: &UTCB) -> Result<(), Error> {
+    let tag = utcb.get_msg_tag();
+    if tag.proto() != FS_PROTO {
+        return Ok(());
+    }
+    let buf = utcb.buffer();
+    let touches = match tag.label() {
+        fs::MKDIR | fs::UNLINK | proto::RMTREE | proto::SET_TIMES => {
+            snapshot_path(crate::path::from_buffer(buf)?)
+        }
+        fs::RENAME | proto::LINK => {
+            let (from, to) = crate::path::pair_from_buffer(buf)?;
+            snapshot_path(from) || snapshot_path(to)
+        }
+        _ => false,
+    };
+    if touches {
+        return Err(Error::PermissionDenied);
+    }
+    Ok(())
+}
+
+fn snapshot_path(path: &str) -> bool {
+    !matches!(classify(path), SnapPath::Live)
+}
+
+fn dir_stat() -> Stat {
+    Stat { mode: S_IFDIR | 0o555, nlink: 2, ..Default::default() }
+}
+
+/// `.snapshots` itself: one directory entry per snapshot.
+struct SnapshotList {
+    names: Vec<String>,
+    next: usize,
+}
+
+impl FileHandleService for SnapshotList {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        Ok(dir_stat())
+    }
+
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::InvalidArgs)
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(Error::PermissionDenied)
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        let end = core::cmp::min(self.next + count, self.names.len());
+        let entries = (self.next..end)
+            .map(|i| proto::dentry(0, i + 1, DT_DIR, self.names[i].as_bytes()))
+            .collect();
+        self.next = end;
+        Ok(entries)
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        // Rewinding is all a directory listing supports
+        if offset != 0 || whence != proto::SEEK_SET {
+            return Err(Error::InvalidArgs);
+        }
+        self.next = 0;
+        Ok(0)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(Error::PermissionDenied)
+    }
+}
+
+impl FsHandle for SnapshotList {}
@@ -0,0 +1,34 @@
+//! Space and inode counts of a volume, as STATFS reports them. Counts are in
+//! blocks of the filesystem's own allocation unit (ext4 blocks, FAT
+//! clusters), which `block_size` gives in bytes.
+
+use glenda::ipc::UTCB;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsStats {
+    pub block_size: usize,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    /// Free blocks ordinary users may take; the rest are reserved for root
+    pub avail_blocks: u64,
+    /// 0 on filesystems without an inode table, which never run out
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+}
+
+impl FsStats {
+    /// A volume that never changes: all of it is in use.
+    pub fn full(block_size: usize, total_blocks: u64, total_inodes: u64) -> Self {
+        Self { block_size, total_blocks, total_inodes, ..Default::default() }
+    }
+
+    /// Fills in a STATFS reply.
+    pub fn encode(&self, utcb: &mut UTCB) {
+        utcb.set_mr(0, self.block_size);
+        utcb.set_mr(1, self.total_blocks as usize);
+        utcb.set_mr(2, self.free_blocks as usize);
+        utcb.set_mr(3, self.avail_blocks as usize);
+        utcb.set_mr(4, self.total_inodes as usize);
+        utcb.set_mr(5, self.free_inodes as usize);
+    }
+}
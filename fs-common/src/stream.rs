@@ -0,0 +1,141 @@
+//! Streaming reads on a client ring. One READ_STREAM submission has the
+//! service push a whole file range through a window of the shared memory,
+//! chunk after chunk, instead of the client submitting a read per buffer,
+//! which suits loading large binaries at boot.
+//!
+//! The window starts with a header the two sides share, followed by `slots`
+//! buffers of `chunk` bytes each, filled in turn: chunk `n` goes into buffer
+//! `n % slots`. Every chunk is announced with a completion flagged
+//! STREAM_MORE that carries its length, and `produced` in the header counts
+//! them. The client hands buffers back by advancing `consumed` and ringing
+//! the doorbell or calling PROCESS_IOURING; a stream whose buffers are all
+//! full waits for that. The last completion, without STREAM_MORE, carries
+//! the bytes streamed in total or the error that ended the stream. The
+//! completion queue must hold at least `slots` entries so no chunk goes
+//! unannounced.
+
+use crate::ring::ShmWindow;
+use core::ptr;
+use glenda::error::Error;
+
+// SQE: `addr` is the client address of the window, `off` the file offset to
+// start at and `len` the bytes to stream, 0 for up to the end of the file
+pub const IOURING_OP_READ_STREAM: u8 = 0x44;
+// CQE flag of every completion but the last of a stream
+pub const STREAM_MORE: u32 = 1;
+
+// Header: chunk size (u32), slot count (u32), both set by the client, then
+// chunks produced (u64) by the service and chunks consumed (u64) by the client
+pub const STREAM_HEADER_SIZE: usize = 24;
+const PRODUCED_OFFSET: usize = 8;
+const CONSUMED_OFFSET: usize = 16;
+// Streams one ring may run at once
+pub const MAX_STREAMS: usize = 4;
+
+pub struct Stream {
+    user_data: u64,
+    window: u64,
+    chunk: usize,
+    slots: usize,
+    // Next file offset to read and bytes still wanted, None up to the end
+    offset: usize,
+    remaining: Option<usize>,
+    produced: u64,
+    total: usize,
+}
+
+impl Stream {
+    /// Starts the stream a READ_STREAM SQE asks for, after checking its
+    /// window lies within the shared memory.
+    pub fn start(
+        shm: &ShmWindow,
+        user_data: u64,
+        addr: u64,
+        offset: u64,
+        len: u32,
+    ) -> Result<Self, Error> {
+        let header = shm.translate(addr, STREAM_HEADER_SIZE)?;
+        let (chunk, slots) = unsafe {
+            let raw = header as *const u32;
+            (ptr::read_volatile(raw) as usize, ptr::read_volatile(raw.add(1)) as usize)
+        };
+        if chunk == 0 || slots == 0 {
+            return Err(Error::InvalidArgs);
+        }
+        let buffers = chunk.checked_mul(slots).ok_or(Error::InvalidArgs)?;
+        shm.translate(addr, STREAM_HEADER_SIZE.checked_add(buffers).ok_or(Error::InvalidArgs)?)?;
+
+        let stream = Self {
+            user_data,
+            window: addr,
+            chunk,
+            slots,
+            offset: usize::try_from(offset).map_err(|_| Error::InvalidArgs)?,
+            remaining: (len != 0).then_some(len as usize),
+            produced: 0,
+            total: 0,
+        };
+        stream.store(shm, PRODUCED_OFFSET, 0)?;
+        Ok(stream)
+    }
+
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// Fills the free buffers with `read(client address, file offset, len)`,
+    /// posting each chunk with `post(user_data, bytes, STREAM_MORE)`. Returns
+    /// the final result once the range is done, for the caller to post
+    /// without the flag; None while the stream waits for buffers.
+    pub fn pump<R, P>(
+        &mut self,
+        shm: &ShmWindow,
+        mut read: R,
+        mut post: P,
+    ) -> Option<Result<usize, Error>>
+    where
+        R: FnMut(u64, usize, usize) -> Result<usize, Error>,
+        P: FnMut(u64, Result<usize, Error>, u32),
+    {
+        loop {
+            if self.remaining == Some(0) {
+                return Some(Ok(self.total));
+            }
+            let consumed = match self.load(shm, CONSUMED_OFFSET) {
+                Ok(consumed) => consumed,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.produced.saturating_sub(consumed) >= self.slots as u64 {
+                return None;
+            }
+
+            let slot = (self.produced % self.slots as u64) as usize;
+            let addr = self.window + (STREAM_HEADER_SIZE + slot * self.chunk) as u64;
+            let len = self.remaining.map_or(self.chunk, |left| left.min(self.chunk));
+            let n = match read(addr, self.offset, len) {
+                Ok(0) => return Some(Ok(self.total)),
+                Ok(n) => n,
+                Err(e) => return Some(Err(e)),
+            };
+            self.offset += n;
+            self.total += n;
+            self.remaining = self.remaining.map(|left| left - n);
+            self.produced += 1;
+            if let Err(e) = self.store(shm, PRODUCED_OFFSET, self.produced) {
+                return Some(Err(e));
+            }
+            post(self.user_data, Ok(n), STREAM_MORE);
+        }
+    }
+
+    fn load(&self, shm: &ShmWindow, at: usize) -> Result<u64, Error> {
+        let field = shm.translate(self.window + at as u64, 8)?;
+        Ok(unsafe { ptr::read_volatile(field as *const u64) })
+    }
+
+    fn store(&self, shm: &ShmWindow, at: usize, value: u64) -> Result<(), Error> {
+        let field = shm.translate(self.window + at as u64, 8)?;
+        unsafe { ptr::write_volatile(field as *mut u64, value) };
+        Ok(())
+    }
+}
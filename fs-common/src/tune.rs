@@ -0,0 +1,114 @@
+//! Cache and writeback settings that operators can change at runtime with
+//! CACHE_TUNE instead of rebuilding the services.
+
+use crate::clock::{self, Timestamp};
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// CACHE_TUNE MR0 bits selecting which of MR1..MR4 to apply
+pub const TUNE_CACHE_BYTES: usize = 1 << 0;
+pub const TUNE_DIRTY_RATIO: usize = 1 << 1;
+pub const TUNE_FLUSH_INTERVAL: usize = 1 << 2;
+pub const TUNE_READAHEAD: usize = 1 << 3;
+const TUNE_ALL: usize = TUNE_CACHE_BYTES | TUNE_DIRTY_RATIO | TUNE_FLUSH_INTERVAL | TUNE_READAHEAD;
+
+pub const DEFAULT_DIRTY_RATIO: usize = 20;
+pub const DEFAULT_FLUSH_INTERVAL_MS: usize = 5000;
+pub const MAX_READAHEAD_BLOCKS: usize = 1024;
+// Requests between periodic writebacks while the clock is not set
+pub const WRITEBACK_REQUESTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheTunables {
+    /// Upper bound on the metadata cache, in bytes.
+    pub cache_max_bytes: usize,
+    /// Percentage of the cache allowed to hold unwritten data before writeback.
+    pub dirty_ratio: usize,
+    /// Longest time dirty data may wait for writeback; 0 leaves it to SYNC.
+    pub flush_interval_ms: usize,
+    pub readahead_blocks: usize,
+}
+
+impl CacheTunables {
+    pub fn new(cache_max_bytes: usize, readahead_blocks: usize) -> Self {
+        Self {
+            cache_max_bytes,
+            dirty_ratio: DEFAULT_DIRTY_RATIO,
+            flush_interval_ms: DEFAULT_FLUSH_INTERVAL_MS,
+            readahead_blocks,
+        }
+    }
+
+    /// Returns these settings with the fields selected by the MR0 mask of a
+    /// CACHE_TUNE request replaced. A zero mask leaves everything as is.
+    pub fn update(&self, utcb: &UTCB) -> Result<Self, Error> {
+        let mask = utcb.get_mr(0);
+        if mask & !TUNE_ALL != 0 {
+            return Err(Error::InvalidArgs);
+        }
+        let mut next = *self;
+        if mask & TUNE_CACHE_BYTES != 0 {
+            next.cache_max_bytes = utcb.get_mr(1);
+        }
+        if mask & TUNE_DIRTY_RATIO != 0 {
+            next.dirty_ratio = utcb.get_mr(2);
+        }
+        if mask & TUNE_FLUSH_INTERVAL != 0 {
+            next.flush_interval_ms = utcb.get_mr(3);
+        }
+        if mask & TUNE_READAHEAD != 0 {
+            next.readahead_blocks = utcb.get_mr(4);
+        }
+        if next.cache_max_bytes == 0
+            || next.dirty_ratio > 100
+            || next.readahead_blocks > MAX_READAHEAD_BLOCKS
+        {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(next)
+    }
+
+    /// Fills in a CACHE_TUNE reply with the settings in effect.
+    pub fn encode(&self, utcb: &mut UTCB) {
+        utcb.set_mr(0, self.cache_max_bytes);
+        utcb.set_mr(1, self.dirty_ratio);
+        utcb.set_mr(2, self.flush_interval_ms);
+        utcb.set_mr(3, self.readahead_blocks);
+    }
+}
+
+/// When periodic writeback is due: once `flush_interval_ms` has passed by
+/// the clock the monitor sets. Until it sets one, time is approximated by
+/// the requests served.
+#[derive(Default)]
+pub struct WritebackTimer {
+    last: Option<Timestamp>,
+    requests: usize,
+}
+
+impl WritebackTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a request served; true when dirty data should go out now.
+    pub fn tick(&mut self, tunables: &CacheTunables) -> bool {
+        if tunables.flush_interval_ms == 0 {
+            return false;
+        }
+        self.requests += 1;
+        let now = clock::now();
+        let due = match now {
+            Some(now) => {
+                let last = *self.last.get_or_insert(now);
+                now.millis_since(last) >= tunables.flush_interval_ms as u64
+            }
+            None => self.requests >= WRITEBACK_REQUESTS,
+        };
+        if due {
+            self.last = now;
+            self.requests = 0;
+        }
+        due
+    }
+}
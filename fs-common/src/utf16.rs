@@ -0,0 +1,73 @@
+//! UTF-16 names, as VFAT long names and exFAT store them, and exFAT's
+//! up-case table. Names cross the IPC boundary as UTF-8; units that do not
+//! decode (an unpaired surrogate) read as U+FFFD.
+//!
+//! Names are not normalized. Both FAT flavours compare names unit by unit
+//! after upcasing, so NFC and NFD spellings of a name are distinct files on
+//! disk; composing them here would make this driver disagree with every
+//! other implementation about which names exist.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use glenda::error::Error;
+
+// Compressed tables run identity mappings together: this unit, then a count
+const IDENTITY_RUN: u16 = 0xFFFF;
+// Units a table can map
+const TABLE_MAX_UNITS: usize = 0x10000;
+
+pub fn decode(units: &[u16]) -> String {
+    core::char::decode_utf16(units.iter().copied())
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+pub fn encode(name: &str) -> Vec<u16> {
+    name.encode_utf16().collect()
+}
+
+/// TableChecksum of the Up-case Table entry, over the table as stored.
+pub fn table_checksum(raw: &[u8]) -> u32 {
+    raw.iter().fold(0u32, |sum, &b| sum.rotate_right(1).wrapping_add(b as u32))
+}
+
+/// exFAT's mapping of each UTF-16 unit to its uppercase, from the volume's
+/// Up-case Table. Units past its end map to themselves.
+pub struct UpcaseTable {
+    map: Vec<u16>,
+}
+
+impl UpcaseTable {
+    /// Expands the table as stored, compressed or not, after checking it
+    /// against the entry's checksum.
+    pub fn parse(raw: &[u8], checksum: u32) -> Result<Self, Error> {
+        if raw.len() % 2 != 0 || table_checksum(raw) != checksum {
+            return Err(Error::DeviceError);
+        }
+        let mut units = raw.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let mut map = Vec::new();
+        while let Some(unit) = units.next() {
+            if map.len() >= TABLE_MAX_UNITS {
+                return Err(Error::DeviceError);
+            }
+            match (unit, units.clone().next()) {
+                (IDENTITY_RUN, Some(count)) => {
+                    units.next();
+                    let end = (map.len() + count as usize).min(TABLE_MAX_UNITS);
+                    map.extend((map.len()..end).map(|u| u as u16));
+                }
+                _ => map.push(unit),
+            }
+        }
+        Ok(Self { map })
+    }
+
+    pub fn upcase(&self, unit: u16) -> u16 {
+        self.map.get(unit as usize).copied().unwrap_or(unit)
+    }
+
+    /// True if `a` and `b` name the same file on this volume.
+    pub fn eq(&self, a: &str, b: &str) -> bool {
+        a.encode_utf16().map(|u| self.upcase(u)).eq(b.encode_utf16().map(|u| self.upcase(u)))
+    }
+}
@@ -0,0 +1,72 @@
+//! Virtual address space of a service for what it maps at runtime: block
+//! device rings and buffers, and the shared memory of client rings. Regions
+//! are reserved from a fixed window and given back when their mapping goes,
+//! each followed by an unmapped guard page so an overrun faults instead of
+//! landing in the next mapping.
+
+use alloc::collections::BTreeMap;
+use glenda::arch::mem::PGSIZE;
+use glenda::error::Error;
+use glenda::interface::VSpaceService;
+use glenda::utils::manager::VSpaceManager;
+
+pub struct VaddrAllocator {
+    start: usize,
+    end: usize,
+    // Base of each reserved region and its size, guard page excluded
+    regions: BTreeMap<usize, usize>,
+}
+
+impl VaddrAllocator {
+    /// Hands out addresses from `start` up to `end`, both page aligned.
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end, regions: BTreeMap::new() }
+    }
+
+    /// Reserves `size` bytes, rounded up to whole pages, at an address
+    /// aligned to `align`, a power of two; at least page aligned either way.
+    /// The lowest gap that fits is taken. OutOfMemory once the window is full.
+    pub fn reserve(&mut self, size: usize, align: usize) -> Result<usize, Error> {
+        if size == 0 || !align.is_power_of_two() {
+            return Err(Error::InvalidArgs);
+        }
+        let size = size.checked_next_multiple_of(PGSIZE).ok_or(Error::InvalidArgs)?;
+        let align = align.max(PGSIZE);
+        let mut candidate = self.start;
+        for (&base, &len) in &self.regions {
+            let at = candidate.checked_next_multiple_of(align).ok_or(Error::OutOfMemory)?;
+            if at.checked_add(size + PGSIZE).is_some_and(|end| end <= base) {
+                break;
+            }
+            candidate = base + len + PGSIZE;
+        }
+        let at = candidate.checked_next_multiple_of(align).ok_or(Error::OutOfMemory)?;
+        match at.checked_add(size + PGSIZE) {
+            Some(end) if end <= self.end => {
+                self.regions.insert(at, size);
+                Ok(at)
+            }
+            _ => Err(Error::OutOfMemory),
+        }
+    }
+
+    /// Size of the region reserved at `base`.
+    pub fn size_of(&self, base: usize) -> Option<usize> {
+        self.regions.get(&base).copied()
+    }
+
+    /// Gives back the region reserved at `base`, once nothing is mapped there.
+    /// Returns its size.
+    pub fn release(&mut self, base: usize) -> Result<usize, Error> {
+        self.regions.remove(&base).ok_or(Error::NotFound)
+    }
+
+    /// Unmaps the region reserved at `base` from `vspace` and gives it back.
+    /// If the unmap fails the pages are still mapped, so the region stays
+    /// reserved.
+    pub fn unmap(&mut self, vspace: &mut VSpaceManager, base: usize) -> Result<(), Error> {
+        let size = self.size_of(base).ok_or(Error::NotFound)?;
+        vspace.unmap(base, size / PGSIZE)?;
+        self.release(base).map(|_| ())
+    }
+}
@@ -0,0 +1,97 @@
+//! Protocol version and feature negotiation. A client sends VERSION with
+//! the newest revision it speaks and the FEAT_* bits it understands; the
+//! service answers with what both sides have and remembers it for the
+//! client. Behaviors that change existing replies are only turned on for
+//! clients that negotiated their bit, so older clients keep working against
+//! newer services. The other bits advertise optional calls, which a client
+//! should check before relying on them.
+
+use alloc::collections::BTreeMap;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// Revision of the extension protocol this tree speaks
+pub const PROTO_VERSION: usize = 1;
+
+// OPEN, OPENAT and CLONE replies transfer an endpoint badged for the new handle
+pub const FEAT_HANDLE_ENDPOINTS: usize = 1 << 0;
+// RING_NOTIFY and doorbells
+pub const FEAT_RING_NOTIFY: usize = 1 << 1;
+// READV and WRITEV ring submissions
+pub const FEAT_IOVEC: usize = 1 << 2;
+// LOCK and UNLOCK
+pub const FEAT_LOCKS: usize = 1 << 3;
+pub const FEAT_CLONE: usize = 1 << 4;
+// RMTREE with JOB_ASYNC and the JOB_* calls
+pub const FEAT_JOBS: usize = 1 << 5;
+pub const FEAT_WIRE: usize = 1 << 6;
+pub const FEAT_OPEN_ASYNC: usize = 1 << 7;
+// HISTORY_LIST and HISTORY_RESTORE
+pub const FEAT_HISTORY: usize = 1 << 8;
+pub const FEAT_LINK: usize = 1 << 9;
+// GETXATTR and LISTXATTR
+pub const FEAT_XATTR: usize = 1 << 10;
+// EVENT_SUBSCRIBE and EVENT_READ
+pub const FEAT_EVENTS: usize = 1 << 11;
+pub const FEAT_SET_TIMES: usize = 1 << 12;
+pub const FEAT_STATFS: usize = 1 << 13;
+pub const FEAT_FALLOCATE: usize = 1 << 14;
+// READ_NEXT and WRITE_NEXT
+pub const FEAT_NEXT: usize = 1 << 15;
+// READ_STREAM ring submissions
+pub const FEAT_STREAM: usize = 1 << 16;
+// ATTACH_DEVICE
+pub const FEAT_ATTACH: usize = 1 << 17;
+// Error replies carry an FsError code in MR1
+pub const FEAT_ERROR_DETAIL: usize = 1 << 18;
+pub const FEAT_ALL: usize = FEAT_HANDLE_ENDPOINTS
+    | FEAT_RING_NOTIFY
+    | FEAT_IOVEC
+    | FEAT_LOCKS
+    | FEAT_CLONE
+    | FEAT_JOBS
+    | FEAT_WIRE
+    | FEAT_OPEN_ASYNC
+    | FEAT_HISTORY
+    | FEAT_LINK
+    | FEAT_XATTR
+    | FEAT_EVENTS
+    | FEAT_SET_TIMES
+    | FEAT_STATFS
+    | FEAT_FALLOCATE
+    | FEAT_NEXT
+    | FEAT_STREAM
+    | FEAT_ATTACH
+    | FEAT_ERROR_DETAIL;
+
+/// What each client negotiated, against the features of this service.
+pub struct Versions {
+    supported: usize,
+    clients: BTreeMap<usize, usize>,
+}
+
+impl Versions {
+    pub fn new(supported: usize) -> Self {
+        Self { supported, clients: BTreeMap::new() }
+    }
+
+    /// Handles VERSION for `client`: MR0 version, MR1 features in, the
+    /// agreed ones out. Asking again renegotiates.
+    pub fn negotiate(&mut self, client: usize, utcb: &mut UTCB) -> Result<(), Error> {
+        let version = core::cmp::min(utcb.get_mr(0), PROTO_VERSION);
+        if version == 0 {
+            return Err(Error::NotSupported);
+        }
+        let features = utcb.get_mr(1) & self.supported;
+        self.clients.insert(client, features);
+        utcb.set_mr(0, version);
+        utcb.set_mr(1, features);
+        Ok(())
+    }
+
+    /// Whether `client` negotiated `feature`. Clients that never sent
+    /// VERSION get none of the gated behaviors.
+    pub fn has(&self, client: usize, feature: usize) -> bool {
+        self.clients.get(&client).is_some_and(|f| f & feature == feature)
+    }
+}
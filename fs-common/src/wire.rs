@@ -0,0 +1,107 @@
+//! Optional integrity layer for clients reached over less trusted transports
+//! (network mounts, protocol bridges). A badge turns it on with WIRE_PROTECT;
+//! from then on every message in either direction carries a sequence number
+//! and a CRC, so corrupted or reordered messages are refused instead of
+//! reaching the filesystem.
+
+use crate::crc::crc32c;
+use alloc::collections::BTreeMap;
+use glenda::error::Error;
+use glenda::ipc::UTCB;
+
+// Message registers covered by the CRC; the protocol never uses more
+pub const WIRE_DATA_MRS: usize = 6;
+// MR6: buffer bytes covered, MR7: sequence number << 32 | CRC-32C
+pub const WIRE_LEN_MR: usize = 6;
+pub const WIRE_CHECK_MR: usize = 7;
+
+#[derive(Clone, Copy)]
+struct Session {
+    // Sequence number expected on the next request
+    rx_seq: u32,
+    // Sequence number put on the next reply
+    tx_seq: u32,
+}
+
+/// Per-badge state of the integrity layer, consulted around dispatch.
+pub struct WireGuard {
+    sessions: BTreeMap<usize, Session>,
+}
+
+impl WireGuard {
+    pub fn new() -> Self {
+        Self { sessions: BTreeMap::new() }
+    }
+
+    /// Handles WIRE_PROTECT: MR0 1 turns checking on for `badge` starting at
+    /// sequence number MR1 in both directions, 0 turns it off.
+    pub fn configure(&mut self, badge: usize, utcb: &UTCB) -> Result<(), Error> {
+        match utcb.get_mr(0) {
+            0 => {
+                self.sessions.remove(&badge);
+            }
+            1 => {
+                let seq = utcb.get_mr(1) as u32;
+                self.sessions.insert(badge, Session { rx_seq: seq, tx_seq: seq });
+            }
+            _ => return Err(Error::InvalidArgs),
+        }
+        Ok(())
+    }
+
+    /// Checks a request from `badge`. Unprotected badges always pass; for
+    /// protected ones a bad CRC or an unexpected sequence number is an IoError
+    /// and the request must not be dispatched.
+    pub fn verify(&mut self, badge: usize, utcb: &UTCB) -> Result<(), Error> {
+        let session = match self.sessions.get_mut(&badge) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let check = utcb.get_mr(WIRE_CHECK_MR) as u64;
+        let seq = (check >> 32) as u32;
+        let len = utcb.get_mr(WIRE_LEN_MR);
+        if len > utcb.buffer().len() || seq != session.rx_seq {
+            return Err(Error::IoError);
+        }
+        if checksum(utcb, seq, len) != check as u32 {
+            return Err(Error::IoError);
+        }
+        session.rx_seq = seq.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Stamps the reply to `badge` if it is protected. The whole buffer is
+    /// covered since the reply length is up to the handler.
+    pub fn seal(&mut self, badge: usize, utcb: &mut UTCB) {
+        let session = match self.sessions.get_mut(&badge) {
+            Some(session) => session,
+            None => return,
+        };
+        let seq = session.tx_seq;
+        session.tx_seq = seq.wrapping_add(1);
+        let len = utcb.buffer().len();
+        let crc = checksum(utcb, seq, len);
+        utcb.set_mr(WIRE_LEN_MR, len);
+        utcb.set_mr(WIRE_CHECK_MR, ((seq as u64) << 32 | crc as u64) as usize);
+    }
+}
+
+impl Default for WireGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// CRC-32C over the tag, the data registers, `len` buffer bytes and the sequence number
+fn checksum(utcb: &UTCB, seq: u32, len: usize) -> u32 {
+    let tag = utcb.get_msg_tag();
+    let mut crc = !0;
+    for word in [tag.proto(), tag.label(), tag.flags().bits()] {
+        crc = crc32c(crc, &(word as u64).to_le_bytes());
+    }
+    for mr in 0..WIRE_DATA_MRS {
+        crc = crc32c(crc, &(utcb.get_mr(mr) as u64).to_le_bytes());
+    }
+    crc = crc32c(crc, &utcb.buffer()[..len]);
+    !crc32c(crc, &seq.to_le_bytes())
+}
@@ -0,0 +1,34 @@
+//! Zero-filling of freshly allocated space, so a file never exposes what an
+//! earlier file left in its blocks. Filesystems with unwritten extents can
+//! instead track such blocks and read them as zeros; FAT has no such bit, so
+//! its clusters are zeroed when allocated. With MNT_ZERO_ALLOC the zeros also
+//! reach the device before the allocation is linked in, so not even a crash in
+//! between can expose stale data.
+
+use glenda::error::Error;
+
+// Largest run written in one go, and the largest block `zeros` hands out
+pub const ZERO_CHUNK: usize = 64 * 1024;
+
+static ZEROES: [u8; ZERO_CHUNK] = [0; ZERO_CHUNK];
+
+/// `len` zero bytes, for writing a block of at most ZERO_CHUNK bytes.
+pub fn zeros(len: usize) -> &'static [u8] {
+    &ZEROES[..len]
+}
+
+/// Zeroes `len` bytes at `offset` through `write`, called with an offset and
+/// a run of at most ZERO_CHUNK zeros.
+pub fn fill(
+    offset: usize,
+    len: usize,
+    mut write: impl FnMut(usize, &[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut done = 0;
+    while done < len {
+        let run = core::cmp::min(len - done, ZERO_CHUNK);
+        write(offset + done, &ZEROES[..run])?;
+        done += run;
+    }
+    Ok(())
+}
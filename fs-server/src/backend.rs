@@ -0,0 +1,104 @@
+use crate::server::CallContext;
+use alloc::boxed::Box;
+use fs_common::clock::TimesRequest;
+use fs_common::handle::FsHandle;
+use fs_common::mount::MountOptions;
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use glenda::error::Error;
+use glenda::ipc::{Badge, UTCB};
+use glenda::protocol::fs::{OpenFlags, Stat};
+
+/// A volume as `FsServer` sees it. Paths are absolute within the volume,
+/// `badge` is the caller's. The server has checked that the volume is
+/// writable before any call that modifies it, so a backend only refuses
+/// what it cannot do; the defaults refuse with NotSupported.
+pub trait FileSystemBackend {
+    /// Prefix of the server's log lines, e.g. "TmpFS".
+    const NAME: &'static str;
+    /// version::FEAT_* bits VERSION offers clients.
+    const FEATURES: usize;
+    /// Set by backends that key their state by path rather than walking a
+    /// tree, as an overlay or a 9P session do. They are handed paths in
+    /// `path::canonical` form, and locks are taken on the path instead of
+    /// the inode, whose number they cannot keep stable.
+    const KEYED_BY_PATH: bool = false;
+
+    /// Opens `path`, creating a file there for O_CREAT.
+    fn open(
+        &mut self,
+        badge: Badge,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error>;
+
+    fn stat_path(&self, badge: Badge, path: &str) -> Result<Stat, Error>;
+
+    /// STATFS counts.
+    fn stats(&self) -> Result<FsStats, Error>;
+
+    fn mkdir(&mut self, _badge: Badge, _path: &str, _mode: u32) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn unlink(&mut self, _badge: Badge, _path: &str) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn rename(&mut self, _badge: Badge, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn link(&mut self, _badge: Badge, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    fn set_times(&mut self, _badge: Badge, _path: &str, _req: TimesRequest) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Removes `path` and everything below it, within the call.
+    fn rmtree(&mut self, _badge: Badge, _path: &str) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Takes new mount options. Only called with no handle open.
+    fn set_mount_options(&mut self, _options: MountOptions) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// False for media that can never be written; REMOUNT_RW is refused then.
+    fn writable(&self) -> bool {
+        true
+    }
+
+    /// False while there is no tree to show, as before an overlay has both
+    /// its layers. The mount point is registered once it turns true.
+    fn ready(&self) -> bool {
+        true
+    }
+
+    /// How long clients may cache what STAT_PATH returns, in milliseconds.
+    fn attr_timeout(&self) -> usize {
+        proto::ATTR_TIMEOUT_MS
+    }
+
+    /// Writes out what the volume holds back, once every handle is closed
+    /// on UNMOUNT or EXIT.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Calls outside the common protocol, such as VOLUME_INFO. Returns None
+    /// for a call the server should dispatch itself; a backend answering one
+    /// wraps it in `handle_call` as the server does.
+    fn extra_call(
+        &mut self,
+        _badge: Badge,
+        _utcb: &mut UTCB,
+        _ctx: &mut CallContext,
+    ) -> Option<Result<(), Error>> {
+        None
+    }
+}
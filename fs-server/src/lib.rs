@@ -0,0 +1,26 @@
+//! The service side of a filesystem: the receive loop, the handle table,
+//! client rings, locks, version and wire negotiation and the mount point,
+//! generic over a `FileSystemBackend` that only knows its volume.
+//!
+//! A backend implements the path operations and hands out `FsHandle`s; the
+//! `FsServer` it is wrapped in answers every call of the common protocol the
+//! same way for all of them.
+//!
+//! It fits a single volume whose calls all finish within the call: tmpfs,
+//! ISO9660, and the overlay and 9P services, which key their state by path
+//! and take their layers or transport through calls of their own. InitrdFS,
+//! FatFS and ExtFS keep their own loops for now: InitrdFS names handles by
+//! badge and lends client windows to its block device, and the other two
+//! park opens, run background jobs and serve several volumes, none of which
+//! `FsServer` does. They share the call checks of `fs_common::call` and the
+//! ring helpers of `fs_common::ring` with it instead.
+
+#![no_std]
+
+extern crate alloc;
+
+mod backend;
+mod server;
+
+pub use backend::FileSystemBackend;
+pub use server::{CallContext, FsServer, ServerLayout};
@@ -0,0 +1,702 @@
+use crate::backend::FileSystemBackend;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fs_common::badge;
+use fs_common::call;
+use fs_common::clock::{self, TimesRequest};
+use fs_common::errors::FsError;
+use fs_common::handle::{FsHandle, ReadOnly};
+use fs_common::locks::{Grant, LockTable};
+use fs_common::mount::{MountOptions, MountPoint, MNT_RDONLY};
+use fs_common::path;
+use fs_common::proto::{self, CURRENT_OFFSET};
+use fs_common::ring::{self, RingAccess, RingSignal, SharedRing};
+use fs_common::slots::SlotAllocator;
+use fs_common::vaddr::VaddrAllocator;
+use fs_common::version::Versions;
+use fs_common::wire::WireGuard;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CapPtr, Endpoint, Frame, Reply, CSPACE_CAP};
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::interface::system::SystemService;
+use glenda::interface::VSpaceService;
+use glenda::ipc::server::handle_call;
+use glenda::ipc::{Badge, MsgFlags, MsgTag, UTCB};
+use glenda::mem::Perms;
+use glenda::protocol;
+use glenda::protocol::fs::OpenFlags;
+use glenda::protocol::{FS_PROTO, PROCESS_PROTO};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Where a server keeps what it is handed, in the service's own CSpace and
+/// address space.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerLayout {
+    /// Slots the CSpaceManager hands out stay below this.
+    pub dynamic_slot_limit: CapPtr,
+    /// Where caps sent along with a call arrive.
+    pub recv_slot: CapPtr,
+    /// Client rings from SETUP_IOURING, and what the backend maps with
+    /// `FsServer::maps`, are mapped between these.
+    pub map_start: usize,
+    pub map_end: usize,
+}
+
+// What a handle's advisory locks are taken on
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum LockKey {
+    // Follows the file across renames
+    Inode(u64),
+    // For backends keyed by path
+    Path(String),
+}
+
+struct OpenHandle {
+    handle: Box<dyn FsHandle>,
+    path: String,
+    lock: LockKey,
+    is_dir: bool,
+    refs: usize,
+    // Set up by SETUP_IOURING
+    ring: Option<SharedRing>,
+}
+
+/// What a backend's own calls may use of the server.
+pub struct CallContext<'s, 'a> {
+    /// Handles open on the volume.
+    pub open_handles: usize,
+    recv_slot: CapPtr,
+    slots: &'s mut SlotAllocator<'a>,
+    res_client: &'s mut ResourceClient,
+}
+
+impl CallContext<'_, '_> {
+    /// Moves the cap sent along with the call into a slot of its own.
+    pub fn take_cap(&mut self) -> Result<CapPtr, Error> {
+        let slot = self.slots.alloc(self.res_client)?;
+        CSPACE_CAP.move_cap(self.recv_slot, slot)?;
+        Ok(slot)
+    }
+
+    /// Deletes a cap from `take_cap` and frees its slot.
+    pub fn drop_cap(&mut self, slot: CapPtr) -> Result<(), Error> {
+        CSPACE_CAP.delete(slot)?;
+        self.slots.free(slot);
+        Ok(())
+    }
+}
+
+/// A filesystem service: `B` behind the common protocol.
+pub struct FsServer<'a, B: FileSystemBackend> {
+    fs: B,
+    // Keyed by handle badge, so lookups only find the caller's own handles
+    handles: BTreeMap<usize, OpenHandle>,
+    read_only: bool,
+    options: MountOptions,
+    mount_point: MountPoint,
+    wire: WireGuard,
+    versions: Versions,
+    // Advisory locks owned by handle id
+    locks: LockTable<LockKey>,
+    next_handle_id: usize,
+    endpoint: Endpoint,
+    reply: Reply,
+    recv: CapPtr,
+    running: bool,
+    // Set once the mount point is registered
+    mounted: bool,
+    recv_slot: CapPtr,
+
+    /// Addresses for client rings, and whatever else the backend maps.
+    pub maps: VaddrAllocator,
+    pub res_client: &'a mut ResourceClient,
+    pub slots: SlotAllocator<'a>,
+    pub vspace: &'a mut VSpaceManager,
+}
+
+impl<'a, B: FileSystemBackend> FsServer<'a, B> {
+    pub fn new(
+        fs: B,
+        layout: ServerLayout,
+        res_client: &'a mut ResourceClient,
+        cspace: &'a mut CSpaceManager,
+        vspace: &'a mut VSpaceManager,
+    ) -> Self {
+        Self {
+            fs,
+            handles: BTreeMap::new(),
+            read_only: false,
+            options: MountOptions::default(),
+            mount_point: MountPoint::none(),
+            wire: WireGuard::new(),
+            versions: Versions::new(B::FEATURES),
+            locks: LockTable::new(),
+            next_handle_id: 1,
+            endpoint: Endpoint::from(CapPtr::null()),
+            reply: Reply::from(CapPtr::null()),
+            recv: CapPtr::null(),
+            running: false,
+            mounted: false,
+            recv_slot: layout.recv_slot,
+            maps: VaddrAllocator::new(layout.map_start, layout.map_end),
+            res_client,
+            slots: SlotAllocator::new(cspace, layout.dynamic_slot_limit),
+            vspace,
+        }
+    }
+
+    /// Where the service registers in the VFS namespace once it runs.
+    pub fn set_mount_point(&mut self, mount_point: MountPoint) {
+        self.mount_point = mount_point;
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.fs
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.fs
+    }
+
+    // Mount options in effect as MNT_* bits, with MNT_RDONLY after REMOUNT_RO
+    fn mounted_options(&self) -> usize {
+        let forced = if self.read_only { MNT_RDONLY } else { 0 };
+        self.options.bits() | forced
+    }
+
+    // Gate for the calls that manage the volume rather than its files
+    fn check_admin(&self, badge: Badge) -> Result<(), Error> {
+        if !badge::is_admin(badge.bits()) {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    // Gate for every call that would modify the volume
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only || self.options.read_only || !self.fs.writable() {
+            return Err(FsError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    fn insert_handle(
+        &mut self,
+        handle: Box<dyn FsHandle>,
+        path: String,
+        badge: Badge,
+        utcb: &mut UTCB,
+    ) -> Result<(), Error> {
+        let stat = handle.stat(badge)?;
+        let is_dir = (stat.mode & S_IFMT) == S_IFDIR;
+        let id = self.next_handle_id;
+        let key = badge::handle_badge(badge::client(badge.bits()), id)?;
+        self.next_handle_id += 1;
+        let lock = if B::KEYED_BY_PATH {
+            LockKey::Path(path.clone())
+        } else {
+            LockKey::Inode(stat.ino as u64)
+        };
+        let entry = OpenHandle { handle, path, lock, is_dir, refs: 1, ring: None };
+        self.handles.insert(key, entry);
+        utcb.set_mr(0, id);
+        Ok(())
+    }
+
+    fn unmap_ring(&mut self, ring: &SharedRing) {
+        if let Err(e) = self.maps.unmap(self.vspace, ring.window().server_base) {
+            glenda::log!("{}: cannot unmap a client ring: {:?}", B::NAME, e);
+        }
+    }
+
+    // Completes ring lock submissions that were waiting on a released lock
+    fn complete_grants(&self, grants: Vec<Grant>) {
+        ring::complete_grants(grants, |id| self.handles.get(&id).and_then(|h| h.ring.as_ref()));
+    }
+
+    // Serves the submissions on the ring of the handle with key `id`, for
+    // PROCESS_IOURING or a doorbell
+    fn process_ring(&mut self, id: usize, badge: Badge) -> Result<(), Error> {
+        let writable = self.check_writable();
+        let entry = self.handles.get_mut(&id).ok_or(Error::NotFound)?;
+        let ring = entry.ring.as_mut().ok_or(Error::InvalidArgs)?;
+        let access = RingAccess {
+            writable,
+            frozen: false,
+            locks: &mut self.locks,
+            key: &entry.lock,
+            owner: id,
+            audit: None,
+        };
+        let grants = ring.process(entry.handle.as_mut(), badge, access);
+        ring.announce();
+        self.complete_grants(grants);
+        Ok(())
+    }
+
+    // A notification on the service endpoint: serves the rings the notifying
+    // badge registered with RING_NOTIFY
+    fn doorbell(&mut self, badge: usize, bits: usize) {
+        if bits & proto::RING_DOORBELL_BITS == 0 {
+            return;
+        }
+        let rings = self.handles.iter().map(|(&id, h)| (id, h.ring.as_ref()));
+        for id in ring::rung(rings, badge) {
+            if let Err(e) = self.process_ring(id, Badge::new(badge)) {
+                glenda::log!("{}: ring of handle {} failed: {:?}", B::NAME, id, e);
+            }
+        }
+    }
+
+    // A path from the caller, in the form the backend keys it by
+    fn path(raw: &str) -> String {
+        if B::KEYED_BY_PATH {
+            path::canonical(raw)
+        } else {
+            String::from(raw)
+        }
+    }
+
+    // Registers the mount point once the backend has a tree to show
+    fn mount(&mut self) {
+        if self.mounted || !self.fs.ready() {
+            return;
+        }
+        self.mounted = true;
+        // Not fatal: clients given the endpoint directly can still call us
+        if let Err(e) = self.mount_point.register(self.endpoint) {
+            glenda::log!("{}: cannot mount with the VFS: {:?}", B::NAME, e);
+        }
+    }
+
+    // The backend's own calls first, then the common protocol
+    fn call(&mut self, badge: usize, utcb: &mut UTCB) -> Result<(), Error> {
+        let mut ctx = CallContext {
+            open_handles: self.handles.len(),
+            recv_slot: self.recv_slot,
+            slots: &mut self.slots,
+            res_client: self.res_client,
+        };
+        match self.fs.extra_call(Badge::new(badge), utcb, &mut ctx) {
+            Some(result) => result,
+            None => self.dispatch(utcb),
+        }
+    }
+
+    // A call: checked, dispatched and answered
+    fn serve(&mut self, badge: usize, utcb: &mut UTCB) {
+        // Handle endpoints share their client's session
+        let client = badge::client(badge);
+        let result =
+            call::admit(&mut self.wire, None, client, utcb).and_then(|_| self.call(badge, utcb));
+        if result.is_ok() {
+            // A call the backend made of its own may have left its tag behind
+            utcb.set_msg_tag(MsgTag::ok());
+        }
+        call::answer(&mut self.wire, &self.versions, client, utcb, result);
+        let _ = self.reply(utcb);
+    }
+
+    // UNMOUNT and EXIT: closes every handle, then lets the backend write out
+    // what it holds. The loop ends after this call's reply.
+    fn shutdown(&mut self) -> Result<(), Error> {
+        self.running = false;
+        let mut result = Ok(());
+        for (_, mut entry) in core::mem::take(&mut self.handles) {
+            result = result.and(entry.handle.close(Badge::null()));
+            if let Some(ring) = entry.ring.take() {
+                self.unmap_ring(&ring);
+            }
+        }
+        self.locks = LockTable::new();
+        result.and(self.fs.shutdown())
+    }
+}
+
+impl<'a, B: FileSystemBackend> SystemService for FsServer<'a, B> {
+    fn init(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn listen(&mut self, ep: Endpoint, reply: CapPtr, recv: CapPtr) -> Result<(), Error> {
+        self.endpoint = ep;
+        self.reply = Reply::from(reply);
+        self.recv = recv;
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        self.mount();
+        self.running = true;
+        while self.running {
+            let mut utcb = unsafe { UTCB::new() };
+            utcb.clear();
+            utcb.set_reply_window(self.reply.cap());
+            utcb.set_recv_window(self.recv_slot);
+
+            if self.endpoint.recv(&mut utcb).is_ok() {
+                let badge = utcb.get_badge().bits();
+                match ring::notification(utcb) {
+                    Some(bits) => self.doorbell(badge, bits),
+                    None => self.serve(badge, utcb),
+                }
+            }
+            self.mount();
+        }
+        if let Err(e) = self.mount_point.unregister() {
+            glenda::log!("{}: cannot unmount from the VFS: {:?}", B::NAME, e);
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
+        let badge = utcb.get_badge();
+        let client = Badge::new(badge::client(badge.bits()));
+        // The handle a call names in MR0
+        let key = |id: usize| badge::handle_key(badge.bits(), id);
+        glenda::ipc_dispatch! {
+            self, utcb,
+            (FS_PROTO, protocol::fs::OPEN) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(0));
+                    if proto::open_mutates(flags) {
+                        s.check_writable()?;
+                    }
+                    let mode = u_inner.get_mr(1) as u32;
+                    let path = Self::path(path::from_buffer(u_inner.buffer())?);
+                    let handle = s.fs.open(badge, &path, flags, mode)?;
+                    s.insert_handle(handle, path, badge, u_inner)
+                })
+            },
+            (FS_PROTO, proto::OPENAT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let base = s.handles.get(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    if !base.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(1));
+                    if proto::open_mutates(flags) {
+                        s.check_writable()?;
+                    }
+                    let mode = u_inner.get_mr(2) as u32;
+                    let path = path::join(&base.path, path::from_buffer(u_inner.buffer())?);
+                    let path = Self::path(&path);
+                    let handle = s.fs.open(badge, &path, flags, mode)?;
+                    s.insert_handle(handle, path, badge, u_inner)
+                })
+            },
+            (FS_PROTO, proto::DUP) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    entry.refs += 1;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::CLONE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    if entry.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let handle = Box::new(ReadOnly::new(entry.handle.duplicate()?));
+                    let path = entry.path.clone();
+                    s.insert_handle(handle, path, badge, u_inner)
+                })
+            },
+            (FS_PROTO, proto::LOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let entry = s.handles.get(&id).ok_or(Error::NotFound)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    s.locks.lock(&entry.lock, id, start, len, u_inner.get_mr(3))
+                })
+            },
+            (FS_PROTO, proto::UNLOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let entry = s.handles.get(&id).ok_or(Error::NotFound)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    let grants = s.locks.unlock(&entry.lock, id, start, len)?;
+                    s.complete_grants(grants);
+                    Ok(())
+                })
+            },
+            // MR0: handle, MR1: client address of the shared memory, MR2: its size;
+            // the memory's frame comes with the call
+            (FS_PROTO, protocol::fs::SETUP_IOURING) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let user_vaddr = u_inner.get_mr(1);
+                    let size = u_inner.get_mr(2);
+                    if size == 0 || size % PGSIZE != 0 {
+                        return Err(Error::InvalidArgs);
+                    }
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let entry = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(s.recv_slot, slot)?;
+                    let server_vaddr = s.maps.reserve(size, PGSIZE)?;
+                    let mapped = s.vspace.map_frame(
+                        Frame::from(slot),
+                        server_vaddr,
+                        Perms::READ | Perms::WRITE,
+                        size / PGSIZE,
+                        s.res_client,
+                        s.slots.cspace(),
+                    );
+                    if let Err(e) = mapped {
+                        let _ = s.maps.release(server_vaddr);
+                        return Err(e);
+                    }
+                    let ring = SharedRing::attach(server_vaddr, user_vaddr, size);
+                    if let Some(old) = entry.ring.replace(ring) {
+                        s.unmap_ring(&old);
+                    }
+                    Ok(())
+                })
+            },
+            // MR0: handle, MR1: bits; the endpoint to notify comes with the call
+            (FS_PROTO, proto::RING_NOTIFY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let ring = entry.ring.as_mut().ok_or(Error::InvalidArgs)?;
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(s.recv_slot, slot)?;
+                    let signal = RingSignal {
+                        notify: Endpoint::from(slot),
+                        bits: u_inner.get_mr(1),
+                        badge: badge.bits(),
+                    };
+                    if let Some(old) = ring.set_signal(signal) {
+                        CSPACE_CAP.delete(old.notify.cap())?;
+                        s.slots.free(old.notify.cap());
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::PROCESS_IOURING) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.process_ring(key(u_inner.get_mr(0))?, badge))
+            },
+            (FS_PROTO, protocol::fs::CLOSE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let id = key(u_inner.get_mr(0))?;
+                    let entry = s.handles.get_mut(&id).ok_or(Error::NotFound)?;
+                    entry.refs -= 1;
+                    if entry.refs == 0 {
+                        let grants = s.locks.release(id);
+                        s.complete_grants(grants);
+                        if let Some(mut entry) = s.handles.remove(&id) {
+                            if let Some(ring) = entry.ring.take() {
+                                s.unmap_ring(&ring);
+                            }
+                            entry.handle.close(badge)?;
+                        }
+                    }
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::GETDENTS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let count = core::cmp::min(
+                        u_inner.get_mr(1),
+                        proto::dents_capacity(u_inner.buffer()),
+                    );
+                    let entries = entry.handle.getdents(badge, count)?;
+                    let written = proto::encode_dents(u_inner.buffer_mut(), &entries);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::MKDIR) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let mode = u_inner.get_mr(0) as u32;
+                    s.fs.mkdir(badge, &Self::path(path::from_buffer(u_inner.buffer())?), mode)
+                })
+            },
+            (FS_PROTO, protocol::fs::UNLINK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    s.fs.unlink(badge, &Self::path(path::from_buffer(u_inner.buffer())?))
+                })
+            },
+            (FS_PROTO, proto::LINK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let (old_path, new_path) = path::pair_from_buffer(u_inner.buffer())?;
+                    s.fs.link(badge, &Self::path(old_path), &Self::path(new_path))
+                })
+            },
+            // buffer: path, MR0-MR3: access and modification times; see proto::SET_TIMES.
+            (FS_PROTO, proto::SET_TIMES) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let req = TimesRequest::from_utcb(u_inner)?;
+                    s.fs.set_times(badge, &Self::path(path::from_buffer(u_inner.buffer())?), req)
+                })
+            },
+            (FS_PROTO, protocol::fs::RENAME) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let (old_path, new_path) = path::pair_from_buffer(u_inner.buffer())?;
+                    s.fs.rename(badge, &Self::path(old_path), &Self::path(new_path))
+                })
+            },
+            // Runs within the call; there is no JOB_ASYNC variant
+            (FS_PROTO, proto::RMTREE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    if u_inner.get_mr(0) & proto::JOB_ASYNC != 0 {
+                        return Err(Error::NotSupported);
+                    }
+                    s.fs.rmtree(badge, &Self::path(path::from_buffer(u_inner.buffer())?))
+                })
+            },
+            (FS_PROTO, proto::REMOUNT_RO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.read_only = true;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::REMOUNT_RW) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    if !s.fs.writable() {
+                        return Err(FsError::ReadOnly.into());
+                    }
+                    // Only a new MOUNT_OPTIONS lifts a read-only mount
+                    if s.options.read_only {
+                        return Err(Error::PermissionDenied);
+                    }
+                    s.read_only = false;
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::MOUNT_OPTIONS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let options = MountOptions::from_bits(u_inner.get_mr(0))?;
+                    if !s.handles.is_empty() {
+                        return Err(Error::WouldBlock);
+                    }
+                    s.fs.set_mount_options(options)?;
+                    s.options = options;
+                    u_inner.set_mr(0, s.mounted_options());
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::UNMOUNT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.check_admin(badge)?;
+                    s.shutdown()
+                })
+            },
+            (FS_PROTO, proto::MOUNT_AT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_admin(badge)?;
+                    let path = path::from_buffer(u_inner.buffer())?;
+                    s.mount_point.move_to(path, s.endpoint)
+                })
+            },
+            (FS_PROTO, proto::STATFS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.fs.stats()?.encode(u_inner);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::VERSION) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.versions.negotiate(client.bits(), u_inner))
+            },
+            (FS_PROTO, proto::WIRE_PROTECT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.wire.configure(client.bits(), u_inner))
+            },
+            (FS_PROTO, proto::SET_CLOCK) => |_s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| clock::configure(client.bits(), u_inner))
+            },
+            (FS_PROTO, protocol::fs::STAT_PATH) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let path = Self::path(path::from_buffer(u_inner.buffer())?);
+                    let stat = s.fs.stat_path(badge, &path)?;
+                    u_inner.set_mr(0, stat.size);
+                    u_inner.set_mr(1, stat.mode as usize);
+                    u_inner.set_mr(2, s.fs.attr_timeout());
+                    u_inner.set_mr(3, stat.atime);
+                    u_inner.set_mr(4, stat.mtime);
+                    u_inner.set_mr(5, stat.ctime);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::SEEK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let offset = u_inner.get_mr(1) as i64;
+                    let pos = entry.handle.seek(badge, offset, u_inner.get_mr(2))?;
+                    u_inner.set_mr(0, pos);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, protocol::fs::TRUNCATE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    entry.handle.truncate(badge, u_inner.get_mr(1))
+                })
+            },
+            (FS_PROTO, proto::READ_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let len = u_inner.get_mr(1);
+                    if len > u_inner.buffer().len() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    // A backend calling out answers through this same buffer
+                    let mut data = vec![0u8; len];
+                    let read_len = entry.handle.read(badge, CURRENT_OFFSET, &mut data)?;
+                    u_inner.buffer_mut()[..read_len].copy_from_slice(&data[..read_len]);
+                    u_inner.set_buffer_len(read_len);
+                    u_inner.set_mr(0, read_len);
+                    Ok(())
+                })
+            },
+            (FS_PROTO, proto::WRITE_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.check_writable()?;
+                    let entry = s.handles.get_mut(&key(u_inner.get_mr(0))?).ok_or(Error::NotFound)?;
+                    let data = u_inner.buffer().to_vec();
+                    let written = entry.handle.write(badge, CURRENT_OFFSET, &data)?;
+                    u_inner.set_buffer_len(0);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
+            (PROCESS_PROTO, protocol::process::EXIT) => |s: &mut Self, _u: &mut UTCB| {
+                if let Err(e) = s.shutdown() {
+                    glenda::log!("{}: shutdown failed: {:?}", B::NAME, e);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn reply(&mut self, utcb: &mut UTCB) -> Result<(), Error> {
+        self.reply.reply(utcb)
+    }
+
+    fn stop(&mut self) {
+        self.running = false;
+    }
+}
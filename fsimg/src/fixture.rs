@@ -0,0 +1,101 @@
+//! The standard image contents: a handful of files whose bytes are a pure
+//! function of their name, so a test can check what it reads back without
+//! shipping the fixture.
+
+use std::fs;
+use std::fs::FileTimes;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// Every fixture file gets this mtime, so images come out byte-identical
+pub const FIXTURE_MTIME: u64 = 1_700_000_000;
+
+pub struct FixtureFile {
+    /// Path relative to the image root, '/' separated.
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// Byte `i` of a pattern file: cycles through 251 values so it never lines
+/// up with block or cluster sizes.
+pub fn pattern_byte(i: usize) -> u8 {
+    ((i * 31 + 7) % 251) as u8
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(pattern_byte).collect()
+}
+
+/// The standard set.
+pub fn standard() -> Vec<FixtureFile> {
+    let mut files = vec![
+        FixtureFile { path: "hello.txt".into(), data: b"Hello, Glenda!\n".to_vec() },
+        FixtureFile { path: "empty".into(), data: Vec::new() },
+        // One byte short of and one past common block and cluster sizes
+        FixtureFile { path: "pattern-4095.bin".into(), data: pattern(4095) },
+        FixtureFile { path: "pattern-4097.bin".into(), data: pattern(4097) },
+        // Spans many clusters and, on ext, the first indirect block
+        FixtureFile { path: "pattern-1m.bin".into(), data: pattern(1024 * 1024) },
+        FixtureFile { path: "zeros-64k.bin".into(), data: vec![0; 64 * 1024] },
+        FixtureFile { path: "dir/nested.txt".into(), data: b"nested\n".to_vec() },
+        FixtureFile { path: "dir/sub/deep.txt".into(), data: b"two levels down\n".to_vec() },
+    ];
+    // Enough entries to push a FAT directory past one cluster
+    for i in 0..64 {
+        files.push(FixtureFile {
+            path: format!("many/file-{:03}.txt", i),
+            data: format!("{}\n", i).into_bytes(),
+        });
+    }
+    files
+}
+
+/// Reads the files below `dir` instead of the standard set, in sorted order.
+pub fn from_dir(dir: &Path) -> io::Result<Vec<FixtureFile>> {
+    let mut files = Vec::new();
+    collect(dir, dir, &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn collect(root: &Path, dir: &Path, files: &mut Vec<FixtureFile>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(root, &path, files)?;
+            continue;
+        }
+        let rel = path.strip_prefix(root).expect("below root");
+        let rel = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>();
+        files.push(FixtureFile { path: rel.join("/"), data: fs::read(&path)? });
+    }
+    Ok(())
+}
+
+/// Writes `files` below a fresh `dir`, with fixed mtimes, for the mkfs tools
+/// to copy from.
+pub fn stage(dir: &Path, files: &[FixtureFile]) -> io::Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    fs::create_dir_all(dir)?;
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(FIXTURE_MTIME);
+    let times = FileTimes::new().set_accessed(time).set_modified(time);
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for file in files {
+        let path = dir.join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+            dirs.push(parent.to_path_buf());
+        }
+        fs::write(&path, &file.data)?;
+        fs::File::options().write(true).open(&path)?.set_times(times)?;
+    }
+    // Directories last, as creating their files touched them
+    dirs.push(dir.to_path_buf());
+    for d in dirs {
+        fs::File::open(&d)?.set_times(times).ok();
+    }
+    Ok(())
+}
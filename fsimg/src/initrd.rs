@@ -0,0 +1,129 @@
+//! Writer for the initrd format initrdfs mounts, version 2: a header holding
+//! the magic, version, hash kind, CRC-32, entry count and table length, then
+//! a table of variable-length entries, padded to 4096 bytes; file data
+//! follows, each file starting on a 4096-byte boundary. Entries name their
+//! parent directory by index, so every directory comes before what it holds.
+
+use crate::fixture::{FixtureFile, FIXTURE_MTIME};
+use std::io;
+
+const INITRD_MAGIC: u32 = 0x99999999;
+const V2_VERSION: u32 = 0x8000_0002;
+const HEADER_HASH: usize = 12;
+const ENTRY_BASE: usize = 32;
+// Fixed part of an entry; the name follows, padded to 8 bytes
+const ENTRY_FIXED: usize = 48;
+const NAME_MAX: usize = 255;
+// initrdfs refuses larger headers
+const MAX_HEADER_SIZE: usize = 1024 * 1024;
+const PARENT_ROOT: u32 = u32::MAX;
+const HASH_CRC32: u32 = 1;
+const ENTRY_FILE: u8 = 1;
+const ENTRY_DIR: u8 = 2;
+const FILE_MODE: u32 = 0o444;
+const DIR_MODE: u32 = 0o555;
+const DATA_ALIGN: usize = 4096;
+
+pub(crate) fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+struct Entry<'a> {
+    kind: u8,
+    name: &'a str,
+    parent: u32,
+    data: &'a [u8],
+}
+
+// Entries for `files` and the directories above them, each directory
+// listed once, before its first child
+fn entries(files: &[FixtureFile]) -> io::Result<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    // Path and table index of each directory added
+    let mut dirs: Vec<(&str, u32)> = Vec::new();
+    for file in files {
+        let (dir_path, name) = match file.path.rsplit_once('/') {
+            Some((dir, name)) => (Some(dir), name),
+            None => (None, file.path.as_str()),
+        };
+        let mut parent = PARENT_ROOT;
+        let mut end = 0;
+        for part in dir_path.into_iter().flat_map(|d| d.split('/')) {
+            check_name(&file.path, part)?;
+            end += part.len();
+            let path = &file.path[..end];
+            end += 1;
+            parent = match dirs.iter().find(|(p, _)| *p == path) {
+                Some(&(_, index)) => index,
+                None => {
+                    entries.push(Entry { kind: ENTRY_DIR, name: part, parent, data: &[] });
+                    let index = (entries.len() - 1) as u32;
+                    dirs.push((path, index));
+                    index
+                }
+            };
+        }
+        check_name(&file.path, name)?;
+        entries.push(Entry { kind: ENTRY_FILE, name, parent, data: &file.data });
+    }
+    Ok(entries)
+}
+
+fn check_name(path: &str, name: &str) -> io::Result<()> {
+    if name.is_empty() || name.len() > NAME_MAX || name == "." || name == ".." {
+        return Err(invalid(format!("{}: bad path component {:?}", path, name)));
+    }
+    Ok(())
+}
+
+/// Builds the image, with directories for the paths' components.
+pub fn build(files: &[FixtureFile]) -> io::Result<Vec<u8>> {
+    let entries = entries(files)?;
+    let table_len: usize =
+        entries.iter().map(|e| (ENTRY_FIXED + e.name.len()).next_multiple_of(8)).sum();
+    let header_size = (ENTRY_BASE + table_len).next_multiple_of(DATA_ALIGN);
+    if header_size > MAX_HEADER_SIZE {
+        return Err(invalid(format!("{} entries do not fit the header", entries.len())));
+    }
+
+    let mut image = vec![0u8; header_size];
+    image[0..4].copy_from_slice(&INITRD_MAGIC.to_le_bytes());
+    image[4..8].copy_from_slice(&V2_VERSION.to_le_bytes());
+    image[8..12].copy_from_slice(&HASH_CRC32.to_le_bytes());
+    image[16..20].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    image[20..24].copy_from_slice(&(table_len as u32).to_le_bytes());
+
+    let mut at = ENTRY_BASE;
+    for entry in &entries {
+        let offset = if entry.kind == ENTRY_FILE { image.len() } else { 0 };
+        let mode = if entry.kind == ENTRY_DIR { DIR_MODE } else { FILE_MODE };
+        image.extend_from_slice(entry.data);
+        image.resize(image.len().next_multiple_of(DATA_ALIGN), 0);
+
+        let raw = &mut image[at..at + ENTRY_FIXED + entry.name.len()];
+        raw[0] = entry.kind;
+        raw[2..4].copy_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        raw[4..8].copy_from_slice(&mode.to_le_bytes());
+        raw[8..12].copy_from_slice(&entry.parent.to_le_bytes());
+        raw[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+        raw[32..40].copy_from_slice(&(entry.data.len() as u64).to_le_bytes());
+        raw[40..48].copy_from_slice(&FIXTURE_MTIME.to_le_bytes());
+        raw[ENTRY_FIXED..].copy_from_slice(entry.name.as_bytes());
+        at += (ENTRY_FIXED + entry.name.len()).next_multiple_of(8);
+    }
+
+    // Taken over the header and entry table with the hash field zeroed
+    let crc = !crc32(!0, &image[..ENTRY_BASE + table_len]);
+    image[HEADER_HASH..HEADER_HASH + 4].copy_from_slice(&crc.to_le_bytes());
+    Ok(image)
+}
@@ -0,0 +1,159 @@
+//! Builds reproducible test images for the filesystem services:
+//!
+//!     fsimg <kind> <image> [--size-mib N] [--from DIR]
+//!     fsimg all <dir> [--from DIR]
+//!
+//! Kinds are ext2, ext3, ext4, fat16, fat32, exfat and initrd. Images hold
+//! the standard fixture (see fixture.rs) unless --from names a directory to
+//! copy instead. Next to each image, `<image>.manifest` lists every file as
+//! "crc32 size path" for tests to check against.
+
+mod fixture;
+mod initrd;
+mod mkfs;
+
+use fixture::FixtureFile;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Ext2,
+    Ext3,
+    Ext4,
+    Fat16,
+    Fat32,
+    ExFat,
+    Initrd,
+}
+
+const ALL: [Kind; 7] =
+    [Kind::Ext2, Kind::Ext3, Kind::Ext4, Kind::Fat16, Kind::Fat32, Kind::ExFat, Kind::Initrd];
+
+impl Kind {
+    fn parse(name: &str) -> Option<Self> {
+        ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Ext2 => "ext2",
+            Kind::Ext3 => "ext3",
+            Kind::Ext4 => "ext4",
+            Kind::Fat16 => "fat16",
+            Kind::Fat32 => "fat32",
+            Kind::ExFat => "exfat",
+            Kind::Initrd => "initrd",
+        }
+    }
+
+    // FAT32 needs 65525 clusters, which 64 MiB gives with 512-byte clusters
+    fn default_size_mib(self) -> u64 {
+        match self {
+            Kind::Fat32 => 64,
+            Kind::Fat16 | Kind::ExFat => 16,
+            _ => 32,
+        }
+    }
+}
+
+struct Options {
+    size_mib: Option<u64>,
+    from: Option<PathBuf>,
+}
+
+fn build(kind: Kind, image: &Path, opts: &Options) -> io::Result<()> {
+    let files = match &opts.from {
+        Some(dir) => fixture::from_dir(dir)?,
+        None => fixture::standard(),
+    };
+    let size_mib = opts.size_mib.unwrap_or(kind.default_size_mib());
+    let staging = image.with_extension("staging");
+    if !matches!(kind, Kind::Initrd | Kind::ExFat) {
+        fixture::stage(&staging, &files)?;
+    }
+
+    let built = match kind {
+        Kind::Ext2 | Kind::Ext3 | Kind::Ext4 => mkfs::ext(image, kind.name(), size_mib, &staging),
+        Kind::Fat16 => mkfs::fat(image, 16, size_mib, &staging),
+        Kind::Fat32 => mkfs::fat(image, 32, size_mib, &staging),
+        Kind::ExFat => mkfs::exfat(image, size_mib),
+        Kind::Initrd => initrd::build(&files).and_then(|data| fs::write(image, data)),
+    };
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    built?;
+
+    let listed: &[FixtureFile] = if kind == Kind::ExFat { &[] } else { &files };
+    write_manifest(&manifest_path(image), listed)?;
+    println!("{}: {} ({} files)", kind.name(), image.display(), listed.len());
+    Ok(())
+}
+
+fn manifest_path(image: &Path) -> PathBuf {
+    let mut name = image.as_os_str().to_owned();
+    name.push(".manifest");
+    PathBuf::from(name)
+}
+
+fn write_manifest(path: &Path, files: &[FixtureFile]) -> io::Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    for file in files {
+        let crc = !initrd::crc32(!0, &file.data);
+        writeln!(out, "{:08x} {} /{}", crc, file.data.len(), file.path)?;
+    }
+    out.flush()
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: fsimg <kind> <image> [--size-mib N] [--from DIR]");
+    eprintln!("       fsimg all <dir> [--from DIR]");
+    let kinds = ALL.iter().map(|k| k.name()).collect::<Vec<_>>();
+    eprintln!("kinds: {}", kinds.join(" "));
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        return usage();
+    }
+    let mut opts = Options { size_mib: None, from: None };
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match (arg.as_str(), rest.next()) {
+            ("--size-mib", Some(n)) => match n.parse() {
+                Ok(n) => opts.size_mib = Some(n),
+                Err(_) => return usage(),
+            },
+            ("--from", Some(dir)) => opts.from = Some(PathBuf::from(dir)),
+            _ => return usage(),
+        }
+    }
+
+    let target = PathBuf::from(&args[1]);
+    let result = if args[0] == "all" {
+        // Each kind gets its default size
+        opts.size_mib = None;
+        fs::create_dir_all(&target).and_then(|_| {
+            ALL.iter().try_for_each(|&kind| {
+                build(kind, &target.join(format!("{}.img", kind.name())), &opts)
+            })
+        })
+    } else {
+        match Kind::parse(&args[0]) {
+            Some(kind) => build(kind, &target, &opts),
+            None => return usage(),
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("fsimg: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Images built with the host's mkfs tools: e2fsprogs for ext2/3/4,
+//! dosfstools and mtools for FAT, exfatprogs for exFAT. Every random or
+//! time-based field the tools allow pinning is pinned.
+
+use crate::fixture::FIXTURE_MTIME;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+pub const VOLUME_LABEL: &str = "GLENDA";
+const EXT_UUID: &str = "476c656e-6461-4673-8000-000000000001";
+const FAT_VOLUME_ID: &str = "474c4e44";
+
+fn run(cmd: &mut Command) -> io::Result<()> {
+    let status = cmd.status().map_err(|e| {
+        let tool = cmd.get_program().to_string_lossy().into_owned();
+        io::Error::new(e.kind(), format!("{}: {}", tool, e))
+    })?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{:?} failed: {}", cmd, status)));
+    }
+    Ok(())
+}
+
+fn fresh(image: &Path) -> io::Result<()> {
+    match fs::remove_file(image) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// `fs_type` is ext2, ext3 or ext4; mke2fs copies `staging` in with -d.
+pub fn ext(image: &Path, fs_type: &str, size_mib: u64, staging: &Path) -> io::Result<()> {
+    fresh(image)?;
+    fs::File::create(image)?.set_len(size_mib * 1024 * 1024)?;
+    run(Command::new("mke2fs")
+        .env("E2FSPROGS_FAKE_TIME", FIXTURE_MTIME.to_string())
+        .args(["-q", "-F", "-t", fs_type, "-b", "4096", "-L", VOLUME_LABEL, "-U", EXT_UUID])
+        .arg("-E")
+        .arg(format!("root_owner=0:0,hash_seed={}", EXT_UUID))
+        .arg("-d")
+        .arg(staging)
+        .arg(image))?;
+
+    // -d copies the host's ctimes, which no one can set; overwrite them
+    let mut script = String::new();
+    let mut paths = vec![String::from("/")];
+    tree(staging, "", &mut paths)?;
+    for path in paths {
+        script.push_str(&format!("sif \"{}\" ctime @{}\n", path, FIXTURE_MTIME));
+    }
+    let script_path = image.with_extension("debugfs");
+    fs::write(&script_path, script)?;
+    let patched = run(Command::new("debugfs")
+        .arg("-w")
+        .arg("-f")
+        .arg(&script_path)
+        .arg(image)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null()));
+    fs::remove_file(&script_path)?;
+    patched
+}
+
+// Every path below `dir`, as seen from the image root
+fn tree(dir: &Path, prefix: &str, paths: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            tree(&entry.path(), &path, paths)?;
+        }
+        paths.push(path);
+    }
+    Ok(())
+}
+
+/// `bits` is 16 or 32. mkfs.fat formats, mcopy fills in `staging`.
+pub fn fat(image: &Path, bits: u32, size_mib: u64, staging: &Path) -> io::Result<()> {
+    fresh(image)?;
+    run(Command::new("mkfs.fat")
+        .args(["--invariant", "-C", "-F", &bits.to_string(), "-n", VOLUME_LABEL])
+        .args(["-i", FAT_VOLUME_ID])
+        .arg(image)
+        .arg((size_mib * 1024).to_string()))?;
+
+    let mut entries =
+        fs::read_dir(staging)?.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>, _>>()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    entries.sort();
+    run(Command::new("mcopy")
+        .env("MTOOLS_SKIP_CHECK", "1")
+        .args(["-s", "-m", "-i"])
+        .arg(image)
+        .args(&entries)
+        .arg("::/"))
+}
+
+/// No host tool writes files into an unmounted exFAT volume, so the image
+/// holds the empty, labelled filesystem only.
+pub fn exfat(image: &Path, size_mib: u64) -> io::Result<()> {
+    fresh(image)?;
+    fs::File::create(image)?.set_len(size_mib * 1024 * 1024)?;
+    run(Command::new("mkfs.exfat").args(["-L", VOLUME_LABEL]).arg(image))
+}
@@ -0,0 +1,437 @@
+//! The client side of the filesystem protocol, spoken over raw IPC so the
+//! suite sees exactly what a service puts on the wire. Handles are the ids
+//! OPEN returns, passed back in MR0 as fatfs and extfs expect.
+
+use crate::layout::{RING_ENTRIES, RING_PAGES, RING_QUEUE_SIZE};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use fs_common::errors::{self, FsError, ERROR_DETAIL_MR};
+use fs_common::events;
+use fs_common::history;
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use fs_common::stream::{IOURING_OP_READ_STREAM, STREAM_HEADER_SIZE, STREAM_MORE};
+
+// What the service notifies our ring endpoints with
+const RING_COMPLETION_BITS: usize = 1;
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CapPtr, CapType, Endpoint, Frame};
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::interface::{CSpaceService, ResourceService, VSpaceService};
+use glenda::io::uring::{IoUringBuffer, IoUringSqe};
+use glenda::ipc::{Badge, MsgFlags, MsgTag, UTCB};
+use glenda::mem::Perms;
+use glenda::protocol::fs::{DEntry, OpenFlags};
+use glenda::protocol::{self, FS_PROTO};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+pub struct FsConn {
+    ep: Endpoint,
+    // ERROR_DETAIL_MR of the last error reply
+    detail: Cell<usize>,
+}
+
+impl FsConn {
+    pub fn new(ep: Endpoint) -> Self {
+        Self { ep, detail: Cell::new(0) }
+    }
+
+    /// The FsError the last failed call came with, if the service gave one.
+    pub fn error_detail(&self) -> Option<FsError> {
+        FsError::from_code(self.detail.get())
+    }
+
+    /// Sends `label` with `mrs` and `buf`, and `cap` if given. Returns the
+    /// reply, or the error the service answered with.
+    fn call(
+        &self,
+        label: usize,
+        mrs: &[usize],
+        buf: &[u8],
+        cap: Option<CapPtr>,
+    ) -> Result<&'static mut UTCB, Error> {
+        let utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        for (i, &mr) in mrs.iter().enumerate() {
+            utcb.set_mr(i, mr);
+        }
+        let mut flags = MsgFlags::NONE;
+        if !buf.is_empty() {
+            utcb.buffer_mut()
+                .get_mut(..buf.len())
+                .ok_or(Error::MessageTooLong)?
+                .copy_from_slice(buf);
+            utcb.set_buffer_len(buf.len());
+            flags |= MsgFlags::HAS_BUFFER;
+        }
+        if let Some(cap) = cap {
+            utcb.set_cap_transfer(cap);
+            flags |= MsgFlags::HAS_CAP;
+        }
+        utcb.set_msg_tag(MsgTag::new(FS_PROTO, label, flags));
+        self.ep.call(utcb)?;
+        if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+            self.detail.set(utcb.get_mr(ERROR_DETAIL_MR));
+            return Err(errors::decode(utcb.get_mr(0)));
+        }
+        Ok(utcb)
+    }
+
+    /// Negotiates with the service. Returns (version, features) in use.
+    pub fn version(&self, version: usize, features: usize) -> Result<(usize, usize), Error> {
+        let utcb = self.call(proto::VERSION, &[version, features], &[], None)?;
+        Ok((utcb.get_mr(0), utcb.get_mr(1)))
+    }
+
+    pub fn open(&self, path: &str, flags: OpenFlags, mode: u32) -> Result<usize, Error> {
+        let utcb =
+            self.call(protocol::fs::OPEN, &[flags.bits(), mode as usize], &cstr(path), None)?;
+        Ok(utcb.get_mr(0))
+    }
+
+    /// OPEN through OPEN_ASYNC; blocks all the same, but the service keeps
+    /// serving others while it walks the path.
+    pub fn open_async(&self, path: &str, flags: OpenFlags, mode: u32) -> Result<usize, Error> {
+        let utcb =
+            self.call(proto::OPEN_ASYNC, &[flags.bits(), mode as usize], &cstr(path), None)?;
+        Ok(utcb.get_mr(0))
+    }
+
+    pub fn close(&self, handle: usize) -> Result<(), Error> {
+        self.call(protocol::fs::CLOSE, &[handle], &[], None).map(|_| ())
+    }
+
+    /// Another read-only handle on the open file `handle`.
+    pub fn clone_handle(&self, handle: usize) -> Result<usize, Error> {
+        Ok(self.call(proto::CLONE, &[handle], &[], None)?.get_mr(0))
+    }
+
+    /// (size, mode) of `path`.
+    pub fn stat_path(&self, path: &str) -> Result<(usize, u32), Error> {
+        let utcb = self.call(protocol::fs::STAT_PATH, &[], &cstr(path), None)?;
+        Ok((utcb.get_mr(0), utcb.get_mr(1) as u32))
+    }
+
+    /// Access, modification and change times of `path`, in seconds.
+    pub fn stat_times(&self, path: &str) -> Result<(usize, usize, usize), Error> {
+        let utcb = self.call(protocol::fs::STAT_PATH, &[], &cstr(path), None)?;
+        Ok((utcb.get_mr(3), utcb.get_mr(4), utcb.get_mr(5)))
+    }
+
+    /// Sets the access and modification times of `path`, each as seconds
+    /// and nanoseconds or proto::UTIME_NOW/UTIME_OMIT.
+    pub fn set_times(
+        &self,
+        path: &str,
+        atime: (usize, usize),
+        mtime: (usize, usize),
+    ) -> Result<(), Error> {
+        let mrs = [atime.0, atime.1, mtime.0, mtime.1];
+        self.call(proto::SET_TIMES, &mrs, &cstr(path), None).map(|_| ())
+    }
+
+    pub fn statfs(&self) -> Result<FsStats, Error> {
+        let utcb = self.call(proto::STATFS, &[], &[], None)?;
+        Ok(FsStats {
+            block_size: utcb.get_mr(0),
+            total_blocks: utcb.get_mr(1) as u64,
+            free_blocks: utcb.get_mr(2) as u64,
+            avail_blocks: utcb.get_mr(3) as u64,
+            total_inodes: utcb.get_mr(4) as u64,
+            free_inodes: utcb.get_mr(5) as u64,
+        })
+    }
+
+    pub fn mkdir(&self, path: &str, mode: u32) -> Result<(), Error> {
+        self.call(protocol::fs::MKDIR, &[mode as usize], &cstr(path), None).map(|_| ())
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<(), Error> {
+        self.call(protocol::fs::UNLINK, &[], &cstr(path), None).map(|_| ())
+    }
+
+    /// Removes `path` and everything below it before returning.
+    pub fn rmtree(&self, path: &str) -> Result<(), Error> {
+        self.call(proto::RMTREE, &[0], &cstr(path), None).map(|_| ())
+    }
+
+    pub fn rename(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let mut buf = cstr(old_path);
+        buf.extend_from_slice(&cstr(new_path));
+        self.call(protocol::fs::RENAME, &[], &buf, None).map(|_| ())
+    }
+
+    /// (generation, size) of each kept version of `path`, newest first.
+    pub fn history_list(&self, path: &str) -> Result<Vec<(u64, usize)>, Error> {
+        let utcb = self.call(proto::HISTORY_LIST, &[], &cstr(path), None)?;
+        let records = utcb.buffer().chunks_exact(history::VERSION_RECORD_SIZE);
+        let versions = records
+            .take(utcb.get_mr(0))
+            .map(|r| {
+                let generation = u64::from_le_bytes(r[..8].try_into().unwrap());
+                (generation, u64::from_le_bytes(r[8..16].try_into().unwrap()) as usize)
+            })
+            .collect();
+        Ok(versions)
+    }
+
+    pub fn history_restore(&self, path: &str, generation: u64) -> Result<(), Error> {
+        let args = [generation as usize];
+        self.call(proto::HISTORY_RESTORE, &args, &cstr(path), None).map(|_| ())
+    }
+
+    /// (sequence, kind, detail) of the events from `from` on, the sequence to
+    /// ask for next and how many were missed.
+    pub fn event_read(&self, from: u64) -> Result<(Vec<(u64, usize, usize)>, u64, usize), Error> {
+        let utcb = self.call(proto::EVENT_READ, &[from as usize], &[], None)?;
+        let field = |r: &[u8], at: usize| u64::from_le_bytes(r[at..at + 8].try_into().unwrap());
+        let found = utcb
+            .buffer()
+            .chunks_exact(events::EVENT_RECORD_SIZE)
+            .take(utcb.get_mr(2))
+            .map(|r| (field(r, 0), field(r, 8) as usize, field(r, 16) as usize))
+            .collect();
+        Ok((found, utcb.get_mr(0) as u64, utcb.get_mr(1)))
+    }
+
+    pub fn getxattr(&self, handle: usize, name: &str) -> Result<Vec<u8>, Error> {
+        let utcb = self.call(proto::GETXATTR, &[handle], &cstr(name), None)?;
+        Ok(utcb.buffer()[..utcb.get_mr(0)].to_vec())
+    }
+
+    /// Names of the extended attributes of `handle`.
+    pub fn listxattr(&self, handle: usize) -> Result<Vec<String>, Error> {
+        let utcb = self.call(proto::LISTXATTR, &[handle], &[], None)?;
+        let names = utcb.buffer()[..utcb.get_mr(0)]
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        Ok(names)
+    }
+
+    pub fn link(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let mut buf = cstr(old_path);
+        buf.extend_from_slice(&cstr(new_path));
+        self.call(proto::LINK, &[], &buf, None).map(|_| ())
+    }
+
+    pub fn seek(&self, handle: usize, offset: i64, whence: usize) -> Result<usize, Error> {
+        Ok(self.call(protocol::fs::SEEK, &[handle, offset as usize, whence], &[], None)?.get_mr(0))
+    }
+
+    /// Up to `count` entries of the directory `handle`; empty at the end.
+    pub fn getdents(&self, handle: usize, count: usize) -> Result<Vec<DEntry>, Error> {
+        let utcb = self.call(protocol::fs::GETDENTS, &[handle, count], &[], None)?;
+        let size = core::mem::size_of::<DEntry>();
+        let count = core::cmp::min(utcb.get_mr(0), proto::dents_capacity(utcb.buffer()));
+        let entries = (0..count)
+            .map(|i| unsafe {
+                core::ptr::read_unaligned(utcb.buffer()[i * size..].as_ptr() as *const DEntry)
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    pub fn lock(&self, handle: usize, start: usize, len: usize, flags: usize) -> Result<(), Error> {
+        self.call(proto::LOCK, &[handle, start, len, flags], &[], None).map(|_| ())
+    }
+
+    pub fn unlock(&self, handle: usize, start: usize, len: usize) -> Result<(), Error> {
+        self.call(proto::UNLOCK, &[handle, start, len], &[], None).map(|_| ())
+    }
+
+    pub fn read_next(&self, handle: usize, len: usize) -> Result<Vec<u8>, Error> {
+        let utcb = self.call(proto::READ_NEXT, &[handle, len], &[], None)?;
+        Ok(utcb.buffer()[..utcb.get_mr(0)].to_vec())
+    }
+
+    pub fn write_next(&self, handle: usize, data: &[u8]) -> Result<usize, Error> {
+        self.call(proto::WRITE_NEXT, &[handle], data, None).map(|utcb| utcb.get_mr(0))
+    }
+
+    pub fn fallocate(
+        &self,
+        handle: usize,
+        offset: usize,
+        len: usize,
+        flags: usize,
+    ) -> Result<(), Error> {
+        self.call(proto::FALLOCATE, &[handle, offset, len, flags], &[], None).map(|_| ())
+    }
+
+    /// Sets up a ring on `handle` in freshly allocated memory at `vaddr`,
+    /// RING_PAGES long.
+    pub fn setup_ring(
+        &self,
+        handle: usize,
+        vaddr: usize,
+        res_client: &mut ResourceClient,
+        cspace: &mut CSpaceManager,
+        vspace: &mut VSpaceManager,
+    ) -> Result<ClientRing, Error> {
+        let size = RING_PAGES * PGSIZE;
+        let slot = cspace.alloc(res_client)?;
+        res_client.alloc(Badge::null(), CapType::Frame, RING_PAGES, slot)?;
+        vspace.map_frame(
+            Frame::from(slot),
+            vaddr,
+            Perms::READ | Perms::WRITE,
+            RING_PAGES,
+            res_client,
+            cspace,
+        )?;
+        let ring = unsafe {
+            IoUringBuffer::new(vaddr as *mut u8, RING_QUEUE_SIZE, RING_ENTRIES, RING_ENTRIES)
+        };
+        self.call(protocol::fs::SETUP_IOURING, &[handle, vaddr, size], &[], Some(slot))?;
+        Ok(ClientRing { handle, ring, vaddr, size, notify: None, next_user_data: 1 })
+    }
+
+    /// Switches `ring` to notifications: from now on it is served on a
+    /// doorbell and announces its completions on an endpoint of our own.
+    pub fn enable_notify(
+        &self,
+        ring: &mut ClientRing,
+        res_client: &mut ResourceClient,
+        cspace: &mut CSpaceManager,
+    ) -> Result<(), Error> {
+        let slot = cspace.alloc(res_client)?;
+        res_client.alloc(Badge::null(), CapType::Endpoint, 0, slot)?;
+        self.call(proto::RING_NOTIFY, &[ring.handle, RING_COMPLETION_BITS], &[], Some(slot))?;
+        ring.notify = Some(Endpoint::from(slot));
+        Ok(())
+    }
+
+    /// Asks the service to serve our notifying rings, without waiting.
+    pub fn doorbell(&self) -> Result<(), Error> {
+        self.ep.notify(proto::RING_DOORBELL_BITS)
+    }
+
+    /// Has the service work through what was submitted on `ring`.
+    pub fn process_ring(&self, ring: &ClientRing) -> Result<(), Error> {
+        self.call(protocol::fs::PROCESS_IOURING, &[ring.handle], &[], None).map(|_| ())
+    }
+}
+
+fn cstr(s: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(s.len() + 1);
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    buf
+}
+
+/// A ring set up on one handle. Data buffers live in the shared memory past
+/// the queues, addressed by their offset from `data()`.
+pub struct ClientRing {
+    handle: usize,
+    ring: IoUringBuffer,
+    vaddr: usize,
+    size: usize,
+    // Set by enable_notify
+    notify: Option<Endpoint>,
+    next_user_data: u64,
+}
+
+impl ClientRing {
+    /// The shared memory after the queues.
+    pub fn data(&mut self) -> &mut [u8] {
+        let len = self.size - RING_QUEUE_SIZE;
+        unsafe { core::slice::from_raw_parts_mut((self.vaddr + RING_QUEUE_SIZE) as *mut u8, len) }
+    }
+
+    /// Address of `at` bytes into `data()`, as SQEs carry it.
+    pub fn data_addr(&self, at: usize) -> u64 {
+        (self.vaddr + RING_QUEUE_SIZE + at) as u64
+    }
+
+    /// Submits one SQE, has `conn` process the ring and returns what its
+    /// completion reports: a byte count, or the error negated into `res`.
+    pub fn run(
+        &mut self,
+        conn: &FsConn,
+        opcode: u8,
+        off: u64,
+        addr: u64,
+        len: u32,
+    ) -> Result<usize, Error> {
+        let user_data = self.next_user_data;
+        self.next_user_data += 1;
+        let sqe = IoUringSqe { opcode, off, addr, len, user_data, ..Default::default() };
+        self.ring.submit(sqe).map_err(|_| Error::WouldBlock)?;
+        match self.notify {
+            // Ring the service and sleep until it announces completions
+            Some(notify) => {
+                conn.doorbell()?;
+                notify.recv(unsafe { UTCB::new() })?;
+            }
+            None => conn.process_ring(self)?,
+        }
+        while let Some(cqe) = self.ring.pop_cqe() {
+            if cqe.user_data == user_data {
+                return match cqe.res {
+                    res if res < 0 => Err(errors::decode(res.unsigned_abs() as usize)),
+                    res => Ok(res as usize),
+                };
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Reads `len` bytes at `off` (0: up to the end) with READ_STREAM, through
+    /// `slots` buffers of `chunk` bytes at the start of `data()`, handing each
+    /// one back as soon as it is copied out.
+    pub fn stream(
+        &mut self,
+        conn: &FsConn,
+        off: u64,
+        len: u32,
+        chunk: usize,
+        slots: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let user_data = self.next_user_data;
+        self.next_user_data += 1;
+        let header = &mut self.data()[..STREAM_HEADER_SIZE];
+        header[..4].copy_from_slice(&(chunk as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&(slots as u32).to_le_bytes());
+        header[8..].fill(0);
+        let addr = self.data_addr(0);
+        let opcode = IOURING_OP_READ_STREAM;
+        let sqe = IoUringSqe { opcode, off, addr, len, user_data, ..Default::default() };
+        self.ring.submit(sqe).map_err(|_| Error::WouldBlock)?;
+
+        let mut out = Vec::new();
+        let mut consumed = 0u64;
+        loop {
+            match self.notify {
+                Some(notify) => {
+                    conn.doorbell()?;
+                    notify.recv(unsafe { UTCB::new() })?;
+                }
+                None => conn.process_ring(self)?,
+            }
+            let mut progressed = false;
+            while let Some(cqe) = self.ring.pop_cqe() {
+                if cqe.user_data != user_data {
+                    continue;
+                }
+                progressed = true;
+                if cqe.res < 0 {
+                    return Err(errors::decode(cqe.res.unsigned_abs() as usize));
+                }
+                if cqe.flags & STREAM_MORE == 0 {
+                    return Ok(out);
+                }
+                let at = STREAM_HEADER_SIZE + (consumed % slots as u64) as usize * chunk;
+                out.extend_from_slice(&self.data()[at..at + cqe.res as usize]);
+                consumed += 1;
+                self.data()[16..24].copy_from_slice(&consumed.to_le_bytes());
+            }
+            if !progressed {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
@@ -0,0 +1,13 @@
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::CapPtr;
+pub const FS_SLOT: CapPtr = CapPtr::from(10);
+
+// Shared memory of the test rings is mapped from here on
+pub const RING_VADDR_BASE: usize = 0x6000_0000;
+// Each ring: the queues in the first page, data buffers in the rest
+pub const RING_PAGES: usize = 4;
+pub const RING_QUEUE_SIZE: usize = PGSIZE;
+pub const RING_ENTRIES: usize = 16;
+
+// Directory the suite creates its files in, removed again at the end
+pub const SCRATCH_DIR: &str = "/fstest.tmp";
@@ -0,0 +1,46 @@
+//! Runs a scripted suite against the filesystem behind the FS endpoint and
+//! logs a verdict per case to the console. The exit code is the number of
+//! failed cases, so the monitor sees a failing run as a failing service.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use glenda::cap::Endpoint;
+use glenda::client::ResourceClient;
+use glenda::interface::ResourceService;
+use glenda::ipc::Badge;
+use glenda::protocol::resource::{ResourceType, FS_ENDPOINT};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use layout::FS_SLOT;
+
+mod client;
+mod layout;
+#[macro_use]
+mod suite;
+mod posix;
+
+#[unsafe(no_mangle)]
+fn main() -> usize {
+    glenda::console::init_logging("FsTest");
+
+    let mut res_client = ResourceClient::new(glenda::cap::MONITOR_CAP);
+    let mut cspace = CSpaceManager::new(glenda::cap::CSPACE_CAP, 16);
+    let mut vspace = VSpaceManager::new(glenda::cap::VSPACE_CAP, 0x7000_0000, 0x8000_0000);
+
+    let fs_cap = res_client
+        .get_cap(Badge::null(), ResourceType::Endpoint, FS_ENDPOINT, FS_SLOT)
+        .expect("FsTest: Failed to get FS endpoint");
+    let conn = client::FsConn::new(Endpoint::from(fs_cap));
+
+    let mut ctx = suite::Ctx::new(conn, &mut res_client, &mut cspace, &mut vspace);
+    let summary = suite::run(&mut ctx);
+    glenda::log!(
+        "FsTest: {} passed, {} failed, {} skipped",
+        summary.passed,
+        summary.failed,
+        summary.skipped
+    );
+    summary.failed
+}
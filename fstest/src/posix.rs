@@ -0,0 +1,270 @@
+//! Cases ported from pjdfstest, for filesystems that keep POSIX semantics
+//! (extfs, tmpfs); run with the `posix` feature. The protocol has no errno,
+//! so each case expects the error the services map it to, and the FsError
+//! detail where the service negotiated FEAT_ERROR_DETAIL:
+//!
+//! ENOENT                        NotFound
+//! EEXIST                        AlreadyExists
+//! ENOTDIR                       InvalidArgs, FsError::NotDir
+//! EISDIR                        InvalidArgs, FsError::IsDir
+//! ENOTEMPTY, EINVAL             InvalidArgs
+//! EACCES, EPERM                 PermissionDenied
+//! EROFS                         PermissionDenied, FsError::ReadOnly
+
+use crate::suite::{
+    expect_err, expect_fs_err, pattern_byte, scratch, step, Case, Check, Ctx, Fail, S_IFDIR,
+    S_IFMT, S_IFREG,
+};
+use alloc::vec::Vec;
+use fs_common::errors::FsError;
+use fs_common::version::{FEAT_LINK, FEAT_STATFS};
+use glenda::error::Error;
+use glenda::io::uring::IOURING_OP_FSYNC;
+use glenda::protocol::fs::OpenFlags;
+
+pub const CASES: &[Case] = &[
+    Case { name: "posix-mode-bits", writes: true, needs: 0, run: mode_bits },
+    Case { name: "posix-open-excl", writes: true, needs: 0, run: open_excl },
+    Case { name: "posix-open-trunc", writes: true, needs: 0, run: open_trunc },
+    Case { name: "posix-open-append", writes: true, needs: 0, run: open_append },
+    Case { name: "posix-enotdir", writes: true, needs: 0, run: enotdir },
+    Case { name: "posix-eisdir", writes: true, needs: 0, run: eisdir },
+    Case { name: "posix-unlink-open", writes: true, needs: 0, run: unlink_open },
+    Case { name: "posix-unlink-dir", writes: true, needs: 0, run: unlink_dir },
+    Case { name: "posix-link", writes: true, needs: FEAT_LINK, run: link },
+    Case { name: "posix-rename-replace", writes: true, needs: 0, run: rename_replace },
+    Case { name: "posix-rename-self", writes: true, needs: 0, run: rename_self },
+    Case { name: "posix-rename-into-self", writes: true, needs: 0, run: rename_into_self },
+    Case { name: "posix-rename-file-over-dir", writes: true, needs: 0, run: rename_file_over_dir },
+    Case { name: "posix-rename-over-full-dir", writes: true, needs: 0, run: rename_over_full_dir },
+    Case { name: "posix-rename-missing", writes: true, needs: 0, run: rename_missing },
+];
+
+// RENAME is optional in the protocol; a service without it skips these
+fn rename(ctx: &Ctx, from: &str, to: &str) -> Result<(), Error> {
+    ctx.conn.rename(&scratch(from), &scratch(to))
+}
+
+fn renamed(r: Result<(), Error>, what: &str) -> Check {
+    match r {
+        Err(Error::NotSupported | Error::NotImplemented) => Err(Fail::Skip("no RENAME")),
+        r => step(r, what),
+    }
+}
+
+fn rename_err(r: Result<(), Error>, want: Error, what: &str) -> Check {
+    match r {
+        Err(Error::NotSupported | Error::NotImplemented) => Err(Fail::Skip("no RENAME")),
+        r => expect_err(r, want, what),
+    }
+}
+
+// Creates `name` holding `len` pattern bytes
+fn create_pattern(ctx: &mut Ctx, name: &str, len: usize) -> Check {
+    let h = ctx.create(name)?;
+    let mut ring = ctx.ring(h)?;
+    let data: Vec<u8> = (0..len).map(pattern_byte).collect();
+    ctx.write(&mut ring, 0, 0, &data)?;
+    step(ctx.conn.close(h), "close")
+}
+
+fn mode_bits(ctx: &mut Ctx) -> Check {
+    let h = ctx.create_mode("mode-file", 0o640)?;
+    step(ctx.conn.close(h), "close")?;
+    let (_, mode) = step(ctx.conn.stat_path(&scratch("mode-file")), "stat file")?;
+    ensure!(mode & S_IFMT == S_IFREG, "file has mode {:o}, not a regular file", mode);
+    ensure!(mode & 0o7777 == 0o640, "file has mode {:o}, created with 640", mode & 0o7777);
+
+    step(ctx.conn.mkdir(&scratch("mode-dir"), 0o750), "mkdir")?;
+    let (_, mode) = step(ctx.conn.stat_path(&scratch("mode-dir")), "stat dir")?;
+    ensure!(mode & S_IFMT == S_IFDIR, "dir has mode {:o}, not a directory", mode);
+    ensure!(mode & 0o7777 == 0o750, "dir has mode {:o}, created with 750", mode & 0o7777);
+    Ok(())
+}
+
+fn open_excl(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("excl")?;
+    step(ctx.conn.close(h), "close")?;
+    let flags = OpenFlags::O_RDWR | OpenFlags::O_CREAT | OpenFlags::O_EXCL;
+    expect_err(ctx.conn.open(&scratch("excl"), flags, 0o644), Error::AlreadyExists, "O_EXCL")
+}
+
+fn open_trunc(ctx: &mut Ctx) -> Check {
+    create_pattern(ctx, "trunc", 5000)?;
+    let h =
+        step(ctx.conn.open(&scratch("trunc"), OpenFlags::O_RDWR | OpenFlags::O_TRUNC, 0), "open")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("trunc")), "stat")?;
+    ensure!(size == 0, "size {} after O_TRUNC", size);
+    step(ctx.conn.close(h), "close")
+}
+
+// Writes land at the end whatever offset they name
+fn open_append(ctx: &mut Ctx) -> Check {
+    create_pattern(ctx, "append", 100)?;
+    let h = step(
+        ctx.conn.open(&scratch("append"), OpenFlags::O_RDWR | OpenFlags::O_APPEND, 0),
+        "open",
+    )?;
+    let mut ring = ctx.ring(h)?;
+    ctx.write(&mut ring, 0, 0, &[0xAA; 50])?;
+    step(ring.run(&ctx.conn, IOURING_OP_FSYNC, 0, 0, 0), "fsync")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("append")), "stat")?;
+    ensure!(size == 150, "size {} after appending 50 bytes to 100", size);
+    let head = ctx.read(&mut ring, 0, 0, 100)?;
+    ensure!((0..100).all(|i| head[i] == pattern_byte(i)), "O_APPEND write overwrote the start");
+    let tail = ctx.read(&mut ring, 0, 100, 50)?;
+    ensure!(tail == [0xAA; 50], "appended bytes read back as {:?}", tail);
+    step(ctx.conn.close(h), "close")
+}
+
+fn enotdir(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("notdir")?;
+    step(ctx.conn.close(h), "close")?;
+    let below = scratch("notdir/x");
+    let open = ctx.conn.open(&below, OpenFlags::O_RDONLY, 0);
+    expect_fs_err(ctx, open, FsError::NotDir, "open below a file")?;
+    let create = ctx.conn.open(&below, OpenFlags::O_RDWR | OpenFlags::O_CREAT, 0o644);
+    expect_fs_err(ctx, create, FsError::NotDir, "create below a file")?;
+    expect_fs_err(ctx, ctx.conn.mkdir(&below, 0o755), FsError::NotDir, "mkdir below a file")?;
+    let open = ctx.conn.open(&scratch("notdir"), OpenFlags::O_DIRECTORY, 0);
+    expect_fs_err(ctx, open, FsError::NotDir, "O_DIRECTORY on a file")
+}
+
+fn eisdir(ctx: &mut Ctx) -> Check {
+    let dir = scratch("isdir");
+    step(ctx.conn.mkdir(&dir, 0o755), "mkdir")?;
+    expect_fs_err(ctx, ctx.conn.open(&dir, OpenFlags::O_WRONLY, 0), FsError::IsDir, "O_WRONLY")?;
+    expect_fs_err(ctx, ctx.conn.open(&dir, OpenFlags::O_RDWR, 0), FsError::IsDir, "O_RDWR")?;
+    let h = step(ctx.conn.open(&dir, OpenFlags::O_RDONLY, 0), "O_RDONLY")?;
+    step(ctx.conn.close(h), "close")
+}
+
+// An unlinked file lives on until its last handle closes, its blocks and
+// inode out of reach of new files until then
+fn unlink_open(ctx: &mut Ctx) -> Check {
+    let len = 5000;
+    create_pattern(ctx, "unlink-open", len)?;
+    let h = step(ctx.conn.open(&scratch("unlink-open"), OpenFlags::O_RDONLY, 0), "open")?;
+    let mut ring = ctx.ring(h)?;
+    step(ctx.conn.unlink(&scratch("unlink-open")), "unlink")?;
+    expect_err(ctx.conn.stat_path(&scratch("unlink-open")), Error::NotFound, "stat")?;
+
+    // Takes the freed blocks, had the unlink freed any
+    let filler = ctx.create("unlink-open-filler")?;
+    let mut filler_ring = ctx.ring(filler)?;
+    let data: Vec<u8> = (0..len).map(|i| !pattern_byte(i)).collect();
+    ctx.write(&mut filler_ring, 0, 0, &data)?;
+    step(ctx.conn.close(filler), "close filler")?;
+
+    let got = ctx.read(&mut ring, 0, 0, len)?;
+    ensure!(got.len() == len, "read {} of {} bytes after unlink", got.len(), len);
+    if let Some(i) = (0..len).find(|&i| got[i] != pattern_byte(i)) {
+        return Err(Fail::Error(alloc::format!("byte {} changed after unlink", i)));
+    }
+    let before = free_inodes(ctx)?;
+    step(ctx.conn.close(h), "close")?;
+    if let (Some(before), Some(after)) = (before, free_inodes(ctx)?) {
+        ensure!(
+            after == before + 1,
+            "{} free inodes after the last close, {} before",
+            after,
+            before
+        );
+    }
+    step(ctx.conn.unlink(&scratch("unlink-open-filler")), "unlink filler")
+}
+
+// Free inodes by STATFS, where the service reports them
+fn free_inodes(ctx: &Ctx) -> Result<Option<u64>, Fail> {
+    if ctx.features & FEAT_STATFS == 0 {
+        return Ok(None);
+    }
+    let stats = step(ctx.conn.statfs(), "statfs")?;
+    Ok((stats.total_inodes != 0).then_some(stats.free_inodes))
+}
+
+fn unlink_dir(ctx: &mut Ctx) -> Check {
+    let dir = scratch("unlink-dir");
+    step(ctx.conn.mkdir(&dir, 0o755), "mkdir")?;
+    let h = ctx.create("unlink-dir/child")?;
+    step(ctx.conn.close(h), "close")?;
+    expect_err(ctx.conn.unlink(&dir), Error::InvalidArgs, "unlink non-empty dir")?;
+
+    step(ctx.conn.unlink(&scratch("unlink-dir/child")), "unlink child")?;
+    step(ctx.conn.unlink(&dir), "unlink empty dir")?;
+    expect_err(ctx.conn.stat_path(&dir), Error::NotFound, "stat")
+}
+
+// Either name keeps the file alive; directories cannot be linked
+fn link(ctx: &mut Ctx) -> Check {
+    let len = 3000;
+    create_pattern(ctx, "link-a", len)?;
+    step(ctx.conn.link(&scratch("link-a"), &scratch("link-b")), "link")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("link-b")), "stat link")?;
+    ensure!(size == len, "link has {} bytes, the file {}", size, len);
+    let taken = ctx.conn.link(&scratch("link-a"), &scratch("link-b"));
+    expect_err(taken, Error::AlreadyExists, "link onto an existing name")?;
+
+    step(ctx.conn.unlink(&scratch("link-a")), "unlink first name")?;
+    let h = step(ctx.conn.open(&scratch("link-b"), OpenFlags::O_RDONLY, 0), "open link")?;
+    let mut ring = ctx.ring(h)?;
+    let got = ctx.read(&mut ring, 0, 0, len)?;
+    ensure!(got.len() == len, "read {} of {} bytes through the link", got.len(), len);
+    if let Some(i) = (0..len).find(|&i| got[i] != pattern_byte(i)) {
+        return Err(Fail::Error(alloc::format!("byte {} differs through the link", i)));
+    }
+    step(ctx.conn.close(h), "close")?;
+
+    step(ctx.conn.mkdir(&scratch("link-dir"), 0o755), "mkdir")?;
+    let dir = ctx.conn.link(&scratch("link-dir"), &scratch("link-dir2"));
+    expect_err(dir, Error::PermissionDenied, "link a directory")
+}
+
+fn rename_replace(ctx: &mut Ctx) -> Check {
+    create_pattern(ctx, "replace-from", 3)?;
+    create_pattern(ctx, "replace-to", 10)?;
+    renamed(rename(ctx, "replace-from", "replace-to"), "rename")?;
+    expect_err(ctx.conn.stat_path(&scratch("replace-from")), Error::NotFound, "stat old")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("replace-to")), "stat new")?;
+    ensure!(size == 3, "target has {} bytes, expected the 3 of the source", size);
+    Ok(())
+}
+
+// Renaming a file onto itself succeeds and changes nothing
+fn rename_self(ctx: &mut Ctx) -> Check {
+    create_pattern(ctx, "self", 7)?;
+    renamed(rename(ctx, "self", "self"), "rename")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("self")), "stat")?;
+    ensure!(size == 7, "file has {} bytes after renaming onto itself, expected 7", size);
+    Ok(())
+}
+
+fn rename_into_self(ctx: &mut Ctx) -> Check {
+    step(ctx.conn.mkdir(&scratch("outer"), 0o755), "mkdir")?;
+    step(ctx.conn.mkdir(&scratch("outer/inner"), 0o755), "mkdir inner")?;
+    rename_err(rename(ctx, "outer", "outer/inner/moved"), Error::InvalidArgs, "rename")?;
+    step(ctx.conn.stat_path(&scratch("outer/inner")), "stat").map(|_| ())
+}
+
+fn rename_file_over_dir(ctx: &mut Ctx) -> Check {
+    create_pattern(ctx, "over-file", 1)?;
+    step(ctx.conn.mkdir(&scratch("over-dir"), 0o755), "mkdir")?;
+    rename_err(rename(ctx, "over-file", "over-dir"), Error::InvalidArgs, "file over dir")?;
+    rename_err(rename(ctx, "over-dir", "over-file"), Error::InvalidArgs, "dir over file")
+}
+
+fn rename_over_full_dir(ctx: &mut Ctx) -> Check {
+    step(ctx.conn.mkdir(&scratch("full-from"), 0o755), "mkdir")?;
+    step(ctx.conn.mkdir(&scratch("full-to"), 0o755), "mkdir target")?;
+    create_pattern(ctx, "full-to/child", 1)?;
+    rename_err(rename(ctx, "full-from", "full-to"), Error::InvalidArgs, "rename")?;
+
+    // An empty target directory is replaced
+    step(ctx.conn.unlink(&scratch("full-to/child")), "unlink child")?;
+    renamed(rename(ctx, "full-from", "full-to"), "rename over empty dir")?;
+    expect_err(ctx.conn.stat_path(&scratch("full-from")), Error::NotFound, "stat old")
+}
+
+fn rename_missing(ctx: &mut Ctx) -> Check {
+    rename_err(rename(ctx, "missing", "anything"), Error::NotFound, "rename")
+}
@@ -0,0 +1,740 @@
+//! The scripted cases. Each runs on its own files below SCRATCH_DIR, so one
+//! failing leaves the others meaningful; cases that modify the volume are
+//! skipped when the filesystem turns out to be read-only.
+
+use crate::client::{ClientRing, FsConn};
+use crate::layout::{RING_PAGES, RING_VADDR_BASE, SCRATCH_DIR};
+use crate::posix;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_common::errors::FsError;
+use fs_common::events::EV_ALL;
+use fs_common::locks::LOCK_EXCLUSIVE;
+use fs_common::proto::{
+    DT_DIR, DT_REG, FALLOC_KEEP_SIZE, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET, UTIME_OMIT,
+};
+use fs_common::ring::{IOURING_OP_READV, IOURING_OP_WRITEV, IOVEC_SIZE};
+use fs_common::version::{
+    FEAT_ALL, FEAT_CLONE, FEAT_ERROR_DETAIL, FEAT_EVENTS, FEAT_FALLOCATE, FEAT_HANDLE_ENDPOINTS,
+    FEAT_HISTORY, FEAT_IOVEC, FEAT_LOCKS, FEAT_NEXT, FEAT_OPEN_ASYNC, FEAT_RING_NOTIFY,
+    FEAT_SET_TIMES, FEAT_STATFS, FEAT_STREAM, FEAT_XATTR, PROTO_VERSION,
+};
+use glenda::arch::mem::PGSIZE;
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::io::uring::{IOURING_OP_FSYNC, IOURING_OP_READ, IOURING_OP_WRITE};
+use glenda::protocol::fs::{DEntry, OpenFlags};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+
+// What fsimg puts into its standard images
+const FIXTURE_HELLO: &[u8] = b"Hello, Glenda!\n";
+
+pub enum Fail {
+    Error(String),
+    Skip(&'static str),
+}
+
+pub type Check = Result<(), Fail>;
+
+macro_rules! ensure {
+    ($cond:expr, $($msg:tt)*) => {
+        if !$cond {
+            return Err(Fail::Error(alloc::format!($($msg)*)));
+        }
+    };
+}
+
+/// The result of `r`, or a failure naming the step that went wrong.
+pub fn step<T>(r: Result<T, Error>, what: &str) -> Result<T, Fail> {
+    r.map_err(|e| Fail::Error(format!("{}: {:?}", what, e)))
+}
+
+/// Passes if `r` failed with `want`.
+pub fn expect_err<T>(r: Result<T, Error>, want: Error, what: &str) -> Check {
+    match r {
+        Err(e) if e == want => Ok(()),
+        Err(e) => Err(Fail::Error(format!("{}: {:?}, expected {:?}", what, e, want))),
+        Ok(_) => Err(Fail::Error(format!("{}: succeeded, expected {:?}", what, want))),
+    }
+}
+
+/// `expect_err` for the error `want` maps to; with FEAT_ERROR_DETAIL the
+/// reply must name `want` itself.
+pub fn expect_fs_err<T>(ctx: &Ctx, r: Result<T, Error>, want: FsError, what: &str) -> Check {
+    expect_err(r, want.base(), what)?;
+    if ctx.features & FEAT_ERROR_DETAIL == 0 {
+        return Ok(());
+    }
+    match ctx.conn.error_detail() {
+        Some(detail) if detail == want => Ok(()),
+        detail => Err(Fail::Error(format!("{}: detail {:?}, expected {:?}", what, detail, want))),
+    }
+}
+
+pub fn scratch(name: &str) -> String {
+    format!("{}/{}", SCRATCH_DIR, name)
+}
+
+/// Byte `i` of the pattern files, matching fsimg's fixture.
+pub fn pattern_byte(i: usize) -> u8 {
+    ((i * 31 + 7) % 251) as u8
+}
+
+pub struct Ctx<'a> {
+    pub conn: FsConn,
+    res_client: &'a mut ResourceClient,
+    cspace: &'a mut CSpaceManager,
+    vspace: &'a mut VSpaceManager,
+    // Where the next ring's memory goes; rings live until the suite exits
+    next_vaddr: usize,
+    // Agreed with the service through VERSION
+    pub features: usize,
+}
+
+impl<'a> Ctx<'a> {
+    pub fn new(
+        conn: FsConn,
+        res_client: &'a mut ResourceClient,
+        cspace: &'a mut CSpaceManager,
+        vspace: &'a mut VSpaceManager,
+    ) -> Self {
+        Self { conn, res_client, cspace, vspace, next_vaddr: RING_VADDR_BASE, features: 0 }
+    }
+
+    pub fn ring(&mut self, handle: usize) -> Result<ClientRing, Fail> {
+        let vaddr = self.next_vaddr;
+        self.next_vaddr += RING_PAGES * PGSIZE;
+        let ring = self.conn.setup_ring(handle, vaddr, self.res_client, self.cspace, self.vspace);
+        step(ring, "setup ring")
+    }
+
+    /// A ring on `handle` that is served on doorbells instead of calls.
+    fn notified_ring(&mut self, handle: usize) -> Result<ClientRing, Fail> {
+        let mut ring = self.ring(handle)?;
+        let enabled = self.conn.enable_notify(&mut ring, self.res_client, self.cspace);
+        step(enabled, "enable notify")?;
+        Ok(ring)
+    }
+
+    pub fn create(&self, name: &str) -> Result<usize, Fail> {
+        self.create_mode(name, 0o644)
+    }
+
+    pub fn create_mode(&self, name: &str, mode: u32) -> Result<usize, Fail> {
+        let flags = OpenFlags::O_RDWR | OpenFlags::O_CREAT | OpenFlags::O_TRUNC;
+        step(self.conn.open(&scratch(name), flags, mode), "create")
+    }
+
+    /// Writes `data` to `ring`'s buffer at `at` and on to the file at `off`.
+    pub fn write(&self, ring: &mut ClientRing, at: usize, off: u64, data: &[u8]) -> Check {
+        ring.data()[at..at + data.len()].copy_from_slice(data);
+        let addr = ring.data_addr(at);
+        let n =
+            step(ring.run(&self.conn, IOURING_OP_WRITE, off, addr, data.len() as u32), "write")?;
+        ensure!(n == data.len(), "write: {} of {} bytes", n, data.len());
+        Ok(())
+    }
+
+    /// Reads up to `len` bytes at `off` through `ring`'s buffer at `at`.
+    pub fn read(
+        &self,
+        ring: &mut ClientRing,
+        at: usize,
+        off: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Fail> {
+        ring.data()[at..at + len].fill(0);
+        let addr = ring.data_addr(at);
+        let n = step(ring.run(&self.conn, IOURING_OP_READ, off, addr, len as u32), "read")?;
+        Ok(ring.data()[at..at + n].to_vec())
+    }
+}
+
+pub struct Case {
+    pub name: &'static str,
+    // Modifies the volume; skipped on read-only filesystems
+    pub writes: bool,
+    // version::FEAT_* bits the case relies on; skipped without them
+    pub needs: usize,
+    pub run: fn(&mut Ctx) -> Check,
+}
+
+pub const CASES: &[Case] = &[
+    Case { name: "stat-root", writes: false, needs: 0, run: stat_root },
+    Case { name: "open-missing", writes: false, needs: 0, run: open_missing },
+    Case { name: "fixture-read", writes: false, needs: 0, run: fixture_read },
+    Case { name: "fixture-list", writes: false, needs: 0, run: fixture_list },
+    Case { name: "open-async", writes: false, needs: FEAT_OPEN_ASYNC, run: open_async },
+    Case { name: "xattr-read", writes: false, needs: FEAT_XATTR, run: xattr_read },
+    Case { name: "events", writes: false, needs: FEAT_EVENTS, run: events },
+    Case { name: "statfs", writes: false, needs: FEAT_STATFS, run: statfs },
+    Case { name: "write-read", writes: true, needs: 0, run: write_read },
+    Case { name: "writev-readv", writes: true, needs: FEAT_IOVEC, run: writev_readv },
+    Case { name: "ring-doorbell", writes: true, needs: FEAT_RING_NOTIFY, run: ring_doorbell },
+    Case { name: "seek", writes: true, needs: 0, run: seek },
+    Case { name: "seek-hole", writes: true, needs: 0, run: seek_hole },
+    Case { name: "large-sparse", writes: true, needs: 0, run: large_sparse },
+    Case { name: "read-write-next", writes: true, needs: FEAT_NEXT, run: read_write_next },
+    Case { name: "read-stream", writes: true, needs: FEAT_STREAM, run: read_stream },
+    Case { name: "fallocate", writes: true, needs: FEAT_FALLOCATE, run: fallocate },
+    Case { name: "rename", writes: true, needs: 0, run: rename },
+    Case { name: "set-times", writes: true, needs: FEAT_SET_TIMES, run: set_times },
+    Case { name: "history", writes: true, needs: FEAT_HISTORY, run: history },
+    Case { name: "getdents", writes: true, needs: 0, run: getdents },
+    Case { name: "locks", writes: true, needs: FEAT_LOCKS, run: locks },
+    Case { name: "clone-read-only", writes: true, needs: FEAT_CLONE, run: clone_read_only },
+    Case { name: "unlink", writes: true, needs: 0, run: unlink },
+];
+
+fn stat_root(ctx: &mut Ctx) -> Check {
+    let (_, mode) = step(ctx.conn.stat_path("/"), "stat /")?;
+    ensure!(mode & S_IFMT == S_IFDIR, "/ has mode {:o}, not a directory", mode);
+    Ok(())
+}
+
+fn open_missing(ctx: &mut Ctx) -> Check {
+    let open = ctx.conn.open("/fstest-no-such-file", OpenFlags::O_RDONLY, 0);
+    expect_err(open, Error::NotFound, "open")
+}
+
+fn fixture_read(ctx: &mut Ctx) -> Check {
+    match ctx.conn.stat_path("/hello.txt") {
+        Err(Error::NotFound) => return Err(Fail::Skip("no fsimg fixture on this volume")),
+        r => step(r, "stat /hello.txt")?,
+    };
+    let h = step(ctx.conn.open("/hello.txt", OpenFlags::O_RDONLY, 0), "open /hello.txt")?;
+    let mut ring = ctx.ring(h)?;
+    let data = ctx.read(&mut ring, 0, 0, 64)?;
+    ensure!(data == FIXTURE_HELLO, "/hello.txt holds {:?}", data);
+    step(ctx.conn.close(h), "close")?;
+
+    // Crosses a 4096-byte block or cluster boundary
+    let h = step(ctx.conn.open("/pattern-4097.bin", OpenFlags::O_RDONLY, 0), "open pattern")?;
+    let mut ring = ctx.ring(h)?;
+    let data = ctx.read(&mut ring, 0, 4000, 200)?;
+    ensure!(data.len() == 97, "read {} bytes at 4000 of 4097", data.len());
+    let bad = data.iter().enumerate().position(|(i, &b)| b != pattern_byte(4000 + i));
+    ensure!(bad.is_none(), "pattern-4097.bin differs at {}", 4000 + bad.unwrap_or(0));
+    step(ctx.conn.close(h), "close")
+}
+
+// Lists the root of the fixture one entry per call, then again after a rewind
+fn fixture_list(ctx: &mut Ctx) -> Check {
+    match ctx.conn.stat_path("/hello.txt") {
+        Err(Error::NotFound) => return Err(Fail::Skip("no fsimg fixture on this volume")),
+        r => step(r, "stat /hello.txt")?,
+    };
+    let flags = OpenFlags::O_RDONLY | OpenFlags::O_DIRECTORY;
+    let h = step(ctx.conn.open("/", flags, 0), "open /")?;
+    let mut seen: Vec<(String, u8)> = Vec::new();
+    loop {
+        let entries = step(ctx.conn.getdents(h, 1), "getdents")?;
+        ensure!(entries.len() <= 1, "asked for one entry, got {}", entries.len());
+        match entries.first() {
+            Some(entry) => seen.push((dent_name(entry), entry.type_)),
+            None => break,
+        }
+    }
+    let has = |name: &str, type_| seen.iter().any(|(n, t)| n == name && *t == type_);
+    ensure!(has("hello.txt", DT_REG), "/hello.txt missing from {:?}", seen);
+    if ctx.conn.stat_path("/dir").is_ok() {
+        ensure!(has("dir", DT_DIR), "/dir missing from {:?}", seen);
+        ensure!(!seen.iter().any(|(n, _)| n == "nested.txt"), "/dir/nested.txt listed in /");
+    }
+
+    step(ctx.conn.seek(h, 0, SEEK_SET), "rewind")?;
+    let again = step(ctx.conn.getdents(h, seen.len() + 1), "getdents after rewind")?;
+    let names: Vec<String> = again.iter().map(dent_name).collect();
+    ensure!(names.len() == seen.len(), "listed {:?} after rewind, {:?} before", names, seen);
+    step(ctx.conn.close(h), "close")
+}
+
+// Walks a path of three components on the fixture
+fn open_async(ctx: &mut Ctx) -> Check {
+    let path = "/dir/sub/deep.txt";
+    match ctx.conn.stat_path(path) {
+        Err(Error::NotFound) => return Err(Fail::Skip("no nested fsimg fixture on this volume")),
+        r => step(r, "stat")?,
+    };
+    let h = step(ctx.conn.open_async(path, OpenFlags::O_RDONLY, 0), "open")?;
+    let mut ring = ctx.ring(h)?;
+    let got = ctx.read(&mut ring, 0, 0, 64)?;
+    ensure!(got == b"two levels down\n", "read {:?} through an async open", got);
+    step(ctx.conn.close(h), "close")?;
+    let missing = ctx.conn.open_async("/dir/missing/x", OpenFlags::O_RDONLY, 0);
+    expect_err(missing, Error::NotFound, "open missing")
+}
+
+// Images rarely carry attributes on the root; every one listed must be readable
+fn xattr_read(ctx: &mut Ctx) -> Check {
+    let flags = OpenFlags::O_RDONLY | OpenFlags::O_DIRECTORY;
+    let h = step(ctx.conn.open("/", flags, 0), "open /")?;
+    let names = step(ctx.conn.listxattr(h), "listxattr")?;
+    for name in &names {
+        match ctx.conn.getxattr(h, name) {
+            // Values in their own inode are not read yet
+            Ok(_) | Err(Error::NotSupported) => {}
+            r => step(r, "getxattr").map(|_| ())?,
+        }
+    }
+    let missing = ctx.conn.getxattr(h, "user.fstest-missing");
+    expect_err(missing, Error::NotFound, "getxattr of a missing name")?;
+    step(ctx.conn.close(h), "close")
+}
+
+// Whatever the service has logged so far must read back in order
+fn events(ctx: &mut Ctx) -> Check {
+    let (found, next, missed) = step(ctx.conn.event_read(0), "event read")?;
+    let mut want = missed as u64;
+    for &(seq, kind, _) in &found {
+        ensure!(seq == want, "event {} where {} was due", seq, want);
+        ensure!(kind.count_ones() == 1 && kind & !EV_ALL == 0, "event {} of kind {:#x}", seq, kind);
+        want += 1;
+    }
+    ensure!(next == want, "told to read on from {} after event {}", next, want);
+    Ok(())
+}
+
+fn statfs(ctx: &mut Ctx) -> Check {
+    let st = step(ctx.conn.statfs(), "statfs")?;
+    ensure!(st.block_size.is_power_of_two(), "block size {}", st.block_size);
+    ensure!(st.total_blocks > 0, "no blocks at all");
+    ensure!(
+        st.free_blocks <= st.total_blocks,
+        "{} of {} blocks free",
+        st.free_blocks,
+        st.total_blocks
+    );
+    ensure!(
+        st.avail_blocks <= st.free_blocks,
+        "{} available of {} free",
+        st.avail_blocks,
+        st.free_blocks
+    );
+    ensure!(
+        st.free_inodes <= st.total_inodes,
+        "{} of {} inodes free",
+        st.free_inodes,
+        st.total_inodes
+    );
+    Ok(())
+}
+
+fn write_read(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("write-read")?;
+    let mut ring = ctx.ring(h)?;
+    let data: Vec<u8> = (0..5000).map(pattern_byte).collect();
+    ctx.write(&mut ring, 0, 0, &data)?;
+    step(ring.run(&ctx.conn, IOURING_OP_FSYNC, 0, 0, 0), "fsync")?;
+
+    let back = ctx.read(&mut ring, 6000, 0, data.len())?;
+    ensure!(back == data, "read back differs from what was written");
+    let tail = ctx.read(&mut ring, 0, 4990, 100)?;
+    ensure!(tail.len() == 10, "read {} bytes at 4990 of 5000", tail.len());
+    let past = ctx.read(&mut ring, 0, 8000, 100)?;
+    ensure!(past.is_empty(), "read {} bytes past the end", past.len());
+
+    let (size, _) = step(ctx.conn.stat_path(&scratch("write-read")), "stat")?;
+    ensure!(size == data.len(), "size {} after writing {}", size, data.len());
+    step(ctx.conn.close(h), "close")
+}
+
+fn fallocate(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("fallocate")?;
+    let mut ring = ctx.ring(h)?;
+    step(ctx.conn.fallocate(h, 0, 8192, 0), "fallocate")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("fallocate")), "stat")?;
+    ensure!(size == 8192, "size {} after preallocating 8192 bytes", size);
+    let data = ctx.read(&mut ring, 0, 0, 8192)?;
+    ensure!(data.iter().all(|&b| b == 0), "preallocated range does not read as zeros");
+
+    // Past the end with KEEP_SIZE: reserved, but the size stays
+    step(ctx.conn.fallocate(h, 8192, 4096, FALLOC_KEEP_SIZE), "fallocate keep-size")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("fallocate")), "stat")?;
+    ensure!(size == 8192, "size {} after a KEEP_SIZE preallocation", size);
+
+    let written: Vec<u8> = (0..100).map(pattern_byte).collect();
+    ctx.write(&mut ring, 0, 4000, &written)?;
+    let back = ctx.read(&mut ring, 0, 4000, written.len())?;
+    ensure!(back == written, "write into the preallocated range reads back differently");
+    step(ctx.conn.close(h), "close")
+}
+
+/// Lays out (buffer offset, length) iovecs as a table at the start of the
+/// ring's data.
+fn iovec_table(ring: &mut ClientRing, iov: &[(usize, usize)]) -> u64 {
+    let table: Vec<(u64, u64)> =
+        iov.iter().map(|&(at, len)| (ring.data_addr(at), len as u64)).collect();
+    for (i, (addr, len)) in table.into_iter().enumerate() {
+        let raw = &mut ring.data()[i * IOVEC_SIZE..(i + 1) * IOVEC_SIZE];
+        raw[..8].copy_from_slice(&addr.to_le_bytes());
+        raw[8..].copy_from_slice(&len.to_le_bytes());
+    }
+    ring.data_addr(0)
+}
+
+fn writev_readv(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("writev-readv")?;
+    let mut ring = ctx.ring(h)?;
+    let data: Vec<u8> = (0..4101).map(pattern_byte).collect();
+
+    // Written in three pieces, read back in two differently split ones
+    let out = [(256, 100), (512, 1), (1024, 4000)];
+    let mut at = 0;
+    for &(buf, len) in &out {
+        ring.data()[buf..buf + len].copy_from_slice(&data[at..at + len]);
+        at += len;
+    }
+    let table = iovec_table(&mut ring, &out);
+    let n = step(ring.run(&ctx.conn, IOURING_OP_WRITEV, 0, table, out.len() as u32), "writev")?;
+    ensure!(n == data.len(), "writev: {} of {} bytes", n, data.len());
+
+    let back = [(5120, 2000), (7168, 2101)];
+    ring.data()[5120..].fill(0);
+    let table = iovec_table(&mut ring, &back);
+    let n = step(ring.run(&ctx.conn, IOURING_OP_READV, 0, table, back.len() as u32), "readv")?;
+    ensure!(n == data.len(), "readv: {} of {} bytes", n, data.len());
+    let mut read = Vec::new();
+    for &(buf, len) in &back {
+        read.extend_from_slice(&ring.data()[buf..buf + len]);
+    }
+    ensure!(read == data, "readv differs from what writev wrote");
+    step(ctx.conn.close(h), "close")
+}
+
+fn ring_doorbell(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("doorbell")?;
+    let mut ring = ctx.notified_ring(h)?;
+    let data: Vec<u8> = (0..3000).map(pattern_byte).collect();
+    ctx.write(&mut ring, 0, 0, &data)?;
+    let back = ctx.read(&mut ring, 4096, 0, data.len())?;
+    ensure!(back == data, "read back differs from what was written");
+    step(ctx.conn.close(h), "close")
+}
+
+fn seek(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("seek")?;
+    let mut ring = ctx.ring(h)?;
+    ctx.write(&mut ring, 0, 0, &[7; 300])?;
+    let end = step(ctx.conn.seek(h, 0, SEEK_END), "seek end")?;
+    ensure!(end == 300, "SEEK_END at {}, expected 300", end);
+    let pos = step(ctx.conn.seek(h, 42, SEEK_SET), "seek set")?;
+    ensure!(pos == 42, "SEEK_SET at {}, expected 42", pos);
+    step(ctx.conn.close(h), "close")
+}
+
+fn seek_hole(ctx: &mut Ctx) -> Check {
+    const FAR: usize = 1 << 20;
+    let h = ctx.create("seek-hole")?;
+    let mut ring = ctx.ring(h)?;
+    ctx.write(&mut ring, 0, 0, &[7; 100])?;
+    ctx.write(&mut ring, 0, FAR as u64, &[7; 100])?;
+    let size = FAR + 100;
+
+    let data = step(ctx.conn.seek(h, 0, SEEK_DATA), "seek data")?;
+    ensure!(data == 0, "SEEK_DATA from 0 at {}", data);
+    // Volumes that keep no holes report the one at the end
+    let hole = step(ctx.conn.seek(h, 0, SEEK_HOLE), "seek hole")?;
+    ensure!((100..=FAR).contains(&hole) || hole == size, "SEEK_HOLE from 0 at {}", hole);
+    if hole < size {
+        let next = step(ctx.conn.seek(h, hole as i64, SEEK_DATA), "seek data after hole")?;
+        ensure!(next > hole && next <= FAR, "SEEK_DATA from the hole at {} found {}", hole, next);
+    }
+    let end = step(ctx.conn.seek(h, size as i64 - 1, SEEK_HOLE), "seek hole at the end")?;
+    ensure!(end == size, "SEEK_HOLE from the last byte at {}, expected {}", end, size);
+    let past = ctx.conn.seek(h, size as i64, SEEK_DATA);
+    expect_err(past, Error::NotFound, "SEEK_DATA at the end")?;
+    step(ctx.conn.close(h), "close")
+}
+
+// A file whose size needs more than 32 bits, nearly all of it hole
+fn large_sparse(ctx: &mut Ctx) -> Check {
+    const FAR: u64 = (1 << 32) + 12345;
+    let h = ctx.create("large-sparse")?;
+    let mut ring = ctx.ring(h)?;
+    let head: Vec<u8> = (0..100).map(pattern_byte).collect();
+    let tail: Vec<u8> = (0..3000).map(|i| !pattern_byte(i)).collect();
+    ctx.write(&mut ring, 0, 0, &head)?;
+    ring.data()[..tail.len()].copy_from_slice(&tail);
+    let addr = ring.data_addr(0);
+    match ring.run(&ctx.conn, IOURING_OP_WRITE, FAR, addr, tail.len() as u32) {
+        Ok(n) => ensure!(n == tail.len(), "write past 4 GiB: {} of {} bytes", n, tail.len()),
+        // FAT caps files below 4 GiB, tmpfs may lack the memory to back one
+        Err(Error::InvalidArgs | Error::OutOfMemory) => {
+            let _ = ctx.conn.close(h);
+            return Err(Fail::Skip("no files past 4 GiB here"));
+        }
+        Err(e) => return Err(Fail::Error(format!("write past 4 GiB: {:?}", e))),
+    }
+    let size = FAR as usize + tail.len();
+
+    let (stat_size, _) = step(ctx.conn.stat_path(&scratch("large-sparse")), "stat")?;
+    ensure!(stat_size == size, "size {} after writing up to {}", stat_size, size);
+    let end = step(ctx.conn.seek(h, 0, SEEK_END), "seek end")?;
+    ensure!(end == size, "SEEK_END at {}, expected {}", end, size);
+
+    let back = ctx.read(&mut ring, 0, 0, head.len())?;
+    ensure!(back == head, "the head reads back differently");
+    // Across the 4 GiB mark, well inside the hole
+    let hole = ctx.read(&mut ring, 0, (1 << 32) - 2048, 4096)?;
+    ensure!(hole.len() == 4096, "read {} of 4096 bytes in the hole", hole.len());
+    ensure!(hole.iter().all(|&b| b == 0), "the hole does not read as zeros");
+    // The end of the hole and the start of the tail in one read
+    let edge = ctx.read(&mut ring, 0, FAR - 100, 200)?;
+    ensure!(edge.len() == 200, "read {} of 200 bytes before the tail", edge.len());
+    ensure!(edge[..100].iter().all(|&b| b == 0), "the hole before the tail is not zeros");
+    ensure!(edge[100..] == tail[..100], "the start of the tail reads back differently");
+    let back = ctx.read(&mut ring, 4096, FAR, tail.len())?;
+    ensure!(back == tail, "the tail reads back differently");
+    let past = ctx.read(&mut ring, 0, size as u64, 100)?;
+    ensure!(past.is_empty(), "read {} bytes past the end", past.len());
+    step(ctx.conn.close(h), "close")
+}
+
+fn read_write_next(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("next")?;
+    for part in [&b"hello "[..], &b"world"[..]] {
+        let n = step(ctx.conn.write_next(h, part), "write next")?;
+        ensure!(n == part.len(), "WRITE_NEXT wrote {} of {} bytes", n, part.len());
+    }
+    step(ctx.conn.seek(h, 0, SEEK_SET), "rewind")?;
+    let first = step(ctx.conn.read_next(h, 6), "read next")?;
+    ensure!(first == b"hello ", "READ_NEXT from 0 read {:?}", first);
+    let rest = step(ctx.conn.read_next(h, 100), "read next")?;
+    ensure!(rest == b"world", "READ_NEXT from 6 read {:?}", rest);
+    let end = step(ctx.conn.read_next(h, 100), "read next at the end")?;
+    ensure!(end.is_empty(), "READ_NEXT at the end read {} bytes", end.len());
+    step(ctx.conn.close(h), "close")
+}
+
+fn read_stream(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("stream")?;
+    let mut ring = ctx.ring(h)?;
+    let data: Vec<u8> = (0..10000).map(pattern_byte).collect();
+    ctx.write(&mut ring, 0, 0, &data)?;
+    step(ring.run(&ctx.conn, IOURING_OP_FSYNC, 0, 0, 0), "fsync")?;
+
+    // More chunks than buffers, so the stream has to wait for some to come back
+    let all = step(ring.stream(&ctx.conn, 0, 0, 1024, 3), "stream to the end")?;
+    ensure!(all == data, "streamed {} bytes that differ from those written", all.len());
+    let part = step(ring.stream(&ctx.conn, 5000, 3000, 1000, 2), "stream a range")?;
+    ensure!(part[..] == data[5000..8000], "streamed range differs from what was written");
+    step(ctx.conn.close(h), "close")
+}
+
+fn rename(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("rename-from")?;
+    let mut ring = ctx.ring(h)?;
+    ctx.write(&mut ring, 0, 0, b"abc")?;
+    step(ctx.conn.close(h), "close")?;
+
+    step(ctx.conn.rename(&scratch("rename-from"), &scratch("rename-to")), "rename")?;
+    expect_err(ctx.conn.stat_path(&scratch("rename-from")), Error::NotFound, "stat old name")?;
+    let (size, _) = step(ctx.conn.stat_path(&scratch("rename-to")), "stat new name")?;
+    ensure!(size == 3, "renamed file has {} bytes, expected 3", size);
+    Ok(())
+}
+
+fn set_times(ctx: &mut Ctx) -> Check {
+    // Midnights, which FAT keeps exactly even for the access date
+    const Y2K: usize = 946_684_800;
+    const DAY: usize = 86_400;
+    let dir = scratch("times");
+    step(ctx.conn.mkdir(&dir, 0o755), "mkdir")?;
+
+    step(ctx.conn.set_times(&dir, (Y2K, 0), (Y2K + DAY, 0)), "set both")?;
+    let (atime, mtime, _) = step(ctx.conn.stat_times(&dir), "stat")?;
+    ensure!(atime == Y2K, "atime {} after setting {}", atime, Y2K);
+    ensure!(mtime == Y2K + DAY, "mtime {} after setting {}", mtime, Y2K + DAY);
+
+    step(ctx.conn.set_times(&dir, (0, UTIME_OMIT), (Y2K, 0)), "set mtime only")?;
+    let (atime, mtime, _) = step(ctx.conn.stat_times(&dir), "stat again")?;
+    ensure!(atime == Y2K, "omitted atime changed to {}", atime);
+    ensure!(mtime == Y2K, "mtime {} after setting {}", mtime, Y2K);
+
+    let nsec = 1_000_000_000;
+    expect_err(ctx.conn.set_times(&dir, (0, nsec), (0, nsec)), Error::InvalidArgs, "bad nsec")?;
+    Ok(())
+}
+
+// Creates or replaces `name` with `data`
+fn put_file(ctx: &mut Ctx, name: &str, data: &[u8]) -> Check {
+    let h = ctx.create(name)?;
+    let mut ring = ctx.ring(h)?;
+    ctx.write(&mut ring, 0, 0, data)?;
+    step(ctx.conn.close(h), "close")
+}
+
+fn history(ctx: &mut Ctx) -> Check {
+    let file = scratch("history/conf");
+    step(ctx.conn.mkdir(&scratch("history"), 0o755), "mkdir")?;
+    step(ctx.conn.mkdir(&scratch("history/.versions"), 0o755), "mkdir .versions")?;
+    put_file(ctx, "history/conf", b"first")?;
+    put_file(ctx, "history/conf", b"second!")?;
+
+    let kept = step(ctx.conn.history_list(&file), "list")?;
+    ensure!(kept.len() == 1, "{} versions kept after one replace", kept.len());
+    ensure!(kept[0].1 == 5, "kept version has {} bytes, expected 5", kept[0].1);
+    step(ctx.conn.history_restore(&file, kept[0].0), "restore")?;
+    let (size, _) = step(ctx.conn.stat_path(&file), "stat")?;
+    ensure!(size == 5, "restored file has {} bytes, expected 5", size);
+
+    // The contents a restore replaces are kept too
+    let kept = step(ctx.conn.history_list(&file), "list after restore")?;
+    ensure!(kept.len() == 2, "{} versions kept after restoring", kept.len());
+    ensure!(kept[0].1 == 7, "newest version has {} bytes, expected 7", kept[0].1);
+    Ok(())
+}
+
+fn getdents(ctx: &mut Ctx) -> Check {
+    let dir = scratch("list");
+    step(ctx.conn.mkdir(&dir, 0o755), "mkdir")?;
+    let names = ["one", "two", "three"];
+    for name in names {
+        let h = ctx.create(&format!("list/{}", name))?;
+        step(ctx.conn.close(h), "close")?;
+    }
+
+    let h = step(ctx.conn.open(&dir, OpenFlags::O_RDONLY | OpenFlags::O_DIRECTORY, 0), "open")?;
+    // Two at a time, so the listing has to resume where it stopped
+    let mut seen: Vec<String> = Vec::new();
+    loop {
+        let entries = step(ctx.conn.getdents(h, 2), "getdents")?;
+        if entries.is_empty() {
+            break;
+        }
+        for entry in entries {
+            let name = dent_name(&entry);
+            if name == "." || name == ".." {
+                continue;
+            }
+            ensure!(!seen.contains(&name), "{} listed twice", name);
+            ensure!(entry.type_ == DT_REG, "{} has type {}, expected a file", name, entry.type_);
+            seen.push(name);
+        }
+    }
+    step(ctx.conn.close(h), "close")?;
+    for name in names {
+        ensure!(seen.iter().any(|s| s == name), "{} missing from the listing", name);
+    }
+    ensure!(seen.len() == names.len(), "listed {:?}", seen);
+    Ok(())
+}
+
+fn dent_name(entry: &DEntry) -> String {
+    let len = entry.name.iter().position(|&b| b == 0).unwrap_or(entry.name.len());
+    String::from_utf8_lossy(&entry.name[..len]).into_owned()
+}
+
+fn locks(ctx: &mut Ctx) -> Check {
+    let first = ctx.create("locks")?;
+    let second = step(ctx.conn.open(&scratch("locks"), OpenFlags::O_RDWR, 0), "open")?;
+
+    step(ctx.conn.lock(first, 0, 100, LOCK_EXCLUSIVE), "lock")?;
+    expect_err(ctx.conn.lock(second, 50, 10, 0), Error::WouldBlock, "overlapping lock")?;
+    step(ctx.conn.lock(second, 100, 10, LOCK_EXCLUSIVE), "adjacent lock")?;
+    step(ctx.conn.unlock(first, 0, 100), "unlock")?;
+    step(ctx.conn.lock(second, 50, 10, 0), "lock after unlock")?;
+
+    // Closing drops the handle's locks
+    step(ctx.conn.close(second), "close")?;
+    step(ctx.conn.lock(first, 0, 0, LOCK_EXCLUSIVE), "lock after close")?;
+    step(ctx.conn.close(first), "close")
+}
+
+fn clone_read_only(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("clone")?;
+    let mut ring = ctx.ring(h)?;
+    ctx.write(&mut ring, 0, 0, b"shared")?;
+
+    let copy = step(ctx.conn.clone_handle(h), "clone")?;
+    let mut copy_ring = ctx.ring(copy)?;
+    let data = ctx.read(&mut copy_ring, 0, 0, 64)?;
+    ensure!(data == b"shared", "clone reads {:?}", data);
+    copy_ring.data()[..1].copy_from_slice(b"x");
+    let addr = copy_ring.data_addr(0);
+    let write = copy_ring.run(&ctx.conn, IOURING_OP_WRITE, 0, addr, 1);
+    expect_err(write, Error::PermissionDenied, "write through clone")?;
+    step(ctx.conn.close(copy), "close clone")?;
+    step(ctx.conn.close(h), "close")
+}
+
+fn unlink(ctx: &mut Ctx) -> Check {
+    let h = ctx.create("unlink")?;
+    step(ctx.conn.close(h), "close")?;
+    step(ctx.conn.unlink(&scratch("unlink")), "unlink")?;
+    expect_err(ctx.conn.stat_path(&scratch("unlink")), Error::NotFound, "stat")?;
+    let open = ctx.conn.open(&scratch("unlink"), OpenFlags::O_RDONLY, 0);
+    expect_err(open, Error::NotFound, "open")
+}
+
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+/// Runs every case, logging each verdict as it comes.
+pub fn run(ctx: &mut Ctx) -> Summary {
+    let mut summary = Summary { passed: 0, failed: 0, skipped: 0 };
+
+    // Handles stay ids in MR0; the suite has no slot to take handle endpoints
+    match ctx.conn.version(PROTO_VERSION, FEAT_ALL & !FEAT_HANDLE_ENDPOINTS) {
+        Ok((version, features)) => {
+            glenda::log!("FsTest: protocol version {}, features {:#x}", version, features);
+            ctx.features = features;
+        }
+        Err(e) => glenda::log!("FsTest: no VERSION ({:?}), optional features off", e),
+    }
+
+    // Leftovers of an interrupted run would fail the create cases
+    let _ = ctx.conn.rmtree(SCRATCH_DIR);
+    let writable = match ctx.conn.mkdir(SCRATCH_DIR, 0o755) {
+        Ok(()) => true,
+        Err(Error::PermissionDenied | Error::NotSupported) => false,
+        Err(e) => {
+            glenda::log!("FsTest: cannot create {}: {:?}", SCRATCH_DIR, e);
+            false
+        }
+    };
+    if !writable {
+        glenda::log!("FsTest: read-only filesystem, skipping the cases that write");
+    }
+
+    let posix: &[Case] = if cfg!(feature = "posix") { posix::CASES } else { &[] };
+    for case in CASES.iter().chain(posix) {
+        let result = if case.writes && !writable {
+            Err(Fail::Skip("read-only filesystem"))
+        } else if case.needs & !ctx.features != 0 {
+            Err(Fail::Skip("feature not negotiated"))
+        } else {
+            (case.run)(ctx)
+        };
+        match result {
+            Ok(()) => {
+                summary.passed += 1;
+                glenda::log!("FsTest: [PASS] {}", case.name);
+            }
+            Err(Fail::Skip(why)) => {
+                summary.skipped += 1;
+                glenda::log!("FsTest: [SKIP] {}: {}", case.name, why);
+            }
+            Err(Fail::Error(why)) => {
+                summary.failed += 1;
+                glenda::log!("FsTest: [FAIL] {}: {}", case.name, why);
+            }
+        }
+    }
+
+    if writable {
+        if let Err(e) = ctx.conn.rmtree(SCRATCH_DIR) {
+            glenda::log!("FsTest: cannot remove {}: {:?}", SCRATCH_DIR, e);
+        }
+    }
+    summary
+}
@@ -0,0 +1,236 @@
+//! Compressed images. An initrd may be stored gzip- or zstd-compressed as a
+//! whole; the codec is picked by the magic at the start of the device, and
+//! the image is unpacked into memory at mount, after which files are served
+//! from the copy. Each codec sits behind a cargo feature of the same name.
+
+use alloc::vec::Vec;
+use glenda::error::Error;
+
+// Unit the device is read in
+pub const SECTOR_SIZE: usize = 4096;
+// Bytes asked of the device per read while streaming
+const CHUNK_SIZE: usize = 64 * 1024;
+// Output grows by this much at a time
+const OUT_STEP: usize = 256 * 1024;
+/// Largest unpacked image; it has to fit the service's heap.
+pub const MAX_IMAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A compression format an image may be stored in.
+pub trait Codec: Sync {
+    fn name(&self) -> &'static str;
+    /// True if an image starting with `head` is in this format.
+    fn matches(&self, head: &[u8]) -> bool;
+    /// Unpacks the image `src` streams, failing with OutOfMemory past `limit` bytes.
+    fn decompress(&self, src: &mut Source<'_>, limit: usize) -> Result<Vec<u8>, Error>;
+}
+
+pub static CODECS: &[&dyn Codec] = &[
+    #[cfg(feature = "gzip")]
+    &gzip::Gzip,
+    #[cfg(feature = "zstd")]
+    &zstd::Zstd,
+];
+
+pub fn sniff(head: &[u8]) -> Option<&'static dyn Codec> {
+    CODECS.iter().copied().find(|c| c.matches(head))
+}
+
+/// The compressed image, read off the device a chunk at a time. The device
+/// size is not known, so a chunk read that fails is retried a sector at a
+/// time; only a sector past the end fails the stream.
+pub struct Source<'a> {
+    read_at: &'a dyn Fn(usize, &mut [u8]) -> Result<(), Error>,
+    buf: Vec<u8>,
+    pos: usize,
+    next_sector: usize,
+    chunk: usize,
+}
+
+impl<'a> Source<'a> {
+    /// `read_at(sector, buf)` fills `buf` from the device at `sector`.
+    pub fn new(read_at: &'a dyn Fn(usize, &mut [u8]) -> Result<(), Error>) -> Self {
+        Self { read_at, buf: Vec::new(), pos: 0, next_sector: 0, chunk: CHUNK_SIZE }
+    }
+
+    /// The bytes read but not consumed yet, reading more if there are none.
+    pub fn fill(&mut self) -> Result<&[u8], Error> {
+        while self.pos == self.buf.len() {
+            self.buf.resize(self.chunk, 0);
+            self.pos = 0;
+            if (self.read_at)(self.next_sector, &mut self.buf).is_ok() {
+                self.next_sector += self.chunk / SECTOR_SIZE;
+                continue;
+            }
+            self.buf.clear();
+            if self.chunk == SECTOR_SIZE {
+                // The stream wants more than the device holds
+                return Err(Error::DeviceError);
+            }
+            self.chunk = SECTOR_SIZE;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.pos = (self.pos + len).min(self.buf.len());
+    }
+
+    pub fn read_exact(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < out.len() {
+            let avail = self.fill()?;
+            let len = avail.len().min(out.len() - done);
+            out[done..done + len].copy_from_slice(&avail[..len]);
+            self.consume(len);
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut byte = [0u8];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+// Makes room for the next piece of output, or fails if `out` is at `limit`
+fn grow(out: &mut Vec<u8>, limit: usize) -> Result<usize, Error> {
+    let start = out.len();
+    if start >= limit {
+        return Err(Error::OutOfMemory);
+    }
+    out.resize((start + OUT_STEP).min(limit), 0);
+    Ok(start)
+}
+
+#[cfg(feature = "gzip")]
+mod gzip {
+    use super::{grow, Codec, Source};
+    use alloc::vec::Vec;
+    use fs_common::crc::crc32;
+    use glenda::error::Error;
+    use miniz_oxide::inflate::stream::{inflate, InflateState};
+    use miniz_oxide::{DataFormat, MZFlush, MZStatus};
+
+    const MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+    // FLG bits of the member header
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    pub struct Gzip;
+
+    impl Codec for Gzip {
+        fn name(&self) -> &'static str {
+            "gzip"
+        }
+
+        fn matches(&self, head: &[u8]) -> bool {
+            head.starts_with(&MAGIC)
+        }
+
+        /// Unpacks the first member and checks it against its CRC-32 and
+        /// length; anything after it is ignored.
+        fn decompress(&self, src: &mut Source<'_>, limit: usize) -> Result<Vec<u8>, Error> {
+            let mut header = [0u8; 10];
+            src.read_exact(&mut header)?;
+            let flags = header[3];
+            if flags & FEXTRA != 0 {
+                let mut len = [0u8; 2];
+                src.read_exact(&mut len)?;
+                for _ in 0..u16::from_le_bytes(len) {
+                    src.read_byte()?;
+                }
+            }
+            for field in [FNAME, FCOMMENT] {
+                if flags & field != 0 {
+                    while src.read_byte()? != 0 {}
+                }
+            }
+            if flags & FHCRC != 0 {
+                src.read_exact(&mut [0u8; 2])?;
+            }
+
+            let mut state = InflateState::new_boxed(DataFormat::Raw);
+            let mut out = Vec::new();
+            loop {
+                let start = grow(&mut out, limit)?;
+                let input = src.fill()?;
+                let res = inflate(&mut state, input, &mut out[start..], MZFlush::None);
+                src.consume(res.bytes_consumed);
+                out.truncate(start + res.bytes_written);
+                match res.status {
+                    Ok(MZStatus::StreamEnd) => break,
+                    Ok(_) => {}
+                    Err(_) => return Err(Error::DeviceError),
+                }
+            }
+
+            let mut trailer = [0u8; 8];
+            src.read_exact(&mut trailer)?;
+            let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+            if !crc32(!0, &out) != crc || out.len() as u32 != size {
+                return Err(Error::DeviceError);
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use super::{grow, Codec, Source};
+    use alloc::vec::Vec;
+    use glenda::error::Error;
+    use ruzstd::decoding::StreamingDecoder;
+    use ruzstd::io::{ErrorKind, Read};
+
+    const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    pub struct Zstd;
+
+    impl Read for Source<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, ruzstd::io::Error> {
+            let avail = self.fill().map_err(|_| ruzstd::io::Error::from(ErrorKind::Other))?;
+            let len = avail.len().min(buf.len());
+            buf[..len].copy_from_slice(&avail[..len]);
+            self.consume(len);
+            Ok(len)
+        }
+    }
+
+    impl Codec for Zstd {
+        fn name(&self) -> &'static str {
+            "zstd"
+        }
+
+        fn matches(&self, head: &[u8]) -> bool {
+            head.starts_with(&MAGIC)
+        }
+
+        /// Unpacks the first frame; anything after it is ignored.
+        fn decompress(&self, src: &mut Source<'_>, limit: usize) -> Result<Vec<u8>, Error> {
+            let mut decoder = StreamingDecoder::new(src).map_err(|_| Error::DeviceError)?;
+            let mut out = Vec::new();
+            loop {
+                let start = match grow(&mut out, limit) {
+                    Ok(start) => start,
+                    // Fine if the frame ends exactly at the limit
+                    Err(e) => match decoder.read(&mut [0u8]) {
+                        Ok(0) => break,
+                        _ => return Err(e),
+                    },
+                };
+                let read = decoder.read(&mut out[start..]).map_err(|_| Error::DeviceError)?;
+                out.truncate(start + read);
+                if read == 0 {
+                    break;
+                }
+            }
+            Ok(out)
+        }
+    }
+}
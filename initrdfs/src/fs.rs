@@ -1,13 +1,138 @@
+use crate::overlay::{Overlay, OVERLAY_MAX_PAGES};
 use alloc::string::String;
 use alloc::vec::Vec;
+use fs_common::bytes::{le_u16, le_u32, le_u64};
+use fs_common::clock;
+use fs_common::crc::crc32;
+use fs_common::errors::FsError;
+use fs_common::limits::{self, INITRD_MAX_FILE_SIZE};
+use fs_common::path::{canonical, join, parent};
+use fs_common::locks::{Grant, LockTable, IOURING_OP_LOCK, IOURING_OP_UNLOCK};
+use fs_common::proto::{dentry, CURRENT_OFFSET, DT_DIR, DT_REG};
+use fs_common::ring::{
+    completion, transfer_vectored, RingSignal, ShmWindow, IOURING_OP_READV, IOURING_OP_WRITEV,
+};
+use fs_common::statfs::FsStats;
+use fs_common::stream::{Stream, IOURING_OP_READ_STREAM, MAX_STREAMS};
+use glenda::arch::mem::PGSIZE;
 use glenda::cap::Frame;
 use glenda::error::Error;
 use glenda::io::uring::IoUringBuffer;
 use glenda::ipc::Badge;
-use glenda::protocol::fs::{OpenFlags, Stat};
+use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
 use glenda::client::volume::VolumeClient;
 
 pub const DEFAULT_STAT: u32 = 0o100444;
+pub const ROOT_DIR_STAT: u32 = 0o040555;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const PERM_MASK: u32 = 0o7777;
+
+// Header: magic, entry count, hash kind and hash (u32 each), then the entry table
+pub const INITRD_MAGIC: u32 = 0x99999999;
+const HEADER_HASH_KIND: usize = 8;
+const HEADER_HASH: usize = 12;
+const ENTRY_BASE: usize = 16;
+const ENTRY_SIZE: usize = 48;
+pub const HEADER_SIZE: usize = 4096;
+
+// Version 2 keeps the magic and hash fields and puts its version where v1
+// has the entry count, which can never have the top bit set. Then come the
+// entry count and the table length in bytes; the table starts at
+// V2_ENTRY_BASE and may run past the first 4096 bytes.
+const V2_VERSION: u32 = 0x8000_0002;
+const V2_COUNT: usize = 16;
+const V2_TABLE_LEN: usize = 20;
+const V2_ENTRY_BASE: usize = 32;
+// Largest header, table included, a v2 image may carry
+pub const MAX_HEADER_SIZE: usize = 1024 * 1024;
+
+// v2 entry: type, pad, name length (u16), mode, parent, uid, gid (u32),
+// pad (u32), data offset, size and mtime (u64), then the name, padded to 8
+const V2_NAME_LEN: usize = 2;
+const V2_MODE: usize = 4;
+const V2_PARENT: usize = 8;
+const V2_UID: usize = 12;
+const V2_GID: usize = 16;
+const V2_OFFSET: usize = 24;
+const V2_SIZE: usize = 32;
+const V2_MTIME: usize = 40;
+const V2_NAME: usize = 48;
+const NAME_MAX: usize = 255;
+// Parent of entries that sit in the root directory
+const PARENT_ROOT: u32 = u32::MAX;
+
+pub const ENTRY_FILE: u8 = 1;
+pub const ENTRY_DIR: u8 = 2;
+// Unit STATFS counts the image in
+const IMAGE_BLOCK_SIZE: usize = 4096;
+
+// Hash kinds; images built before the field existed carry zero there
+pub const HASH_NONE: u32 = 0;
+pub const HASH_CRC32: u32 = 1;
+
+fn header_u32(header: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]])
+}
+
+pub fn hash_kind(header: &[u8]) -> u32 {
+    header_u32(header, HEADER_HASH_KIND)
+}
+
+fn is_v2(header: &[u8]) -> bool {
+    header_u32(header, 4) == V2_VERSION
+}
+
+/// Bytes of the image the header and entry table take: the first 4096 for
+/// v1, as many as the table needs for v2. Read that much before `InitrdFS::new`.
+pub fn header_len(header: &[u8; 4096]) -> Result<usize, Error> {
+    if header_u32(header, 0) != INITRD_MAGIC {
+        return Err(Error::InvalidArgs);
+    }
+    if !is_v2(header) {
+        return Ok(HEADER_SIZE);
+    }
+    (header_u32(header, V2_TABLE_LEN) as usize)
+        .checked_add(V2_ENTRY_BASE)
+        .filter(|&end| end <= MAX_HEADER_SIZE)
+        .map(|end| end.next_multiple_of(HEADER_SIZE))
+        .ok_or(Error::DeviceError)
+}
+
+// End of the entry table within the header
+fn table_end(header: &[u8]) -> Option<usize> {
+    if is_v2(header) {
+        (header_u32(header, V2_TABLE_LEN) as usize).checked_add(V2_ENTRY_BASE)
+    } else {
+        let count = header_u32(header, 4) as usize;
+        count.checked_mul(ENTRY_SIZE).and_then(|len| len.checked_add(ENTRY_BASE))
+    }
+}
+
+/// Checks the magic, that the entry table fits the header, and the hash over
+/// the header and entry table, computed with the hash field zeroed.
+pub fn verify_header(header: &[u8]) -> Result<(), Error> {
+    if header.len() < HEADER_SIZE || header_u32(header, 0) != INITRD_MAGIC {
+        return Err(Error::InvalidArgs);
+    }
+    let end = table_end(header).filter(|&end| end <= header.len()).ok_or(Error::DeviceError)?;
+
+    match hash_kind(header) {
+        // Legacy images stay mountable unless the build insists on a hash
+        HASH_NONE if cfg!(feature = "require-header-hash") => Err(Error::NotSupported),
+        HASH_NONE => Ok(()),
+        HASH_CRC32 => {
+            let crc = crc32(!0, &header[..HEADER_HASH]);
+            let crc = crc32(crc, &[0; 4]);
+            let crc = !crc32(crc, &header[HEADER_HASH + 4..end]);
+            if crc != header_u32(header, HEADER_HASH) {
+                return Err(Error::DeviceError);
+            }
+            Ok(())
+        }
+        _ => Err(Error::NotSupported),
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct InitrdEntry {
@@ -15,57 +140,237 @@ pub struct InitrdEntry {
     pub offset: usize,
     pub size: usize,
     pub name: String,
+    // Index of the directory entry holding this one, None for the root
+    pub parent: Option<usize>,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+}
+
+impl InitrdEntry {
+    pub fn is_dir(&self) -> bool {
+        self._type == ENTRY_DIR
+    }
+}
+
+/// Where file data comes from: the device, or the unpacked copy of a
+/// compressed image.
+pub struct Image<'a> {
+    pub blk: &'a VolumeClient,
+    pub unpacked: Option<&'a [u8]>,
+}
+
+impl Image<'_> {
+    /// Fills `buf` from image offset `offset`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if let Some(image) = self.unpacked {
+            let src = image.get(offset..offset + buf.len()).ok_or(Error::DeviceError)?;
+            buf.copy_from_slice(src);
+            return Ok(());
+        }
+
+        let block_size = 4096;
+        let start_sector = offset / block_size;
+        let end_sector = (offset + buf.len()).div_ceil(block_size);
+        let read_size = (end_sector - start_sector) * block_size;
+
+        let mut temp_buf = alloc::vec![0u8; read_size];
+
+        self.blk.read_at(start_sector, read_size as u32, &mut temp_buf)?;
+
+        let copy_start = offset % block_size;
+        buf.copy_from_slice(&temp_buf[copy_start..copy_start + buf.len()]);
+        Ok(())
+    }
+
+    /// Reads `len` bytes at image offset `offset` into shared memory at the
+    /// server address `addr`.
+    pub fn read_shm(&self, offset: usize, len: usize, addr: usize) -> Result<(), Error> {
+        match self.unpacked {
+            Some(image) => {
+                let src = image.get(offset..offset + len).ok_or(Error::DeviceError)?;
+                // The caller checked `addr` lies in a mapped client window
+                unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), addr as *mut u8, len) };
+                Ok(())
+            }
+            None => self.blk.read_shm(offset / 4096, len as u32, addr),
+        }
+    }
+}
+
+/// The image and the overlay over it, as file data is read and written.
+pub struct Layers<'a> {
+    pub image: Image<'a>,
+    pub overlay: &'a mut Overlay,
+}
+
+/// What a path resolved to: an image entry (None for the root directory)
+/// or an overlay node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Image(Option<usize>),
+    Upper(usize),
 }
 
 // Represents an open file in Initrd
 pub struct InitrdFile {
+    // Canonical path the handle was opened on
+    pub path: String,
+    pub layer: Layer,
+    // Where an image file's data starts and its size; overlay nodes keep theirs
     pub offset: usize,
     pub size: usize,
+    pub pos: usize,
+    pub is_dir: bool,
+    // Opened for writing; such handles always point into the overlay
+    pub writable: bool,
+    pub append: bool,
+    pub refs: usize,
     pub uring: Option<IoUringBuffer>,
     pub user_shm_base: usize,
     pub server_shm_base: usize,
+    pub shm_size: usize,
+    // Set up by RING_NOTIFY
+    pub signal: Option<RingSignal>,
+    // READ_STREAM submissions still pushing chunks
+    streams: Vec<Stream>,
 }
 
 impl InitrdFile {
-    pub fn new(offset: usize, size: usize) -> Self {
-        Self { offset, size, uring: None, user_shm_base: 0, server_shm_base: 0 }
+    pub fn new(path: String, layer: Layer, offset: usize, size: usize) -> Self {
+        Self {
+            path,
+            layer,
+            offset,
+            size,
+            pos: 0,
+            is_dir: false,
+            writable: false,
+            append: false,
+            refs: 1,
+            uring: None,
+            user_shm_base: 0,
+            server_shm_base: 0,
+            shm_size: 0,
+            signal: None,
+            streams: Vec::new(),
+        }
+    }
+
+    // Positions in a directory are indices into the entry table, then into
+    // the directory's overlay entries
+    pub fn new_dir(path: String, layer: Layer) -> Self {
+        Self { is_dir: true, ..Self::new(path, layer, 0, 0) }
+    }
+
+    /// A fresh handle on the same file, for CLONE.
+    pub fn reopen(&self) -> Self {
+        Self {
+            is_dir: self.is_dir,
+            writable: self.writable,
+            append: self.append,
+            ..Self::new(self.path.clone(), self.layer, self.offset, self.size)
+        }
+    }
+
+    /// Key of the file in the lock table: image files by where their data
+    /// starts, overlay nodes counting down from the top.
+    pub fn lock_key(&self) -> usize {
+        match self.layer {
+            Layer::Upper(id) => usize::MAX - id,
+            Layer::Image(_) => self.offset,
+        }
+    }
+
+    fn file_size(&self, overlay: &Overlay) -> usize {
+        match self.layer {
+            Layer::Upper(id) => overlay.node(id).map_or(0, |node| node.size()),
+            Layer::Image(_) => self.size,
+        }
+    }
+
+    // The overlay node behind a handle that may write
+    fn upper(&self) -> Result<usize, Error> {
+        match self.layer {
+            Layer::Upper(id) if self.writable => Ok(id),
+            _ => Err(Error::PermissionDenied),
+        }
     }
 
     pub fn read(
         &mut self,
-        blk_client: &VolumeClient,
+        layers: &Layers,
         _badge: Badge,
         offset: usize,
         buf: &mut [u8],
     ) -> Result<usize, Error> {
-        if offset >= self.size {
+        if self.is_dir {
+            return Err(FsError::IsDir.into());
+        }
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        let read_len = limits::read_len(offset, buf.len(), self.file_size(layers.overlay));
+        if read_len == 0 {
             return Ok(0);
         }
-        let available = self.size - offset;
-        let read_len = core::cmp::min(available, buf.len() as usize) as usize;
-
-        let block_size = 4096;
-        let start_pos = self.offset + offset;
-        let end_pos = start_pos + read_len as usize;
-
-        let start_sector = start_pos / block_size;
-        let end_sector = (end_pos + block_size - 1) / block_size;
-        let sector_count = end_sector - start_sector;
-        let read_size = sector_count * block_size;
-
-        let mut temp_buf = alloc::vec![0u8; read_size as usize];
-
-        blk_client.read_at(start_sector, read_size as u32, &mut temp_buf)?;
 
-        let copy_start = (start_pos % block_size) as usize;
         let actual_read = core::cmp::min(read_len, buf.len());
-        buf[..actual_read].copy_from_slice(&temp_buf[copy_start..copy_start + actual_read]);
+        match self.layer {
+            Layer::Upper(id) => {
+                layers.overlay.node(id)?.read(offset, &mut buf[..actual_read]);
+            }
+            Layer::Image(_) => layers.image.read(self.offset + offset, &mut buf[..actual_read])?,
+        }
 
+        if advance {
+            self.pos = offset + actual_read;
+        }
         Ok(actual_read)
     }
 
-    pub fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
-        Ok(Stat { size: self.size, mode: DEFAULT_STAT, ..Default::default() })
+    pub fn write(
+        &mut self,
+        overlay: &mut Overlay,
+        _badge: Badge,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let id = self.upper()?;
+        let advance = offset == CURRENT_OFFSET;
+        let offset = match (self.append, advance) {
+            (true, _) => self.file_size(overlay),
+            (false, true) => self.pos,
+            (false, false) => offset,
+        };
+        let end = offset.checked_add(data.len()).ok_or(Error::InvalidArgs)?;
+        if end as u64 > INITRD_MAX_FILE_SIZE {
+            return Err(Error::InvalidArgs);
+        }
+        let written = overlay.write(id, offset, data)?;
+        if advance {
+            self.pos = offset + written;
+        }
+        Ok(written)
+    }
+
+    pub fn truncate(&mut self, overlay: &mut Overlay, size: usize) -> Result<(), Error> {
+        if size as u64 > INITRD_MAX_FILE_SIZE {
+            return Err(Error::InvalidArgs);
+        }
+        overlay.truncate(self.upper()?, size)
+    }
+
+    pub fn seek(
+        &mut self,
+        overlay: &Overlay,
+        _badge: Badge,
+        offset: i64,
+        whence: usize,
+    ) -> Result<usize, Error> {
+        let size = self.file_size(overlay);
+        self.pos = limits::seek_target(self.pos, size, offset, whence, INITRD_MAX_FILE_SIZE)?;
+        Ok(self.pos)
     }
 
     pub fn setup_iouring(
@@ -79,6 +384,7 @@ impl InitrdFile {
     ) -> Result<(), Error> {
         self.server_shm_base = server_vaddr;
         self.user_shm_base = user_vaddr;
+        self.shm_size = size;
         self.uring = Some(unsafe { IoUringBuffer::attach(server_vaddr as *mut u8, size) });
         if let Some(f) = frame {
             let shm = glenda::mem::shm::SharedMemory::new(f, server_vaddr, size);
@@ -87,31 +393,153 @@ impl InitrdFile {
         Ok(())
     }
 
+    fn window(&self) -> ShmWindow {
+        ShmWindow {
+            user_base: self.user_shm_base,
+            server_base: self.server_shm_base,
+            size: self.shm_size,
+        }
+    }
+
+    // Server-side address of `len` bytes of the shared buffer at client
+    // address `addr`
+    fn shm_target(&self, addr: u64, len: usize) -> Result<usize, Error> {
+        let addr = usize::try_from(addr).map_err(|_| Error::InvalidArgs)?;
+        let shm_off = addr.checked_sub(self.user_shm_base).ok_or(Error::InvalidArgs)?;
+        limits::checked_end(shm_off, len, self.shm_size as u64)?;
+        Ok(self.server_shm_base + shm_off)
+    }
+
+    // Reads `len` bytes at file offset `off` into the client buffer at `addr`
+    fn uring_read(&self, layers: &Layers, addr: u64, off: u64, len: usize) -> Result<usize, Error> {
+        if self.is_dir {
+            return Err(FsError::IsDir.into());
+        }
+        let off = usize::try_from(off).map_err(|_| Error::InvalidArgs)?;
+        let len = limits::read_len(off, len, self.file_size(layers.overlay));
+        let server_addr = self.shm_target(addr, len)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        match self.layer {
+            Layer::Upper(id) => {
+                // shm_target checked the range lies in the mapped window
+                let dst = unsafe { core::slice::from_raw_parts_mut(server_addr as *mut u8, len) };
+                layers.overlay.node(id)?.read(off, dst);
+            }
+            Layer::Image(_) => layers.image.read_shm(self.offset + off, len, server_addr)?,
+        }
+        Ok(len)
+    }
+
+    // Writes `len` bytes from the client buffer at `addr` at file offset `off`
+    fn uring_write(
+        &mut self,
+        layers: &mut Layers,
+        addr: u64,
+        off: u64,
+        len: usize,
+    ) -> Result<usize, Error> {
+        let server_addr = self.shm_target(addr, len)?;
+        // shm_target checked the range lies in the mapped window
+        let src = unsafe { core::slice::from_raw_parts(server_addr as *const u8, len) };
+        let off = usize::try_from(off).map_err(|_| Error::InvalidArgs)?;
+        self.write(layers.overlay, Badge::null(), off, src)
+    }
+
+    /// Serves the submissions queued on the ring. Lock submissions go to
+    /// `locks` under the handle's badge; the returned grants are queued locks,
+    /// of this or other handles, that an unlock here let through.
     pub fn process_iouring(
         &mut self,
-        blk_client: &VolumeClient,
-        _badge: Badge,
-    ) -> Result<(), Error> {
+        layers: &mut Layers,
+        badge: Badge,
+        locks: &mut LockTable<usize>,
+    ) -> Result<Vec<Grant>, Error> {
+        let mut grants = Vec::new();
         if let Some(ring) = self.uring.take() {
             while let Some(sqe) = ring.pop_sqe() {
-                use glenda::io::uring::{IoUringCqe, IOURING_OP_READ};
+                use glenda::io::uring::{
+                    IoUringCqe, IOURING_OP_FSYNC, IOURING_OP_READ, IOURING_OP_WRITE,
+                };
 
                 let res = match sqe.opcode {
                     IOURING_OP_READ => {
-                        let addr = sqe.addr as usize;
-                        let len = sqe.len as u32;
-                        let offset = sqe.off as usize;
-
-                        if addr < self.user_shm_base {
-                            -(Error::InvalidArgs as i32)
-                        } else {
-                            let server_addr = addr - self.user_shm_base + self.server_shm_base;
-                            let start_pos = self.offset + offset;
-                            let start_sector = start_pos / 4096;
-                            match blk_client.read_shm(start_sector, len, server_addr) {
-                                Ok(_) => len as i32,
-                                Err(e) => -(e as i32),
+                        match self.uring_read(layers, sqe.addr, sqe.off, sqe.len as usize) {
+                            Ok(len) => len as i32,
+                            Err(e) => -(e as i32),
+                        }
+                    }
+                    IOURING_OP_READV => {
+                        let read =
+                            self.window().iovecs(sqe.addr, sqe.len as usize).and_then(|iov| {
+                                transfer_vectored(&iov, sqe.off as usize, |addr, at, len| {
+                                    self.uring_read(layers, addr, at as u64, len)
+                                })
+                            });
+                        match read {
+                            Ok(len) => len as i32,
+                            Err(e) => -(e as i32),
+                        }
+                    }
+                    IOURING_OP_WRITE => {
+                        match self.uring_write(layers, sqe.addr, sqe.off, sqe.len as usize) {
+                            Ok(len) => len as i32,
+                            Err(e) => -(e as i32),
+                        }
+                    }
+                    IOURING_OP_WRITEV => {
+                        let written =
+                            self.window().iovecs(sqe.addr, sqe.len as usize).and_then(|iov| {
+                                transfer_vectored(&iov, sqe.off as usize, |addr, at, len| {
+                                    self.uring_write(layers, addr, at as u64, len)
+                                })
+                            });
+                        match written {
+                            Ok(len) => len as i32,
+                            Err(e) => -(e as i32),
+                        }
+                    }
+                    // Nothing is ever written back, so there is nothing to flush
+                    IOURING_OP_FSYNC => 0,
+                    IOURING_OP_LOCK if self.is_dir => -(Error::InvalidArgs as i32),
+                    IOURING_OP_LOCK => {
+                        let flags = sqe.addr as usize;
+                        match locks.lock_or_wait(
+                            &self.lock_key(),
+                            badge.bits(),
+                            sqe.off,
+                            sqe.len as u64,
+                            flags,
+                            sqe.user_data,
+                        ) {
+                            Ok(true) => 0,
+                            // Completed by whichever unlock lets it through
+                            Ok(false) => continue,
+                            Err(e) => -(e as i32),
+                        }
+                    }
+                    IOURING_OP_UNLOCK => {
+                        let key = self.lock_key();
+                        match locks.unlock(&key, badge.bits(), sqe.off, sqe.len as u64) {
+                            Ok(granted) => {
+                                grants.extend(granted);
+                                0
+                            }
+                            Err(e) => -(e as i32),
+                        }
+                    }
+                    IOURING_OP_READ_STREAM if self.streams.len() == MAX_STREAMS => {
+                        -(Error::OutOfMemory as i32)
+                    }
+                    IOURING_OP_READ_STREAM => {
+                        let window = self.window();
+                        match Stream::start(&window, sqe.user_data, sqe.addr, sqe.off, sqe.len) {
+                            Ok(stream) => {
+                                self.streams.push(stream);
+                                continue;
                             }
+                            Err(e) => -(e as i32),
                         }
                     }
                     _ => -(Error::NotSupported as i32),
@@ -120,86 +548,434 @@ impl InitrdFile {
                 let cqe = IoUringCqe { user_data: sqe.user_data, res, flags: 0 };
                 ring.push_cqe(cqe).ok();
             }
+            self.pump_streams(layers, &ring);
             self.uring = Some(ring);
+            self.announce();
+        }
+        Ok(grants)
+    }
+
+    // Moves the streams on as far as their free buffers allow
+    fn pump_streams(&mut self, layers: &Layers, ring: &IoUringBuffer) {
+        let mut streams = core::mem::take(&mut self.streams);
+        let window = self.window();
+        streams.retain_mut(|stream| {
+            let read = |addr, at, len| self.uring_read(layers, addr, at as u64, len);
+            let post = |user_data, res, flags| {
+                ring.push_cqe(completion(user_data, res, flags)).ok();
+            };
+            match stream.pump(&window, read, post) {
+                Some(res) => {
+                    ring.push_cqe(completion(stream.user_data(), res, 0)).ok();
+                    false
+                }
+                None => true,
+            }
+        });
+        self.streams = streams;
+    }
+
+    /// Posts a completion for an earlier submission, e.g. a queued lock.
+    pub fn complete(&self, user_data: u64, res: i32) {
+        if let Some(ring) = &self.uring {
+            ring.push_cqe(glenda::io::uring::IoUringCqe { user_data, res, flags: 0 }).ok();
+        }
+    }
+
+    /// Tells the client new completions are waiting, if it asked to be told.
+    pub fn announce(&self) {
+        if let Some(signal) = &self.signal {
+            signal.raise();
         }
-        Ok(())
     }
 }
 
 pub struct InitrdFS {
     entries: Vec<InitrdEntry>,
+    overlay: Overlay,
 }
 
 impl InitrdFS {
-    pub fn new(header_buf: [u8; 4096]) -> Self {
-        let magic =
-            u32::from_le_bytes([header_buf[0], header_buf[1], header_buf[2], header_buf[3]]);
-        if magic != 0x99999999 {
-            // This should have been checked earlier but let's be safe
-        }
-
-        let count = u32::from_le_bytes([header_buf[4], header_buf[5], header_buf[6], header_buf[7]])
-            as usize;
-        let mut entries = Vec::with_capacity(count);
-
-        let entry_base = 16;
-        let entry_size = 48;
-        for i in 0..count {
-            let offset = entry_base + i * entry_size;
-            let type_byte = header_buf[offset];
-            let file_offset = u32::from_le_bytes([
-                header_buf[offset + 1],
-                header_buf[offset + 2],
-                header_buf[offset + 3],
-                header_buf[offset + 4],
-            ]) as usize;
-            let file_size = u32::from_le_bytes([
-                header_buf[offset + 5],
-                header_buf[offset + 6],
-                header_buf[offset + 7],
-                header_buf[offset + 8],
-            ]) as usize;
-
-            let mut name_buf = [0u8; 32];
-            name_buf.copy_from_slice(&header_buf[offset + 16..offset + 48]);
-            let name_len = name_buf.iter().position(|&b| b == 0).unwrap_or(32);
-            let name = core::str::from_utf8(&name_buf[..name_len]).unwrap_or("unknown");
-
-            entries.push(InitrdEntry {
-                _type: type_byte,
-                name: alloc::string::String::from(name),
-                offset: file_offset,
-                size: file_size,
-            });
-        }
-        Self { entries }
+    /// Parses the header and entry table, `header_len` bytes of them.
+    pub fn new(header_buf: &[u8]) -> Result<Self, Error> {
+        verify_header(header_buf)?;
+        let entries =
+            if is_v2(header_buf) { parse_v2(header_buf)? } else { parse_v1(header_buf) };
+        Ok(Self { entries, overlay: Overlay::new() })
+    }
+
+    pub fn overlay(&self) -> &Overlay {
+        &self.overlay
+    }
+
+    pub fn overlay_mut(&mut self) -> &mut Overlay {
+        &mut self.overlay
+    }
+
+    /// Image entry at canonical `path`; None is the root.
+    fn lookup(&self, path: &str) -> Result<Option<usize>, Error> {
+        let mut node: Option<usize> = None;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            if let Some(dir) = node {
+                if !self.entries[dir].is_dir() {
+                    return Err(FsError::NotDir.into());
+                }
+            }
+            node = Some(
+                self.entries
+                    .iter()
+                    .position(|e| e.parent == node && e.name == part)
+                    .ok_or(Error::NotFound)?,
+            );
+        }
+        Ok(node)
+    }
+
+    /// What canonical `path` names: the overlay's node if it has one, else
+    /// the image's entry unless a whiteout hides it.
+    fn resolve(&self, path: &str) -> Result<Layer, Error> {
+        if let Some(id) = self.overlay.find(path) {
+            return Ok(Layer::Upper(id));
+        }
+        if self.overlay.hides(path) {
+            return Err(Error::NotFound);
+        }
+        self.lookup(path).map(Layer::Image)
+    }
+
+    fn is_dir(&self, layer: Layer) -> bool {
+        match layer {
+            Layer::Image(None) => true,
+            Layer::Image(Some(i)) => self.entries[i].is_dir(),
+            Layer::Upper(id) => self.overlay.node(id).is_ok_and(|node| node.is_dir),
+        }
     }
 
     pub fn open_handle(
         &mut self,
         path: &str,
-        _flags: OpenFlags,
-        _mode: u32,
+        flags: OpenFlags,
+        mode: u32,
+        image: &Image,
+    ) -> Result<InitrdFile, Error> {
+        self.open_at("/", path, flags, mode, image)
+    }
+
+    /// Opens `path` relative to the directory at `base`. Opening an image
+    /// file for writing copies it into the overlay first.
+    pub fn open_at(
+        &mut self,
+        base: &str,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+        image: &Image,
     ) -> Result<InitrdFile, Error> {
-        let clean_path = path.trim_start_matches('/');
-        for entry in &self.entries {
-            if entry.name == clean_path {
-                return Ok(InitrdFile::new(entry.offset, entry.size));
+        let path = canonical(&join(base, path));
+        let trunc = flags.contains(OpenFlags::O_TRUNC);
+        let writable =
+            flags.intersects(OpenFlags::O_WRONLY | OpenFlags::O_RDWR | OpenFlags::O_APPEND);
+        let layer = match self.resolve(&path) {
+            Ok(_) if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) => {
+                return Err(Error::AlreadyExists)
+            }
+            Ok(layer) => layer,
+            Err(Error::NotFound) if flags.contains(OpenFlags::O_CREAT) => {
+                Layer::Upper(self.create(&path, false, mode)?)
+            }
+            Err(e) => return Err(e),
+        };
+
+        if self.is_dir(layer) {
+            if writable || trunc {
+                return Err(FsError::IsDir.into());
+            }
+            return Ok(InitrdFile::new_dir(path, layer));
+        }
+        if flags.contains(OpenFlags::O_DIRECTORY) {
+            return Err(FsError::NotDir.into());
+        }
+        if writable || trunc {
+            self.overlay.check_writable()?;
+        }
+
+        let (layer, offset, size) = match layer {
+            Layer::Image(Some(i)) if writable || trunc => {
+                // Truncated anyway, so there is nothing worth copying
+                let len = if trunc { 0 } else { self.entries[i].size };
+                (Layer::Upper(self.copy_up(&path, i, len, image)?), 0, 0)
+            }
+            Layer::Image(Some(i)) => (layer, self.entries[i].offset, self.entries[i].size),
+            layer => (layer, 0, 0),
+        };
+        if let (Layer::Upper(id), true) = (layer, trunc) {
+            self.overlay.truncate(id, 0)?;
+        }
+
+        let mut file = InitrdFile::new(path, layer, offset, size);
+        file.writable = writable;
+        file.append = flags.contains(OpenFlags::O_APPEND);
+        Ok(file)
+    }
+
+    // Adds a file or directory at canonical `path` to the overlay
+    fn create(&mut self, path: &str, is_dir: bool, mode: u32) -> Result<usize, Error> {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if name.is_empty() || name.len() > NAME_MAX {
+            return Err(Error::InvalidArgs);
+        }
+        if !self.is_dir(self.resolve(parent(path))?) {
+            return Err(FsError::NotDir.into());
+        }
+        let mode = if is_dir { S_IFDIR } else { S_IFREG } | mode & PERM_MASK;
+        let mtime = clock::now().map_or(0, |now| now.stat_secs() as u64);
+        self.overlay.add(String::from(path), is_dir, mode, mtime)
+    }
+
+    // Copies the first `len` bytes of image file `index` into a new overlay
+    // node at `path`
+    fn copy_up(
+        &mut self,
+        path: &str,
+        index: usize,
+        len: usize,
+        image: &Image,
+    ) -> Result<usize, Error> {
+        let entry = &self.entries[index];
+        let (offset, mode, mtime) = (entry.offset, entry.mode, entry.mtime);
+        let id = self.overlay.add(String::from(path), false, mode, mtime)?;
+
+        let mut page = [0u8; PGSIZE];
+        let mut copy = |overlay: &mut Overlay| -> Result<(), Error> {
+            let mut at = 0;
+            while at < len {
+                let n = PGSIZE.min(len - at);
+                image.read(offset + at, &mut page[..n])?;
+                // Pages of zeros stay holes and cost nothing
+                if page[..n].iter().any(|&b| b != 0) {
+                    overlay.write(id, at, &page[..n])?;
+                }
+                at += n;
+            }
+            overlay.truncate(id, len)
+        };
+        if let Err(e) = copy(&mut self.overlay) {
+            let _ = self.overlay.unlink(path);
+            self.overlay.release(id);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u32) -> Result<(), Error> {
+        let path = canonical(path);
+        match self.resolve(&path) {
+            Ok(_) => Err(Error::AlreadyExists),
+            Err(Error::NotFound) => self.create(&path, true, mode).map(|_| ()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Removes `path`, hiding it behind a whiteout if the image has it.
+    /// Returns the overlay node it named, to free once no handle uses it.
+    pub fn unlink(&mut self, path: &str) -> Result<Option<usize>, Error> {
+        let path = canonical(path);
+        if path == "/" {
+            return Err(Error::InvalidArgs);
+        }
+        self.overlay.check_writable()?;
+        let layer = self.resolve(&path)?;
+        if self.is_dir(layer) && !self.getdents(&path, layer, &mut 0, 1).is_empty() {
+            return Err(Error::InvalidArgs);
+        }
+
+        let upper = match layer {
+            Layer::Upper(id) => {
+                self.overlay.unlink(&path)?;
+                Some(id)
             }
+            Layer::Image(_) => None,
+        };
+        // Otherwise the image's entry would show through again
+        if self.lookup(&path).is_ok() {
+            self.overlay.whiteout(path)?;
         }
-        Err(Error::NotFound)
+        Ok(upper)
     }
 
     pub fn stat(&self, path: &str) -> Result<Stat, Error> {
-        let clean_path = path.trim_start_matches('/');
-        if clean_path.is_empty() {
-            return Ok(Stat { size: 0, mode: 0o040555, ..Default::default() });
+        self.layer_stat(self.resolve(&canonical(path))?)
+    }
+
+    /// Attributes of what a handle is open on. Image entries are numbered
+    /// by table index past the root's 1, overlay nodes after them.
+    pub fn layer_stat(&self, layer: Layer) -> Result<Stat, Error> {
+        let node = match layer {
+            Layer::Image(node) => return Ok(self.node_stat(node)),
+            Layer::Upper(id) => (id, self.overlay.node(id)?),
+        };
+        let (id, node) = node;
+        Ok(Stat {
+            ino: self.entries.len() + 2 + id,
+            mode: node.mode,
+            nlink: if node.is_dir { 2 } else { 1 },
+            size: node.size(),
+            atime: node.mtime as usize,
+            mtime: node.mtime as usize,
+            ctime: node.mtime as usize,
+            ..Default::default()
+        })
+    }
+
+    fn node_stat(&self, node: Option<usize>) -> Stat {
+        let Some(i) = node else {
+            return Stat { ino: 1, mode: ROOT_DIR_STAT, nlink: 2, ..Default::default() };
+        };
+        let entry = &self.entries[i];
+        Stat {
+            ino: i + 2,
+            mode: entry.mode,
+            nlink: if entry.is_dir() { 2 } else { 1 },
+            uid: entry.uid,
+            gid: entry.gid,
+            size: entry.size,
+            atime: entry.mtime as usize,
+            mtime: entry.mtime as usize,
+            ctime: entry.mtime as usize,
+            ..Default::default()
         }
-        for entry in &self.entries {
-            if entry.name == clean_path {
-                return Ok(Stat { size: entry.size, mode: DEFAULT_STAT, ..Default::default() });
+    }
+
+    /// Up to `count` entries of directory `dir` at `path` from `*pos` on,
+    /// moving `*pos` past the last one returned. Positions below the table
+    /// length are image entries, skipped when the overlay replaced or
+    /// removed them; the directory's overlay entries follow.
+    pub fn getdents(&self, path: &str, dir: Layer, pos: &mut usize, count: usize) -> Vec<DEntry> {
+        let mut out = Vec::new();
+        let base = self.entries.len();
+        if let Layer::Image(dir) = dir {
+            while out.len() < count && *pos < base {
+                let entry = &self.entries[*pos];
+                *pos += 1;
+                if entry.parent != dir {
+                    continue;
+                }
+                let child = join(path, &entry.name);
+                if self.overlay.find(&child).is_none() && !self.overlay.hides(&child) {
+                    let type_ = if entry.is_dir() { DT_DIR } else { DT_REG };
+                    out.push(dentry(*pos + 1, *pos, type_, entry.name.as_bytes()));
+                }
             }
         }
-        Err(Error::NotFound)
+        *pos = (*pos).max(base);
+
+        let children = self.overlay.children(path).skip(*pos - base);
+        for (name, id) in children.take(count - out.len()) {
+            *pos += 1;
+            let is_dir = self.overlay.node(id).is_ok_and(|node| node.is_dir);
+            let type_ = if is_dir { DT_DIR } else { DT_REG };
+            out.push(dentry(base + 2 + id, *pos, type_, name.as_bytes()));
+        }
+        out
+    }
+
+    /// STATFS: the image, all of it in use, plus the overlay's budget, free
+    /// as far as nothing was written into it yet.
+    pub fn stats(&self) -> FsStats {
+        let end = self.entries.iter().map(|e| e.offset + e.size).fold(HEADER_SIZE, usize::max);
+        let blocks = end.div_ceil(IMAGE_BLOCK_SIZE) as u64;
+        let free = (OVERLAY_MAX_PAGES - self.overlay.used_pages()) as u64;
+        // The root directory is not in the entry table
+        let inodes = (self.entries.len() + 1 + self.overlay.node_count()) as u64;
+        FsStats {
+            total_blocks: blocks + OVERLAY_MAX_PAGES as u64,
+            free_blocks: free,
+            avail_blocks: if self.overlay.is_read_only() { 0 } else { free },
+            ..FsStats::full(IMAGE_BLOCK_SIZE, blocks, inodes)
+        }
+    }
+}
+
+// v1: fixed 48-byte entries, all files in the root, read-only for everyone
+fn parse_v1(header_buf: &[u8]) -> Vec<InitrdEntry> {
+    let count = header_u32(header_buf, 4) as usize;
+    let mut entries = Vec::with_capacity(count);
+
+    for i in 0..count {
+        let offset = ENTRY_BASE + i * ENTRY_SIZE;
+        let file_offset = header_u32(header_buf, offset + 1) as usize;
+        let file_size = header_u32(header_buf, offset + 5) as usize;
+
+        let mut name_buf = [0u8; 32];
+        name_buf.copy_from_slice(&header_buf[offset + 16..offset + 48]);
+        let name_len = name_buf.iter().position(|&b| b == 0).unwrap_or(32);
+        let name = core::str::from_utf8(&name_buf[..name_len]).unwrap_or("unknown");
+
+        entries.push(InitrdEntry {
+            // v1 has no directories, whatever the type byte says
+            _type: ENTRY_FILE,
+            name: String::from(name),
+            offset: file_offset,
+            size: file_size,
+            parent: None,
+            mode: DEFAULT_STAT,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        });
+    }
+    entries
+}
+
+// v2: variable-length entries. A parent has to come before its children
+// and be a directory, so the tree cannot loop.
+fn parse_v2(header_buf: &[u8]) -> Result<Vec<InitrdEntry>, Error> {
+    let count = header_u32(header_buf, V2_COUNT) as usize;
+    let end = table_end(header_buf).ok_or(Error::DeviceError)?;
+    let table = &header_buf[..end];
+    let mut entries: Vec<InitrdEntry> = Vec::new();
+    let mut at = V2_ENTRY_BASE;
+
+    for _ in 0..count {
+        let field = |off: usize| le_u32(table, at + off).map_err(|_| Error::DeviceError);
+        let wide = |off: usize| le_u64(table, at + off).map_err(|_| Error::DeviceError);
+        let _type = *table.get(at).ok_or(Error::DeviceError)?;
+        let name_len = le_u16(table, at + V2_NAME_LEN).map_err(|_| Error::DeviceError)? as usize;
+        let name = table.get(at + V2_NAME..at + V2_NAME + name_len).ok_or(Error::DeviceError)?;
+        let name = core::str::from_utf8(name).map_err(|_| Error::DeviceError)?;
+        if name_len > NAME_MAX || matches!(name, "" | "." | "..") || name.contains('/') {
+            return Err(Error::DeviceError);
+        }
+
+        let parent = match field(V2_PARENT)? {
+            PARENT_ROOT => None,
+            p if (p as usize) < entries.len() && entries[p as usize].is_dir() => Some(p as usize),
+            _ => return Err(Error::DeviceError),
+        };
+        let mode = field(V2_MODE)? & PERM_MASK;
+        let (mode, offset, size) = match _type {
+            ENTRY_DIR => (S_IFDIR | mode, 0, 0),
+            ENTRY_FILE => {
+                let offset = usize::try_from(wide(V2_OFFSET)?).map_err(|_| Error::DeviceError)?;
+                let size = usize::try_from(wide(V2_SIZE)?).map_err(|_| Error::DeviceError)?;
+                offset.checked_add(size).ok_or(Error::DeviceError)?;
+                (S_IFREG | mode, offset, size)
+            }
+            _ => return Err(Error::NotSupported),
+        };
+
+        entries.push(InitrdEntry {
+            _type,
+            offset,
+            size,
+            name: String::from(name),
+            parent,
+            mode,
+            uid: field(V2_UID)?,
+            gid: field(V2_GID)?,
+            mtime: wide(V2_MTIME)?,
+        });
+        at += (V2_NAME + name_len).next_multiple_of(8);
     }
+    Ok(entries)
 }
@@ -4,3 +4,13 @@ pub const VFS_SLOT: CapPtr = CapPtr::from(11);
 pub const VOLUME_SLOT: CapPtr = CapPtr::from(12);
 pub const SHM_SLOT: CapPtr = CapPtr::from(13);
 pub const RING_SLOT: CapPtr = CapPtr::from(14);
+// Slots the CSpaceManager hands out stay below this
+pub const DYNAMIC_SLOT_LIMIT: CapPtr = CapPtr::from(0x100);
+
+// The block device ring and buffer and client rings from SETUP_IOURING are
+// mapped between these; the VSpaceManager has the range above to itself
+pub const MAP_START: usize = 0x4000_0000;
+pub const MAP_END: usize = 0x7000_0000;
+pub const BLOCK_RING_SIZE: usize = 4096;
+// The driver picks the size of the block buffer; this is the most it may map
+pub const BLOCK_SHM_SIZE: usize = 16 * 1024 * 1024;
@@ -14,8 +14,10 @@ use glenda::ipc::Badge;
 use glenda::protocol::resource::{FS_ENDPOINT, VOLUME_ENDPOINT};
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
 
+mod decompress;
 mod fs;
 mod layout;
+mod overlay;
 mod server;
 
 use layout::{DEVICE_SLOT, VFS_SLOT};
@@ -69,6 +71,10 @@ fn main() -> usize {
     }
 
     if let Err(e) = server.init() {
+        // Not an initrd: tell the supervisor so it can try the next service
+        if let Some(found) = server.declined() {
+            return found.declined_exit();
+        }
         log!("Failed to init: {:?}", e);
         return 1;
     }
@@ -0,0 +1,227 @@
+//! Writable layer over the image, held in memory and gone with the service.
+//! A file opened for writing is copied up whole, new files and directories
+//! exist only here, and removing an image entry leaves a whiteout that hides
+//! it and everything below it. Paths are absolute and canonical.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use fs_common::errors::FsError;
+use glenda::arch::mem::PGSIZE;
+use glenda::error::Error;
+
+/// Pages the layer may hold, 16 MiB; writes past that fail with NoSpace.
+pub const OVERLAY_MAX_PAGES: usize = 4096;
+
+pub struct Node {
+    pub is_dir: bool,
+    pub mode: u32,
+    pub mtime: u64,
+    size: usize,
+    // Pages written so far, by index; the rest of the file reads as zeros
+    pages: BTreeMap<usize, Box<[u8; PGSIZE]>>,
+}
+
+impl Node {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Copies out what `buf` can take from `offset` on, up to the end of file.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.size.saturating_sub(offset));
+        let mut done = 0;
+        while done < len {
+            let at = offset + done;
+            let (index, within) = (at / PGSIZE, at % PGSIZE);
+            let n = (PGSIZE - within).min(len - done);
+            match self.pages.get(&index) {
+                Some(page) => buf[done..done + n].copy_from_slice(&page[within..within + n]),
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+        len
+    }
+}
+
+pub struct Overlay {
+    nodes: BTreeMap<usize, Node>,
+    paths: BTreeMap<String, usize>,
+    whiteouts: BTreeSet<String>,
+    next_id: usize,
+    pages: usize,
+    read_only: bool,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            paths: BTreeMap::new(),
+            whiteouts: BTreeSet::new(),
+            next_id: 0,
+            pages: 0,
+            read_only: false,
+        }
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(FsError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.paths.get(path).copied()
+    }
+
+    pub fn node(&self, id: usize) -> Result<&Node, Error> {
+        self.nodes.get(&id).ok_or(Error::NotFound)
+    }
+
+    /// True if a whiteout covers `path` or a directory above it.
+    pub fn hides(&self, path: &str) -> bool {
+        let mut at = path;
+        loop {
+            if self.whiteouts.contains(at) {
+                return true;
+            }
+            match at.rfind('/') {
+                Some(0) | None => return false,
+                Some(i) => at = &at[..i],
+            }
+        }
+    }
+
+    /// Names `path` a new, empty file or directory.
+    pub fn add(
+        &mut self,
+        path: String,
+        is_dir: bool,
+        mode: u32,
+        mtime: u64,
+    ) -> Result<usize, Error> {
+        self.check_writable()?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let node = Node { is_dir, mode, mtime, size: 0, pages: BTreeMap::new() };
+        self.nodes.insert(id, node);
+        self.paths.insert(path, id);
+        Ok(id)
+    }
+
+    /// Writes `data` at `offset`, allocating the pages it lands on. Fails
+    /// without writing anything if the layer cannot take them all.
+    pub fn write(&mut self, id: usize, offset: usize, data: &[u8]) -> Result<usize, Error> {
+        self.check_writable()?;
+        let end = offset.checked_add(data.len()).ok_or(Error::InvalidArgs)?;
+        let node = self.nodes.get_mut(&id).ok_or(Error::NotFound)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let span = offset / PGSIZE..end.div_ceil(PGSIZE);
+        let missing = span.clone().filter(|i| !node.pages.contains_key(i)).count();
+        if self.pages + missing > OVERLAY_MAX_PAGES {
+            return Err(FsError::NoSpace.into());
+        }
+        self.pages += missing;
+
+        let mut done = 0;
+        for index in span {
+            let page = node.pages.entry(index).or_insert_with(|| Box::new([0; PGSIZE]));
+            let within = (offset + done) % PGSIZE;
+            let n = (PGSIZE - within).min(data.len() - done);
+            page[within..within + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        node.size = node.size.max(end);
+        Ok(data.len())
+    }
+
+    pub fn truncate(&mut self, id: usize, size: usize) -> Result<(), Error> {
+        self.check_writable()?;
+        let node = self.nodes.get_mut(&id).ok_or(Error::NotFound)?;
+        if node.is_dir {
+            return Err(FsError::IsDir.into());
+        }
+        if size < node.size {
+            let dropped = node.pages.split_off(&size.div_ceil(PGSIZE));
+            self.pages -= dropped.len();
+            // Bytes past the new end read as zeros should the file grow again
+            if let Some(page) = node.pages.get_mut(&(size / PGSIZE)) {
+                page[size % PGSIZE..].fill(0);
+            }
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    /// Takes `path` out of the namespace. Its node stays for the handles
+    /// still open on it until `release`.
+    pub fn unlink(&mut self, path: &str) -> Result<(), Error> {
+        self.check_writable()?;
+        self.paths.remove(path).map(|_| ()).ok_or(Error::NotFound)
+    }
+
+    /// Hides the image entry at `path` and below.
+    pub fn whiteout(&mut self, path: String) -> Result<(), Error> {
+        self.check_writable()?;
+        self.whiteouts.insert(path);
+        Ok(())
+    }
+
+    /// Frees node `id` once no path names it; called as its last handle closes.
+    pub fn release(&mut self, id: usize) {
+        if self.paths.values().any(|&v| v == id) {
+            return;
+        }
+        if let Some(node) = self.nodes.remove(&id) {
+            self.pages -= node.pages.len();
+        }
+    }
+
+    /// Name and node of each entry directly inside `dir`, in name order.
+    pub fn children<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = (&'a str, usize)> + 'a {
+        let prefix = if dir == "/" { 1 } else { dir.len() + 1 };
+        self.paths
+            .range::<str, _>((core::ops::Bound::Excluded(dir), core::ops::Bound::Unbounded))
+            .take_while(move |(path, _)| path.starts_with(dir))
+            .filter(move |(path, _)| {
+                path.len() > prefix
+                    && (dir == "/" || path.as_bytes()[dir.len()] == b'/')
+                    && !path[prefix..].contains('/')
+            })
+            .map(move |(path, &id)| (&path[prefix..], id))
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn used_pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Drops everything written, as at UNMOUNT.
+    pub fn clear(&mut self) {
+        let read_only = self.read_only;
+        *self = Self::new();
+        self.read_only = read_only;
+    }
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,4 +1,6 @@
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use glenda::arch::mem::PGSIZE;
 use glenda::cap::{CapPtr, Endpoint, Frame, Reply, CSPACE_CAP, RECV_SLOT};
 use glenda::client::volume::VolumeClient;
 use glenda::client::{FsClient, ResourceClient};
@@ -11,26 +13,67 @@ use glenda::ipc::{Badge, MsgFlags, MsgTag, UTCB};
 use glenda::mem::shm::ShmParams;
 use glenda::protocol;
 use glenda::protocol::fs::OpenFlags;
-use glenda::interface::{CSpaceService, VSpaceService};
+use glenda::protocol::PROCESS_PROTO;
+use glenda::interface::VSpaceService;
 use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use fs_common::clock;
+use fs_common::device::DeviceInfo;
+use fs_common::errors::{self, FsError};
+use fs_common::health::IoStats;
+use fs_common::locks::{Grant, LockTable};
+use fs_common::mount::{MountOptions, MNT_CASEFOLD};
+use fs_common::path;
+use fs_common::probe::{self, FsType};
+use fs_common::proto::{self, CURRENT_OFFSET};
+use fs_common::ring::{self, RingSignal};
+use fs_common::slots::SlotAllocator;
+use fs_common::vaddr::VaddrAllocator;
+use fs_common::version::{self, Versions};
+use fs_common::wire::WireGuard;
 
-use crate::fs::InitrdFS;
-use crate::layout::{RING_SLOT, SHM_SLOT};
+use crate::decompress::{self, Source, MAX_IMAGE_SIZE};
+use crate::fs::{Image, InitrdFS, Layer, Layers, HEADER_SIZE};
+use crate::layout::{
+    BLOCK_RING_SIZE, BLOCK_SHM_SIZE, DYNAMIC_SLOT_LIMIT, MAP_END, MAP_START, RING_SLOT, SHM_SLOT,
+};
+
+// Nothing is ever written back, so there are no background jobs
+const FEATURES: usize = version::FEAT_RING_NOTIFY
+    | version::FEAT_IOVEC
+    | version::FEAT_LOCKS
+    | version::FEAT_CLONE
+    | version::FEAT_WIRE
+    | version::FEAT_STATFS
+    | version::FEAT_NEXT
+    | version::FEAT_STREAM
+    | version::FEAT_ERROR_DETAIL;
 
 pub struct InitrdServer<'a> {
     blk_client: Option<VolumeClient>,
     dev_ep: Endpoint,
+    device: DeviceInfo,
+    // What the device held when init declined it
+    declined: Option<FsType>,
+    wire: WireGuard,
+    versions: Versions,
+    io_stats: IoStats,
     res_client: &'a mut ResourceClient,
     vfs_client: &'a mut FsClient,
     fs: Option<InitrdFS>,
+    // The whole image, when it was stored compressed
+    unpacked: Option<Vec<u8>>,
     open_files: BTreeMap<usize, crate::fs::InitrdFile>,
+    // Advisory locks by InitrdFile::lock_key, owned by handle badge
+    locks: LockTable<usize>,
     next_badge: usize,
-    next_vaddr: usize,
+    maps: VaddrAllocator,
+    // Client window the volume client took over as its buffer, if any
+    blk_shm: Option<usize>,
     endpoint: Endpoint,
     reply: Reply,
     recv: CapPtr,
     running: bool,
-    cspace: &'a mut CSpaceManager,
+    slots: SlotAllocator<'a>,
     vspace: &'a mut VSpaceManager,
 }
 
@@ -45,20 +88,101 @@ impl<'a> InitrdServer<'a> {
         Self {
             blk_client: None,
             dev_ep,
+            device: DeviceInfo::unknown(),
+            declined: None,
+            wire: WireGuard::new(),
+            versions: Versions::new(FEATURES),
+            io_stats: IoStats::new(),
             res_client,
             vfs_client,
             fs: None,
+            unpacked: None,
             open_files: BTreeMap::new(),
+            locks: LockTable::new(),
             next_badge: 1,
-            next_vaddr: 0x4000_0000,
+            maps: VaddrAllocator::new(MAP_START, MAP_END),
+            blk_shm: None,
             endpoint: Endpoint::from(CapPtr::null()),
             reply: Reply::from(CapPtr::null()),
             recv: CapPtr::null(),
             running: false,
-            cspace,
+            slots: SlotAllocator::new(cspace, DYNAMIC_SLOT_LIMIT),
             vspace,
         }
     }
+
+    /// Set when init failed because the device holds no initrd image, so the
+    /// caller can hand it to another service.
+    pub fn declined(&self) -> Option<FsType> {
+        self.declined
+    }
+
+    // UNMOUNT and EXIT. Nothing is written back: the handles and whatever
+    // the overlay holds go away and the loop ends after the reply.
+    fn shutdown(&mut self) {
+        for (_, file) in core::mem::take(&mut self.open_files) {
+            self.drop_window(file.server_shm_base);
+        }
+        self.locks = LockTable::new();
+        if let Some(fs) = &mut self.fs {
+            fs.overlay_mut().clear();
+        }
+        self.running = false;
+    }
+
+    // Frees overlay node `id` if it was unlinked and no handle is left on it
+    fn release_node(&mut self, id: usize) {
+        if self.open_files.values().any(|f| f.layer == Layer::Upper(id)) {
+            return;
+        }
+        if let Some(fs) = &mut self.fs {
+            fs.overlay_mut().release(id);
+        }
+    }
+
+    // Unmaps the client window at `base` unless an open file or the volume
+    // client still uses it
+    fn drop_window(&mut self, base: usize) {
+        let in_use = self.blk_shm == Some(base)
+            || self.open_files.values().any(|f| f.server_shm_base == base);
+        if base == 0 || in_use || self.maps.size_of(base).is_none() {
+            return;
+        }
+        if let Err(e) = self.maps.unmap(self.vspace, base) {
+            log!("Cannot unmap a client ring: {:?}", e);
+        }
+    }
+
+    // Completes ring lock submissions that were waiting on a released lock
+    fn complete_grants(&self, grants: Vec<Grant>) {
+        for grant in grants {
+            if let Some(file) = self.open_files.get(&grant.owner) {
+                file.complete(grant.user_data, 0);
+                file.announce();
+            }
+        }
+    }
+
+    // A notification on the service endpoint: serves the ring of the
+    // notifying badge's file, if it registered with RING_NOTIFY
+    fn doorbell(&mut self, badge: usize, bits: usize) {
+        if bits & proto::RING_DOORBELL_BITS == 0 {
+            return;
+        }
+        let (Some(blk), Some(fs)) = (self.blk_client.as_ref(), self.fs.as_mut()) else {
+            return;
+        };
+        let image = Image { blk, unpacked: self.unpacked.as_deref() };
+        let mut layers = Layers { image, overlay: fs.overlay_mut() };
+        let file = match self.open_files.get_mut(&badge) {
+            Some(file) if file.signal.is_some_and(|s| s.badge == badge) => file,
+            _ => return,
+        };
+        match file.process_iouring(&mut layers, Badge::new(badge), &mut self.locks) {
+            Ok(grants) => self.complete_grants(grants),
+            Err(e) => log!("Ring of handle {} failed: {:?}", badge, e),
+        }
+    }
 }
 
 impl<'a> SystemService for InitrdServer<'a> {
@@ -66,9 +190,8 @@ impl<'a> SystemService for InitrdServer<'a> {
         // We use VolumeClient to let Fossil allocate and manage the buffer.
         // This ensures the buffer is correctly registered with Fossil/Drivers for zero-copy.
 
-        let ring_vaddr = self.next_vaddr;
-        self.next_vaddr += 4096;
-        let shm_vaddr = self.next_vaddr;
+        let ring_vaddr = self.maps.reserve(BLOCK_RING_SIZE, PGSIZE)?;
+        let shm_vaddr = self.maps.reserve(BLOCK_SHM_SIZE, PGSIZE)?;
 
         let ring_params = RingParams {
             sq_entries: 16,
@@ -76,7 +199,7 @@ impl<'a> SystemService for InitrdServer<'a> {
             notify_ep: self.endpoint,
             recv_slot: RING_SLOT,
             vaddr: ring_vaddr,
-            size: 4096,
+            size: BLOCK_RING_SIZE,
         };
         let shm_params = ShmParams {
             frame: Frame::from(CapPtr::null()),
@@ -86,9 +209,12 @@ impl<'a> SystemService for InitrdServer<'a> {
             recv_slot: SHM_SLOT,
         };
 
+        let utcb = unsafe { UTCB::new() };
+        self.device = DeviceInfo::query(self.dev_ep, utcb);
+
         let mut blk_client =
             VolumeClient::new(self.dev_ep, self.res_client, ring_params, shm_params);
-        blk_client.connect(self.vspace, self.cspace)?;
+        blk_client.connect(self.vspace, self.slots.cspace())?;
 
         self.blk_client = Some(blk_client);
 
@@ -99,11 +225,52 @@ impl<'a> SystemService for InitrdServer<'a> {
         );
 
         // Read the Initrd header (sector 0)
-        let mut header_buf = [0u8; 4096];
-        self.blk_client.as_ref().unwrap().read_at(0, 4096, &mut header_buf)?;
+        let mut header_buf = [0u8; HEADER_SIZE];
+        let blk_client = self.blk_client.as_ref().unwrap();
+        self.io_stats
+            .run(HEADER_SIZE, || blk_client.read_at(0, HEADER_SIZE as u32, &mut header_buf))?;
         log!("Header read complete");
 
-        self.fs = Some(InitrdFS::new(header_buf));
+        // A compressed image is unpacked whole before anything looks inside
+        if let Some(codec) = decompress::sniff(&header_buf) {
+            log!("Image is {}-compressed, unpacking", codec.name());
+            let read_at = |sector, buf: &mut [u8]| {
+                self.io_stats.run(buf.len(), || blk_client.read_at(sector, buf.len() as u32, buf))
+            };
+            let image =
+                codec.decompress(&mut Source::new(&read_at), MAX_IMAGE_SIZE).map_err(|e| {
+                    log!("Cannot unpack the image: {:?}", e);
+                    e
+                })?;
+            log!("Unpacked {} bytes", image.len());
+            header_buf.copy_from_slice(image.get(..HEADER_SIZE).ok_or(Error::DeviceError)?);
+            self.unpacked = Some(image);
+        }
+
+        let found = probe::identify(&header_buf);
+        if found != FsType::Initrd {
+            log!("Declining device, it holds {:?}", found);
+            self.declined = Some(found);
+            return Err(Error::NotSupported);
+        }
+
+        if crate::fs::hash_kind(&header_buf) == crate::fs::HASH_NONE {
+            log!("Initrd header carries no hash; entry table is unverified");
+        }
+        // A v2 entry table may run on past the first block
+        let len = crate::fs::header_len(&header_buf)?;
+        let mut header = header_buf.to_vec();
+        if len > HEADER_SIZE {
+            header.resize(len, 0);
+            let rest = &mut header[HEADER_SIZE..];
+            let image = Image { blk: blk_client, unpacked: self.unpacked.as_deref() };
+            self.io_stats.run(rest.len(), || image.read(HEADER_SIZE, rest))?;
+        }
+        let fs = InitrdFS::new(&header).map_err(|e| {
+            log!("Initrd header rejected ({:?}): corrupt or unsupported image", e);
+            e
+        })?;
+        self.fs = Some(fs);
         Ok(())
     }
 
@@ -126,13 +293,27 @@ impl<'a> SystemService for InitrdServer<'a> {
                 continue;
             }
 
-            if let Err(e) = self.dispatch(&mut utcb) {
+            let badge = utcb.get_badge().bits();
+            // Notifications expect no reply
+            if let Some(bits) = ring::notification(utcb) {
+                self.doorbell(badge, bits);
+                continue;
+            }
+            errors::clear();
+            if let Err(e) = self.wire.verify(badge, utcb).and_then(|_| self.dispatch(&mut utcb)) {
                 utcb.set_msg_tag(MsgTag::err());
                 utcb.set_mr(0, e as usize);
             }
+            if self.versions.has(badge, version::FEAT_ERROR_DETAIL) {
+                errors::annotate(utcb);
+            }
+            self.wire.seal(badge, utcb);
 
             let _ = self.reply(&mut utcb);
         }
+        if let Err(e) = self.vfs_client.umount(Badge::null(), "/") {
+            log!("Cannot unmount from the VFS: {:?}", e);
+        }
         Ok(())
     }
 
@@ -146,9 +327,11 @@ impl<'a> SystemService for InitrdServer<'a> {
                     let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(0));
                     let mode = u_inner.get_mr(1) as u32;
                     let path = core::str::from_utf8(u_inner.buffer()).map_err(|_| Error::InvalidArgs)?;
+                    let blk = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let image = Image { blk, unpacked: s.unpacked.as_deref() };
 
                     if let Some(fs) = &mut s.fs {
-                        let handle = fs.open_handle(path, flags, mode)?;
+                        let handle = fs.open_handle(path, flags, mode, &image)?;
                         let badge = s.next_badge;
                         s.next_badge += 1;
                         s.open_files.insert(badge, handle);
@@ -158,49 +341,290 @@ impl<'a> SystemService for InitrdServer<'a> {
                     }
                 })
             },
+            (protocol::FS_PROTO, proto::OPENAT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let base = s.open_files.get(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    if !base.is_dir {
+                        return Err(FsError::NotDir.into());
+                    }
+                    let base = &base.path;
+                    let flags = OpenFlags::from_bits_truncate(u_inner.get_mr(0));
+                    let mode = u_inner.get_mr(1) as u32;
+                    let path = core::str::from_utf8(u_inner.buffer()).map_err(|_| Error::InvalidArgs)?;
+                    let blk = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let image = Image { blk, unpacked: s.unpacked.as_deref() };
+
+                    if let Some(fs) = &mut s.fs {
+                        let handle = fs.open_at(base, path, flags, mode, &image)?;
+                        let badge = s.next_badge;
+                        s.next_badge += 1;
+                        s.open_files.insert(badge, handle);
+                        Ok(badge)
+                    } else {
+                        Err(Error::NotInitialized)
+                    }
+                })
+            },
+            (protocol::FS_PROTO, proto::DUP) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    handle.refs += 1;
+                    Ok(())
+                })
+            },
+            // Only the overlay takes writes, so these just open and close it
+            (protocol::FS_PROTO, proto::REMOUNT_RO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    fs.overlay_mut().set_read_only(true);
+                    Ok(())
+                })
+            },
+            (protocol::FS_PROTO, proto::REMOUNT_RW) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    fs.overlay_mut().set_read_only(false);
+                    Ok(())
+                })
+            },
+            (protocol::FS_PROTO, proto::MOUNT_OPTIONS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let options = MountOptions::from_bits(u_inner.get_mr(0))?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    fs.overlay_mut().set_read_only(options.read_only);
+                    // Names in the archive match exactly
+                    u_inner.set_mr(0, options.bits() & !MNT_CASEFOLD);
+                    Ok(())
+                })
+            },
+            (protocol::FS_PROTO, proto::SET_CLOCK) => |_s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| clock::configure(badge_bits, u_inner))
+            },
+            (protocol::FS_PROTO, proto::VOLUME_INFO) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.device.encode(u_inner, s.device.tuning()))
+            },
+            (protocol::FS_PROTO, proto::VERSION) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.versions.negotiate(badge_bits, u_inner))
+            },
+            (protocol::FS_PROTO, proto::WIRE_PROTECT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| s.wire.configure(badge_bits, u_inner))
+            },
+            (protocol::FS_PROTO, proto::UNMOUNT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    s.shutdown();
+                    Ok(())
+                })
+            },
+            (PROCESS_PROTO, protocol::process::EXIT) => |s: &mut Self, _u: &mut UTCB| {
+                s.shutdown();
+                Ok(())
+            },
+            (protocol::FS_PROTO, proto::STATFS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    fs.stats().encode(u_inner);
+                    Ok(())
+                })
+            },
+            (protocol::FS_PROTO, proto::VOLUME_STATS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    s.io_stats.counts().encode(u_inner);
+                    Ok(())
+                })
+            },
             (protocol::FS_PROTO, protocol::fs::STAT_PATH) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
                     let path = core::str::from_utf8(u_inner.buffer()).map_err(|_| Error::InvalidArgs)?;
                     if let Some(fs) = &mut s.fs {
                         let stat = fs.stat(path)?;
                         unsafe { u_inner.write_obj(&stat) }.map_err(|_| Error::Unknown)?;
+                        // Attributes only change through the overlay
+                        let timeout = if fs.overlay().is_read_only() {
+                            proto::ATTR_TIMEOUT_NEVER
+                        } else {
+                            proto::ATTR_TIMEOUT_MS
+                        };
+                        u_inner.set_mr(2, timeout);
                         Ok(())
                     } else {
                         Err(Error::NotInitialized)
                     }
                 })
             },
+            (protocol::FS_PROTO, proto::CLONE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |_u_inner| {
+                    let handle = s.open_files.get(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    if handle.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    // Nothing is cached per handle, so a clone is a fresh open
+                    let clone = handle.reopen();
+                    let badge = s.next_badge;
+                    s.next_badge += 1;
+                    s.open_files.insert(badge, clone);
+                    Ok(badge)
+                })
+            },
+            // The badge names the handle, so the range starts at MR1
+            (protocol::FS_PROTO, proto::LOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let handle = s.open_files.get(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    if handle.is_dir {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    s.locks.lock(&handle.lock_key(), badge_bits, start, len, u_inner.get_mr(3))
+                })
+            },
+            (protocol::FS_PROTO, proto::UNLOCK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let handle = s.open_files.get(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    let (start, len) = (u_inner.get_mr(1) as u64, u_inner.get_mr(2) as u64);
+                    let grants = s.locks.unlock(&handle.lock_key(), badge_bits, start, len)?;
+                    s.complete_grants(grants);
+                    Ok(())
+                })
+            },
             (protocol::FS_PROTO, protocol::fs::CLOSE) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |_u_inner| {
-                    if let Some(_handle) = s.open_files.remove(&badge_bits) {
-                        Ok(())
-                    } else {
-                        Err(Error::InvalidArgs)
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    handle.refs -= 1;
+                    if handle.refs == 0 {
+                        if let Some(file) = s.open_files.remove(&badge_bits) {
+                            s.drop_window(file.server_shm_base);
+                            if let Layer::Upper(id) = file.layer {
+                                s.release_node(id);
+                            }
+                        }
+                        let grants = s.locks.release(badge_bits);
+                        s.complete_grants(grants);
                     }
+                    Ok(())
                 })
             },
             (protocol::FS_PROTO, protocol::fs::STAT) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
-                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
-                    let stat = handle.stat(badge)?;
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let handle = s.open_files.get(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    let stat = fs.layer_stat(handle.layer)?;
                     unsafe { u_inner.write_obj(&stat) }.map_err(|_| Error::Unknown)?;
                     Ok(())
                 })
             },
+            // The badge names the handle, so the count is in MR1
+            (protocol::FS_PROTO, protocol::fs::GETDENTS) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    if !handle.is_dir {
+                        return Err(FsError::NotDir.into());
+                    }
+                    let count =
+                        core::cmp::min(u_inner.get_mr(1), proto::dents_capacity(u_inner.buffer()));
+                    let entries = fs.getdents(&handle.path, handle.layer, &mut handle.pos, count);
+                    let written = proto::encode_dents(u_inner.buffer_mut(), &entries);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
+            (protocol::FS_PROTO, protocol::fs::SEEK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_ref().ok_or(Error::NotInitialized)?;
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    let offset = u_inner.get_mr(0) as i64;
+                    let pos = handle.seek(fs.overlay(), badge, offset, u_inner.get_mr(1))?;
+                    Ok(pos)
+                })
+            },
             (protocol::FS_PROTO, protocol::fs::READ_SYNC) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
-                    let blk_client = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let blk = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let image = Image { blk, unpacked: s.unpacked.as_deref() };
+                    let layers = Layers { image, overlay: fs.overlay_mut() };
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    let len = u_inner.get_mr(0);
+                    let offset = u_inner.get_mr(1) as usize;
+                    let buf = u_inner.buffer_mut();
+                    if len > buf.len() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let read_len = s
+                        .io_stats
+                        .run(len, || handle.read(&layers, badge, offset, &mut buf[..len]))?;
+                    Ok(read_len)
+                })
+            },
+            // Like READ_SYNC: MR0: length, MR1: offset, the data in the buffer
+            (protocol::FS_PROTO, protocol::fs::WRITE_SYNC) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
                     let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
                     let len = u_inner.get_mr(0);
                     let offset = u_inner.get_mr(1) as usize;
+                    let buf = u_inner.buffer();
+                    if len > buf.len() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    handle.write(fs.overlay_mut(), badge, offset, &buf[..len])
+                })
+            },
+            // The badge names the handle, so the size is in MR1
+            (protocol::FS_PROTO, protocol::fs::TRUNCATE) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    handle.truncate(fs.overlay_mut(), u_inner.get_mr(1))
+                })
+            },
+            (protocol::FS_PROTO, protocol::fs::MKDIR) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let mode = u_inner.get_mr(0) as u32;
+                    fs.mkdir(path::from_buffer(u_inner.buffer())?, mode)
+                })
+            },
+            (protocol::FS_PROTO, protocol::fs::UNLINK) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    if let Some(id) = fs.unlink(path::from_buffer(u_inner.buffer())?)? {
+                        s.release_node(id);
+                    }
+                    Ok(())
+                })
+            },
+            // The handle is the badge here, so the length moves up to MR0
+            (protocol::FS_PROTO, proto::READ_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let blk = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let image = Image { blk, unpacked: s.unpacked.as_deref() };
+                    let layers = Layers { image, overlay: fs.overlay_mut() };
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    let len = u_inner.get_mr(0);
                     let buf = u_inner.buffer_mut();
                     if len > buf.len() {
                         return Err(Error::InvalidArgs);
                     }
-                    let read_len = handle.read(blk_client, badge, offset, &mut buf[..len])?;
+                    let read_len = s.io_stats.run(len, || {
+                        handle.read(&layers, badge, CURRENT_OFFSET, &mut buf[..len])
+                    })?;
+                    u_inner.set_buffer_len(read_len);
                     Ok(read_len)
                 })
             },
+            // Writes the whole buffer at the handle's position
+            (protocol::FS_PROTO, proto::WRITE_NEXT) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    let written =
+                        handle.write(fs.overlay_mut(), badge, CURRENT_OFFSET, u_inner.buffer())?;
+                    u_inner.set_buffer_len(0);
+                    u_inner.set_mr(0, written);
+                    Ok(())
+                })
+            },
             (protocol::FS_PROTO, protocol::fs::SETUP_IOURING) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |u_inner| {
                     let blk_client = s.blk_client.as_mut().ok_or(Error::NotInitialized)?;
@@ -209,36 +633,77 @@ impl<'a> SystemService for InitrdServer<'a> {
                     let size = u_inner.get_mr(2);
 
                     let frame = if u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
-                        let slot = s.cspace.alloc(s.res_client)?;
+                        let slot = s.slots.alloc(s.res_client)?;
                         CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
                         Some(Frame::from(slot))
                     } else {
                         None
                     };
 
-                    let addr_server = s.next_vaddr;
-                    s.next_vaddr += size;
-
+                    let addr_server = s.maps.reserve(size, PGSIZE)?;
                     if let Some(f) = frame {
-                        s.vspace.map_frame(
+                        let mapped = s.vspace.map_frame(
                             f,
                             addr_server,
                             glenda::mem::Perms::READ | glenda::mem::Perms::WRITE,
                             size / 4096,
                             s.res_client,
-                            s.cspace,
-                        )?;
+                            s.slots.cspace(),
+                        );
+                        if let Err(e) = mapped {
+                            let _ = s.maps.release(addr_server);
+                            return Err(e);
+                        }
                     }
 
+                    let old = handle.server_shm_base;
                     handle.setup_iouring(blk_client, badge, addr_server, addr_user, size, frame)?;
+                    // The volume client now works in this window, so the ones
+                    // it and the handle used before may be free
+                    let old_blk = match frame {
+                        Some(_) => s.blk_shm.replace(addr_server),
+                        None => None,
+                    };
+                    s.drop_window(old);
+                    if let Some(old_blk) = old_blk {
+                        s.drop_window(old_blk);
+                    }
+                    Ok(())
+                })
+            },
+            // MR1: bits; the endpoint to notify comes with the call
+            (protocol::FS_PROTO, proto::RING_NOTIFY) => |s: &mut Self, u: &mut UTCB| {
+                handle_call(u, |u_inner| {
+                    if !u_inner.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let file = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
+                    if file.uring.is_none() {
+                        return Err(Error::InvalidArgs);
+                    }
+                    let slot = s.slots.alloc(s.res_client)?;
+                    CSPACE_CAP.move_cap(RECV_SLOT, slot)?;
+                    let signal = RingSignal {
+                        notify: Endpoint::from(slot),
+                        bits: u_inner.get_mr(1),
+                        badge: badge_bits,
+                    };
+                    if let Some(old) = file.signal.replace(signal) {
+                        CSPACE_CAP.delete(old.notify.cap())?;
+                        s.slots.free(old.notify.cap());
+                    }
                     Ok(())
                 })
             },
             (protocol::FS_PROTO, protocol::fs::PROCESS_IOURING) => |s: &mut Self, u: &mut UTCB| {
                 handle_call(u, |_u_inner| {
-                    let blk_client = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let blk = s.blk_client.as_ref().ok_or(Error::NotInitialized)?;
+                    let fs = s.fs.as_mut().ok_or(Error::NotInitialized)?;
+                    let image = Image { blk, unpacked: s.unpacked.as_deref() };
+                    let mut layers = Layers { image, overlay: fs.overlay_mut() };
                     let handle = s.open_files.get_mut(&badge_bits).ok_or(Error::InvalidArgs)?;
-                    handle.process_iouring(blk_client, badge)?;
+                    let grants = handle.process_iouring(&mut layers, badge, &mut s.locks)?;
+                    s.complete_grants(grants);
                     Ok(())
                 })
             }
@@ -0,0 +1,104 @@
+use alloc::sync::Arc;
+use fs_common::health::{IoErrorCounts, IoStats};
+use fs_common::partition::Partition;
+use glenda::cap::Endpoint;
+use glenda::client::volume::VolumeClient;
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::io::uring::RingParams;
+use glenda::mem::shm::ShmParams;
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+// Unit the volume driver reads in
+pub const DEV_BLOCK_SIZE: usize = 4096;
+
+/// Reads the volume. ISO 9660 media are never written, so unlike fatfs's
+/// reader there is no write path and no buffer cache to keep coherent.
+pub struct BlockReader {
+    client: VolumeClient,
+    // Shared with every clone, so it covers all IO on the volume
+    stats: Arc<IoStats>,
+    // Device byte range the filesystem lives in; offsets are relative to `base`
+    base: usize,
+    size: Option<usize>,
+}
+
+impl BlockReader {
+    pub fn new(
+        endpoint: Endpoint,
+        res_client: &mut ResourceClient,
+        ring_params: RingParams,
+        shm_params: ShmParams,
+    ) -> Self {
+        Self {
+            client: VolumeClient::new(endpoint, res_client, ring_params, shm_params),
+            stats: Arc::new(IoStats::new()),
+            base: 0,
+            size: None,
+        }
+    }
+
+    pub fn init(
+        &mut self,
+        vspace: &mut VSpaceManager,
+        cspace: &mut CSpaceManager,
+    ) -> Result<(), Error> {
+        self.client.connect(vspace, cspace)
+    }
+
+    /// Confines IO to `part`, or opens up the whole device again for `None`.
+    pub fn set_partition(&mut self, part: Option<&Partition>) {
+        self.base = part.map_or(0, |p| p.start as usize);
+        self.size = part.map(|p| p.len as usize);
+    }
+
+    // Device offset of `len` bytes at `offset` into the partition
+    fn locate(&self, offset: usize, len: usize) -> Result<usize, Error> {
+        let end = offset.checked_add(len).ok_or(Error::InvalidArgs)?;
+        if self.size.is_some_and(|size| end > size) {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(self.base + offset)
+    }
+
+    pub fn error_counts(&self) -> IoErrorCounts {
+        self.stats.counts()
+    }
+
+    pub fn read_offset(&self, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let block_size = DEV_BLOCK_SIZE;
+        let start_pos = self.locate(offset, buf.len())?;
+        let end_pos = start_pos + buf.len();
+
+        let start_sector = start_pos / block_size;
+        let end_sector = end_pos.div_ceil(block_size);
+        let read_size = (end_sector - start_sector) * block_size;
+
+        if start_pos % block_size == 0 && buf.len() == read_size {
+            self.stats
+                .run(buf.len(), || self.client.read_at(start_sector, buf.len() as u32, buf))?;
+        } else {
+            let mut temp_buf = alloc::vec![0u8; read_size];
+            self.stats.run(read_size, || {
+                self.client.read_at(start_sector, read_size as u32, &mut temp_buf)
+            })?;
+            let copy_start = start_pos % block_size;
+            buf.copy_from_slice(&temp_buf[copy_start..copy_start + buf.len()]);
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Clone for BlockReader {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            stats: self.stats.clone(),
+            base: self.base,
+            size: self.size,
+        }
+    }
+}
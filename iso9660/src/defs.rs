@@ -0,0 +1,216 @@
+//! On-disk structures of ISO 9660 (ECMA-119). Numbers are stored both-endian;
+//! only the little-endian half is read.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_common::bytes::{le_u16, le_u32};
+use fs_common::clock;
+use glenda::error::Error;
+
+// The first 16 sectors are the system area, left to boot code
+pub const SECTOR_SIZE: usize = 2048;
+pub const DESCRIPTOR_START: usize = 16;
+// Descriptors are scanned up to this many sectors for the terminator
+pub const MAX_DESCRIPTORS: usize = 64;
+pub const STANDARD_ID: &[u8; 5] = b"CD001";
+
+pub const VD_PRIMARY: u8 = 1;
+pub const VD_TERMINATOR: u8 = 255;
+
+pub const FLAG_HIDDEN: u8 = 0x01;
+pub const FLAG_DIRECTORY: u8 = 0x02;
+pub const FLAG_ASSOCIATED: u8 = 0x04;
+// More records of the same file follow, each with one more extent
+pub const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+// Fixed part of a directory record, before the file identifier
+pub const DIR_RECORD_HEADER: usize = 33;
+// Offset of the root directory record in the primary volume descriptor
+const PVD_ROOT_RECORD: usize = 156;
+
+/// The primary volume descriptor, as far as the driver needs it.
+#[derive(Debug, Clone)]
+pub struct PrimaryVolume {
+    pub volume_id: String,
+    pub volume_blocks: u32,
+    pub block_size: u32,
+    pub path_table_size: u32,
+    // Location of the little-endian (type L) path table
+    pub path_table_lba: u32,
+    pub root: DirRecord,
+}
+
+impl PrimaryVolume {
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < SECTOR_SIZE || buf[0] != VD_PRIMARY || &buf[1..6] != STANDARD_ID {
+            return Err(Error::InvalidArgs);
+        }
+        let block_size = le_u16(buf, 128)? as u32;
+        // The standard allows any power of two from 512 up to the sector size
+        if !block_size.is_power_of_two() || !(512..=SECTOR_SIZE as u32).contains(&block_size) {
+            return Err(Error::NotSupported);
+        }
+        let (root, _) = DirRecord::parse(&buf[PVD_ROOT_RECORD..], 0)?.ok_or(Error::InvalidArgs)?;
+        Ok(Self {
+            volume_id: d_string(&buf[40..72]),
+            volume_blocks: le_u32(buf, 80)?,
+            block_size,
+            path_table_size: le_u32(buf, 132)?,
+            path_table_lba: le_u32(buf, 140)?,
+            root,
+        })
+    }
+}
+
+/// One directory record, with the extents of every record of a multi-extent
+/// file once those are joined.
+#[derive(Debug, Clone)]
+pub struct DirRecord {
+    // (logical block, bytes)
+    pub extents: Vec<(u32, u32)>,
+    pub size: u64,
+    pub flags: u8,
+    // Seconds since the epoch, UTC
+    pub recorded: i64,
+    // File identifier as stored: `\0` for the directory itself, `\x01` for its
+    // parent, `NAME.EXT;1` otherwise
+    pub ident: Vec<u8>,
+    pub system_use: Vec<u8>,
+    // Byte offset of the record on the volume
+    pub location: u64,
+}
+
+impl DirRecord {
+    /// Parses the record at the start of `buf`, found at byte `location` of
+    /// the volume. Returns it and its length, or None for the zero padding
+    /// that ends the records of a sector.
+    pub fn parse(buf: &[u8], location: u64) -> Result<Option<(Self, usize)>, Error> {
+        let len = match buf.first() {
+            None | Some(0) => return Ok(None),
+            Some(&len) => len as usize,
+        };
+        if len < DIR_RECORD_HEADER || len > buf.len() {
+            return Err(Error::InvalidArgs);
+        }
+        let ident_len = buf[32] as usize;
+        let ident_end = DIR_RECORD_HEADER + ident_len;
+        if ident_end > len {
+            return Err(Error::InvalidArgs);
+        }
+        // The identifier is padded to an even length
+        let su_start = ident_end + (ident_len + 1) % 2;
+        let extent = (le_u32(buf, 2)?, le_u32(buf, 10)?);
+        let record = Self {
+            extents: alloc::vec![extent],
+            size: extent.1 as u64,
+            flags: buf[25],
+            recorded: recorded_time(&buf[18..25]),
+            ident: buf[DIR_RECORD_HEADER..ident_end].to_vec(),
+            system_use: buf.get(su_start..len).unwrap_or(&[]).to_vec(),
+            location,
+        };
+        Ok(Some((record, len)))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// The directory itself or its parent, rather than an entry in it.
+    pub fn is_dot(&self) -> bool {
+        self.ident == [0] || self.ident == [1]
+    }
+
+    /// Appends the extent of the next record of a multi-extent file.
+    pub fn join(&mut self, next: &DirRecord) {
+        self.extents.extend_from_slice(&next.extents);
+        self.size += next.size;
+        self.flags = next.flags;
+    }
+
+    pub fn first_block(&self) -> u32 {
+        self.extents[0].0
+    }
+}
+
+/// One entry of the path table: a directory, its first block and the index
+/// of its parent's entry, counted from 1 with the root its own parent.
+#[derive(Debug, Clone)]
+pub struct PathTableEntry {
+    pub extent: u32,
+    pub parent: u16,
+    pub ident: Vec<u8>,
+}
+
+/// Parses a little-endian path table.
+pub fn parse_path_table(buf: &[u8]) -> Result<Vec<PathTableEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= buf.len() {
+        let ident_len = buf[pos] as usize;
+        if ident_len == 0 {
+            break;
+        }
+        let ident = buf.get(pos + 8..pos + 8 + ident_len).ok_or(Error::InvalidArgs)?;
+        entries.push(PathTableEntry {
+            extent: le_u32(buf, pos + 2)?,
+            parent: le_u16(buf, pos + 6)?,
+            ident: ident.to_vec(),
+        });
+        pos += 8 + ident_len + ident_len % 2;
+    }
+    Ok(entries)
+}
+
+/// A file identifier as clients see it without extensions: the `;1` version
+/// and a bare trailing dot dropped, in lower case.
+pub fn plain_name(ident: &[u8]) -> String {
+    let ident = match ident.iter().position(|&b| b == b';') {
+        Some(end) => &ident[..end],
+        None => ident,
+    };
+    let ident = ident.strip_suffix(b".").unwrap_or(ident);
+    ident.iter().map(|&b| (b as char).to_ascii_lowercase()).collect()
+}
+
+// A d-character field, space padded
+fn d_string(field: &[u8]) -> String {
+    let text: String = field.iter().map(|&b| b as char).collect();
+    String::from(text.trim_end())
+}
+
+/// The 7-byte recording date of a directory record: years since 1900,
+/// month, day, hour, minute, second and the offset from UTC in 15 minutes.
+pub fn recorded_time(field: &[u8]) -> i64 {
+    if field.len() < 7 || field[1] == 0 {
+        return 0;
+    }
+    let secs = clock::unix_seconds(
+        1900 + field[0] as i64,
+        field[1] as u32,
+        field[2] as u32,
+        field[3] as u32,
+        field[4] as u32,
+        field[5] as u32,
+    );
+    secs - field[6] as i8 as i64 * 15 * 60
+}
+
+/// The 17-byte date of a volume descriptor and of Rock Ridge's long-form
+/// TF entries: sixteen ASCII digits, YYYYMMDDhhmmsscc, then the UTC offset.
+pub fn long_time(field: &[u8]) -> i64 {
+    let digits = |range: core::ops::Range<usize>| -> Option<u32> {
+        let text = core::str::from_utf8(field.get(range)?).ok()?;
+        text.parse().ok()
+    };
+    let parts = (digits(0..4), digits(4..6), digits(6..8), digits(8..10), digits(10..12));
+    let (Some(year), Some(month), Some(day), Some(hour), Some(min)) = parts else {
+        return 0;
+    };
+    if month == 0 || field.len() < 17 {
+        return 0;
+    }
+    let sec = digits(12..14).unwrap_or(0);
+    let secs = clock::unix_seconds(year as i64, month, day, hour, min, sec);
+    secs - field[16] as i8 as i64 * 15 * 60
+}
@@ -0,0 +1,486 @@
+use crate::block::BlockReader;
+use crate::defs::{self, DirRecord, PathTableEntry, PrimaryVolume};
+use crate::layout::{NOTIFY_SLOT, RECV_BUFFER_SLOT, RECV_RING_SLOT};
+use crate::ops::{Attributes, IsoOps, Plain, RockRidge};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use fs_common::errors::FsError;
+use fs_common::handle::FsHandle;
+use fs_common::limits::{self, ISO9660_MAX_FILE_SIZE};
+use fs_common::partition::{self, PartitionSelect};
+use fs_common::proto::{self, CURRENT_OFFSET, DT_DIR, DT_REG};
+use fs_common::statfs::FsStats;
+use glenda::cap::{Endpoint, Frame};
+use glenda::client::ResourceClient;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::interface::ResourceService;
+use glenda::io::uring::RingParams;
+use glenda::ipc::Badge;
+use glenda::mem::shm::ShmParams;
+use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+
+// Largest path table loaded at mount; a bigger one is ignored and
+// directories are found by reading their parents instead
+const MAX_PATH_TABLE: usize = 1 << 20;
+// Largest directory read in full
+const MAX_DIR_SIZE: u64 = 16 << 20;
+
+// What handles need of the volume
+struct Volume {
+    reader: BlockReader,
+    ops: Box<dyn IsoOps>,
+    block_size: usize,
+}
+
+impl Volume {
+    fn block_offset(&self, block: u32) -> usize {
+        block as usize * self.block_size
+    }
+
+    // Reads `buf.len()` bytes at `offset` of the file made of `extents`,
+    // stopping at its end
+    fn read_extents(
+        &self,
+        extents: &[(u32, u32)],
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut done = 0;
+        let mut start = 0;
+        for &(block, len) in extents {
+            let len = len as usize;
+            let pos = offset + done;
+            if done == buf.len() {
+                break;
+            }
+            if pos < start + len {
+                let within = pos - start;
+                let n = (len - within).min(buf.len() - done);
+                let at = self.block_offset(block) + within;
+                self.reader.read_offset(at, &mut buf[done..done + n])?;
+                done += n;
+            }
+            start += len;
+        }
+        Ok(done)
+    }
+
+    // The records in directory `dir`, `.` and `..` left out and multi-extent
+    // files joined into one record
+    fn records(&self, dir: &DirRecord) -> Result<Vec<DirRecord>, Error> {
+        if dir.size > MAX_DIR_SIZE {
+            return Err(Error::OutOfMemory);
+        }
+        let mut data = alloc::vec![0u8; dir.size as usize];
+        let read = self.read_extents(&dir.extents, 0, &mut data)?;
+        data.truncate(read);
+
+        let base = self.block_offset(dir.first_block()) as u64;
+        let mut records: Vec<DirRecord> = Vec::new();
+        let mut pending: Option<DirRecord> = None;
+        let mut pos = 0;
+        while pos < data.len() {
+            // Records never cross a sector; zeros pad out the rest of one
+            let Some((record, len)) = DirRecord::parse(&data[pos..], base + pos as u64)? else {
+                pos = (pos / defs::SECTOR_SIZE + 1) * defs::SECTOR_SIZE;
+                continue;
+            };
+            pos += len;
+            if record.is_dot() || record.flags & defs::FLAG_ASSOCIATED != 0 {
+                continue;
+            }
+            let record = match pending.take() {
+                Some(mut first) => {
+                    first.join(&record);
+                    first
+                }
+                None => record,
+            };
+            if record.flags & defs::FLAG_MULTI_EXTENT != 0 {
+                pending = Some(record);
+            } else {
+                records.push(record);
+            }
+        }
+        records.extend(pending);
+        Ok(records)
+    }
+
+    // The `.` record of the directory starting at `block`, which carries the
+    // directory's own extent and attributes
+    fn dot(&self, block: u32) -> Result<DirRecord, Error> {
+        let mut buf = alloc::vec![0u8; 255];
+        let at = self.block_offset(block);
+        self.reader.read_offset(at, &mut buf)?;
+        let (dot, _) = DirRecord::parse(&buf, at as u64)?.ok_or(Error::InvalidArgs)?;
+        if !dot.is_dir() || dot.first_block() != block {
+            return Err(Error::InvalidArgs);
+        }
+        Ok(dot)
+    }
+}
+
+/// A directory record and what clients see of it.
+#[derive(Clone)]
+pub struct Node {
+    pub record: DirRecord,
+    pub attrs: Attributes,
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        self.record.is_dir()
+    }
+
+    // Directories are numbered by their own `.` record, which is the same
+    // whichever way they were reached; files by their record
+    fn ino(&self) -> usize {
+        if self.is_dir() {
+            self.record.first_block() as usize
+        } else {
+            self.record.location as usize
+        }
+    }
+
+    pub fn stat(&self, block_size: usize) -> Stat {
+        Stat {
+            ino: self.ino(),
+            mode: self.attrs.mode,
+            nlink: self.attrs.nlink,
+            uid: self.attrs.uid,
+            gid: self.attrs.gid,
+            size: self.record.size as usize,
+            blksize: block_size as u32,
+            blocks: (self.record.size as usize).div_ceil(512),
+            atime: self.attrs.atime.max(0) as usize,
+            mtime: self.attrs.mtime.max(0) as usize,
+            ctime: self.attrs.ctime.max(0) as usize,
+            ..Default::default()
+        }
+    }
+}
+
+pub struct IsoFs {
+    volume: Arc<Volume>,
+    primary: PrimaryVolume,
+    // Directories in path table order; empty when the table is not used
+    path_table: Vec<PathTableEntry>,
+}
+
+impl IsoFs {
+    /// Connects to the block device and narrows IO to the selected
+    /// partition. The volume is not looked at beyond the partition table, so
+    /// the caller can probe it before committing to `mount`.
+    pub fn open_reader(
+        block_device: Endpoint,
+        ring_vaddr: usize,
+        ring_size: usize,
+        res_client: &mut ResourceClient,
+        vspace: &mut VSpaceManager,
+        cspace: &mut CSpaceManager,
+        select: PartitionSelect,
+    ) -> Result<BlockReader, Error> {
+        res_client.alloc(Badge::null(), glenda::cap::CapType::Endpoint, 0, NOTIFY_SLOT)?;
+        let ring_params = RingParams {
+            sq_entries: 4,
+            cq_entries: 4,
+            vaddr: ring_vaddr,
+            size: ring_size,
+            notify_ep: Endpoint::from(NOTIFY_SLOT),
+            recv_slot: RECV_RING_SLOT,
+        };
+        let shm_params = ShmParams {
+            frame: Frame::from(glenda::cap::CapPtr::null()),
+            vaddr: 0,
+            size: 0,
+            paddr: 0,
+            recv_slot: RECV_BUFFER_SLOT,
+        };
+
+        let mut reader = BlockReader::new(block_device, res_client, ring_params, shm_params);
+        reader.init(vspace, cspace)?;
+        let part = partition::select(
+            |offset, buf| reader.read_offset(offset as usize, buf).map(|_| ()),
+            select,
+            // Hybrid images carry their tables in 512-byte LBAs
+            partition::SECTOR_SIZE,
+        )?;
+        if let Some(part) = &part {
+            glenda::log!(
+                "ISO9660: partition {} at {:#x}, {} bytes",
+                part.index,
+                part.start,
+                part.len
+            );
+        }
+        reader.set_partition(part.as_ref());
+        Ok(reader)
+    }
+
+    /// Reads the volume descriptors and the path table. Supplementary
+    /// descriptors (Joliet) are passed over: Rock Ridge, where present,
+    /// already gives the long names.
+    pub fn mount(reader: BlockReader) -> Result<Self, Error> {
+        let primary = Self::find_primary(&reader)?;
+        let block_size = primary.block_size as usize;
+        let plain = Volume { reader, ops: Box::new(Plain), block_size };
+        let root_dot = plain.dot(primary.root.first_block())?;
+        let ops: Box<dyn IsoOps> = match RockRidge::detect(&root_dot, primary.block_size) {
+            Some(rock_ridge) => Box::new(rock_ridge),
+            None => Box::new(Plain),
+        };
+        let volume = Volume { reader: plain.reader, ops, block_size };
+
+        let mut path_table = Vec::new();
+        let table_size = primary.path_table_size as usize;
+        if volume.ops.path_table_names() && table_size <= MAX_PATH_TABLE {
+            let mut buf = alloc::vec![0u8; table_size];
+            volume.reader.read_offset(volume.block_offset(primary.path_table_lba), &mut buf)?;
+            path_table = defs::parse_path_table(&buf)?;
+        }
+        glenda::log!(
+            "ISO9660: volume '{}', {} blocks of {} bytes, {}, {} directories in the path table",
+            primary.volume_id,
+            primary.volume_blocks,
+            block_size,
+            if volume.ops.path_table_names() { "plain names" } else { "Rock Ridge" },
+            path_table.len()
+        );
+        Ok(Self { volume: Arc::new(volume), primary, path_table })
+    }
+
+    fn find_primary(reader: &BlockReader) -> Result<PrimaryVolume, Error> {
+        let mut buf = alloc::vec![0u8; defs::SECTOR_SIZE];
+        for sector in defs::DESCRIPTOR_START..defs::DESCRIPTOR_START + defs::MAX_DESCRIPTORS {
+            reader.read_offset(sector * defs::SECTOR_SIZE, &mut buf)?;
+            if &buf[1..6] != defs::STANDARD_ID {
+                break;
+            }
+            match buf[0] {
+                defs::VD_PRIMARY => return PrimaryVolume::parse(&buf),
+                defs::VD_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn error_counts(&self) -> fs_common::health::IoErrorCounts {
+        self.volume.reader.error_counts()
+    }
+
+    pub fn stats(&self) -> FsStats {
+        FsStats::full(self.volume.block_size, self.primary.volume_blocks as u64, 0)
+    }
+
+    fn node(&self, record: DirRecord) -> Result<Node, Error> {
+        let attrs = self.volume.ops.attributes(&self.volume.reader, &record)?;
+        Ok(Node { record, attrs })
+    }
+
+    fn root(&self) -> Result<Node, Error> {
+        let dot = self.volume.dot(self.primary.root.first_block())?;
+        let mut node = self.node(dot)?;
+        node.attrs.name = String::from("/");
+        Ok(node)
+    }
+
+    /// The directory at `path` by the path table: the deepest directory
+    /// it names and the components left over.
+    fn walk_path_table<'p>(&self, path: &'p str) -> Option<(u32, Vec<&'p str>)> {
+        let root = self.path_table.first()?;
+        let (mut extent, mut index) = (root.extent, 1usize);
+        let mut parts = path.split('/').filter(|p| !p.is_empty()).peekable();
+        while let Some(&part) = parts.peek() {
+            let found = self.path_table.iter().enumerate().skip(index).find(|(_, e)| {
+                e.parent as usize == index
+                    && self.volume.ops.names_match(&defs::plain_name(&e.ident), part)
+            });
+            let Some((i, entry)) = found else {
+                break;
+            };
+            extent = entry.extent;
+            index = i + 1;
+            parts.next();
+        }
+        Some((extent, parts.collect()))
+    }
+
+    /// The file or directory at absolute `path`.
+    pub fn lookup(&self, path: &str) -> Result<Node, Error> {
+        let mut node = self.root()?;
+        let mut rest: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+        if let Some((extent, left)) = self.walk_path_table(path) {
+            if left.len() < rest.len() {
+                let dot = self.volume.dot(extent)?;
+                let name = rest[rest.len() - left.len() - 1];
+                node = self.node(dot)?;
+                node.attrs.name = String::from(name);
+                rest = left;
+            }
+        }
+        for part in rest {
+            if part == "." {
+                continue;
+            }
+            if part == ".." {
+                return Err(Error::InvalidArgs);
+            }
+            if !node.is_dir() {
+                return Err(FsError::NotDir.into());
+            }
+            node = self.find(&node.record, part)?;
+        }
+        Ok(node)
+    }
+
+    // Entry `name` in directory `dir`
+    fn find(&self, dir: &DirRecord, name: &str) -> Result<Node, Error> {
+        for record in self.volume.records(dir)? {
+            if record.flags & defs::FLAG_HIDDEN != 0 && self.volume.ops.path_table_names() {
+                continue;
+            }
+            let node = self.node(record)?;
+            if self.volume.ops.names_match(&node.attrs.name, name) {
+                return Ok(node);
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn stat_path(&self, path: &str) -> Result<Stat, Error> {
+        Ok(self.lookup(path)?.stat(self.volume.block_size))
+    }
+
+    pub fn open_handle(&self, path: &str, flags: OpenFlags) -> Result<Box<dyn FsHandle>, Error> {
+        if proto::open_mutates(flags) {
+            return Err(FsError::ReadOnly.into());
+        }
+        let node = self.lookup(path)?;
+        let stat = node.stat(self.volume.block_size);
+        if node.is_dir() {
+            let mut entries = Vec::new();
+            for (off, record) in self.volume.records(&node.record)?.into_iter().enumerate() {
+                let child = self.node(record)?;
+                let type_ = if child.is_dir() { DT_DIR } else { DT_REG };
+                entries.push(proto::dentry(child.ino(), off, type_, child.attrs.name.as_bytes()));
+            }
+            return Ok(Box::new(IsoDir { stat, entries, pos: 0 }));
+        }
+        if flags.contains(OpenFlags::O_DIRECTORY) {
+            return Err(FsError::NotDir.into());
+        }
+        Ok(Box::new(IsoFile { volume: self.volume.clone(), node, stat, pos: 0 }))
+    }
+}
+
+pub struct IsoFile {
+    volume: Arc<Volume>,
+    node: Node,
+    stat: Stat,
+    pos: usize,
+}
+
+impl FileHandleService for IsoFile {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        Ok(self.stat)
+    }
+
+    fn read(&mut self, _badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        let len = limits::read_len(offset, buf.len(), self.node.record.size as usize);
+        let read = self.volume.read_extents(&self.node.record.extents, offset, &mut buf[..len])?;
+        if advance {
+            self.pos = offset + read;
+        }
+        Ok(read)
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(FsError::ReadOnly.into())
+    }
+
+    fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
+        Err(FsError::NotDir.into())
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let size = self.node.record.size as usize;
+        self.pos = limits::seek_target(self.pos, size, offset, whence, ISO9660_MAX_FILE_SIZE)?;
+        Ok(self.pos)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(FsError::ReadOnly.into())
+    }
+}
+
+impl FsHandle for IsoFile {
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        let volume = self.volume.clone();
+        Ok(Box::new(IsoFile { volume, node: self.node.clone(), stat: self.stat, pos: 0 }))
+    }
+}
+
+/// A directory listing, read whole when the directory is opened.
+pub struct IsoDir {
+    stat: Stat,
+    entries: Vec<DEntry>,
+    // Entries returned so far
+    pos: usize,
+}
+
+impl FileHandleService for IsoDir {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        Ok(self.stat)
+    }
+
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        let end = self.entries.len().min(self.pos.saturating_add(count));
+        let start = self.pos.min(end);
+        self.pos = end;
+        Ok(self.entries[start..end].to_vec())
+    }
+
+    /// Positions count entries; SEEK_SET 0 starts the listing over.
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let len = self.entries.len();
+        self.pos = limits::seek_target(self.pos, len, offset, whence, len as u64)?;
+        Ok(self.pos)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(FsError::IsDir.into())
+    }
+}
+
+impl FsHandle for IsoDir {}
@@ -0,0 +1,26 @@
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::{CapPtr, Endpoint};
+pub const DEVICE_SLOT: CapPtr = CapPtr::from(10);
+pub const VOLUME_SLOT: CapPtr = CapPtr::from(11);
+
+pub const NOTIFY_SLOT: CapPtr = CapPtr::from(13);
+pub const RECV_RING_SLOT: CapPtr = CapPtr::from(14);
+pub const RECV_BUFFER_SLOT: CapPtr = CapPtr::from(15);
+pub const VFS_SLOT: CapPtr = CapPtr::from(9);
+
+// Slots the CSpaceManager hands out stay below this
+pub const DYNAMIC_SLOT_LIMIT: CapPtr = CapPtr::from(0x100);
+// Where caps sent along with a call arrive
+pub const RECV_SLOT: CapPtr = CapPtr::from(0x100);
+
+pub const VOLUME_CAP: Endpoint = Endpoint::from(VOLUME_SLOT);
+
+pub const RING_SIZE: usize = PGSIZE;
+
+// The block device ring and client rings from SETUP_IOURING are mapped between
+// these; the VSpaceManager has the range above to itself
+pub const MAP_START: usize = 0x4000_0000;
+pub const MAP_END: usize = 0x7000_0000;
+
+// Where the volume appears in the VFS namespace until MOUNT_AT moves it
+pub const MOUNT_PATH: &str = "/mnt/cdrom";
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+use fs_common::mount::MountPoint;
+use fs_common::partition::PartitionSelect;
+use fs_server::ServerLayout;
+use glenda::cap::{CapPtr, CapType, Endpoint, ENDPOINT_CAP, ENDPOINT_SLOT, REPLY_CAP};
+use glenda::client::FsClient;
+use glenda::interface::system::SystemService;
+use glenda::interface::ResourceService;
+use glenda::ipc::Badge;
+use glenda::protocol::resource::{ResourceType, FS_ENDPOINT, VOLUME_ENDPOINT};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use layout::{
+    DEVICE_SLOT, DYNAMIC_SLOT_LIMIT, MAP_END, MAP_START, MOUNT_PATH, RECV_SLOT, VFS_SLOT,
+    VOLUME_CAP, VOLUME_SLOT,
+};
+use server::IsoVolume;
+
+mod block;
+mod defs;
+mod fs;
+mod layout;
+mod ops;
+mod server;
+
+pub use server::IsoFsService;
+
+#[unsafe(no_mangle)]
+fn main() -> usize {
+    glenda::console::init_logging("ISO9660");
+
+    let mut res_client = glenda::client::ResourceClient::new(glenda::cap::MONITOR_CAP);
+    let mut cspace = CSpaceManager::new(glenda::cap::CSPACE_CAP, 16);
+    let mut vspace = VSpaceManager::new(glenda::cap::VSPACE_CAP, 0x7000_0000, 0x8000_0000);
+
+    res_client
+        .get_cap(Badge::null(), ResourceType::Endpoint, VOLUME_ENDPOINT, VOLUME_SLOT)
+        .expect("ISO9660: Failed to get volume endpoint");
+
+    let vol_client = glenda::client::VolumeClient::new_simple(VOLUME_CAP, &res_client);
+    let block_device = vol_client
+        .get_device(Badge::null(), DEVICE_SLOT)
+        .expect("ISO9660: Failed to get block device");
+
+    res_client
+        .alloc(Badge::null(), CapType::Endpoint, 0, ENDPOINT_SLOT)
+        .expect("ISO9660: Failed to allocate endpoint");
+    // Without a VFS the volume is only reachable through its endpoint
+    let vfs = res_client.get_cap(Badge::null(), ResourceType::Endpoint, FS_ENDPOINT, VFS_SLOT);
+
+    let layout = ServerLayout {
+        dynamic_slot_limit: DYNAMIC_SLOT_LIMIT,
+        recv_slot: RECV_SLOT,
+        map_start: MAP_START,
+        map_end: MAP_END,
+    };
+    let volume = IsoVolume::new();
+    let mut service = IsoFsService::new(volume, layout, &mut res_client, &mut cspace, &mut vspace);
+    service
+        .listen(ENDPOINT_CAP, REPLY_CAP.cap(), CapPtr::null())
+        .expect("ISO9660: Failed to listen");
+    match vfs {
+        Ok(cap) => {
+            let vfs = FsClient::new(Endpoint::from(cap));
+            service.set_mount_point(MountPoint::new(vfs, MOUNT_PATH));
+        }
+        Err(e) => glenda::log!("ISO9660: no VFS endpoint ({:?}), not mounting", e),
+    }
+    if let Err(e) = server::init_fs(&mut service, block_device, PartitionSelect::Auto) {
+        // Not our format: tell the supervisor so it can try the next service
+        if let Some(found) = service.backend().declined() {
+            return found.declined_exit();
+        }
+        panic!("Failed to init ISO9660: {:?}", e);
+    }
+
+    service.run().expect("ISO9660 service crashed");
+    0
+}
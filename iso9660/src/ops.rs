@@ -0,0 +1,209 @@
+use crate::block::BlockReader;
+use crate::defs::{self, DirRecord};
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_common::bytes::le_u32;
+use fs_common::casefold;
+use glenda::error::Error;
+
+// Modes for files without Rock Ridge: everything readable, nothing writable
+const PLAIN_DIR_MODE: u32 = 0o040555;
+const PLAIN_FILE_MODE: u32 = 0o100444;
+
+// Continuation areas followed per record, against loops on damaged media
+const MAX_CONTINUATIONS: usize = 16;
+// Largest continuation area read
+const MAX_CONTINUATION_LEN: usize = defs::SECTOR_SIZE;
+
+// TF entry flags: which times follow, and in which form
+const TF_CREATION: u8 = 0x01;
+const TF_MODIFY: u8 = 0x02;
+const TF_ACCESS: u8 = 0x04;
+const TF_ATTRIBUTES: u8 = 0x08;
+const TF_LONG_FORM: u8 = 0x80;
+// NM entry flags; a name split over several entries is simply joined
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+
+/// What clients see of a directory record.
+#[derive(Debug, Clone)]
+pub struct Attributes {
+    pub name: String,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+}
+
+pub trait IsoOps: Send + Sync {
+    /// Name and attributes of `record`, read from the extensions it carries.
+    fn attributes(&self, reader: &BlockReader, record: &DirRecord) -> Result<Attributes, Error>;
+    /// Whether stored name `stored` is `name`.
+    fn names_match(&self, stored: &str, name: &str) -> bool;
+    /// Whether the path table holds the names clients look up, so directories
+    /// can be found without reading their parents.
+    fn path_table_names(&self) -> bool {
+        false
+    }
+}
+
+/// Plain ISO 9660: upper-case 8.3-style names, matched without regard to
+/// case, and fixed read-only modes.
+pub struct Plain;
+
+impl IsoOps for Plain {
+    fn attributes(&self, _reader: &BlockReader, record: &DirRecord) -> Result<Attributes, Error> {
+        Ok(plain_attributes(record))
+    }
+
+    fn names_match(&self, stored: &str, name: &str) -> bool {
+        casefold::eq(stored, name)
+    }
+
+    fn path_table_names(&self) -> bool {
+        true
+    }
+}
+
+fn plain_attributes(record: &DirRecord) -> Attributes {
+    let is_dir = record.is_dir();
+    Attributes {
+        name: defs::plain_name(&record.ident),
+        mode: if is_dir { PLAIN_DIR_MODE } else { PLAIN_FILE_MODE },
+        nlink: if is_dir { 2 } else { 1 },
+        uid: 0,
+        gid: 0,
+        atime: record.recorded,
+        mtime: record.recorded,
+        ctime: record.recorded,
+    }
+}
+
+/// Rock Ridge: POSIX names, modes, owners and times kept in SUSP entries in
+/// the system use area of each record, which may continue elsewhere on the
+/// volume. Names are matched exactly.
+pub struct RockRidge {
+    // Bytes at the start of each system use area the SP entry says to skip
+    skip: usize,
+    // Logical block size, the unit of continuation area locations
+    block_size: usize,
+}
+
+impl RockRidge {
+    /// Rock Ridge if the `.` record of the root directory starts its system
+    /// use area with an SP entry.
+    pub fn detect(root_dot: &DirRecord, block_size: u32) -> Option<Self> {
+        let su = &root_dot.system_use;
+        let sp = su.len() >= 7 && &su[0..2] == b"SP" && su[4] == 0xBE && su[5] == 0xEF;
+        sp.then(|| Self { skip: su[6] as usize, block_size: block_size as usize })
+    }
+
+    // Applies one SUSP entry to `attrs`; returns a continuation area to read
+    fn apply(
+        &self,
+        entry: &[u8],
+        attrs: &mut Attributes,
+        name: &mut Option<String>,
+    ) -> Option<(u32, u32, u32)> {
+        let body = &entry[4..];
+        match &entry[0..2] {
+            b"PX" if body.len() >= 32 => {
+                attrs.mode = le_u32(body, 0).unwrap_or(attrs.mode);
+                attrs.nlink = le_u32(body, 8).unwrap_or(attrs.nlink);
+                attrs.uid = le_u32(body, 16).unwrap_or(0);
+                attrs.gid = le_u32(body, 24).unwrap_or(0);
+            }
+            b"NM" if !body.is_empty() => {
+                let flags = body[0];
+                if flags & (NM_CURRENT | NM_PARENT) == 0 {
+                    let part = String::from_utf8_lossy(&body[1..]);
+                    name.get_or_insert_with(String::new).push_str(&part);
+                }
+            }
+            b"TF" if !body.is_empty() => apply_times(body, attrs),
+            b"CE" if body.len() >= 24 => {
+                let block = le_u32(body, 0).ok()?;
+                let offset = le_u32(body, 8).ok()?;
+                let len = le_u32(body, 16).ok()?;
+                return Some((block, offset, len));
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+impl IsoOps for RockRidge {
+    fn attributes(&self, reader: &BlockReader, record: &DirRecord) -> Result<Attributes, Error> {
+        let mut attrs = plain_attributes(record);
+        let mut name = None;
+        let mut area: Vec<u8> = record.system_use.get(self.skip..).unwrap_or(&[]).to_vec();
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut next = None;
+            for entry in susp_entries(&area) {
+                if &entry[0..2] == b"ST" {
+                    break;
+                }
+                if let Some(ce) = self.apply(entry, &mut attrs, &mut name) {
+                    next = Some(ce);
+                }
+            }
+            let Some((block, offset, len)) = next else {
+                break;
+            };
+            let len = (len as usize).min(MAX_CONTINUATION_LEN);
+            let at = block as usize * self.block_size + offset as usize;
+            area = alloc::vec![0u8; len];
+            reader.read_offset(at, &mut area)?;
+        }
+        if let Some(name) = name {
+            attrs.name = name;
+        }
+        Ok(attrs)
+    }
+
+    fn names_match(&self, stored: &str, name: &str) -> bool {
+        stored == name
+    }
+}
+
+// The entries of a system use area: two signature bytes, a length and a
+// version, each at least 4 bytes long
+fn susp_entries(area: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut pos = 0;
+    core::iter::from_fn(move || {
+        let len = *area.get(pos + 2)? as usize;
+        if len < 4 || pos + len > area.len() {
+            return None;
+        }
+        let entry = &area[pos..pos + len];
+        pos += len;
+        Some(entry)
+    })
+}
+
+// TF: a flags byte, then each flagged time in the order of the flag bits
+fn apply_times(body: &[u8], attrs: &mut Attributes) {
+    let flags = body[0];
+    let width = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+    let mut pos = 1;
+    for bit in [TF_CREATION, TF_MODIFY, TF_ACCESS, TF_ATTRIBUTES] {
+        if flags & bit == 0 {
+            continue;
+        }
+        let Some(field) = body.get(pos..pos + width) else {
+            return;
+        };
+        let time = if width == 17 { defs::long_time(field) } else { defs::recorded_time(field) };
+        match bit {
+            TF_MODIFY => attrs.mtime = time,
+            TF_ACCESS => attrs.atime = time,
+            TF_ATTRIBUTES => attrs.ctime = time,
+            _ => {}
+        }
+        pos += width;
+    }
+}
@@ -0,0 +1,154 @@
+use crate::fs::IsoFs;
+use crate::layout::RING_SIZE;
+use alloc::boxed::Box;
+use fs_common::device::DeviceInfo;
+use fs_common::handle::FsHandle;
+use fs_common::mount::MountOptions;
+use fs_common::partition::PartitionSelect;
+use fs_common::probe::{self, FsType};
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use fs_common::version;
+use fs_server::{CallContext, FileSystemBackend, FsServer};
+use glenda::arch::mem::PGSIZE;
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::server::handle_call;
+use glenda::ipc::{Badge, UTCB};
+use glenda::protocol::fs::{OpenFlags, Stat};
+use glenda::protocol::FS_PROTO;
+
+/// The ISO9660 service: the medium behind the shared server loop.
+pub type IsoFsService<'a> = FsServer<'a, IsoVolume>;
+
+/// The medium, once `init_fs` has mounted it.
+pub struct IsoVolume {
+    fs: Option<IsoFs>,
+    device: DeviceInfo,
+    // What the volume held when init_fs declined it
+    declined: Option<FsType>,
+}
+
+impl IsoVolume {
+    pub fn new() -> Self {
+        Self { fs: None, device: DeviceInfo::unknown(), declined: None }
+    }
+
+    /// Set when init_fs failed because the volume is not this service's
+    /// format, so the caller can hand it to another service.
+    pub fn declined(&self) -> Option<FsType> {
+        self.declined
+    }
+
+    fn fs(&self) -> Result<&IsoFs, Error> {
+        self.fs.as_ref().ok_or(Error::NotInitialized)
+    }
+}
+
+impl Default for IsoVolume {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Mounts the medium behind `block_device` as the backend of `service`.
+pub fn init_fs(
+    service: &mut IsoFsService,
+    block_device: Endpoint,
+    partition: PartitionSelect,
+) -> Result<(), Error> {
+    let utcb = unsafe { UTCB::new() };
+    let device = DeviceInfo::query(block_device, utcb);
+    // Left reserved if the mount fails, as the ring may be mapped already
+    let ring_vaddr = service.maps.reserve(RING_SIZE, PGSIZE)?;
+    let reader = IsoFs::open_reader(
+        block_device,
+        ring_vaddr,
+        RING_SIZE,
+        service.res_client,
+        service.vspace,
+        service.slots.cspace(),
+        partition,
+    )?;
+    let volume = service.backend_mut();
+    volume.device = device;
+    let found = probe::probe(|offset, buf| reader.read_offset(offset as usize, buf).map(|_| ()))?;
+    if found != FsType::Iso9660 {
+        glenda::log!("ISO9660: declining volume, it holds {:?}", found);
+        volume.declined = Some(found);
+        return Err(Error::NotSupported);
+    }
+    volume.fs = Some(IsoFs::mount(reader)?);
+    Ok(())
+}
+
+impl FileSystemBackend for IsoVolume {
+    const NAME: &'static str = "ISO9660";
+    // A read-only medium: nothing to link, stamp or run in the background
+    const FEATURES: usize = version::FEAT_RING_NOTIFY
+        | version::FEAT_IOVEC
+        | version::FEAT_LOCKS
+        | version::FEAT_CLONE
+        | version::FEAT_WIRE
+        | version::FEAT_STATFS
+        | version::FEAT_NEXT
+        | version::FEAT_STREAM
+        | version::FEAT_ERROR_DETAIL;
+
+    fn open(
+        &mut self,
+        _badge: Badge,
+        path: &str,
+        flags: OpenFlags,
+        _mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        self.fs()?.open_handle(path, flags)
+    }
+
+    fn stat_path(&self, _badge: Badge, path: &str) -> Result<Stat, Error> {
+        self.fs()?.stat_path(path)
+    }
+
+    fn stats(&self) -> Result<FsStats, Error> {
+        Ok(self.fs()?.stats())
+    }
+
+    fn set_mount_options(&mut self, options: MountOptions) -> Result<(), Error> {
+        // Names are matched as the medium records them
+        if options.casefold {
+            return Err(Error::NotSupported);
+        }
+        Ok(())
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    // The medium never changes under a client
+    fn attr_timeout(&self) -> usize {
+        proto::ATTR_TIMEOUT_NEVER
+    }
+
+    fn extra_call(
+        &mut self,
+        _badge: Badge,
+        utcb: &mut UTCB,
+        _ctx: &mut CallContext,
+    ) -> Option<Result<(), Error>> {
+        let tag = utcb.get_msg_tag();
+        if tag.proto() != FS_PROTO {
+            return None;
+        }
+        match tag.label() {
+            proto::VOLUME_INFO => {
+                Some(handle_call(utcb, |u_inner| self.device.encode(u_inner, self.device.tuning())))
+            }
+            proto::VOLUME_STATS => Some(handle_call(utcb, |u_inner| {
+                self.fs()?.error_counts().encode(u_inner);
+                Ok(())
+            })),
+            _ => None,
+        }
+    }
+}
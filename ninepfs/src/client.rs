@@ -0,0 +1,342 @@
+//! The 9P2000.L client: one request at a time over the transport endpoint,
+//! each carried whole in the IPC buffer. Calls go through the service's own
+//! UTCB, so a handler has to take its arguments out of the request before
+//! making one.
+
+use crate::msg::{self, Qid, Reader, Writer};
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_common::errors;
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::{MsgFlags, MsgTag, UTCB};
+use glenda::protocol::FS_PROTO;
+use spin::Mutex;
+
+// Requests are never outstanding together, so one tag does
+const TAG: u16 = 1;
+// Largest message offered at Tversion; the IPC buffer may cap it lower
+const MAX_MSIZE: usize = 64 * 1024;
+
+/// Attributes Rgetattr returns.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Attr {
+    pub qid: Qid,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub size: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+/// An entry of Rreaddir, with the offset to continue the listing from.
+#[derive(Debug, Clone)]
+pub struct Dirent {
+    pub qid: Qid,
+    pub offset: u64,
+    pub kind: u8,
+    pub name: String,
+}
+
+struct Fids {
+    next: u32,
+    free: Vec<u32>,
+}
+
+pub struct Client {
+    transport: Endpoint,
+    msize: usize,
+    fids: Mutex<Fids>,
+}
+
+impl Client {
+    /// Negotiates the protocol version and message size with the server
+    /// behind `transport`.
+    pub fn connect(transport: Endpoint) -> Result<Self, Error> {
+        let buffer = unsafe { UTCB::new() }.buffer().len();
+        let mut client = Self {
+            transport,
+            msize: MAX_MSIZE.min(buffer),
+            fids: Mutex::new(Fids { next: 0, free: Vec::new() }),
+        };
+        let mut w = Writer::new(msg::TVERSION, msg::NOTAG);
+        w.u32(client.msize as u32).str(msg::VERSION);
+        let reply = client.rpc(msg::TVERSION, w)?;
+        let mut r = Reader::new(&reply);
+        let msize = r.u32()? as usize;
+        if r.str()? != msg::VERSION {
+            glenda::log!("NineFS: server does not speak {}", msg::VERSION);
+            return Err(Error::NotSupported);
+        }
+        if msize <= msg::IO_HEADER_SIZE {
+            return Err(Error::NotSupported);
+        }
+        client.msize = client.msize.min(msize);
+        Ok(client)
+    }
+
+    /// The most a single Tread or Twrite carries.
+    pub fn iounit(&self) -> usize {
+        self.msize - msg::IO_HEADER_SIZE
+    }
+
+    pub fn alloc_fid(&self) -> u32 {
+        let mut fids = self.fids.lock();
+        match fids.free.pop() {
+            Some(fid) => fid,
+            None => {
+                fids.next += 1;
+                fids.next
+            }
+        }
+    }
+
+    fn free_fid(&self, fid: u32) {
+        self.fids.lock().free.push(fid);
+    }
+
+    // Sends T-message `w` of type `kind`; returns the body of the R-message
+    fn rpc(&self, kind: u8, w: Writer) -> Result<Vec<u8>, Error> {
+        let request = w.finish()?;
+        if request.len() > self.msize {
+            return Err(Error::MessageTooLong);
+        }
+        let utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        utcb.buffer_mut()
+            .get_mut(..request.len())
+            .ok_or(Error::MessageTooLong)?
+            .copy_from_slice(&request);
+        utcb.set_buffer_len(request.len());
+        utcb.set_msg_tag(MsgTag::new(FS_PROTO, proto::NINEP_RPC, MsgFlags::HAS_BUFFER));
+        self.transport.call(utcb)?;
+        if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+            return Err(errors::decode(utcb.get_mr(0)));
+        }
+
+        let reply = utcb.buffer();
+        let mut r = Reader::new(reply);
+        let size = r.u32()? as usize;
+        let (rkind, _tag) = (r.u8()?, r.u16()?);
+        let body = reply.get(msg::HEADER_SIZE..size).ok_or(Error::IoError)?;
+        if rkind == msg::RLERROR {
+            return Err(msg::errno(Reader::new(body).u32()?));
+        }
+        if rkind != kind + 1 {
+            return Err(Error::IoError);
+        }
+        Ok(body.to_vec())
+    }
+
+    /// Attaches `fid` to the root of tree `aname` as `uid`.
+    pub fn attach(&self, fid: u32, uid: u32, aname: &str) -> Result<Qid, Error> {
+        let mut w = Writer::new(msg::TATTACH, TAG);
+        w.u32(fid).u32(msg::NOFID).str("").str(aname).u32(uid);
+        Reader::new(&self.rpc(msg::TATTACH, w)?).qid()
+    }
+
+    /// Walks `names` from `fid` to a new fid, which is freed again on
+    /// failure. No names clones `fid`.
+    pub fn walk(&self, fid: u32, names: &[&str]) -> Result<u32, Error> {
+        let newfid = self.alloc_fid();
+        let result = self.walk_to(fid, newfid, names);
+        if result.is_err() {
+            self.free_fid(newfid);
+        }
+        result.map(|_| newfid)
+    }
+
+    fn walk_to(&self, fid: u32, newfid: u32, names: &[&str]) -> Result<(), Error> {
+        let mut chunks = names.chunks(msg::MAX_WALK);
+        let first = chunks.next().unwrap_or(&[]);
+        self.walk_step(fid, newfid, first)?;
+        for chunk in chunks {
+            if let Err(e) = self.walk_step(newfid, newfid, chunk) {
+                let _ = self.clunk_only(newfid);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // One Twalk; a partial walk means the name after the last qid is missing
+    fn walk_step(&self, fid: u32, newfid: u32, names: &[&str]) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TWALK, TAG);
+        w.u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            w.str(name);
+        }
+        let reply = self.rpc(msg::TWALK, w)?;
+        let walked = Reader::new(&reply).u16()? as usize;
+        // Only a complete walk moves newfid
+        if walked < names.len() {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Opens `fid` with Linux open `flags`; returns the qid and the iounit.
+    pub fn lopen(&self, fid: u32, flags: u32) -> Result<(Qid, u32), Error> {
+        let mut w = Writer::new(msg::TLOPEN, TAG);
+        w.u32(fid).u32(flags);
+        let reply = self.rpc(msg::TLOPEN, w)?;
+        let mut r = Reader::new(&reply);
+        Ok((r.qid()?, r.u32()?))
+    }
+
+    /// Creates `name` in directory `fid`, which then stands for the new
+    /// file, opened with `flags`.
+    pub fn lcreate(&self, fid: u32, name: &str, flags: u32, mode: u32) -> Result<Qid, Error> {
+        let mut w = Writer::new(msg::TLCREATE, TAG);
+        w.u32(fid).str(name).u32(flags).u32(mode).u32(0);
+        Reader::new(&self.rpc(msg::TLCREATE, w)?).qid()
+    }
+
+    pub fn getattr(&self, fid: u32) -> Result<Attr, Error> {
+        let mut w = Writer::new(msg::TGETATTR, TAG);
+        w.u32(fid).u64(msg::GETATTR_BASIC);
+        let reply = self.rpc(msg::TGETATTR, w)?;
+        let mut r = Reader::new(&reply);
+        let _valid = r.u64()?;
+        let qid = r.qid()?;
+        let (mode, uid, gid) = (r.u32()?, r.u32()?, r.u32()?);
+        let nlink = r.u64()?;
+        let _rdev = r.u64()?;
+        let (size, blksize, blocks) = (r.u64()?, r.u64()?, r.u64()?);
+        let atime = r.u64()?;
+        let _ = r.u64()?;
+        let mtime = r.u64()?;
+        let _ = r.u64()?;
+        let ctime = r.u64()?;
+        Ok(Attr { qid, mode, uid, gid, nlink, size, blksize, blocks, atime, mtime, ctime })
+    }
+
+    /// Tsetattr with `valid` bits; the fields not named are ignored.
+    pub fn setattr(
+        &self,
+        fid: u32,
+        valid: u32,
+        size: u64,
+        atime: (u64, u64),
+        mtime: (u64, u64),
+    ) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TSETATTR, TAG);
+        w.u32(fid).u32(valid).u32(0).u32(0).u32(0).u64(size);
+        w.u64(atime.0).u64(atime.1).u64(mtime.0).u64(mtime.1);
+        self.rpc(msg::TSETATTR, w).map(|_| ())
+    }
+
+    /// Up to `count` bytes of directory entries from listing position `offset`.
+    pub fn readdir(&self, fid: u32, offset: u64, count: usize) -> Result<Vec<Dirent>, Error> {
+        let count = count.min(self.iounit());
+        let mut w = Writer::new(msg::TREADDIR, TAG);
+        w.u32(fid).u64(offset).u32(count as u32);
+        let reply = self.rpc(msg::TREADDIR, w)?;
+        let mut r = Reader::new(&reply);
+        let len = r.u32()? as usize;
+        let mut r = Reader::new(r.bytes(len)?);
+        let mut entries = Vec::new();
+        while !r.is_empty() {
+            let qid = r.qid()?;
+            let (offset, kind) = (r.u64()?, r.u8()?);
+            entries.push(Dirent { qid, offset, kind, name: r.str()? });
+        }
+        Ok(entries)
+    }
+
+    pub fn fsync(&self, fid: u32) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TFSYNC, TAG);
+        w.u32(fid).u32(0);
+        self.rpc(msg::TFSYNC, w).map(|_| ())
+    }
+
+    /// Gives the file `fid` the further name `name` in directory `dfid`.
+    pub fn link(&self, dfid: u32, fid: u32, name: &str) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TLINK, TAG);
+        w.u32(dfid).u32(fid).str(name);
+        self.rpc(msg::TLINK, w).map(|_| ())
+    }
+
+    pub fn mkdir(&self, dfid: u32, name: &str, mode: u32) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TMKDIR, TAG);
+        w.u32(dfid).str(name).u32(mode).u32(0);
+        self.rpc(msg::TMKDIR, w).map(|_| ())
+    }
+
+    pub fn renameat(
+        &self,
+        old_dfid: u32,
+        old: &str,
+        new_dfid: u32,
+        new: &str,
+    ) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TRENAMEAT, TAG);
+        w.u32(old_dfid).str(old).u32(new_dfid).str(new);
+        self.rpc(msg::TRENAMEAT, w).map(|_| ())
+    }
+
+    pub fn unlinkat(&self, dfid: u32, name: &str, flags: u32) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TUNLINKAT, TAG);
+        w.u32(dfid).str(name).u32(flags);
+        self.rpc(msg::TUNLINKAT, w).map(|_| ())
+    }
+
+    /// Reads into `buf` at `offset`, at most `iounit` bytes.
+    pub fn read(&self, fid: u32, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let count = buf.len().min(self.iounit());
+        let mut w = Writer::new(msg::TREAD, TAG);
+        w.u32(fid).u64(offset).u32(count as u32);
+        let reply = self.rpc(msg::TREAD, w)?;
+        let mut r = Reader::new(&reply);
+        let read = (r.u32()? as usize).min(count);
+        buf[..read].copy_from_slice(r.bytes(read)?);
+        Ok(read)
+    }
+
+    /// Writes the start of `data` at `offset`, at most `iounit` bytes.
+    pub fn write(&self, fid: u32, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let data = &data[..data.len().min(self.iounit())];
+        let mut w = Writer::new(msg::TWRITE, TAG);
+        w.u32(fid).u64(offset).u32(data.len() as u32).bytes(data);
+        let reply = self.rpc(msg::TWRITE, w)?;
+        // A server claiming more than was sent must not move the caller past its data
+        Ok((Reader::new(&reply).u32()? as usize).min(data.len()))
+    }
+
+    pub fn statfs(&self, fid: u32) -> Result<FsStats, Error> {
+        let mut w = Writer::new(msg::TSTATFS, TAG);
+        w.u32(fid);
+        let reply = self.rpc(msg::TSTATFS, w)?;
+        let mut r = Reader::new(&reply);
+        let (_kind, bsize) = (r.u32()?, r.u32()?);
+        Ok(FsStats {
+            block_size: bsize as usize,
+            total_blocks: r.u64()?,
+            free_blocks: r.u64()?,
+            avail_blocks: r.u64()?,
+            total_inodes: r.u64()?,
+            free_inodes: r.u64()?,
+        })
+    }
+
+    /// Forgets `fid`, on the server and here.
+    pub fn clunk(&self, fid: u32) -> Result<(), Error> {
+        let result = self.clunk_only(fid);
+        self.free_fid(fid);
+        result
+    }
+
+    // Tclunk without giving the fid back, for one walk_to still owns
+    fn clunk_only(&self, fid: u32) -> Result<(), Error> {
+        let mut w = Writer::new(msg::TCLUNK, TAG);
+        w.u32(fid);
+        self.rpc(msg::TCLUNK, w).map(|_| ())
+    }
+}
@@ -0,0 +1,432 @@
+//! The remote tree as the filesystem protocol sees it. Every path is walked
+//! from the fid attached to the root; handles hold an opened fid of their own
+//! and clunk it on close.
+
+use crate::client::{Attr, Client};
+use crate::msg;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use fs_common::errors::FsError;
+use fs_common::handle::FsHandle;
+use fs_common::limits;
+use fs_common::path;
+use fs_common::proto::{self, CURRENT_OFFSET, UTIME_NOW, UTIME_OMIT};
+use fs_common::statfs::FsStats;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::ipc::Badge;
+use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
+
+// Files on the server may be as large as the server allows; cap offsets so
+// they still fit a signed seek
+const NINEP_MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+pub struct NineFs {
+    client: Arc<Client>,
+    root: u32,
+}
+
+impl NineFs {
+    /// Attaches to tree `aname` of the server behind `client` as `uid`.
+    pub fn attach(client: Client, uid: u32, aname: &str) -> Result<Self, Error> {
+        let root = client.alloc_fid();
+        client.attach(root, uid, aname)?;
+        Ok(Self { client: Arc::new(client), root })
+    }
+
+    /// Clunks the root fid; the session ends with it.
+    pub fn detach(&self) {
+        let _ = self.client.clunk(self.root);
+    }
+
+    // A fresh fid for absolute `path`
+    fn walk(&self, path: &str) -> Result<u32, Error> {
+        let names: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+        self.client.walk(self.root, &names)
+    }
+
+    // A fresh fid for the directory holding `path`, and the last name
+    fn walk_parent<'p>(&self, path: &'p str) -> Result<(u32, &'p str), Error> {
+        let name = path.rsplit('/').next().unwrap_or("");
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(Error::InvalidArgs);
+        }
+        Ok((self.walk(path::parent(path))?, name))
+    }
+
+    // Runs `f` on a fid walked to `path`, clunking it afterwards
+    fn with_fid<T>(&self, path: &str, f: impl FnOnce(u32) -> Result<T, Error>) -> Result<T, Error> {
+        let fid = self.walk(path)?;
+        let result = f(fid);
+        let _ = self.client.clunk(fid);
+        result
+    }
+
+    pub fn open_handle(
+        &self,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let lflags = linux_flags(flags);
+        let fid = match self.walk(path) {
+            Ok(_) if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) => {
+                return Err(Error::AlreadyExists);
+            }
+            Ok(fid) => fid,
+            Err(Error::NotFound) if flags.contains(OpenFlags::O_CREAT) => {
+                let (fid, name) = self.walk_parent(path)?;
+                if let Err(e) = self.client.lcreate(fid, name, lflags, mode & 0o7777) {
+                    let _ = self.client.clunk(fid);
+                    return Err(e);
+                }
+                return self.handle(fid, path, flags);
+            }
+            Err(e) => return Err(e),
+        };
+        // O_CREAT only matters to a name that did not exist
+        let lflags = lflags & !(msg::L_O_CREAT | msg::L_O_EXCL);
+        if let Err(e) = self.client.lopen(fid, lflags) {
+            let _ = self.client.clunk(fid);
+            return Err(e);
+        }
+        self.handle(fid, path, flags)
+    }
+
+    // Wraps opened `fid`, clunking it if its attributes cannot be had
+    fn handle(&self, fid: u32, path: &str, flags: OpenFlags) -> Result<Box<dyn FsHandle>, Error> {
+        let attr = match self.client.getattr(fid) {
+            Ok(attr) => attr,
+            Err(e) => {
+                let _ = self.client.clunk(fid);
+                return Err(e);
+            }
+        };
+        let client = self.client.clone();
+        let path = String::from(path);
+        if attr.qid.is_dir() {
+            return Ok(Box::new(NineDir { client, fid, cookie: 0, pos: 0, done: false }));
+        }
+        if flags.contains(OpenFlags::O_DIRECTORY) {
+            let _ = self.client.clunk(fid);
+            return Err(FsError::NotDir.into());
+        }
+        let append = flags.contains(OpenFlags::O_APPEND);
+        let root = self.root;
+        Ok(Box::new(NineFile { client, root, path, fid, pos: 0, append }))
+    }
+
+    pub fn mkdir(&self, path: &str, mode: u32) -> Result<(), Error> {
+        let (dfid, name) = self.walk_parent(path)?;
+        let result = self.client.mkdir(dfid, name, mode & 0o7777);
+        let _ = self.client.clunk(dfid);
+        result
+    }
+
+    /// Removes the file or empty directory at `path`.
+    pub fn unlink(&self, path: &str) -> Result<(), Error> {
+        let (dfid, name) = self.walk_parent(path)?;
+        let result = match self.client.unlinkat(dfid, name, 0) {
+            // Servers that check want to be told it is a directory
+            Err(Error::InvalidArgs) => self.client.unlinkat(dfid, name, msg::AT_REMOVEDIR),
+            result => result,
+        };
+        let _ = self.client.clunk(dfid);
+        result
+    }
+
+    /// Removes `path` and everything below it, depth first.
+    pub fn rmtree(&self, path: &str) -> Result<(), Error> {
+        let is_dir = self.with_fid(path, |fid| self.client.getattr(fid))?.qid.is_dir();
+        if is_dir {
+            for name in self.list(path)? {
+                self.rmtree(&path::join(path, &name))?;
+            }
+        }
+        self.unlink(path)
+    }
+
+    // Names in directory `path`, without `.` and `..`
+    fn list(&self, path: &str) -> Result<Vec<String>, Error> {
+        self.with_fid(path, |fid| {
+            self.client.lopen(fid, 0)?;
+            let mut names = Vec::new();
+            let mut cookie = 0;
+            loop {
+                let entries = self.client.readdir(fid, cookie, self.client.iounit())?;
+                let Some(last) = entries.last() else {
+                    return Ok(names);
+                };
+                cookie = last.offset;
+                let named = entries.into_iter().filter(|e| e.name != "." && e.name != "..");
+                names.extend(named.map(|e| e.name));
+            }
+        })
+    }
+
+    pub fn rename(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let (old_dfid, old_name) = self.walk_parent(old_path)?;
+        let result = self.walk_parent(new_path).and_then(|(new_dfid, new_name)| {
+            let result = self.client.renameat(old_dfid, old_name, new_dfid, new_name);
+            let _ = self.client.clunk(new_dfid);
+            result
+        });
+        let _ = self.client.clunk(old_dfid);
+        result
+    }
+
+    /// Gives the file at `old_path` the further name `new_path`.
+    pub fn link(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.with_fid(old_path, |fid| {
+            let (dfid, name) = self.walk_parent(new_path)?;
+            let result = self.client.link(dfid, fid, name);
+            let _ = self.client.clunk(dfid);
+            result
+        })
+    }
+
+    /// SET_TIMES with MR0-MR3 as the client sent them. UTIME_NOW is left
+    /// to the server's clock.
+    pub fn set_times(&self, path: &str, times: [usize; 4]) -> Result<(), Error> {
+        let mut valid = 0;
+        let mut spec = |sec: usize, nsec: usize, bit: u32, set: u32| match nsec {
+            UTIME_OMIT => Ok((0, 0)),
+            UTIME_NOW => {
+                valid |= bit;
+                Ok((0, 0))
+            }
+            nsec if nsec < 1_000_000_000 => {
+                valid |= bit | set;
+                Ok((sec as u64, nsec as u64))
+            }
+            _ => Err(Error::InvalidArgs),
+        };
+        let atime = spec(times[0], times[1], msg::SETATTR_ATIME, msg::SETATTR_ATIME_SET)?;
+        let mtime = spec(times[2], times[3], msg::SETATTR_MTIME, msg::SETATTR_MTIME_SET)?;
+        self.with_fid(path, |fid| self.client.setattr(fid, valid, 0, atime, mtime))
+    }
+
+    pub fn stat_path(&self, path: &str) -> Result<Stat, Error> {
+        self.with_fid(path, |fid| self.client.getattr(fid)).map(|attr| stat(&attr))
+    }
+
+    pub fn stats(&self) -> Result<FsStats, Error> {
+        self.client.statfs(self.root)
+    }
+}
+
+// OPEN flags as Tlopen and Tlcreate take them
+fn linux_flags(flags: OpenFlags) -> u32 {
+    let mut lflags = 0;
+    for (flag, lflag) in [
+        (OpenFlags::O_WRONLY, msg::L_O_WRONLY),
+        (OpenFlags::O_RDWR, msg::L_O_RDWR),
+        (OpenFlags::O_CREAT, msg::L_O_CREAT),
+        (OpenFlags::O_EXCL, msg::L_O_EXCL),
+        (OpenFlags::O_TRUNC, msg::L_O_TRUNC),
+        (OpenFlags::O_APPEND, msg::L_O_APPEND),
+        (OpenFlags::O_DIRECTORY, msg::L_O_DIRECTORY),
+    ] {
+        if flags.contains(flag) {
+            lflags |= lflag;
+        }
+    }
+    lflags
+}
+
+fn stat(attr: &Attr) -> Stat {
+    Stat {
+        ino: attr.qid.path as usize,
+        mode: attr.mode,
+        nlink: attr.nlink as u32,
+        uid: attr.uid,
+        gid: attr.gid,
+        size: attr.size as usize,
+        blksize: attr.blksize as u32,
+        blocks: attr.blocks as usize,
+        atime: attr.atime as usize,
+        mtime: attr.mtime as usize,
+        ctime: attr.ctime as usize,
+        ..Default::default()
+    }
+}
+
+pub struct NineFile {
+    client: Arc<Client>,
+    // For duplicate, which cannot walk from an opened fid
+    root: u32,
+    path: String,
+    fid: u32,
+    pos: usize,
+    append: bool,
+}
+
+impl FileHandleService for NineFile {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.client.clunk(self.fid)
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        self.client.getattr(self.fid).map(|attr| stat(&attr))
+    }
+
+    fn read(&mut self, _badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        let mut done = 0;
+        while done < buf.len() {
+            let read = self.client.read(self.fid, (offset + done) as u64, &mut buf[done..])?;
+            if read == 0 {
+                break;
+            }
+            done += read;
+        }
+        if advance {
+            self.pos = offset + done;
+        }
+        Ok(done)
+    }
+
+    // The server opened the fid with O_APPEND, so it puts the data at the end
+    fn write(&mut self, _badge: Badge, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        limits::checked_end(offset, buf.len(), NINEP_MAX_FILE_SIZE)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let written = self.client.write(self.fid, (offset + done) as u64, &buf[done..])?;
+            if written == 0 {
+                break;
+            }
+            done += written;
+        }
+        if self.append {
+            self.pos = self.client.getattr(self.fid)?.size as usize;
+        } else if advance {
+            self.pos = offset + done;
+        }
+        Ok(done)
+    }
+
+    fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
+        Err(FsError::NotDir.into())
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let size = match whence {
+            proto::SEEK_SET | proto::SEEK_CUR => 0,
+            _ => self.client.getattr(self.fid)?.size as usize,
+        };
+        self.pos = limits::seek_target(self.pos, size, offset, whence, NINEP_MAX_FILE_SIZE)?;
+        Ok(self.pos)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.client.fsync(self.fid)
+    }
+
+    fn truncate(&mut self, _badge: Badge, size: usize) -> Result<(), Error> {
+        let none = (0, 0);
+        self.client.setattr(self.fid, msg::SETATTR_SIZE, size as u64, none, none)
+    }
+}
+
+impl FsHandle for NineFile {
+    /// Walks to the file again: an opened fid cannot be cloned.
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        let names: Vec<&str> = self.path.split('/').filter(|p| !p.is_empty()).collect();
+        let fid = self.client.walk(self.root, &names)?;
+        if let Err(e) = self.client.lopen(fid, 0) {
+            let _ = self.client.clunk(fid);
+            return Err(e);
+        }
+        let (client, root, path) = (self.client.clone(), self.root, self.path.clone());
+        Ok(Box::new(NineFile { client, root, path, fid, pos: 0, append: false }))
+    }
+}
+
+pub struct NineDir {
+    client: Arc<Client>,
+    fid: u32,
+    // Where Treaddir continues, as the server numbers it
+    cookie: u64,
+    // Entries returned so far
+    pos: usize,
+    done: bool,
+}
+
+impl NineDir {
+    // Up to `count` entries from the cookie on
+    fn next(&mut self, count: usize) -> Result<Vec<DEntry>, Error> {
+        let mut out = Vec::new();
+        let bytes = core::mem::size_of::<DEntry>() * count.max(1);
+        while out.len() < count && !self.done {
+            let entries = self.client.readdir(self.fid, self.cookie, bytes)?;
+            if entries.is_empty() {
+                self.done = true;
+            }
+            for entry in entries {
+                if out.len() == count {
+                    break;
+                }
+                self.cookie = entry.offset;
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                let ino = entry.qid.path as usize;
+                out.push(proto::dentry(ino, self.pos, entry.kind, entry.name.as_bytes()));
+                self.pos += 1;
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl FileHandleService for NineDir {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.client.clunk(self.fid)
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        self.client.getattr(self.fid).map(|attr| stat(&attr))
+    }
+
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        self.next(count)
+    }
+
+    /// Positions count entries. The server's cookies are opaque, so the
+    /// listing is read again from the start up to the target.
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let target = limits::seek_target(self.pos, self.pos, offset, whence, u32::MAX as u64)?;
+        if target < self.pos {
+            self.cookie = 0;
+            self.pos = 0;
+            self.done = false;
+        }
+        while self.pos < target && !self.done {
+            self.next(target - self.pos)?;
+        }
+        Ok(self.pos)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(FsError::IsDir.into())
+    }
+}
+
+impl FsHandle for NineDir {}
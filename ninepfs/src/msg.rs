@@ -0,0 +1,208 @@
+//! 9P2000.L messages: a little-endian header of size[4] type[1] tag[2], then
+//! the fields of the type. Strings are a 16-bit length and UTF-8 bytes.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use fs_common::errors::FsError;
+use glenda::error::Error;
+
+pub const VERSION: &str = "9P2000.L";
+pub const HEADER_SIZE: usize = 7;
+// Header and fields of Rread/Twrite ahead of the data
+pub const IO_HEADER_SIZE: usize = 24;
+
+pub const NOTAG: u16 = 0xFFFF;
+pub const NOFID: u32 = 0xFFFF_FFFF;
+
+// T-message types; the R-message is always one more
+pub const TLERROR: u8 = 6;
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+
+// Names a single Twalk may carry
+pub const MAX_WALK: usize = 16;
+
+// Tgetattr request mask: the basic fields of struct stat
+pub const GETATTR_BASIC: u64 = 0x0000_07ff;
+// Tsetattr valid bits
+pub const SETATTR_SIZE: u32 = 0x0008;
+pub const SETATTR_ATIME: u32 = 0x0010;
+pub const SETATTR_MTIME: u32 = 0x0020;
+pub const SETATTR_ATIME_SET: u32 = 0x0080;
+pub const SETATTR_MTIME_SET: u32 = 0x0100;
+
+// Linux open flags, as Tlopen and Tlcreate take them
+pub const L_O_WRONLY: u32 = 0o1;
+pub const L_O_RDWR: u32 = 0o2;
+pub const L_O_CREAT: u32 = 0o100;
+pub const L_O_EXCL: u32 = 0o200;
+pub const L_O_TRUNC: u32 = 0o1000;
+pub const L_O_APPEND: u32 = 0o2000;
+pub const L_O_DIRECTORY: u32 = 0o200000;
+// Tunlinkat flag for directories
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+pub const QID_DIR: u8 = 0x80;
+
+/// The server's identity for a file: type bits, version and a unique path.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub fn is_dir(&self) -> bool {
+        self.kind & QID_DIR != 0
+    }
+}
+
+/// Builds a T-message.
+pub struct Writer {
+    buf: Vec<u8>,
+    // Set by a string too long for its 16-bit length; `finish` then fails
+    overlong: bool,
+}
+
+impl Writer {
+    pub fn new(kind: u8, tag: u16) -> Self {
+        let mut w = Self { buf: Vec::with_capacity(64), overlong: false };
+        w.u32(0).u8(kind).u16(tag);
+        w
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Self {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn str(&mut self, s: &str) -> &mut Self {
+        let Ok(len) = u16::try_from(s.len()) else {
+            self.overlong = true;
+            return self;
+        };
+        self.u16(len);
+        self.buf.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// The message with its size filled in. MessageTooLong if a string
+    /// did not fit its length field.
+    pub fn finish(mut self) -> Result<Vec<u8>, Error> {
+        if self.overlong {
+            return Err(Error::MessageTooLong);
+        }
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        Ok(self.buf)
+    }
+}
+
+/// Reads the fields of an R-message, failing on one cut short.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let out = self.buf.get(self.pos..self.pos + len).ok_or(Error::IoError)?;
+        self.pos += len;
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(raw))
+    }
+
+    pub fn str(&mut self) -> Result<String, Error> {
+        let len = self.u16()? as usize;
+        let raw = self.bytes(len)?;
+        Ok(String::from_utf8_lossy(raw).into_owned())
+    }
+
+    pub fn qid(&mut self) -> Result<Qid, Error> {
+        Ok(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+/// The Linux errno of an Rlerror as the error our clients see.
+pub fn errno(code: u32) -> Error {
+    match code {
+        1 | 13 => Error::PermissionDenied,
+        2 => Error::NotFound,
+        5 => Error::IoError,
+        11 => Error::WouldBlock,
+        12 => Error::OutOfMemory,
+        17 => Error::AlreadyExists,
+        20 => FsError::NotDir.into(),
+        21 => FsError::IsDir.into(),
+        28 => FsError::NoSpace.into(),
+        30 => FsError::ReadOnly.into(),
+        36 | 22 | 39 => Error::InvalidArgs,
+        38 | 95 => Error::NotSupported,
+        110 => Error::Timeout,
+        _ => Error::IoError,
+    }
+}
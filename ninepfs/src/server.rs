@@ -0,0 +1,170 @@
+use crate::client::Client;
+use crate::fs::NineFs;
+use alloc::boxed::Box;
+use alloc::string::String;
+use fs_common::clock::TimesRequest;
+use fs_common::handle::FsHandle;
+use fs_common::mount::MountOptions;
+use fs_common::path;
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use fs_common::version;
+use fs_server::{CallContext, FileSystemBackend, FsServer};
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::server::handle_call;
+use glenda::ipc::{Badge, MsgFlags, UTCB};
+use glenda::protocol::fs::{OpenFlags, Stat};
+use glenda::protocol::FS_PROTO;
+
+/// The 9P service: the remote tree behind the shared server loop. It
+/// mounts once ATTACH_TRANSPORT has brought a session.
+pub type NineFsService<'a> = FsServer<'a, NineSession>;
+
+/// The 9P session, None until ATTACH_TRANSPORT.
+#[derive(Default)]
+pub struct NineSession {
+    fs: Option<NineFs>,
+}
+
+impl NineSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn fs(&self) -> Result<&NineFs, Error> {
+        self.fs.as_ref().ok_or(Error::NotInitialized)
+    }
+
+    // ATTACH_TRANSPORT: takes the transport transferred with the call and
+    // attaches to the tree the buffer names as the uid in MR0
+    fn attach(
+        &mut self,
+        badge: Badge,
+        utcb: &mut UTCB,
+        ctx: &mut CallContext,
+    ) -> Result<(), Error> {
+        if badge.bits() != 0 {
+            return Err(Error::PermissionDenied);
+        }
+        if !utcb.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+            return Err(Error::InvalidArgs);
+        }
+        // Handles hold fids of the old session
+        if ctx.open_handles != 0 {
+            return Err(Error::WouldBlock);
+        }
+        // Taken before the transport reuses the buffer
+        let uid = utcb.get_mr(0) as u32;
+        let aname = String::from(path::from_buffer(utcb.buffer())?);
+        let slot = ctx.take_cap()?;
+        let fs = Client::connect(Endpoint::from(slot))
+            .and_then(|client| NineFs::attach(client, uid, &aname));
+        let fs = match fs {
+            Ok(fs) => fs,
+            Err(e) => {
+                ctx.drop_cap(slot)?;
+                return Err(e);
+            }
+        };
+        if let Some(old) = self.fs.replace(fs) {
+            old.detach();
+        }
+        Ok(())
+    }
+}
+
+impl FileSystemBackend for NineSession {
+    const NAME: &'static str = "NineFS";
+    const FEATURES: usize = version::FEAT_RING_NOTIFY
+        | version::FEAT_IOVEC
+        | version::FEAT_LOCKS
+        | version::FEAT_CLONE
+        | version::FEAT_WIRE
+        | version::FEAT_LINK
+        | version::FEAT_SET_TIMES
+        | version::FEAT_STATFS
+        | version::FEAT_NEXT
+        | version::FEAT_STREAM
+        | version::FEAT_ERROR_DETAIL;
+    // Locks are only ours: the server never sees them, so other clients of
+    // the tree are not held off
+    const KEYED_BY_PATH: bool = true;
+
+    fn open(
+        &mut self,
+        _badge: Badge,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        self.fs()?.open_handle(path, flags, mode)
+    }
+
+    fn stat_path(&self, _badge: Badge, path: &str) -> Result<Stat, Error> {
+        self.fs()?.stat_path(path)
+    }
+
+    fn stats(&self) -> Result<FsStats, Error> {
+        self.fs()?.stats()
+    }
+
+    fn mkdir(&mut self, _badge: Badge, path: &str, mode: u32) -> Result<(), Error> {
+        self.fs()?.mkdir(path, mode)
+    }
+
+    fn unlink(&mut self, _badge: Badge, path: &str) -> Result<(), Error> {
+        self.fs()?.unlink(path)
+    }
+
+    fn rename(&mut self, _badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.fs()?.rename(old_path, new_path)
+    }
+
+    fn link(&mut self, _badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.fs()?.link(old_path, new_path)
+    }
+
+    // Checked by the server, applied by the server's clock
+    fn set_times(&mut self, _badge: Badge, path: &str, req: TimesRequest) -> Result<(), Error> {
+        self.fs()?.set_times(path, req.to_mrs())
+    }
+
+    // The server is asked synchronously, so there is no JOB_ASYNC variant
+    fn rmtree(&mut self, _badge: Badge, path: &str) -> Result<(), Error> {
+        self.fs()?.rmtree(path)
+    }
+
+    fn set_mount_options(&mut self, options: MountOptions) -> Result<(), Error> {
+        // Names go to the server as given
+        if options.casefold {
+            return Err(Error::NotSupported);
+        }
+        Ok(())
+    }
+
+    fn ready(&self) -> bool {
+        self.fs.is_some()
+    }
+
+    // Ends the session; the transport is left as it is
+    fn shutdown(&mut self) -> Result<(), Error> {
+        if let Some(fs) = self.fs.take() {
+            fs.detach();
+        }
+        Ok(())
+    }
+
+    fn extra_call(
+        &mut self,
+        badge: Badge,
+        utcb: &mut UTCB,
+        ctx: &mut CallContext,
+    ) -> Option<Result<(), Error>> {
+        let tag = utcb.get_msg_tag();
+        if tag.proto() != FS_PROTO || tag.label() != proto::ATTACH_TRANSPORT {
+            return None;
+        }
+        Some(handle_call(utcb, |u_inner| self.attach(badge, u_inner, ctx)))
+    }
+}
@@ -0,0 +1,687 @@
+//! One namespace over two filesystems. What the upper layer holds wins;
+//! the lower layer shows through wherever the upper has nothing, and is
+//! never written to. A lower file is copied up whole the first time it is
+//! opened for writing, its directories with it.
+//!
+//! Removing something that exists below leaves a whiteout in the upper
+//! directory, an empty file named `.wh.` and the hidden name. A directory
+//! made where a lower one was removed holds an opaque marker, `.wh..wh..opq`,
+//! so the lower contents stay hidden. Both are kept from clients, who cannot
+//! create names starting with `.wh.` either.
+
+use crate::layer::{Layer, PathStat};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use fs_common::errors::FsError;
+use fs_common::handle::FsHandle;
+use fs_common::limits;
+use fs_common::path::{join, parent};
+use fs_common::proto::{CURRENT_OFFSET, SEEK_CUR, SEEK_END, SEEK_SET};
+use fs_common::statfs::FsStats;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::ipc::Badge;
+use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+// Entries asked of a layer per GETDENTS while merging a directory
+const DENTS_BATCH: usize = 32;
+
+/// Where a path resolved to.
+#[derive(Debug, Clone, Copy)]
+pub enum Found {
+    Upper(PathStat),
+    Lower(PathStat),
+}
+
+impl Found {
+    pub fn stat(&self) -> PathStat {
+        match self {
+            Found::Upper(stat) | Found::Lower(stat) => *stat,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Overlay {
+    lower: Option<Arc<Layer>>,
+    upper: Option<Arc<Layer>>,
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_lower(&mut self, layer: Layer) {
+        self.lower = Some(Arc::new(layer));
+    }
+
+    pub fn set_upper(&mut self, layer: Layer) {
+        self.upper = Some(Arc::new(layer));
+    }
+
+    /// True once both layers are attached.
+    pub fn is_ready(&self) -> bool {
+        self.layers().is_ok()
+    }
+
+    fn layers(&self) -> Result<(&Arc<Layer>, &Arc<Layer>), Error> {
+        match (&self.lower, &self.upper) {
+            (Some(lower), Some(upper)) => Ok((lower, upper)),
+            _ => Err(Error::NotInitialized),
+        }
+    }
+
+    /// What canonical `path` names: the upper entry if there is one, else
+    /// the lower one unless a whiteout or an opaque directory hides it.
+    pub fn find(&self, path: &str) -> Result<Found, Error> {
+        let (lower, upper) = self.layers()?;
+        match upper.stat_path(path) {
+            Ok(stat) => return Ok(Found::Upper(stat)),
+            Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        if self.hidden(path)? {
+            return Err(Error::NotFound);
+        }
+        lower.stat_path(path).map(Found::Lower)
+    }
+
+    // True if the lower entry at `path` is hidden: it or a directory above
+    // it has a whiteout, or a directory above it is opaque
+    fn hidden(&self, path: &str) -> Result<bool, Error> {
+        let (_, upper) = self.layers()?;
+        let slashes: Vec<usize> = path.match_indices('/').map(|(at, _)| at).collect();
+        for (i, &at) in slashes.iter().enumerate() {
+            let end = slashes.get(i + 1).copied().unwrap_or(path.len());
+            let (dir, name) = (if at == 0 { "/" } else { &path[..at] }, &path[at + 1..end]);
+            if name.is_empty() {
+                continue;
+            }
+            if upper.exists(&whiteout_path(dir, name))?
+                || upper.exists(&join(dir, OPAQUE_MARKER))?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // True if the lower layer has `path` and it shows through
+    fn lower_visible(&self, path: &str) -> Result<bool, Error> {
+        let (lower, _) = self.layers()?;
+        Ok(lower.exists(path)? && !self.hidden(path)?)
+    }
+
+    /// Makes sure directory `dir` exists in the upper layer, copying up the
+    /// lower directories along the way with their modes.
+    fn copy_up_dirs(&self, dir: &str) -> Result<(), Error> {
+        let (lower, upper) = self.layers()?;
+        let mut at = String::new();
+        for part in dir.split('/').filter(|p| !p.is_empty()) {
+            at.push('/');
+            at.push_str(part);
+            match upper.stat_path(&at) {
+                Ok(stat) if stat.is_dir() => continue,
+                Ok(_) => return Err(FsError::NotDir.into()),
+                Err(Error::NotFound) if !self.hidden(&at)? => {}
+                Err(Error::NotFound) => return Err(Error::NotFound),
+                Err(e) => return Err(e),
+            }
+            let stat = lower.stat_path(&at)?;
+            if !stat.is_dir() {
+                return Err(FsError::NotDir.into());
+            }
+            upper.mkdir(&at, stat.mode & 0o7777)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the first `len` bytes of the lower file at `path` up, keeping
+    /// its mode and times.
+    fn copy_up(&self, path: &str, stat: PathStat, len: usize) -> Result<(), Error> {
+        let (lower, upper) = self.layers()?;
+        self.copy_up_dirs(parent(path))?;
+        let create = OpenFlags::O_WRONLY | OpenFlags::O_CREAT | OpenFlags::O_EXCL;
+        let dst = upper.open(path, create, stat.mode & 0o7777)?;
+        let copied = lower.open(path, OpenFlags::O_RDONLY, 0).and_then(|src| {
+            let mut buf = vec![0u8; lower.chunk_size().min(upper.chunk_size())];
+            let mut done = 0;
+            let result = loop {
+                if done >= len {
+                    break Ok(());
+                }
+                let want = buf.len().min(len - done);
+                match lower.read_next(src, &mut buf[..want]) {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => match upper.write_next(dst, &buf[..n]) {
+                        Ok(written) if written == n => done += n,
+                        Ok(_) => break Err(FsError::NoSpace.into()),
+                        Err(e) => break Err(e),
+                    },
+                    Err(e) => break Err(e),
+                }
+            };
+            let _ = lower.close(src);
+            result
+        });
+        let _ = upper.close(dst);
+        if let Err(e) = copied {
+            let _ = upper.unlink(path);
+            return Err(e);
+        }
+        // Best effort: the copy is still good with fresh times
+        let times = [stat.atime, 0, stat.mtime, 0];
+        let _ = upper.set_times(path, times);
+        Ok(())
+    }
+
+    // Hides the lower entry at `path`
+    fn whiteout(&self, path: &str) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        let (dir, name) = split(path)?;
+        self.copy_up_dirs(dir)?;
+        let flags = OpenFlags::O_WRONLY | OpenFlags::O_CREAT;
+        upper.close(upper.open(&whiteout_path(dir, name), flags, 0)?)
+    }
+
+    // Drops the whiteout at `path`, if any, before something is put there.
+    // Returns whether there was one.
+    fn clear_whiteout(&self, path: &str) -> Result<bool, Error> {
+        let (_, upper) = self.layers()?;
+        let (dir, name) = split(path)?;
+        match upper.unlink(&whiteout_path(dir, name)) {
+            Ok(()) => Ok(true),
+            Err(Error::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn open_handle(
+        &self,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let (lower, upper) = self.layers()?;
+        let writable = flags.intersects(OpenFlags::O_WRONLY | OpenFlags::O_RDWR);
+        let trunc = flags.contains(OpenFlags::O_TRUNC);
+        let found = match self.find(path) {
+            Ok(_) if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) => {
+                return Err(Error::AlreadyExists)
+            }
+            Ok(found) => found,
+            Err(Error::NotFound) if flags.contains(OpenFlags::O_CREAT) => {
+                let (dir, _) = split(path)?;
+                self.copy_up_dirs(dir)?;
+                self.clear_whiteout(path)?;
+                let handle = upper.open(path, flags, mode)?;
+                return Ok(Box::new(LayerFile::new(upper.clone(), path, handle, flags)));
+            }
+            Err(e) => return Err(e),
+        };
+
+        if found.stat().is_dir() {
+            if writable || trunc {
+                return Err(FsError::IsDir.into());
+            }
+            return Ok(Box::new(self.merged_dir(path, found)?));
+        }
+        if flags.contains(OpenFlags::O_DIRECTORY) {
+            return Err(FsError::NotDir.into());
+        }
+        let reopen = flags.difference(OpenFlags::O_CREAT | OpenFlags::O_EXCL);
+        match found {
+            Found::Lower(stat) if writable || trunc => {
+                // Truncated anyway, so there is nothing worth copying
+                self.copy_up(path, stat, if trunc { 0 } else { stat.size })?;
+                let handle = upper.open(path, reopen, 0)?;
+                Ok(Box::new(LayerFile::new(upper.clone(), path, handle, flags)))
+            }
+            Found::Lower(_) => {
+                let handle = lower.open(path, OpenFlags::O_RDONLY, 0)?;
+                Ok(Box::new(LayerFile::new(lower.clone(), path, handle, OpenFlags::O_RDONLY)))
+            }
+            Found::Upper(_) => {
+                let handle = upper.open(path, reopen, 0)?;
+                Ok(Box::new(LayerFile::new(upper.clone(), path, handle, flags)))
+            }
+        }
+    }
+
+    /// The directory at `path` as listed now: upper entries first, then the
+    /// lower ones neither shadowed nor whited out.
+    fn merged_dir(&self, path: &str, found: Found) -> Result<MergedDir, Error> {
+        let (lower, upper) = self.layers()?;
+        let mut entries = Vec::new();
+        let mut names = BTreeSet::new();
+        let mut whiteouts = BTreeSet::new();
+        let mut opaque = false;
+        if let Found::Upper(_) = found {
+            for entry in list(upper, path)? {
+                let name = dent_name(&entry);
+                if name == OPAQUE_MARKER {
+                    opaque = true;
+                } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    whiteouts.insert(String::from(hidden));
+                } else {
+                    names.insert(String::from(name));
+                    entries.push(entry);
+                }
+            }
+        }
+        let lower_shows = match found {
+            Found::Lower(_) => true,
+            Found::Upper(_) => !opaque && self.lower_visible(path)?,
+        };
+        if lower_shows {
+            for entry in list(lower, path)? {
+                let name = dent_name(&entry);
+                if !names.contains(name) && !whiteouts.contains(name) {
+                    entries.push(entry);
+                }
+            }
+        }
+        for (i, entry) in entries.iter_mut().enumerate() {
+            entry.off = i + 1;
+        }
+        Ok(MergedDir { stat: found.stat(), entries, pos: 0 })
+    }
+
+    /// True if the directory at `path` lists nothing.
+    fn is_empty_dir(&self, path: &str, found: Found) -> Result<bool, Error> {
+        let listed = self.merged_dir(path, found)?.entries;
+        Ok(listed.iter().all(|e| matches!(dent_name(e), "." | "..")))
+    }
+
+    pub fn mkdir(&self, path: &str, mode: u32) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        match self.find(path) {
+            Ok(_) => return Err(Error::AlreadyExists),
+            Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let (dir, _) = split(path)?;
+        self.copy_up_dirs(dir)?;
+        let replaced = self.clear_whiteout(path)?;
+        upper.mkdir(path, mode)?;
+        // A lower directory of that name was removed; its entries stay gone
+        if replaced {
+            let marker = join(path, OPAQUE_MARKER);
+            upper.close(upper.open(&marker, OpenFlags::O_WRONLY | OpenFlags::O_CREAT, 0)?)?;
+        }
+        Ok(())
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        split(path)?;
+        let found = self.find(path)?;
+        if found.stat().is_dir() && !self.is_empty_dir(path, found)? {
+            return Err(Error::InvalidArgs);
+        }
+        if let Found::Upper(stat) = found {
+            // An upper directory may still hold whiteouts
+            if stat.is_dir() {
+                upper.rmtree(path)?;
+            } else {
+                upper.unlink(path)?;
+            }
+        }
+        if self.lower_visible(path)? {
+            self.whiteout(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn rmtree(&self, path: &str) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        split(path)?;
+        let found = self.find(path)?;
+        if let Found::Upper(_) = found {
+            upper.rmtree(path)?;
+        }
+        if self.lower_visible(path)? {
+            self.whiteout(path)?;
+        }
+        Ok(())
+    }
+
+    /// Renames within the upper layer, copying a lower file up first. A
+    /// directory merged with a lower one cannot move: the lower contents
+    /// would have to be copied whole, so that fails with NotSupported.
+    pub fn rename(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        let from = self.find(old_path)?;
+        split(new_path)?;
+        if old_path == new_path {
+            return Ok(());
+        }
+        let is_dir = from.stat().is_dir();
+        let merged = match from {
+            Found::Lower(_) => true,
+            Found::Upper(_) => {
+                self.lower_visible(old_path)? && !upper.exists(&join(old_path, OPAQUE_MARKER))?
+            }
+        };
+        if is_dir && merged {
+            return Err(Error::NotSupported);
+        }
+        let to = match self.find(new_path) {
+            Ok(to) => Some(to),
+            Err(Error::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        if let Some(to) = to {
+            if to.stat().is_dir() != is_dir || (is_dir && !self.is_empty_dir(new_path, to)?) {
+                return Err(Error::InvalidArgs);
+            }
+        }
+
+        if let Found::Lower(stat) = from {
+            self.copy_up(old_path, stat, stat.size)?;
+        }
+        let (dir, _) = split(new_path)?;
+        self.copy_up_dirs(dir)?;
+        // Whiteouts left in the replaced directory would otherwise block it
+        if let Some(Found::Upper(stat)) = to {
+            if stat.is_dir() {
+                upper.rmtree(new_path)?;
+            }
+        }
+        self.clear_whiteout(new_path)?;
+        upper.rename(old_path, new_path)?;
+        if is_dir && self.lower_visible(new_path)? {
+            let marker = join(new_path, OPAQUE_MARKER);
+            upper.close(upper.open(&marker, OpenFlags::O_WRONLY | OpenFlags::O_CREAT, 0)?)?;
+        }
+        if self.lower_visible(old_path)? {
+            self.whiteout(old_path)?;
+        }
+        Ok(())
+    }
+
+    /// Gives the file at `old_path` the further name `new_path`, in the
+    /// upper layer.
+    pub fn link(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        let from = self.find(old_path)?;
+        if from.stat().is_dir() {
+            return Err(Error::PermissionDenied);
+        }
+        match self.find(new_path) {
+            Ok(_) => return Err(Error::AlreadyExists),
+            Err(Error::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        if let Found::Lower(stat) = from {
+            self.copy_up(old_path, stat, stat.size)?;
+        }
+        let (dir, _) = split(new_path)?;
+        self.copy_up_dirs(dir)?;
+        self.clear_whiteout(new_path)?;
+        upper.link(old_path, new_path)
+    }
+
+    /// SET_TIMES on whatever `path` names, copied up first if need be.
+    pub fn set_times(&self, path: &str, times: [usize; 4]) -> Result<(), Error> {
+        let (_, upper) = self.layers()?;
+        match self.find(path)? {
+            Found::Lower(stat) if stat.is_dir() => self.copy_up_dirs(path)?,
+            Found::Lower(stat) => self.copy_up(path, stat, stat.size)?,
+            Found::Upper(_) => {}
+        }
+        upper.set_times(path, times)
+    }
+
+    pub fn stat_path(&self, path: &str) -> Result<PathStat, Error> {
+        Ok(self.find(path)?.stat())
+    }
+
+    /// STATFS of the upper layer, where anything written goes.
+    pub fn stats(&self) -> Result<FsStats, Error> {
+        self.layers()?.1.statfs()
+    }
+}
+
+fn whiteout_path(dir: &str, name: &str) -> String {
+    let mut hidden = String::from(WHITEOUT_PREFIX);
+    hidden.push_str(name);
+    join(dir, &hidden)
+}
+
+// Directory and last component of a canonical path, refusing the root and
+// the names the overlay keeps for itself
+fn split(path: &str) -> Result<(&str, &str), Error> {
+    let name = &path[path.rfind('/').map_or(0, |at| at + 1)..];
+    if name.is_empty() || name.starts_with(WHITEOUT_PREFIX) {
+        return Err(Error::InvalidArgs);
+    }
+    Ok((parent(path), name))
+}
+
+fn dent_name(entry: &DEntry) -> &str {
+    let len = entry.name.iter().position(|&b| b == 0).unwrap_or(entry.name.len());
+    core::str::from_utf8(&entry.name[..len]).unwrap_or_default()
+}
+
+// Every entry of the directory at `path` in `layer`
+fn list(layer: &Layer, path: &str) -> Result<Vec<DEntry>, Error> {
+    let handle = layer.open(path, OpenFlags::O_RDONLY | OpenFlags::O_DIRECTORY, 0)?;
+    let mut out = Vec::new();
+    let result = loop {
+        match layer.getdents(handle, DENTS_BATCH) {
+            Ok(batch) if batch.is_empty() => break Ok(()),
+            Ok(batch) => out.extend(batch),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = layer.close(handle);
+    result.map(|_| out)
+}
+
+/// An open file in one of the layers. The layer's handle position is
+/// ours alone, so reads and writes only seek it when they land elsewhere.
+pub struct LayerFile {
+    layer: Arc<Layer>,
+    path: String,
+    handle: usize,
+    pos: usize,
+    // Where the layer's handle is
+    layer_pos: usize,
+    writable: bool,
+    append: bool,
+}
+
+impl LayerFile {
+    fn new(layer: Arc<Layer>, path: &str, handle: usize, flags: OpenFlags) -> Self {
+        Self {
+            layer,
+            path: String::from(path),
+            handle,
+            pos: 0,
+            layer_pos: 0,
+            writable: flags.intersects(OpenFlags::O_WRONLY | OpenFlags::O_RDWR),
+            append: flags.contains(OpenFlags::O_APPEND),
+        }
+    }
+
+    fn seek_to(&mut self, offset: usize) -> Result<(), Error> {
+        if self.layer_pos != offset {
+            self.layer_pos = self.layer.seek(self.handle, offset as i64, SEEK_SET)?;
+        }
+        Ok(())
+    }
+}
+
+impl FileHandleService for LayerFile {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.layer.close(self.handle)
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        let stat = self.layer.stat_path(&self.path)?;
+        Ok(Stat {
+            mode: stat.mode,
+            nlink: 1,
+            size: stat.size,
+            atime: stat.atime,
+            mtime: stat.mtime,
+            ctime: stat.ctime,
+            ..Default::default()
+        })
+    }
+
+    fn read(&mut self, _badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        self.seek_to(offset)?;
+        let chunk = self.layer.chunk_size();
+        let mut done = 0;
+        while done < buf.len() {
+            let want = chunk.min(buf.len() - done);
+            let read = self.layer.read_next(self.handle, &mut buf[done..done + want])?;
+            done += read;
+            self.layer_pos += read;
+            if read < want {
+                break;
+            }
+        }
+        if advance {
+            self.pos = offset + done;
+        }
+        Ok(done)
+    }
+
+    fn write(&mut self, _badge: Badge, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        if !self.writable {
+            return Err(Error::PermissionDenied);
+        }
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if self.append {
+            // The end as other handles left it
+            self.layer_pos = self.layer.seek(self.handle, 0, SEEK_END)?;
+            self.layer_pos
+        } else if advance {
+            self.pos
+        } else {
+            offset
+        };
+        self.seek_to(offset)?;
+        let chunk = self.layer.chunk_size();
+        let mut done = 0;
+        while done < buf.len() {
+            let want = chunk.min(buf.len() - done);
+            let written = self.layer.write_next(self.handle, &buf[done..done + want])?;
+            done += written;
+            self.layer_pos += written;
+            if written < want {
+                break;
+            }
+        }
+        if advance || self.append {
+            self.pos = offset + done;
+        }
+        Ok(done)
+    }
+
+    fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
+        Err(FsError::NotDir.into())
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        // The layer's idea of the current position may not be ours
+        let (offset, whence) = match whence {
+            SEEK_CUR => {
+                ((self.pos as i64).checked_add(offset).ok_or(Error::InvalidArgs)?, SEEK_SET)
+            }
+            _ => (offset, whence),
+        };
+        self.layer_pos = self.layer.seek(self.handle, offset, whence)?;
+        self.pos = self.layer_pos;
+        Ok(self.pos)
+    }
+
+    // Writes are with the layer by the time they return; making them
+    // durable is its business
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, size: usize) -> Result<(), Error> {
+        if !self.writable {
+            return Err(Error::PermissionDenied);
+        }
+        self.layer.truncate(self.handle, size)
+    }
+}
+
+impl FsHandle for LayerFile {
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        let handle = self.layer.open(&self.path, OpenFlags::O_RDONLY, 0)?;
+        let layer = self.layer.clone();
+        Ok(Box::new(LayerFile::new(layer, &self.path, handle, OpenFlags::O_RDONLY)))
+    }
+}
+
+/// A directory listing, merged when the directory was opened.
+pub struct MergedDir {
+    stat: PathStat,
+    entries: Vec<DEntry>,
+    pos: usize,
+}
+
+impl FileHandleService for MergedDir {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        Ok(Stat {
+            mode: self.stat.mode,
+            nlink: 2,
+            size: self.entries.len(),
+            atime: self.stat.atime,
+            mtime: self.stat.mtime,
+            ctime: self.stat.ctime,
+            ..Default::default()
+        })
+    }
+
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        let out: Vec<DEntry> = self.entries.iter().skip(self.pos).take(count).copied().collect();
+        self.pos += out.len();
+        Ok(out)
+    }
+
+    /// Positions count entries; SEEK_SET 0 starts the listing over.
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let len = self.entries.len();
+        self.pos = limits::seek_target(self.pos, len, offset, whence, len as u64)?;
+        Ok(self.pos)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(FsError::IsDir.into())
+    }
+}
+
+impl FsHandle for MergedDir {}
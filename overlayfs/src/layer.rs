@@ -0,0 +1,198 @@
+//! The client side of the filesystem protocol, spoken to the services the
+//! overlay stacks. Calls go through the service's own UTCB, so a handler has
+//! to take its arguments out of the request before calling a layer.
+
+use alloc::vec::Vec;
+use fs_common::errors::{self, FsError, ERROR_DETAIL_MR};
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use fs_common::version::{self, PROTO_VERSION};
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::{MsgFlags, MsgTag, UTCB};
+use glenda::protocol::fs::{DEntry, OpenFlags};
+use glenda::protocol::{self, FS_PROTO};
+
+// What the overlay relies on a layer for, beyond the base protocol
+const WANTED: usize = version::FEAT_LINK
+    | version::FEAT_SET_TIMES
+    | version::FEAT_STATFS
+    | version::FEAT_NEXT
+    | version::FEAT_ERROR_DETAIL;
+
+/// Attributes STAT_PATH returns.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathStat {
+    pub size: usize,
+    pub mode: u32,
+    pub atime: usize,
+    pub mtime: usize,
+    pub ctime: usize,
+}
+
+impl PathStat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+}
+
+pub struct Layer {
+    ep: Endpoint,
+}
+
+impl Layer {
+    /// Connects to the service behind `ep`, asking for error details so an
+    /// FsError a layer fails with reaches the overlay's own clients.
+    pub fn new(ep: Endpoint) -> Self {
+        let layer = Self { ep };
+        // Older services answer NotImplemented and still serve the rest
+        let _ = layer.call(proto::VERSION, &[PROTO_VERSION, WANTED], &[]);
+        layer
+    }
+
+    /// Sends `label` with `mrs` and `buf`. Returns the reply, or the error
+    /// the layer answered with, its FsError noted again for our reply.
+    fn call(&self, label: usize, mrs: &[usize], buf: &[u8]) -> Result<&'static mut UTCB, Error> {
+        let utcb = unsafe { UTCB::new() };
+        utcb.clear();
+        for (i, &mr) in mrs.iter().enumerate() {
+            utcb.set_mr(i, mr);
+        }
+        let mut flags = MsgFlags::NONE;
+        if !buf.is_empty() {
+            utcb.buffer_mut()
+                .get_mut(..buf.len())
+                .ok_or(Error::MessageTooLong)?
+                .copy_from_slice(buf);
+            utcb.set_buffer_len(buf.len());
+            flags |= MsgFlags::HAS_BUFFER;
+        }
+        utcb.set_msg_tag(MsgTag::new(FS_PROTO, label, flags));
+        self.ep.call(utcb)?;
+        if utcb.get_msg_tag().flags().contains(MsgFlags::ERROR) {
+            return Err(match FsError::from_code(utcb.get_mr(ERROR_DETAIL_MR)) {
+                Some(detail) => detail.into(),
+                None => errors::decode(utcb.get_mr(0)),
+            });
+        }
+        Ok(utcb)
+    }
+
+    /// The most a single READ_NEXT or WRITE_NEXT carries.
+    pub fn chunk_size(&self) -> usize {
+        unsafe { UTCB::new() }.buffer().len()
+    }
+
+    pub fn open(&self, path: &str, flags: OpenFlags, mode: u32) -> Result<usize, Error> {
+        let mrs = [flags.bits(), mode as usize];
+        Ok(self.call(protocol::fs::OPEN, &mrs, &cstr(path))?.get_mr(0))
+    }
+
+    pub fn close(&self, handle: usize) -> Result<(), Error> {
+        self.call(protocol::fs::CLOSE, &[handle], &[]).map(|_| ())
+    }
+
+    pub fn stat_path(&self, path: &str) -> Result<PathStat, Error> {
+        let utcb = self.call(protocol::fs::STAT_PATH, &[], &cstr(path))?;
+        Ok(PathStat {
+            size: utcb.get_mr(0),
+            mode: utcb.get_mr(1) as u32,
+            atime: utcb.get_mr(3),
+            mtime: utcb.get_mr(4),
+            ctime: utcb.get_mr(5),
+        })
+    }
+
+    /// True if `path` names something; other failures than NotFound count.
+    pub fn exists(&self, path: &str) -> Result<bool, Error> {
+        match self.stat_path(path) {
+            Ok(_) => Ok(true),
+            Err(Error::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn mkdir(&self, path: &str, mode: u32) -> Result<(), Error> {
+        self.call(protocol::fs::MKDIR, &[mode as usize], &cstr(path)).map(|_| ())
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<(), Error> {
+        self.call(protocol::fs::UNLINK, &[], &cstr(path)).map(|_| ())
+    }
+
+    pub fn rmtree(&self, path: &str) -> Result<(), Error> {
+        self.call(proto::RMTREE, &[0], &cstr(path)).map(|_| ())
+    }
+
+    pub fn rename(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.call(protocol::fs::RENAME, &[], &pair(old_path, new_path)).map(|_| ())
+    }
+
+    pub fn link(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.call(proto::LINK, &[], &pair(old_path, new_path)).map(|_| ())
+    }
+
+    /// SET_TIMES with MR0-MR3 as the overlay's client sent them.
+    pub fn set_times(&self, path: &str, times: [usize; 4]) -> Result<(), Error> {
+        self.call(proto::SET_TIMES, &times, &cstr(path)).map(|_| ())
+    }
+
+    pub fn statfs(&self) -> Result<FsStats, Error> {
+        let utcb = self.call(proto::STATFS, &[], &[])?;
+        Ok(FsStats {
+            block_size: utcb.get_mr(0),
+            total_blocks: utcb.get_mr(1) as u64,
+            free_blocks: utcb.get_mr(2) as u64,
+            avail_blocks: utcb.get_mr(3) as u64,
+            total_inodes: utcb.get_mr(4) as u64,
+            free_inodes: utcb.get_mr(5) as u64,
+        })
+    }
+
+    /// Up to `count` entries of the directory `handle`; empty at the end.
+    pub fn getdents(&self, handle: usize, count: usize) -> Result<Vec<DEntry>, Error> {
+        let utcb = self.call(protocol::fs::GETDENTS, &[handle, count], &[])?;
+        let size = core::mem::size_of::<DEntry>();
+        let count = core::cmp::min(utcb.get_mr(0), proto::dents_capacity(utcb.buffer()));
+        let entries = (0..count)
+            .map(|i| unsafe {
+                core::ptr::read_unaligned(utcb.buffer()[i * size..].as_ptr() as *const DEntry)
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    pub fn seek(&self, handle: usize, offset: i64, whence: usize) -> Result<usize, Error> {
+        let mrs = [handle, offset as usize, whence];
+        Ok(self.call(protocol::fs::SEEK, &mrs, &[])?.get_mr(0))
+    }
+
+    /// Reads into `buf` at the handle position, at most `chunk_size` bytes.
+    pub fn read_next(&self, handle: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let utcb = self.call(proto::READ_NEXT, &[handle, buf.len()], &[])?;
+        let read = utcb.get_mr(0).min(buf.len());
+        buf[..read].copy_from_slice(&utcb.buffer()[..read]);
+        Ok(read)
+    }
+
+    pub fn write_next(&self, handle: usize, data: &[u8]) -> Result<usize, Error> {
+        Ok(self.call(proto::WRITE_NEXT, &[handle], data)?.get_mr(0))
+    }
+
+    pub fn truncate(&self, handle: usize, size: usize) -> Result<(), Error> {
+        self.call(protocol::fs::TRUNCATE, &[handle, size], &[]).map(|_| ())
+    }
+}
+
+fn cstr(s: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(s.len() + 1);
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+    buf
+}
+
+fn pair(first: &str, second: &str) -> Vec<u8> {
+    let mut buf = cstr(first);
+    buf.extend_from_slice(&cstr(second));
+    buf
+}
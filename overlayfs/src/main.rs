@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+use fs::Overlay;
+use fs_common::mount::MountPoint;
+use fs_server::ServerLayout;
+use glenda::cap::{CapPtr, CapType, Endpoint, ENDPOINT_CAP, ENDPOINT_SLOT, REPLY_CAP};
+use glenda::client::FsClient;
+use glenda::interface::system::SystemService;
+use glenda::interface::ResourceService;
+use glenda::ipc::Badge;
+use glenda::protocol::resource::{ResourceType, FS_ENDPOINT};
+use glenda::utils::manager::{CSpaceManager, VSpaceManager};
+use layout::{DYNAMIC_SLOT_LIMIT, MAP_END, MAP_START, MOUNT_PATH, RECV_SLOT, VFS_SLOT};
+
+mod fs;
+mod layer;
+mod layout;
+mod server;
+
+pub use server::OverlayFsService;
+
+#[unsafe(no_mangle)]
+fn main() -> usize {
+    glenda::console::init_logging("OverlayFS");
+
+    let mut res_client = glenda::client::ResourceClient::new(glenda::cap::MONITOR_CAP);
+    let mut cspace = CSpaceManager::new(glenda::cap::CSPACE_CAP, 16);
+    let mut vspace = VSpaceManager::new(glenda::cap::VSPACE_CAP, 0x7000_0000, 0x8000_0000);
+
+    res_client
+        .alloc(Badge::null(), CapType::Endpoint, 0, ENDPOINT_SLOT)
+        .expect("OverlayFS: Failed to allocate endpoint");
+    // Without a VFS the tree is only reachable through its endpoint
+    let vfs = res_client.get_cap(Badge::null(), ResourceType::Endpoint, FS_ENDPOINT, VFS_SLOT);
+
+    // The layers come with ATTACH_LAYER once their services are up
+    let layout = ServerLayout {
+        dynamic_slot_limit: DYNAMIC_SLOT_LIMIT,
+        recv_slot: RECV_SLOT,
+        map_start: MAP_START,
+        map_end: MAP_END,
+    };
+    let overlay = Overlay::new();
+    let mut service =
+        OverlayFsService::new(overlay, layout, &mut res_client, &mut cspace, &mut vspace);
+    service
+        .listen(ENDPOINT_CAP, REPLY_CAP.cap(), CapPtr::null())
+        .expect("OverlayFS: Failed to listen");
+    match vfs {
+        Ok(cap) => {
+            let vfs = FsClient::new(Endpoint::from(cap));
+            service.set_mount_point(MountPoint::new(vfs, MOUNT_PATH));
+        }
+        Err(e) => glenda::log!("OverlayFS: no VFS endpoint ({:?}), not mounting", e),
+    }
+
+    service.run().expect("OverlayFS service crashed");
+    0
+}
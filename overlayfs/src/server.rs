@@ -0,0 +1,147 @@
+use crate::fs::Overlay;
+use crate::layer::Layer;
+use alloc::boxed::Box;
+use fs_common::clock::TimesRequest;
+use fs_common::handle::FsHandle;
+use fs_common::mount::MountOptions;
+use fs_common::proto;
+use fs_common::statfs::FsStats;
+use fs_common::version;
+use fs_server::{CallContext, FileSystemBackend, FsServer};
+use glenda::cap::Endpoint;
+use glenda::error::Error;
+use glenda::ipc::server::handle_call;
+use glenda::ipc::{Badge, MsgFlags, UTCB};
+use glenda::protocol::fs::{OpenFlags, Stat};
+use glenda::protocol::FS_PROTO;
+
+/// The overlay service: the merged tree behind the shared server loop. It
+/// mounts once ATTACH_LAYER has brought both layers.
+pub type OverlayFsService<'a> = FsServer<'a, Overlay>;
+
+impl Overlay {
+    // ATTACH_LAYER: takes the service transferred with the call as the
+    // layer MR0 names
+    fn attach(
+        &mut self,
+        badge: Badge,
+        utcb: &mut UTCB,
+        ctx: &mut CallContext,
+    ) -> Result<(), Error> {
+        if badge.bits() != 0 {
+            return Err(Error::PermissionDenied);
+        }
+        if !utcb.get_msg_tag().flags().contains(MsgFlags::HAS_CAP) {
+            return Err(Error::InvalidArgs);
+        }
+        let which = utcb.get_mr(0);
+        if which != proto::LAYER_LOWER && which != proto::LAYER_UPPER {
+            return Err(Error::InvalidArgs);
+        }
+        // Handles hold the layer they were opened on
+        if ctx.open_handles != 0 {
+            return Err(Error::WouldBlock);
+        }
+        let layer = Layer::new(Endpoint::from(ctx.take_cap()?));
+        if which == proto::LAYER_LOWER {
+            self.set_lower(layer);
+        } else {
+            self.set_upper(layer);
+        }
+        Ok(())
+    }
+}
+
+impl FileSystemBackend for Overlay {
+    const NAME: &'static str = "OverlayFS";
+    const FEATURES: usize = version::FEAT_RING_NOTIFY
+        | version::FEAT_IOVEC
+        | version::FEAT_LOCKS
+        | version::FEAT_CLONE
+        | version::FEAT_WIRE
+        | version::FEAT_LINK
+        | version::FEAT_SET_TIMES
+        | version::FEAT_STATFS
+        | version::FEAT_NEXT
+        | version::FEAT_STREAM
+        | version::FEAT_ERROR_DETAIL;
+    // The layers' inodes are not the overlay's, and a copy-up changes them
+    const KEYED_BY_PATH: bool = true;
+
+    fn open(
+        &mut self,
+        _badge: Badge,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        self.open_handle(path, flags, mode)
+    }
+
+    fn stat_path(&self, _badge: Badge, path: &str) -> Result<Stat, Error> {
+        let stat = Overlay::stat_path(self, path)?;
+        Ok(Stat {
+            size: stat.size,
+            mode: stat.mode,
+            atime: stat.atime,
+            mtime: stat.mtime,
+            ctime: stat.ctime,
+            ..Default::default()
+        })
+    }
+
+    fn stats(&self) -> Result<FsStats, Error> {
+        Overlay::stats(self)
+    }
+
+    fn mkdir(&mut self, _badge: Badge, path: &str, mode: u32) -> Result<(), Error> {
+        Overlay::mkdir(self, path, mode)
+    }
+
+    fn unlink(&mut self, _badge: Badge, path: &str) -> Result<(), Error> {
+        Overlay::unlink(self, path)
+    }
+
+    fn rename(&mut self, _badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        Overlay::rename(self, old_path, new_path)
+    }
+
+    fn link(&mut self, _badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        Overlay::link(self, old_path, new_path)
+    }
+
+    // Checked by the server, applied by the upper layer's clock
+    fn set_times(&mut self, _badge: Badge, path: &str, req: TimesRequest) -> Result<(), Error> {
+        Overlay::set_times(self, path, req.to_mrs())
+    }
+
+    // Layers are asked synchronously, so there is no JOB_ASYNC variant
+    fn rmtree(&mut self, _badge: Badge, path: &str) -> Result<(), Error> {
+        Overlay::rmtree(self, path)
+    }
+
+    fn set_mount_options(&mut self, options: MountOptions) -> Result<(), Error> {
+        // Names go to the layers as given
+        if options.casefold {
+            return Err(Error::NotSupported);
+        }
+        Ok(())
+    }
+
+    fn ready(&self) -> bool {
+        self.is_ready()
+    }
+
+    fn extra_call(
+        &mut self,
+        badge: Badge,
+        utcb: &mut UTCB,
+        ctx: &mut CallContext,
+    ) -> Option<Result<(), Error>> {
+        let tag = utcb.get_msg_tag();
+        if tag.proto() != FS_PROTO || tag.label() != proto::ATTACH_LAYER {
+            return None;
+        }
+        Some(handle_call(utcb, |u_inner| self.attach(badge, u_inner, ctx)))
+    }
+}
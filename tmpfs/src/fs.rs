@@ -0,0 +1,239 @@
+use crate::tree::{Limits, Tree};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use fs_common::clock::TimesRequest;
+use fs_common::errors::FsError;
+use fs_common::handle::FsHandle;
+use fs_common::limits::{self, TMPFS_MAX_FILE_SIZE};
+use fs_common::mount::MountOptions;
+use fs_common::proto::CURRENT_OFFSET;
+use fs_common::statfs::FsStats;
+use glenda::error::Error;
+use glenda::interface::fs::FileHandleService;
+use glenda::ipc::Badge;
+use glenda::protocol::fs::{DEntry, OpenFlags, Stat};
+use spin::Mutex;
+
+/// A tmpfs volume. Handles share the tree with it, so data written through
+/// one is seen by every other at once.
+pub struct TmpFs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl TmpFs {
+    pub fn new(limits: Limits) -> Self {
+        Self { tree: Arc::new(Mutex::new(Tree::new(limits))) }
+    }
+
+    pub fn set_mount_options(&mut self, options: MountOptions) {
+        self.tree.lock().set_noatime(options.noatime);
+    }
+
+    /// Opens absolute `path`, creating a file there for O_CREAT.
+    pub fn open_handle(
+        &mut self,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        let mut tree = self.tree.lock();
+        let (ino, created) = match tree.lookup(path) {
+            Ok(_) if flags.contains(OpenFlags::O_CREAT | OpenFlags::O_EXCL) => {
+                return Err(Error::AlreadyExists)
+            }
+            Ok(ino) => (ino, false),
+            Err(Error::NotFound) if flags.contains(OpenFlags::O_CREAT) => {
+                let (dir, name) = tree.lookup_parent(path)?;
+                (tree.create(dir, name, mode, false)?, true)
+            }
+            Err(e) => return Err(e),
+        };
+
+        let trunc = flags.contains(OpenFlags::O_TRUNC);
+        let writable = flags.intersects(OpenFlags::O_WRONLY | OpenFlags::O_RDWR);
+        if tree.is_dir(ino)? {
+            if writable || trunc {
+                return Err(FsError::IsDir.into());
+            }
+            tree.acquire(ino)?;
+            return Ok(Box::new(TmpDirHandle { tree: self.tree.clone(), ino, pos: 0 }));
+        }
+        if flags.contains(OpenFlags::O_DIRECTORY) {
+            return Err(FsError::NotDir.into());
+        }
+        if trunc && !created {
+            tree.truncate(ino, 0)?;
+        }
+        tree.acquire(ino)?;
+        let append = flags.contains(OpenFlags::O_APPEND);
+        Ok(Box::new(TmpFileHandle { tree: self.tree.clone(), ino, pos: 0, append }))
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u32) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let (dir, name) = tree.lookup_parent(path)?;
+        tree.create(dir, name, mode, true).map(|_| ())
+    }
+
+    pub fn unlink(&mut self, path: &str) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let (dir, name) = tree.lookup_parent(path)?;
+        tree.unlink(dir, name)
+    }
+
+    pub fn rmtree(&mut self, path: &str) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let (dir, name) = tree.lookup_parent(path)?;
+        tree.rmtree(dir, name)
+    }
+
+    pub fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let (from_dir, from_name) = tree.lookup_parent(old_path)?;
+        let (to_dir, to_name) = tree.lookup_parent(new_path)?;
+        tree.rename(from_dir, from_name, to_dir, to_name)
+    }
+
+    /// Gives the file at `old_path` the further name `new_path`.
+    pub fn link(&mut self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let ino = tree.lookup(old_path)?;
+        let (dir, name) = tree.lookup_parent(new_path)?;
+        tree.link(ino, dir, name)
+    }
+
+    pub fn stat_path(&self, path: &str) -> Result<Stat, Error> {
+        let tree = self.tree.lock();
+        tree.stat(tree.lookup(path)?)
+    }
+
+    pub fn set_times(&mut self, path: &str, req: TimesRequest) -> Result<(), Error> {
+        let mut tree = self.tree.lock();
+        let ino = tree.lookup(path)?;
+        tree.set_times(ino, req)
+    }
+
+    pub fn stats(&self) -> FsStats {
+        self.tree.lock().stats()
+    }
+}
+
+pub struct TmpFileHandle {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+    pos: usize,
+    append: bool,
+}
+
+impl FileHandleService for TmpFileHandle {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.tree.lock().release(self.ino);
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        self.tree.lock().stat(self.ino)
+    }
+
+    fn read(&mut self, _badge: Badge, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if advance { self.pos } else { offset };
+        let read = self.tree.lock().read(self.ino, offset, buf)?;
+        if advance {
+            self.pos = offset + read;
+        }
+        Ok(read)
+    }
+
+    fn write(&mut self, _badge: Badge, offset: usize, buf: &[u8]) -> Result<usize, Error> {
+        let mut tree = self.tree.lock();
+        let advance = offset == CURRENT_OFFSET;
+        let offset = if self.append {
+            tree.size(self.ino)?
+        } else if advance {
+            self.pos
+        } else {
+            offset
+        };
+        let written = tree.write(self.ino, offset, buf)?;
+        if advance || self.append {
+            self.pos = offset + written;
+        }
+        Ok(written)
+    }
+
+    fn getdents(&mut self, _badge: Badge, _count: usize) -> Result<Vec<DEntry>, Error> {
+        Err(FsError::NotDir.into())
+    }
+
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let size = self.tree.lock().size(self.ino)?;
+        self.pos = limits::seek_target(self.pos, size, offset, whence, TMPFS_MAX_FILE_SIZE)?;
+        Ok(self.pos)
+    }
+
+    // Nothing to write back: the data never leaves memory
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, size: usize) -> Result<(), Error> {
+        self.tree.lock().truncate(self.ino, size)
+    }
+}
+
+impl FsHandle for TmpFileHandle {
+    fn duplicate(&self) -> Result<Box<dyn FsHandle>, Error> {
+        self.tree.lock().acquire(self.ino)?;
+        let tree = self.tree.clone();
+        Ok(Box::new(TmpFileHandle { tree, ino: self.ino, pos: 0, append: self.append }))
+    }
+}
+
+pub struct TmpDirHandle {
+    tree: Arc<Mutex<Tree>>,
+    ino: u64,
+    // Entries returned so far
+    pos: usize,
+}
+
+impl FileHandleService for TmpDirHandle {
+    fn close(&mut self, _badge: Badge) -> Result<(), Error> {
+        self.tree.lock().release(self.ino);
+        Ok(())
+    }
+
+    fn stat(&self, _badge: Badge) -> Result<Stat, Error> {
+        self.tree.lock().stat(self.ino)
+    }
+
+    fn read(&mut self, _badge: Badge, _offset: usize, _buf: &mut [u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn write(&mut self, _badge: Badge, _offset: usize, _buf: &[u8]) -> Result<usize, Error> {
+        Err(FsError::IsDir.into())
+    }
+
+    fn getdents(&mut self, _badge: Badge, count: usize) -> Result<Vec<DEntry>, Error> {
+        self.tree.lock().dents(self.ino, &mut self.pos, count)
+    }
+
+    /// Positions count entries; SEEK_SET 0 starts the listing over.
+    fn seek(&mut self, _badge: Badge, offset: i64, whence: usize) -> Result<usize, Error> {
+        let size = self.tree.lock().size(self.ino)?;
+        self.pos = limits::seek_target(self.pos, size, offset, whence, TMPFS_MAX_FILE_SIZE)?;
+        Ok(self.pos)
+    }
+
+    fn sync(&mut self, _badge: Badge) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _badge: Badge, _size: usize) -> Result<(), Error> {
+        Err(FsError::IsDir.into())
+    }
+}
+
+impl FsHandle for TmpDirHandle {}
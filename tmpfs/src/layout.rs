@@ -0,0 +1,21 @@
+use glenda::cap::CapPtr;
+
+pub const VFS_SLOT: CapPtr = CapPtr::from(9);
+
+// Slots the CSpaceManager hands out stay below this
+pub const DYNAMIC_SLOT_LIMIT: CapPtr = CapPtr::from(0x100);
+// Where caps sent along with a call arrive
+pub const RECV_SLOT: CapPtr = CapPtr::from(0x100);
+
+// Client rings from SETUP_IOURING are mapped between these; the VSpaceManager
+// has the range above to itself
+pub const MAP_START: usize = 0x4000_0000;
+pub const MAP_END: usize = 0x7000_0000;
+
+// Where the volume appears in the VFS namespace until MOUNT_AT moves it
+pub const MOUNT_PATH: &str = "/tmp";
+
+// Pages file data may take, 64 MiB; writes past that fail with NoSpace
+pub const MAX_PAGES: usize = 16384;
+// Files and directories the volume may hold, the root included
+pub const MAX_INODES: usize = 65536;
@@ -0,0 +1,81 @@
+use crate::fs::TmpFs;
+use alloc::boxed::Box;
+use fs_common::clock::TimesRequest;
+use fs_common::handle::FsHandle;
+use fs_common::mount::MountOptions;
+use fs_common::statfs::FsStats;
+use fs_common::version;
+use fs_server::{FileSystemBackend, FsServer};
+use glenda::error::Error;
+use glenda::ipc::Badge;
+use glenda::protocol::fs::{OpenFlags, Stat};
+
+/// The tmpfs service: a `TmpFs` behind the shared server loop.
+pub type TmpFsService<'a> = FsServer<'a, TmpFs>;
+
+impl FileSystemBackend for TmpFs {
+    const NAME: &'static str = "TmpFS";
+    const FEATURES: usize = version::FEAT_RING_NOTIFY
+        | version::FEAT_IOVEC
+        | version::FEAT_LOCKS
+        | version::FEAT_CLONE
+        | version::FEAT_WIRE
+        | version::FEAT_LINK
+        | version::FEAT_SET_TIMES
+        | version::FEAT_STATFS
+        | version::FEAT_NEXT
+        | version::FEAT_STREAM
+        | version::FEAT_ERROR_DETAIL;
+
+    fn open(
+        &mut self,
+        _badge: Badge,
+        path: &str,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> Result<Box<dyn FsHandle>, Error> {
+        self.open_handle(path, flags, mode)
+    }
+
+    fn stat_path(&self, _badge: Badge, path: &str) -> Result<Stat, Error> {
+        TmpFs::stat_path(self, path)
+    }
+
+    fn stats(&self) -> Result<FsStats, Error> {
+        Ok(TmpFs::stats(self))
+    }
+
+    fn mkdir(&mut self, _badge: Badge, path: &str, mode: u32) -> Result<(), Error> {
+        TmpFs::mkdir(self, path, mode)
+    }
+
+    fn unlink(&mut self, _badge: Badge, path: &str) -> Result<(), Error> {
+        TmpFs::unlink(self, path)
+    }
+
+    fn rename(&mut self, _badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        TmpFs::rename(self, old_path, new_path)
+    }
+
+    fn link(&mut self, _badge: Badge, old_path: &str, new_path: &str) -> Result<(), Error> {
+        TmpFs::link(self, old_path, new_path)
+    }
+
+    fn set_times(&mut self, _badge: Badge, path: &str, req: TimesRequest) -> Result<(), Error> {
+        TmpFs::set_times(self, path, req)
+    }
+
+    // Memory is quick to free, so running within the call is fine
+    fn rmtree(&mut self, _badge: Badge, path: &str) -> Result<(), Error> {
+        TmpFs::rmtree(self, path)
+    }
+
+    fn set_mount_options(&mut self, options: MountOptions) -> Result<(), Error> {
+        // Names are kept as given
+        if options.casefold {
+            return Err(Error::NotSupported);
+        }
+        TmpFs::set_mount_options(self, options);
+        Ok(())
+    }
+}
@@ -0,0 +1,480 @@
+//! The files and directories of a tmpfs volume, all of them on the heap.
+//! Directories map names to inode numbers, files keep their data in pages
+//! allocated on first write, so holes cost nothing. An inode lives while a
+//! name or an open handle refers to it.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fs_common::clock::{self, TimesRequest, Timestamp};
+use fs_common::errors::FsError;
+use fs_common::limits::{self, TMPFS_MAX_FILE_SIZE};
+use fs_common::proto::{dentry, DT_DIR, DT_REG};
+use fs_common::statfs::FsStats;
+use glenda::arch::mem::PGSIZE;
+use glenda::error::Error;
+use glenda::protocol::fs::{DEntry, Stat};
+
+pub const ROOT_INO: u64 = 1;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+const PERM_MASK: u32 = 0o7777;
+// Anyone may create in the root, and only remove their own: /tmp
+const ROOT_MODE: u32 = S_IFDIR | 0o1777;
+const NAME_MAX: usize = 255;
+
+/// How much the volume may hold.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_pages: usize,
+    pub max_inodes: usize,
+}
+
+enum Body {
+    File {
+        size: usize,
+        // Pages written so far, by index; the rest of the file reads as zeros
+        pages: BTreeMap<usize, Box<[u8; PGSIZE]>>,
+    },
+    Dir {
+        // The root is its own parent
+        parent: u64,
+        entries: BTreeMap<String, u64>,
+    },
+}
+
+struct Node {
+    mode: u32,
+    // Names of the node; a directory only ever has one
+    nlink: u32,
+    opens: usize,
+    atime: Timestamp,
+    mtime: Timestamp,
+    ctime: Timestamp,
+    body: Body,
+}
+
+impl Node {
+    fn new(mode: u32, body: Body) -> Self {
+        let now = clock::now().unwrap_or_default();
+        Self { mode, nlink: 1, opens: 0, atime: now, mtime: now, ctime: now, body }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.body, Body::Dir { .. })
+    }
+
+    fn changed(&mut self) {
+        if let Some(now) = clock::now() {
+            self.ctime = now;
+        }
+    }
+
+    fn modified(&mut self) {
+        if let Some(now) = clock::now() {
+            self.mtime = now;
+            self.ctime = now;
+        }
+    }
+}
+
+pub struct Tree {
+    nodes: BTreeMap<u64, Node>,
+    next_ino: u64,
+    // Pages allocated across all files
+    pages: usize,
+    limits: Limits,
+    noatime: bool,
+}
+
+impl Tree {
+    pub fn new(limits: Limits) -> Self {
+        let root = Node::new(ROOT_MODE, Body::Dir { parent: ROOT_INO, entries: BTreeMap::new() });
+        Self {
+            nodes: BTreeMap::from([(ROOT_INO, root)]),
+            next_ino: ROOT_INO + 1,
+            pages: 0,
+            limits,
+            noatime: false,
+        }
+    }
+
+    pub fn set_noatime(&mut self, noatime: bool) {
+        self.noatime = noatime;
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node, Error> {
+        self.nodes.get(&ino).ok_or(Error::NotFound)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> Result<&mut Node, Error> {
+        self.nodes.get_mut(&ino).ok_or(Error::NotFound)
+    }
+
+    pub fn is_dir(&self, ino: u64) -> Result<bool, Error> {
+        Ok(self.node(ino)?.is_dir())
+    }
+
+    fn entries(&self, dir: u64) -> Result<&BTreeMap<String, u64>, Error> {
+        match &self.node(dir)?.body {
+            Body::Dir { entries, .. } => Ok(entries),
+            Body::File { .. } => Err(FsError::NotDir.into()),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: u64) -> Result<&mut BTreeMap<String, u64>, Error> {
+        match &mut self.node_mut(dir)?.body {
+            Body::Dir { entries, .. } => Ok(entries),
+            Body::File { .. } => Err(FsError::NotDir.into()),
+        }
+    }
+
+    /// The node `name` names inside directory `dir`.
+    pub fn step(&self, dir: u64, name: &str) -> Result<u64, Error> {
+        match &self.node(dir)?.body {
+            Body::Dir { .. } if name == "." => Ok(dir),
+            Body::Dir { parent, .. } if name == ".." => Ok(*parent),
+            Body::Dir { entries, .. } => entries.get(name).copied().ok_or(Error::NotFound),
+            Body::File { .. } => Err(FsError::NotDir.into()),
+        }
+    }
+
+    /// The node at absolute `path`.
+    pub fn lookup(&self, path: &str) -> Result<u64, Error> {
+        path.split('/')
+            .filter(|p| !p.is_empty())
+            .try_fold(ROOT_INO, |ino, part| self.step(ino, part))
+    }
+
+    /// The directory that holds or would hold `path`, and the name in it.
+    pub fn lookup_parent<'p>(&self, path: &'p str) -> Result<(u64, &'p str), Error> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." || name.len() > NAME_MAX {
+            return Err(Error::InvalidArgs);
+        }
+        let dir = self.lookup(dir)?;
+        self.entries(dir)?;
+        Ok((dir, name))
+    }
+
+    /// Adds an empty file or directory named `name` to directory `dir`.
+    pub fn create(&mut self, dir: u64, name: &str, mode: u32, is_dir: bool) -> Result<u64, Error> {
+        if self.entries(dir)?.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        if self.nodes.len() >= self.limits.max_inodes {
+            return Err(FsError::NoSpace.into());
+        }
+        let node = if is_dir {
+            Node::new(
+                S_IFDIR | mode & PERM_MASK,
+                Body::Dir { parent: dir, entries: BTreeMap::new() },
+            )
+        } else {
+            Node::new(S_IFREG | mode & PERM_MASK, Body::File { size: 0, pages: BTreeMap::new() })
+        };
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.nodes.insert(ino, node);
+        self.entries_mut(dir)?.insert(String::from(name), ino);
+        self.node_mut(dir)?.modified();
+        Ok(ino)
+    }
+
+    /// Copies out what `buf` can take from `offset` on, up to the end of file.
+    pub fn read(&mut self, ino: u64, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let noatime = self.noatime;
+        let node = self.node_mut(ino)?;
+        let Body::File { size, pages } = &node.body else {
+            return Err(FsError::IsDir.into());
+        };
+        let len = limits::read_len(offset, buf.len(), *size);
+        let mut done = 0;
+        while done < len {
+            let at = offset + done;
+            let (index, within) = (at / PGSIZE, at % PGSIZE);
+            let n = (PGSIZE - within).min(len - done);
+            match pages.get(&index) {
+                Some(page) => buf[done..done + n].copy_from_slice(&page[within..within + n]),
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+        if !noatime {
+            if let Some(now) = clock::now() {
+                node.atime = now;
+            }
+        }
+        Ok(len)
+    }
+
+    /// Writes `data` at `offset`, allocating the pages it lands on. Fails
+    /// without writing anything if the volume cannot take them all.
+    pub fn write(&mut self, ino: u64, offset: usize, data: &[u8]) -> Result<usize, Error> {
+        let end = limits::checked_end(offset, data.len(), TMPFS_MAX_FILE_SIZE)?;
+        let (used, max) = (self.pages, self.limits.max_pages);
+        let node = self.node_mut(ino)?;
+        let Body::File { size, pages } = &mut node.body else {
+            return Err(FsError::IsDir.into());
+        };
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let span = offset / PGSIZE..end.div_ceil(PGSIZE);
+        let missing = span.clone().filter(|i| !pages.contains_key(i)).count();
+        if used + missing > max {
+            return Err(FsError::NoSpace.into());
+        }
+
+        let mut done = 0;
+        for index in span {
+            let page = pages.entry(index).or_insert_with(|| Box::new([0; PGSIZE]));
+            let within = (offset + done) % PGSIZE;
+            let n = (PGSIZE - within).min(data.len() - done);
+            page[within..within + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        *size = (*size).max(end);
+        node.modified();
+        self.pages += missing;
+        Ok(data.len())
+    }
+
+    /// Sets the size of file `ino`, freeing the pages past the new end.
+    pub fn truncate(&mut self, ino: u64, new_size: usize) -> Result<(), Error> {
+        if new_size as u64 > TMPFS_MAX_FILE_SIZE {
+            return Err(Error::InvalidArgs);
+        }
+        let node = self.node_mut(ino)?;
+        let Body::File { size, pages } = &mut node.body else {
+            return Err(FsError::IsDir.into());
+        };
+        let mut dropped = 0;
+        if new_size < *size {
+            dropped = pages.split_off(&new_size.div_ceil(PGSIZE)).len();
+            // Bytes past the new end read as zeros should the file grow again
+            if let Some(page) = pages.get_mut(&(new_size / PGSIZE)) {
+                page[new_size % PGSIZE..].fill(0);
+            }
+        }
+        *size = new_size;
+        node.modified();
+        self.pages -= dropped;
+        Ok(())
+    }
+
+    /// Removes `name` from directory `dir`. A directory has to be empty; a
+    /// file goes once its last name and handle are gone.
+    pub fn unlink(&mut self, dir: u64, name: &str) -> Result<(), Error> {
+        let ino = self.step(dir, name)?;
+        if let Body::Dir { entries, .. } = &self.node(ino)?.body {
+            if ino == ROOT_INO || !entries.is_empty() {
+                return Err(Error::InvalidArgs);
+            }
+        }
+        self.detach(dir, name);
+        self.drop_link(ino);
+        Ok(())
+    }
+
+    /// Removes `name` from directory `dir` and everything below it.
+    pub fn rmtree(&mut self, dir: u64, name: &str) -> Result<(), Error> {
+        let ino = self.step(dir, name)?;
+        // Depth first, so each directory is empty by the time it is removed
+        let mut stack = vec![(dir, String::from(name), ino)];
+        while let Some((parent, name, ino)) = stack.last().cloned() {
+            let first = match &self.node(ino)?.body {
+                Body::Dir { entries, .. } => entries.iter().next(),
+                Body::File { .. } => None,
+            };
+            match first {
+                Some((child, &child_ino)) => stack.push((ino, child.clone(), child_ino)),
+                None => {
+                    self.unlink(parent, &name)?;
+                    stack.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives file `ino` the further name `name` in directory `dir`.
+    pub fn link(&mut self, ino: u64, dir: u64, name: &str) -> Result<(), Error> {
+        if self.node(ino)?.is_dir() {
+            return Err(Error::PermissionDenied);
+        }
+        if self.entries(dir)?.contains_key(name) {
+            return Err(Error::AlreadyExists);
+        }
+        self.entries_mut(dir)?.insert(String::from(name), ino);
+        self.node_mut(dir)?.modified();
+        let node = self.node_mut(ino)?;
+        node.nlink += 1;
+        node.changed();
+        Ok(())
+    }
+
+    /// Moves `from_name` in `from_dir` to `to_name` in `to_dir`, replacing a
+    /// file there with a file or an empty directory with a directory.
+    pub fn rename(
+        &mut self,
+        from_dir: u64,
+        from_name: &str,
+        to_dir: u64,
+        to_name: &str,
+    ) -> Result<(), Error> {
+        let ino = self.step(from_dir, from_name)?;
+        let is_dir = self.node(ino)?.is_dir();
+        if is_dir && self.is_within(to_dir, ino)? {
+            return Err(Error::InvalidArgs);
+        }
+        match self.entries(to_dir)?.get(to_name).copied() {
+            Some(target) if target == ino => return Ok(()),
+            Some(target) => {
+                let replaced = self.node(target)?;
+                let nonempty =
+                    matches!(&replaced.body, Body::Dir { entries, .. } if !entries.is_empty());
+                if replaced.is_dir() != is_dir || nonempty {
+                    return Err(Error::InvalidArgs);
+                }
+                self.detach(to_dir, to_name);
+                self.drop_link(target);
+            }
+            None => {}
+        }
+        self.detach(from_dir, from_name);
+        self.entries_mut(to_dir)?.insert(String::from(to_name), ino);
+        self.node_mut(to_dir)?.modified();
+        let node = self.node_mut(ino)?;
+        if let Body::Dir { parent, .. } = &mut node.body {
+            *parent = to_dir;
+        }
+        node.changed();
+        Ok(())
+    }
+
+    // True if directory `dir` is `ancestor` or somewhere below it
+    fn is_within(&self, mut dir: u64, ancestor: u64) -> Result<bool, Error> {
+        loop {
+            if dir == ancestor {
+                return Ok(true);
+            }
+            match &self.node(dir)?.body {
+                Body::Dir { parent, .. } if dir != ROOT_INO => dir = *parent,
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    // Takes `name` out of directory `dir` without touching what it names
+    fn detach(&mut self, dir: u64, name: &str) {
+        if let Ok(entries) = self.entries_mut(dir) {
+            entries.remove(name);
+        }
+        if let Ok(node) = self.node_mut(dir) {
+            node.modified();
+        }
+    }
+
+    fn drop_link(&mut self, ino: u64) {
+        if let Ok(node) = self.node_mut(ino) {
+            node.nlink -= 1;
+            node.changed();
+        }
+        self.reap(ino);
+    }
+
+    // Frees `ino` once neither a name nor a handle refers to it
+    fn reap(&mut self, ino: u64) {
+        match self.nodes.get(&ino) {
+            Some(node) if node.nlink == 0 && node.opens == 0 => {}
+            _ => return,
+        }
+        if let Some(Node { body: Body::File { pages, .. }, .. }) = self.nodes.remove(&ino) {
+            self.pages -= pages.len();
+        }
+    }
+
+    /// Counts a handle opened on `ino`.
+    pub fn acquire(&mut self, ino: u64) -> Result<(), Error> {
+        self.node_mut(ino)?.opens += 1;
+        Ok(())
+    }
+
+    /// Counts a handle on `ino` closed, freeing it if it was the last thing
+    /// keeping an unlinked node.
+    pub fn release(&mut self, ino: u64) {
+        if let Ok(node) = self.node_mut(ino) {
+            node.opens -= 1;
+        }
+        self.reap(ino);
+    }
+
+    pub fn set_times(&mut self, ino: u64, req: TimesRequest) -> Result<(), Error> {
+        let node = self.node_mut(ino)?;
+        if let Some(atime) = req.atime {
+            node.atime = atime;
+        }
+        if let Some(mtime) = req.mtime {
+            node.mtime = mtime;
+        }
+        node.changed();
+        Ok(())
+    }
+
+    pub fn size(&self, ino: u64) -> Result<usize, Error> {
+        Ok(match &self.node(ino)?.body {
+            Body::File { size, .. } => *size,
+            Body::Dir { entries, .. } => entries.len(),
+        })
+    }
+
+    pub fn stat(&self, ino: u64) -> Result<Stat, Error> {
+        let node = self.node(ino)?;
+        let (nlink, blocks) = match &node.body {
+            Body::File { pages, .. } => (node.nlink, pages.len() * (PGSIZE / 512)),
+            Body::Dir { .. } => (2, 0),
+        };
+        Ok(Stat {
+            ino: ino as usize,
+            mode: node.mode,
+            nlink,
+            size: self.size(ino)?,
+            blksize: PGSIZE as u32,
+            blocks,
+            atime: node.atime.stat_secs(),
+            mtime: node.mtime.stat_secs(),
+            ctime: node.ctime.stat_secs(),
+            ..Default::default()
+        })
+    }
+
+    /// Up to `count` entries of directory `dir` from the `*pos`th on, in
+    /// name order, moving `*pos` past the last one returned.
+    pub fn dents(&self, dir: u64, pos: &mut usize, count: usize) -> Result<Vec<DEntry>, Error> {
+        let entries = self.entries(dir)?;
+        let mut out = Vec::new();
+        for (name, &ino) in entries.iter().skip(*pos).take(count) {
+            *pos += 1;
+            let type_ = if self.node(ino)?.is_dir() { DT_DIR } else { DT_REG };
+            out.push(dentry(ino as usize, *pos, type_, name.as_bytes()));
+        }
+        Ok(out)
+    }
+
+    /// STATFS: the page budget and inode limit, less what is taken.
+    pub fn stats(&self) -> FsStats {
+        let free = (self.limits.max_pages - self.pages) as u64;
+        FsStats {
+            block_size: PGSIZE,
+            total_blocks: self.limits.max_pages as u64,
+            free_blocks: free,
+            avail_blocks: free,
+            total_inodes: self.limits.max_inodes as u64,
+            free_inodes: (self.limits.max_inodes - self.nodes.len()) as u64,
+        }
+    }
+}